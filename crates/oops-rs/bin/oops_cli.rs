@@ -0,0 +1,172 @@
+//! # oops-cli
+//!
+//! Operator tools that act like oops on a running stack.
+//!
+//! ## Commands
+//!
+//! - `inject --asset <symbol or address> --price <price>`: sends vega a price update for the
+//!   aggregator the asset is priced by, as if oops had captured it. The bundle is flagged as
+//!   `PriceUpdateTx::Rehearsal`, so vega and profito evaluate it like any synthetic update, but
+//!   profito only logs the bundle it would have submitted. Meant to rehearse crash scenarios and
+//!   check the pipeline end to end without waiting for the market to move.
+//!
+//! The price is in the decimals of the aggregator, either as an integer or in scientific notation
+//! (`2500e8` is 2500 on an 8 decimals feed).
+//!
+
+use alloy::{
+    primitives::{keccak256, utils::parse_units, Address, U256},
+    providers::{IpcConnect, Provider, ProviderBuilder},
+};
+use clap::{Parser, Subcommand};
+use overlord_shared::{
+    common::get_reserves_data, constants::GHO_PRICE_ORACLE, market::AaveMarket,
+    resolvers::resolve_price_source, sol_bindings::AaveOracle, MessageBundle, PriceUpdateBundle,
+    PriceUpdateTx,
+};
+use std::{
+    error::Error,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const VEGA_INBOUND_ENDPOINT: &str = "ipc:///tmp/vega_inbound";
+const INJECT_SOURCE: &str = "inject";
+// How long to wait for vega to take the update before giving up
+const SEND_TIMEOUT_MS: i32 = 5000;
+
+#[derive(Parser, Debug)]
+#[clap(name = "oops-cli", about = "Operator tools for oops-rs")]
+struct OopsCliArgs {
+    #[clap(subcommand)]
+    command: OopsCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum OopsCommand {
+    /// Send vega a rehearsal price update, which goes through vega and profito but is never submitted
+    Inject {
+        /// Reserve whose price moves, by symbol (e.g. WETH) or underlying address
+        #[clap(long)]
+        asset: String,
+        /// New price in the decimals of the aggregator, e.g. 2500e8
+        #[clap(long)]
+        price: String,
+    },
+}
+
+/// Parses a price given as an integer or in scientific notation (`2500e8`, `0.9998e8`)
+fn parse_price(price: &str) -> Result<U256, Box<dyn Error>> {
+    let parsed = match price.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => {
+            let exponent = exponent
+                .parse::<u8>()
+                .map_err(|e| format!("Invalid exponent in price {}: {}", price, e))?;
+            parse_units(mantissa, exponent)
+                .map(|units| units.get_absolute())
+                .map_err(|e| format!("Invalid price {}: {}", price, e))?
+        }
+        None => U256::from_str(price).map_err(|e| format!("Invalid price {}: {}", price, e))?,
+    };
+    if parsed.is_zero() {
+        return Err("Price must be greater than zero".into());
+    }
+    Ok(parsed)
+}
+
+async fn inject(asset: String, price: String) -> Result<(), Box<dyn Error>> {
+    let new_price = parse_price(&price)?;
+    let provider = Arc::new(
+        ProviderBuilder::new()
+            .on_ipc(IpcConnect::new("/tmp/reth.ipc".to_string()))
+            .await?,
+    );
+    // Aggregators are shared by every market, so the core one is enough to find the feed
+    let market = AaveMarket::core();
+    let asset_address = Address::from_str(&asset).ok();
+    let reserve = get_reserves_data(provider.clone(), &market)
+        .await?
+        .into_iter()
+        .find(|reserve| {
+            asset_address.map_or(reserve.symbol.eq_ignore_ascii_case(&asset), |address| {
+                address == reserve.underlyingAsset
+            })
+        })
+        .ok_or_else(|| format!("{} isn't a reserve of the market", asset))?;
+    let source = AaveOracle::new(market.oracle, provider.clone())
+        .getSourceOfAsset(reserve.underlyingAsset)
+        .call()
+        .await
+        .map_err(|e| {
+            format!(
+                "Failed to get the price source of {}: {}",
+                reserve.symbol, e
+            )
+        })?
+        ._0;
+    // GHO has no aggregator, vega maps its updates through GHO_PRICE_ORACLE
+    let (forward_to, decimals) = match resolve_price_source(&provider, source).await?.aggregator {
+        Some(aggregator) => (aggregator.address, Some(aggregator.decimals)),
+        None => (GHO_PRICE_ORACLE, None),
+    };
+    let inclusion_block = provider.get_block_number().await? + 1;
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis());
+    let id = keccak256(format!("{}{}{}", forward_to, new_price, now_ms));
+    let bundle = PriceUpdateBundle {
+        trace_id: format!("{:?}", id)[2..10].to_string(),
+        tx_hash: format!("{:?}", id),
+        price_update_tx: PriceUpdateTx::Rehearsal,
+        inclusion_block: inclusion_block.to_string(),
+        tx_new_price: new_price,
+        forward_to,
+        tx_from: Address::ZERO,
+        tx_to: forward_to,
+        tx_input: Default::default(),
+        source: INJECT_SOURCE.to_string(),
+        slot_deadline_ms: None,
+    };
+    let trace_id = bundle.trace_id.clone();
+
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::PUSH)?;
+    socket.set_linger(SEND_TIMEOUT_MS)?;
+    socket.set_sndtimeo(SEND_TIMEOUT_MS)?;
+    socket.connect(VEGA_INBOUND_ENDPOINT)?;
+    socket
+        .send(bincode::serialize(&MessageBundle::PriceUpdate(bundle))?, 0)
+        .map_err(|e| format!("Failed to send update to vega (is it running?): {}", e))?;
+
+    println!(
+        "Injected {} at {} through {} ({} decimals), aimed at block {}",
+        reserve.symbol,
+        new_price,
+        forward_to,
+        decimals.map_or("unknown".to_string(), |decimals| decimals.to_string()),
+        inclusion_block
+    );
+    println!(
+        "Follow it in the vega and profito logs with trace_id {}",
+        trace_id
+    );
+    Ok(())
+}
+
+fn run(command: OopsCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        OopsCommand::Inject { asset, price } => {
+            tokio::runtime::Runtime::new()?.block_on(inject(asset, price))?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = OopsCliArgs::parse();
+    if let Err(e) = run(args.command) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
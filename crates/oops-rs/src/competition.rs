@@ -0,0 +1,94 @@
+use alloy::{
+    primitives::{Address, Bytes},
+    rpc::types::Transaction,
+    sol_types::SolCall,
+};
+use mev_share_sse::Event as MevShareEvent;
+use overlord_shared::{sol_bindings::pool::AaveV3Pool, PoolActivity};
+use std::collections::HashSet;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+// Activity is only a hint for bribes, so it's dropped rather than slowing the streams down
+const POOL_ACTIVITY_QUEUE_SIZE: usize = 1024;
+
+/// Reports the pending txs and MEV-Share hints calling the pools of the followed markets to
+/// profito-rs, which takes them as a proxy of how many other searchers are after the same
+/// liquidations. Calls to anyone else's contract (how most searchers liquidate) aren't seen.
+#[derive(Clone)]
+pub struct PoolWatch {
+    pools: HashSet<Address>,
+    activity: mpsc::Sender<PoolActivity>,
+}
+
+impl PoolWatch {
+    /// Records `tx` if it calls one of the pools
+    pub fn observe_tx(&self, tx: &Transaction) {
+        let Some(pool) = tx.to.filter(|to| self.pools.contains(to)) else {
+            return;
+        };
+        self.send(PoolActivity {
+            tx_hash: format!("{:?}", tx.hash),
+            source: "mempool".to_string(),
+            pool,
+            assets: liquidated_assets(&tx.input),
+        });
+    }
+
+    /// Records every tx of `event` calling one of the pools
+    pub fn observe_hint(&self, event: &MevShareEvent, source: &str) {
+        for tx in event.transactions.iter() {
+            let Some(pool) = tx.to.filter(|to| self.pools.contains(to)) else {
+                continue;
+            };
+            self.send(PoolActivity {
+                tx_hash: format!("{:?}", event.hash),
+                source: source.to_string(),
+                pool,
+                assets: tx.calldata.as_ref().map_or(vec![], liquidated_assets),
+            });
+        }
+    }
+
+    fn send(&self, activity: PoolActivity) {
+        if let Err(e) = self.activity.try_send(activity) {
+            warn!("Dropping pool activity for profito: {e}");
+        }
+    }
+}
+
+/// Collateral and debt of a liquidationCall(), nothing for any other call
+fn liquidated_assets(input: &Bytes) -> Vec<Address> {
+    match AaveV3Pool::liquidationCallCall::abi_decode(input, true) {
+        Ok(call) => vec![call.collateralAsset, call.debtAsset],
+        Err(_) => vec![],
+    }
+}
+
+/// Watches `pools`, sending what it sees to profito-rs on a socket of its own
+pub fn spawn_pool_watch(pools: HashSet<Address>, profito_endpoint: &'static str) -> PoolWatch {
+    let (activity, mut received) = mpsc::channel(POOL_ACTIVITY_QUEUE_SIZE);
+    info!("Reporting activity on {} pools to profito", pools.len());
+    tokio::spawn(async move {
+        let context = zmq::Context::new();
+        let profito_socket = context.socket(zmq::PUSH).unwrap();
+        if let Err(e) = profito_socket.connect(profito_endpoint) {
+            error!("Failed to connect the pool watch to profito: {e}");
+            return;
+        }
+        while let Some(activity) = received.recv().await {
+            let serialized = match bincode::serialize(&activity) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    error!("Failed to serialize pool activity: {e}");
+                    continue;
+                }
+            };
+            // Nobody may be listening, in which case it shouldn't pile up
+            if let Err(e) = profito_socket.send(&serialized, zmq::DONTWAIT) {
+                warn!("Failed to send pool activity to profito: {e}");
+            }
+        }
+    });
+    PoolWatch { pools, activity }
+}
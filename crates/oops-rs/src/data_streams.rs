@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    primitives::{keccak256, Address, Bytes, B256, U256},
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+    sol,
+    sol_types::{SolCall, SolType, SolValue},
+};
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use overlord_shared::{
+    chain_profile::ChainProfile,
+    constants::WETH,
+    retry::{Backoff, RetryPolicy},
+    status::Counter,
+    MessageBundle, PriceUpdateBundle, PriceUpdateTx,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::time::sleep;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    dispatch::SlotScheduler,
+    price_source::{get_streamed_feeds, parse_feed_pairs},
+};
+
+const DATA_STREAMS_URL_ENV: &str = "OOPS_DATA_STREAMS_URL";
+const DATA_STREAMS_CLIENT_ID_ENV: &str = "OOPS_DATA_STREAMS_CLIENT_ID";
+const DATA_STREAMS_SECRET_ENV: &str = "OOPS_DATA_STREAMS_SECRET";
+const DATA_STREAMS_FEEDS_ENV: &str = "OOPS_DATA_STREAMS_FEEDS";
+const DATA_STREAMS_MIN_CHANGE_BPS_ENV: &str = "OOPS_DATA_STREAMS_MIN_CHANGE_BPS";
+// VerifierProxy that verifies reports, which is how they land onchain
+const DATA_STREAMS_VERIFIER_ENV: &str = "OOPS_DATA_STREAMS_VERIFIER";
+const DATA_STREAMS_URL: &str = "wss://ws.dataengine.chain.link";
+const DATA_STREAMS_WS_PATH: &str = "/api/v1/ws";
+const DATA_STREAMS_SOURCE: &str = "data_streams";
+// Reports come in about every second, most of them with the same price as the previous one
+const DATA_STREAMS_MIN_CHANGE_BPS: u64 = 5;
+// Decimals of the benchmark price of crypto streams
+const REPORT_PRICE_DECIMALS: u8 = 18;
+
+sol! {
+    interface IVerifierProxy {
+        function verify(bytes calldata payload, bytes calldata parameterPayload)
+            external
+            payable
+            returns (bytes memory verifierResponse);
+    }
+
+    /// What the Data Streams API returns, and the verifier takes
+    struct FullReport {
+        bytes32[3] reportContext;
+        bytes reportBlob;
+        bytes32[] rawRs;
+        bytes32[] rawSs;
+        bytes32 rawVs;
+    }
+
+    /// Fields every report schema (v2, v3 and v4) starts with
+    struct ReportHeader {
+        bytes32 feedId;
+        uint32 validFromTimestamp;
+        uint32 observationsTimestamp;
+        uint192 nativeFee;
+        uint192 linkFee;
+        uint32 expiresAt;
+        int192 price;
+    }
+}
+
+#[derive(Deserialize)]
+struct StreamMessage {
+    report: StreamReport,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StreamReport {
+    #[serde(rename = "feedID")]
+    feed_id: B256,
+    full_report: Bytes,
+}
+
+pub struct DataStreamsConfig {
+    url: String,
+    client_id: String,
+    secret: String,
+    verifier: Address,
+    /// Aggregator of every followed stream, by feed id
+    aggregators: HashMap<B256, Address>,
+    min_change_bps: u64,
+}
+
+impl DataStreamsConfig {
+    /// None unless the credentials and DATA_STREAMS_FEEDS_ENV (a comma-separated list of
+    /// `feed_id=aggregator` pairs) are set
+    pub fn from_env() -> Option<Self> {
+        let aggregators = parse_feed_pairs(
+            &std::env::var(DATA_STREAMS_FEEDS_ENV).unwrap_or_default(),
+            DATA_STREAMS_FEEDS_ENV,
+        );
+        if aggregators.is_empty() {
+            info!("No Data Streams feeds set, not following any");
+            return None;
+        }
+        let (Ok(client_id), Ok(secret)) = (
+            std::env::var(DATA_STREAMS_CLIENT_ID_ENV),
+            std::env::var(DATA_STREAMS_SECRET_ENV),
+        ) else {
+            warn!(
+                "{} and {} are needed to follow Data Streams, not following any",
+                DATA_STREAMS_CLIENT_ID_ENV, DATA_STREAMS_SECRET_ENV
+            );
+            return None;
+        };
+        let verifier = match std::env::var(DATA_STREAMS_VERIFIER_ENV).map(|value| value.parse()) {
+            Ok(Ok(verifier)) => verifier,
+            Ok(Err(e)) => {
+                warn!(
+                    "Invalid {}, not following Data Streams: {}",
+                    DATA_STREAMS_VERIFIER_ENV, e
+                );
+                return None;
+            }
+            Err(_) => {
+                warn!(
+                    "{} is needed to land Data Streams reports, not following any",
+                    DATA_STREAMS_VERIFIER_ENV
+                );
+                return None;
+            }
+        };
+        let min_change_bps = match std::env::var(DATA_STREAMS_MIN_CHANGE_BPS_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                warn!(
+                    "Invalid {} value {}, using {}: {}",
+                    DATA_STREAMS_MIN_CHANGE_BPS_ENV, value, DATA_STREAMS_MIN_CHANGE_BPS, e
+                );
+                DATA_STREAMS_MIN_CHANGE_BPS
+            }),
+            Err(_) => DATA_STREAMS_MIN_CHANGE_BPS,
+        };
+        Some(Self {
+            url: std::env::var(DATA_STREAMS_URL_ENV).unwrap_or(DATA_STREAMS_URL.to_string()),
+            client_id,
+            secret,
+            verifier,
+            aggregators,
+            min_change_bps,
+        })
+    }
+
+    fn path_with_query(&self) -> String {
+        let feed_ids = self
+            .aggregators
+            .keys()
+            .map(|feed_id| feed_id.to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        format!("{}?feedIDs={}", DATA_STREAMS_WS_PATH, feed_ids)
+    }
+
+    /// HMAC the API expects in X-Authorization-Signature-SHA256, over the method, path, hash of
+    /// the (empty) body, client id and timestamp
+    fn signature(&self, path_with_query: &str, timestamp_ms: u128) -> String {
+        let body_hash = hex::encode(Sha256::digest(b""));
+        let message = format!(
+            "GET {} {} {} {}",
+            path_with_query, body_hash, self.client_id, timestamp_ms
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC takes keys of any size");
+        mac.update(message.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
+
+/// Feed a full report belongs to, its benchmark price, and the fee (in wei) to verify it
+fn decode_report(full_report: &[u8]) -> Result<(B256, U256, U256), Box<dyn std::error::Error>> {
+    let report = FullReport::abi_decode_params(full_report, false)?;
+    let header = ReportHeader::abi_decode(&report.reportBlob, false)?;
+    if !header.price.is_positive() {
+        return Err(format!("Non positive price in report of feed {}", header.feedId).into());
+    }
+    Ok((
+        header.feedId,
+        U256::from_limbs_slice(header.price.into_raw().as_limbs()),
+        U256::from(header.nativeFee),
+    ))
+}
+
+/// Calldata of the verifier call that lands `full_report`, paying its fee in the native token.
+/// The FeeManager takes the wrapped native token as the fee token for that.
+fn encode_verify(full_report: Bytes) -> Bytes {
+    IVerifierProxy::verifyCall {
+        payload: full_report,
+        parameterPayload: WETH.abi_encode().into(),
+    }
+    .abi_encode()
+    .into()
+}
+
+/// Converts a report price to the decimals of the aggregator it's compared against
+fn scale_price(price: U256, decimals: u8) -> U256 {
+    match decimals.cmp(&REPORT_PRICE_DECIMALS) {
+        std::cmp::Ordering::Less => {
+            price / U256::from(10).pow(U256::from(REPORT_PRICE_DECIMALS - decimals))
+        }
+        std::cmp::Ordering::Equal => price,
+        std::cmp::Ordering::Greater => {
+            price * U256::from(10).pow(U256::from(decimals - REPORT_PRICE_DECIMALS))
+        }
+    }
+}
+
+/// Follows Chainlink Data Streams, whose reports land through a verifier call instead of a
+/// transmit(), so they never show up as pending price updates. Every report whose price moved at
+/// least min_change_bps since the last one forwarded for its feed is sent to vega as a
+/// PriceUpdateBundle for the feed's aggregator, with the verifier call as `tx_input`.
+///
+/// There's no tx to backrun, so the bundles are `PriceUpdateTx::Payload` with that call, which
+/// profito puts ahead of the liquidation. They're never held: the report lands whenever someone
+/// verifies it, not at a slot boundary.
+pub fn spawn_data_streams(
+    config: DataStreamsConfig,
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    chain_profile: ChainProfile,
+    slot_scheduler: SlotScheduler,
+    vega_endpoint: &'static str,
+    reports_forwarded: &'static Counter,
+) {
+    tokio::spawn(async move {
+        let context = zmq::Context::new();
+        let vega_socket = context.socket(zmq::PUSH).unwrap();
+        if let Err(e) = vega_socket.connect(vega_endpoint) {
+            error!("Failed to connect the Data Streams source to Vega: {e}");
+            return;
+        }
+        let feeds = get_streamed_feeds(&provider, &config.aggregators).await;
+        if feeds.is_empty() {
+            error!("None of the Data Streams feeds can be followed");
+            return;
+        }
+        info!("Following {} Data Streams feeds", feeds.len());
+        let mut last_forwarded: HashMap<B256, U256> = HashMap::new();
+        let mut backoff = Backoff::new(RetryPolicy::RECONNECT);
+        loop {
+            let path_with_query = config.path_with_query();
+            let timestamp_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_millis());
+            let mut request =
+                match format!("{}{}", config.url, path_with_query).into_client_request() {
+                    Ok(request) => request,
+                    Err(e) => {
+                        error!("Invalid Data Streams URL {}: {}", config.url, e);
+                        return;
+                    }
+                };
+            let headers = request.headers_mut();
+            for (name, value) in [
+                ("Authorization", config.client_id.clone()),
+                ("X-Authorization-Timestamp", timestamp_ms.to_string()),
+                (
+                    "X-Authorization-Signature-SHA256",
+                    config.signature(&path_with_query, timestamp_ms),
+                ),
+            ] {
+                match HeaderValue::from_str(&value) {
+                    Ok(value) => {
+                        headers.insert(name, value);
+                    }
+                    Err(e) => {
+                        error!("Invalid Data Streams {} header: {}", name, e);
+                        return;
+                    }
+                }
+            }
+            let mut stream = match connect_async(request).await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    error!(
+                        "Failed to connect to Data Streams: {}. Retrying in {:?}...",
+                        e, delay
+                    );
+                    sleep(delay).await;
+                    continue;
+                }
+            };
+            info!("Connected to Data Streams at {}", config.url);
+            let connected_at = Instant::now();
+            while let Some(message) = stream.next().await {
+                let text = match message {
+                    Ok(Message::Text(text)) => text,
+                    Ok(Message::Close(_)) => break,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!("Data Streams connection failed: {}", e);
+                        break;
+                    }
+                };
+                let report = match serde_json::from_str::<StreamMessage>(&text) {
+                    Ok(message) => message.report,
+                    Err(e) => {
+                        warn!("Failed to parse Data Streams message: {}", e);
+                        continue;
+                    }
+                };
+                let Some(feed) = feeds.get(&report.feed_id) else {
+                    continue;
+                };
+                let (price, native_fee) = match decode_report(&report.full_report) {
+                    Ok((feed_id, _, _)) if feed_id != report.feed_id => {
+                        warn!(
+                            "Report of feed {} was sent as one of {}",
+                            feed_id, report.feed_id
+                        );
+                        continue;
+                    }
+                    Ok((_, price, native_fee)) => (scale_price(price, feed.decimals), native_fee),
+                    Err(e) => {
+                        warn!("Failed to decode report of feed {}: {}", report.feed_id, e);
+                        continue;
+                    }
+                };
+                if let Some(last_price) = last_forwarded.get(&report.feed_id) {
+                    if last_price.is_zero()
+                        || price.abs_diff(*last_price) * U256::from(10_000) / *last_price
+                            < U256::from(config.min_change_bps)
+                    {
+                        continue;
+                    }
+                }
+                let expected_block = match chain_profile.estimate_inclusion_block(&provider).await {
+                    Ok(block) => block,
+                    Err(e) => {
+                        warn!("Failed to estimate Data Streams inclusion block: {e}");
+                        u64::MIN
+                    }
+                };
+                let dispatch_plan = slot_scheduler.plan(&chain_profile, expected_block);
+                let report_hash = keccak256(&report.full_report);
+                let verify_input = encode_verify(report.full_report);
+                let bundle = PriceUpdateBundle {
+                    trace_id: format!("{:?}", report_hash)[2..10].to_string(),
+                    tx_hash: format!("{:?}", report_hash),
+                    price_update_tx: PriceUpdateTx::Payload {
+                        to: config.verifier,
+                        input: verify_input.clone(),
+                        value: native_fee,
+                    },
+                    inclusion_block: dispatch_plan.inclusion_block.to_string(),
+                    tx_new_price: price,
+                    forward_to: feed.aggregator,
+                    tx_from: Address::ZERO,
+                    tx_to: config.verifier,
+                    tx_input: verify_input,
+                    source: DATA_STREAMS_SOURCE.to_string(),
+                    slot_deadline_ms: dispatch_plan.deadline_ms,
+                };
+                let trace_id = bundle.trace_id.clone();
+                let serialized = match bincode::serialize(&MessageBundle::PriceUpdate(bundle)) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        error!("Failed to serialize Data Streams update {trace_id}: {e}");
+                        continue;
+                    }
+                };
+                if let Err(e) = vega_socket.send(&serialized, 0) {
+                    error!("Failed to send Data Streams update {trace_id} to Vega: {e}");
+                    continue;
+                }
+                last_forwarded.insert(report.feed_id, price);
+                reports_forwarded.inc();
+                info!(
+                    message = "DATA STREAMS update sent.",
+                    trace_id = %trace_id,
+                    feed_id = %report.feed_id,
+                    forward_to = %feed.aggregator,
+                    price = %price,
+                    expected_block = %dispatch_plan.inclusion_block,
+                );
+            }
+            backoff.connection_lasted(connected_at.elapsed());
+            let delay = backoff.next_delay();
+            warn!(
+                "Data Streams connection closed. Reconnecting in {:?}...",
+                delay
+            );
+            sleep(delay).await;
+        }
+    });
+}
@@ -0,0 +1,123 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use alloy::{
+    primitives::{Address, U256},
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+};
+use overlord_shared::{sol_bindings::AccessControlledOCR2Aggregator, NewPrice};
+use tracing::{info, warn};
+
+use crate::price_mirror::PriceMirror;
+
+const MIN_DEVIATION_BPS_ENV: &str = "OOPS_MIN_DEVIATION_BPS";
+const DEVIATION_BYPASS_FEEDS_ENV: &str = "OOPS_DEVIATION_BYPASS_FEEDS";
+
+/// Drops price updates that barely move their feed, since they can't make anyone liquidatable
+/// that wasn't already. Disabled (every update passes) unless MIN_DEVIATION_BPS_ENV is set.
+#[derive(Clone, Debug)]
+pub struct DeviationFilter {
+    min_deviation_bps: u64,
+    /// Aggregators whose updates always pass, e.g. volatile feeds where small moves matter
+    bypass_feeds: HashSet<Address>,
+    /// Latest answer of each feed, and the inclusion block it was read for
+    latest_answers: HashMap<Address, (U256, u64)>,
+    /// Preferred over reading the latest answer from the node, when enabled
+    price_mirror: Option<Arc<PriceMirror>>,
+}
+
+impl DeviationFilter {
+    pub fn from_env(price_mirror: Option<Arc<PriceMirror>>) -> Self {
+        let min_deviation_bps = match std::env::var(MIN_DEVIATION_BPS_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                warn!(
+                    "Invalid {} value {}, forwarding every update: {}",
+                    MIN_DEVIATION_BPS_ENV, value, e
+                );
+                0
+            }),
+            Err(_) => 0,
+        };
+        let bypass_feeds = std::env::var(DEVIATION_BYPASS_FEEDS_ENV)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|feed| !feed.is_empty())
+            .filter_map(|feed| match feed.parse::<Address>() {
+                Ok(address) => Some(address),
+                Err(e) => {
+                    warn!(
+                        "Ignoring invalid address {} in {}: {}",
+                        feed, DEVIATION_BYPASS_FEEDS_ENV, e
+                    );
+                    None
+                }
+            })
+            .collect::<HashSet<Address>>();
+        info!(
+            "Forwarding updates that deviate at least {} bps from the latest answer ({} feeds bypass the filter)",
+            min_deviation_bps,
+            bypass_feeds.len()
+        );
+        Self {
+            min_deviation_bps,
+            bypass_feeds,
+            latest_answers: HashMap::new(),
+            price_mirror,
+        }
+    }
+
+    /// Whether `new_price` is at least min_deviation_bps away from the latest answer of its feed.
+    /// The answer comes from the price mirror if it has one, and is otherwise read once per feed
+    /// and inclusion block. Updates of feeds in the bypass list, or whose latest answer can't be
+    /// read, always pass.
+    pub async fn passes(
+        &mut self,
+        provider: &RootProvider<PubSubFrontend>,
+        new_price: &NewPrice,
+        block: u64,
+    ) -> bool {
+        if self.min_deviation_bps == 0 || self.bypass_feeds.contains(&new_price.chainlink_address) {
+            return true;
+        }
+        if let Some(deviation_bps) = self
+            .price_mirror
+            .as_ref()
+            .and_then(|mirror| mirror.deviation_bps(new_price))
+        {
+            return deviation_bps >= U256::from(self.min_deviation_bps);
+        }
+        let latest_answer = match self.latest_answers.get(&new_price.chainlink_address) {
+            Some((answer, read_for)) if *read_for == block => *answer,
+            _ => {
+                let answer = match AccessControlledOCR2Aggregator::new(
+                    new_price.chainlink_address,
+                    provider.clone(),
+                )
+                .latestAnswer()
+                .call()
+                .await
+                {
+                    Ok(response) if response._0.is_positive() => response._0.into_raw(),
+                    Ok(_) => return true,
+                    Err(e) => {
+                        warn!(
+                            "Failed to get latestAnswer from aggregator {}, forwarding the update: {}",
+                            new_price.chainlink_address, e
+                        );
+                        return true;
+                    }
+                };
+                self.latest_answers
+                    .insert(new_price.chainlink_address, (answer, block));
+                answer
+            }
+        };
+        let deviation_bps =
+            new_price.price.abs_diff(latest_answer) * U256::from(10_000) / latest_answer;
+        deviation_bps >= U256::from(self.min_deviation_bps)
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use overlord_shared::chain_profile::ChainProfile;
+use tokio::{
+    sync::mpsc,
+    time::{sleep_until, Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+const DISPATCH_CUTOFF_MS_ENV: &str = "OOPS_DISPATCH_CUTOFF_MS";
+const DISPATCH_HOLD_ENV: &str = "OOPS_DISPATCH_HOLD";
+// How long before the end of a slot a bundle has to reach the builders to make the next block
+const DISPATCH_CUTOFF_MS: u64 = 1000;
+
+/// When and for which block a captured update is sent to vega
+#[derive(Clone, Copy, Debug)]
+pub struct DispatchPlan {
+    pub inclusion_block: u64,
+    /// Unix time (in milliseconds) after which bundles can't make `inclusion_block`
+    pub deadline_ms: Option<u64>,
+    /// Set if the update should only be sent at the next block boundary
+    pub hold_until: Option<Instant>,
+}
+
+/// Uses the slot phase at capture time to decide which block a captured update can still be
+/// backrun in, and by when. Updates captured past the cutoff can't make the next block, so they're
+/// aimed at the one after it and, if DISPATCH_HOLD_ENV is set, held until the slot ends so vega and
+/// profito evaluate them on the head the bundle will actually land on.
+///
+/// Chains without slots always dispatch right away, with no deadline.
+#[derive(Clone, Copy, Debug)]
+pub struct SlotScheduler {
+    cutoff: Duration,
+    hold_late_updates: bool,
+}
+
+impl SlotScheduler {
+    pub fn from_env() -> Self {
+        let cutoff_ms = match std::env::var(DISPATCH_CUTOFF_MS_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                warn!(
+                    "Invalid {} value {}, using {}: {}",
+                    DISPATCH_CUTOFF_MS_ENV, value, DISPATCH_CUTOFF_MS, e
+                );
+                DISPATCH_CUTOFF_MS
+            }),
+            Err(_) => DISPATCH_CUTOFF_MS,
+        };
+        let hold_late_updates = std::env::var(DISPATCH_HOLD_ENV)
+            .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        info!(
+            "Updates captured less than {} ms before the end of a slot are aimed at the block after the next one{}",
+            cutoff_ms,
+            if hold_late_updates {
+                ", and held until the slot ends"
+            } else {
+                ""
+            }
+        );
+        Self {
+            cutoff: Duration::from_millis(cutoff_ms),
+            hold_late_updates,
+        }
+    }
+
+    pub fn plan(&self, chain_profile: &ChainProfile, expected_block: u64) -> DispatchPlan {
+        let immediate = DispatchPlan {
+            inclusion_block: expected_block,
+            deadline_ms: None,
+            hold_until: None,
+        };
+        let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) else {
+            return immediate;
+        };
+        let now_ms = now.as_millis() as u64;
+        let Some(slot_end_ms) = chain_profile.slot_end_millis(now_ms) else {
+            return immediate;
+        };
+        let cutoff_ms = self.cutoff.as_millis() as u64;
+        let remaining_ms = slot_end_ms - now_ms;
+        if remaining_ms > cutoff_ms || expected_block == 0 {
+            return DispatchPlan {
+                deadline_ms: Some(slot_end_ms.saturating_sub(cutoff_ms)),
+                ..immediate
+            };
+        }
+        let slot_ms = chain_profile.block_time().as_millis() as u64;
+        DispatchPlan {
+            inclusion_block: expected_block + 1,
+            deadline_ms: Some(slot_end_ms + slot_ms - cutoff_ms),
+            hold_until: self
+                .hold_late_updates
+                .then(|| Instant::now() + Duration::from_millis(remaining_ms)),
+        }
+    }
+}
+
+/// Sends held updates to vega once their hold is over, on a socket of its own so the processor
+/// doesn't wait for them. Takes the serialized message, the time to send it at, and its trace id.
+pub fn spawn_held_dispatcher(
+    vega_endpoint: &'static str,
+) -> mpsc::UnboundedSender<(Vec<u8>, Instant, String)> {
+    let (sender, mut held) = mpsc::unbounded_channel::<(Vec<u8>, Instant, String)>();
+    let (due_sender, mut due) = mpsc::unbounded_channel::<(Vec<u8>, String)>();
+    tokio::spawn(async move {
+        while let Some((message, hold_until, trace_id)) = held.recv().await {
+            let due_sender = due_sender.clone();
+            tokio::spawn(async move {
+                sleep_until(hold_until).await;
+                let _ = due_sender.send((message, trace_id));
+            });
+        }
+    });
+    tokio::spawn(async move {
+        let context = zmq::Context::new();
+        let vega_socket = context.socket(zmq::PUSH).unwrap();
+        if let Err(e) = vega_socket.connect(vega_endpoint) {
+            error!("Failed to connect the held update dispatcher to Vega: {e}");
+            return;
+        }
+        while let Some((message, trace_id)) = due.recv().await {
+            match vega_socket.send(&message, 0) {
+                Ok(_) => info!(message = "Held update sent.", trace_id = %trace_id),
+                Err(e) => error!("Failed to send held update {trace_id} to Vega: {e}"),
+            }
+        }
+    });
+    sender
+}
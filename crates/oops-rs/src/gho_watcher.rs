@@ -0,0 +1,164 @@
+use std::sync::Arc;
+
+use alloy::{
+    primitives::{Address, Bytes, U256},
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::{Filter, Log},
+    sol_types::SolEvent,
+};
+use futures_util::StreamExt;
+use overlord_shared::{
+    constants::{GHO_PRICE_ORACLE, GHO_VARIABLE_DEBT_TOKEN},
+    retry::{Backoff, RetryPolicy},
+    sol_bindings::{
+        GhoOracle,
+        GhoVariableDebtToken::{
+            DiscountPercentUpdated, DiscountRateStrategyUpdated, DiscountTokenUpdated,
+        },
+    },
+    status::Counter,
+    MessageBundle, PriceUpdateBundle, PriceUpdateTx,
+};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+const GHO_WATCHER_ENV: &str = "OOPS_GHO_WATCHER";
+const GHO_WATCHER_SOURCE: &str = "gho_watcher";
+// Price the GHO oracle reports, in case reading it fails
+const GHO_FIXED_PRICE: U256 = U256::from_limbs([100_000_000, 0, 0, 0]); // 1 USD
+
+/// Whether the GHO watcher runs, set with GHO_WATCHER_ENV. On by default.
+pub fn gho_watcher_enabled() -> bool {
+    std::env::var(GHO_WATCHER_ENV)
+        .map(|value| !(value == "0" || value.eq_ignore_ascii_case("false")))
+        .unwrap_or(true)
+}
+
+/// Why a GHO debt token event can leave GHO borrowers worse off. None if it can't.
+fn gho_event_reason(log: &Log) -> Option<String> {
+    match log.topic0().copied()? {
+        DiscountPercentUpdated::SIGNATURE_HASH => {
+            let event = log.log_decode::<DiscountPercentUpdated>().ok()?.inner.data;
+            // A bigger discount only lowers the debt of the user
+            (event.newDiscountPercent < event.oldDiscountPercent).then(|| {
+                format!(
+                    "discount of {} went from {} to {}",
+                    event.user, event.oldDiscountPercent, event.newDiscountPercent
+                )
+            })
+        }
+        DiscountRateStrategyUpdated::SIGNATURE_HASH => {
+            Some("discount rate strategy updated".to_string())
+        }
+        DiscountTokenUpdated::SIGNATURE_HASH => Some("discount token updated".to_string()),
+        _ => None,
+    }
+}
+
+/// GHO's price is fixed, so its aggregator never gets a transmit() to backrun, but the debt of
+/// GHO borrowers still grows when their stkAAVE discount shrinks or the discount rules change.
+///
+/// Follows those events on the GHO variable debt token and sends vega a synthetic update for
+/// GHO_PRICE_ORACLE (at most one per block), with the current GHO price and no tx to backrun, so
+/// GHO borrowers are evaluated again on the state the event left them in.
+pub fn spawn_gho_watcher(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    vega_endpoint: &'static str,
+    synthetic_updates: &'static Counter,
+) {
+    tokio::spawn(async move {
+        let context = zmq::Context::new();
+        let vega_socket = context.socket(zmq::PUSH).unwrap();
+        if let Err(e) = vega_socket.connect(vega_endpoint) {
+            error!("Failed to connect the GHO watcher to Vega: {e}");
+            return;
+        }
+        let filter = Filter::new()
+            .address(GHO_VARIABLE_DEBT_TOKEN)
+            .event_signature(vec![
+                DiscountPercentUpdated::SIGNATURE_HASH,
+                DiscountRateStrategyUpdated::SIGNATURE_HASH,
+                DiscountTokenUpdated::SIGNATURE_HASH,
+            ]);
+        let mut last_sent_block = 0;
+        let mut backoff = Backoff::new(RetryPolicy::RECONNECT);
+        loop {
+            let subscription = match provider.subscribe_logs(&filter).await {
+                Ok(subscription) => {
+                    backoff.reset();
+                    subscription
+                }
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    error!(
+                        "Failed to subscribe to GHO debt token events: {}. Retrying in {:?}...",
+                        e, delay
+                    );
+                    sleep(delay).await;
+                    continue;
+                }
+            };
+            info!("GHO watcher subscribed to GHO debt token events");
+            let mut logs = subscription.into_stream();
+            while let Some(log) = logs.next().await {
+                // Events of a block are all evaluated by the same update
+                let (Some(block_number), Some(tx_hash)) = (log.block_number, log.transaction_hash)
+                else {
+                    continue;
+                };
+                if log.removed || block_number <= last_sent_block {
+                    continue;
+                }
+                let Some(reason) = gho_event_reason(&log) else {
+                    continue;
+                };
+                let gho_price = match GhoOracle::new(GHO_PRICE_ORACLE, provider.clone())
+                    .latestAnswer()
+                    .call()
+                    .await
+                {
+                    Ok(answer) if answer._0.is_positive() => answer._0.into_raw(),
+                    _ => {
+                        warn!("Couldn't read a GHO price, using {}", GHO_FIXED_PRICE);
+                        GHO_FIXED_PRICE
+                    }
+                };
+                let bundle = PriceUpdateBundle {
+                    trace_id: format!("{:?}", tx_hash)[2..10].to_string(),
+                    tx_hash: format!("{:?}", tx_hash),
+                    price_update_tx: PriceUpdateTx::Synthetic,
+                    inclusion_block: (block_number + 1).to_string(),
+                    tx_new_price: gho_price,
+                    forward_to: GHO_PRICE_ORACLE,
+                    tx_from: Address::ZERO,
+                    tx_to: GHO_VARIABLE_DEBT_TOKEN,
+                    tx_input: Bytes::new(),
+                    source: GHO_WATCHER_SOURCE.to_string(),
+                    slot_deadline_ms: None,
+                };
+                let serialized =
+                    match bincode::serialize(&MessageBundle::PriceUpdate(bundle.clone())) {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            error!("Failed to serialize GHO update {}: {e}", bundle.trace_id);
+                            continue;
+                        }
+                    };
+                if let Err(e) = vega_socket.send(&serialized, 0) {
+                    error!("Failed to send GHO update {} to Vega: {e}", bundle.trace_id);
+                    continue;
+                }
+                last_sent_block = block_number;
+                synthetic_updates.inc();
+                info!(
+                    message = "GHO update sent.",
+                    trace_id = %bundle.trace_id,
+                    block = block_number,
+                    reason = %reason,
+                );
+            }
+            warn!("GHO debt token event subscription ended, subscribing again");
+        }
+    });
+}
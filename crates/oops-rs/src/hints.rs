@@ -0,0 +1,113 @@
+use std::{collections::HashMap, error::Error};
+
+use alloy::{
+    primitives::{Address, U256},
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+};
+use mev_share_sse::Event as MevShareEvent;
+use overlord_shared::{
+    chain_profile::ChainProfile, sol_bindings::AccessControlledOCR2Aggregator, PriceRangeEstimate,
+    PriceUpdateHintBundle,
+};
+use tracing::warn;
+
+use crate::price_mirror::PriceMirror;
+
+/// How far (in basis points) from the latest answer we expect a pending update to land.
+/// Most of the feeds we track have a 0.5% to 1% deviation threshold, so anything past
+/// 1% would have been transmitted already.
+const HINT_PRICE_DEVIATION_BPS: u64 = 100;
+
+/// Builds a hint for a MEV-Share forward() call whose calldata was withheld.
+///
+/// The `to` address is the AuthorizedForwarder of a node operator, which usually transmits to
+/// many aggregators. If the event also exposes logs we narrow the candidates down to the
+/// aggregators that emitted them, otherwise every aggregator fed by this forwarder is included.
+/// Latest answers come from `price_mirror` when it has them.
+///
+/// The function selector only tells it's a forward() call, so nothing says which way the price
+/// moves: each range spans both directions, and consumers have to check both of its ends.
+pub async fn build_price_update_hint(
+    provider: RootProvider<PubSubFrontend>,
+    forwarder_feeds: &HashMap<Address, Vec<Address>>,
+    event: &MevShareEvent,
+    forwarder: Address,
+    chain_profile: &ChainProfile,
+    source: &str,
+    price_mirror: Option<&PriceMirror>,
+) -> Result<PriceUpdateHintBundle, Box<dyn Error>> {
+    let feeds = match forwarder_feeds.get(&forwarder) {
+        Some(feeds) => feeds,
+        None => return Err(format!("Forwarder {} is not a tracked transmitter", forwarder).into()),
+    };
+    let logged_feeds = feeds
+        .iter()
+        .filter(|feed| event.logs.iter().any(|log| &log.address == *feed))
+        .cloned()
+        .collect::<Vec<Address>>();
+    let candidate_feeds = if logged_feeds.is_empty() {
+        feeds.clone()
+    } else {
+        logged_feeds
+    };
+
+    let mut price_ranges = vec![];
+    for feed in candidate_feeds {
+        let mirrored_answer = price_mirror
+            .and_then(|mirror| mirror.latest(&feed))
+            .map(|mirrored| mirrored.answer);
+        let latest_answer = match mirrored_answer {
+            Some(answer) => answer,
+            None => match AccessControlledOCR2Aggregator::new(feed, provider.clone())
+                .latestRoundData()
+                .call()
+                .await
+            {
+                Ok(round_data) if !round_data.answer.is_negative() => round_data.answer.into_raw(),
+                Ok(_) => {
+                    warn!("Ignoring negative latest answer from aggregator {}", feed);
+                    continue;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to get latestRoundData from aggregator {}: {}",
+                        feed, e
+                    );
+                    continue;
+                }
+            },
+        };
+        let deviation = latest_answer * U256::from(HINT_PRICE_DEVIATION_BPS) / U256::from(10000);
+        price_ranges.push(PriceRangeEstimate {
+            chainlink_address: feed,
+            latest_answer,
+            low: latest_answer - deviation,
+            high: latest_answer + deviation,
+        });
+    }
+    if price_ranges.is_empty() {
+        return Err(format!(
+            "No price ranges could be estimated for forwarder {}",
+            forwarder
+        )
+        .into());
+    }
+
+    let expected_block = match chain_profile.estimate_inclusion_block(&provider).await {
+        Ok(block) => block,
+        Err(e) => {
+            warn!("Failed to estimate inclusion block for hint: {e}");
+            u64::MIN
+        }
+    };
+
+    Ok(PriceUpdateHintBundle {
+        trace_id: format!("{:?}", event.hash)[2..10].to_string(),
+        tx_hash: format!("{:?}", event.hash).to_string(),
+        inclusion_block: format!("{}", &expected_block).to_string(),
+        tx_to: forwarder,
+        price_ranges,
+        source: source.to_string(),
+    })
+}
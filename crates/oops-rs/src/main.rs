@@ -1,63 +1,372 @@
 use alloy::{
+    consensus::TxEnvelope,
+    eips::eip2718::Encodable2718,
     hex,
-    primitives::{Address, Bytes, U256},
+    primitives::{Address, Bytes, B256, U256},
     providers::{IpcConnect, Provider, ProviderBuilder, RootProvider},
     pubsub::{PubSubFrontend, Subscription},
     rpc::{client::ClientBuilder, types::Transaction},
     sol_types::SolCall,
 };
+use clap::Parser;
 use ethers_core::abi::{decode, ParamType};
 use futures_util::StreamExt;
 use lru::LruCache;
 use mev_share_sse::{client::EventStream, Event as MevShareEvent, EventClient};
+use once_cell::sync::Lazy;
 use overlord_shared::{
+    asset_filter::ASSET_FILTER,
+    chain_profile::ChainProfile,
     common::get_reserves_data,
-    constants::GHO_PRICE_ORACLE,
+    constants::{GHO_PRICE_ORACLE, OOPS_INBOUND_ENDPOINT, PROFITO_COMPETITION_ENDPOINT},
+    market::resolve_markets_from_env,
+    resolvers::resolve_aggregator,
+    retry::{any_error, retry, Backoff, RetryPolicy},
     sol_bindings::{
         AccessControlledOCR2Aggregator, AuthorizedForwarder, EACAggregatorProxy,
         IUiPoolDataProviderV3::AggregatedReserveData,
     },
-    MessageBundle, NewPrice, PriceUpdateBundle,
+    startup::{self, StartupPhase},
+    status::{Counter, Gauge, StatusReporter},
+    MessageBundle, NewPrice, PriceUpdateBundle, PriceUpdateTx, ReportSequence,
 };
 
+use serde::Deserialize;
 use std::{
     collections::{HashMap, HashSet},
     error::Error,
     num::NonZeroUsize,
     sync::Arc,
+    time::Instant,
 };
 
-use futures::stream::FuturesUnordered;
+use futures::{future::select_all, stream::FuturesUnordered};
 use tokio::{
-    sync::broadcast,
-    time::{sleep, Duration},
+    sync::{broadcast::error::RecvError, RwLock},
+    time::{interval, sleep, Duration},
 };
 use tracing::{error, info, warn};
 use tracing_appender::rolling::{self, Rotation};
 use tracing_subscriber::fmt::{time::LocalTime, writer::BoxMakeWriter};
 
 mod sol_bindings;
-use sol_bindings::{forwardCall, transmitCall, transmitSecondaryCall, ForwardToDestination};
+use sol_bindings::{
+    forwardCall, transmitCall, transmitSecondaryCall, ForwardToDestination, TypeAndVersionInterface,
+};
+
+mod deviation;
+use deviation::DeviationFilter;
+
+mod hints;
+use hints::build_price_update_hint;
+
+mod tx_queue;
+use tx_queue::PendingTxQueue;
+
+mod dispatch;
+use dispatch::{spawn_held_dispatcher, SlotScheduler};
+
+mod price_audit;
+use price_audit::{spawn_price_auditor, AuditCounters, CapturedPrice};
+
+mod price_mirror;
+use price_mirror::{spawn_price_mirror, PriceMirror};
+
+mod pending_updates;
+use pending_updates::{
+    spawn_cancellation_sender, spawn_pending_update_watcher, PendingUpdateTracker,
+};
+
+mod self_test;
 
-mod resolvers;
-use resolvers::resolve_aggregator;
+mod sender_filter;
+use sender_filter::{sender_filter_enabled, subscribe_pending_txs_from, SenderFiltering};
+
+mod competition;
+use competition::spawn_pool_watch;
+
+mod gho_watcher;
+use gho_watcher::{gho_watcher_enabled, spawn_gho_watcher};
+
+mod data_streams;
+use data_streams::{spawn_data_streams, DataStreamsConfig};
+
+mod price_source;
+use price_source::spawn_price_source;
+
+mod pyth;
+use pyth::{PythConfig, PythSource};
 
 const IPC_URL: &str = "/tmp/reth.ipc";
 const MEV_SHARE_MAINNET_SSE_URL: &str = "https://mev-share.flashbots.net";
-const SECONDS_BEFORE_RECONNECTION: u64 = 2;
+// Comma separated name=url list of hint streams. Flashbots' MEV-Share only, if not set or empty
+const HINT_STREAMS_ENV: &str = "OOPS_HINT_STREAMS";
+// Source of the price updates found in the public mempool
+const MEMPOOL_SOURCE: &str = "mempool";
+// Attempts at connecting to the node at startup before giving up
+const STARTUP_CONNECTION_ATTEMPTS: u32 = 10;
 const VEGA_INBOUND_ENDPOINT: &str = "ipc:///tmp/vega_inbound";
 const OOPS_PRICE_CACHE_SIZE: usize = 10;
+// A price seen again this many blocks after it was sent is a new update, unless
+// PRICE_CACHE_TTL_BLOCKS_ENV says otherwise
+const PRICE_CACHE_TTL_BLOCKS: u64 = 25;
+const PRICE_CACHE_TTL_BLOCKS_ENV: &str = "OOPS_PRICE_CACHE_TTL_BLOCKS";
+const PENDING_TX_QUEUE_SIZE: usize = 2048;
+// Transmit-looking txs get their own lane so unrelated mempool traffic can't evict them. Nothing
+// is evicted from it, going over this size is only reported.
+const PENDING_TX_PRIORITY_QUEUE_SIZE: usize = 256;
+// Regular txs dropped by the queue are only logged once every this many
+const DROPPED_TX_LOG_INTERVAL: u64 = 1000;
+// How often a sender filtered subscription checks whether the authorized senders changed
+const SENDER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+static PRICE_CACHE_TTL_BLOCKS_SETTING: Lazy<u64> = Lazy::new(|| {
+    let Ok(value) = std::env::var(PRICE_CACHE_TTL_BLOCKS_ENV) else {
+        return PRICE_CACHE_TTL_BLOCKS;
+    };
+    value.parse().unwrap_or_else(|e| {
+        warn!(
+            "Invalid {} value {}, using {} blocks: {}",
+            PRICE_CACHE_TTL_BLOCKS_ENV, value, PRICE_CACHE_TTL_BLOCKS, e
+        );
+        PRICE_CACHE_TTL_BLOCKS
+    })
+});
+
+// Reported by the periodic status line
+static TXS_SEEN: Counter = Counter::new();
+static TXS_DROPPED: Counter = Counter::new();
+static TXS_LAGGED: Counter = Counter::new();
+static TXS_SKIPPED_BY_SENDER: Counter = Counter::new();
+static QUEUE_DEPTH: Gauge = Gauge::new();
+static PRIORITY_QUEUE_DEPTH: Gauge = Gauge::new();
+static UPDATES_DECODED: Counter = Counter::new();
+static UPDATES_FILTERED: Counter = Counter::new();
+static HINTS_SENT: Counter = Counter::new();
+static UPDATES_CANCELLED: Counter = Counter::new();
+static STALE_FEEDS: Gauge = Gauge::new();
+static PRICES_VALIDATED: Counter = Counter::new();
+static PRICE_MISMATCHES: Counter = Counter::new();
+static PRICES_UNVERIFIED: Counter = Counter::new();
+static GHO_UPDATES_SENT: Counter = Counter::new();
+static DATA_STREAMS_UPDATES_SENT: Counter = Counter::new();
+static PYTH_UPDATES_SENT: Counter = Counter::new();
+
+#[derive(Parser)]
+#[clap(
+    name = "oops-rs",
+    about = "Scouts the mempool and MEV-Share for Chainlink price updates"
+)]
+struct OopsArgs {
+    /// Run the decoding, aggregator resolution and serialization paths against the bundled
+    /// fixtures and exit. Doesn't need a node.
+    #[clap(long)]
+    self_test: bool,
+}
+
+/// Addresses allowed to send price updates, and the feeds each forwarder transmits to. Replaced as
+/// a whole when whistleblower-rs reports that Aave changed a price source.
+struct WatchedFeeds {
+    authorized_senders: Vec<Address>,
+    forwarder_feeds: HashMap<Address, Vec<Address>>,
+    report_formats: HashMap<Address, ReportFormat>,
+}
+
+/// `typeAndVersion()` types of the aggregators that transmit OCR2 reports
+const OCR2_AGGREGATOR_TYPES: [&str; 3] = [
+    "AccessControlledOCR2Aggregator",
+    "OCR2Aggregator",
+    "DualAggregator",
+];
+/// `typeAndVersion()` types of the aggregators that transmit OCR3 reports
+const OCR3_AGGREGATOR_TYPES: [&str; 2] = ["AccessControlledOCR3Aggregator", "OCR3Aggregator"];
+
+/// How an aggregator identifies the reports it receives in transmit(). Both formats transmit the
+/// same median report (see report_param_types()), OCR3 only drops epochs and rounds. Aggregators
+/// we don't know the format of are assumed to be OCR2, which is what most feeds run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ReportFormat {
+    /// reportContext[1]: 27 byte padding, 4-byte epoch and 1-byte round
+    #[default]
+    Ocr2,
+    /// reportContext[1]: 24 byte padding and 8-byte sequence number, see OCR3Base.sol
+    Ocr3,
+}
+
+impl ReportFormat {
+    /// Tells the format from what the aggregator's `typeAndVersion()` returns, e.g.
+    /// "AccessControlledOCR2Aggregator 1.0.0". None if the type isn't a known aggregator.
+    fn from_type_and_version(type_and_version: &str) -> Option<Self> {
+        let (aggregator_type, _version) = type_and_version.split_once(' ')?;
+        if OCR2_AGGREGATOR_TYPES.contains(&aggregator_type) {
+            Some(ReportFormat::Ocr2)
+        } else if OCR3_AGGREGATOR_TYPES.contains(&aggregator_type) {
+            Some(ReportFormat::Ocr3)
+        } else {
+            None
+        }
+    }
+
+    fn report_sequence(&self, report_context: &[B256; 3]) -> ReportSequence {
+        let context = report_context[1];
+        match self {
+            ReportFormat::Ocr2 => ReportSequence::EpochAndRound {
+                epoch: u32::from_be_bytes(context[27..31].try_into().unwrap()),
+                round: context[31],
+            },
+            ReportFormat::Ocr3 => ReportSequence::SequenceNumber(u64::from_be_bytes(
+                context[24..].try_into().unwrap(),
+            )),
+        }
+    }
+}
+
+/// `_decodeReport(bytes memory rawReport)` of OCR2Aggregator.sol:
+/// (uint32 observationsTimestamp, bytes32 rawObservers, int192[] observations, int192 juels)
+fn report_param_types() -> [ParamType; 4] {
+    [
+        ParamType::Uint(32),                             // observationsTimestamp
+        ParamType::FixedBytes(32),                       // rawObservers
+        ParamType::Array(Box::new(ParamType::Int(192))), // observations
+        ParamType::Int(192),                             // juelsPerFeeCoin
+    ]
+}
+
+/// An SSE endpoint streaming MEV-Share events. Besides Flashbots, this can be any relay (or an
+/// adapter in front of another hint feed) that speaks the same format.
+#[derive(Clone, Debug)]
+struct HintStream {
+    /// Sent along with the price updates found in this stream, so sources can be compared
+    name: Arc<str>,
+    url: String,
+}
+
+impl HintStream {
+    /// Parses HINT_STREAMS_ENV. Entries without a name are named after their url. A value without
+    /// any entries is taken as unset, since no stream could ever connect.
+    fn from_env() -> Vec<HintStream> {
+        let flashbots = || {
+            vec![HintStream {
+                name: "flashbots".into(),
+                url: MEV_SHARE_MAINNET_SSE_URL.to_string(),
+            }]
+        };
+        let Ok(value) = std::env::var(HINT_STREAMS_ENV) else {
+            return flashbots();
+        };
+        let hint_streams: Vec<HintStream> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.split_once('=') {
+                Some((name, url)) => HintStream {
+                    name: name.trim().into(),
+                    url: url.trim().to_string(),
+                },
+                None => HintStream {
+                    name: entry.into(),
+                    url: entry.to_string(),
+                },
+            })
+            .collect();
+        if hint_streams.is_empty() {
+            warn!(
+                "{} has no hint streams, using Flashbots' MEV-Share only",
+                HINT_STREAMS_ENV
+            );
+            return flashbots();
+        }
+        hint_streams
+    }
+}
 
 struct ProcessingHandles {
     mempool: tokio::task::JoinHandle<()>,
-    mevshare: tokio::task::JoinHandle<()>,
+    /// One per connected hint stream
+    mevshare: Vec<tokio::task::JoinHandle<()>>,
     processor: tokio::task::JoinHandle<()>,
 }
 
 #[derive(Clone)]
 enum PendingTxType {
     FromMempool(Transaction),
-    FromMevShare(MevShareEvent),
+    /// An event, and the name of the hint stream it came from
+    FromMevShare(MevShareEvent, Arc<str>),
+}
+
+impl PendingTxType {
+    fn tx_hash(&self) -> String {
+        match self {
+            PendingTxType::FromMempool(tx_body) => format!("{:?}", tx_body.hash),
+            PendingTxType::FromMevShare(event, _) => format!("{:?}", event.hash),
+        }
+    }
+
+    /// Cheap check to decide which lane of the pending tx queue this goes to. The processor
+    /// still does the full validation.
+    fn looks_like_price_update(&self) -> bool {
+        match self {
+            PendingTxType::FromMempool(tx_body) => is_transmit_call(tx_body),
+            PendingTxType::FromMevShare(event, _) => event.transactions.iter().any(|tx| {
+                tx.function_selector
+                    .as_ref()
+                    .map_or(false, |selector| selector == &[0x6f, 0xad, 0xcf, 0x72])
+            }),
+        }
+    }
+}
+
+fn record_queue_depth((priority_depth, depth): (usize, usize)) {
+    PRIORITY_QUEUE_DEPTH.set(priority_depth as u64);
+    QUEUE_DEPTH.set(depth as u64);
+}
+
+/// Pushes a tx into the pending tx queue, logging whatever had to be evicted to make room for it
+fn enqueue_pending_tx(queue: &PendingTxQueue<PendingTxType>, pending_tx: PendingTxType) {
+    let priority = pending_tx.looks_like_price_update();
+    TXS_SEEN.inc();
+    let evicted = queue.push(pending_tx, priority);
+    let (priority_depth, depth) = queue.depth();
+    record_queue_depth((priority_depth, depth));
+    if priority && priority_depth > PENDING_TX_PRIORITY_QUEUE_SIZE {
+        warn!(
+            message = "Priority lane of the pending tx queue is over capacity, the processor is falling behind.",
+            priority_depth = priority_depth,
+            priority_overflows = queue.stats().priority_overflows,
+        );
+    }
+    let Some(evicted) = evicted else {
+        return;
+    };
+    TXS_DROPPED.inc();
+    let stats = queue.stats();
+    if stats.dropped % DROPPED_TX_LOG_INTERVAL == 1 {
+        warn!(
+            message = "Pending tx queue is full, dropping oldest txs.",
+            last_dropped_tx_hash = %evicted.tx_hash(),
+            dropped = stats.dropped,
+            enqueued = stats.enqueued,
+            depth = depth,
+        );
+    }
+}
+
+/// Identifies a report regardless of how it reached us
+type ReportId = (Address, ReportSequence);
+
+/// Whether the same report was already sent to vega less than PRICE_CACHE_TTL_BLOCKS before
+/// `block` (e.g. it was seen in the mempool and then on MEV-Share). Records it as sent for `block`
+/// otherwise.
+fn is_cached_price(cache: &mut LruCache<ReportId, u64>, new_price: &NewPrice, block: u64) -> bool {
+    let report_id = (new_price.chainlink_address, new_price.sequence);
+    if let Some(&cached_block) = cache.get(&report_id) {
+        if block.saturating_sub(cached_block) < *PRICE_CACHE_TTL_BLOCKS_SETTING {
+            return true;
+        }
+    }
+    cache.put(report_id, block);
+    false
 }
 
 fn is_transmit_secondary(calldata: Option<Bytes>) -> bool {
@@ -95,8 +404,12 @@ async fn get_tx_sender_from_contract(
         .ok_or_else(|| "No transmitters found".into())
 }
 
-/// Extract the new price from the input data of a transaction
-fn get_price_from_input(tx_input: &Bytes) -> Result<NewPrice, Box<dyn Error>> {
+/// Extract the new price from the input data of a transaction. The report is decoded with the
+/// format of the aggregator it's forwarded to (OCR2 if it isn't in `report_formats`).
+fn get_price_from_input(
+    tx_input: &Bytes,
+    report_formats: &HashMap<Address, ReportFormat>,
+) -> Result<NewPrice, Box<dyn Error>> {
     // get `data` from forward(address to, bytes calldata data)
     let forward_calldata = match forwardCall::abi_decode(tx_input, false) {
         Ok(data) => data,
@@ -112,12 +425,12 @@ fn get_price_from_input(tx_input: &Bytes) -> Result<NewPrice, Box<dyn Error>> {
     //   bytes32[] calldata ss,
     //   bytes32 rawVs
     // )
-    let transmit_report = match transmitCall::abi_decode(&forward_data, false) {
-        Ok(data) => data.report,
+    let (report_context, transmit_report) = match transmitCall::abi_decode(&forward_data, false) {
+        Ok(data) => (data.reportContext, data.report),
         Err(e1) => {
             // If transmit fails, try transmitSecondary
             match transmitSecondaryCall::abi_decode(&forward_data, false) {
-                Ok(data) => data.report,
+                Ok(data) => (data.reportContext, data.report),
                 Err(e2) => {
                     error!("Failed to decode both transmit calls: \ntransmit: {e1}\ntransmitSecondary: {e2}");
                     return Err(Box::new(e2));
@@ -126,19 +439,17 @@ fn get_price_from_input(tx_input: &Bytes) -> Result<NewPrice, Box<dyn Error>> {
         }
     };
 
-    // this is what the function _decodeReport(bytes memory rawReport) of OCR2Aggregator.sol does
-    let decoded_transmit_report = match decode(
-        &[
-            ParamType::Uint(32),                             // observationsTimestamp
-            ParamType::FixedBytes(32),                       // rawObservers
-            ParamType::Array(Box::new(ParamType::Int(192))), // observations
-            ParamType::Int(192),                             // juelsPerFeeCoin
-        ],
-        &transmit_report,
-    ) {
+    let report_format = report_formats
+        .get(&forward_calldata.to)
+        .copied()
+        .unwrap_or_default();
+    let decoded_transmit_report = match decode(&report_param_types(), &transmit_report) {
         Ok(decoded) => decoded,
         Err(e) => {
-            error!("Failed to decode transmit report: {}", e);
+            error!(
+                "Failed to decode {:?} transmit report for {}: {}",
+                report_format, forward_calldata.to, e
+            );
             return Err(Box::new(e));
         }
     };
@@ -150,6 +461,7 @@ fn get_price_from_input(tx_input: &Bytes) -> Result<NewPrice, Box<dyn Error>> {
     Ok(NewPrice {
         price: answer,
         chainlink_address: forward_calldata.to,
+        sequence: report_format.report_sequence(&report_context),
     })
 }
 
@@ -210,12 +522,12 @@ async fn task_get_transmitters(
     provider_clone: Arc<RootProvider<PubSubFrontend>>,
     price_oracle: Address,
     symbol: String,
-) -> Result<Option<Vec<Address>>, Box<dyn Error + Send + Sync>> {
+) -> Result<Option<(Address, ReportFormat, Vec<Address>)>, Box<dyn Error + Send + Sync>> {
     info!("Resolving aggregator for {}", &symbol);
 
     // First get the aggregator address
     // This can be anywhere from 1 to 3 or 4 RPC calls
-    let addr = match resolve_aggregator(provider_clone.clone(), price_oracle).await {
+    let addr = match resolve_aggregator(&provider_clone, price_oracle).await {
         Ok(addr) => addr,
         Err(e) => {
             return Err(format!("resolve_aggregator() call failed for {}: {}", symbol, e).into())
@@ -223,7 +535,7 @@ async fn task_get_transmitters(
     };
 
     if addr == GHO_PRICE_ORACLE {
-        return Ok(Some(vec![]));
+        return Ok(Some((addr, ReportFormat::default(), vec![])));
     }
 
     // Then get the actual aggregator from the proxy
@@ -243,6 +555,9 @@ async fn task_get_transmitters(
         }
     };
 
+    // 1 RPC call. Feeds are being migrated to OCR3 aggregators, which encode reports differently
+    let report_format = get_report_format(provider_clone.clone(), agg_address).await;
+
     let transmitters = match AccessControlledOCR2Aggregator::new(agg_address, provider_clone)
         .getTransmitters()
         .call()
@@ -258,8 +573,38 @@ async fn task_get_transmitters(
         }
     };
 
-    // Return the transmitters
-    Ok(Some(transmitters))
+    // Return the aggregator along with its report format and transmitters
+    Ok(Some((agg_address, report_format, transmitters)))
+}
+
+/// Asks the aggregator for its `typeAndVersion()`. OCR2 if it can't be read or isn't known
+async fn get_report_format(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    aggregator: Address,
+) -> ReportFormat {
+    match TypeAndVersionInterface::new(aggregator, provider)
+        .typeAndVersion()
+        .call()
+        .await
+    {
+        Ok(response) => match ReportFormat::from_type_and_version(&response._0) {
+            Some(report_format) => report_format,
+            None => {
+                warn!(
+                    "Unknown typeAndVersion() {:?} of aggregator {}, assuming OCR2",
+                    response._0, aggregator
+                );
+                ReportFormat::Ocr2
+            }
+        },
+        Err(e) => {
+            warn!(
+                "Couldn't get typeAndVersion() from aggregator {}, assuming OCR2: {}",
+                aggregator, e
+            );
+            ReportFormat::Ocr2
+        }
+    }
 }
 
 /// Get authorized senders from a transmitter address
@@ -287,27 +632,53 @@ async fn task_get_authorized_senders(
 
 /// Get the list of addresses that we will listen to for new price updates
 ///
-/// 1. Collect all values from each item in AAVE_V3_UI_POOL_DATA's `getReservesData(AAVE_V3_PROVIDER_ADDRESS)`
+/// 1. Collect all values from each item in AAVE_V3_UI_POOL_DATA's `getReservesData()`, for every
+///    market in OVERLORD_AAVE_MARKETS. Reserves priced by the same oracle are only kept once.
 /// 2. The "priceOracle" attributes are contracts which are either EACAggregatorInterface, or
 ///    some subtype of it, but eventually resolve to an EACAggregatorInterface and, more importantly,
 ///    to something that implements a `getTransmitters()` function
 /// 3. Call `getTransmitters()` on each of these contracts and collect the addresses. Remove
 ///    duplicates and return the list. Those are all the addresses authorized to send
 ///    price updates to relevant assets, and those are the only ones we need to listen to.
+///
+/// Also returns which aggregators each transmitter (AuthorizedForwarder) sends updates to, which
+/// is what lets us evaluate MEV-Share hints that don't disclose the forward() calldata, and the
+/// report format of each aggregator.
 async fn collect_transmitters(
     provider: Arc<RootProvider<PubSubFrontend>>,
-) -> Result<Vec<Address>, Box<dyn Error>> {
-    // one RPC call
-    let reserves = match get_reserves_data(provider.clone()).await {
-        Ok(response) => response,
-        Err(e) => {
-            return Err(format!(
-                "Error fetching reserves data in collect_transmitters(): {}",
-                e
-            )
-            .into())
-        }
-    };
+) -> Result<WatchedFeeds, Box<dyn Error>> {
+    // one RPC call per market
+    let mut reserves = vec![];
+    let mut seen_oracles = HashSet::new();
+    for market in resolve_markets_from_env(provider.clone()).await {
+        let market_reserves = match get_reserves_data(provider.clone(), &market).await {
+            Ok(response) => response,
+            Err(e) if market.is_core() => {
+                return Err(format!(
+                    "Error fetching reserves data in collect_transmitters(): {}",
+                    e
+                )
+                .into())
+            }
+            Err(e) => {
+                warn!(
+                    "Error fetching reserves data of the {} market, not following its feeds: {}",
+                    market.name, e
+                );
+                continue;
+            }
+        };
+        reserves.extend(market_reserves.into_iter().filter(|reserve| {
+            let allowed = ASSET_FILTER.is_allowed(&reserve.underlyingAsset);
+            if !allowed {
+                info!(
+                    "Not following the feed of {}, ignored asset",
+                    reserve.symbol
+                );
+            }
+            allowed && seen_oracles.insert(reserve.priceOracle)
+        }));
+    }
 
     // Create a HashMap that maps price oracles to their respective reserve data
     let mut reserves_mapping: HashMap<Address, AggregatedReserveData> = HashMap::new();
@@ -333,12 +704,25 @@ async fn collect_transmitters(
     let mut unique_transmitters = HashSet::new();
     let mut sender_tasks = FuturesUnordered::new();
     let mut collected_authorized_forwarders = Vec::new();
+    let mut forwarder_feeds: HashMap<Address, Vec<Address>> = HashMap::new();
+    let mut report_formats: HashMap<Address, ReportFormat> = HashMap::new();
 
     // Process aggregator tasks as they complete
     while let Some(result) = aggregator_tasks.next().await {
         match result {
-            Ok(Some(transmitters)) => {
+            Ok(Some((aggregator, report_format, transmitters))) => {
+                if report_format != ReportFormat::Ocr2 {
+                    info!(
+                        "Aggregator {} sends {:?} reports",
+                        aggregator, report_format
+                    );
+                }
+                report_formats.insert(aggregator, report_format);
                 for transmitter in transmitters {
+                    forwarder_feeds
+                        .entry(transmitter)
+                        .or_default()
+                        .push(aggregator);
                     // Skip if we've already processed this transmitter
                     if unique_transmitters.insert(transmitter) {
                         // Only process new/unique transmitters
@@ -353,7 +737,7 @@ async fn collect_transmitters(
                 }
             }
             Ok(None) => {
-                // This was a GHO_PRICE_ORACLE, skip it
+                // This was a GHO_PRICE_ORACLE, which the GHO watcher covers instead
                 continue;
             }
             Err(e) => return Err(e),
@@ -379,7 +763,157 @@ async fn collect_transmitters(
     // Remove duplicates from collected authorized forwarders
     let unique_forwarders: HashSet<_> = collected_authorized_forwarders.drain(..).collect();
     collected_authorized_forwarders = unique_forwarders.into_iter().collect();
-    Ok(collected_authorized_forwarders)
+    Ok(WatchedFeeds {
+        authorized_senders: collected_authorized_forwarders,
+        forwarder_feeds,
+        report_formats,
+    })
+}
+
+impl WatchedFeeds {
+    /// Starts watching `aggregator` on top of the current feeds, without collecting everything
+    /// again. `senders` are the authorized senders of its transmitters.
+    fn add_aggregator(
+        &mut self,
+        aggregator: Address,
+        report_format: ReportFormat,
+        transmitters: &[Address],
+        senders: Vec<Address>,
+    ) {
+        self.report_formats.insert(aggregator, report_format);
+        for transmitter in transmitters {
+            let feeds = self.forwarder_feeds.entry(*transmitter).or_default();
+            if !feeds.contains(&aggregator) {
+                feeds.push(aggregator);
+            }
+        }
+        for sender in senders {
+            if !self.authorized_senders.contains(&sender) {
+                self.authorized_senders.push(sender);
+            }
+        }
+    }
+}
+
+/// Reads what add_aggregator() needs for a single aggregator and its transmitters
+async fn resolve_aggregator_senders(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    aggregator: Address,
+    transmitters: &[Address],
+) -> (ReportFormat, Vec<Address>) {
+    let report_format = get_report_format(provider.clone(), aggregator).await;
+    let mut senders = vec![];
+    for transmitter in transmitters {
+        match task_get_authorized_senders(provider.clone(), *transmitter).await {
+            Ok(transmitter_senders) => senders.extend(transmitter_senders),
+            Err(e) => error!("Error getting authorized senders: {}", e),
+        }
+    }
+    (report_format, senders)
+}
+
+/// Collects the transmitters again every time whistleblower-rs reports an AssetSourceUpdated
+/// event, so a swapped adapter doesn't leave oops listening to the wrong forwarders. If they can't
+/// be collected, the new aggregator and the transmitters whistleblower-rs resolved for it are
+/// added to the current ones. Either way, the price mirror starts following the new aggregators.
+fn spawn_asset_source_listener(
+    watched_feeds: Arc<RwLock<WatchedFeeds>>,
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    price_mirror: Option<Arc<PriceMirror>>,
+) -> tokio::task::JoinHandle<()> {
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let context = zmq::Context::new();
+        let inbound_socket = match context.socket(zmq::PULL) {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("Failed to create oops inbound socket, price source changes will be ignored: {e}");
+                return;
+            }
+        };
+        if let Err(e) = inbound_socket.bind(OOPS_INBOUND_ENDPOINT) {
+            error!("Failed to bind to {OOPS_INBOUND_ENDPOINT}, price source changes will be ignored: {e}");
+            return;
+        }
+        info!("Listening for price source changes on {OOPS_INBOUND_ENDPOINT}");
+        loop {
+            let msg = match inbound_socket.recv_bytes(0) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    warn!("Failed to receive inbound message: {e}");
+                    continue;
+                }
+            };
+            let update = match bincode::deserialize::<MessageBundle>(&msg) {
+                Ok(MessageBundle::AssetSourceUpdate(update)) => update,
+                Ok(other) => {
+                    warn!("Ignoring unexpected inbound message: {:?}", other);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Failed to deserialize inbound message: {e}");
+                    continue;
+                }
+            };
+            info!(
+                message = "Price source changed, collecting transmitters again",
+                trace_id = %update.trace_id,
+                asset = %update.asset,
+                source = %update.source,
+                aggregator = ?update.aggregator,
+            );
+            let new_feeds = match runtime.block_on(collect_transmitters(provider.clone())) {
+                Ok(resp) => resp,
+                Err(e) => {
+                    let Some(aggregator) = update.aggregator else {
+                        error!("Failed to collect transmitters, keeping the current ones: {e}");
+                        continue;
+                    };
+                    error!(
+                        "Failed to collect transmitters, adding the {} of {aggregator} to the current ones: {e}",
+                        update.transmitters.len()
+                    );
+                    let (report_format, senders) = runtime.block_on(resolve_aggregator_senders(
+                        provider.clone(),
+                        aggregator,
+                        &update.transmitters,
+                    ));
+                    watched_feeds.blocking_write().add_aggregator(
+                        aggregator,
+                        report_format,
+                        &update.transmitters,
+                        senders,
+                    );
+                    if let Some(price_mirror) = price_mirror.as_ref() {
+                        price_mirror.watch([aggregator]);
+                    }
+                    continue;
+                }
+            };
+            if let Some(aggregator) = update.aggregator {
+                if !new_feeds
+                    .forwarder_feeds
+                    .values()
+                    .any(|feeds| feeds.contains(&aggregator))
+                {
+                    warn!(
+                        "New aggregator {aggregator} of {} has no tracked forwarder",
+                        update.asset
+                    );
+                }
+            }
+            if let Some(price_mirror) = price_mirror.as_ref() {
+                price_mirror.watch(new_feeds.forwarder_feeds.values().flatten().cloned());
+            }
+            let mut watched_feeds = watched_feeds.blocking_write();
+            info!(
+                "Now listening to {} authorized senders (was {})",
+                new_feeds.authorized_senders.len(),
+                watched_feeds.authorized_senders.len()
+            );
+            *watched_feeds = new_feeds;
+        }
+    })
 }
 
 fn _setup_logging() {
@@ -393,69 +927,48 @@ fn _setup_logging() {
         .init();
 }
 
-fn get_slot_information() -> (f32, f32) {
-    const SLOT_DURATION: f32 = 12.0;
-
-    // Get current time with subsecond precision
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap();
-
-    // Convert to total seconds and nanoseconds
-    let total_seconds = now.as_secs();
-    let nanos = now.subsec_nanos() as f32 / 1_000_000_000.0;
-
-    // Get seconds within the current minute and add nanoseconds
-    let seconds_in_minute = (total_seconds % 60) as f32 + nanos;
-
-    // Calculate seconds within the current slot
-    let captured_at = seconds_in_minute % SLOT_DURATION;
-
-    // Calculate remaining time in slot
-    let remaining = SLOT_DURATION - captured_at;
-
-    // Round to 1 decimal place
-    let captured_at = (captured_at * 10.0).round() / 10.0;
-    let remaining = (remaining * 10.0).round() / 10.0;
-
-    (captured_at, remaining)
-}
-
 /// Create a new provider to connect to the Ethereum node
 async fn create_provider() -> Result<RootProvider<PubSubFrontend>, Box<dyn Error>> {
-    let ipc = IpcConnect::new(IPC_URL.to_string());
-    let client = match ClientBuilder::default().ipc(ipc).await {
-        Ok(client) => {
-            client.set_channel_size(2048);
-            client
-        }
-        Err(e) => {
-            error!(
-                "Failed to connect to IPC: {e}. Retrying in {} seconds...",
-                SECONDS_BEFORE_RECONNECTION
-            );
-            sleep(Duration::from_secs(SECONDS_BEFORE_RECONNECTION)).await;
-            return Err(Box::new(e));
-        }
-    };
+    let client = retry(
+        RetryPolicy::RECONNECT.with_max_attempts(STARTUP_CONNECTION_ATTEMPTS),
+        "connect to IPC",
+        any_error,
+        || ClientBuilder::default().ipc(IpcConnect::new(IPC_URL.to_string())),
+    )
+    .await?;
+    client.set_channel_size(2048);
     let provider = ProviderBuilder::new().on_client(client);
     Ok(provider)
 }
 
-/// Create a mempool stream to listen for pending transactions
+/// Create a mempool stream to listen for pending transactions. If `senders` is set, only the
+/// transactions of those senders are let through, see `sender_filter`.
 async fn create_subscription_stream(
     provider: &RootProvider<PubSubFrontend>,
-) -> Result<Subscription<Transaction>, Box<dyn Error>> {
+    senders: Option<&[Address]>,
+) -> Result<(Subscription<Transaction>, Option<SenderFiltering>), Box<dyn Error>> {
+    if let Some(senders) = senders {
+        let (stream, filtering) = subscribe_pending_txs_from(provider, senders).await?;
+        return Ok((stream, Some(filtering)));
+    }
     let stream = match provider.subscribe_full_pending_transactions().await {
         Ok(stream) => stream,
         Err(e) => return Err(Box::new(e)),
     };
-    Ok(stream)
+    Ok((stream, None))
+}
+
+/// Encodes a signed pending tx the way eth_getRawTransactionByHash returns it
+fn encode_raw_tx(tx: &Transaction) -> Result<Bytes, Box<dyn Error>> {
+    let envelope = TxEnvelope::try_from(tx.clone())?;
+    Ok(envelope.encoded_2718().into())
 }
 
-async fn create_mev_share_stream() -> Result<EventStream<mev_share_sse::Event>, Box<dyn Error>> {
+async fn create_mev_share_stream(
+    url: &str,
+) -> Result<EventStream<mev_share_sse::Event>, Box<dyn Error>> {
     let client = EventClient::default();
-    let stream = match client.events(MEV_SHARE_MAINNET_SSE_URL).await {
+    let stream = match client.events(url).await {
         Ok(stream) => stream,
         Err(e) => {
             return Err(Box::new(e));
@@ -466,15 +979,27 @@ async fn create_mev_share_stream() -> Result<EventStream<mev_share_sse::Event>,
 
 #[tokio::main]
 async fn main() {
+    let args = OopsArgs::parse();
+    if args.self_test {
+        let passed = self_test::run().await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
     _setup_logging();
 
-    let new_price_cache: LruCache<NewPrice, ()> =
+    let new_price_cache: LruCache<ReportId, u64> =
         LruCache::new(NonZeroUsize::new(OOPS_PRICE_CACHE_SIZE).unwrap());
     info!(
-        "Price cache initialized with size: {}",
-        OOPS_PRICE_CACHE_SIZE
+        "Price cache initialized with size: {}, entries expire after {} blocks",
+        OOPS_PRICE_CACHE_SIZE, *PRICE_CACHE_TTL_BLOCKS_SETTING
     );
 
+    let slot_scheduler = SlotScheduler::from_env();
+    let price_mirror = PriceMirror::from_env();
+    let deviation_filter = DeviationFilter::from_env(price_mirror.clone());
+    let hint_streams = HintStream::from_env();
+    info!("Hint streams: {:?}", hint_streams);
+
     let provider = match create_provider().await {
         Ok(provider) => provider,
         Err(e) => {
@@ -483,95 +1008,274 @@ async fn main() {
         }
     };
 
-    let transmitters = match collect_transmitters(Arc::new(provider.clone())).await {
+    // Decides how the inclusion block of pending updates is estimated
+    let chain_profile = match ChainProfile::from_provider(&provider).await {
+        Ok(chain_profile) => chain_profile,
+        Err(e) => {
+            error!("Failed to get the chain profile: {e}");
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "Chain is {} ({}), block cadence: {:?}",
+        chain_profile.name, chain_profile.chain_id, chain_profile.cadence
+    );
+
+    let startup_permit = startup::acquire("oops-rs", StartupPhase::Live).await;
+    let watched_feeds = match collect_transmitters(Arc::new(provider.clone())).await {
         Ok(resp) => resp,
         Err(e) => {
             error!("Failed to collect transmitters: {e}");
             std::process::exit(1);
         }
     };
+    drop(startup_permit);
     info!(
         "Transmitters we would listen to for price updates: {:?}",
-        transmitters
+        watched_feeds.authorized_senders
+    );
+    if let Some(price_mirror) = price_mirror.as_ref() {
+        let mut aggregators = watched_feeds
+            .forwarder_feeds
+            .values()
+            .flatten()
+            .cloned()
+            .collect::<Vec<Address>>();
+        aggregators.sort();
+        aggregators.dedup();
+        spawn_price_mirror(
+            price_mirror.clone(),
+            Arc::new(provider.clone()),
+            aggregators,
+            &STALE_FEEDS,
+        );
+    }
+    let watched_feeds = Arc::new(RwLock::new(watched_feeds));
+    spawn_asset_source_listener(
+        watched_feeds.clone(),
+        Arc::new(provider.clone()),
+        price_mirror.clone(),
+    );
+
+    // Mempool updates are watched until they land, and cancelled if they're replaced or dropped
+    let (pending_updates, cancellations) = PendingUpdateTracker::new(&UPDATES_CANCELLED);
+    spawn_cancellation_sender(cancellations, VEGA_INBOUND_ENDPOINT);
+    // Pending calls to the pools tell profito how contested its liquidations are
+    let pool_watch = spawn_pool_watch(
+        resolve_markets_from_env(Arc::new(provider.clone()))
+            .await
+            .into_iter()
+            .map(|market| market.pool)
+            .collect(),
+        PROFITO_COMPETITION_ENDPOINT,
+    );
+    spawn_pending_update_watcher(pending_updates.clone(), Arc::new(provider.clone()));
+    let held_updates = spawn_held_dispatcher(VEGA_INBOUND_ENDPOINT);
+    // Decoded prices are checked against what their aggregator reports once they should have landed
+    let price_auditor = spawn_price_auditor(
+        Arc::new(provider.clone()),
+        AuditCounters {
+            validated: &PRICES_VALIDATED,
+            mismatched: &PRICE_MISMATCHES,
+            unverified: &PRICES_UNVERIFIED,
+        },
     );
 
+    // GHO has no transmitters, so its borrowers are reevaluated on events of the GHO debt token
+    if gho_watcher_enabled() {
+        spawn_gho_watcher(
+            Arc::new(provider.clone()),
+            VEGA_INBOUND_ENDPOINT,
+            &GHO_UPDATES_SENT,
+        );
+    }
+    // Reports of pull-based feeds land through a verifier call, not a transmit() in the mempool
+    if let Some(data_streams_config) = DataStreamsConfig::from_env() {
+        spawn_data_streams(
+            data_streams_config,
+            Arc::new(provider.clone()),
+            chain_profile,
+            slot_scheduler,
+            VEGA_INBOUND_ENDPOINT,
+            &DATA_STREAMS_UPDATES_SENT,
+        );
+    }
+    if let Some(pyth_config) = PythConfig::from_env() {
+        if let Some(pyth) = PythSource::new(pyth_config, Arc::new(provider.clone())).await {
+            spawn_price_source(
+                pyth,
+                Arc::new(provider.clone()),
+                chain_profile,
+                slot_scheduler,
+                VEGA_INBOUND_ENDPOINT,
+                &PYTH_UPDATES_SENT,
+            );
+        }
+    }
+
+    StatusReporter::new("oops-rs")
+        .counter("txs_seen", &TXS_SEEN)
+        .counter("txs_dropped", &TXS_DROPPED)
+        .counter("txs_lagged", &TXS_LAGGED)
+        .counter("txs_skipped_by_sender", &TXS_SKIPPED_BY_SENDER)
+        .gauge("queue_depth", &QUEUE_DEPTH)
+        .gauge("priority_queue_depth", &PRIORITY_QUEUE_DEPTH)
+        .counter("updates_decoded", &UPDATES_DECODED)
+        .counter("updates_filtered", &UPDATES_FILTERED)
+        .counter("hints_sent", &HINTS_SENT)
+        .counter("updates_cancelled", &UPDATES_CANCELLED)
+        .counter("prices_validated", &PRICES_VALIDATED)
+        .counter("price_mismatches", &PRICE_MISMATCHES)
+        .counter("prices_unverified", &PRICES_UNVERIFIED)
+        .counter("gho_updates_sent", &GHO_UPDATES_SENT)
+        .counter("data_streams_updates_sent", &DATA_STREAMS_UPDATES_SENT)
+        .counter("pyth_updates_sent", &PYTH_UPDATES_SENT)
+        .gauge("stale_feeds", &STALE_FEEDS)
+        .spawn(Arc::new(provider.clone()));
+
+    let filter_by_sender = sender_filter_enabled();
+    let mut backoff = Backoff::new(RetryPolicy::RECONNECT);
     loop {
         let provider = provider.clone();
         // Outer loop to restart IPC on major connection issues
-        let mut mempool_tx_stream = match create_subscription_stream(&provider).await {
+        let subscribed_senders = watched_feeds.read().await.authorized_senders.clone();
+        let (mut mempool_tx_stream, sender_filtering) = match create_subscription_stream(
+            &provider,
+            filter_by_sender.then_some(subscribed_senders.as_slice()),
+        )
+        .await
+        {
             Ok(stream) => stream,
             Err(e) => {
+                let delay = backoff.next_delay();
                 error!(
-                    "Failed to subscribe to mempool transactions: {e}. Retrying in {} seconds...",
-                    SECONDS_BEFORE_RECONNECTION
+                    "Failed to subscribe to mempool transactions: {e}. Retrying in {:?}...",
+                    delay
                 );
-                sleep(Duration::from_secs(SECONDS_BEFORE_RECONNECTION)).await;
+                sleep(delay).await;
                 continue;
             }
         };
-        let mut mev_share_tx_stream = match create_mev_share_stream().await {
-            Ok(stream) => stream,
-            Err(e) => {
-                error!(
-                    "Failed to create mev-share stream: {e}. Retrying in {} seconds...",
-                    SECONDS_BEFORE_RECONNECTION
-                );
-                sleep(Duration::from_secs(SECONDS_BEFORE_RECONNECTION)).await;
-                continue;
+        // A hint stream that can't be reached is left out until the next restart, as long as
+        // another one is up
+        let mut mev_share_tx_streams = vec![];
+        for hint_stream in hint_streams.iter() {
+            match create_mev_share_stream(&hint_stream.url).await {
+                Ok(stream) => mev_share_tx_streams.push((hint_stream.name.clone(), stream)),
+                Err(e) => error!(
+                    "Failed to create {} hint stream ({}): {e}",
+                    hint_stream.name, hint_stream.url
+                ),
             }
-        };
+        }
+        if mev_share_tx_streams.is_empty() {
+            let delay = backoff.next_delay();
+            error!(
+                "No hint stream could be created. Retrying in {:?}...",
+                delay
+            );
+            sleep(delay).await;
+            continue;
+        }
+        let connected_at = Instant::now();
 
-        let (tx_buffer, mut rx_buffer) = broadcast::channel::<PendingTxType>(2048);
-        let tx_buffer_for_mempool = tx_buffer.clone();
-        let tx_buffer_for_mev_share = tx_buffer.clone();
+        let pending_tx_queue =
+            PendingTxQueue::new(PENDING_TX_QUEUE_SIZE, PENDING_TX_PRIORITY_QUEUE_SIZE);
+        let mut rx_buffer = pending_tx_queue.receiver();
+        let tx_buffer_for_mempool = pending_tx_queue.clone();
+        let pending_updates_for_mempool = pending_updates.clone();
+        let watched_feeds_for_mempool = watched_feeds.clone();
+        let pool_watch_for_mempool = pool_watch.clone();
 
         let mempool_receiver_handle = tokio::spawn(async move {
+            let mut sender_check = interval(SENDER_CHECK_INTERVAL);
             loop {
-                match mempool_tx_stream.recv().await {
-                    Ok(tx_body) => {
-                        if let Err(e) =
-                            tx_buffer_for_mempool.send(PendingTxType::FromMempool(tx_body))
+                let received = tokio::select! {
+                    received = mempool_tx_stream.recv() => received,
+                    _ = sender_check.tick(), if sender_filtering.is_some() => {
+                        // The subscription only lets the senders it was created with through
+                        if watched_feeds_for_mempool.read().await.authorized_senders
+                            != subscribed_senders
                         {
-                            error!("Failed to send tx to buffer from mempool receiver: {e}");
+                            info!("Authorized senders changed, subscribing to pending txs again");
                             break;
                         }
+                        continue;
+                    }
+                };
+                match received {
+                    Ok(tx_body) => {
+                        pool_watch_for_mempool.observe_tx(&tx_body);
+                        if sender_filtering
+                            .as_ref()
+                            .is_some_and(|filtering| !filtering.allows(&tx_body.from))
+                        {
+                            TXS_SKIPPED_BY_SENDER.inc();
+                            continue;
+                        }
+                        // Checked before queueing, since the queue may evict the replacement
+                        pending_updates_for_mempool.check_replacement(&tx_body);
+                        enqueue_pending_tx(
+                            &tx_buffer_for_mempool,
+                            PendingTxType::FromMempool(tx_body),
+                        );
+                    }
+                    // The subscription is a broadcast channel, which skips what we didn't read
+                    // in time instead of waiting for us. Those txs are lost, but the stream is fine.
+                    Err(RecvError::Lagged(skipped)) => {
+                        TXS_LAGGED.add(skipped);
+                        warn!(
+                            message = "Mempool subscription lagged, txs were skipped.",
+                            skipped = skipped,
+                        );
                     }
-                    Err(e) => {
-                        error!("Unknow stream enqueue error on mempool receiver: {e}");
+                    Err(RecvError::Closed) => {
+                        error!("Mempool subscription closed");
                         break;
                     }
                 }
             }
+            tx_buffer_for_mempool.close();
         });
 
-        let mev_share_receiver_handle = tokio::spawn(async move {
-            while let Some(event) = mev_share_tx_stream.next().await {
-                match event {
-                    Ok(event) => {
-                        if event.transactions.is_empty() {
-                            continue;
-                        };
-                        if let Err(e) =
-                            tx_buffer_for_mev_share.send(PendingTxType::FromMevShare(event))
-                        {
-                            error!("Failed to send tx to buffer from mev-share receiver: {e}");
+        let mut mev_share_receiver_handles = vec![];
+        for (source, mut mev_share_tx_stream) in mev_share_tx_streams {
+            let tx_buffer_for_mev_share = pending_tx_queue.clone();
+            let pool_watch = pool_watch.clone();
+            mev_share_receiver_handles.push(tokio::spawn(async move {
+                while let Some(event) = mev_share_tx_stream.next().await {
+                    match event {
+                        Ok(event) => {
+                            if event.transactions.is_empty() {
+                                continue;
+                            };
+                            pool_watch.observe_hint(&event, &source);
+                            enqueue_pending_tx(
+                                &tx_buffer_for_mev_share,
+                                PendingTxType::FromMevShare(event, source.clone()),
+                            );
+                        }
+                        Err(e) => {
+                            error!("Unknow stream enqueue error on {source} receiver: {e}");
                             break;
-                        };
-                    }
-                    Err(e) => {
-                        error!("Unknow stream enqueue error on mev-share receiver: {e}");
-                        break;
+                        }
                     }
                 }
-            }
-        });
+                tx_buffer_for_mev_share.close();
+            }));
+        }
 
         let processor_handle = tokio::spawn({
-            let allowed_addresses = transmitters.clone();
+            let watched_feeds = watched_feeds.clone();
             let provider_clone = provider.clone();
             let vega_context = zmq::Context::new();
             let vega_socket = vega_context.socket(zmq::PUSH).unwrap();
             let mut new_price_cache = new_price_cache.clone();
+            let mut deviation_filter = deviation_filter.clone();
+            let pending_updates = pending_updates.clone();
+            let price_mirror = price_mirror.clone();
+            let price_auditor = price_auditor.clone();
+            let held_updates = held_updates.clone();
             match vega_socket.connect(VEGA_INBOUND_ENDPOINT) {
                 Ok(_) => info!("Connected to vega inbound endpoint"),
                 Err(e) => {
@@ -580,7 +1284,8 @@ async fn main() {
                 }
             }
             async move {
-                while let Ok(tx_body) = rx_buffer.recv().await {
+                while let Some(tx_body) = rx_buffer.recv().await {
+                    record_queue_depth(rx_buffer.depth());
                     match tx_body {
                         PendingTxType::FromMempool(tx_body) => {
                             let tx_hash = tx_body.hash;
@@ -588,23 +1293,44 @@ async fn main() {
                             if !is_transmit_call(&tx_body) {
                                 continue;
                             }
-                            if !allowed_addresses.contains(&tx_from) {
+                            if !watched_feeds
+                                .read()
+                                .await
+                                .authorized_senders
+                                .contains(&tx_from)
+                            {
                                 warn!(
                                     message = "Found mempool valid transmit() call from non-tracked address",
                                     tx_from = %format!("{:?}", tx_from),
                                     tx_hash = %format!("{:?}", tx_hash),
-                                    slot_info = %format!("{:?}", get_slot_information()),
+                                    slot_info = %format!("{:?}", chain_profile.slot_position()),
                                 );
                                 continue;
                             }
-                            let new_price = match get_price_from_input(&tx_body.input) {
+                            let new_price = match get_price_from_input(
+                                &tx_body.input,
+                                &watched_feeds.read().await.report_formats,
+                            ) {
                                 Ok(new_price) => new_price,
                                 Err(e) => {
                                     error!("MEMPOOL INVALID PRICE UPDATE: failed to get price from input: {e}");
                                     continue;
                                 }
                             };
-                            if new_price_cache.get(&new_price).is_some() {
+                            UPDATES_DECODED.inc();
+                            let expected_block = match chain_profile
+                                .estimate_inclusion_block(&provider_clone)
+                                .await
+                            {
+                                Ok(block) => block,
+                                Err(e) => {
+                                    warn!("Failed to estimate mempool inclusion block: {e}");
+                                    u64::MIN
+                                }
+                            };
+                            let dispatch_plan = slot_scheduler.plan(&chain_profile, expected_block);
+                            let expected_block = dispatch_plan.inclusion_block;
+                            if is_cached_price(&mut new_price_cache, &new_price, expected_block) {
                                 info!(
                                     message = "Ignoring cached MEMPOOL update.",
                                     trace_id = %format!("{:?}", tx_hash)[2..10],
@@ -613,32 +1339,46 @@ async fn main() {
                                     forward_to = %new_price.chainlink_address,
                                 );
                                 continue;
-                            } else {
-                                new_price_cache.put(new_price.clone(), ());
-                            };
-                            let expected_block = match provider_clone.get_block_number().await {
-                                // When reading the block, the provider is going to return the last submitted block
-                                // that it's aware of, meaning that a pending tx is expected to land on block + 1
-                                // at the earliest
-                                Ok(block) => block + 1,
-                                Err(e) => {
-                                    warn!("Failed to get mempool block number: {e}");
-                                    u64::MIN
-                                }
-                            };
+                            }
+                            if !deviation_filter
+                                .passes(&provider_clone, &new_price, expected_block)
+                                .await
+                            {
+                                UPDATES_FILTERED.inc();
+                                info!(
+                                    message = "Ignoring MEMPOOL update below the deviation threshold.",
+                                    trace_id = %format!("{:?}", tx_hash)[2..10],
+                                    price = %new_price.price,
+                                    forward_to = %new_price.chainlink_address,
+                                );
+                                continue;
+                            }
                             let raw_tx = match provider_clone
                                 .get_raw_transaction_by_hash(tx_hash)
                                 .await
                             {
-                                Ok(raw_tx) => raw_tx,
-                                Err(e) => {
-                                    error!("Failed to get mempool raw transaction by hash: {e}");
-                                    continue;
+                                Ok(Some(raw_tx)) => raw_tx,
+                                result => {
+                                    if let Err(e) = result {
+                                        warn!("Failed to get mempool raw transaction by hash: {e}");
+                                    }
+                                    // The node no longer has it (e.g. it already left the pool),
+                                    // but the pending tx we got is signed
+                                    match encode_raw_tx(&tx_body) {
+                                        Ok(raw_tx) => raw_tx,
+                                        Err(e) => {
+                                            error!(
+                                                "Failed to rebuild raw transaction {}: {e}",
+                                                tx_hash
+                                            );
+                                            continue;
+                                        }
+                                    }
                                 }
                             };
                             let bundle = PriceUpdateBundle {
                                 tx_hash: format!("{:?}", &tx_hash).to_string(),
-                                raw_tx: raw_tx,
+                                price_update_tx: PriceUpdateTx::RawTx(raw_tx),
                                 inclusion_block: format!("{}", &expected_block).to_string(),
                                 trace_id: format!("{:?}", &tx_hash)[2..10].to_string(),
                                 tx_new_price: new_price.price,
@@ -647,7 +1387,9 @@ async fn main() {
                                     .to
                                     .expect("This mempool tx didn't define a TO address"),
                                 tx_from,
-                                tx_input: tx_body.input,
+                                tx_input: tx_body.input.clone(),
+                                source: MEMPOOL_SOURCE.to_string(),
+                                slot_deadline_ms: dispatch_plan.deadline_ms,
                             };
                             let message_bundle = MessageBundle::PriceUpdate(bundle.clone());
                             let serialized_bundle = match bincode::serialize(&message_bundle) {
@@ -657,25 +1399,46 @@ async fn main() {
                                     continue;
                                 }
                             };
-                            match vega_socket.send(&serialized_bundle, 0) {
-                                Ok(_) => (),
-                                Err(e) => {
-                                    error!("Failed to send mempool bundle to Vega: {e}");
-                                    continue;
+                            match dispatch_plan.hold_until {
+                                Some(hold_until) => {
+                                    let _ = held_updates.send((
+                                        serialized_bundle,
+                                        hold_until,
+                                        bundle.trace_id.clone(),
+                                    ));
+                                }
+                                None => {
+                                    if let Err(e) = vega_socket.send(&serialized_bundle, 0) {
+                                        error!("Failed to send mempool bundle to Vega: {e}");
+                                        continue;
+                                    }
                                 }
                             };
+                            pending_updates.track(
+                                &tx_body,
+                                &bundle.trace_id,
+                                new_price.chainlink_address,
+                                expected_block,
+                            );
                             info!(
                                 message = "MEMPOOL update sent.",
                                 trace_id = %bundle.trace_id,
                                 expected_block = %expected_block,
+                                deadline_ms = ?dispatch_plan.deadline_ms,
+                                held = dispatch_plan.hold_until.is_some(),
                                 tx_hash = %format!("{:?}", tx_hash),
-                                slot_info = %format!("{:?}", get_slot_information()),
+                                slot_info = %format!("{:?}", chain_profile.slot_position()),
                                 price = %format!("{:?}", new_price.price),
                                 forward_to = %new_price.chainlink_address,
                             );
+                            let _ = price_auditor.send(CapturedPrice {
+                                trace_id: bundle.trace_id,
+                                new_price,
+                                expected_block,
+                            });
                         }
-                        PendingTxType::FromMevShare(event) => {
-                            for tx in event.transactions {
+                        PendingTxType::FromMevShare(event, source) => {
+                            for tx in event.transactions.clone() {
                                 if tx.to.is_none() {
                                     // MevShare tx doesn't define 'to' field. Nothing to do.
                                     continue;
@@ -693,13 +1456,60 @@ async fn main() {
                                     continue;
                                 }
                                 if tx.calldata.is_none() {
-                                    // No calldata available for this mevshare event. Nothing to do
+                                    // No calldata available for this mevshare event, so we can't know the exact
+                                    // price. Send a hint so vega can at least pre-warm the likely candidates.
+                                    let hint = match build_price_update_hint(
+                                        provider_clone.clone(),
+                                        &watched_feeds.read().await.forwarder_feeds,
+                                        &event,
+                                        tx.to.unwrap(),
+                                        &chain_profile,
+                                        &source,
+                                        price_mirror.as_deref(),
+                                    )
+                                    .await
+                                    {
+                                        Ok(hint) => hint,
+                                        Err(e) => {
+                                            warn!("Failed to build MEVSHRE hint: {e}");
+                                            continue;
+                                        }
+                                    };
+                                    let message_bundle =
+                                        MessageBundle::PriceUpdateHint(hint.clone());
+                                    let serialized_bundle =
+                                        match bincode::serialize(&message_bundle) {
+                                            Ok(bundle) => bundle,
+                                            Err(e) => {
+                                                error!("Failed to serialize hint bundle: {e}");
+                                                continue;
+                                            }
+                                        };
+                                    match vega_socket.send(&serialized_bundle, 0) {
+                                        Ok(_) => (),
+                                        Err(e) => {
+                                            error!("Failed to send hint bundle to Vega: {e}");
+                                            continue;
+                                        }
+                                    };
+                                    HINTS_SENT.inc();
+                                    info!(
+                                        message = "MEVSHRE hint sent.",
+                                        trace_id = %hint.trace_id,
+                                        source = %source,
+                                        expected_block = %hint.inclusion_block,
+                                        tx_hash = %hint.tx_hash,
+                                        slot_info = %format!("{:?}", chain_profile.slot_position()),
+                                        forwarder = %hint.tx_to,
+                                        feeds = hint.price_ranges.len(),
+                                    );
                                     continue;
                                 }
                                 let tx_calldata = tx.calldata.clone();
                                 if is_transmit_secondary(tx.calldata.clone()) {
                                     let new_price = match get_price_from_input(
                                         &tx.calldata.unwrap(),
+                                        &watched_feeds.read().await.report_formats,
                                     ) {
                                         Ok(new_price) => new_price,
                                         Err(e) => {
@@ -707,7 +1517,25 @@ async fn main() {
                                             continue;
                                         }
                                     };
-                                    if new_price_cache.get(&new_price).is_some() {
+                                    UPDATES_DECODED.inc();
+                                    let expected_block = match chain_profile
+                                        .estimate_inclusion_block(&provider_clone)
+                                        .await
+                                    {
+                                        Ok(block) => block,
+                                        Err(e) => {
+                                            warn!("Failed to estimate inclusion block: {e}");
+                                            u64::MIN
+                                        }
+                                    };
+                                    let dispatch_plan =
+                                        slot_scheduler.plan(&chain_profile, expected_block);
+                                    let expected_block = dispatch_plan.inclusion_block;
+                                    if is_cached_price(
+                                        &mut new_price_cache,
+                                        &new_price,
+                                        expected_block,
+                                    ) {
                                         info!(
                                             message = "Ignoring cached MEVSHRE update.",
                                             trace_id = %format!("{:?}", event.hash)[2..10].to_string(),
@@ -716,9 +1544,20 @@ async fn main() {
                                             forward_to = %new_price.chainlink_address,
                                         );
                                         continue;
-                                    } else {
-                                        new_price_cache.put(new_price.clone(), ());
-                                    };
+                                    }
+                                    if !deviation_filter
+                                        .passes(&provider_clone, &new_price, expected_block)
+                                        .await
+                                    {
+                                        UPDATES_FILTERED.inc();
+                                        info!(
+                                            message = "Ignoring MEVSHRE update below the deviation threshold.",
+                                            trace_id = %format!("{:?}", event.hash)[2..10].to_string(),
+                                            price = %new_price.price,
+                                            forward_to = %new_price.chainlink_address,
+                                        );
+                                        continue;
+                                    }
                                     let tx_from = match get_tx_sender_from_contract(
                                         provider.clone(),
                                         new_price.chainlink_address,
@@ -733,20 +1572,9 @@ async fn main() {
                                             continue;
                                         }
                                     };
-                                    let expected_block =
-                                        match provider_clone.get_block_number().await {
-                                            // When reading the block, the provider is going to return the last submitted block
-                                            // that it's aware of, meaning that a pending tx is expected to land on block + 1
-                                            // at the earliest
-                                            Ok(block) => block + 1,
-                                            Err(e) => {
-                                                warn!("Failed to get block number: {e}");
-                                                u64::MIN
-                                            }
-                                        };
                                     let bundle = PriceUpdateBundle {
                                         tx_hash: format!("{:?}", event.hash).to_string(),
-                                        raw_tx: None, // I believe that we can pass the hash if it's a mevshare update
+                                        price_update_tx: PriceUpdateTx::HashOnly(event.hash),
                                         trace_id: format!("{:?}", event.hash)[2..10].to_string(),
                                         inclusion_block: format!("{}", &expected_block).to_string(),
                                         tx_new_price: new_price.price,
@@ -754,6 +1582,8 @@ async fn main() {
                                         tx_to: tx.to.unwrap(),
                                         tx_from,
                                         tx_input: tx_calldata.unwrap(),
+                                        source: source.to_string(),
+                                        slot_deadline_ms: dispatch_plan.deadline_ms,
                                     };
                                     let message_bundle = MessageBundle::PriceUpdate(bundle.clone());
                                     let serialized_bundle =
@@ -764,22 +1594,39 @@ async fn main() {
                                                 continue;
                                             }
                                         };
-                                    match vega_socket.send(&serialized_bundle, 0) {
-                                        Ok(_) => (),
-                                        Err(e) => {
-                                            error!("Failed to send bundle to Vega: {e}");
-                                            continue;
+                                    match dispatch_plan.hold_until {
+                                        Some(hold_until) => {
+                                            let _ = held_updates.send((
+                                                serialized_bundle,
+                                                hold_until,
+                                                bundle.trace_id.clone(),
+                                            ));
+                                        }
+                                        None => {
+                                            if let Err(e) = vega_socket.send(&serialized_bundle, 0)
+                                            {
+                                                error!("Failed to send bundle to Vega: {e}");
+                                                continue;
+                                            }
                                         }
                                     };
                                     info!(
                                         message = "MEVSHRE update sent.",
                                         trace_id = %bundle.trace_id,
+                                        source = %source,
                                         expected_block = %expected_block,
+                                        deadline_ms = ?dispatch_plan.deadline_ms,
+                                        held = dispatch_plan.hold_until.is_some(),
                                         tx_hash = %format!("{:?}", event.hash),
-                                        slot_info = %format!("{:?}", get_slot_information()),
+                                        slot_info = %format!("{:?}", chain_profile.slot_position()),
                                         price = %format!("{:?}", new_price.price),
                                         forward_to = %new_price.chainlink_address,
                                     );
+                                    let _ = price_auditor.send(CapturedPrice {
+                                        trace_id: bundle.trace_id,
+                                        new_price,
+                                        expected_block,
+                                    });
                                 }
                             }
                         }
@@ -794,22 +1641,32 @@ async fn main() {
 
         let mut handles = ProcessingHandles {
             mempool: mempool_receiver_handle,
-            mevshare: mev_share_receiver_handle,
+            mevshare: mev_share_receiver_handles,
             processor: processor_handle,
         };
 
         tokio::select! {
             _ = &mut handles.mempool => error!("Mempool receiver handle ended unexpectedly. Restarting all handlers"),
-            _ = &mut handles.mevshare => error!("MevShare receiver handle ended unexpectedly. Restarting all handlers"),
+            _ = select_all(handles.mevshare.iter_mut()) => error!("MevShare receiver handle ended unexpectedly. Restarting all handlers"),
             _ = &mut handles.processor => error!("Processor handle ended unexpectedly. Restarting all handlers"),
         };
 
-        info!("tokio::select finished. Aborting all handlers");
+        let stats = pending_tx_queue.stats();
+        info!(
+            message = "tokio::select finished. Aborting all handlers",
+            enqueued = stats.enqueued,
+            dropped = stats.dropped,
+            priority_enqueued = stats.priority_enqueued,
+            priority_overflows = stats.priority_overflows,
+        );
         handles.mempool.abort();
-        handles.mevshare.abort();
+        for handle in handles.mevshare.iter() {
+            handle.abort();
+        }
         handles.processor.abort();
         info!("Handlers ended. Restarting all handlers");
 
-        sleep(Duration::from_secs(SECONDS_BEFORE_RECONNECTION)).await;
+        backoff.connection_lasted(connected_at.elapsed());
+        backoff.wait().await;
     }
 }
@@ -0,0 +1,257 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use alloy::{
+    primitives::{Address, B256},
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::Transaction,
+};
+use overlord_shared::{
+    status::Counter, MessageBundle, PriceUpdateCancelReason, PriceUpdateCancellation,
+};
+use tokio::{
+    sync::mpsc,
+    time::{interval, Duration},
+};
+use tracing::{error, info, warn};
+
+// How often the watcher looks for a new head to check the pending updates against
+const HEAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Updates still pending this many blocks after their expected inclusion block are no longer
+// watched. Bundles built on them are long expired by then.
+const MAX_BLOCKS_PAST_INCLUSION: u64 = 5;
+
+struct PendingUpdate {
+    tx_hash: B256,
+    trace_id: String,
+    forward_to: Address,
+    expected_block: u64,
+}
+
+/// Mempool price updates already forwarded to vega-rs that haven't landed yet, by sender and
+/// nonce. Updates that get replaced or dropped are cancelled through the channel returned by
+/// `new`, so vega-rs and profito-rs stop acting on their price.
+///
+/// MEV-Share updates aren't tracked, since their txs never show up in the node's pool.
+pub struct PendingUpdateTracker {
+    pending: Mutex<HashMap<(Address, u64), PendingUpdate>>,
+    cancellations: mpsc::UnboundedSender<PriceUpdateCancellation>,
+    cancelled: &'static Counter,
+}
+
+impl PendingUpdateTracker {
+    pub fn new(
+        cancelled: &'static Counter,
+    ) -> (Arc<Self>, mpsc::UnboundedReceiver<PriceUpdateCancellation>) {
+        let (cancellations, receiver) = mpsc::unbounded_channel();
+        let tracker = Arc::new(Self {
+            pending: Mutex::new(HashMap::new()),
+            cancellations,
+            cancelled,
+        });
+        (tracker, receiver)
+    }
+
+    pub fn track(
+        &self,
+        tx: &Transaction,
+        trace_id: &str,
+        forward_to: Address,
+        expected_block: u64,
+    ) {
+        self.pending.lock().unwrap().insert(
+            (tx.from, tx.nonce),
+            PendingUpdate {
+                tx_hash: tx.hash,
+                trace_id: trace_id.to_string(),
+                forward_to,
+                expected_block,
+            },
+        );
+    }
+
+    /// Cancels the pending update `tx` replaces, if any. Meant to be called for every mempool
+    /// tx, whatever it calls, since a replacement doesn't have to be another transmit().
+    pub fn check_replacement(&self, tx: &Transaction) {
+        let mut pending = self.pending.lock().unwrap();
+        let key = (tx.from, tx.nonce);
+        if pending
+            .get(&key)
+            .map_or(true, |update| update.tx_hash == tx.hash)
+        {
+            return;
+        }
+        if let Some(update) = pending.remove(&key) {
+            self.cancel(
+                update,
+                PriceUpdateCancelReason::Replaced { by: Some(tx.hash) },
+            );
+        }
+    }
+
+    /// Stops watching the updates that landed, and cancels the ones whose nonce was used by
+    /// another tx or that the node no longer has
+    async fn check_pending(&self, provider: &RootProvider<PubSubFrontend>, head: u64) {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, update)| (*key, update.tx_hash, update.expected_block))
+            .collect::<Vec<_>>();
+        for ((from, nonce), tx_hash, expected_block) in pending {
+            let reason = match Self::pending_status(provider, from, nonce, tx_hash).await {
+                Ok(PendingStatus::Landed) => {
+                    self.forget((from, nonce), tx_hash);
+                    continue;
+                }
+                Ok(PendingStatus::Pending) => {
+                    if head > expected_block + MAX_BLOCKS_PAST_INCLUSION {
+                        info!(
+                            "Update {:?} still pending {} blocks after its expected inclusion block, no longer watching it",
+                            tx_hash,
+                            head - expected_block
+                        );
+                        self.forget((from, nonce), tx_hash);
+                    }
+                    continue;
+                }
+                Ok(PendingStatus::Cancelled(reason)) => reason,
+                Err(e) => {
+                    warn!("Failed to check pending update {:?}: {}", tx_hash, e);
+                    continue;
+                }
+            };
+            let mut pending = self.pending.lock().unwrap();
+            // It may have been replaced from the mempool while we were checking
+            if pending
+                .get(&(from, nonce))
+                .map_or(false, |update| update.tx_hash == tx_hash)
+            {
+                if let Some(update) = pending.remove(&(from, nonce)) {
+                    self.cancel(update, reason);
+                }
+            }
+        }
+    }
+
+    async fn pending_status(
+        provider: &RootProvider<PubSubFrontend>,
+        from: Address,
+        nonce: u64,
+        tx_hash: B256,
+    ) -> Result<PendingStatus, Box<dyn std::error::Error>> {
+        if provider.get_transaction_receipt(tx_hash).await?.is_some() {
+            return Ok(PendingStatus::Landed);
+        }
+        if provider.get_transaction_count(from).await? > nonce {
+            // The nonce is read from a newer head than the receipt was, so the update itself may
+            // have landed in between
+            if provider.get_transaction_receipt(tx_hash).await?.is_some() {
+                return Ok(PendingStatus::Landed);
+            }
+            return Ok(PendingStatus::Cancelled(
+                PriceUpdateCancelReason::Replaced { by: None },
+            ));
+        }
+        if provider.get_transaction_by_hash(tx_hash).await?.is_none() {
+            return Ok(PendingStatus::Cancelled(PriceUpdateCancelReason::Dropped));
+        }
+        Ok(PendingStatus::Pending)
+    }
+
+    fn forget(&self, key: (Address, u64), tx_hash: B256) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending
+            .get(&key)
+            .map_or(false, |update| update.tx_hash == tx_hash)
+        {
+            pending.remove(&key);
+        }
+    }
+
+    fn cancel(&self, update: PendingUpdate, reason: PriceUpdateCancelReason) {
+        self.cancelled.inc();
+        warn!(
+            message = "Pending update cancelled.",
+            trace_id = %update.trace_id,
+            tx_hash = %format!("{:?}", update.tx_hash),
+            forward_to = %update.forward_to,
+            reason = ?reason,
+        );
+        let cancellation = PriceUpdateCancellation {
+            trace_id: update.trace_id,
+            tx_hash: format!("{:?}", update.tx_hash),
+            forward_to: update.forward_to,
+            reason,
+        };
+        if self.cancellations.send(cancellation).is_err() {
+            error!("Cancellation sender is gone, the cancellation was lost");
+        }
+    }
+}
+
+enum PendingStatus {
+    Landed,
+    Pending,
+    Cancelled(PriceUpdateCancelReason),
+}
+
+/// Checks the pending updates every time the head moves
+pub fn spawn_pending_update_watcher(
+    tracker: Arc<PendingUpdateTracker>,
+    provider: Arc<RootProvider<PubSubFrontend>>,
+) {
+    tokio::spawn(async move {
+        let mut ticker = interval(HEAD_POLL_INTERVAL);
+        let mut checked_head = 0;
+        loop {
+            ticker.tick().await;
+            let head = match provider.get_block_number().await {
+                Ok(head) => head,
+                Err(e) => {
+                    warn!("Failed to get the head to check pending updates: {}", e);
+                    continue;
+                }
+            };
+            if head == checked_head {
+                continue;
+            }
+            tracker.check_pending(&provider, head).await;
+            checked_head = head;
+        }
+    });
+}
+
+/// Forwards cancellations to vega-rs on a socket of its own, since they can come from the
+/// mempool receiver as well as the watcher
+pub fn spawn_cancellation_sender(
+    mut cancellations: mpsc::UnboundedReceiver<PriceUpdateCancellation>,
+    vega_endpoint: &'static str,
+) {
+    tokio::spawn(async move {
+        let context = zmq::Context::new();
+        let vega_socket = context.socket(zmq::PUSH).unwrap();
+        if let Err(e) = vega_socket.connect(vega_endpoint) {
+            error!("Failed to connect the cancellation sender to Vega: {e}");
+            return;
+        }
+        while let Some(cancellation) = cancellations.recv().await {
+            let trace_id = cancellation.trace_id.clone();
+            let serialized =
+                match bincode::serialize(&MessageBundle::PriceUpdateCancelled(cancellation)) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        error!("Failed to serialize cancellation of {trace_id}: {e}");
+                        continue;
+                    }
+                };
+            if let Err(e) = vega_socket.send(&serialized, 0) {
+                error!("Failed to send cancellation of {trace_id} to Vega: {e}");
+            }
+        }
+    });
+}
@@ -0,0 +1,191 @@
+use alloy::{
+    eips::BlockId,
+    primitives::U256,
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+};
+use overlord_shared::{
+    sol_bindings::AccessControlledOCR2Aggregator, status::Counter, NewPrice, ReportSequence,
+};
+use std::sync::Arc;
+use tokio::{
+    sync::mpsc,
+    time::{interval, Duration},
+};
+use tracing::{error, info, warn};
+
+// How often the auditor looks for a new head
+const HEAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Updates are audited on the state this many blocks after their expected inclusion block
+const AUDIT_DELAY_BLOCKS: u64 = 1;
+
+/// A price oops decoded from a pending transmit() and forwarded to vega
+pub struct CapturedPrice {
+    pub trace_id: String,
+    pub new_price: NewPrice,
+    pub expected_block: u64,
+}
+
+pub struct AuditCounters {
+    /// The report landed and its answer is the decoded price
+    pub validated: &'static Counter,
+    /// The report landed with a different answer than the decoded one, i.e. a decoding bug
+    pub mismatched: &'static Counter,
+    /// The report can't be told apart from a later one, or didn't land (yet)
+    pub unverified: &'static Counter,
+}
+
+enum AuditResult {
+    Validated,
+    Mismatched(U256),
+    Unverified(String),
+}
+
+/// Checks every captured price against what its aggregator reports AUDIT_DELAY_BLOCKS after the
+/// expected inclusion block, so a change in a feed's report format that breaks
+/// `get_price_from_input` shows up as mismatches instead of going unnoticed.
+///
+/// OCR2 reports are identified by their epoch and round (from `latestTransmissionDetails()`), so an
+/// update that didn't land, or was followed by another one in the same block, isn't taken for a
+/// mismatch. OCR3 aggregators don't expose the sequence number of their latest report, so only
+/// their answers are compared.
+pub fn spawn_price_auditor(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    counters: AuditCounters,
+) -> mpsc::UnboundedSender<CapturedPrice> {
+    let (sender, mut captured_prices) = mpsc::unbounded_channel::<CapturedPrice>();
+    tokio::spawn(async move {
+        let mut pending: Vec<CapturedPrice> = vec![];
+        let mut ticker = interval(HEAD_POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                Some(captured) = captured_prices.recv() => {
+                    // Without an inclusion block there's no block to audit on
+                    if captured.expected_block > 0 {
+                        pending.push(captured);
+                    }
+                }
+                _ = ticker.tick() => {
+                    if pending.is_empty() {
+                        continue;
+                    }
+                    let head = match provider.get_block_number().await {
+                        Ok(head) => head,
+                        Err(e) => {
+                            warn!("Failed to get the head to audit captured prices: {}", e);
+                            continue;
+                        }
+                    };
+                    let (due, not_due): (Vec<CapturedPrice>, Vec<CapturedPrice>) = pending
+                        .into_iter()
+                        .partition(|captured| head >= captured.expected_block + AUDIT_DELAY_BLOCKS);
+                    pending = not_due;
+                    for captured in due {
+                        audit(&provider, &captured, &counters).await;
+                    }
+                }
+            }
+        }
+    });
+    sender
+}
+
+async fn audit(
+    provider: &RootProvider<PubSubFrontend>,
+    captured: &CapturedPrice,
+    counters: &AuditCounters,
+) {
+    let aggregator = captured.new_price.chainlink_address;
+    let audit_block = BlockId::number(captured.expected_block + AUDIT_DELAY_BLOCKS);
+    let result = match audit_at(provider, &captured.new_price, audit_block).await {
+        Ok(result) => result,
+        Err(e) => {
+            warn!(
+                "Failed to audit price of {} for {}: {}",
+                captured.trace_id, aggregator, e
+            );
+            return;
+        }
+    };
+    match result {
+        AuditResult::Validated => {
+            counters.validated.inc();
+            info!(
+                message = "Captured price validated.",
+                trace_id = %captured.trace_id,
+                forward_to = %aggregator,
+                price = %captured.new_price.price,
+            );
+        }
+        AuditResult::Mismatched(answer) => {
+            counters.mismatched.inc();
+            error!(
+                message = "Captured price doesn't match the landed answer. Check the report format of the aggregator.",
+                trace_id = %captured.trace_id,
+                forward_to = %aggregator,
+                decoded_price = %captured.new_price.price,
+                landed_answer = %answer,
+                sequence = %captured.new_price.sequence,
+            );
+        }
+        AuditResult::Unverified(reason) => {
+            counters.unverified.inc();
+            info!(
+                message = "Captured price couldn't be verified.",
+                trace_id = %captured.trace_id,
+                forward_to = %aggregator,
+                reason = %reason,
+            );
+        }
+    }
+}
+
+async fn audit_at(
+    provider: &RootProvider<PubSubFrontend>,
+    new_price: &NewPrice,
+    block: BlockId,
+) -> Result<AuditResult, Box<dyn std::error::Error>> {
+    let aggregator =
+        AccessControlledOCR2Aggregator::new(new_price.chainlink_address, provider.clone());
+    let answer = aggregator
+        .latestRoundData()
+        .block(block)
+        .call()
+        .await?
+        .answer
+        .into_raw();
+    let landed_report = match new_price.sequence {
+        ReportSequence::EpochAndRound { .. } => match aggregator
+            .latestTransmissionDetails()
+            .block(block)
+            .call()
+            .await
+        {
+            Ok(details) => Some(ReportSequence::EpochAndRound {
+                epoch: details.epoch,
+                round: details.round,
+            }),
+            Err(e) => {
+                warn!(
+                    "Failed to get latestTransmissionDetails of {}, comparing answers only: {}",
+                    new_price.chainlink_address, e
+                );
+                None
+            }
+        },
+        // latestTransmissionDetails() only knows epochs and rounds
+        ReportSequence::SequenceNumber(_) => None,
+    };
+    Ok(match landed_report {
+        Some(landed) if landed != new_price.sequence => AuditResult::Unverified(format!(
+            "latest report is {}, not {}",
+            landed, new_price.sequence
+        )),
+        _ if answer == new_price.price => AuditResult::Validated,
+        Some(_) => AuditResult::Mismatched(answer),
+        None => AuditResult::Unverified(format!(
+            "latest answer is {}, which may belong to another report",
+            answer
+        )),
+    })
+}
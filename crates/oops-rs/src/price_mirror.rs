@@ -0,0 +1,237 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    primitives::{Address, U256},
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::Filter,
+    sol_types::SolEvent,
+};
+use futures_util::StreamExt;
+use overlord_shared::{
+    retry::{Backoff, RetryPolicy},
+    sol_bindings::AccessControlledOCR2Aggregator::{self, AnswerUpdated},
+    status::Gauge,
+    NewPrice,
+};
+use tokio::time::{interval, sleep, Duration};
+use tracing::{error, info, warn};
+
+const PRICE_MIRROR_ENV: &str = "OOPS_PRICE_MIRROR";
+const PRICE_MIRROR_MAX_AGE_ENV: &str = "OOPS_PRICE_MIRROR_MAX_AGE_SECS";
+// Longest heartbeat of the feeds Aave uses on mainnet (stablecoins update at least once a day)
+const PRICE_MIRROR_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+// How often answers are checked for staleness
+const STALENESS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Latest answer of an aggregator, as reported by its last landed transmit()
+#[derive(Clone, Copy, Debug)]
+pub struct MirroredAnswer {
+    pub answer: U256,
+    /// `updatedAt` of the round, in seconds
+    pub updated_at: u64,
+}
+
+/// Mirrors the latest answer of every watched aggregator from the AnswerUpdated events of landed
+/// transmit() calls, so pending updates can be compared with the current price (and feeds that
+/// stopped updating can be noticed) without calling the node.
+///
+/// Optional, enabled with PRICE_MIRROR_ENV. Without it, whatever needs an answer reads it from the
+/// node instead.
+pub struct PriceMirror {
+    watched: RwLock<HashSet<Address>>,
+    answers: RwLock<HashMap<Address, MirroredAnswer>>,
+    max_age_secs: u64,
+}
+
+impl PriceMirror {
+    pub fn from_env() -> Option<Arc<Self>> {
+        let enabled = std::env::var(PRICE_MIRROR_ENV)
+            .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        if !enabled {
+            info!("Price mirror disabled, latest answers are read from the node");
+            return None;
+        }
+        let max_age_secs = match std::env::var(PRICE_MIRROR_MAX_AGE_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                warn!(
+                    "Invalid {} value {}, using {}: {}",
+                    PRICE_MIRROR_MAX_AGE_ENV, value, PRICE_MIRROR_MAX_AGE_SECS, e
+                );
+                PRICE_MIRROR_MAX_AGE_SECS
+            }),
+            Err(_) => PRICE_MIRROR_MAX_AGE_SECS,
+        };
+        info!(
+            "Price mirror enabled, answers older than {}s are reported as stale",
+            max_age_secs
+        );
+        Some(Arc::new(Self {
+            watched: RwLock::new(HashSet::new()),
+            answers: RwLock::new(HashMap::new()),
+            max_age_secs,
+        }))
+    }
+
+    /// Starts mirroring `aggregators`, on top of the ones already watched. Answers of aggregators
+    /// that were never seeded are known once their next update lands.
+    pub fn watch(&self, aggregators: impl IntoIterator<Item = Address>) {
+        self.watched.write().unwrap().extend(aggregators);
+    }
+
+    pub fn latest(&self, aggregator: &Address) -> Option<MirroredAnswer> {
+        self.answers.read().unwrap().get(aggregator).copied()
+    }
+
+    /// How far `new_price` is from the mirrored answer of its aggregator, in basis points
+    pub fn deviation_bps(&self, new_price: &NewPrice) -> Option<U256> {
+        let latest = self.latest(&new_price.chainlink_address)?;
+        if latest.answer.is_zero() {
+            return None;
+        }
+        Some(new_price.price.abs_diff(latest.answer) * U256::from(10_000) / latest.answer)
+    }
+
+    /// Aggregators whose latest answer is older than the max age, with its age in seconds
+    pub fn stale_feeds(&self) -> Vec<(Address, u64)> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        self.answers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(aggregator, answer)| (*aggregator, now.saturating_sub(answer.updated_at)))
+            .filter(|(_, age)| *age > self.max_age_secs)
+            .collect()
+    }
+
+    /// Keeps the newest of the two answers, since events of a reorg or a resubscription can arrive
+    /// late
+    fn record(&self, aggregator: Address, answer: MirroredAnswer) {
+        let mut answers = self.answers.write().unwrap();
+        match answers.get(&aggregator) {
+            Some(current) if current.updated_at > answer.updated_at => (),
+            _ => {
+                answers.insert(aggregator, answer);
+            }
+        }
+    }
+
+    /// Watches `aggregators` and reads their current answer, so lookups work before their next
+    /// update lands
+    pub async fn seed(&self, provider: &RootProvider<PubSubFrontend>, aggregators: &[Address]) {
+        self.watch(aggregators.iter().cloned());
+        let mut seeded = 0;
+        for aggregator in aggregators {
+            match AccessControlledOCR2Aggregator::new(*aggregator, provider.clone())
+                .latestRoundData()
+                .call()
+                .await
+            {
+                Ok(round_data) if round_data.answer.is_positive() => {
+                    self.record(
+                        *aggregator,
+                        MirroredAnswer {
+                            answer: round_data.answer.into_raw(),
+                            updated_at: round_data.updatedAt.saturating_to(),
+                        },
+                    );
+                    seeded += 1;
+                }
+                Ok(_) => warn!(
+                    "Not mirroring non-positive answer of aggregator {}",
+                    aggregator
+                ),
+                Err(e) => warn!(
+                    "Failed to get latestRoundData from aggregator {}: {}",
+                    aggregator, e
+                ),
+            }
+        }
+        info!(
+            "Price mirror seeded with {}/{} aggregators",
+            seeded,
+            aggregators.len()
+        );
+    }
+}
+
+/// Follows the AnswerUpdated events of `aggregators` (resubscribing if the subscription ends) and
+/// reports stale answers through `stale_feeds`
+pub fn spawn_price_mirror(
+    mirror: Arc<PriceMirror>,
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    aggregators: Vec<Address>,
+    stale_feeds: &'static Gauge,
+) {
+    let staleness_mirror = mirror.clone();
+    tokio::spawn(async move {
+        let mut ticker = interval(STALENESS_CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let stale = staleness_mirror.stale_feeds();
+            for (aggregator, age) in stale.iter() {
+                warn!(
+                    "Aggregator {} hasn't updated its answer in {}s",
+                    aggregator, age
+                );
+            }
+            stale_feeds.set(stale.len() as u64);
+        }
+    });
+    tokio::spawn(async move {
+        mirror.seed(&provider, &aggregators).await;
+        // Filtered by address on our side, so aggregators watched after startup are mirrored without
+        // subscribing again
+        let filter = Filter::new().event_signature(AnswerUpdated::SIGNATURE_HASH);
+        let mut backoff = Backoff::new(RetryPolicy::RECONNECT);
+        loop {
+            let subscription = match provider.subscribe_logs(&filter).await {
+                Ok(subscription) => {
+                    backoff.reset();
+                    subscription
+                }
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    error!(
+                        "Failed to subscribe to AnswerUpdated events: {}. Retrying in {:?}...",
+                        e, delay
+                    );
+                    sleep(delay).await;
+                    continue;
+                }
+            };
+            let mut logs = subscription.into_stream();
+            while let Some(log) = logs.next().await {
+                let aggregator = log.inner.address;
+                if !mirror.watched.read().unwrap().contains(&aggregator) {
+                    continue;
+                }
+                let event = match log.log_decode::<AnswerUpdated>() {
+                    Ok(event) => event.inner.data,
+                    Err(e) => {
+                        warn!("Failed to decode AnswerUpdated of {}: {}", aggregator, e);
+                        continue;
+                    }
+                };
+                if !event.current.is_positive() {
+                    continue;
+                }
+                mirror.record(
+                    aggregator,
+                    MirroredAnswer {
+                        answer: event.current.into_raw(),
+                        updated_at: event.updatedAt.saturating_to(),
+                    },
+                );
+            }
+            warn!("AnswerUpdated subscription ended, subscribing again");
+        }
+    });
+}
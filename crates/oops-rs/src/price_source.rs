@@ -0,0 +1,183 @@
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use alloy::{
+    primitives::{Address, Bytes, B256, U256},
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+};
+use overlord_shared::{
+    chain_profile::ChainProfile, sol_bindings::AccessControlledOCR2Aggregator, status::Counter,
+    MessageBundle, PriceUpdateBundle, PriceUpdateTx,
+};
+use tracing::{error, info, warn};
+
+use crate::dispatch::SlotScheduler;
+
+/// A price published by an oracle family other than Chainlink OCR, ready to be routed to vega
+pub struct SourcedUpdate {
+    /// Identifies the update (e.g. the hash of its payload), the trace id is taken from it
+    pub id: B256,
+    /// Price source Aave reads the new price from, which vega maps to the affected reserves
+    pub forward_to: Address,
+    /// New price, in the decimals of `forward_to`
+    pub price: U256,
+    /// Contract the update lands on once someone pushes it on-chain
+    pub target: Address,
+    /// What `target` is called with to land the update
+    pub payload: Bytes,
+    /// Wei sent along with the call, for sources charging a fee to land updates
+    pub value: U256,
+}
+
+/// An oracle family oops can capture updates from, besides pending Chainlink transmit() calls.
+///
+/// Sources deal with their own connections and reconnects, and only hand over updates that moved
+/// the price enough to be worth evaluating. Routing them to vega is up to spawn_price_source().
+pub trait PriceSource: Send + 'static {
+    /// Used as the `source` of the bundles, and in logs
+    fn name(&self) -> &'static str;
+
+    /// Waits for the next update. None once the source can't produce any more.
+    fn next_update(&mut self) -> impl Future<Output = Option<SourcedUpdate>> + Send;
+}
+
+/// A feed of a non-Chainlink source, and the price source Aave reads it through
+#[derive(Clone, Copy, Debug)]
+pub struct StreamedFeed {
+    pub aggregator: Address,
+    pub decimals: u8,
+}
+
+/// Parses a comma-separated list of `feed_id=aggregator` pairs, as the sources are configured
+pub fn parse_feed_pairs(value: &str, env_name: &str) -> HashMap<B256, Address> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let parsed = pair.split_once('=').and_then(|(feed_id, aggregator)| {
+                Some((
+                    feed_id.trim().parse().ok()?,
+                    aggregator.trim().parse().ok()?,
+                ))
+            });
+            if parsed.is_none() {
+                warn!("Ignoring invalid pair {} in {}", pair, env_name);
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// Reads the decimals of the aggregator of every feed. Feeds whose aggregator can't be read are
+/// left out.
+pub async fn get_streamed_feeds(
+    provider: &RootProvider<PubSubFrontend>,
+    aggregators: &HashMap<B256, Address>,
+) -> HashMap<B256, StreamedFeed> {
+    let mut feeds = HashMap::new();
+    for (feed_id, aggregator) in aggregators {
+        match AccessControlledOCR2Aggregator::new(*aggregator, provider.clone())
+            .decimals()
+            .call()
+            .await
+        {
+            Ok(response) => {
+                feeds.insert(
+                    *feed_id,
+                    StreamedFeed {
+                        aggregator: *aggregator,
+                        decimals: response._0,
+                    },
+                );
+            }
+            Err(e) => warn!(
+                "Failed to get decimals of aggregator {}, not following feed {}: {}",
+                aggregator, feed_id, e
+            ),
+        }
+    }
+    feeds
+}
+
+/// Sends every update of `source` to vega as a PriceUpdateBundle for its `forward_to`, with the
+/// payload as `tx_input`.
+///
+/// There's no pending tx to backrun, so the bundles are `PriceUpdateTx::Payload` with the call
+/// that lands the update, which profito puts ahead of the liquidation. They're never held: the
+/// update lands whenever someone pushes it, not at a slot boundary.
+pub fn spawn_price_source<S: PriceSource>(
+    mut source: S,
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    chain_profile: ChainProfile,
+    slot_scheduler: SlotScheduler,
+    vega_endpoint: &'static str,
+    updates_forwarded: &'static Counter,
+) {
+    tokio::spawn(async move {
+        let context = zmq::Context::new();
+        let vega_socket = context.socket(zmq::PUSH).unwrap();
+        if let Err(e) = vega_socket.connect(vega_endpoint) {
+            error!(
+                "Failed to connect the {} source to Vega: {e}",
+                source.name()
+            );
+            return;
+        }
+        while let Some(update) = source.next_update().await {
+            let expected_block = match chain_profile.estimate_inclusion_block(&provider).await {
+                Ok(block) => block,
+                Err(e) => {
+                    warn!("Failed to estimate {} inclusion block: {e}", source.name());
+                    u64::MIN
+                }
+            };
+            let dispatch_plan = slot_scheduler.plan(&chain_profile, expected_block);
+            let bundle = PriceUpdateBundle {
+                trace_id: format!("{:?}", update.id)[2..10].to_string(),
+                tx_hash: format!("{:?}", update.id),
+                price_update_tx: PriceUpdateTx::Payload {
+                    to: update.target,
+                    input: update.payload.clone(),
+                    value: update.value,
+                },
+                inclusion_block: dispatch_plan.inclusion_block.to_string(),
+                tx_new_price: update.price,
+                forward_to: update.forward_to,
+                tx_from: Address::ZERO,
+                tx_to: update.target,
+                tx_input: update.payload,
+                source: source.name().to_string(),
+                slot_deadline_ms: dispatch_plan.deadline_ms,
+            };
+            let trace_id = bundle.trace_id.clone();
+            let serialized = match bincode::serialize(&MessageBundle::PriceUpdate(bundle)) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    error!(
+                        "Failed to serialize {} update {trace_id}: {e}",
+                        source.name()
+                    );
+                    continue;
+                }
+            };
+            if let Err(e) = vega_socket.send(&serialized, 0) {
+                error!(
+                    "Failed to send {} update {trace_id} to Vega: {e}",
+                    source.name()
+                );
+                continue;
+            }
+            updates_forwarded.inc();
+            info!(
+                message = "Sourced update sent.",
+                source = source.name(),
+                trace_id = %trace_id,
+                forward_to = %update.forward_to,
+                price = %update.price,
+                expected_block = %dispatch_plan.inclusion_block,
+            );
+        }
+        warn!("{} source stopped producing updates", source.name());
+    });
+}
@@ -0,0 +1,292 @@
+use std::{collections::HashMap, sync::Arc, time::Instant};
+
+use alloy::{
+    primitives::{address, keccak256, Address, Bytes, B256, U256},
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+    sol,
+    sol_types::SolCall,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures_util::{SinkExt, StreamExt};
+use overlord_shared::retry::{Backoff, RetryPolicy};
+use serde::Deserialize;
+use tokio::{net::TcpStream, time::sleep};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tracing::{error, info, warn};
+
+use crate::price_source::{
+    get_streamed_feeds, parse_feed_pairs, PriceSource, SourcedUpdate, StreamedFeed,
+};
+
+const PYTH_HERMES_URL_ENV: &str = "OOPS_PYTH_HERMES_URL";
+const PYTH_FEEDS_ENV: &str = "OOPS_PYTH_FEEDS";
+const PYTH_MIN_CHANGE_BPS_ENV: &str = "OOPS_PYTH_MIN_CHANGE_BPS";
+const PYTH_HERMES_URL: &str = "wss://hermes.pyth.network/ws";
+const PYTH_SOURCE: &str = "pyth";
+// Pyth contract updates are pushed to on Ethereum mainnet
+const PYTH_CONTRACT: Address = address!("4305FB66699C3B2702D4d05CF36551390A4c69C6");
+// Hermes publishes every 400ms, most of the time with about the same price
+const PYTH_MIN_CHANGE_BPS: u64 = 5;
+
+sol! {
+    #[sol(rpc)]
+    interface IPyth {
+        function updatePriceFeeds(bytes[] calldata updateData) external payable;
+        function getUpdateFee(bytes[] calldata updateData)
+            external
+            view
+            returns (uint256 feeAmount);
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum HermesMessage {
+    PriceUpdate {
+        price_feed: HermesPriceFeed,
+    },
+    Response {
+        status: String,
+        error: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+struct HermesPriceFeed {
+    id: B256,
+    price: HermesPrice,
+    vaa: String,
+}
+
+#[derive(Deserialize)]
+struct HermesPrice {
+    price: String,
+    expo: i32,
+}
+
+pub struct PythConfig {
+    url: String,
+    /// Aggregator of every followed price feed, by Pyth price id
+    aggregators: HashMap<B256, Address>,
+    min_change_bps: u64,
+}
+
+impl PythConfig {
+    /// None unless PYTH_FEEDS_ENV (a comma-separated list of `price_id=aggregator` pairs) is set
+    pub fn from_env() -> Option<Self> {
+        let aggregators = parse_feed_pairs(
+            &std::env::var(PYTH_FEEDS_ENV).unwrap_or_default(),
+            PYTH_FEEDS_ENV,
+        );
+        if aggregators.is_empty() {
+            info!("No Pyth feeds set, not following any");
+            return None;
+        }
+        let min_change_bps = match std::env::var(PYTH_MIN_CHANGE_BPS_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                warn!(
+                    "Invalid {} value {}, using {}: {}",
+                    PYTH_MIN_CHANGE_BPS_ENV, value, PYTH_MIN_CHANGE_BPS, e
+                );
+                PYTH_MIN_CHANGE_BPS
+            }),
+            Err(_) => PYTH_MIN_CHANGE_BPS,
+        };
+        Some(Self {
+            url: std::env::var(PYTH_HERMES_URL_ENV).unwrap_or(PYTH_HERMES_URL.to_string()),
+            aggregators,
+            min_change_bps,
+        })
+    }
+}
+
+/// Converts a Pyth price (`price * 10^expo`) to the decimals of the aggregator it's read through
+fn scale_price(price: &HermesPrice, decimals: u8) -> Option<U256> {
+    let value = U256::from(price.price.parse::<u64>().ok().filter(|value| *value > 0)?);
+    let shift = decimals as i32 + price.expo;
+    let factor = U256::from(10).pow(U256::from(shift.unsigned_abs()));
+    Some(if shift >= 0 {
+        value * factor
+    } else {
+        value / factor
+    })
+}
+
+/// Pyth pull oracle, followed through the Hermes price service. Its updates only land on-chain
+/// when someone calls updatePriceFeeds() with them, so they're forwarded as soon as Hermes
+/// publishes them, with that call as the payload.
+pub struct PythSource {
+    config: PythConfig,
+    feeds: HashMap<B256, StreamedFeed>,
+    stream: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    connected_at: Instant,
+    backoff: Backoff,
+    last_forwarded: HashMap<B256, U256>,
+    provider: Arc<RootProvider<PubSubFrontend>>,
+}
+
+impl PythSource {
+    /// None if none of the aggregators of the feeds can be read
+    pub async fn new(
+        config: PythConfig,
+        provider: Arc<RootProvider<PubSubFrontend>>,
+    ) -> Option<Self> {
+        let feeds = get_streamed_feeds(&provider, &config.aggregators).await;
+        if feeds.is_empty() {
+            error!("None of the Pyth feeds can be followed");
+            return None;
+        }
+        info!("Following {} Pyth feeds", feeds.len());
+        Some(Self {
+            config,
+            feeds,
+            stream: None,
+            connected_at: Instant::now(),
+            backoff: Backoff::new(RetryPolicy::RECONNECT),
+            last_forwarded: HashMap::new(),
+            provider,
+        })
+    }
+
+    async fn connect(
+        &self,
+    ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Box<dyn std::error::Error + Send + Sync>>
+    {
+        let (mut stream, _) = connect_async(self.config.url.as_str()).await?;
+        let subscribe = serde_json::json!({
+            "type": "subscribe",
+            "ids": self.feeds.keys().collect::<Vec<&B256>>(),
+            "binary": true,
+        });
+        stream.send(Message::Text(subscribe.to_string())).await?;
+        Ok(stream)
+    }
+
+    /// Turns a published price into an update, if it moved enough since the last one forwarded.
+    /// The update carries the fee the Pyth contract charges to land it, read from the contract.
+    async fn to_update(&mut self, price_feed: HermesPriceFeed) -> Option<SourcedUpdate> {
+        let feed = *self.feeds.get(&price_feed.id)?;
+        let Some(price) = scale_price(&price_feed.price, feed.decimals) else {
+            warn!(
+                "Invalid price {} for Pyth feed {}",
+                price_feed.price.price, price_feed.id
+            );
+            return None;
+        };
+        if let Some(last_price) = self.last_forwarded.get(&price_feed.id) {
+            if last_price.is_zero()
+                || price.abs_diff(*last_price) * U256::from(10_000) / *last_price
+                    < U256::from(self.config.min_change_bps)
+            {
+                return None;
+            }
+        }
+        let vaa = match BASE64.decode(&price_feed.vaa) {
+            Ok(vaa) => vaa,
+            Err(e) => {
+                warn!(
+                    "Failed to decode update of Pyth feed {}: {}",
+                    price_feed.id, e
+                );
+                return None;
+            }
+        };
+        let update_data = vec![Bytes::from(vaa)];
+        let fee = match IPyth::new(PYTH_CONTRACT, self.provider.clone())
+            .getUpdateFee(update_data.clone())
+            .call()
+            .await
+        {
+            Ok(response) => response.feeAmount,
+            Err(e) => {
+                warn!(
+                    "Failed to get the update fee of Pyth feed {}: {}",
+                    price_feed.id, e
+                );
+                return None;
+            }
+        };
+        let payload = IPyth::updatePriceFeedsCall {
+            updateData: update_data,
+        }
+        .abi_encode();
+        self.last_forwarded.insert(price_feed.id, price);
+        Some(SourcedUpdate {
+            id: keccak256(&payload),
+            forward_to: feed.aggregator,
+            price,
+            target: PYTH_CONTRACT,
+            payload: payload.into(),
+            value: fee,
+        })
+    }
+}
+
+impl PriceSource for PythSource {
+    fn name(&self) -> &'static str {
+        PYTH_SOURCE
+    }
+
+    async fn next_update(&mut self) -> Option<SourcedUpdate> {
+        loop {
+            let Some(stream) = self.stream.as_mut() else {
+                match self.connect().await {
+                    Ok(stream) => {
+                        info!("Connected to Pyth Hermes at {}", self.config.url);
+                        self.stream = Some(stream);
+                        self.connected_at = Instant::now();
+                    }
+                    Err(e) => {
+                        let delay = self.backoff.next_delay();
+                        error!(
+                            "Failed to connect to Pyth Hermes: {}. Retrying in {:?}...",
+                            e, delay
+                        );
+                        sleep(delay).await;
+                    }
+                }
+                continue;
+            };
+            let text = match stream.next().await {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(_))) | None => {
+                    self.stream = None;
+                    self.backoff.connection_lasted(self.connected_at.elapsed());
+                    let delay = self.backoff.next_delay();
+                    warn!(
+                        "Pyth Hermes connection closed. Reconnecting in {:?}...",
+                        delay
+                    );
+                    sleep(delay).await;
+                    continue;
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    warn!("Pyth Hermes connection failed: {}", e);
+                    self.stream = None;
+                    self.backoff.connection_lasted(self.connected_at.elapsed());
+                    self.backoff.wait().await;
+                    continue;
+                }
+            };
+            match serde_json::from_str::<HermesMessage>(&text) {
+                Ok(HermesMessage::PriceUpdate { price_feed }) => {
+                    if let Some(update) = self.to_update(price_feed).await {
+                        return Some(update);
+                    }
+                }
+                Ok(HermesMessage::Response { status, error }) => {
+                    if status != "success" {
+                        error!(
+                            "Pyth Hermes rejected the subscription: {}",
+                            error.unwrap_or(status)
+                        );
+                        return None;
+                    }
+                }
+                Err(e) => warn!("Failed to parse Pyth Hermes message: {}", e),
+            }
+        }
+    }
+}
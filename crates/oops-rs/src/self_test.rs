@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+
+use alloy::{
+    primitives::{keccak256, Address, Bytes, U256},
+    sol_types::SolCall,
+};
+use overlord_shared::{
+    resolvers::{resolve_aggregator, AdapterGetter, AdapterReader},
+    MessageBundle, PriceRangeEstimate, PriceUpdateBundle, PriceUpdateHintBundle, PriceUpdateTx,
+    ReportSequence,
+};
+use serde::Deserialize;
+
+use crate::sol_bindings::{forwardCall, transmitCall, transmitSecondaryCall};
+use crate::{get_price_from_input, is_transmit_secondary, ReportFormat};
+
+// Bundled with the binary, so the self test runs the same wherever it's deployed
+const FIXTURES: &str = include_str!("../fixtures/self_test.json");
+
+// Selectors the processor matches on raw bytes instead of going through the bindings
+const FORWARD_SELECTOR: [u8; 4] = [0x6f, 0xad, 0xcf, 0x72];
+const TRANSMIT_SELECTOR: [u8; 4] = [0xb1, 0xdc, 0x65, 0xa4];
+const TRANSMIT_SECONDARY_SELECTOR: [u8; 4] = [0xba, 0x0c, 0xb2, 0x9e];
+
+#[derive(Deserialize)]
+struct Fixtures {
+    decode: Vec<DecodeFixture>,
+    rpc_responses: Vec<RecordedResponse>,
+    resolution: Vec<ResolutionFixture>,
+}
+
+#[derive(Deserialize)]
+struct DecodeFixture {
+    description: String,
+    calldata: Bytes,
+    transmit_secondary: bool,
+    /// Format of the aggregator the calldata is forwarded to. OCR2 if missing
+    #[serde(default)]
+    report_format: ReportFormat,
+    /// None if decoding is expected to fail
+    expected: Option<ExpectedPrice>,
+}
+
+#[derive(Deserialize)]
+struct ExpectedPrice {
+    /// Decimal string, in the feed's units
+    price: String,
+    chainlink_address: Address,
+    /// Checked when present, for OCR3 reports
+    #[serde(default)]
+    sequence_number: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RecordedResponse {
+    adapter: Address,
+    getter: AdapterGetter,
+    returns: Address,
+}
+
+#[derive(Deserialize)]
+struct ResolutionFixture {
+    description: String,
+    oracle: Address,
+    /// None if the oracle is expected to be rejected
+    expected: Option<Address>,
+}
+
+/// Replays the adapter calls recorded in the fixtures. Calls that weren't recorded fail, like
+/// they would against a node.
+struct RecordedAdapterReader(HashMap<(Address, AdapterGetter), Address>);
+
+impl AdapterReader for RecordedAdapterReader {
+    async fn read(&self, adapter: Address, getter: AdapterGetter) -> Option<Address> {
+        self.0.get(&(adapter, getter)).copied()
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    passed: usize,
+    failed: usize,
+}
+
+impl Summary {
+    fn check(&mut self, section: &str, description: &str, result: Result<(), String>) {
+        match result {
+            Ok(()) => {
+                self.passed += 1;
+                println!("PASS | {} | {}", section, description);
+            }
+            Err(reason) => {
+                self.failed += 1;
+                println!("FAIL | {} | {} | {}", section, description, reason);
+            }
+        }
+    }
+}
+
+/// Runs every fixture through the same functions the processor uses, printing one line per
+/// check and a summary. Returns whether all of them passed.
+pub async fn run() -> bool {
+    let fixtures: Fixtures = match serde_json::from_str(FIXTURES) {
+        Ok(fixtures) => fixtures,
+        Err(e) => {
+            println!("FAIL | fixtures | Failed to parse bundled fixtures: {}", e);
+            return false;
+        }
+    };
+    let mut summary = Summary::default();
+
+    check_selectors(&mut summary);
+    let decoded_bundles = check_decoding(&mut summary, &fixtures.decode);
+    check_resolution(&mut summary, &fixtures).await;
+    check_serialization(&mut summary, decoded_bundles);
+
+    println!(
+        "Self test finished: {} passed, {} failed",
+        summary.passed, summary.failed
+    );
+    summary.failed == 0
+}
+
+fn check_selectors(summary: &mut Summary) {
+    for (name, expected, actual) in [
+        ("forward", FORWARD_SELECTOR, forwardCall::SELECTOR),
+        ("transmit", TRANSMIT_SELECTOR, transmitCall::SELECTOR),
+        (
+            "transmitSecondary",
+            TRANSMIT_SECONDARY_SELECTOR,
+            transmitSecondaryCall::SELECTOR,
+        ),
+    ] {
+        let result = if expected == actual {
+            Ok(())
+        } else {
+            Err(format!(
+                "hardcoded selector 0x{} doesn't match the binding's 0x{}",
+                hex::encode(expected),
+                hex::encode(actual)
+            ))
+        };
+        summary.check("selectors", name, result);
+    }
+}
+
+/// Returns a bundle built from each successfully decoded fixture, like the processor would
+fn check_decoding(summary: &mut Summary, fixtures: &[DecodeFixture]) -> Vec<PriceUpdateBundle> {
+    let mut bundles = vec![];
+    for fixture in fixtures {
+        let report_formats = match &fixture.expected {
+            Some(expected) => HashMap::from([(expected.chainlink_address, fixture.report_format)]),
+            None => HashMap::new(),
+        };
+        let decoded =
+            get_price_from_input(&fixture.calldata, &report_formats).map_err(|e| e.to_string());
+        let result = match (&fixture.expected, decoded) {
+            (None, Err(_)) => Ok(()),
+            (None, Ok(new_price)) => Err(format!(
+                "expected a decoding error, got price {} for {}",
+                new_price.price, new_price.chainlink_address
+            )),
+            (Some(_), Err(e)) => Err(format!("failed to decode: {}", e)),
+            (Some(expected), Ok(new_price)) => match U256::from_str_radix(&expected.price, 10) {
+                Err(e) => Err(format!("invalid expected price {}: {}", expected.price, e)),
+                Ok(expected_price) if new_price.price != expected_price => Err(format!(
+                    "price is {}, expected {}",
+                    new_price.price, expected_price
+                )),
+                Ok(_) if new_price.chainlink_address != expected.chainlink_address => Err(format!(
+                    "forwarded to {}, expected {}",
+                    new_price.chainlink_address, expected.chainlink_address
+                )),
+                Ok(_)
+                    if expected.sequence_number.is_some_and(|sequence_number| {
+                        new_price.sequence != ReportSequence::SequenceNumber(sequence_number)
+                    }) =>
+                {
+                    Err(format!("report has {}", new_price.sequence))
+                }
+                Ok(_)
+                    if is_transmit_secondary(Some(fixture.calldata.clone()))
+                        != fixture.transmit_secondary =>
+                {
+                    Err(format!(
+                        "transmitSecondary detection returned {}",
+                        !fixture.transmit_secondary
+                    ))
+                }
+                Ok(_) => {
+                    bundles.push(PriceUpdateBundle {
+                        trace_id: format!("selftest{}", bundles.len()),
+                        tx_hash: format!("{:?}", keccak256(&fixture.calldata)),
+                        price_update_tx: PriceUpdateTx::HashOnly(keccak256(&fixture.calldata)),
+                        inclusion_block: "0".to_string(),
+                        tx_new_price: new_price.price,
+                        forward_to: new_price.chainlink_address,
+                        tx_from: Address::ZERO,
+                        tx_to: Address::ZERO,
+                        tx_input: fixture.calldata.clone(),
+                        source: "selftest".to_string(),
+                        slot_deadline_ms: None,
+                    });
+                    Ok(())
+                }
+            },
+        };
+        summary.check("decode", &fixture.description, result);
+    }
+    bundles
+}
+
+async fn check_resolution(summary: &mut Summary, fixtures: &Fixtures) {
+    let reader = RecordedAdapterReader(
+        fixtures
+            .rpc_responses
+            .iter()
+            .map(|response| ((response.adapter, response.getter), response.returns))
+            .collect(),
+    );
+    for fixture in &fixtures.resolution {
+        let resolved = resolve_aggregator(&reader, fixture.oracle)
+            .await
+            .map_err(|e| e.to_string());
+        let result = match (fixture.expected, resolved) {
+            (None, Err(_)) => Ok(()),
+            (None, Ok(aggregator)) => Err(format!("expected a rejection, got {}", aggregator)),
+            (Some(_), Err(e)) => Err(format!("failed to resolve: {}", e)),
+            (Some(expected), Ok(aggregator)) if aggregator == expected => Ok(()),
+            (Some(expected), Ok(aggregator)) => {
+                Err(format!("resolved to {}, expected {}", aggregator, expected))
+            }
+        };
+        summary.check("resolution", &fixture.description, result);
+    }
+}
+
+/// Round-trips every bundle through bincode, the way it travels to vega
+fn check_serialization(summary: &mut Summary, bundles: Vec<PriceUpdateBundle>) {
+    for bundle in bundles {
+        let description = format!("price update {}", bundle.trace_id);
+        let hint = PriceUpdateHintBundle {
+            trace_id: bundle.trace_id.clone(),
+            tx_hash: bundle.tx_hash.clone(),
+            inclusion_block: bundle.inclusion_block.clone(),
+            tx_to: bundle.tx_to,
+            price_ranges: vec![PriceRangeEstimate {
+                chainlink_address: bundle.forward_to,
+                latest_answer: bundle.tx_new_price,
+                low: bundle.tx_new_price,
+                high: bundle.tx_new_price,
+            }],
+            source: bundle.source.clone(),
+        };
+        let result =
+            round_trip(&MessageBundle::PriceUpdate(bundle.clone())).and_then(
+                |message| match message {
+                    MessageBundle::PriceUpdate(decoded)
+                        if decoded.tx_new_price == bundle.tx_new_price
+                            && decoded.forward_to == bundle.forward_to
+                            && decoded.tx_input == bundle.tx_input =>
+                    {
+                        Ok(())
+                    }
+                    other => Err(format!("deserialized into a different bundle: {:?}", other)),
+                },
+            );
+        summary.check("serialization", &description, result);
+
+        let description = format!("price update hint {}", hint.trace_id);
+        let result =
+            round_trip(&MessageBundle::PriceUpdateHint(hint.clone())).and_then(|message| {
+                match message {
+                    MessageBundle::PriceUpdateHint(decoded)
+                        if decoded.price_ranges.len() == 1
+                            && decoded.price_ranges[0].chainlink_address
+                                == hint.price_ranges[0].chainlink_address
+                            && decoded.price_ranges[0].high == hint.price_ranges[0].high =>
+                    {
+                        Ok(())
+                    }
+                    other => Err(format!("deserialized into a different hint: {:?}", other)),
+                }
+            });
+        summary.check("serialization", &description, result);
+    }
+}
+
+fn round_trip(message: &MessageBundle) -> Result<MessageBundle, String> {
+    let serialized = bincode::serialize(message).map_err(|e| format!("serialize: {}", e))?;
+    bincode::deserialize(&serialized).map_err(|e| format!("deserialize: {}", e))
+}
@@ -0,0 +1,117 @@
+use alloy::{
+    primitives::Address,
+    providers::{Provider, RootProvider},
+    pubsub::{PubSubFrontend, Subscription},
+    rpc::types::Transaction,
+};
+use std::error::Error;
+use tracing::{info, warn};
+
+const SENDER_FILTER_ENV: &str = "OOPS_SENDER_FILTER";
+// Subscription that takes a list of senders. Nodes that don't know it reject the eth_subscribe.
+const FILTERED_SUBSCRIPTION_KIND: &str = "alchemy_pendingTransactions";
+// With 4 hashes, this keeps false positives around 0.02%
+const BITS_PER_SENDER: usize = 32;
+const HASHES: usize = 4;
+
+/// Bloom filter over the authorized senders, so mempool txs from anyone else are dropped before
+/// they reach the queue. Addresses are already uniformly distributed, so their own bytes are used
+/// as the hashes. False positives just go through the usual checks, and there are no false
+/// negatives.
+pub struct SenderFilter {
+    bits: Vec<u64>,
+    mask: usize,
+}
+
+impl SenderFilter {
+    pub fn new(senders: &[Address]) -> Self {
+        let bit_count = (senders.len() * BITS_PER_SENDER)
+            .next_power_of_two()
+            .max(64);
+        let mut filter = Self {
+            bits: vec![0; bit_count / 64],
+            mask: bit_count - 1,
+        };
+        for sender in senders {
+            for bit in bit_indexes(filter.mask, sender) {
+                filter.bits[bit / 64] |= 1u64 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    pub fn may_contain(&self, address: &Address) -> bool {
+        bit_indexes(self.mask, address).all(|bit| self.bits[bit / 64] & (1u64 << (bit % 64)) != 0)
+    }
+}
+
+fn bit_indexes(mask: usize, address: &Address) -> impl Iterator<Item = usize> + '_ {
+    (0..HASHES).map(move |i| {
+        let chunk = [
+            address[i * 4],
+            address[i * 4 + 1],
+            address[i * 4 + 2],
+            address[i * 4 + 3],
+        ];
+        u32::from_be_bytes(chunk) as usize & mask
+    })
+}
+
+/// Where pending txs from unknown senders are filtered out
+pub enum SenderFiltering {
+    /// The node only sends the txs of the authorized senders
+    Node,
+    /// The node sends every pending tx, and they're checked against the filter
+    InProcess(SenderFilter),
+}
+
+impl SenderFiltering {
+    pub fn allows(&self, sender: &Address) -> bool {
+        match self {
+            SenderFiltering::Node => true,
+            SenderFiltering::InProcess(filter) => filter.may_contain(sender),
+        }
+    }
+}
+
+/// Whether pending txs should be filtered by sender, set with SENDER_FILTER_ENV. Off by default,
+/// since it hides transmit() calls from senders we don't track.
+pub fn sender_filter_enabled() -> bool {
+    std::env::var(SENDER_FILTER_ENV)
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Subscribes to the pending txs of `senders` only, if the node supports filtering them by
+/// sender. Otherwise falls back to every pending tx, and a filter to drop the ones of other
+/// senders with.
+pub async fn subscribe_pending_txs_from(
+    provider: &RootProvider<PubSubFrontend>,
+    senders: &[Address],
+) -> Result<(Subscription<Transaction>, SenderFiltering), Box<dyn Error>> {
+    let params = serde_json::json!({
+        "fromAddress": senders,
+        "hashesOnly": false,
+    });
+    match provider
+        .subscribe::<_, Transaction>((FILTERED_SUBSCRIPTION_KIND, params))
+        .await
+    {
+        Ok(subscription) => {
+            info!(
+                "Node filters pending txs by sender, subscribed to the txs of {} senders",
+                senders.len()
+            );
+            return Ok((subscription, SenderFiltering::Node));
+        }
+        Err(e) => warn!(
+            "Node can't filter pending txs by sender, filtering them in process: {}",
+            e
+        ),
+    }
+    let subscription = provider.subscribe_full_pending_transactions().await?;
+    Ok((
+        subscription,
+        SenderFiltering::InProcess(SenderFilter::new(senders)),
+    ))
+}
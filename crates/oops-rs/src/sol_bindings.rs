@@ -32,4 +32,10 @@ sol!(
         function transmitters() external view returns (address[] memory);
         function getTransmitters() external view returns (address[] memory);
     }
+
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    contract TypeAndVersionInterface {
+        function typeAndVersion() external pure returns (string memory);
+    }
 );
@@ -0,0 +1,135 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::watch;
+
+/// Overflow accounting of a PendingTxQueue. It's published through a watch channel, which is
+/// also what wakes up the consumer when something is pushed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueStats {
+    pub enqueued: u64,
+    pub priority_enqueued: u64,
+    /// Regular items evicted to make room for newer ones
+    pub dropped: u64,
+    /// Priority items pushed while the priority lane was already at its capacity. They're kept
+    /// anyway, so this only means the processor is falling behind.
+    pub priority_overflows: u64,
+    pub closed: bool,
+}
+
+struct Lanes<T> {
+    priority: VecDeque<T>,
+    regular: VecDeque<T>,
+}
+
+/// Bounded multi-producer, single-consumer queue with two lanes. Items in the priority lane are
+/// always consumed first and are never evicted: its capacity is a soft limit that only counts
+/// overflows. When the regular lane is full, its oldest item is evicted and returned to the
+/// producer, so it can tell what was lost.
+pub struct PendingTxQueue<T> {
+    lanes: Mutex<Lanes<T>>,
+    capacity: usize,
+    priority_capacity: usize,
+    signal: watch::Sender<QueueStats>,
+}
+
+impl<T> PendingTxQueue<T> {
+    pub fn new(capacity: usize, priority_capacity: usize) -> Arc<Self> {
+        let (signal, _) = watch::channel(QueueStats::default());
+        Arc::new(Self {
+            lanes: Mutex::new(Lanes {
+                priority: VecDeque::with_capacity(priority_capacity),
+                regular: VecDeque::with_capacity(capacity),
+            }),
+            capacity,
+            priority_capacity,
+            signal,
+        })
+    }
+
+    /// Enqueues `item` and returns the item evicted to make room for it, if any. Priority items
+    /// never evict anything.
+    pub fn push(&self, item: T, priority: bool) -> Option<T> {
+        let (evicted, overflowed) = {
+            let mut lanes = self.lanes.lock().unwrap();
+            if priority {
+                lanes.priority.push_back(item);
+                (None, lanes.priority.len() > self.priority_capacity)
+            } else {
+                lanes.regular.push_back(item);
+                if lanes.regular.len() > self.capacity {
+                    (lanes.regular.pop_front(), false)
+                } else {
+                    (None, false)
+                }
+            }
+        };
+        self.signal.send_modify(|stats| {
+            if priority {
+                stats.priority_enqueued += 1;
+                stats.priority_overflows += overflowed as u64;
+            } else {
+                stats.enqueued += 1;
+                stats.dropped += evicted.is_some() as u64;
+            }
+        });
+        evicted
+    }
+
+    /// Items waiting in the (priority, regular) lanes
+    pub fn depth(&self) -> (usize, usize) {
+        let lanes = self.lanes.lock().unwrap();
+        (lanes.priority.len(), lanes.regular.len())
+    }
+
+    /// Makes the receiver return None once the queue is drained
+    pub fn close(&self) {
+        self.signal.send_modify(|stats| stats.closed = true);
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        *self.signal.borrow()
+    }
+
+    pub fn receiver(self: &Arc<Self>) -> PendingTxReceiver<T> {
+        PendingTxReceiver {
+            queue: self.clone(),
+            signal: self.signal.subscribe(),
+        }
+    }
+}
+
+pub struct PendingTxReceiver<T> {
+    queue: Arc<PendingTxQueue<T>>,
+    signal: watch::Receiver<QueueStats>,
+}
+
+impl<T> PendingTxReceiver<T> {
+    pub fn depth(&self) -> (usize, usize) {
+        self.queue.depth()
+    }
+
+    /// Waits for the next item, priority lane first. Returns None once the queue has been closed
+    /// and everything in it was consumed.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            // Mark the current version as seen before looking at the lanes, so a push that lands
+            // right after we find them empty still wakes us up
+            let closed = self.signal.borrow_and_update().closed;
+            {
+                let mut lanes = self.queue.lanes.lock().unwrap();
+                if let Some(item) = lanes.priority.pop_front() {
+                    return Some(item);
+                }
+                if let Some(item) = lanes.regular.pop_front() {
+                    return Some(item);
+                }
+            }
+            if closed || self.signal.changed().await.is_err() {
+                return None;
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use alloy::primitives::Address;
+use once_cell::sync::Lazy;
+use std::{collections::HashSet, str::FromStr};
+use tracing::{info, warn};
+
+/// Comma-separated reserve (underlying) addresses. When set, every other asset is ignored.
+pub const ASSET_ALLOWLIST_ENV: &str = "OVERLORD_ASSET_ALLOWLIST";
+/// Comma-separated reserve (underlying) addresses that are always ignored
+pub const ASSET_DENYLIST_ENV: &str = "OVERLORD_ASSET_DENYLIST";
+
+pub static ASSET_FILTER: Lazy<AssetFilter> = Lazy::new(AssetFilter::from_env);
+
+/// Reserves the operator doesn't want to deal with at all (e.g. low-liquidity ones), read once
+/// at startup. Unlike the kill switch, which stops anything touching a blocked asset, ignored
+/// assets are just left out: oops doesn't follow their feeds, vega drops users that can only be
+/// liquidated through them, and profito never picks them as collateral or debt.
+pub struct AssetFilter {
+    allowed: Option<HashSet<Address>>,
+    denied: HashSet<Address>,
+}
+
+fn parse_assets(env_name: &str) -> Option<HashSet<Address>> {
+    let value = std::env::var(env_name).ok()?;
+    let assets = value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match Address::from_str(entry) {
+            Ok(asset) => Some(asset),
+            Err(e) => {
+                warn!("Ignoring invalid {} entry '{}': {}", env_name, entry, e);
+                None
+            }
+        })
+        .collect::<HashSet<Address>>();
+    Some(assets)
+}
+
+impl AssetFilter {
+    pub fn from_env() -> Self {
+        let allowed = parse_assets(ASSET_ALLOWLIST_ENV).filter(|allowed| !allowed.is_empty());
+        let denied = parse_assets(ASSET_DENYLIST_ENV).unwrap_or_default();
+        if let Some(allowed) = &allowed {
+            info!("Only these assets will be considered: {:?}", allowed);
+        }
+        if !denied.is_empty() {
+            info!("These assets will be ignored: {:?}", denied);
+        }
+        Self { allowed, denied }
+    }
+
+    /// False if neither list is set, in which case every asset is allowed
+    pub fn is_active(&self) -> bool {
+        self.allowed.is_some() || !self.denied.is_empty()
+    }
+
+    pub fn is_allowed(&self, asset: &Address) -> bool {
+        !self.denied.contains(asset)
+            && self
+                .allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(asset))
+    }
+}
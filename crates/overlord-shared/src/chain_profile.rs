@@ -0,0 +1,181 @@
+use alloy::{
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::{BlockId, BlockTransactionsKind},
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+type ChainProfileError = Box<dyn std::error::Error + Send + Sync>;
+
+// Assumed for chains whose cadence is unknown
+const FALLBACK_BLOCK_TIME: Duration = Duration::from_secs(12);
+
+/// How a chain produces blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockCadence {
+    /// Blocks can only be proposed at fixed slots counted from genesis. Missed slots don't take
+    /// a block number, so the next block is always the head + 1, whenever it lands.
+    FixedInterval {
+        genesis_timestamp: u64,
+        slot_millis: u64,
+    },
+    /// A sequencer orders txs as they arrive and seals blocks at roughly this interval. There's
+    /// no slot to aim for, and blocks keep coming while a tx is being processed.
+    Sequencer { target_block_millis: u64 },
+    /// Nothing is known about how this chain produces blocks
+    Unknown,
+}
+
+/// What overlord needs to know about a chain to time its submissions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChainProfile {
+    pub name: &'static str,
+    pub chain_id: u64,
+    pub cadence: BlockCadence,
+}
+
+pub const ETHEREUM_MAINNET: ChainProfile = ChainProfile {
+    name: "ethereum",
+    chain_id: 1,
+    cadence: BlockCadence::FixedInterval {
+        // Beacon chain genesis
+        genesis_timestamp: 1606824023,
+        slot_millis: 12_000,
+    },
+};
+
+pub const ARBITRUM_ONE: ChainProfile = ChainProfile {
+    name: "arbitrum",
+    chain_id: 42161,
+    cadence: BlockCadence::Sequencer {
+        target_block_millis: 250,
+    },
+};
+
+pub const BASE: ChainProfile = ChainProfile {
+    name: "base",
+    chain_id: 8453,
+    cadence: BlockCadence::Sequencer {
+        target_block_millis: 2_000,
+    },
+};
+
+const KNOWN_PROFILES: [ChainProfile; 3] = [ETHEREUM_MAINNET, ARBITRUM_ONE, BASE];
+
+impl ChainProfile {
+    /// Profile of a known chain, or one with an unknown cadence otherwise
+    pub fn from_chain_id(chain_id: u64) -> Self {
+        KNOWN_PROFILES
+            .into_iter()
+            .find(|profile| profile.chain_id == chain_id)
+            .unwrap_or_else(|| {
+                warn!(
+                    "No profile for chain id {}, block cadence is unknown",
+                    chain_id
+                );
+                ChainProfile {
+                    name: "unknown",
+                    chain_id,
+                    cadence: BlockCadence::Unknown,
+                }
+            })
+    }
+
+    /// Profile of the chain the node behind `provider` follows
+    pub async fn from_provider(
+        provider: &RootProvider<PubSubFrontend>,
+    ) -> Result<Self, ChainProfileError> {
+        let chain_id = provider.get_chain_id().await?;
+        Ok(Self::from_chain_id(chain_id))
+    }
+
+    /// Expected time between blocks. Chains with an unknown cadence are assumed to produce one
+    /// every 12 seconds.
+    pub fn block_time(&self) -> Duration {
+        match self.cadence {
+            BlockCadence::FixedInterval { slot_millis, .. } => Duration::from_millis(slot_millis),
+            BlockCadence::Sequencer {
+                target_block_millis,
+            } => Duration::from_millis(target_block_millis),
+            BlockCadence::Unknown => FALLBACK_BLOCK_TIME,
+        }
+    }
+
+    /// Milliseconds elapsed since the current slot started, and the slot length. Only chains with
+    /// fixed slots have them.
+    fn millis_into_slot(&self, now_millis: u64) -> Option<(u64, u64)> {
+        let BlockCadence::FixedInterval {
+            genesis_timestamp,
+            slot_millis,
+        } = self.cadence
+        else {
+            return None;
+        };
+        let into_slot = now_millis.checked_sub(genesis_timestamp * 1000)? % slot_millis;
+        Some((into_slot, slot_millis))
+    }
+
+    /// Seconds elapsed since the current slot started and seconds left until it ends, rounded to
+    /// one decimal. Only chains with fixed slots have them.
+    pub fn slot_position(&self) -> Option<(f32, f32)> {
+        let now_millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_millis() as u64;
+        let (into_slot, slot_millis) = self.millis_into_slot(now_millis)?;
+        let captured_at = (into_slot as f32 / 100.0).round() / 10.0;
+        let remaining = ((slot_millis - into_slot) as f32 / 100.0).round() / 10.0;
+        Some((captured_at, remaining))
+    }
+
+    /// Unix time (in seconds) at which `slot` starts, which is the timestamp of its block. Only
+    /// chains with fixed slots have them.
+    pub fn slot_timestamp(&self, slot: u64) -> Option<u64> {
+        let BlockCadence::FixedInterval {
+            genesis_timestamp,
+            slot_millis,
+        } = self.cadence
+        else {
+            return None;
+        };
+        Some(genesis_timestamp + slot * slot_millis / 1000)
+    }
+
+    /// Unix time (in milliseconds) at which the slot current at `now_millis` ends. Only chains with
+    /// fixed slots have them.
+    pub fn slot_end_millis(&self, now_millis: u64) -> Option<u64> {
+        let (into_slot, slot_millis) = self.millis_into_slot(now_millis)?;
+        Some(now_millis - into_slot + slot_millis)
+    }
+
+    /// First block a tx seen right now can be included in.
+    ///
+    /// The node's head is the last block it knows of. With slots, the next block is head + 1
+    /// regardless of how long it takes to land. Sequencers may have sealed more blocks since the
+    /// head was produced, so the blocks expected to exist by now are skipped too.
+    pub async fn estimate_inclusion_block(
+        &self,
+        provider: &RootProvider<PubSubFrontend>,
+    ) -> Result<u64, ChainProfileError> {
+        let BlockCadence::Sequencer {
+            target_block_millis,
+        } = self.cadence
+        else {
+            return Ok(provider.get_block_number().await? + 1);
+        };
+        let head = provider
+            .get_block(BlockId::latest(), BlockTransactionsKind::Hashes)
+            .await?
+            .ok_or("Node returned no latest block")?;
+        let now_millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let blocks_since_head =
+            now_millis.saturating_sub(head.header.timestamp * 1000) / target_block_millis;
+        Ok(head.header.number + blocks_since_head + 1)
+    }
+
+    /// Number of blocks expected to be produced within `budget`, at least one
+    pub fn blocks_within(&self, budget: Duration) -> u64 {
+        ((budget.as_millis() / self.block_time().as_millis()) as u64).max(1)
+    }
+}
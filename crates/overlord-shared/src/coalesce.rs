@@ -0,0 +1,111 @@
+use alloy::transports::{RpcError, TransportError, TransportErrorKind};
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::OnceCell;
+
+/// Lets identical calls made at the same time share one RPC round-trip (single-flight). The first
+/// caller of a key makes the call, and everyone else asking for the same key before it finishes
+/// waits for its result instead of making their own. Nothing is kept once the call finishes, so
+/// keys should include the block the call is made at, and results can't go stale.
+///
+/// Results are cloned to every waiter. So are errors: the caller whose call failed gets its error,
+/// and the waiters a copy of it (see SharedError) instead of making the call again. Only if the
+/// caller making it is dropped, a waiter makes the call instead.
+pub struct Coalescer<K, V, E> {
+    in_flight: Mutex<HashMap<K, Arc<OnceCell<Result<V, E>>>>>,
+}
+
+impl<K, V, E> Default for Coalescer<K, V, E>
+where
+    K: Clone + Eq + Hash,
+    V: Clone,
+    E: SharedError,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, E> Coalescer<K, V, E>
+where
+    K: Clone + Eq + Hash,
+    V: Clone,
+    E: SharedError,
+{
+    pub fn new() -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Result of `call` for `key`, or of the call already in flight for it
+    pub async fn run<F, Fut>(&self, key: K, call: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let cell = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        // The caller making the call keeps its error, the cell holds the copy waiters get
+        let mut own_error = None;
+        let shared = cell
+            .get_or_init(|| async {
+                call().await.map_err(|e| {
+                    let shared = e.share();
+                    own_error = Some(e);
+                    shared
+                })
+            })
+            .await;
+        let result = match (own_error, shared) {
+            (Some(e), _) => Err(e),
+            (None, Ok(value)) => Ok(value.clone()),
+            (None, Err(e)) => Err(e.share()),
+        };
+        // Only the entry of this call, a later one for the same key may have replaced it already
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, &cell))
+        {
+            in_flight.remove(&key);
+        }
+        result
+    }
+}
+
+/// Copy of an error for the waiters of a failed call. The errors of alloy can't be cloned, so the
+/// copy keeps what retries and logs need: the node's error response as it is, and the message of
+/// anything else, still told apart as a transport failure (retryable) or not.
+pub trait SharedError {
+    fn share(&self) -> Self;
+}
+
+impl SharedError for TransportError {
+    fn share(&self) -> Self {
+        match self {
+            RpcError::ErrorResp(payload) => RpcError::ErrorResp(payload.clone()),
+            RpcError::NullResp => RpcError::NullResp,
+            RpcError::Transport(_) => TransportErrorKind::custom_str(&self.to_string()),
+            _ => RpcError::local_usage_str(&self.to_string()),
+        }
+    }
+}
+
+impl SharedError for alloy::contract::Error {
+    fn share(&self) -> Self {
+        match self {
+            Self::TransportError(e) => Self::TransportError(e.share()),
+            _ => Self::TransportError(RpcError::local_usage_str(&self.to_string())),
+        }
+    }
+}
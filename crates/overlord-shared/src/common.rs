@@ -1,23 +1,380 @@
-use alloy::{providers::RootProvider, pubsub::PubSubFrontend};
+use alloy::{
+    primitives::{utils::format_units, Address, U256},
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::BlockId,
+    transports::Transport,
+};
 
-use std::sync::Arc;
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, fmt, sync::Arc};
 
-use crate::constants::{AAVE_V3_PROVIDER_ADDRESS, AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS};
-use crate::sol_bindings::{AaveUIPoolDataProvider, IUiPoolDataProviderV3::AggregatedReserveData};
+use crate::coalesce::Coalescer;
+use crate::constants::AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS;
+use crate::decimals_guard::DECIMALS_GUARD;
+use crate::market::AaveMarket;
+use crate::retry::{is_retryable_call_error, retry, RetryPolicy};
+use crate::sol_bindings::{
+    pool::AaveV3Pool, AaveOracle, AaveUIPoolDataProvider,
+    IUiPoolDataProviderV3::AggregatedReserveData,
+};
 
-pub async fn get_reserves_data(
+// marketReferenceCurrencyPriceInUsd comes straight from a Chainlink USD feed
+const USD_PRICE_DECIMALS: u8 = 8;
+
+// Bursts of events have dozens of tasks reading the same reserves and prices at the same block,
+// so identical reads in flight share one round-trip (see Coalescer)
+type CallCoalescer<K, V> = Coalescer<K, V, alloy::contract::Error>;
+static RESERVES_DATA_CALLS: Lazy<CallCoalescer<(Address, BlockId), Vec<AggregatedReserveData>>> =
+    Lazy::new(Coalescer::new);
+static RESERVES_LIST_CALLS: Lazy<CallCoalescer<(Address, BlockId), Vec<Address>>> =
+    Lazy::new(Coalescer::new);
+static ASSET_PRICE_CALLS: Lazy<CallCoalescer<(Address, Address, BlockId), U256>> =
+    Lazy::new(Coalescer::new);
+
+/// The e-mode category a user opted into. Only reserves enabled in `collateral_bitmap` get the
+/// category liquidation threshold and bonus, every other reserve keeps its own parameters.
+#[derive(Clone, Debug)]
+pub struct UserEModeCategory {
+    pub id: u8,
+    pub ltv: U256,
+    pub liquidation_threshold: U256,
+    pub liquidation_bonus: U256,
+    pub collateral_bitmap: u128,
+}
+
+impl UserEModeCategory {
+    /// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/configuration/EModeConfiguration.sol#L48
+    pub fn is_collateral_in_category(&self, reserve_index: usize) -> bool {
+        // In Solidity: (bitmap >> reserveIndex) & 1 != 0
+        reserve_index < 128 && (self.collateral_bitmap >> reserve_index) & 1 != 0
+    }
+}
+
+/// Why an amount of base currency couldn't be converted to base units
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConversionError {
+    /// NaN, infinite or negative
+    InvalidAmount(f64),
+    /// Doesn't fit in a U256
+    OutOfRange(f64),
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::InvalidAmount(amount) => write!(f, "invalid amount {}", amount),
+            ConversionError::OutOfRange(base_units) => {
+                write!(f, "{} base units don't fit in 256 bits", base_units)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// The currency every AaveOracle price (and every `*Base` amount of getUserAccountData) is quoted
+/// in. Aave v3 on Ethereum uses USD with 8 decimals, but some markets quote in ETH or other units.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BaseCurrency {
+    /// AaveOracle.BASE_CURRENCY(), the zero address for USD
+    pub address: Address,
+    /// AaveOracle.BASE_CURRENCY_UNIT()
+    pub unit: U256,
+    pub decimals: u8,
+    /// Price of one unit of base currency in USD, with USD_PRICE_DECIMALS decimals
+    pub price_in_usd: U256,
+}
+
+impl BaseCurrency {
+    /// Base currency of the Aave v3 Ethereum market
+    pub const USD: BaseCurrency = BaseCurrency {
+        address: Address::ZERO,
+        unit: U256::from_limbs([100_000_000, 0, 0, 0]),
+        decimals: 8,
+        price_in_usd: U256::from_limbs([100_000_000, 0, 0, 0]),
+    };
+
+    /// Converts a whole amount of base currency (e.g. 100.0 for $100 in a USD market) to base units.
+    /// NaN, infinite and negative amounts are an error, as are amounts that don't fit in a U256.
+    pub fn to_base_units(self, amount: f64) -> Result<U256, ConversionError> {
+        if !amount.is_finite() || amount < 0.0 {
+            return Err(ConversionError::InvalidAmount(amount));
+        }
+        let base_units = amount * 10f64.powi(self.decimals as i32);
+        U256::try_from(base_units).map_err(|_| ConversionError::OutOfRange(base_units))
+    }
+
+    /// Converts an amount in base units to a whole amount of base currency. Only meant for
+    /// filtering and logging, since it loses precision.
+    pub fn to_f64(self, amount: U256) -> f64 {
+        format_units(amount, self.decimals)
+            .ok()
+            .and_then(|amount| amount.parse().ok())
+            .unwrap_or(f64::MAX)
+    }
+
+    /// Converts an amount in base units to USD. Same caveats as to_f64().
+    pub fn to_usd(self, amount: U256) -> f64 {
+        self.to_f64(amount) * self.price_in_usd.to::<u128>() as f64
+            / 10f64.powi(USD_PRICE_DECIMALS as i32)
+    }
+
+    /// Converts a USD amount to base units. Same errors as to_base_units(), which a base currency
+    /// without a USD price also gets.
+    pub fn usd_to_base_units(self, amount_in_usd: f64) -> Result<U256, ConversionError> {
+        let price_in_usd =
+            self.price_in_usd.to::<u128>() as f64 / 10f64.powi(USD_PRICE_DECIMALS as i32);
+        self.to_base_units(amount_in_usd / price_in_usd)
+    }
+
+    /// Formats an amount in base units for logging
+    pub fn format(self, amount: U256) -> String {
+        format_units(amount, self.decimals).unwrap_or_else(|_| "CONVERSION_ERROR".to_string())
+    }
+}
+
+/// Reads the market base currency from the AaveOracle. It never changes for a deployed market,
+/// so services only need to call this once at startup.
+pub async fn get_base_currency(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
+) -> Result<BaseCurrency, Box<dyn std::error::Error>> {
+    get_base_currency_at(provider, market, BlockId::latest()).await
+}
+
+/// Same as get_base_currency(), but read at `block`. Its USD price is the one of that block.
+pub async fn get_base_currency_at(
     provider: Arc<RootProvider<PubSubFrontend>>,
-) -> Result<Vec<AggregatedReserveData>, Box<dyn std::error::Error>> {
+    market: &AaveMarket,
+    block: BlockId,
+) -> Result<BaseCurrency, Box<dyn std::error::Error>> {
+    let aave_oracle = AaveOracle::new(market.oracle, provider.clone());
+    let address = match aave_oracle.BASE_CURRENCY().block(block).call().await {
+        Ok(response) => response._0,
+        Err(e) => return Err(format!("Error trying to call BASE_CURRENCY: {}", e).into()),
+    };
+    let unit = match aave_oracle.BASE_CURRENCY_UNIT().block(block).call().await {
+        Ok(response) => response._0,
+        Err(e) => return Err(format!("Error trying to call BASE_CURRENCY_UNIT: {}", e).into()),
+    };
+    let decimals =
+        match (0..=77u8).find(|decimals| U256::from(10).pow(U256::from(*decimals)) == unit) {
+            Some(decimals) => decimals,
+            None => return Err(format!("Base currency unit {} is not a power of 10", unit).into()),
+        };
+    // The UI data provider reports the USD price of the base currency next to the reserves data.
+    // https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/helpers/UiPoolDataProviderV3.sol
+    let price_in_usd =
+        match AaveUIPoolDataProvider::new(AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS, provider.clone())
+            .getReservesData(market.addresses_provider)
+            .block(block)
+            .call()
+            .await
+        {
+            Ok(reserves_data) => reserves_data._1.marketReferenceCurrencyPriceInUsd,
+            Err(e) => return Err(format!("Error trying to call getReservesData: {}", e).into()),
+        };
+    if price_in_usd.is_negative() || price_in_usd.is_zero() {
+        return Err(format!("Invalid base currency price in USD: {}", price_in_usd).into());
+    }
+    Ok(BaseCurrency {
+        address,
+        unit,
+        decimals,
+        price_in_usd: price_in_usd.into_raw(),
+    })
+}
+
+pub async fn get_reserves_data<T, P>(
+    provider: Arc<P>,
+    market: &AaveMarket,
+) -> Result<Vec<AggregatedReserveData>, Box<dyn std::error::Error>>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    get_reserves_data_at(provider, market, BlockId::latest()).await
+}
+
+/// Same as get_reserves_data(), but read at `block`. The decimals of reserves read for the first
+/// time are checked by DECIMALS_GUARD, which callers should ask before using a reserve.
+pub async fn get_reserves_data_at<T, P>(
+    provider: Arc<P>,
+    market: &AaveMarket,
+    block: BlockId,
+) -> Result<Vec<AggregatedReserveData>, Box<dyn std::error::Error>>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
     /*
        According to https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/helpers/UiPoolDataProviderV3.sol#L45
        the reserves data is ordered the same way as the reserves list (it actually calls pool.getReservesList() and uses it as index)
     */
-    match AaveUIPoolDataProvider::new(AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS, provider.clone())
-        .getReservesData(AAVE_V3_PROVIDER_ADDRESS)
-        .call()
+    let ui_pool_data_provider =
+        &AaveUIPoolDataProvider::new(AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS, provider.clone());
+    let addresses_provider = market.addresses_provider;
+    // Every service reads it at startup, so a node that's busy right then shouldn't stop them
+    match RESERVES_DATA_CALLS
+        .run((addresses_provider, block), || async move {
+            retry(
+                RetryPolicy::RPC,
+                "call getReservesData",
+                is_retryable_call_error,
+                || async move {
+                    ui_pool_data_provider
+                        .getReservesData(addresses_provider)
+                        .block(block)
+                        .call()
+                        .await
+                },
+            )
+            .await
+            .map(|reserves_data| reserves_data._0)
+        })
         .await
     {
-        Ok(reserves_data) => Ok(reserves_data._0),
+        Ok(reserves_data) => {
+            DECIMALS_GUARD.check(provider, market, &reserves_data).await;
+            Ok(reserves_data)
+        }
         Err(e) => Err(format!("Error trying to call getReservesData: {}", e).into()),
     }
 }
+
+/// pool.getReservesList() of `market` at `block`. The list is ordered the same way as the
+/// _reserveList storage of the pool, see
+/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/pool/Pool.sol#L532
+pub async fn get_reserves_list_at(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
+    block: BlockId,
+) -> Result<Vec<Address>, alloy::contract::Error> {
+    let pool = market.pool;
+    RESERVES_LIST_CALLS
+        .run((pool, block), || async move {
+            AaveV3Pool::new(pool, provider)
+                .getReservesList()
+                .block(block)
+                .call()
+                .await
+                .map(|reserves_list| reserves_list._0)
+        })
+        .await
+}
+
+/// `oracle.getAssetPrice(asset)` at `block`
+pub async fn get_asset_price_at<T, P>(
+    provider: Arc<P>,
+    oracle: Address,
+    asset: Address,
+    block: BlockId,
+) -> Result<U256, alloy::contract::Error>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    ASSET_PRICE_CALLS
+        .run((oracle, asset, block), || async move {
+            AaveOracle::new(oracle, provider)
+                .getAssetPrice(asset)
+                .block(block)
+                .call()
+                .await
+                .map(|price_response| price_response._0)
+        })
+        .await
+}
+
+/// Returns the e-mode category of the user, or None if the user isn't in e-mode.
+pub async fn get_user_emode_category(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
+    user_address: Address,
+) -> Result<Option<UserEModeCategory>, Box<dyn std::error::Error>> {
+    get_user_emode_category_at(provider, market, user_address, BlockId::latest()).await
+}
+
+/// Same as get_user_emode_category(), but read at `block`
+pub async fn get_user_emode_category_at(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
+    user_address: Address,
+    block: BlockId,
+) -> Result<Option<UserEModeCategory>, Box<dyn std::error::Error>> {
+    let category_id = match AaveV3Pool::new(market.pool, provider.clone())
+        .getUserEMode(user_address)
+        .block(block)
+        .call()
+        .await
+    {
+        Ok(response) => response._0,
+        Err(e) => return Err(format!("Error trying to call getUserEMode: {}", e).into()),
+    };
+    if category_id == U256::ZERO {
+        return Ok(None);
+    }
+
+    let mut emode_categories = get_emode_categories_at(provider.clone(), market, block).await?;
+    let category_id = category_id.saturating_to::<u8>();
+    match emode_categories.remove(&category_id) {
+        Some(emode) => Ok(Some(emode)),
+        None => Err(format!(
+            "User {} is in e-mode category {} but it wasn't returned by getEModes",
+            user_address, category_id
+        )
+        .into()),
+    }
+}
+
+/// Returns every e-mode category configured in the pool, by id
+pub async fn get_emode_categories<T, P>(
+    provider: Arc<P>,
+    market: &AaveMarket,
+) -> Result<HashMap<u8, UserEModeCategory>, Box<dyn std::error::Error>>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    get_emode_categories_at(provider, market, BlockId::latest()).await
+}
+
+/// Same as get_emode_categories(), but read at `block`
+pub async fn get_emode_categories_at<T, P>(
+    provider: Arc<P>,
+    market: &AaveMarket,
+    block: BlockId,
+) -> Result<HashMap<u8, UserEModeCategory>, Box<dyn std::error::Error>>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    // getEModeCategoryData() on the pool is deprecated since v3.2 and doesn't expose the collateral
+    // bitmap, so we read the categories from the UI data provider instead
+    let emodes =
+        match AaveUIPoolDataProvider::new(AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS, provider.clone())
+            .getEModes(market.addresses_provider)
+            .block(block)
+            .call()
+            .await
+        {
+            Ok(response) => response._0,
+            Err(e) => return Err(format!("Error trying to call getEModes: {}", e).into()),
+        };
+    Ok(emodes
+        .into_iter()
+        .map(|emode| {
+            (
+                emode.id,
+                UserEModeCategory {
+                    id: emode.id,
+                    ltv: U256::from(emode.eMode.ltv),
+                    liquidation_threshold: U256::from(emode.eMode.liquidationThreshold),
+                    liquidation_bonus: U256::from(emode.eMode.liquidationBonus),
+                    collateral_bitmap: emode.eMode.collateralBitmap,
+                },
+            )
+        })
+        .collect())
+}
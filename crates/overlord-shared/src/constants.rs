@@ -1,6 +1,10 @@
 use alloy::primitives::{address, Address};
 
 pub const PROFITO_INBOUND_ENDPOINT: &str = "ipc:///tmp/profito_inbound";
+pub const PROFITO_CANCEL_ENDPOINT: &str = "ipc:///tmp/profito_cancellations";
+pub const PROFITO_COMPETITION_ENDPOINT: &str = "ipc:///tmp/profito_competition";
+pub const OOPS_INBOUND_ENDPOINT: &str = "ipc:///tmp/oops_inbound";
+pub const VEGA_ACK_ENDPOINT: &str = "ipc:///tmp/vega_acks";
 pub const AAVE_ORACLE_ADDRESS: Address = address!("0x54586bE62E3c3580375aE3723C145253060Ca0C2");
 pub const AAVE_V3_PROVIDER_ADDRESS: Address = address!("2f39d218133afab8f2b819b1066c7e434ad94e9e");
 pub const AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS: Address =
@@ -8,10 +12,22 @@ pub const AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS: Address =
 pub const AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS: Address =
     address!("3f78bbd206e4d3c504eb854232eda7e47e9fd8fc");
 pub const UNISWAP_V3_FACTORY: Address = address!("1F98431c8aD98523631AE4a59f267346ea31F984");
+pub const UNISWAP_V3_QUOTER: Address = address!("b27308f9F90D607463bb33eA1BeBb41C27CE5AB6");
+pub const UNISWAP_V2_FACTORY: Address = address!("5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f");
+pub const UNISWAP_V2_ROUTER: Address = address!("7a250d5630B4cF539739dF2C5dAcb4c659F2488D");
+pub const CURVE_META_REGISTRY: Address = address!("F98B45FA17DE75FB1aD0e7aFD971b0ca00e379fC");
+pub const BALANCER_VAULT: Address = address!("BA12222222228d8Ba445958a75a0704d566BF2C8");
 pub const FOXDIE_ADDRESS: Address = address!("55710f6cE35d5b6928D7192D0955387C2cf6c492");
 pub const MORPHO: Address = address!("BBBBBbbBBb9cC5e90e3b3Af64bdAF62C37EEFFCb");
+pub const MULTICALL3_ADDRESS: Address = address!("cA11bde05977b3631167028862bE2a173976CA11");
+// No pool events exist before this block, so log scans can start here
+pub const AAVE_V3_POOL_DEPLOYMENT_BLOCK: u64 = 16291127;
+// Emits the reserve listing and configuration events
+pub const AAVE_V3_POOL_CONFIGURATOR_ADDRESS: Address =
+    address!("64b761D848206f447Fe2dd461b0c635Ec39EbB27");
 
 // used on bpchecker
 pub const AAVE_V3_POOL_ADDRESS: Address = address!("87870Bca3F3fD6335C3F4ce8392D69350B4fA4E2");
 pub const WETH: Address = address!("C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2");
 pub const GHO_PRICE_ORACLE: Address = address!("D110cac5d8682A3b045D5524a9903E031d70FCCd");
+pub const GHO_VARIABLE_DEBT_TOKEN: Address = address!("786dBff3f1292ae8F92ea68Cf93c30b34B1ed04B");
@@ -0,0 +1,243 @@
+use alloy::{
+    primitives::{Address, U256},
+    providers::Provider,
+    transports::Transport,
+};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{error, info, warn};
+
+use crate::market::AaveMarket;
+use crate::reserve_configuration::ReserveConfiguration;
+use crate::sol_bindings::{pool::AaveV3Pool, IUiPoolDataProviderV3::AggregatedReserveData, ERC20};
+
+/// Path to the operator controlled list of reserves whose decimals were checked by hand.
+///
+/// The file has one reserve per line, as its underlying asset address followed by the decimals
+/// getReservesData() reports for it (`0x... 18`). A reserve whose decimals don't add up is only
+/// used again once it's listed with the decimals being reported, so a later change of them is
+/// caught again. Empty lines and lines starting with `#` are ignored. The guard is asked from hot
+/// loops, so the file is checked for changes at most once every ACK_POLL_INTERVAL.
+pub const DECIMALS_ACK_FILE_ENV: &str = "OVERLORD_DECIMALS_ACK_FILE";
+
+const ACK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub static DECIMALS_GUARD: Lazy<DecimalsGuard> = Lazy::new(DecimalsGuard::from_env);
+
+/// Outcome of checking the decimals of a reserve
+enum Verdict {
+    Consistent,
+    /// Why the decimals don't add up
+    Inconsistent(String),
+    /// decimals() or the reserve configuration couldn't be read. The reserve is quarantined until
+    /// they can, since nothing says its decimals are right.
+    Unreadable,
+}
+
+#[derive(Default)]
+struct DecimalsGuardState {
+    /// Decimals reported for every reserve checked so far, by the pool of its market and its
+    /// underlying asset, since the same asset may be configured differently in each market
+    checked: HashMap<(Address, Address), (U256, Verdict)>,
+    modified: Option<SystemTime>,
+    polled_at: Option<Instant>,
+    acknowledged: HashMap<Address, U256>,
+}
+
+/// Wrong decimals silently corrupt every amount derived from a reserve, so the decimals
+/// getReservesData() reports are checked against the token's decimals() and the ones in the
+/// reserve configuration bitmap. A reserve whose values don't agree is quarantined until an
+/// operator acknowledges its decimals (see DECIMALS_ACK_FILE_ENV).
+///
+/// Each reserve is checked the first time it's read, and again only if the decimals reported for
+/// it change.
+pub struct DecimalsGuard {
+    ack_path: Option<PathBuf>,
+    state: Mutex<DecimalsGuardState>,
+}
+
+impl DecimalsGuard {
+    pub fn from_env() -> Self {
+        let ack_path = std::env::var(DECIMALS_ACK_FILE_ENV).ok().map(PathBuf::from);
+        match &ack_path {
+            Some(path) => info!(
+                "Decimals acknowledgements will be read from {}",
+                path.display()
+            ),
+            None => info!(
+                "{} not set, reserves with inconsistent decimals can't be acknowledged",
+                DECIMALS_ACK_FILE_ENV
+            ),
+        }
+        Self {
+            ack_path,
+            state: Mutex::new(DecimalsGuardState::default()),
+        }
+    }
+
+    /// Checks the reserves of `reserves_data` that weren't checked yet, or whose reported
+    /// decimals changed. A reserve whose decimals can't be read is quarantined, and checked again
+    /// on the next call.
+    pub async fn check<T, P>(
+        &self,
+        provider: Arc<P>,
+        market: &AaveMarket,
+        reserves_data: &[AggregatedReserveData],
+    ) where
+        T: Transport + Clone,
+        P: Provider<T>,
+    {
+        let unchecked: Vec<(Address, String, U256)> = {
+            let state = self.refresh();
+            reserves_data
+                .iter()
+                .filter(|reserve| {
+                    state
+                        .checked
+                        .get(&(market.pool, reserve.underlyingAsset))
+                        .is_none_or(|(decimals, verdict)| {
+                            *decimals != reserve.decimals || matches!(verdict, Verdict::Unreadable)
+                        })
+                })
+                .map(|reserve| {
+                    (
+                        reserve.underlyingAsset,
+                        reserve.symbol.clone(),
+                        reserve.decimals,
+                    )
+                })
+                .collect()
+        };
+        let pool = AaveV3Pool::new(market.pool, provider.clone());
+        for (asset, symbol, decimals) in unchecked {
+            let token_decimals = ERC20::new(asset, provider.clone()).decimals().call().await;
+            let configuration = pool.getConfiguration(asset).call().await;
+            let verdict = match (token_decimals, configuration) {
+                (Ok(token_decimals), Ok(configuration)) => {
+                    let token_decimals = U256::from(token_decimals._0);
+                    let configuration_decimals =
+                        U256::from(ReserveConfiguration::decode(configuration._0.data).decimals);
+                    if token_decimals == decimals && configuration_decimals == decimals {
+                        Verdict::Consistent
+                    } else {
+                        Verdict::Inconsistent(format!(
+                            "getReservesData reports {} decimals, decimals() returns {} and the \
+                             reserve configuration has {}",
+                            decimals, token_decimals, configuration_decimals
+                        ))
+                    }
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    warn!(
+                        "Couldn't check the decimals of {} ({}) of the {} market, quarantining \
+                         it until they can be: {}",
+                        symbol, asset, market.name, e
+                    );
+                    Verdict::Unreadable
+                }
+            };
+            let mut state = self.refresh();
+            if let Verdict::Inconsistent(inconsistency) = &verdict {
+                if state.acknowledged.get(&asset) == Some(&decimals) {
+                    warn!(
+                        "{} ({}) of the {} market is acknowledged, even though {}",
+                        symbol, asset, market.name, inconsistency
+                    );
+                } else {
+                    error!(
+                        "{} ({}) of the {} market is quarantined until its decimals are \
+                         acknowledged in {}: {}",
+                        symbol, asset, market.name, DECIMALS_ACK_FILE_ENV, inconsistency
+                    );
+                }
+            }
+            state
+                .checked
+                .insert((market.pool, asset), (decimals, verdict));
+        }
+    }
+
+    /// True if the decimals of the reserve of `asset` in `market` don't add up (or couldn't be
+    /// read), and haven't been acknowledged
+    pub fn is_quarantined(&self, market: &AaveMarket, asset: &Address) -> bool {
+        let state = self.refresh();
+        match state.checked.get(&(market.pool, *asset)) {
+            Some((_, Verdict::Consistent)) | None => false,
+            Some((decimals, _)) => state.acknowledged.get(asset) != Some(decimals),
+        }
+    }
+
+    /// Returns the first asset from `assets` quarantined in `market`, if any
+    pub fn first_quarantined(
+        &self,
+        market: &AaveMarket,
+        assets: impl IntoIterator<Item = Address>,
+    ) -> Option<Address> {
+        assets
+            .into_iter()
+            .find(|asset| self.is_quarantined(market, asset))
+    }
+
+    /// Re-reads the acknowledgements if the file changed since the last time we looked at it,
+    /// which is at most once every ACK_POLL_INTERVAL. A missing file means nothing is
+    /// acknowledged.
+    fn refresh(&self) -> MutexGuard<'_, DecimalsGuardState> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(path) = &self.ack_path else {
+            return state;
+        };
+        if state
+            .polled_at
+            .is_some_and(|polled_at| polled_at.elapsed() < ACK_POLL_INTERVAL)
+        {
+            return state;
+        }
+        state.polled_at = Some(Instant::now());
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified == state.modified {
+            return state;
+        }
+        let contents = match modified {
+            Some(_) => match std::fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    warn!(
+                        "Failed to read decimals acknowledgements {}: {}",
+                        path.display(),
+                        e
+                    );
+                    return state;
+                }
+            },
+            None => String::new(),
+        };
+        let mut acknowledged = HashMap::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let asset = fields.next().map(Address::from_str);
+            let decimals = fields.next().map(U256::from_str);
+            match (asset, decimals) {
+                (Some(Ok(asset)), Some(Ok(decimals))) => {
+                    acknowledged.insert(asset, decimals);
+                }
+                _ => warn!("Ignoring invalid decimals acknowledgement '{}'", line),
+            }
+        }
+        info!(
+            acknowledged = acknowledged.len(),
+            "Decimals acknowledgements reloaded"
+        );
+        state.modified = modified;
+        state.acknowledged = acknowledged;
+        state
+    }
+}
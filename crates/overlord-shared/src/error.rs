@@ -0,0 +1,103 @@
+use alloy::transports::TransportError;
+use std::{fmt, path::Path};
+
+use crate::retry::is_retryable_rpc_error;
+
+/// A request to the node that failed. Node failures (which may go away if tried again) are told
+/// apart from responses that couldn't be decoded. The error types of every service wrap it, so
+/// they're all built and retried the same way.
+#[derive(Debug)]
+pub enum NodeError {
+    /// The node couldn't be reached, or returned an error for the request (a revert included)
+    Rpc {
+        call: String,
+        source: TransportError,
+    },
+    /// The call couldn't be encoded, or its response decoded
+    Decode {
+        call: String,
+        source: alloy::contract::Error,
+    },
+}
+
+impl NodeError {
+    /// Error of a contract call to `call`
+    pub fn call(call: impl Into<String>, error: alloy::contract::Error) -> Self {
+        match error {
+            alloy::contract::Error::TransportError(source) => Self::Rpc {
+                call: call.into(),
+                source,
+            },
+            source => Self::Decode {
+                call: call.into(),
+                source,
+            },
+        }
+    }
+
+    /// Error of a request to the node that isn't a contract call
+    pub fn rpc(call: impl Into<String>, source: TransportError) -> Self {
+        Self::Rpc {
+            call: call.into(),
+            source,
+        }
+    }
+
+    /// True for node failures that may go away if tried again, see is_retryable_rpc_error()
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Rpc { source, .. } => is_retryable_rpc_error(source),
+            Self::Decode { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rpc { call, source } => write!(f, "Error trying to call {}: {}", call, source),
+            Self::Decode { call, source } => write!(
+                f,
+                "Error trying to decode the response of {}: {}",
+                call, source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Rpc { source, .. } => Some(source),
+            Self::Decode { source, .. } => Some(source),
+        }
+    }
+}
+
+/// A file (checkpoint, snapshot, dump, ...) that couldn't be read or written
+#[derive(Debug)]
+pub struct FileError {
+    pub path: String,
+    pub source: std::io::Error,
+}
+
+impl FileError {
+    pub fn new(path: impl AsRef<Path>, source: std::io::Error) -> Self {
+        Self {
+            path: path.as_ref().display().to_string(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Error accessing {}: {}", self.path, self.source)
+    }
+}
+
+impl std::error::Error for FileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
@@ -0,0 +1,207 @@
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+/// Path to the operator controlled list of experiments to run.
+///
+/// The file has one flag per line, optionally followed by how many times a day the experiment
+/// can be used (`cheapest_swap_tier 20`). A flag without a limit can be used any number of times,
+/// and a limit of 0 turns it off. Empty lines and lines starting with `#` are ignored. Like the
+/// kill switch, the file is checked on every call, so editing it takes effect immediately.
+pub const FEATURE_FLAGS_FILE_ENV: &str = "OVERLORD_FEATURE_FLAGS_FILE";
+
+pub static FEATURE_FLAGS: Lazy<FeatureFlags> = Lazy::new(FeatureFlags::from_env);
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+#[derive(Default)]
+struct FeatureFlagsState {
+    modified: Option<SystemTime>,
+    /// Max uses per day of every enabled flag, None if it has no limit
+    max_uses_per_day: HashMap<String, Option<u32>>,
+    /// Day (since the unix epoch, UTC) the uses are counted for
+    day: u64,
+    uses: HashMap<String, u32>,
+}
+
+/// Experimental behavior (new swap routers, bribe strategies, ...) runs next to the default path,
+/// and is only taken at a decision point if its flag is enabled and hasn't used up its daily
+/// limit. Uses are counted per process and start over every day (UTC), and when the process
+/// restarts.
+pub struct FeatureFlags {
+    path: Option<PathBuf>,
+    state: Mutex<FeatureFlagsState>,
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() / SECONDS_PER_DAY)
+}
+
+impl FeatureFlags {
+    pub fn from_env() -> Self {
+        let path = std::env::var(FEATURE_FLAGS_FILE_ENV)
+            .ok()
+            .map(PathBuf::from);
+        match &path {
+            Some(path) => info!("Feature flags will be read from {}", path.display()),
+            None => info!(
+                "{} not set, experiments are disabled",
+                FEATURE_FLAGS_FILE_ENV
+            ),
+        }
+        Self {
+            path,
+            state: Mutex::new(FeatureFlagsState::default()),
+        }
+    }
+
+    /// True if `flag` is enabled and has uses left today. Doesn't count a use.
+    pub fn is_enabled(&self, flag: &str) -> bool {
+        let state = self.refresh();
+        match state.max_uses_per_day.get(flag) {
+            Some(Some(max_uses)) => state.uses.get(flag).copied().unwrap_or_default() < *max_uses,
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Counts a use of `flag` and returns true if it's enabled and had uses left today, so the
+    /// experiment should be taken. Call it at the decision point, once per decision.
+    pub fn try_use(&self, flag: &str) -> bool {
+        let mut state = self.refresh();
+        let max_uses = match state.max_uses_per_day.get(flag) {
+            Some(max_uses) => *max_uses,
+            None => return false,
+        };
+        let uses = state.uses.entry(flag.to_string()).or_default();
+        if max_uses.is_some_and(|max_uses| *uses >= max_uses) {
+            return false;
+        }
+        *uses += 1;
+        match max_uses {
+            Some(max_uses) if *uses == max_uses => info!(
+                "Experiment {} used {} times today, that's its limit until tomorrow",
+                flag, uses
+            ),
+            Some(max_uses) => info!("Experiment {} used ({} of {} today)", flag, uses, max_uses),
+            None => info!("Experiment {} used ({} today)", flag, uses),
+        }
+        true
+    }
+
+    /// Like try_use(), for decisions taken before it's known whether the experiment runs, e.g. a
+    /// bundle built with it that may not be submitted. The use is given back when the returned
+    /// reservation is dropped, unless it's committed first.
+    pub fn try_reserve(&'static self, flag: &str) -> Option<FlagReservation> {
+        let day = self.refresh().day;
+        self.try_use(flag).then(|| FlagReservation {
+            flags: self,
+            flag: flag.to_string(),
+            day,
+            committed: false,
+        })
+    }
+
+    /// Gives back a use of `flag` counted on `day`. Uses of a previous day are already gone.
+    fn release(&self, flag: &str, day: u64) {
+        let mut state = self.refresh();
+        if state.day != day {
+            return;
+        }
+        if let Some(uses) = state.uses.get_mut(flag) {
+            *uses = uses.saturating_sub(1);
+            info!("Experiment {} wasn't used after all ({} today)", flag, uses);
+        }
+    }
+
+    /// Re-reads the flags if the file changed since the last time we looked at it, and starts
+    /// counting uses over if the day changed. A missing file means every flag is off.
+    fn refresh(&self) -> MutexGuard<'_, FeatureFlagsState> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let day = today();
+        if day != state.day {
+            state.day = day;
+            state.uses.clear();
+        }
+        let Some(path) = &self.path else {
+            return state;
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified == state.modified {
+            return state;
+        }
+        let contents = match modified {
+            Some(_) => match std::fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    warn!("Failed to read feature flags {}: {}", path.display(), e);
+                    return state;
+                }
+            },
+            None => String::new(),
+        };
+        let mut max_uses_per_day = HashMap::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let Some(flag) = fields.next() else {
+                continue;
+            };
+            let max_uses = match fields.next().map(str::parse::<u32>) {
+                Some(Ok(max_uses)) => Some(max_uses),
+                Some(Err(e)) => {
+                    warn!(
+                        "Ignoring feature flag with an invalid limit '{}': {}",
+                        line, e
+                    );
+                    continue;
+                }
+                None => None,
+            };
+            if max_uses == Some(0) {
+                continue;
+            }
+            max_uses_per_day.insert(flag.to_string(), max_uses);
+        }
+        info!(
+            flags = ?max_uses_per_day,
+            "Feature flags reloaded"
+        );
+        state.modified = modified;
+        state.max_uses_per_day = max_uses_per_day;
+        state
+    }
+}
+
+/// A use of a flag counted by FeatureFlags::try_reserve(), given back when dropped unless it's
+/// committed
+pub struct FlagReservation {
+    flags: &'static FeatureFlags,
+    flag: String,
+    day: u64,
+    committed: bool,
+}
+
+impl FlagReservation {
+    /// Keeps the use counted, once the experiment actually ran
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for FlagReservation {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.flags.release(&self.flag, self.day);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Snapshots of a market's reserves data, and of the reserves and configuration of some of its
+//! users. They're vendored under fixtures/reserves so code working on reserves can be exercised
+//! with data shaped like a real market (dozens of reserves, price sources behind adapters, users
+//! with several collaterals and debts) without a node. `profito-cli capture-fixture` records them
+//! from a node at a known block. Synthetic ones are made up, and have no block.
+//!
+//! Calls are kept the way the node returned them (ABI encoded), so loading a fixture goes through
+//! the same decoding as reading the market does.
+
+use alloy::{
+    primitives::{Address, Bytes, U256},
+    sol_types::SolCall,
+};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::market::AaveMarket;
+use crate::sol_bindings::{
+    AaveUIPoolDataProvider,
+    IUiPoolDataProviderV3::{AggregatedReserveData, BaseCurrencyInfo, UserReserveData},
+};
+
+/// Name and contents of every vendored fixture
+const FIXTURES: &[(&str, &str)] = &[(
+    "synthetic_core_37_reserves",
+    include_str!("../fixtures/reserves/synthetic_core_37_reserves.json"),
+)];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReservesFixture {
+    /// Where the data comes from, and anything worth knowing about it
+    pub description: String,
+    pub chain_id: u64,
+    /// Block the fixture was captured at, None for synthetic ones that weren't read from a node
+    pub block_number: Option<u64>,
+    pub market: AaveMarket,
+    /// Return data of UiPoolDataProviderV3.getReservesData()
+    pub reserves_data: Bytes,
+    pub users: Vec<UserFixture>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserFixture {
+    pub address: Address,
+    /// Bitmap of pool.getUserConfiguration()
+    pub user_configuration: U256,
+    pub emode_category: u8,
+    /// Return data of UiPoolDataProviderV3.getUserReservesData()
+    pub user_reserves_data: Bytes,
+}
+
+impl ReservesFixture {
+    /// Names of the vendored fixtures
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        FIXTURES.iter().map(|(name, _)| *name)
+    }
+
+    /// The vendored fixture called `name`
+    pub fn load(name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let (_, contents) = FIXTURES
+            .iter()
+            .find(|(fixture, _)| *fixture == name)
+            .ok_or_else(|| format!("There's no fixture {}", name))?;
+        Ok(serde_json::from_str(contents)?)
+    }
+
+    /// A fixture that isn't vendored, e.g. one just captured
+    pub fn read(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Reserves in the order of pool.getReservesList(), like get_reserves_data() returns them
+    pub fn reserves_data(&self) -> Result<Vec<AggregatedReserveData>, Box<dyn std::error::Error>> {
+        Ok(self.decode_reserves_data()?._0)
+    }
+
+    pub fn base_currency_info(&self) -> Result<BaseCurrencyInfo, Box<dyn std::error::Error>> {
+        Ok(self.decode_reserves_data()?._1)
+    }
+
+    pub fn user(&self, address: Address) -> Option<&UserFixture> {
+        self.users.iter().find(|user| user.address == address)
+    }
+
+    fn decode_reserves_data(
+        &self,
+    ) -> Result<AaveUIPoolDataProvider::getReservesDataReturn, Box<dyn std::error::Error>> {
+        AaveUIPoolDataProvider::getReservesDataCall::abi_decode_returns(&self.reserves_data, true)
+            .map_err(|e| format!("Error trying to decode the reserves data: {}", e).into())
+    }
+}
+
+impl UserFixture {
+    /// The user's balances in every reserve of the market, as the UI data provider returns them
+    /// (before get_user_reserves_data() drops the ones that don't count)
+    pub fn user_reserves_data(&self) -> Result<Vec<UserReserveData>, Box<dyn std::error::Error>> {
+        match AaveUIPoolDataProvider::getUserReservesDataCall::abi_decode_returns(
+            &self.user_reserves_data,
+            true,
+        ) {
+            Ok(user_reserves_data) => Ok(user_reserves_data._0),
+            Err(e) => Err(format!("Error trying to decode the user reserves data: {}", e).into()),
+        }
+    }
+}
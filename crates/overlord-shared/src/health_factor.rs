@@ -0,0 +1,247 @@
+//! The account data formula of the pool (health factor included), on balances and prices that
+//! were already read. Nothing here is async or needs a provider, so vega's HF engine and
+//! profito's calculations share the same arithmetic, whatever they read their inputs from.
+//!
+//! https://github.com/aave-dao/aave-v3-origin/blob/bb6ea42947f349fe8182a0ea30c5a7883d1f9ed1/src/contracts/protocol/libraries/logic/GenericLogic.sol#L63
+
+use alloy::primitives::U256;
+
+use crate::{
+    math::{percent_mul, wad_div, MathError},
+    sol_bindings::pool::AaveV3Pool,
+};
+
+/// What a single reserve of a user adds to its account data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AccountTerm {
+    /// Zero unless the reserve counts as the user's collateral
+    pub collateral_in_base_currency: U256,
+    pub debt_in_base_currency: U256,
+    /// Effective parameters (in basis points), i.e. the e-mode ones if the asset is collateral
+    /// in the user category
+    pub ltv: U256,
+    pub liquidation_threshold: U256,
+}
+
+/// Same values as pool.getUserAccountData()
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AccountData {
+    pub total_collateral_in_base_currency: U256,
+    pub total_debt_in_base_currency: U256,
+    pub available_borrows_in_base_currency: U256,
+    pub avg_ltv: U256,
+    pub avg_liquidation_threshold: U256,
+    /// U256::MAX without debt
+    pub health_factor: U256,
+}
+
+impl From<AccountData> for AaveV3Pool::getUserAccountDataReturn {
+    fn from(account_data: AccountData) -> Self {
+        Self {
+            totalCollateralBase: account_data.total_collateral_in_base_currency,
+            totalDebtBase: account_data.total_debt_in_base_currency,
+            availableBorrowsBase: account_data.available_borrows_in_base_currency,
+            currentLiquidationThreshold: account_data.avg_liquidation_threshold,
+            ltv: account_data.avg_ltv,
+            healthFactor: account_data.health_factor,
+        }
+    }
+}
+
+/// `balance` of an asset with `asset_unit` (10^decimals) priced at `asset_price`, in base
+/// currency
+pub fn value_in_base_currency(
+    balance: U256,
+    asset_price: U256,
+    asset_unit: U256,
+) -> Result<U256, MathError> {
+    let value = balance
+        .checked_mul(asset_price)
+        .ok_or(MathError::Overflow)?;
+    value
+        .checked_div(asset_unit)
+        .ok_or(MathError::DivisionByZero)
+}
+
+/// Adds up the terms of every reserve of a user into its account data, failing where the pool
+/// would revert
+pub fn calculate_account_data(
+    terms: impl IntoIterator<Item = AccountTerm>,
+) -> Result<AccountData, MathError> {
+    let mut total_collateral_in_base_currency = U256::ZERO;
+    let mut total_debt_in_base_currency = U256::ZERO;
+    let mut avg_ltv = U256::ZERO;
+    let mut avg_liquidation_threshold = U256::ZERO;
+
+    for term in terms {
+        total_collateral_in_base_currency = checked_add(
+            total_collateral_in_base_currency,
+            term.collateral_in_base_currency,
+        )?;
+        avg_ltv = checked_add(
+            avg_ltv,
+            checked_mul(term.collateral_in_base_currency, term.ltv)?,
+        )?;
+        avg_liquidation_threshold = checked_add(
+            avg_liquidation_threshold,
+            checked_mul(term.collateral_in_base_currency, term.liquidation_threshold)?,
+        )?;
+        total_debt_in_base_currency =
+            checked_add(total_debt_in_base_currency, term.debt_in_base_currency)?;
+    }
+
+    if total_collateral_in_base_currency != U256::ZERO {
+        avg_ltv /= total_collateral_in_base_currency;
+        avg_liquidation_threshold /= total_collateral_in_base_currency;
+    }
+
+    let health_factor = if total_debt_in_base_currency == U256::ZERO {
+        U256::MAX
+    } else {
+        wad_div(
+            percent_mul(total_collateral_in_base_currency, avg_liquidation_threshold)?,
+            total_debt_in_base_currency,
+        )?
+    };
+
+    // https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/logic/GenericLogic.sol#L193
+    let available_borrows_in_base_currency =
+        percent_mul(total_collateral_in_base_currency, avg_ltv)?
+            .saturating_sub(total_debt_in_base_currency);
+
+    Ok(AccountData {
+        total_collateral_in_base_currency,
+        total_debt_in_base_currency,
+        available_borrows_in_base_currency,
+        avg_ltv,
+        avg_liquidation_threshold,
+        health_factor,
+    })
+}
+
+fn checked_add(a: U256, b: U256) -> Result<U256, MathError> {
+    a.checked_add(b).ok_or(MathError::Overflow)
+}
+
+fn checked_mul(a: U256, b: U256) -> Result<U256, MathError> {
+    a.checked_mul(b).ok_or(MathError::Overflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Base currency has 8 decimals
+    fn base(amount: u64) -> U256 {
+        U256::from(amount) * U256::from(100_000_000)
+    }
+
+    fn term(collateral: u64, debt: u64, ltv: u64, liquidation_threshold: u64) -> AccountTerm {
+        AccountTerm {
+            collateral_in_base_currency: base(collateral),
+            debt_in_base_currency: base(debt),
+            ltv: U256::from(ltv),
+            liquidation_threshold: U256::from(liquidation_threshold),
+        }
+    }
+
+    #[test]
+    fn no_terms() {
+        assert_eq!(
+            calculate_account_data(std::iter::empty::<AccountTerm>()),
+            Ok(AccountData {
+                health_factor: U256::MAX,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn zero_debt() {
+        let account_data = calculate_account_data([term(1_000, 0, 7_500, 8_000)]).unwrap();
+        assert_eq!(account_data.health_factor, U256::MAX);
+        assert_eq!(account_data.total_debt_in_base_currency, U256::ZERO);
+        assert_eq!(account_data.available_borrows_in_base_currency, base(750));
+        assert_eq!(account_data.avg_liquidation_threshold, U256::from(8_000));
+    }
+
+    #[test]
+    fn zero_collateral() {
+        let account_data = calculate_account_data([term(0, 100, 0, 0)]).unwrap();
+        assert_eq!(account_data.health_factor, U256::ZERO);
+        assert_eq!(account_data.avg_ltv, U256::ZERO);
+        assert_eq!(account_data.avg_liquidation_threshold, U256::ZERO);
+        assert_eq!(account_data.available_borrows_in_base_currency, U256::ZERO);
+    }
+
+    #[test]
+    fn averages_weighted_by_collateral() {
+        let account_data = calculate_account_data([
+            term(1_000, 0, 7_000, 8_000),
+            term(3_000, 0, 8_000, 8_500),
+            term(0, 2_000, 0, 0),
+        ])
+        .unwrap();
+        assert_eq!(account_data.total_collateral_in_base_currency, base(4_000));
+        assert_eq!(account_data.total_debt_in_base_currency, base(2_000));
+        assert_eq!(account_data.avg_ltv, U256::from(7_750));
+        assert_eq!(account_data.avg_liquidation_threshold, U256::from(8_375));
+        // 4000 * 83.75% / 2000
+        assert_eq!(
+            account_data.health_factor,
+            U256::from(1_675_000_000_000_000_000u64)
+        );
+    }
+
+    #[test]
+    fn e_mode() {
+        // The same position is healthy with the e-mode parameters of the collateral and
+        // liquidatable with its own
+        let e_mode =
+            calculate_account_data([term(2_000, 0, 9_300, 9_500), term(0, 1_800, 9_300, 9_500)])
+                .unwrap();
+        assert_eq!(
+            e_mode.health_factor,
+            U256::from(1_055_555_555_555_555_556u64)
+        );
+        assert_eq!(e_mode.available_borrows_in_base_currency, base(60));
+        let no_e_mode =
+            calculate_account_data([term(2_000, 0, 7_800, 8_100), term(0, 1_800, 7_800, 8_100)])
+                .unwrap();
+        assert_eq!(
+            no_e_mode.health_factor,
+            U256::from(900_000_000_000_000_000u64)
+        );
+        assert_eq!(no_e_mode.available_borrows_in_base_currency, U256::ZERO);
+    }
+
+    #[test]
+    fn isolation() {
+        // Only the isolated asset counts as collateral, anything else supplied has no collateral
+        // value in its term
+        let account_data = calculate_account_data([
+            term(1_000, 0, 6_500, 7_000),
+            term(0, 0, 8_000, 8_500),
+            term(0, 500, 0, 0),
+        ])
+        .unwrap();
+        assert_eq!(account_data.total_collateral_in_base_currency, base(1_000));
+        assert_eq!(account_data.avg_ltv, U256::from(6_500));
+        assert_eq!(account_data.avg_liquidation_threshold, U256::from(7_000));
+        assert_eq!(
+            account_data.health_factor,
+            U256::from(1_400_000_000_000_000_000u64)
+        );
+        assert_eq!(account_data.available_borrows_in_base_currency, base(150));
+    }
+
+    #[test]
+    fn overflow() {
+        let huge = AccountTerm {
+            collateral_in_base_currency: U256::MAX,
+            ltv: U256::from(2),
+            ..Default::default()
+        };
+        assert_eq!(calculate_account_data([huge]), Err(MathError::Overflow));
+    }
+}
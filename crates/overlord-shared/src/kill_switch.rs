@@ -0,0 +1,135 @@
+use alloy::primitives::Address;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
+use tracing::{info, warn};
+
+use crate::market::AaveMarket;
+
+/// Path to the operator controlled "do not touch" list.
+///
+/// The file has one asset (reserve underlying) address per line, or a whole market as `market:`
+/// followed by its Pool or PoolAddressesProvider address. Empty lines and lines starting with `#`
+/// are ignored, and a line with a single `*` halts everything. The file is checked on every call,
+/// so editing it takes effect immediately without restarting any service.
+pub const KILL_SWITCH_FILE_ENV: &str = "OVERLORD_KILL_SWITCH_FILE";
+
+pub static KILL_SWITCH: Lazy<KillSwitch> = Lazy::new(KillSwitch::from_env);
+
+#[derive(Default)]
+struct KillSwitchState {
+    modified: Option<SystemTime>,
+    blocked_assets: HashSet<Address>,
+    /// Pool or PoolAddressesProvider addresses, whichever the operator wrote
+    blocked_markets: HashSet<Address>,
+    halt_all: bool,
+}
+
+pub struct KillSwitch {
+    path: Option<PathBuf>,
+    state: Mutex<KillSwitchState>,
+}
+
+impl KillSwitch {
+    pub fn from_env() -> Self {
+        let path = std::env::var(KILL_SWITCH_FILE_ENV).ok().map(PathBuf::from);
+        match &path {
+            Some(path) => info!("Kill switch list will be read from {}", path.display()),
+            None => info!("{} not set, kill switch is disabled", KILL_SWITCH_FILE_ENV),
+        }
+        Self {
+            path,
+            state: Mutex::new(KillSwitchState::default()),
+        }
+    }
+
+    /// True if the operator asked to stop everything
+    pub fn is_halted(&self) -> bool {
+        self.refresh().halt_all
+    }
+
+    /// True if the asset must not be evaluated nor liquidated
+    pub fn is_blocked(&self, asset: Address) -> bool {
+        let state = self.refresh();
+        state.halt_all || state.blocked_assets.contains(&asset)
+    }
+
+    /// True if nothing in the market must be evaluated nor liquidated
+    pub fn is_market_blocked(&self, market: &AaveMarket) -> bool {
+        let state = self.refresh();
+        state.halt_all
+            || state.blocked_markets.contains(&market.pool)
+            || state.blocked_markets.contains(&market.addresses_provider)
+    }
+
+    /// Returns the first blocked asset from `assets`, if any
+    pub fn first_blocked(&self, assets: impl IntoIterator<Item = Address>) -> Option<Address> {
+        let state = self.refresh();
+        assets
+            .into_iter()
+            .find(|asset| state.halt_all || state.blocked_assets.contains(asset))
+    }
+
+    /// Re-reads the list if the file changed since the last time we looked at it.
+    /// A missing file means nothing is blocked.
+    fn refresh(&self) -> MutexGuard<'_, KillSwitchState> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(path) = &self.path else {
+            return state;
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified == state.modified {
+            return state;
+        }
+        let contents = match modified {
+            Some(_) => match std::fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    warn!("Failed to read kill switch list {}: {}", path.display(), e);
+                    return state;
+                }
+            },
+            None => String::new(),
+        };
+        let mut blocked_assets = HashSet::new();
+        let mut blocked_markets = HashSet::new();
+        let mut halt_all = false;
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line == "*" {
+                halt_all = true;
+                continue;
+            }
+            let (entries, address) = match line.strip_prefix("market:") {
+                Some(market) => (&mut blocked_markets, market.trim()),
+                None => (&mut blocked_assets, line),
+            };
+            match Address::from_str(address) {
+                Ok(address) => {
+                    entries.insert(address);
+                }
+                Err(e) => warn!("Ignoring invalid kill switch entry '{}': {}", line, e),
+            }
+        }
+        info!(
+            halt_all = halt_all,
+            blocked_assets = ?blocked_assets,
+            blocked_markets = ?blocked_markets,
+            "Kill switch list reloaded"
+        );
+        *state = KillSwitchState {
+            modified,
+            blocked_assets,
+            blocked_markets,
+            halt_all,
+        };
+        state
+    }
+}
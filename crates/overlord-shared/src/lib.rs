@@ -1,40 +1,199 @@
-use alloy::primitives::{Address, Bytes, U256};
+use alloy::primitives::{Address, Bytes, B256, U256};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
+pub mod asset_filter;
+pub mod chain_profile;
+pub mod coalesce;
 pub mod common;
 pub mod constants;
+pub mod decimals_guard;
+pub mod error;
+pub mod feature_flags;
+#[cfg(feature = "fixtures")]
+pub mod fixtures;
+pub mod health_factor;
+pub mod kill_switch;
+pub mod market;
+pub mod math;
+pub mod multicall;
+pub mod reserve_configuration;
+pub mod resolvers;
+pub mod retry;
 pub mod sol_bindings;
+pub mod startup;
+pub mod status;
+use market::AaveMarket;
 use sol_bindings::pool::AaveV3Pool;
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct UnderwaterUserEvent {
     pub address: Address,
+    /// Aave instance the user was found in, where profito reads (and liquidates) the position
+    pub market: AaveMarket,
     pub trace_id: String,
+    /// Run of vega's pipeline that found the user, see new_run_id()
+    pub run_id: String,
     pub tx_hash: Option<String>,
-    pub raw_tx: Option<Bytes>,
+    /// None for users found by vega's initial sweep, which has no price update to backrun
+    pub price_update_tx: Option<PriceUpdateTx>,
     pub inclusion_block: String,
+    /// Unix time (in milliseconds) after which a bundle can't make `inclusion_block`. None if the
+    /// chain has no slots, or there's no price update.
+    pub slot_deadline_ms: Option<u64>,
     pub total_collateral_base: U256,
     pub user_account_data: AaveV3Pool::getUserAccountDataReturn,
     pub new_asset_prices: Vec<(Address, String, U256)>,
 }
 
+/// Identifies one run of vega's pipeline for `trace_id`. trace_id stays the same when the same
+/// price update is processed again (sent by several sources, or replayed), so logs, files and
+/// records of each run carry this instead to be told apart. It starts with the trace_id, so
+/// grepping for the trace_id still finds every run.
+pub fn new_run_id(trace_id: &str) -> String {
+    format!("{}-{:08x}", trace_id, rand::random::<u32>())
+}
+
+/// Sent by profito-rs back to vega-rs for every UnderwaterUserEvent it receives
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnderwaterUserAck {
+    pub trace_id: String,
+    pub address: Address,
+    /// PoolAddressesProvider of the market of the event, the same user can be underwater in more
+    /// than one
+    pub market: Address,
+}
+
+/// How a backrun bundle includes the price update tx
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PriceUpdateTx {
+    /// The signed tx, for updates seen in the public mempool
+    RawTx(Bytes),
+    /// The hash of a MEV-Share event. Its tx is private, so bundles can only include it by hash
+    /// (a `{hash}` bundle item, per the MEV-Share spec), which only MEV-Share can resolve.
+    HashOnly(B256),
+    /// No tx to backrun. Set on bundles oops derives from events that change positions without a
+    /// price update (like GHO discount changes), so bundles only carry the liquidation.
+    Synthetic,
+    /// No tx to backrun, the price lands through a call anyone can make (verifying a Data Streams
+    /// report, pushing a Pyth update). profito signs that call (`to`, `input`, with `value` wei
+    /// for its fee) and puts it in the bundle ahead of the liquidation.
+    Payload {
+        to: Address,
+        input: Bytes,
+        value: U256,
+    },
+    /// No tx to backrun, and nothing gets submitted either. Set on bundles injected with oops-cli
+    /// to rehearse a price move through the live pipeline: vega and profito handle them like any
+    /// synthetic update, but profito only logs the bundle it would have sent.
+    Rehearsal,
+}
+
+impl PriceUpdateTx {
+    pub fn raw_tx(&self) -> Option<&Bytes> {
+        match self {
+            PriceUpdateTx::RawTx(raw_tx) => Some(raw_tx),
+            PriceUpdateTx::HashOnly(_)
+            | PriceUpdateTx::Synthetic
+            | PriceUpdateTx::Payload { .. }
+            | PriceUpdateTx::Rehearsal => None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PriceUpdateBundle {
     pub trace_id: String, // This is just a string that helps trace the bundle through overlord
     pub tx_hash: String,  // The pending tx hash
-    pub raw_tx: Option<Bytes>, // The raw tx bytes
+    pub price_update_tx: PriceUpdateTx, // What backrun bundles include to land after the update
     pub inclusion_block: String,
     pub tx_new_price: U256, // The new price of the asset indicated by this update. Used by profito to calculate profit
     pub forward_to: Address, // Used in chainlink_address_to_asset mapping to determine which asset is affected by this tx
     pub tx_from: Address, // Used to recreate the price update tx. This is the address that submitted the forward() call.
     pub tx_to: Address, // Used to recreate the price update tx. This is the address that receives the forward() call.
     pub tx_input: Bytes, // Used to recreate the price update tx. These are the contents of the forward() call.
+    pub source: String, // Where oops found the tx ("mempool" or the name of a hint stream). Used to compare sources
+    pub slot_deadline_ms: Option<u64>, // Unix millis after which bundles can't make inclusion_block. None without slots
+}
+
+/// Why a pending price update won't land
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PriceUpdateCancelReason {
+    /// Another tx from the same sender with the same nonce was seen in the mempool, or landed.
+    /// None if it landed without ever being seen.
+    Replaced { by: Option<B256> },
+    /// The node no longer has the update in its pool and it didn't land
+    Dropped,
+}
+
+/// Sent by oops-rs when a price update it already forwarded is replaced or dropped from the
+/// mempool, so vega-rs and profito-rs stop acting on its price
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PriceUpdateCancellation {
+    pub trace_id: String, // Same as the PriceUpdateBundle being cancelled
+    pub tx_hash: String,
+    pub forward_to: Address,
+    pub reason: PriceUpdateCancelReason,
+}
+
+/// Sent by oops-rs to profito-rs for every pending tx or MEV-Share hint it sees calling an Aave
+/// pool, which tells how closely other searchers are watching it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PoolActivity {
+    pub tx_hash: String, // The pending tx hash, or the MEV-Share event hash
+    pub source: String,  // "mempool" or the name of the hint stream
+    pub pool: Address,
+    pub assets: Vec<Address>, // Collateral and debt of a liquidationCall() with visible calldata
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PriceRangeEstimate {
+    pub chainlink_address: Address, // One of the aggregators the forwarder transmits to
+    pub latest_answer: U256,        // Answer of the latest round at the time the hint was seen
+    pub low: U256,                  // Lowest price the pending update is expected to report
+    pub high: U256,                 // Highest price the pending update is expected to report
+}
+
+/// Sent instead of a PriceUpdateBundle when a MEV-Share event declares a forward() call but
+/// withholds the calldata, so the exact new price (and sometimes the exact feed) is unknown.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PriceUpdateHintBundle {
+    pub trace_id: String,
+    pub tx_hash: String, // The MEV-Share event hash
+    pub inclusion_block: String,
+    pub tx_to: Address, // The forwarder declared by the hint
+    pub price_ranges: Vec<PriceRangeEstimate>,
+    pub source: String, // The hint stream the MEV-Share event came from
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct NewPrice {
     pub price: U256,
     pub chainlink_address: Address,
+    /// Identifies the report within its aggregator
+    pub sequence: ReportSequence,
+}
+
+/// Where a report sits among the ones of its aggregator, from reportContext[1] of transmit()
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ReportSequence {
+    /// OCR2: 27 byte padding, 4-byte epoch and 1-byte round
+    EpochAndRound { epoch: u32, round: u8 },
+    /// OCR3 has no rounds: 24 byte padding and an 8-byte sequence number
+    SequenceNumber(u64),
+}
+
+impl fmt::Display for ReportSequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReportSequence::EpochAndRound { epoch, round } => {
+                write!(f, "epoch {} round {}", epoch, round)
+            }
+            ReportSequence::SequenceNumber(sequence_number) => {
+                write!(f, "sequence number {}", sequence_number)
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -43,6 +202,21 @@ pub enum WhistleblowerEventType {
     Borrow,
     Supply,
     Repay,
+    Withdraw,
+    // The user is the second arg, after the reserve
+    ReserveUsedAsCollateralEnabled,
+    ReserveUsedAsCollateralDisabled,
+    SwapBorrowRateMode,
+    RebalanceStableBorrowRate,
+    // The user is the first arg, followed by the new category
+    UserEModeSet,
+    // Emitted by the PoolConfigurator, the reserve is always the first arg
+    ReserveInitialized,
+    CollateralConfigurationChanged,
+    ReserveFrozen,
+    ReservePaused,
+    // Emitted by aTokens and variable debt tokens, args are the reserve, from, to and amount
+    Transfer,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -55,11 +229,79 @@ pub struct WhistleblowerEventDetails {
 pub struct WhistleblowerUpdate {
     pub trace_id: String, // First few chars of tx hash
     pub block_number: u64,
+    pub market: Address, // PoolAddressesProvider of the Aave instance that emitted the event
     pub event_details: WhistleblowerEventDetails,
 }
 
+/// Sent by whistleblower-rs when the AaveOracle points an asset to a new price source, so the
+/// services that resolved the old one can follow the change.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AssetSourceUpdate {
+    pub trace_id: String, // First few chars of tx hash
+    pub block_number: u64,
+    pub market: Address, // PoolAddressesProvider of the Aave instance whose oracle changed
+    pub asset: Address,
+    pub source: Address,
+    /// OCR aggregator behind the new source. None for GHO, or if the source couldn't be resolved.
+    pub aggregator: Option<Address>,
+    /// Transmitters of the aggregator, i.e. the forwarders its price updates will go through
+    pub transmitters: Vec<Address>,
+}
+
+/// Decides the order users are evaluated in. High priority users go before any normal one,
+/// whatever their last known health factor.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchlistPriority {
+    High,
+    #[default]
+    Normal,
+}
+
+/// A user close to liquidation, as exported by vega-rs or curated by external risk tooling
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WatchlistEntry {
+    pub user: Address,
+    /// Last health factor vega computed (1e18 = 1). Ignored on import.
+    #[serde(default)]
+    pub health_factor: Option<U256>,
+    /// Reserves the user supplies as collateral or borrows. Ignored on import.
+    #[serde(default)]
+    pub assets: Vec<Address>,
+    #[serde(default)]
+    pub priority: WatchlistPriority,
+    /// Name of the Aave instance the user is tracked in, the core market if missing
+    #[serde(default)]
+    pub market: Option<String>,
+}
+
+/// Asks vega-rs to write its watchlist to `path` (json), with every user whose last known health
+/// factor is below `max_health_factor` plus every user with a priority other than normal
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WatchlistExportRequest {
+    pub path: String,
+    pub max_health_factor: U256,
+}
+
+/// Asks vega-rs to undo every change to its user cache made by updates of the blocks after
+/// `block_number`, in the market named `market` or in all of them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheUndoRequest {
+    pub block_number: u64,
+    pub market: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum MessageBundle {
     PriceUpdate(PriceUpdateBundle),
+    PriceUpdateHint(PriceUpdateHintBundle),
+    PriceUpdateCancelled(PriceUpdateCancellation),
     WhistleblowerNotification(WhistleblowerUpdate),
+    AssetSourceUpdate(AssetSourceUpdate),
+    WatchlistExport(WatchlistExportRequest),
+    /// Users to track (if they aren't already) with the given priority
+    WatchlistImport(Vec<WatchlistEntry>),
+    CacheUndo(CacheUndoRequest),
 }
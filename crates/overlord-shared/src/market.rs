@@ -0,0 +1,192 @@
+use alloy::{
+    primitives::{address, Address},
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, sync::Arc};
+use tracing::{error, info, warn};
+
+use crate::constants::{
+    AAVE_ORACLE_ADDRESS, AAVE_V3_POOL_ADDRESS, AAVE_V3_POOL_CONFIGURATOR_ADDRESS,
+    AAVE_V3_POOL_DEPLOYMENT_BLOCK, AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS,
+    AAVE_V3_PROVIDER_ADDRESS,
+};
+use crate::sol_bindings::IPoolAddressesProvider;
+
+/// Comma-separated names of the markets to follow, see KNOWN_MARKETS. Defaults to all of them.
+pub const MARKETS_ENV: &str = "OVERLORD_AAVE_MARKETS";
+
+/// An Aave v3 instance everyone knows by its PoolAddressesProvider, which every other address of
+/// the instance is resolved from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MarketPreset {
+    pub name: &'static str,
+    pub addresses_provider: Address,
+    /// No pool events exist before this block, so log scans can start here
+    pub deployment_block: u64,
+}
+
+pub const CORE_MARKET: MarketPreset = MarketPreset {
+    name: "core",
+    addresses_provider: AAVE_V3_PROVIDER_ADDRESS,
+    deployment_block: AAVE_V3_POOL_DEPLOYMENT_BLOCK,
+};
+
+// Both instances were deployed in the second half of 2024. Log scans start a bit before that,
+// which only costs a few empty ranges.
+pub const LIDO_MARKET: MarketPreset = MarketPreset {
+    name: "lido",
+    addresses_provider: address!("cfBf336fe147D643B9Cb705648500e101504B16d"),
+    deployment_block: 20_000_000,
+};
+
+pub const ETHERFI_MARKET: MarketPreset = MarketPreset {
+    name: "etherfi",
+    addresses_provider: address!("eBa440B438Ad808101d1c451C1C5322c90BEFCdA"),
+    deployment_block: 20_000_000,
+};
+
+const KNOWN_MARKETS: [MarketPreset; 3] = [CORE_MARKET, LIDO_MARKET, ETHERFI_MARKET];
+
+/// The addresses of an Aave v3 instance on Ethereum. Each instance has its own pool, listings,
+/// oracle and users, so positions on one are invisible to the contracts of another.
+///
+/// Carried by the events vega sends profito, so every read of a liquidation opportunity goes to
+/// the instance the user was found in.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AaveMarket {
+    pub name: String,
+    /// PoolAddressesProvider of the instance. The UI pool data provider takes it to tell instances
+    /// apart, and it identifies the market in messages.
+    pub addresses_provider: Address,
+    pub pool: Address,
+    pub pool_configurator: Address,
+    pub oracle: Address,
+    /// AaveProtocolDataProvider
+    pub data_provider: Address,
+    pub deployment_block: u64,
+}
+
+impl AaveMarket {
+    /// The main instance, the one every address in constants belongs to
+    pub fn core() -> Self {
+        Self {
+            name: CORE_MARKET.name.to_string(),
+            addresses_provider: CORE_MARKET.addresses_provider,
+            pool: AAVE_V3_POOL_ADDRESS,
+            pool_configurator: AAVE_V3_POOL_CONFIGURATOR_ADDRESS,
+            oracle: AAVE_ORACLE_ADDRESS,
+            data_provider: AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS,
+            deployment_block: CORE_MARKET.deployment_block,
+        }
+    }
+
+    pub fn is_core(&self) -> bool {
+        self.addresses_provider == CORE_MARKET.addresses_provider
+    }
+
+    /// Reads the addresses of the instance behind `preset` from its PoolAddressesProvider
+    pub async fn resolve(
+        provider: Arc<RootProvider<PubSubFrontend>>,
+        preset: MarketPreset,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if preset == CORE_MARKET {
+            return Ok(Self::core());
+        }
+        let addresses_provider =
+            IPoolAddressesProvider::new(preset.addresses_provider, provider.clone());
+        let pool = match addresses_provider.getPool().call().await {
+            Ok(response) => response._0,
+            Err(e) => return Err(format!("Error trying to call getPool: {}", e).into()),
+        };
+        let pool_configurator = match addresses_provider.getPoolConfigurator().call().await {
+            Ok(response) => response._0,
+            Err(e) => return Err(format!("Error trying to call getPoolConfigurator: {}", e).into()),
+        };
+        let oracle = match addresses_provider.getPriceOracle().call().await {
+            Ok(response) => response._0,
+            Err(e) => return Err(format!("Error trying to call getPriceOracle: {}", e).into()),
+        };
+        let data_provider = match addresses_provider.getPoolDataProvider().call().await {
+            Ok(response) => response._0,
+            Err(e) => return Err(format!("Error trying to call getPoolDataProvider: {}", e).into()),
+        };
+        Ok(Self {
+            name: preset.name.to_string(),
+            addresses_provider: preset.addresses_provider,
+            pool,
+            pool_configurator,
+            oracle,
+            data_provider,
+            deployment_block: preset.deployment_block,
+        })
+    }
+
+    /// `path` with the market name appended to its file stem, so files written per market (like
+    /// checkpoints and snapshots) don't overwrite each other. The core market keeps `path` as is,
+    /// so files written before there were other markets are still found.
+    pub fn scoped_path(&self, path: &str) -> String {
+        if self.is_core() {
+            return path.to_string();
+        }
+        let path = Path::new(path);
+        let stem = path
+            .file_stem()
+            .map_or(String::new(), |stem| stem.to_string_lossy().to_string());
+        let file_name = match path.extension() {
+            Some(extension) => format!("{}-{}.{}", stem, self.name, extension.to_string_lossy()),
+            None => format!("{}-{}", stem, self.name),
+        };
+        path.with_file_name(file_name).to_string_lossy().to_string()
+    }
+}
+
+/// Presets of the markets in MARKETS_ENV, or every known market if it isn't set. Unknown names
+/// are logged and skipped, and the core market is always followed.
+pub fn market_presets_from_env() -> Vec<MarketPreset> {
+    let Ok(value) = std::env::var(MARKETS_ENV) else {
+        return KNOWN_MARKETS.to_vec();
+    };
+    let mut presets = vec![CORE_MARKET];
+    for name in value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        match KNOWN_MARKETS
+            .iter()
+            .find(|preset| preset.name.eq_ignore_ascii_case(name))
+        {
+            Some(preset) if !presets.contains(preset) => presets.push(*preset),
+            Some(_) => {}
+            None => warn!("Ignoring unknown market {} in {}", name, MARKETS_ENV),
+        }
+    }
+    presets
+}
+
+/// Resolves every market in MARKETS_ENV. Markets whose addresses can't be read are left out, so
+/// a flaky read of one instance doesn't take the others down. The core market comes first and
+/// never fails, its addresses are constants.
+pub async fn resolve_markets_from_env(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+) -> Vec<AaveMarket> {
+    let mut markets = vec![];
+    for preset in market_presets_from_env() {
+        match AaveMarket::resolve(provider.clone(), preset).await {
+            Ok(market) => {
+                info!(
+                    "Following the {} market (pool {}, oracle {})",
+                    market.name, market.pool, market.oracle
+                );
+                markets.push(market);
+            }
+            Err(e) => error!(
+                "Failed to resolve the addresses of the {} market, it won't be followed: {}",
+                preset.name, e
+            ),
+        }
+    }
+    markets
+}
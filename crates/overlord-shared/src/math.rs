@@ -0,0 +1,225 @@
+//! Overflow-checked versions of Aave's WadRayMath and PercentageMath.
+//!
+//! U256 arithmetic wraps on overflow, so a plain `(a * b + HALF_RAY) / RAY` silently returns
+//! garbage where the pool would revert. These functions return an error on exactly the inputs
+//! the Solidity libraries revert on, and the same result everywhere else.
+
+use alloy::primitives::U256;
+use std::fmt;
+
+pub const WAD: U256 = U256::from_limbs([1_000_000_000_000_000_000, 0, 0, 0]); // 1e18
+pub const HALF_WAD: U256 = U256::from_limbs([500_000_000_000_000_000, 0, 0, 0]); // 0.5e18
+pub const RAY: U256 = U256::from_limbs([0x9fd0803ce8000000, 0x33b2e3c, 0, 0]); // 1e27
+pub const HALF_RAY: U256 = U256::from_limbs([0x4fe8401e74000000, 0x19d971e, 0, 0]); // 0.5e27
+pub const PERCENTAGE_FACTOR: U256 = U256::from_limbs([10_000, 0, 0, 0]); // 100.00%
+pub const HALF_PERCENTAGE_FACTOR: U256 = U256::from_limbs([5_000, 0, 0, 0]); // 50.00%
+
+/// Why an operation would have reverted on-chain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MathError {
+    Overflow,
+    DivisionByZero,
+}
+
+impl fmt::Display for MathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MathError::Overflow => write!(f, "arithmetic overflow"),
+            MathError::DivisionByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+impl std::error::Error for MathError {}
+
+/// `(a * b + half_unit) / unit`, reverting if `a * b + half_unit` doesn't fit in 256 bits
+fn checked_mul_rounded(a: U256, b: U256, unit: U256, half_unit: U256) -> Result<U256, MathError> {
+    if !b.is_zero() && a > (U256::MAX - half_unit) / b {
+        return Err(MathError::Overflow);
+    }
+    Ok((a * b + half_unit) / unit)
+}
+
+/// `(a * unit + b / 2) / b`, reverting if `b` is zero or `a * unit + b / 2` doesn't fit in 256 bits
+fn checked_div_rounded(a: U256, b: U256, unit: U256) -> Result<U256, MathError> {
+    if b.is_zero() {
+        return Err(MathError::DivisionByZero);
+    }
+    let half_b = b / U256::from(2);
+    if a > (U256::MAX - half_b) / unit {
+        return Err(MathError::Overflow);
+    }
+    Ok((a * unit + half_b) / b)
+}
+
+/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/math/WadRayMath.sol#L29
+pub fn wad_mul(a: U256, b: U256) -> Result<U256, MathError> {
+    checked_mul_rounded(a, b, WAD, HALF_WAD)
+}
+
+/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/math/WadRayMath.sol#L47
+pub fn wad_div(a: U256, b: U256) -> Result<U256, MathError> {
+    checked_div_rounded(a, b, WAD)
+}
+
+/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/math/WadRayMath.sol#L65
+pub fn ray_mul(a: U256, b: U256) -> Result<U256, MathError> {
+    checked_mul_rounded(a, b, RAY, HALF_RAY)
+}
+
+/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/math/WadRayMath.sol#L83
+pub fn ray_div(a: U256, b: U256) -> Result<U256, MathError> {
+    checked_div_rounded(a, b, RAY)
+}
+
+/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/math/PercentageMath.sol#L25
+pub fn percent_mul(value: U256, percentage: U256) -> Result<U256, MathError> {
+    checked_mul_rounded(value, percentage, PERCENTAGE_FACTOR, HALF_PERCENTAGE_FACTOR)
+}
+
+/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/math/PercentageMath.sol#L48
+pub fn percent_div(value: U256, percentage: U256) -> Result<U256, MathError> {
+    checked_div_rounded(value, percentage, PERCENTAGE_FACTOR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::U512;
+
+    fn one() -> U256 {
+        U256::from(1)
+    }
+
+    #[test]
+    fn wad_mul_vectors() {
+        assert_eq!(wad_mul(WAD, WAD), Ok(WAD));
+        assert_eq!(wad_mul(U256::MAX, U256::ZERO), Ok(U256::ZERO));
+        assert_eq!(wad_mul(U256::MAX, one()), Err(MathError::Overflow));
+    }
+
+    #[test]
+    fn wad_div_vectors() {
+        assert_eq!(wad_div(WAD, WAD), Ok(WAD));
+        assert_eq!(wad_div(one(), U256::ZERO), Err(MathError::DivisionByZero));
+        assert_eq!(wad_div(U256::MAX / WAD, one()), Ok(U256::MAX / WAD * WAD));
+        assert_eq!(
+            wad_div(U256::MAX / WAD + one(), one()),
+            Err(MathError::Overflow)
+        );
+    }
+
+    #[test]
+    fn ray_mul_vectors() {
+        let max_operand = (U256::MAX - HALF_RAY) / U256::from(2);
+        assert_eq!(ray_mul(RAY, RAY), Ok(RAY));
+        assert_eq!(ray_mul(U256::MAX, U256::ZERO), Ok(U256::ZERO));
+        assert_eq!(ray_mul(U256::MAX, one()), Err(MathError::Overflow));
+        assert_eq!(
+            ray_mul(max_operand, U256::from(2)),
+            Ok(
+                U256::from_str_radix("115792089237316195423570985008687907853269984665640", 10)
+                    .unwrap()
+            )
+        );
+        assert_eq!(
+            ray_mul(max_operand + one(), U256::from(2)),
+            Err(MathError::Overflow)
+        );
+    }
+
+    #[test]
+    fn ray_div_vectors() {
+        assert_eq!(ray_div(RAY, RAY), Ok(RAY));
+        assert_eq!(ray_div(one(), U256::ZERO), Err(MathError::DivisionByZero));
+    }
+
+    #[test]
+    fn percent_mul_vectors() {
+        assert_eq!(
+            percent_mul(U256::from(10_000), U256::from(5_000)),
+            Ok(U256::from(5_000))
+        );
+        // half rounds up
+        assert_eq!(percent_mul(one(), U256::from(5_000)), Ok(one()));
+        assert_eq!(percent_mul(one(), U256::from(4_999)), Ok(U256::ZERO));
+        assert_eq!(percent_mul(U256::MAX, U256::ZERO), Ok(U256::ZERO));
+        assert_eq!(percent_mul(U256::MAX, one()), Err(MathError::Overflow));
+    }
+
+    #[test]
+    fn percent_div_vectors() {
+        assert_eq!(
+            percent_div(U256::from(5_000), U256::from(5_000)),
+            Ok(U256::from(10_000))
+        );
+        assert_eq!(
+            percent_div(one(), U256::ZERO),
+            Err(MathError::DivisionByZero)
+        );
+        assert_eq!(
+            percent_div(U256::MAX / PERCENTAGE_FACTOR + one(), one()),
+            Err(MathError::Overflow)
+        );
+    }
+
+    /// The same operation on 512 bits, where it can't wrap. It overflows exactly when the
+    /// intermediate doesn't fit in 256 bits, which is what the Solidity revert conditions check.
+    fn reference_mul(a: U256, b: U256, unit: U256, half_unit: U256) -> Result<U256, MathError> {
+        let numerator = U512::from(a) * U512::from(b) + U512::from(half_unit);
+        if numerator > U512::from(U256::MAX) {
+            return Err(MathError::Overflow);
+        }
+        Ok(U256::from(numerator / U512::from(unit)))
+    }
+
+    fn reference_div(a: U256, b: U256, unit: U256) -> Result<U256, MathError> {
+        if b.is_zero() {
+            return Err(MathError::DivisionByZero);
+        }
+        let numerator = U512::from(a) * U512::from(unit) + U512::from(b / U256::from(2));
+        if numerator > U512::from(U256::MAX) {
+            return Err(MathError::Overflow);
+        }
+        Ok(U256::from(numerator / U512::from(b)))
+    }
+
+    /// xorshift64* values of random bit length, so small, mid-sized and huge inputs all show up
+    struct Inputs(u64);
+
+    impl Inputs {
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn next_u256(&mut self) -> U256 {
+            let value = U256::from_limbs([
+                self.next_u64(),
+                self.next_u64(),
+                self.next_u64(),
+                self.next_u64(),
+            ]);
+            value >> (self.next_u64() % 257) as usize
+        }
+    }
+
+    #[test]
+    fn matches_the_512_bit_reference() {
+        let mut inputs = Inputs(42);
+        for _ in 0..10_000 {
+            let (a, b) = (inputs.next_u256(), inputs.next_u256());
+            assert_eq!(wad_mul(a, b), reference_mul(a, b, WAD, HALF_WAD));
+            assert_eq!(wad_div(a, b), reference_div(a, b, WAD));
+            assert_eq!(ray_mul(a, b), reference_mul(a, b, RAY, HALF_RAY));
+            assert_eq!(ray_div(a, b), reference_div(a, b, RAY));
+            assert_eq!(
+                percent_mul(a, b),
+                reference_mul(a, b, PERCENTAGE_FACTOR, HALF_PERCENTAGE_FACTOR)
+            );
+            assert_eq!(percent_div(a, b), reference_div(a, b, PERCENTAGE_FACTOR));
+        }
+    }
+}
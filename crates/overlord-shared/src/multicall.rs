@@ -0,0 +1,240 @@
+use alloy::{
+    network::TransactionBuilder,
+    primitives::{Address, U256},
+    providers::Provider,
+    rpc::types::{state::StateOverride, TransactionRequest},
+    sol_types::SolCall,
+    transports::Transport,
+};
+
+use crate::constants::{AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS, MULTICALL3_ADDRESS};
+use crate::market::AaveMarket;
+use crate::sol_bindings::{
+    pool::AaveV3Pool,
+    AaveUIPoolDataProvider,
+    IMulticall3::{self, Call3, Call3Result},
+    IUiPoolDataProviderV3::UserReserveData,
+};
+
+// Batches have to fit in the node's eth_call gas cap (50M by default on reth).
+// getUserAccountData costs up to ~150k gas for users with many positions,
+// while getUserReservesData loops over every reserve of the market, so it's a lot heavier.
+pub const USER_ACCOUNT_DATA_BATCH_SIZE: usize = 250;
+pub const USER_RESERVES_DATA_BATCH_SIZE: usize = 100;
+
+/// Why a multicall failed as a whole (the node call itself, or decoding its results)
+pub type MulticallError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Sends all `calls` in a single Multicall3.aggregate3() eth_call. Every call is allowed to fail,
+/// so the result of each one has to be checked individually.
+async fn aggregate3<T, P>(
+    provider: &P,
+    calls: Vec<Call3>,
+    state_override: Option<&StateOverride>,
+) -> Result<Vec<Call3Result>, MulticallError>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    let tx = TransactionRequest::default()
+        .with_to(MULTICALL3_ADDRESS)
+        .with_input(IMulticall3::aggregate3Call { calls }.abi_encode());
+    let output = match state_override {
+        Some(overrides) => provider.call(&tx).overrides(overrides).await?,
+        None => provider.call(&tx).await?,
+    };
+    Ok(IMulticall3::aggregate3Call::abi_decode_returns(&output, true)?.returnData)
+}
+
+/// Fetches getUserAccountData() for `users` in a single call. The result is aligned with `users`,
+/// with None for the users whose call reverted. Callers should keep `users` under
+/// USER_ACCOUNT_DATA_BATCH_SIZE.
+pub async fn get_user_account_data_batch<T, P>(
+    provider: &P,
+    market: &AaveMarket,
+    users: &[Address],
+    state_override: Option<&StateOverride>,
+) -> Result<Vec<Option<AaveV3Pool::getUserAccountDataReturn>>, MulticallError>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    let calls = users
+        .iter()
+        .map(|user| Call3 {
+            target: market.pool,
+            allowFailure: true,
+            callData: AaveV3Pool::getUserAccountDataCall { user: *user }
+                .abi_encode()
+                .into(),
+        })
+        .collect();
+    let results = aggregate3(provider, calls, state_override).await?;
+    if results.len() != users.len() {
+        return Err(format!(
+            "Multicall returned {} results for {} getUserAccountData calls",
+            results.len(),
+            users.len()
+        )
+        .into());
+    }
+    Ok(results
+        .into_iter()
+        .map(|result| {
+            if !result.success {
+                return None;
+            }
+            AaveV3Pool::getUserAccountDataCall::abi_decode_returns(&result.returnData, true).ok()
+        })
+        .collect())
+}
+
+/// Fetches getUserReservesData() for `users` in a single call. The result is aligned with `users`,
+/// with None for the users whose call reverted. Callers should keep `users` under
+/// USER_RESERVES_DATA_BATCH_SIZE.
+pub async fn get_user_reserves_data_batch<T, P>(
+    provider: &P,
+    market: &AaveMarket,
+    users: &[Address],
+) -> Result<Vec<Option<Vec<UserReserveData>>>, MulticallError>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    let calls = users
+        .iter()
+        .map(|user| Call3 {
+            target: AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS,
+            allowFailure: true,
+            callData: AaveUIPoolDataProvider::getUserReservesDataCall {
+                provider: market.addresses_provider,
+                user: *user,
+            }
+            .abi_encode()
+            .into(),
+        })
+        .collect();
+    let results = aggregate3(provider, calls, None).await?;
+    if results.len() != users.len() {
+        return Err(format!(
+            "Multicall returned {} results for {} getUserReservesData calls",
+            results.len(),
+            users.len()
+        )
+        .into());
+    }
+    Ok(results
+        .into_iter()
+        .map(|result| {
+            if !result.success {
+                return None;
+            }
+            AaveUIPoolDataProvider::getUserReservesDataCall::abi_decode_returns(
+                &result.returnData,
+                true,
+            )
+            .ok()
+            .map(|data| data._0)
+        })
+        .collect())
+}
+
+/// Fetches getUserEMode() for `users` in a single call. The result is aligned with `users`, with
+/// None for the users whose call reverted. Callers should keep `users` under
+/// USER_ACCOUNT_DATA_BATCH_SIZE.
+pub async fn get_user_emode_batch<T, P>(
+    provider: &P,
+    market: &AaveMarket,
+    users: &[Address],
+) -> Result<Vec<Option<u8>>, MulticallError>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    let calls = users
+        .iter()
+        .map(|user| Call3 {
+            target: market.pool,
+            allowFailure: true,
+            callData: AaveV3Pool::getUserEModeCall { user: *user }
+                .abi_encode()
+                .into(),
+        })
+        .collect();
+    let results = aggregate3(provider, calls, None).await?;
+    if results.len() != users.len() {
+        return Err(format!(
+            "Multicall returned {} results for {} getUserEMode calls",
+            results.len(),
+            users.len()
+        )
+        .into());
+    }
+    Ok(results
+        .into_iter()
+        .map(|result| {
+            if !result.success {
+                return None;
+            }
+            AaveV3Pool::getUserEModeCall::abi_decode_returns(&result.returnData, true)
+                .ok()
+                .map(|data| data._0.saturating_to::<u8>())
+        })
+        .collect())
+}
+
+/// Fetches getReserveNormalizedIncome() and getReserveNormalizedVariableDebt() for `assets` in a
+/// single call. The result is aligned with `assets`, with None for the reserves whose calls
+/// reverted.
+pub async fn get_reserve_normalized_indexes_batch<T, P>(
+    provider: &P,
+    market: &AaveMarket,
+    assets: &[Address],
+) -> Result<Vec<Option<(U256, U256)>>, MulticallError>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    let calls = assets
+        .iter()
+        .flat_map(|asset| {
+            [
+                AaveV3Pool::getReserveNormalizedIncomeCall { asset: *asset }.abi_encode(),
+                AaveV3Pool::getReserveNormalizedVariableDebtCall { asset: *asset }.abi_encode(),
+            ]
+        })
+        .map(|call_data| Call3 {
+            target: market.pool,
+            allowFailure: true,
+            callData: call_data.into(),
+        })
+        .collect();
+    let results = aggregate3(provider, calls, None).await?;
+    if results.len() != assets.len() * 2 {
+        return Err(format!(
+            "Multicall returned {} results for {} normalized index calls",
+            results.len(),
+            assets.len() * 2
+        )
+        .into());
+    }
+    Ok(results
+        .chunks(2)
+        .map(|results| {
+            if !results[0].success || !results[1].success {
+                return None;
+            }
+            let income = AaveV3Pool::getReserveNormalizedIncomeCall::abi_decode_returns(
+                &results[0].returnData,
+                true,
+            )
+            .ok()?;
+            let debt = AaveV3Pool::getReserveNormalizedVariableDebtCall::abi_decode_returns(
+                &results[1].returnData,
+                true,
+            )
+            .ok()?;
+            Some((income._0, debt._0))
+        })
+        .collect())
+}
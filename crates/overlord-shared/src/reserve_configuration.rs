@@ -0,0 +1,84 @@
+use alloy::primitives::U256;
+
+use crate::sol_bindings::IUiPoolDataProviderV3::AggregatedReserveData;
+
+// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/configuration/ReserveConfiguration.sol#L12-L48
+const LIQUIDATION_THRESHOLD_START_BIT_POSITION: usize = 16;
+const LIQUIDATION_THRESHOLD_MASK: u64 = 0xFFFF;
+const RESERVE_DECIMALS_START_BIT_POSITION: usize = 48;
+const RESERVE_DECIMALS_MASK: u64 = 0xFF;
+const BORROWABLE_IN_ISOLATION_BIT_POSITION: usize = 61;
+const SILOED_BORROWING_BIT_POSITION: usize = 62;
+const DEBT_CEILING_START_BIT_POSITION: usize = 212;
+const DEBT_CEILING_MASK: u64 = 0xFF_FFFF_FFFF;
+
+/// The parts of a reserve configuration bitmap that decide which pairs of a user can be liquidated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReserveConfiguration {
+    /// In basis points. The pool refuses to liquidate a collateral whose threshold is 0.
+    pub liquidation_threshold: u64,
+    pub decimals: u64,
+    /// Whether the asset can be borrowed against isolated collateral
+    pub borrowable_in_isolation: bool,
+    /// Whether a user borrowing the asset can't borrow anything else
+    pub siloed_borrowing: bool,
+    /// With 2 decimals. Non zero for isolated assets, which are the only collateral of the users
+    /// supplying them as collateral.
+    pub debt_ceiling: u64,
+}
+
+impl ReserveConfiguration {
+    /// Decodes the bitmap returned by the pool's getConfiguration()
+    pub fn decode(bitmap: U256) -> Self {
+        let bits = |start: usize, mask: u64| (bitmap >> start).as_limbs()[0] & mask;
+        Self {
+            liquidation_threshold: bits(
+                LIQUIDATION_THRESHOLD_START_BIT_POSITION,
+                LIQUIDATION_THRESHOLD_MASK,
+            ),
+            decimals: bits(RESERVE_DECIMALS_START_BIT_POSITION, RESERVE_DECIMALS_MASK),
+            borrowable_in_isolation: bitmap.bit(BORROWABLE_IN_ISOLATION_BIT_POSITION),
+            siloed_borrowing: bitmap.bit(SILOED_BORROWING_BIT_POSITION),
+            debt_ceiling: bits(DEBT_CEILING_START_BIT_POSITION, DEBT_CEILING_MASK),
+        }
+    }
+
+    /// The same values, as getReservesData() already decodes them
+    pub fn from_reserve_data(reserve: &AggregatedReserveData) -> Self {
+        Self {
+            liquidation_threshold: reserve.reserveLiquidationThreshold.saturating_to(),
+            decimals: reserve.decimals.saturating_to(),
+            borrowable_in_isolation: reserve.borrowableInIsolation,
+            siloed_borrowing: reserve.isSiloedBorrowing,
+            debt_ceiling: reserve.debtCeiling.saturating_to(),
+        }
+    }
+
+    pub fn is_isolated(&self) -> bool {
+        self.debt_ceiling != 0
+    }
+
+    /// Reason why liquidations taking this reserve as collateral revert, if any. Mirrors the
+    /// COLLATERAL_CANNOT_BE_LIQUIDATED check of validateLiquidationCall(), which only looks at the
+    /// threshold of the reserve (not the one of the user's e-mode). Isolated assets are usually
+    /// offboarded this way, leaving their suppliers with a health factor of 0 nobody can liquidate.
+    /// https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/ValidationLogic.sol
+    ///
+    /// Isolation mode and siloed borrowing don't block liquidations otherwise. They restrict
+    /// which collaterals and debts a user can have at once, and the user's positions already
+    /// reflect that.
+    pub fn get_collateral_blocker(&self) -> Option<String> {
+        if self.liquidation_threshold != 0 {
+            return None;
+        }
+        Some(if self.is_isolated() {
+            format!(
+                "it's isolated (debt ceiling {}.{:02}) and its liquidation threshold is 0",
+                self.debt_ceiling / 100,
+                self.debt_ceiling % 100
+            )
+        } else {
+            "its liquidation threshold is 0".to_string()
+        })
+    }
+}
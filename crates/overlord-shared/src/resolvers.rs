@@ -0,0 +1,589 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use alloy::{
+    primitives::{address, aliases::I192, Address},
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::sol_bindings::{
+    sDAIAggregator::sDAISynchronicityPriceAdapter, AccessControlledOCR2Aggregator,
+    CLSynchronicityPriceAdapterPegToBase, EACAggregatorProxy, PriceCapAdapterStable,
+    WstETHAggregator::WstETHPriceCapAdapter,
+};
+
+/// These are living data structures, in the sense that will need to be updated if a new asset is onboarded
+/// into AAVE v3.
+/// If we don't have a resolver for that type, then a resolver function would need to be added as well,
+/// so that it can return the address of the EACAggreagator at the root of the hierarchy.
+/// Price sources that aren't listed are probed instead (see probe_adapter_chain()), so a new asset
+/// works in the meantime, at the cost of a few failed calls the first time it's resolved.
+pub static EAC_AGGREGATOR_PROXY_ORACLES: Lazy<Vec<Address>> = Lazy::new(|| {
+    let oracles = vec![
+        address!("5f4eC3Df9cbd43714FE2740f5E3616155c5b8419"),
+        address!("Cd627aA160A6fA45Eb793D19Ef54f5062F20f33f"),
+        address!("ec1D1B3b0443256cc3860e24a46F108e699484Aa"),
+        address!("DC3EA94CD0AC27d9A86C180091e7f78C683d3699"),
+        address!("dF2917806E30300537aEB49A7663062F4d1F2b5F"),
+        address!("553303d460EE0afB37EdFf9bE42922D8FF63220e"),
+        address!("5C00128d4d1c2F4f652C267d7bcdD7aC99C16E16"),
+        address!("c929ad75B72593967DE83E7F7Cda0493458261D9"),
+        address!("4E155eD98aFE9034b7A5962f6C84c86d869daA9d"),
+        address!("7A9f34a0Aa917D438e9b6E630067062B7F8f6f3d"),
+        address!("f8fF43E991A81e6eC886a3D281A2C6cC19aE70Fc"),
+        address!("6Ebc52C8C1089be9eB3945C4350B68B8E4C2233f"),
+        address!("F4030086522a5bEEa4988F8cA5B36dbC97BeE88c"),
+        address!("C7e9b623ed51F033b32AE7f1282b1AD62C28C183"),
+        address!("F02C1e2A3B77c1cacC72f72B44f7d0a4c62e4a85"),
+        address!("b41E773f507F7a7EA890b1afB7d2b660c30C8B0A"),
+    ];
+    oracles
+});
+
+pub static PRICE_CAP_ADAPTER_STABLE_ORACLES: Lazy<Vec<Address>> = Lazy::new(|| {
+    let oracles = vec![
+        address!("736bF902680e68989886e9807CD7Db4B3E015d3C"),
+        address!("4F01b76391A05d32B20FA2d05dD5963eE8db20E6"),
+        address!("aEb897E1Dc6BbdceD3B9D551C71a8cf172F27AC4"),
+        address!("C26D4a1c46d884cfF6dE9800B6aE7A8Cf48B4Ff8"),
+        address!("45D270263BBee500CF8adcf2AbC0aC227097b036"),
+        address!("02AeE5b225366302339748951E1a924617b8814F"),
+        address!("150bAe7Ce224555D39AfdBc6Cb4B8204E594E022"),
+        address!("9eCdfaCca946614cc32aF63F3DBe50959244F3af"),
+        address!("f0eaC18E908B34770FDEe46d069c846bDa866759"),
+        address!("2FA6A78E3d617c1013a22938411602dc9Da98dBa"),
+    ];
+    oracles
+});
+
+/// ie. wstETH, cbETH, rETH, osETH, ETHx, etc
+pub static SPECIFIC_PRICE_CAP_ADAPTERS: Lazy<Vec<Address>> = Lazy::new(|| {
+    let oracles = vec![
+        address!("6243d2F41b4ec944F731f647589E28d9745a2674"),
+        address!("B4aB0c94159bc2d8C133946E7241368fc2F2a010"),
+        address!("f112aF6F0A332B815fbEf3Ff932c057E570b62d3"),
+        address!("5AE8365D0a30D67145f0c55A08760C250559dB64"),
+        address!("0A2AF898cEc35197e6944D9E0F525C2626393442"),
+        address!("D6270dAabFe4862306190298C2B48fed9e15C847"),
+        address!("47F52B2e43D0386cF161e001835b03Ad49889e3b"),
+        address!("95a85D0d2f3115702d813549a80040387738A430"),
+        address!("577C217cB5b1691A500D48aA7F69346409cFd668"),
+    ];
+    oracles
+});
+
+pub static CL_SYNCHRO_PRICE_PEG_ADAPTERS: Lazy<Vec<Address>> = Lazy::new(|| {
+    let oracles = vec![
+        address!("230E0321Cf38F09e247e50Afc7801EA2351fe56F"),
+        address!("b01e6C9af83879B8e06a092f0DD94309c0D497E4"),
+        address!("Daa4B74C6bAc4e25188e64ebc68DB5050b690cAc"),
+    ];
+    oracles
+});
+
+pub static SUSDE_PRICE_ADAPTERS: Lazy<Vec<Address>> = Lazy::new(|| {
+    let oracles = vec![address!("42bc86f2f08419280a99d8fbEa4672e7c30a86ec")];
+    oracles
+});
+
+pub static SDAI_PRICE_ADAPTERS: Lazy<Vec<Address>> = Lazy::new(|| {
+    let oracles = vec![address!("29081f7aB5a644716EfcDC10D5c926c5fEe9F72B")];
+    oracles
+});
+
+pub static GHO_ADAPTER: Lazy<Vec<Address>> = Lazy::new(|| {
+    let oracles = vec![address!("D110cac5d8682A3b045D5524a9903E031d70FCCd")];
+    oracles
+});
+
+pub static PENDLE_PRICE_CAP_ADAPTERS: Lazy<Vec<Address>> = Lazy::new(|| {
+    let oracles = vec![
+        address!("5292AB3292D076271f853Ed8e05e61cc02F0A2C6"),
+        address!("759B9B72700A129CD7AD8e53F9c99cb48Fd57105"),
+    ];
+    oracles
+});
+
+/// Getters of the price adapters Aave uses as price sources. Each one returns the address of the
+/// next contract down the pricing chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+pub enum AdapterGetter {
+    AssetToUsdAggregator,
+    AssetToPeg,
+    BaseToUsdAggregator,
+    DaiToUsd,
+    /// `aggregator()` of an EACAggregatorProxy. Only adapter chains that were probed end with it
+    Aggregator,
+}
+
+/// Getters tried, in order, on every contract of a price source that isn't in any of the groups.
+/// The first one that answers is the next hop, except for `aggregator()`, which means the contract
+/// is the EACAggregatorProxy we were looking for.
+const PROBED_GETTERS: [AdapterGetter; 5] = [
+    AdapterGetter::AssetToUsdAggregator,
+    AdapterGetter::BaseToUsdAggregator,
+    AdapterGetter::AssetToPeg,
+    AdapterGetter::DaiToUsd,
+    AdapterGetter::Aggregator,
+];
+
+/// Longest adapter chain probing follows. The known ones have at most 2 hops
+const MAX_PROBED_HOPS: usize = 4;
+
+/// Adapter chains found by probing, by price source. Adapters are immutable, and a new price
+/// source has a new address, so they are never invalidated
+static PROBED_CHAINS: Lazy<Mutex<HashMap<Address, AdapterChain>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Where the resolvers read adapters from. That's normally the node, but anything that can answer
+/// the getters works, e.g. the recorded responses replayed by the oops-rs self test.
+pub trait AdapterReader {
+    /// Returns None if the call failed
+    fn read(
+        &self,
+        adapter: Address,
+        getter: AdapterGetter,
+    ) -> impl Future<Output = Option<Address>> + Send;
+}
+
+impl AdapterReader for Arc<RootProvider<PubSubFrontend>> {
+    async fn read(&self, adapter: Address, getter: AdapterGetter) -> Option<Address> {
+        // Adapters exposing the same getter share its signature, so one binding per getter
+        // covers all of them
+        let response = match getter {
+            AdapterGetter::AssetToUsdAggregator => {
+                PriceCapAdapterStable::new(adapter, self.clone())
+                    .ASSET_TO_USD_AGGREGATOR()
+                    .call()
+                    .await
+                    .map(|response| response._0)
+            }
+            AdapterGetter::AssetToPeg => {
+                CLSynchronicityPriceAdapterPegToBase::new(adapter, self.clone())
+                    .ASSET_TO_PEG()
+                    .call()
+                    .await
+                    .map(|response| response._0)
+            }
+            AdapterGetter::BaseToUsdAggregator => WstETHPriceCapAdapter::new(adapter, self.clone())
+                .BASE_TO_USD_AGGREGATOR()
+                .call()
+                .await
+                .map(|response| response._0),
+            AdapterGetter::DaiToUsd => sDAISynchronicityPriceAdapter::new(adapter, self.clone())
+                .DAI_TO_USD()
+                .call()
+                .await
+                .map(|response| response._0),
+            AdapterGetter::Aggregator => EACAggregatorProxy::new(adapter, self.clone())
+                .aggregator()
+                .call()
+                .await
+                .map(|response| response._0),
+        };
+        response.ok()
+    }
+}
+
+/// Which of the groups above a price source belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PriceSourceKind {
+    EacAggregatorProxy,
+    PriceCapAdapterStable,
+    SpecificPriceCapAdapter,
+    ClSynchronicityPriceAdapterPegToBase,
+    SUSDePriceCapAdapter,
+    SDaiSynchronicityPriceAdapter,
+    PendlePriceCapAdapter,
+    Gho,
+    /// Not in any of the groups, its chain was found by probe_adapter_chain()
+    Probed,
+}
+
+impl PriceSourceKind {
+    /// None if the price source isn't in any of the groups
+    pub fn of(price_source: Address) -> Option<Self> {
+        match price_source {
+            addr if EAC_AGGREGATOR_PROXY_ORACLES.contains(&addr) => Some(Self::EacAggregatorProxy),
+            addr if PRICE_CAP_ADAPTER_STABLE_ORACLES.contains(&addr) => {
+                Some(Self::PriceCapAdapterStable)
+            }
+            addr if SPECIFIC_PRICE_CAP_ADAPTERS.contains(&addr) => {
+                Some(Self::SpecificPriceCapAdapter)
+            }
+            addr if CL_SYNCHRO_PRICE_PEG_ADAPTERS.contains(&addr) => {
+                Some(Self::ClSynchronicityPriceAdapterPegToBase)
+            }
+            addr if SUSDE_PRICE_ADAPTERS.contains(&addr) => Some(Self::SUSDePriceCapAdapter),
+            addr if SDAI_PRICE_ADAPTERS.contains(&addr) => {
+                Some(Self::SDaiSynchronicityPriceAdapter)
+            }
+            addr if PENDLE_PRICE_CAP_ADAPTERS.contains(&addr) => Some(Self::PendlePriceCapAdapter),
+            addr if GHO_ADAPTER.contains(&addr) => Some(Self::Gho),
+            _ => None,
+        }
+    }
+
+    /// Getters called, in order, to get from a price source of this kind to its
+    /// EACAggregatorProxy. Same path the resolve_* function of each kind follows. Empty for probed
+    /// price sources, whose path is only known from their AdapterChain.
+    pub fn getters(&self) -> &'static [AdapterGetter] {
+        match self {
+            Self::EacAggregatorProxy | Self::Gho | Self::Probed => &[],
+            Self::PriceCapAdapterStable => &[AdapterGetter::AssetToUsdAggregator],
+            Self::SpecificPriceCapAdapter => &[AdapterGetter::BaseToUsdAggregator],
+            Self::ClSynchronicityPriceAdapterPegToBase => &[AdapterGetter::AssetToPeg],
+            Self::SUSDePriceCapAdapter => &[
+                AdapterGetter::BaseToUsdAggregator,
+                AdapterGetter::AssetToUsdAggregator,
+            ],
+            Self::SDaiSynchronicityPriceAdapter => &[AdapterGetter::DaiToUsd],
+            Self::PendlePriceCapAdapter => &[
+                AdapterGetter::AssetToUsdAggregator,
+                AdapterGetter::AssetToUsdAggregator,
+            ],
+        }
+    }
+}
+
+/// Caller needs to check the return value and handle the special GHO case (because
+/// that aggregator doesn't implement `getTransmitters()`)
+pub async fn resolve_aggregator<R: AdapterReader>(
+    reader: &R,
+    oracle_address_for_aave: Address,
+) -> Result<Address, Box<dyn std::error::Error>> {
+    let Some(kind) = PriceSourceKind::of(oracle_address_for_aave) else {
+        return probe_adapter_chain(reader, oracle_address_for_aave)
+            .await
+            .map(|chain| chain.proxy);
+    };
+    let aggregator = match kind {
+        PriceSourceKind::EacAggregatorProxy => {
+            resolve_eac_aggregator_proxy(reader, oracle_address_for_aave).await
+        }
+        PriceSourceKind::PriceCapAdapterStable => {
+            resolve_asset_to_usd_aggregator(reader, oracle_address_for_aave).await
+        }
+        PriceSourceKind::SpecificPriceCapAdapter => {
+            resolve_base_to_usd_aggregator(reader, oracle_address_for_aave).await
+        }
+        PriceSourceKind::ClSynchronicityPriceAdapterPegToBase => {
+            resolve_asset_to_peg(reader, oracle_address_for_aave).await
+        }
+        PriceSourceKind::SUSDePriceCapAdapter => {
+            resolve_susde_aggregator(reader, oracle_address_for_aave).await
+        }
+        PriceSourceKind::SDaiSynchronicityPriceAdapter => {
+            resolve_dai_to_usd_aggregator(reader, oracle_address_for_aave).await
+        }
+        PriceSourceKind::PendlePriceCapAdapter => {
+            resolve_pendle_aggregator(reader, oracle_address_for_aave).await
+        }
+        PriceSourceKind::Gho => resolve_gho_aggregator(reader, oracle_address_for_aave).await,
+        PriceSourceKind::Probed => unreachable!("PriceSourceKind::of() never returns Probed"),
+    };
+    Ok(aggregator)
+}
+
+/// One getter call down a price source's adapter chain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdapterHop {
+    pub adapter: Address,
+    pub getter: AdapterGetter,
+    /// What the getter returned, i.e. the next contract down the chain
+    pub next: Address,
+}
+
+/// Path from an Aave price source to the EACAggregatorProxy at the root of its hierarchy
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AdapterChain {
+    pub price_source: Address,
+    pub kind: PriceSourceKind,
+    /// Empty if the price source is the proxy itself (or GHO's fixed price oracle)
+    pub hops: Vec<AdapterHop>,
+    /// Same address resolve_aggregator() returns
+    pub proxy: Address,
+}
+
+/// The OCR aggregator a proxy currently forwards to. It's the contract transmit() calls end up
+/// in, so its address is what price updates are keyed by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OcrAggregatorInfo {
+    pub address: Address,
+    pub description: String,
+    pub decimals: u8,
+    /// Answers outside [min_answer, max_answer] are clamped by the aggregator
+    pub min_answer: I192,
+    pub max_answer: I192,
+    pub transmitters: Vec<Address>,
+}
+
+/// Everything between an Aave price source and the aggregator its prices come from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvedPriceSource {
+    pub chain: AdapterChain,
+    /// None for GHO, whose price is fixed and has no aggregator behind it
+    pub aggregator: Option<OcrAggregatorInfo>,
+}
+
+/// Follows `price_source` down to its EACAggregatorProxy, recording every hop. Unlike
+/// resolve_aggregator(), a getter that can't be read is an error instead of the zero address.
+pub async fn trace_adapter_chain<R: AdapterReader>(
+    reader: &R,
+    price_source: Address,
+) -> Result<AdapterChain, Box<dyn Error>> {
+    let Some(kind) = PriceSourceKind::of(price_source) else {
+        return probe_adapter_chain(reader, price_source).await;
+    };
+    let mut hops = vec![];
+    let mut current = price_source;
+    for getter in kind.getters() {
+        let next = match reader.read(current, *getter).await {
+            Some(next) if next != Address::ZERO => next,
+            _ => {
+                return Err(format!(
+                    "Failed to read {:?} from {} (price source {})",
+                    getter, current, price_source
+                )
+                .into())
+            }
+        };
+        hops.push(AdapterHop {
+            adapter: current,
+            getter: *getter,
+            next,
+        });
+        current = next;
+    }
+    Ok(AdapterChain {
+        price_source,
+        kind,
+        hops,
+        proxy: current,
+    })
+}
+
+/// Finds the adapter chain of a price source that isn't in any of the groups, by trying every
+/// getter in PROBED_GETTERS on each contract until one of them is an EACAggregatorProxy (either
+/// because it's listed as one or because it answers `aggregator()`). The chain is cached, so a
+/// price source is only probed once.
+pub async fn probe_adapter_chain<R: AdapterReader>(
+    reader: &R,
+    price_source: Address,
+) -> Result<AdapterChain, Box<dyn Error>> {
+    if let Some(chain) = PROBED_CHAINS.lock().unwrap().get(&price_source) {
+        return Ok(chain.clone());
+    }
+    let mut hops = vec![];
+    let mut current = price_source;
+    'probing: while !EAC_AGGREGATOR_PROXY_ORACLES.contains(&current) {
+        if hops.len() == MAX_PROBED_HOPS {
+            return Err(format!(
+                "Gave up probing price source {} after {} hops",
+                price_source, MAX_PROBED_HOPS
+            )
+            .into());
+        }
+        for getter in PROBED_GETTERS {
+            let Some(next) = reader.read(current, getter).await else {
+                continue;
+            };
+            if next == Address::ZERO {
+                continue;
+            }
+            if getter == AdapterGetter::Aggregator {
+                // `current` is the proxy, `next` the aggregator behind it
+                break 'probing;
+            }
+            hops.push(AdapterHop {
+                adapter: current,
+                getter,
+                next,
+            });
+            current = next;
+            continue 'probing;
+        }
+        return Err(format!(
+            "This price oracle didn't match any group, and {} doesn't answer any known getter (price source {})",
+            current, price_source
+        )
+        .into());
+    }
+    let chain = AdapterChain {
+        price_source,
+        kind: PriceSourceKind::Probed,
+        hops,
+        proxy: current,
+    };
+    info!(
+        "Probed price source {} down to proxy {} in {} hops",
+        price_source,
+        chain.proxy,
+        chain.hops.len()
+    );
+    PROBED_CHAINS
+        .lock()
+        .unwrap()
+        .insert(price_source, chain.clone());
+    Ok(chain)
+}
+
+/// Resolves any Aave price source to its full adapter chain, and the OCR aggregator behind it
+/// along with its metadata.
+pub async fn resolve_price_source(
+    provider: &Arc<RootProvider<PubSubFrontend>>,
+    price_source: Address,
+) -> Result<ResolvedPriceSource, Box<dyn Error>> {
+    let chain = trace_adapter_chain(provider, price_source).await?;
+    if chain.kind == PriceSourceKind::Gho {
+        return Ok(ResolvedPriceSource {
+            chain,
+            aggregator: None,
+        });
+    }
+    let aggregator = get_ocr_aggregator_info(provider, chain.proxy).await?;
+    Ok(ResolvedPriceSource {
+        chain,
+        aggregator: Some(aggregator),
+    })
+}
+
+/// Reads the aggregator `proxy` currently points to, and its metadata
+pub async fn get_ocr_aggregator_info(
+    provider: &Arc<RootProvider<PubSubFrontend>>,
+    proxy: Address,
+) -> Result<OcrAggregatorInfo, Box<dyn Error>> {
+    let address = EACAggregatorProxy::new(proxy, provider.clone())
+        .aggregator()
+        .call()
+        .await
+        .map_err(|e| format!("Failed to get aggregator() from {}: {}", proxy, e))?
+        ._0;
+    let aggregator = AccessControlledOCR2Aggregator::new(address, provider.clone());
+    let metadata_error = |e: alloy::contract::Error| {
+        format!("Failed to read metadata of aggregator {}: {}", address, e)
+    };
+    Ok(OcrAggregatorInfo {
+        address,
+        description: aggregator
+            .description()
+            .call()
+            .await
+            .map_err(metadata_error)?
+            ._0,
+        decimals: aggregator
+            .decimals()
+            .call()
+            .await
+            .map_err(metadata_error)?
+            ._0,
+        min_answer: aggregator
+            .minAnswer()
+            .call()
+            .await
+            .map_err(metadata_error)?
+            ._0,
+        max_answer: aggregator
+            .maxAnswer()
+            .call()
+            .await
+            .map_err(metadata_error)?
+            ._0,
+        transmitters: aggregator
+            .getTransmitters()
+            .call()
+            .await
+            .map_err(metadata_error)?
+            ._0,
+    })
+}
+
+pub async fn resolve_eac_aggregator_proxy<R: AdapterReader>(
+    _reader: &R,
+    oracle_address_for_aave: Address,
+) -> Address {
+    oracle_address_for_aave
+}
+
+pub async fn resolve_gho_aggregator<R: AdapterReader>(
+    _reader: &R,
+    oracle_address_for_aave: Address,
+) -> Address {
+    oracle_address_for_aave
+}
+
+pub async fn resolve_asset_to_usd_aggregator<R: AdapterReader>(
+    reader: &R,
+    oracle_address_for_aave: Address,
+) -> Address {
+    reader
+        .read(oracle_address_for_aave, AdapterGetter::AssetToUsdAggregator)
+        .await
+        .unwrap_or(Address::ZERO)
+}
+
+pub async fn resolve_asset_to_peg<R: AdapterReader>(
+    reader: &R,
+    oracle_address_for_aave: Address,
+) -> Address {
+    reader
+        .read(oracle_address_for_aave, AdapterGetter::AssetToPeg)
+        .await
+        .unwrap_or(Address::ZERO)
+}
+
+pub async fn resolve_susde_aggregator<R: AdapterReader>(
+    reader: &R,
+    oracle_address_for_aave: Address,
+) -> Address {
+    let base_to_usd_aggregator = reader
+        .read(oracle_address_for_aave, AdapterGetter::BaseToUsdAggregator)
+        .await
+        .unwrap_or(Address::ZERO);
+    resolve_asset_to_usd_aggregator(reader, base_to_usd_aggregator).await
+}
+
+pub async fn resolve_dai_to_usd_aggregator<R: AdapterReader>(
+    reader: &R,
+    oracle_address_for_aave: Address,
+) -> Address {
+    reader
+        .read(oracle_address_for_aave, AdapterGetter::DaiToUsd)
+        .await
+        .unwrap_or(Address::ZERO)
+}
+
+/// Pendle PT adapters price the PT as the price of its underlying minus a discount that decays
+/// linearly until MATURITY (discountRatePerYear * time to maturity). The underlying is priced by
+/// another adapter, so the chain is two ASSET_TO_USD_AGGREGATOR hops long. The discount doesn't
+/// depend on the answer, so a PT price moves proportionally to the feed at the root.
+pub async fn resolve_pendle_aggregator<R: AdapterReader>(
+    reader: &R,
+    oracle_address_for_aave: Address,
+) -> Address {
+    let asset_to_usd_aggregator_address = reader
+        .read(oracle_address_for_aave, AdapterGetter::AssetToUsdAggregator)
+        .await
+        .unwrap_or(Address::ZERO);
+    resolve_asset_to_usd_aggregator(reader, asset_to_usd_aggregator_address).await
+}
+
+/// wstETH, cbETH, rETH, eBTC, weETH, osETH, ETHx and rsETH adapters all price their asset
+/// against a BASE_TO_USD_AGGREGATOR
+pub async fn resolve_base_to_usd_aggregator<R: AdapterReader>(
+    reader: &R,
+    oracle_address_for_aave: Address,
+) -> Address {
+    reader
+        .read(oracle_address_for_aave, AdapterGetter::BaseToUsdAggregator)
+        .await
+        .unwrap_or(Address::ZERO)
+}
@@ -0,0 +1,147 @@
+use alloy::transports::{RpcError, TransportError};
+use std::{fmt::Display, future::Future, time::Duration};
+use tokio::time::sleep;
+use tracing::warn;
+
+/// How long to wait between attempts at something that failed, and how many attempts to make.
+///
+/// Waits double from `initial_delay` up to `max_delay`, and each one is jittered between half and
+/// all of it, so services (or tasks) that failed at the same time don't retry in lockstep.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// None keeps trying forever
+    pub max_attempts: Option<u32>,
+}
+
+impl RetryPolicy {
+    /// For the connections and subscriptions services keep open, which are retried forever
+    pub const RECONNECT: RetryPolicy = RetryPolicy {
+        initial_delay: Duration::from_millis(500),
+        max_delay: Duration::from_secs(30),
+        max_attempts: None,
+    };
+
+    /// For one-off RPC calls, which had better fail soon than hold up whoever needs the result
+    pub const RPC: RetryPolicy = RetryPolicy {
+        initial_delay: Duration::from_millis(200),
+        max_delay: Duration::from_secs(2),
+        max_attempts: Some(4),
+    };
+
+    pub const fn with_max_attempts(self, max_attempts: u32) -> Self {
+        Self {
+            max_attempts: Some(max_attempts),
+            ..self
+        }
+    }
+
+    /// Wait after the `failures`-th consecutive failure (starting at 1)
+    pub fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(16);
+        let delay = self
+            .initial_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        let half_millis = delay.as_millis() as u64 / 2;
+        Duration::from_millis(half_millis + rand::random_range(0..=half_millis))
+    }
+}
+
+/// Counts the consecutive failures of something retried in a loop (a reconnection loop, say),
+/// so waits grow while it keeps failing and start over once it works.
+#[derive(Debug)]
+pub struct Backoff {
+    policy: RetryPolicy,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            failures: 0,
+        }
+    }
+
+    /// Counts a failure, and returns how long to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+        self.policy.delay(self.failures)
+    }
+
+    /// Counts a failure, and waits before the next attempt
+    pub async fn wait(&mut self) {
+        sleep(self.next_delay()).await;
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// For connections that fail after working for a while: the count starts over if it lasted
+    /// longer than the longest wait, so a healthy connection that drops isn't retried slowly
+    pub fn connection_lasted(&mut self, duration: Duration) {
+        if duration >= self.policy.max_delay {
+            self.reset();
+        }
+    }
+}
+
+/// Runs `operation` until it succeeds, fails with an error `is_retryable` rejects, or runs out of
+/// attempts, and returns its last result. Failed attempts are logged along with `what` was tried.
+pub async fn retry<T, E, F, Fut>(
+    policy: RetryPolicy,
+    what: &str,
+    is_retryable: impl Fn(&E) -> bool,
+    mut operation: F,
+) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut backoff = Backoff::new(policy);
+    let mut attempt = 1;
+    loop {
+        let error = match operation().await {
+            Ok(result) => return Ok(result),
+            Err(e) => e,
+        };
+        if !is_retryable(&error) || policy.max_attempts.is_some_and(|max| attempt >= max) {
+            return Err(error);
+        }
+        let delay = backoff.next_delay();
+        warn!(
+            "Failed to {} (attempt {}): {}. Retrying in {:?}",
+            what, attempt, error, delay
+        );
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// For operations whose every error is worth retrying, like connecting
+pub fn any_error<E>(_: &E) -> bool {
+    true
+}
+
+/// Transport failures, rate limits and empty responses are worth retrying. Errors returned by
+/// the node for the request itself (a revert, a bad param) will come back the same.
+pub fn is_retryable_rpc_error(error: &TransportError) -> bool {
+    match error {
+        RpcError::Transport(_) | RpcError::NullResp => true,
+        // Too many requests, and the "limit exceeded" code some providers use for it
+        RpcError::ErrorResp(payload) => matches!(payload.code, 429 | -32005),
+        _ => false,
+    }
+}
+
+/// Same as is_retryable_rpc_error(), for contract calls
+pub fn is_retryable_call_error(error: &alloy::contract::Error) -> bool {
+    match error {
+        alloy::contract::Error::TransportError(e) => is_retryable_rpc_error(e),
+        _ => false,
+    }
+}
@@ -41,6 +41,18 @@ sol!(
     "src/abis/aave_v3_oracle.json"
 );
 
+sol!(
+    #[allow(missing_docs)]
+    #[allow(clippy::too_many_arguments)]
+    #[sol(rpc)]
+    interface IPoolAddressesProvider {
+        function getPool() external view returns (address);
+        function getPoolConfigurator() external view returns (address);
+        function getPriceOracle() external view returns (address);
+        function getPoolDataProvider() external view returns (address);
+    }
+);
+
 sol!(
     #[allow(missing_docs)]
     #[allow(clippy::too_many_arguments)]
@@ -290,7 +302,201 @@ sol!(
             uint256 aavePremium; // AAVE V3 premium (if applicable, otherwise zero)
         }
 
+        enum SwapVenue {
+            UNISWAP_V3, // 0
+            UNISWAP_V2, // 1
+            CURVE,      // 2
+            BALANCER    // 3
+        }
+
+        struct SwapStep {
+            SwapVenue venue;
+            address tokenIn;
+            address tokenOut;
+            // abi encoded: uint24 fee (Uniswap V3), address pair (Uniswap V2),
+            // (address pool, int128 i, int128 j) (Curve) or bytes32 poolId (Balancer)
+            bytes data;
+        }
+
         function triggerLiquidation(LiquidationParams calldata params) external;
+        // Swaps the collateral through `route` instead of the two Uniswap V3 legs of the params
+        function triggerLiquidationWithRoute(
+            LiquidationParams calldata params,
+            SwapStep[] calldata route
+        ) external;
+        // Repays the debt with Foxdie's own balance and keeps the collateral as aTokens
+        // (receiveAToken), without flash loan nor swaps
+        function triggerLiquidationReceiveAToken(LiquidationParams calldata params) external;
+    }
+);
+
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    interface IUniswapV2Factory {
+        function getPair(address tokenA, address tokenB) external view returns (address pair);
+    }
+);
+
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    interface IUniswapV2Router {
+        function getAmountsOut(uint256 amountIn, address[] calldata path)
+            external
+            view
+            returns (uint256[] memory amounts);
+    }
+);
+
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    interface ICurveMetaRegistry {
+        function find_pool_for_coins(address _from, address _to) external view returns (address);
+        function get_coin_indices(address _pool, address _from, address _to)
+            external
+            view
+            returns (int128, int128, bool);
+    }
+);
+
+// Stableswap pools. Crypto pools take uint256 indexes instead.
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    interface ICurvePool {
+        function get_dy(int128 i, int128 j, uint256 dx) external view returns (uint256);
+    }
+);
+
+sol!(
+    #[allow(missing_docs)]
+    #[derive(Debug)]
+    #[sol(rpc)]
+    interface IBalancerVault {
+        struct BatchSwapStep {
+            bytes32 poolId;
+            uint256 assetInIndex;
+            uint256 assetOutIndex;
+            uint256 amount;
+            bytes userData;
+        }
+
+        struct FundManagement {
+            address sender;
+            bool fromInternalBalance;
+            address recipient;
+            bool toInternalBalance;
+        }
+
+        function getProtocolFeesCollector() external view returns (address);
+        function getPoolTokens(bytes32 poolId)
+            external
+            view
+            returns (address[] memory tokens, uint256[] memory balances, uint256 lastChangeBlock);
+        // Not a view, but it only simulates the swaps, so it's meant to be called with eth_call
+        function queryBatchSwap(
+            uint8 kind,
+            BatchSwapStep[] memory swaps,
+            address[] memory assets,
+            FundManagement memory funds
+        ) external returns (int256[] memory assetDeltas);
+    }
+);
+
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    interface IBalancerProtocolFeesCollector {
+        // 18 decimals fixed point, charged on top of every flash loan of the vault
+        function getFlashLoanFeePercentage() external view returns (uint256);
+    }
+);
+
+sol!(
+    #[allow(missing_docs)]
+    #[derive(Debug)]
+    #[sol(rpc)]
+    interface IMulticall3 {
+        struct Call3 {
+            address target;
+            bool allowFailure;
+            bytes callData;
+        }
+
+        struct Call3Result {
+            bool success;
+            bytes returnData;
+        }
+
+        function aggregate3(Call3[] calldata calls) external payable returns (Call3Result[] memory returnData);
+    }
+);
+
+sol!(
+    #[allow(missing_docs)]
+    #[derive(Debug)]
+    #[sol(rpc)]
+    interface IPoolConfigurator {
+        event ReserveInitialized(
+            address indexed asset,
+            address indexed aToken,
+            address stableDebtToken,
+            address variableDebtToken,
+            address interestRateStrategyAddress
+        );
+        event CollateralConfigurationChanged(
+            address indexed asset,
+            uint256 ltv,
+            uint256 liquidationThreshold,
+            uint256 liquidationBonus
+        );
+        event ReserveFrozen(address indexed asset, bool frozen);
+        event ReservePaused(address indexed asset, bool paused);
+
+        function MAX_GRACE_PERIOD() external view returns (uint40);
+    }
+);
+
+// Constants of the LiquidationLogic library (Aave v3.3) the pools are linked against. Libraries
+// get getters for their public constants like contracts do.
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    interface ILiquidationLogic {
+        function MIN_BASE_MAX_CLOSE_FACTOR_THRESHOLD() external view returns (uint256);
+        function CLOSE_FACTOR_HF_THRESHOLD() external view returns (uint256);
+        function DEFAULT_LIQUIDATION_CLOSE_FACTOR() external view returns (uint256);
+    }
+);
+
+sol!(
+    #[allow(missing_docs)]
+    #[derive(Debug)]
+    #[sol(rpc)]
+    interface GhoVariableDebtToken {
+        event DiscountPercentUpdated(
+            address indexed user,
+            uint256 oldDiscountPercent,
+            uint256 indexed newDiscountPercent
+        );
+        event DiscountRateStrategyUpdated(
+            address indexed oldDiscountRateStrategy,
+            address indexed newDiscountRateStrategy
+        );
+        event DiscountTokenUpdated(
+            address indexed oldDiscountToken,
+            address indexed newDiscountToken
+        );
+    }
+);
+
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    interface GhoOracle {
+        function latestAnswer() external view returns (int256);
     }
 );
 
@@ -0,0 +1,238 @@
+//! Staggers the heavy initialization reads of the services on the same host.
+//!
+//! When the whole stack restarts at once (e.g. after a host reboot), every service would hit the
+//! node with its initialization reads at the same time. Instead, each heavy phase takes a lock
+//! file before it starts, so only one of them runs at a time, and phases that restore live
+//! monitoring go before the ones that backfill caches.
+//!
+//! Lock files are locked (flock) for as long as their phase runs, so the lock is released with
+//! the service even if it crashes. A phase that waited longer than STARTUP_MAX_WAIT_SECS_ENV
+//! seconds starts anyway.
+
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::time::{sleep, Instant};
+use tracing::{info, warn};
+
+/// Directory the lock files are kept in. Setting it to an empty string disables the coordination.
+pub const STARTUP_LOCK_DIR_ENV: &str = "OVERLORD_STARTUP_LOCK_DIR";
+pub const STARTUP_MAX_WAIT_SECS_ENV: &str = "OVERLORD_STARTUP_MAX_WAIT_SECS";
+const STARTUP_LOCK_DIR: &str = "/tmp/overlord_startup";
+const STARTUP_MAX_WAIT_SECS: u64 = 600;
+const POLL_INTERVAL_MS: u64 = 500;
+// Gives the live phases of services started at the same time a chance to register before a
+// backfill looks for them
+const BACKFILL_GRACE_SECS: u64 = 3;
+const LIVE_MARKER_PREFIX: &str = "live-";
+
+/// What a heavy init phase is for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartupPhase {
+    /// Reads needed before live updates can be monitored (e.g. oops collecting transmitters)
+    Live,
+    /// Reads that fill caches, which only run once no live phase is waiting
+    Backfill,
+}
+
+impl StartupPhase {
+    fn lock_file(&self) -> &'static str {
+        match self {
+            StartupPhase::Live => "live.lock",
+            StartupPhase::Backfill => "backfill.lock",
+        }
+    }
+}
+
+/// Held while a heavy init phase runs. Dropping it lets the next phase start.
+pub struct StartupPermit {
+    /// Lock file of the phase, locked until it's closed. It's never removed: a service waiting on
+    /// the removed file and one creating a new one could both lock theirs.
+    lock: Option<File>,
+    /// Live phase markers, removed on drop
+    files: Vec<PathBuf>,
+}
+
+impl Drop for StartupPermit {
+    fn drop(&mut self) {
+        for file in &self.files {
+            let _ = fs::remove_file(file);
+        }
+    }
+}
+
+fn lock_dir() -> Option<PathBuf> {
+    match std::env::var(STARTUP_LOCK_DIR_ENV) {
+        Ok(dir) if dir.is_empty() => None,
+        Ok(dir) => Some(PathBuf::from(dir)),
+        Err(_) => Some(PathBuf::from(STARTUP_LOCK_DIR)),
+    }
+}
+
+fn max_wait() -> Duration {
+    let secs = match std::env::var(STARTUP_MAX_WAIT_SECS_ENV) {
+        Ok(value) => value.parse().unwrap_or_else(|e| {
+            warn!(
+                "Invalid {} value {}, using {}: {}",
+                STARTUP_MAX_WAIT_SECS_ENV, value, STARTUP_MAX_WAIT_SECS, e
+            );
+            STARTUP_MAX_WAIT_SECS
+        }),
+        Err(_) => STARTUP_MAX_WAIT_SECS,
+    };
+    Duration::from_secs(secs)
+}
+
+fn pid_is_alive(pid: u32) -> bool {
+    Path::new(&format!("/proc/{pid}")).exists()
+}
+
+/// True if the file exists and was written by a process that's still running
+fn held_by_live_process(path: &Path) -> bool {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return false;
+    };
+    match contents.split_whitespace().next().map(str::parse::<u32>) {
+        Some(Ok(pid)) => pid == std::process::id() || pid_is_alive(pid),
+        // Still being written
+        _ => true,
+    }
+}
+
+fn write_owner_file(path: &Path, service: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    writeln!(file, "{} {}", std::process::id(), service)
+}
+
+/// Locks the lock file of the phase, None if another service holds it. The phase holds the lock
+/// from here until the returned file is closed, so no other service can take it in between.
+fn try_lock(path: &Path, service: &str) -> Option<File> {
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Failed to open startup lock {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => return None,
+        Err(TryLockError::Error(e)) => {
+            warn!("Failed to lock startup lock {}: {}", path.display(), e);
+            return None;
+        }
+    }
+    // Only tells who holds it, the lock is what keeps other services out
+    let _ = file
+        .set_len(0)
+        .and_then(|_| writeln!(file, "{} {}", std::process::id(), service));
+    Some(file)
+}
+
+/// True if a live phase is waiting or running, which its marker tells until its permit is dropped
+fn live_phase_pending(dir: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with(LIVE_MARKER_PREFIX) {
+            return false;
+        }
+        if held_by_live_process(&entry.path()) {
+            return true;
+        }
+        let _ = fs::remove_file(entry.path());
+        false
+    })
+}
+
+/// Waits until `service` can run a heavy init phase and returns a permit to hold while it runs.
+///
+/// Phases of the same kind run one at a time, and backfills also wait for every live phase that's
+/// waiting or running. Never fails: if the lock files can't be used, the phase just starts.
+pub async fn acquire(service: &str, phase: StartupPhase) -> StartupPermit {
+    let mut permit = StartupPermit {
+        lock: None,
+        files: Vec::new(),
+    };
+    let Some(dir) = lock_dir() else {
+        return permit;
+    };
+    if let Err(e) = fs::create_dir_all(&dir) {
+        warn!(
+            "Failed to create startup lock dir {}, starting {:?} phase right away: {}",
+            dir.display(),
+            phase,
+            e
+        );
+        return permit;
+    }
+    if phase == StartupPhase::Live {
+        let marker = dir.join(format!(
+            "{}{}-{}",
+            LIVE_MARKER_PREFIX,
+            service,
+            std::process::id()
+        ));
+        if write_owner_file(&marker, service).is_ok() {
+            permit.files.push(marker);
+        }
+    } else {
+        sleep(Duration::from_secs(BACKFILL_GRACE_SECS)).await;
+    }
+
+    let lock_path = dir.join(phase.lock_file());
+    let max_wait = max_wait();
+    let started = Instant::now();
+    let mut logged = false;
+    loop {
+        let ready = match phase {
+            StartupPhase::Live => true,
+            StartupPhase::Backfill => !live_phase_pending(&dir),
+        };
+        if ready {
+            if let Some(lock) = try_lock(&lock_path, service) {
+                permit.lock = Some(lock);
+                break;
+            }
+        }
+        if started.elapsed() >= max_wait {
+            warn!(
+                "{} waited {:?} to start its {:?} phase, starting it anyway",
+                service, max_wait, phase
+            );
+            break;
+        }
+        if !logged {
+            info!(
+                "Waiting for other services to finish their init before starting the {:?} phase of {}",
+                phase, service
+            );
+            logged = true;
+        }
+        sleep(Duration::from_millis(POLL_INTERVAL_MS)).await;
+    }
+    if logged {
+        info!(
+            "{:?} phase of {} starting after {:?}",
+            phase,
+            service,
+            started.elapsed()
+        );
+    }
+    permit
+}
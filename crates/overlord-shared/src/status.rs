@@ -0,0 +1,150 @@
+use alloy::{
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{info, warn};
+
+/// Seconds between status lines. Set it to 0 to disable them.
+pub const STATUS_INTERVAL_ENV: &str = "OVERLORD_STATUS_INTERVAL_SECS";
+const DEFAULT_STATUS_INTERVAL_SECS: u64 = 60;
+
+/// Number of times something happened since the previous status line
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> u64 {
+        self.0.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// Current value of something, reported as is on every status line
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Logs a summarized status line every STATUS_INTERVAL_ENV seconds, so quiet periods still show
+/// that a service is alive and that its node is following the chain. Counters and gauges are
+/// meant to be statics owned by each service.
+pub struct StatusReporter {
+    service: &'static str,
+    counters: Vec<(&'static str, &'static Counter)>,
+    gauges: Vec<(&'static str, &'static Gauge)>,
+}
+
+impl StatusReporter {
+    pub fn new(service: &'static str) -> Self {
+        Self {
+            service,
+            counters: Vec::new(),
+            gauges: Vec::new(),
+        }
+    }
+
+    pub fn counter(mut self, name: &'static str, counter: &'static Counter) -> Self {
+        self.counters.push((name, counter));
+        self
+    }
+
+    pub fn gauge(mut self, name: &'static str, gauge: &'static Gauge) -> Self {
+        self.gauges.push((name, gauge));
+        self
+    }
+
+    /// Spawns the task that logs the status lines, unless they were disabled. `provider` is only
+    /// used to read the node head block.
+    pub fn spawn(self, provider: Arc<RootProvider<PubSubFrontend>>) {
+        let Some(period) = status_interval() else {
+            info!("{} is 0, status lines are disabled", STATUS_INTERVAL_ENV);
+            return;
+        };
+        info!(
+            "{} will log a status line every {}s",
+            self.service,
+            period.as_secs()
+        );
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // The first tick completes immediately, and there's nothing to report yet
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let head_block = match provider.get_block_number().await {
+                    Ok(block) => block.to_string(),
+                    Err(e) => {
+                        warn!("Status line couldn't read the head block: {}", e);
+                        "unavailable".to_string()
+                    }
+                };
+                info!(
+                    "STATUS | {} | last {}s | {} | head_block={}",
+                    self.service,
+                    period.as_secs(),
+                    self.summary(),
+                    head_block
+                );
+            }
+        });
+    }
+
+    fn summary(&self) -> String {
+        self.counters
+            .iter()
+            .map(|(name, counter)| format!("{}={}", name, counter.take()))
+            .chain(
+                self.gauges
+                    .iter()
+                    .map(|(name, gauge)| format!("{}={}", name, gauge.get())),
+            )
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+fn status_interval() -> Option<Duration> {
+    let secs = match std::env::var(STATUS_INTERVAL_ENV) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|e| {
+            warn!(
+                "Invalid {} value '{}' ({}), using {}s",
+                STATUS_INTERVAL_ENV, value, e, DEFAULT_STATUS_INTERVAL_SECS
+            );
+            DEFAULT_STATUS_INTERVAL_SECS
+        }),
+        Err(_) => DEFAULT_STATUS_INTERVAL_SECS,
+    };
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
@@ -14,6 +14,12 @@
 //! 
 //! This is best used through the bur.sh script at the root of this repo
 //! 
+//! `bpchecker <address> [path_to_ipc] [--block N]`
+//! 
+//! With `--block N` every read is done at block N instead of the latest one, to see what the bot
+//! should have seen at a past (missed) liquidation. Unless N is very recent, the node must be an
+//! archive node. Gas prices are still the current ones.
+//! 
 //! ## Output
 //!
 //! 1. **Analysis**: Detailed breakdown of all debt/collateral combinations
@@ -26,13 +32,19 @@ use alloy::{
     primitives::{aliases::U24, utils::format_units, Address, U256},
     providers::{IpcConnect, Provider, ProviderBuilder, RootProvider},
     pubsub::PubSubFrontend,
+    rpc::types::BlockId,
 };
 use ethers_core::{types::H256, utils::hex};
 use overlord_shared::{
-    common::get_reserves_data,
+    common::{
+        get_base_currency_at, get_reserves_data_at, get_user_emode_category_at, BaseCurrency,
+        UserEModeCategory,
+    },
     constants::{
-        AAVE_ORACLE_ADDRESS, AAVE_V3_POOL_ADDRESS, AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS,
+        AAVE_ORACLE_ADDRESS, AAVE_V3_POOL_ADDRESS, AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS, WETH,
     },
+    kill_switch::KILL_SWITCH,
+    market::AaveMarket,
     sol_bindings::{
         pool::AaveV3Pool,
         AaveOracle, AaveProtocolDataProvider, Foxdie,
@@ -43,9 +55,14 @@ use profito_rs::{
     cache::PriceCache,
     calculations::{
         calculate_actual_debt_to_liquidate, calculate_best_swap_fees, calculate_user_account_data,
-        calculate_user_balances, estimate_gas, get_best_liquidity_provider, get_reserves_list,
-        percent_div, percent_mul, BestPair, BRIBE_IN_BASIS_POINTS,
+        calculate_user_balances, estimate_gas, get_best_liquidity_provider,
+        get_effective_liquidation_bonus, get_reserve_index, get_reserves_list, percent_div,
+        percent_mul, quote_collateral_swap, BestPair, BRIBE_IN_BASIS_POINTS,
     },
+    execution_mode::ExecutionMode,
+    gas::wei_to_asset_units,
+    liquidation_params::LiquidationParams,
+    swap_routes::SwapRoute,
     utils::{
         generate_reserve_details_by_asset, get_user_reserves_data, ReserveConfigurationEnhancedData,
     },
@@ -56,9 +73,10 @@ use tokio::sync::Mutex;
 async fn get_user_health_factor(
     provider: Arc<RootProvider<PubSubFrontend>>,
     user: Address,
+    block: BlockId,
 ) -> U256 {
     let pool = AaveV3Pool::new(AAVE_V3_POOL_ADDRESS, provider.clone());
-    match pool.getUserAccountData(user).call().await {
+    match pool.getUserAccountData(user).block(block).call().await {
         Ok(account_data) => account_data.healthFactor,
         Err(e) => {
             eprintln!("Error trying to call getUserAccountData: {}", e);
@@ -90,9 +108,13 @@ fn print_debt_collateral_title(
     );
 }
 
-async fn get_asset_price(provider: Arc<RootProvider<PubSubFrontend>>, asset: Address) -> U256 {
+async fn get_asset_price(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    asset: Address,
+    block: BlockId,
+) -> U256 {
     let aave_oracle = AaveOracle::new(AAVE_ORACLE_ADDRESS, provider.clone());
-    match aave_oracle.getAssetPrice(asset).call().await {
+    match aave_oracle.getAssetPrice(asset).block(block).call().await {
         Ok(price_response) => price_response._0,
         Err(e) => {
             eprintln!("Error trying to call getAssetPrice: {}", e);
@@ -106,7 +128,10 @@ async fn get_asset_price(provider: Arc<RootProvider<PubSubFrontend>>, asset: Add
 async fn calculate_available_collateral_to_liquidate(
     provider: Arc<RootProvider<PubSubFrontend>>,
     collateral_asset: Address,
+    debt_asset: Address,
+    native_asset_price: U256,
     collateral_decimals: U256,
+    base_currency: &BaseCurrency,
     // all original args for this function under this line
     collateral_asset_price: U256,
     collateral_asset_unit: U256,
@@ -115,6 +140,7 @@ async fn calculate_available_collateral_to_liquidate(
     debt_to_cover: U256,
     user_collateral_balance: U256,
     liquidation_bonus: U256,
+    block: BlockId,
 ) -> (U256, U256, U256, U256, U256) {
     // https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L633
 
@@ -122,6 +148,7 @@ async fn calculate_available_collateral_to_liquidate(
         AaveProtocolDataProvider::new(AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS, provider.clone());
     let liquidation_protocol_fee_percentage = match protocol
         .getLiquidationProtocolFee(collateral_asset)
+        .block(block)
         .call()
         .await
     {
@@ -133,7 +160,7 @@ async fn calculate_available_collateral_to_liquidate(
     };
     let base_collateral = (debt_asset_price * debt_to_cover * collateral_asset_unit)
         / (collateral_asset_price * debt_asset_unit);
-    let max_collateral_to_liquidate = percent_mul(base_collateral, liquidation_bonus);
+    let max_collateral_to_liquidate = percent_mul(base_collateral, liquidation_bonus).unwrap();
 
     let mut collateral_amount: U256;
     let debt_amount_needed: U256;
@@ -143,7 +170,8 @@ async fn calculate_available_collateral_to_liquidate(
             (collateral_asset_price * collateral_amount * debt_asset_unit)
                 / (debt_asset_price * collateral_asset_unit),
             liquidation_bonus,
-        );
+        )
+        .unwrap();
     } else {
         collateral_amount = max_collateral_to_liquidate;
         debt_amount_needed = debt_to_cover;
@@ -159,9 +187,9 @@ async fn calculate_available_collateral_to_liquidate(
         (collateral_amount * collateral_asset_price) / collateral_asset_unit;
     if liquidation_protocol_fee_percentage != U256::ZERO {
         let bonus_collateral =
-            collateral_amount - percent_div(collateral_amount, liquidation_bonus);
+            collateral_amount - percent_div(collateral_amount, liquidation_bonus).unwrap();
         liquidation_protocol_fee =
-            percent_mul(bonus_collateral, liquidation_protocol_fee_percentage);
+            percent_mul(bonus_collateral, liquidation_protocol_fee_percentage).unwrap();
         collateral_amount -= liquidation_protocol_fee;
     }
 
@@ -176,12 +204,36 @@ async fn calculate_available_collateral_to_liquidate(
         debt_in_collateral_units - collateral_amount
     };
 
-    let (gas_used, gas_price, execution_gas_cost) = estimate_gas(provider.clone()).await;
-    // this assumes we will swap in 1% fee pools (could be more sophisticated)
-    // uniswap v3 fees are represented as hundredths of basis points: 1% == 100; 0,3% == 30; 0,05% == 5; 0,01% == 1
-    let swap_loss_factor = U256::from(100);
-    let swap_total_cost = percent_mul(collateral_amount, swap_loss_factor);
-    let total_cost = execution_gas_cost + swap_total_cost;
+    let (gas_used, gas_price, execution_gas_cost_in_wei) =
+        estimate_gas(provider.clone(), collateral_asset, debt_asset).await;
+    let execution_gas_cost = wei_to_asset_units(
+        execution_gas_cost_in_wei,
+        native_asset_price,
+        collateral_asset_price,
+        collateral_asset_unit,
+    );
+    // What the swaps actually return for the collateral (pool fees and price impact included),
+    // against what it's worth at oracle prices
+    let debt_asset_out = match quote_collateral_swap(
+        provider.clone(),
+        collateral_asset,
+        debt_asset,
+        collateral_amount,
+        block,
+    )
+    .await
+    {
+        Ok(swap_route) => swap_route.amount_out,
+        Err(e) => {
+            eprintln!("Error quoting the collateral swap: {}", e);
+            U256::ZERO
+        }
+    };
+    let debt_asset_out_in_collateral_units =
+        (debt_asset_out * debt_asset_price * collateral_asset_unit)
+            / (collateral_asset_price * debt_asset_unit);
+    let swap_total_cost = collateral_amount.saturating_sub(debt_asset_out_in_collateral_units);
+    let total_cost = execution_gas_cost.saturating_add(swap_total_cost);
     let net_profit = if total_cost > base_profit {
         U256::MIN
     } else {
@@ -189,13 +241,13 @@ async fn calculate_available_collateral_to_liquidate(
     };
     println!("\t\tv3.3 profit calculation:");
     println!(
-        "\t\t\tbase profit = abs(collateral amount - debt in collateral units) = abs({} - {}) = {} ($ {})",
+        "\t\t\tbase profit = abs(collateral amount - debt in collateral units) = abs({} - {}) = {} (base currency {})",
         collateral_amount,
         debt_in_collateral_units,
         base_profit,
         format_units(
             base_profit * collateral_asset_price,
-            8 + u8::try_from(collateral_decimals).unwrap()
+            base_currency.decimals + u8::try_from(collateral_decimals).unwrap()
         )
         .unwrap()
     );
@@ -204,11 +256,14 @@ async fn calculate_available_collateral_to_liquidate(
         debt_in_collateral_units
     );
     println!(
-        "\t\t\texecution gas cost (used, price, total): {}, {}, {}",
-        gas_used, gas_price, execution_gas_cost
+        "\t\t\texecution gas cost (used, price in wei, total in wei, total in collateral units): {}, {}, {}, {}",
+        gas_used, gas_price, execution_gas_cost_in_wei, execution_gas_cost
+    );
+    println!(
+        "\t\t\tswap total cost = col amount - quoted swap output in col units = {} - {} = {}",
+        collateral_amount, debt_asset_out_in_collateral_units, swap_total_cost
     );
-    println!("\t\t\tswap total cost: {}", swap_total_cost);
-    println!("\t\t\tnet profit = col amount - debt in col units - execution cost - swap cost = {} ($ {})", net_profit, format_units(net_profit * collateral_asset_price, 8 + u8::try_from(collateral_decimals).unwrap()).unwrap());
+    println!("\t\t\tnet profit = col amount - debt in col units - execution cost - swap cost = {} (base currency {})", net_profit, format_units(net_profit * collateral_asset_price, base_currency.decimals + u8::try_from(collateral_decimals).unwrap()).unwrap());
 
     (
         collateral_amount,
@@ -228,14 +283,19 @@ async fn get_best_liquidation_opportunity(
     assets_supplied: Vec<UserReserveData>,
     reserves_configuration: HashMap<Address, ReserveConfigurationEnhancedData>,
     reserves_data: Vec<AggregatedReserveData>,
+    reserves_list: &[Address],
     provider: Arc<RootProvider<PubSubFrontend>>,
     user_address: Address,
     health_factor_v33: U256,
     total_debt_in_base_currency: U256,
+    user_emode: &Option<UserEModeCategory>,
+    base_currency: &BaseCurrency,
+    block: BlockId,
 ) -> Option<BestPair> {
     // Essentially, inspect executeLiquidationCall internals
     // for every collateral/debt pair possible
     let mut best_pair: Option<BestPair> = None;
+    let native_asset_price = get_asset_price(provider.clone(), WETH, block).await;
     let total_combinations = assets_borrowed.len() * assets_supplied.len();
     let mut current_count = 1;
     for borrowed_reserve in assets_borrowed
@@ -256,6 +316,14 @@ async fn get_best_liquidation_opportunity(
                 supplied_reserve.clone(),
                 reserves_configuration.clone(),
             );
+            if let Some(asset) = KILL_SWITCH.first_blocked([
+                supplied_reserve.underlyingAsset,
+                borrowed_reserve.underlyingAsset,
+            ]) {
+                println!("\t\tSkipped: {} is in the kill switch list", asset);
+                current_count += 1;
+                continue;
+            }
 
             // begin section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L234-L238
             let (collateral_reserve, user_collateral_balance, debt_reserve, user_reserve_debt) =
@@ -265,6 +333,7 @@ async fn get_best_liquidation_opportunity(
                     borrowed_reserve,
                     provider.clone(),
                     user_address,
+                    block,
                 )
                 .await
                 {
@@ -283,11 +352,23 @@ async fn get_best_liquidation_opportunity(
             // begin section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L252-L276
             // TODO(Hernan): you should at least visually check if liquidationBonus is returning what you're expecting, since
             // the solidity implementation uses bit masking to get the value.
-            let liquidation_bonus = collateral_reserve.reserveLiquidationBonus;
+            let collateral_reserve_index =
+                match get_reserve_index(reserves_list, collateral_reserve.underlyingAsset) {
+                    Ok(collateral_reserve_index) => collateral_reserve_index,
+                    Err(e) => {
+                        eprintln!("Skipping pair: {}", e);
+                        continue;
+                    }
+                };
+            let liquidation_bonus = get_effective_liquidation_bonus(
+                collateral_reserve_index,
+                collateral_reserve.reserveLiquidationBonus,
+                user_emode,
+            );
             let collateral_asset_price =
-                get_asset_price(provider.clone(), supplied_reserve.underlyingAsset).await;
+                get_asset_price(provider.clone(), supplied_reserve.underlyingAsset, block).await;
             let debt_asset_price =
-                get_asset_price(provider.clone(), borrowed_reserve.underlyingAsset).await;
+                get_asset_price(provider.clone(), borrowed_reserve.underlyingAsset, block).await;
             let collateral_asset_unit = U256::from(10).pow(collateral_reserve.decimals);
             let debt_asset_unit = U256::from(10).pow(debt_reserve.decimals);
             let user_reserve_debt_in_base_currency =
@@ -316,7 +397,10 @@ async fn get_best_liquidation_opportunity(
                 total_debt_in_base_currency,
                 debt_asset_unit,
                 debt_asset_price,
-            );
+                // Same as the contracts bpchecker was written against
+                &LiquidationParams::FALLBACK,
+            )
+            .unwrap();
             println!(
                 "\t\tv3.3 actual debt to liquidate: {}",
                 actual_debt_to_liquidate
@@ -336,7 +420,10 @@ async fn get_best_liquidation_opportunity(
             ) = calculate_available_collateral_to_liquidate(
                 provider.clone(),
                 collateral_reserve.underlyingAsset,
+                debt_reserve.underlyingAsset,
+                native_asset_price,
                 collateral_reserve.decimals,
+                base_currency,
                 collateral_asset_price,
                 collateral_asset_unit,
                 debt_asset_price,
@@ -344,22 +431,29 @@ async fn get_best_liquidation_opportunity(
                 actual_debt_to_liquidate,
                 user_collateral_balance,
                 liquidation_bonus,
+                block,
             )
             .await;
             println!("\t\tv3.3 actual collateral to liquidate, actual debt to liquidate, fee amount, collateral to liquidate in base currency = {} / {} / {} / {}", actual_collateral_to_liquidate, actual_debt_to_liquidate, liquidation_protocol_fee_amount, collateral_to_liquidate_in_base_currency);
             let best_liquidity_provider = get_best_liquidity_provider(
                 provider.clone(),
+                &AaveMarket::core(),
                 borrowed_reserve.underlyingAsset,
                 actual_debt_to_liquidate,
+                block,
             )
             .await;
             println!(
-                "\t\ttake liquidity from {:?}",
-                best_liquidity_provider.source
+                "\t\ttake liquidity from {:?} (premium {})",
+                best_liquidity_provider.source, best_liquidity_provider.premium
             );
             for reason in best_liquidity_provider.reasons.iter() {
                 println!("\t\t\t- {}", reason);
             }
+            // Same as profito, the premium is repaid out of the swapped collateral
+            let net_profit = net_profit.saturating_sub(
+                best_liquidity_provider.premium * debt_asset_price / debt_asset_unit,
+            );
             println!(""); // space before next pair
                           // end section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L309
 
@@ -377,8 +471,15 @@ async fn get_best_liquidation_opportunity(
                     printable_net_profit: String::from(""), // we don't use printable_net_profit here
                     actual_collateral_to_liquidate,
                     actual_debt_to_liquidate,
+                    debt_to_liquidate_in_base_currency: actual_debt_to_liquidate * debt_asset_price
+                        / debt_asset_unit,
                     liquidation_protocol_fee_amount,
                     flash_loan_source: best_liquidity_provider.source,
+                    flash_loan_premium: best_liquidity_provider.premium,
+                    weth_price: native_asset_price,
+                    execution_mode: ExecutionMode::SwapAndRepay,
+                    // No bundle is built here, so the route isn't kept
+                    swap_route: SwapRoute::default(),
                 });
             }
 
@@ -455,18 +556,28 @@ fn print_liquidate_in_prod_instructions(
         collateral_to_weth_fee.to_string(), // collateralToWethFee
         weth_to_debt_fee.to_string(),      // wethToDebtFee
         BRIBE_IN_BASIS_POINTS,             // bribePercentBps
-        1,                                 // flashLoanSource
-        0,                                 // aavePremium
+        best.flash_loan_source as u8,      // flashLoanSource
+        best.aave_premium(),               // aavePremium
     );
     println!("\n");
 }
 
 #[tokio::main]
 async fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // --block N can go anywhere, everything else is positional
+    let mut historical_block: Option<u64> = None;
+    if let Some(position) = args.iter().position(|arg| arg == "--block") {
+        let value = args
+            .get(position + 1)
+            .expect("--block needs a block number");
+        historical_block = Some(value.parse().expect("Invalid block number"));
+        args.drain(position..position + 2);
+    }
 
     if args.len() <= 2 {
-        eprintln!("Usage: {} <address> [path_to_ipc]", args[0]);
+        eprintln!("Usage: {} <address> [path_to_ipc] [--block N]", args[0]);
         std::process::exit(1);
     }
 
@@ -479,19 +590,31 @@ async fn main() {
     let provider = ProviderBuilder::new().on_ipc(ipc).await.unwrap();
     let provider = Arc::new(provider);
 
-    let block_number = provider.get_block_number().await.unwrap_or_default();
+    let block_number = match historical_block {
+        Some(block_number) => block_number,
+        None => provider.get_block_number().await.unwrap_or_default(),
+    };
+    let block = historical_block.map_or(BlockId::latest(), BlockId::number);
     println!(
         "Received address: {:?} at block {} (IPC: {})",
         user_address, block_number, ipc_path,
     );
+    if historical_block.is_some() {
+        println!("Reading state at historical block {} (requires an archive node), gas prices are current", block_number);
+    }
+
+    // bpchecker only knows about the core market
+    let market = AaveMarket::core();
 
     // Get user reserves data
-    let user_reserves_data = get_user_reserves_data(provider.clone(), user_address).await;
+    let user_reserves_data =
+        get_user_reserves_data(provider.clone(), &market, user_address, block).await;
 
     // Create reserve configuration struct
-    let reserves_configuration = generate_reserve_details_by_asset(provider.clone())
-        .await
-        .unwrap();
+    let reserves_configuration =
+        generate_reserve_details_by_asset(provider.clone(), &market, block)
+            .await
+            .unwrap();
     let assets_borrowed = user_reserves_data
         .iter()
         .filter(|reserve| reserve.scaledVariableDebt > U256::ZERO)
@@ -510,22 +633,49 @@ async fn main() {
     // `reserves_data` is Vec<AggregatedReserveData> and holds information about reserves in general,
     // while `user_reserves_data` holds information about a particular user's reserves
     // they're not the same
-    let reserves_list = get_reserves_list(provider.clone()).await.unwrap();
-    let reserves_data = get_reserves_data(provider.clone()).await.unwrap();
+    let reserves_list = get_reserves_list(provider.clone(), &market, block)
+        .await
+        .unwrap();
+    let reserves_data = get_reserves_data_at(provider.clone(), &market, block)
+        .await
+        .unwrap();
+    let base_currency = get_base_currency_at(provider.clone(), &market, block)
+        .await
+        .unwrap();
+    println!(
+        "Base currency: {} ({} decimals, 1 unit = {} USD)",
+        base_currency.address,
+        base_currency.decimals,
+        base_currency.to_usd(base_currency.unit)
+    );
 
     // max_traces is 0 because we only use the price fetching feature for compatibility with
     // `calculate_user_account_data`, not the actual cache.
     let price_cache = Arc::new(Mutex::new(PriceCache::new(0)));
 
+    let user_emode = get_user_emode_category_at(provider.clone(), &market, user_address, block)
+        .await
+        .unwrap();
+    match &user_emode {
+        Some(emode) => println!(
+            "User is in e-mode category {} (LT: {}, LB: {}, collateral bitmap: {:#x})",
+            emode.id, emode.liquidation_threshold, emode.liquidation_bonus, emode.collateral_bitmap
+        ),
+        None => println!("User is not in e-mode"),
+    }
+
     // Calculate user account data
     let (total_collateral_in_base_currency, total_debt_in_base_currency, health_factor_v33) =
         match calculate_user_account_data(
             price_cache.clone(),
             provider.clone(),
+            &market,
             user_address,
             reserves_list.clone(),
             reserves_data.clone(),
+            &user_emode,
             None,
+            block,
         )
         .await
         {
@@ -549,7 +699,7 @@ async fn main() {
         format_units(health_factor_v33, "eth").unwrap()
     );
 
-    let user_health_factor = get_user_health_factor(provider.clone(), user_address).await;
+    let user_health_factor = get_user_health_factor(provider.clone(), user_address, block).await;
     println!("\n### User HF (value GET'd) ###");
     println!("\t {}", format_units(user_health_factor, "eth").unwrap());
 
@@ -604,10 +754,14 @@ async fn main() {
         assets_supplied,
         reserves_configuration.clone(),
         reserves_data.clone(),
+        &reserves_list,
         provider.clone(),
         user_address,
         health_factor_v33,
         total_debt_in_base_currency,
+        &user_emode,
+        &base_currency,
+        block,
     )
     .await
     {
@@ -633,9 +787,14 @@ async fn main() {
         println!("\t\treceiveAToken = false,");
         println!("\t)");
 
-        let (collateral_to_weth_fee, weth_to_debt_fee) =
-            calculate_best_swap_fees(provider.clone(), best.collateral_asset, best.debt_asset)
-                .await;
+        // Nothing is submitted, so a use of the experiment is given back right away
+        let (collateral_to_weth_fee, weth_to_debt_fee, _) = calculate_best_swap_fees(
+            provider.clone(),
+            best.collateral_asset,
+            best.debt_asset,
+            block,
+        )
+        .await;
         let price_update_tx_hash = std::env::var("PRICE_UPDATE_TX")
             .ok()
             .and_then(|hash| hash.parse::<H256>().ok())
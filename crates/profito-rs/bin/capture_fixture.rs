@@ -0,0 +1,179 @@
+//! `profito-cli capture-fixture`, only built with the `fixtures` feature so profito itself
+//! doesn't carry the vendored fixtures.
+
+use alloy::{
+    primitives::{Address, Bytes},
+    providers::{IpcConnect, Provider, ProviderBuilder, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::{BlockId, TransactionInput, TransactionRequest},
+    sol_types::SolCall,
+};
+use overlord_shared::{
+    constants::AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS,
+    fixtures::{ReservesFixture, UserFixture},
+    market::{market_presets_from_env, AaveMarket},
+    sol_bindings::{pool::AaveV3Pool, AaveUIPoolDataProvider},
+};
+use std::{path::PathBuf, sync::Arc};
+
+/// Return data of `call` to `to` at `block`, as the node sent it
+async fn call_raw<C: SolCall>(
+    provider: &RootProvider<PubSubFrontend>,
+    to: Address,
+    call: C,
+    block: BlockId,
+) -> Result<Bytes, String> {
+    let request = TransactionRequest::default()
+        .to(to)
+        .input(TransactionInput::new(call.abi_encode().into()));
+    provider
+        .call(&request)
+        .block(block)
+        .await
+        .map_err(|e| format!("Error trying to call {}: {}", C::SIGNATURE, e))
+}
+
+pub async fn capture_fixture(
+    out: PathBuf,
+    ipc_path: String,
+    market_name: String,
+    block: Option<u64>,
+    users: Vec<Address>,
+    description: String,
+) {
+    let provider = match ProviderBuilder::new()
+        .on_ipc(IpcConnect::new(ipc_path))
+        .await
+    {
+        Ok(provider) => Arc::new(provider),
+        Err(e) => {
+            eprintln!("Failed to connect to the node: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let Some(preset) = market_presets_from_env()
+        .into_iter()
+        .find(|preset| preset.name.eq_ignore_ascii_case(&market_name))
+    else {
+        eprintln!("Unknown market {}", market_name);
+        std::process::exit(1);
+    };
+    let market = match AaveMarket::resolve(provider.clone(), preset).await {
+        Ok(market) => market,
+        Err(e) => {
+            eprintln!("Failed to resolve the {} market: {}", market_name, e);
+            std::process::exit(1);
+        }
+    };
+    let block_number = match block {
+        Some(block) => block,
+        None => match provider.get_block_number().await {
+            Ok(head) => head,
+            Err(e) => {
+                eprintln!("Failed to get the latest block: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+    let chain_id = match provider.get_chain_id().await {
+        Ok(chain_id) => chain_id,
+        Err(e) => {
+            eprintln!("Failed to get the chain id: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let at = BlockId::number(block_number);
+
+    let reserves_data = match call_raw(
+        &provider,
+        AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS,
+        AaveUIPoolDataProvider::getReservesDataCall {
+            provider: market.addresses_provider,
+        },
+        at,
+    )
+    .await
+    {
+        Ok(reserves_data) => reserves_data,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut user_fixtures = vec![];
+    for user in users {
+        let user_reserves_data = match call_raw(
+            &provider,
+            AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS,
+            AaveUIPoolDataProvider::getUserReservesDataCall {
+                provider: market.addresses_provider,
+                user,
+            },
+            at,
+        )
+        .await
+        {
+            Ok(user_reserves_data) => user_reserves_data,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        let pool = AaveV3Pool::new(market.pool, provider.clone());
+        let user_configuration = match pool.getUserConfiguration(user).block(at).call().await {
+            Ok(user_config) => user_config._0.data,
+            Err(e) => {
+                eprintln!("Error trying to call getUserConfiguration: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let emode_category = match pool.getUserEMode(user).block(at).call().await {
+            Ok(response) => response._0.saturating_to::<u8>(),
+            Err(e) => {
+                eprintln!("Error trying to call getUserEMode: {}", e);
+                std::process::exit(1);
+            }
+        };
+        user_fixtures.push(UserFixture {
+            address: user,
+            user_configuration,
+            emode_category,
+            user_reserves_data,
+        });
+    }
+
+    let fixture = ReservesFixture {
+        description,
+        chain_id,
+        block_number: Some(block_number),
+        market,
+        reserves_data,
+        users: user_fixtures,
+    };
+    // Decoded the way loading it will, so a fixture that can't be loaded isn't written
+    let reserves = match fixture.reserves_data() {
+        Ok(reserves) => reserves,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    for user in fixture.users.iter() {
+        if let Err(e) = user.user_reserves_data() {
+            eprintln!("{} of {}", e, user.address);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = fixture.write(&out) {
+        eprintln!("Failed to write {}: {}", out.display(), e);
+        std::process::exit(1);
+    }
+    println!(
+        "Wrote {} reserves and {} users of the {} market at block {} to {}",
+        reserves.len(),
+        fixture.users.len(),
+        fixture.market.name,
+        block_number,
+        out.display()
+    );
+}
@@ -0,0 +1,1024 @@
+//! # profito-cli
+//!
+//! Debugging commands for profito-rs.
+//!
+//! ## Commands
+//!
+//! - `replay-sim <snapshot>`: re-runs the calculation recorded in a simulation snapshot (see
+//!   `profito_rs::snapshot`) against an Anvil fork of the snapshot block, and compares the result
+//!   with what profito and vega got at the time. The fork source must be an archive node unless the
+//!   snapshot is very recent.
+//! - `decode-revert <data>`: classifies hex revert data (Aave error codes, Uniswap and Balancer
+//!   errors, panics, custom errors), see `profito_rs::revert`
+//! - `check-math`: checks the overflow-checked wad/ray/percent math in `overlord_shared::math`
+//!   against a 512-bit reference implementation on pseudo-random inputs. The known vectors are
+//!   tests of that module.
+//! - `check-parity`: recomputes recent on-chain liquidations with profito's math at the block
+//!   before each one, and compares the amounts with the ones in their `LiquidationCall` events.
+//!   Needs an archive node unless the liquidations are very recent.
+//! - `capture-fixture <out>`: records the reserves data of a market and the reserves and
+//!   configuration of some of its users at a block, as a fixture `overlord_shared::fixtures` can
+//!   load. Vendored fixtures go under `crates/overlord-shared/fixtures/reserves`. Only built with
+//!   the `fixtures` feature.
+//!
+//! ## Output
+//!
+//! 1. **Inputs**: Differences between the state captured in the snapshot and the state on the fork
+//! 2. **Replay**: Account data recomputed with the snapshot prices
+//! 3. **On-chain**: Account data reported by the pool after applying the pending price update tx
+//!
+
+#[cfg(feature = "fixtures")]
+mod capture_fixture;
+
+use alloy::{
+    node_bindings::Anvil,
+    primitives::{Address, Bytes, B256, U256, U512},
+    providers::{IpcConnect, Provider, ProviderBuilder, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::{BlockId, Filter},
+    sol_types::SolEvent,
+};
+use clap::{Parser, Subcommand};
+use overlord_shared::{
+    common::{
+        get_reserves_data, get_reserves_data_at, get_user_emode_category,
+        get_user_emode_category_at,
+    },
+    error::NodeError,
+    market::{market_presets_from_env, AaveMarket},
+    math::{
+        percent_div, percent_mul, ray_div, ray_mul, wad_div, wad_mul, MathError,
+        HALF_PERCENTAGE_FACTOR, HALF_RAY, HALF_WAD, PERCENTAGE_FACTOR, RAY, WAD,
+    },
+    sol_bindings::{pool::AaveV3Pool, AaveOracle, AaveProtocolDataProvider},
+};
+use profito_rs::{
+    cache::PriceCache,
+    calculations::{
+        calculate_actual_debt_to_liquidate, calculate_liquidation_amounts,
+        calculate_user_account_data, calculate_user_balances, get_effective_liquidation_bonus,
+        get_reserve_index, get_reserves_list,
+    },
+    error::ProfitoError,
+    liquidation_params::{LiquidationParams, LIQUIDATION_LOGIC_ADDRESS_ENV},
+    revert::RevertReason,
+    snapshot::{
+        health_factors_diverge, read_simulation_snapshot, SimulationSnapshot, SnapshotAccountData,
+        SnapshotReserve,
+    },
+    utils::get_user_reserves_data,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Mutex;
+
+#[derive(Parser, Debug)]
+#[clap(name = "profito-cli", about = "Debugging commands for profito-rs")]
+struct ProfitoCliArgs {
+    #[clap(subcommand)]
+    command: ProfitoCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ProfitoCommand {
+    /// Re-run a simulation snapshot against a fork of the block it was captured at
+    ReplaySim {
+        /// Path to the snapshot json file
+        snapshot: PathBuf,
+        /// Node to fork from. Must be able to serve state at the snapshot block.
+        #[clap(long, default_value = "/tmp/reth.ipc")]
+        fork_url: String,
+        /// Fork this block instead of the one recorded in the snapshot
+        #[clap(long)]
+        block: Option<u64>,
+    },
+    /// Decode and classify the revert data of a failed call or transaction
+    DecodeRevert {
+        /// Hex encoded revert data, e.g. 0x08c379a0...
+        data: Bytes,
+    },
+    /// Check the wad/ray/percent math against reference vectors and random inputs
+    CheckMath {
+        /// Random inputs tried per operation
+        #[clap(long, default_value_t = 100_000)]
+        iterations: u64,
+        /// Seed for the random inputs. Defaults to the current time, and is printed so failures
+        /// can be reproduced.
+        #[clap(long)]
+        seed: Option<u64>,
+    },
+    /// Recompute recent on-chain liquidations with profito's math and compare the amounts
+    CheckParity {
+        /// Node to read from. Must be able to serve state at the blocks of the liquidations.
+        #[clap(long, default_value = "/tmp/reth.ipc")]
+        ipc_path: String,
+        /// Market the liquidations happened on
+        #[clap(long, default_value = "core")]
+        market: String,
+        /// How many blocks back to look for liquidations
+        #[clap(long, default_value_t = 7_200)]
+        blocks: u64,
+        /// Most liquidations compared, the most recent ones
+        #[clap(long, default_value_t = 50)]
+        limit: usize,
+        /// Difference (in basis points) up to which amounts are considered equal. The state is
+        /// read a block before the liquidation, so a block of interest is always missing.
+        #[clap(long, default_value_t = 1)]
+        tolerance_bps: u64,
+    },
+    /// Record the reserves of a market and some of its users at a block as a fixture
+    #[cfg(feature = "fixtures")]
+    CaptureFixture {
+        /// Path the fixture json file is written to
+        out: PathBuf,
+        /// Node to read from. Must be able to serve state at the block.
+        #[clap(long, default_value = "/tmp/reth.ipc")]
+        ipc_path: String,
+        /// Market to read
+        #[clap(long, default_value = "core")]
+        market: String,
+        /// Read this block instead of the latest one
+        #[clap(long)]
+        block: Option<u64>,
+        /// Users whose reserves and configuration are recorded, can be repeated
+        #[clap(long = "user")]
+        users: Vec<Address>,
+        /// What the fixture is for, kept in it
+        #[clap(long)]
+        description: String,
+    },
+}
+
+fn print_account_data(label: &str, account_data: &SnapshotAccountData) {
+    println!(
+        "{:<10} collateral: {:>30} | debt: {:>30} | HF: {}",
+        label,
+        account_data.total_collateral_base,
+        account_data.total_debt_base,
+        account_data.health_factor,
+    );
+}
+
+/// Prints every difference between the inputs captured in the snapshot and the ones on the fork.
+/// Returns the number of differences found.
+async fn compare_inputs(
+    snapshot: &SimulationSnapshot,
+    provider: Arc<RootProvider<PubSubFrontend>>,
+) -> usize {
+    let mut differences = 0;
+
+    let user_configuration = match AaveV3Pool::new(snapshot.market.pool, provider.clone())
+        .getUserConfiguration(snapshot.user)
+        .call()
+        .await
+    {
+        Ok(user_config) => user_config._0.data,
+        Err(e) => {
+            eprintln!("Error trying to call getUserConfiguration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if user_configuration != snapshot.user_configuration {
+        differences += 1;
+        println!(
+            "  user configuration: snapshot {:#x}, fork {:#x}",
+            snapshot.user_configuration, user_configuration
+        );
+    }
+
+    let user_emode_category =
+        match get_user_emode_category(provider.clone(), &snapshot.market, snapshot.user).await {
+            Ok(user_emode) => user_emode.map_or(0, |emode| emode.id),
+            Err(e) => {
+                eprintln!("Error trying to get user e-mode category: {}", e);
+                std::process::exit(1);
+            }
+        };
+    if user_emode_category != snapshot.user_emode_category {
+        differences += 1;
+        println!(
+            "  e-mode category: snapshot {}, fork {}",
+            snapshot.user_emode_category, user_emode_category
+        );
+    }
+
+    let fork_user_reserves = get_user_reserves_data(
+        provider.clone(),
+        &snapshot.market,
+        snapshot.user,
+        BlockId::latest(),
+    )
+    .await
+    .into_iter()
+    .map(|reserve| (reserve.underlyingAsset, reserve))
+    .collect::<HashMap<Address, _>>();
+    for user_reserve in &snapshot.user_reserves {
+        match fork_user_reserves.get(&user_reserve.underlying_asset) {
+            Some(fork_reserve) => {
+                if fork_reserve.scaledATokenBalance != user_reserve.scaled_a_token_balance
+                    || fork_reserve.scaledVariableDebt != user_reserve.scaled_variable_debt
+                    || fork_reserve.usageAsCollateralEnabledOnUser
+                        != user_reserve.usage_as_collateral_enabled
+                {
+                    differences += 1;
+                    println!(
+                        "  user reserve {}: snapshot (aToken {}, debt {}, collateral {}), fork (aToken {}, debt {}, collateral {})",
+                        user_reserve.underlying_asset,
+                        user_reserve.scaled_a_token_balance,
+                        user_reserve.scaled_variable_debt,
+                        user_reserve.usage_as_collateral_enabled,
+                        fork_reserve.scaledATokenBalance,
+                        fork_reserve.scaledVariableDebt,
+                        fork_reserve.usageAsCollateralEnabledOnUser,
+                    );
+                }
+            }
+            None => {
+                differences += 1;
+                println!(
+                    "  user reserve {} is in the snapshot but not on the fork",
+                    user_reserve.underlying_asset
+                );
+            }
+        }
+    }
+    for asset in fork_user_reserves.keys() {
+        if !snapshot
+            .user_reserves
+            .iter()
+            .any(|user_reserve| &user_reserve.underlying_asset == asset)
+        {
+            differences += 1;
+            println!(
+                "  user reserve {} is on the fork but not in the snapshot",
+                asset
+            );
+        }
+    }
+
+    let fork_reserves = match get_reserves_data(provider.clone(), &snapshot.market).await {
+        Ok(reserves_data) => reserves_data
+            .iter()
+            .map(SnapshotReserve::from)
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            eprintln!("Error trying to get reserves data: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if fork_reserves.len() != snapshot.reserves.len() {
+        differences += 1;
+        println!(
+            "  reserves count: snapshot {}, fork {}",
+            snapshot.reserves.len(),
+            fork_reserves.len()
+        );
+    }
+    for (snapshot_reserve, fork_reserve) in snapshot.reserves.iter().zip(fork_reserves.iter()) {
+        // Indexes accrue every block, so only parameters and ordering are compared
+        if snapshot_reserve.underlying_asset != fork_reserve.underlying_asset
+            || snapshot_reserve.liquidation_threshold != fork_reserve.liquidation_threshold
+            || snapshot_reserve.liquidation_bonus != fork_reserve.liquidation_bonus
+            || snapshot_reserve.decimals != fork_reserve.decimals
+            || snapshot_reserve.virtual_acc_active != fork_reserve.virtual_acc_active
+        {
+            differences += 1;
+            println!(
+                "  reserve {}: snapshot {:?}, fork {:?}",
+                snapshot_reserve.symbol, snapshot_reserve, fork_reserve
+            );
+        }
+    }
+
+    differences
+}
+
+async fn replay_sim(snapshot_path: PathBuf, fork_url: String, block: Option<u64>) {
+    let snapshot = match read_simulation_snapshot(&snapshot_path) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let block_number = block.unwrap_or(snapshot.block_number);
+    println!(
+        "Replaying {} for {} on the {} market at block {} (fork of {})",
+        snapshot.trace_id, snapshot.user, snapshot.market.name, block_number, fork_url
+    );
+
+    let fork_path = format!("./replay_{}.ipc", snapshot.trace_id);
+    let mut anvil = Anvil::new()
+        .fork(fork_url)
+        .fork_block_number(block_number)
+        .args(vec![
+            "--ipc".to_string(),
+            fork_path.clone(),
+            "--auto-impersonate".to_string(),
+        ])
+        .spawn();
+    let provider = match ProviderBuilder::new()
+        .on_ipc(IpcConnect::new(fork_path.clone()))
+        .await
+    {
+        Ok(provider) => Arc::new(provider),
+        Err(e) => {
+            eprintln!("Failed to connect to fork: {}", e);
+            let _ = anvil.child_mut().kill();
+            std::process::exit(1);
+        }
+    };
+
+    // Step 1: make sure the fork sees the same state profito saw
+    println!("\nInputs (snapshot vs fork):");
+    let differences = compare_inputs(&snapshot, provider.clone()).await;
+    if differences == 0 {
+        println!("  no differences");
+    }
+
+    // Step 2: recompute with the exact same prices
+    let price_cache = Arc::new(Mutex::new(PriceCache::new(2)));
+    price_cache
+        .lock()
+        .await
+        .override_price(
+            snapshot.trace_id.clone(),
+            snapshot.market.oracle,
+            snapshot
+                .prices
+                .iter()
+                .map(|(asset, price)| (*asset, asset.to_string(), *price))
+                .collect(),
+        )
+        .await;
+    let reserves_list = get_reserves_list(provider.clone(), &snapshot.market, BlockId::latest())
+        .await
+        .unwrap();
+    let reserves_data = get_reserves_data(provider.clone(), &snapshot.market)
+        .await
+        .unwrap();
+    let user_emode = get_user_emode_category(provider.clone(), &snapshot.market, snapshot.user)
+        .await
+        .unwrap();
+    let replayed = match calculate_user_account_data(
+        price_cache,
+        provider.clone(),
+        &snapshot.market,
+        snapshot.user,
+        reserves_list,
+        reserves_data,
+        &user_emode,
+        Some(snapshot.trace_id.clone()),
+        BlockId::latest(),
+    )
+    .await
+    {
+        Ok((collateral, debt, hf)) => SnapshotAccountData {
+            total_collateral_base: collateral,
+            total_debt_base: debt,
+            health_factor: hf,
+        },
+        Err(e) => {
+            eprintln!("Error calculating user account data: {}", e);
+            let _ = anvil.child_mut().kill();
+            std::process::exit(1);
+        }
+    };
+
+    // Step 3: apply the pending price update and ask the pool
+    let on_chain = match &snapshot.raw_tx {
+        Some(raw_tx) => match provider.send_raw_transaction(raw_tx).await {
+            Ok(pending_tx) => match pending_tx.get_receipt().await {
+                Ok(receipt) => {
+                    println!(
+                        "\nPrice update tx mined on the fork at block {:?} (success: {})",
+                        receipt.block_number,
+                        receipt.status()
+                    );
+                    AaveV3Pool::new(snapshot.market.pool, provider.clone())
+                        .getUserAccountData(snapshot.user)
+                        .call()
+                        .await
+                        .ok()
+                        .map(|account_data| SnapshotAccountData {
+                            total_collateral_base: account_data.totalCollateralBase,
+                            total_debt_base: account_data.totalDebtBase,
+                            health_factor: account_data.healthFactor,
+                        })
+                }
+                Err(e) => {
+                    println!("\nFailed to get price update tx receipt: {}", e);
+                    None
+                }
+            },
+            Err(e) => {
+                println!("\nFailed to send price update tx to the fork: {}", e);
+                None
+            }
+        },
+        None => {
+            println!("\nSnapshot has no raw tx, skipping on-chain comparison");
+            None
+        }
+    };
+
+    println!("\nAccount data:");
+    print_account_data("vega", &snapshot.expected);
+    print_account_data("profito", &snapshot.simulated);
+    print_account_data("replay", &replayed);
+    if let Some(on_chain) = &on_chain {
+        print_account_data("on-chain", on_chain);
+    }
+
+    println!();
+    if replayed != snapshot.simulated {
+        println!("Replay differs from the recorded profito result: the calculation is not deterministic with these inputs, or the inputs changed (see above)");
+    } else {
+        println!("Replay matches the recorded profito result");
+    }
+    let reference = on_chain.as_ref().unwrap_or(&snapshot.expected);
+    if health_factors_diverge(reference.health_factor, replayed.health_factor) {
+        println!(
+            "Replayed HF still diverges from the {} HF",
+            if on_chain.is_some() {
+                "on-chain"
+            } else {
+                "vega"
+            }
+        );
+    } else {
+        println!("Replayed HF is within tolerance");
+    }
+
+    let _ = std::fs::remove_file(&fork_path);
+}
+
+type MathOp = fn(U256, U256) -> Result<U256, MathError>;
+
+/// How an operation rounds, i.e. what its reference implementation computes
+#[derive(Clone, Copy)]
+enum Rounding {
+    /// `(a * b + half_unit) / unit`
+    Mul { unit: U256, half_unit: U256 },
+    /// `(a * unit + b / 2) / b`
+    Div { unit: U256 },
+}
+
+const MATH_OPS: &[(&str, MathOp, Rounding)] = &[
+    (
+        "wad_mul",
+        wad_mul,
+        Rounding::Mul {
+            unit: WAD,
+            half_unit: HALF_WAD,
+        },
+    ),
+    ("wad_div", wad_div, Rounding::Div { unit: WAD }),
+    (
+        "ray_mul",
+        ray_mul,
+        Rounding::Mul {
+            unit: RAY,
+            half_unit: HALF_RAY,
+        },
+    ),
+    ("ray_div", ray_div, Rounding::Div { unit: RAY }),
+    (
+        "percent_mul",
+        percent_mul,
+        Rounding::Mul {
+            unit: PERCENTAGE_FACTOR,
+            half_unit: HALF_PERCENTAGE_FACTOR,
+        },
+    ),
+    (
+        "percent_div",
+        percent_div,
+        Rounding::Div {
+            unit: PERCENTAGE_FACTOR,
+        },
+    ),
+];
+
+/// The same operation computed on 512 bits, where it can't wrap. It overflows exactly when the
+/// 512-bit intermediate doesn't fit in 256 bits, which is what the Solidity revert conditions
+/// check for.
+fn reference(rounding: Rounding, a: U256, b: U256) -> Result<U256, MathError> {
+    let (numerator, denominator) = match rounding {
+        Rounding::Mul { unit, half_unit } => {
+            (U512::from(a) * U512::from(b) + U512::from(half_unit), unit)
+        }
+        Rounding::Div { unit } => {
+            if b.is_zero() {
+                return Err(MathError::DivisionByZero);
+            }
+            (
+                U512::from(a) * U512::from(unit) + U512::from(b / U256::from(2)),
+                b,
+            )
+        }
+    };
+    if numerator > U512::from(U256::MAX) {
+        return Err(MathError::Overflow);
+    }
+    Ok(U256::from(numerator / U512::from(denominator)))
+}
+
+/// xorshift64*, plenty for picking inputs and reproducible from the seed alone
+struct InputGenerator(u64);
+
+impl InputGenerator {
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A value of random bit length, so small, mid-sized and huge inputs are all likely
+    fn next_u256(&mut self) -> U256 {
+        let value = U256::from_limbs([
+            self.next_u64(),
+            self.next_u64(),
+            self.next_u64(),
+            self.next_u64(),
+        ]);
+        value >> (self.next_u64() % 257) as usize
+    }
+
+    /// A pair of inputs, a third of the time right around the overflow threshold for `b`
+    fn next_pair(&mut self, rounding: Rounding) -> (U256, U256) {
+        let a = self.next_u256();
+        let b = self.next_u256();
+        if self.next_u64() % 3 != 0 {
+            return (a, b);
+        }
+        let threshold = match rounding {
+            Rounding::Mul { half_unit, .. } if !b.is_zero() => (U256::MAX - half_unit) / b,
+            Rounding::Div { unit } => (U256::MAX - b / U256::from(2)) / unit,
+            _ => return (a, b),
+        };
+        let offset = U256::from(self.next_u64() % 3);
+        let a = if self.next_u64() % 2 == 0 {
+            threshold.saturating_sub(offset)
+        } else {
+            threshold.saturating_add(offset)
+        };
+        (a, b)
+    }
+}
+
+fn check_math(iterations: u64, seed: Option<u64>) {
+    let seed = seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |now| now.as_nanos() as u64)
+    });
+    println!("Seed: {}", seed);
+    let mut failures = 0;
+
+    // xorshift gets stuck at 0
+    let mut generator = InputGenerator(seed.max(1));
+    for (name, op, rounding) in MATH_OPS {
+        let mut overflows = 0;
+        for _ in 0..iterations {
+            let (a, b) = generator.next_pair(*rounding);
+            let expected = reference(*rounding, a, b);
+            let result = op(a, b);
+            if result == Err(MathError::Overflow) {
+                overflows += 1;
+            }
+            if result != expected {
+                failures += 1;
+                println!(
+                    "  {}({}, {}): reference {:?}, got {:?}",
+                    name, a, b, expected, result
+                );
+            }
+        }
+        println!(
+            "{:<12} {} random inputs, {} overflows",
+            name, iterations, overflows
+        );
+    }
+
+    if failures > 0 {
+        println!("{} mismatches", failures);
+        std::process::exit(1);
+    }
+    println!("All results match");
+}
+
+// eth_getLogs ranges, small enough for any node
+const LIQUIDATION_LOGS_RANGE: u64 = 2_000;
+
+/// What profito's math got wrong about a liquidation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ParityIssue {
+    HealthFactor,
+    CloseFactor,
+    CollateralCap,
+    Bonus,
+    Unreadable,
+}
+
+impl ParityIssue {
+    fn description(&self) -> &'static str {
+        match self {
+            ParityIssue::HealthFactor => {
+                "the user wasn't liquidatable (HF or prices, or an earlier tx of the same block)"
+            }
+            ParityIssue::CloseFactor => "more debt was repaid than the close factor allows",
+            ParityIssue::CollateralCap => "all the collateral was taken, but the amounts differ",
+            ParityIssue::Bonus => "same debt, different collateral (bonus, protocol fee or prices)",
+            ParityIssue::Unreadable => "the state couldn't be read, or the math failed",
+        }
+    }
+}
+
+/// A liquidation as profito would have computed it, for the debt the liquidator repaid
+struct RecomputedLiquidation {
+    health_factor: U256,
+    /// Most debt profito would have repaid
+    max_debt_to_liquidate: U256,
+    debt_to_liquidate: U256,
+    /// Net of the protocol fee, like liquidatedCollateralAmount
+    collateral_to_liquidate: U256,
+    /// The collateral didn't cover the debt plus the bonus, so all of it was taken
+    collateral_capped: bool,
+}
+
+/// Recomputes `liquidation` with the state of the block before it, and the prices of the block
+/// it's in. Liquidations usually backrun a price update of their own block, which a state read
+/// before the block wouldn't see.
+async fn recompute_liquidation(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
+    params: &LiquidationParams,
+    liquidation: &AaveV3Pool::LiquidationCall,
+    block_number: u64,
+) -> Result<RecomputedLiquidation, ProfitoError> {
+    let block = BlockId::number(block_number - 1);
+    let user = liquidation.user;
+    let user_reserve_data = get_user_reserves_data(provider.clone(), market, user, block).await;
+    let position = |asset: Address| {
+        user_reserve_data
+            .iter()
+            .find(|reserve| reserve.underlyingAsset == asset)
+    };
+    let (Some(supplied_reserve), Some(borrowed_reserve)) = (
+        position(liquidation.collateralAsset),
+        position(liquidation.debtAsset),
+    ) else {
+        return Err(ProfitoError::InconsistentState(
+            "the user had no position in the pair before the liquidation".to_string(),
+        ));
+    };
+    let reserves_list = get_reserves_list(provider.clone(), market, block).await?;
+    let collateral_reserve_index = get_reserve_index(&reserves_list, liquidation.collateralAsset)?;
+    let reserves_data = get_reserves_data_at(provider.clone(), market, block).await?;
+    let user_emode = get_user_emode_category_at(provider.clone(), market, user, block).await?;
+
+    let oracle = AaveOracle::new(market.oracle, provider.clone());
+    let mut prices = vec![];
+    for reserve in user_reserve_data.iter().filter(|reserve| {
+        reserve.scaledATokenBalance > U256::ZERO || reserve.scaledVariableDebt > U256::ZERO
+    }) {
+        match oracle
+            .getAssetPrice(reserve.underlyingAsset)
+            .block(BlockId::number(block_number))
+            .call()
+            .await
+        {
+            Ok(response) => prices.push((
+                reserve.underlyingAsset,
+                reserve.underlyingAsset.to_string(),
+                response._0,
+            )),
+            Err(e) => return Err(NodeError::call("getAssetPrice", e).into()),
+        }
+    }
+    let price_of = |asset: Address| {
+        prices
+            .iter()
+            .find(|(price_asset, _, _)| *price_asset == asset)
+            .map_or(U256::ZERO, |(_, _, price)| *price)
+    };
+    let trace_id = format!("parity-{}", block_number);
+    let price_cache = Arc::new(Mutex::new(PriceCache::new(1)));
+    price_cache
+        .lock()
+        .await
+        .override_price(trace_id.clone(), market.oracle, prices.clone())
+        .await;
+    let (_, total_debt_in_base_currency, health_factor) = calculate_user_account_data(
+        price_cache,
+        provider.clone(),
+        market,
+        user,
+        reserves_list,
+        reserves_data.clone(),
+        &user_emode,
+        Some(trace_id),
+        block,
+    )
+    .await?;
+
+    let (collateral_reserve, user_collateral_balance, debt_reserve, user_reserve_debt) =
+        calculate_user_balances(
+            reserves_data.clone(),
+            supplied_reserve,
+            borrowed_reserve,
+            provider.clone(),
+            user,
+            block,
+        )
+        .await?;
+    let collateral_asset_price = price_of(liquidation.collateralAsset);
+    let debt_asset_price = price_of(liquidation.debtAsset);
+    let collateral_asset_unit = U256::from(10).pow(collateral_reserve.decimals);
+    let debt_asset_unit = U256::from(10).pow(debt_reserve.decimals);
+    let max_debt_to_liquidate = calculate_actual_debt_to_liquidate(
+        user_reserve_debt,
+        user_collateral_balance * collateral_asset_price / collateral_asset_unit,
+        user_reserve_debt * debt_asset_price / debt_asset_unit,
+        health_factor,
+        total_debt_in_base_currency,
+        debt_asset_unit,
+        debt_asset_price,
+        params,
+    )?;
+
+    let liquidation_bonus = get_effective_liquidation_bonus(
+        collateral_reserve_index,
+        collateral_reserve.reserveLiquidationBonus,
+        &user_emode,
+    );
+    let liquidation_protocol_fee_percentage =
+        match AaveProtocolDataProvider::new(market.data_provider, provider.clone())
+            .getLiquidationProtocolFee(liquidation.collateralAsset)
+            .block(block)
+            .call()
+            .await
+        {
+            Ok(response) => response._0,
+            Err(e) => return Err(NodeError::call("getLiquidationProtocolFee", e).into()),
+        };
+    // The liquidator may have asked for less than the most it could repay
+    let (collateral_to_liquidate, debt_to_liquidate, liquidation_protocol_fee) =
+        calculate_liquidation_amounts(
+            collateral_asset_price,
+            collateral_asset_unit,
+            debt_asset_price,
+            debt_asset_unit,
+            liquidation.debtToCover.min(max_debt_to_liquidate),
+            user_collateral_balance,
+            liquidation_bonus,
+            liquidation_protocol_fee_percentage,
+        )?;
+    Ok(RecomputedLiquidation {
+        health_factor,
+        max_debt_to_liquidate,
+        debt_to_liquidate,
+        collateral_to_liquidate,
+        collateral_capped: collateral_to_liquidate + liquidation_protocol_fee
+            >= user_collateral_balance,
+    })
+}
+
+fn within_tolerance(a: U256, b: U256, tolerance_bps: u64) -> bool {
+    a.abs_diff(b) * PERCENTAGE_FACTOR <= a.max(b) * U256::from(tolerance_bps)
+}
+
+/// The first thing profito got wrong about `liquidation`, if anything. A liquidator repaying less
+/// than the most it could is fine, but can't be told apart from a pool that allowed less than
+/// profito thinks, unless the collateral ran out.
+fn get_parity_issue(
+    recomputed: &RecomputedLiquidation,
+    liquidation: &AaveV3Pool::LiquidationCall,
+    tolerance_bps: u64,
+) -> Option<ParityIssue> {
+    if recomputed.health_factor >= WAD {
+        return Some(ParityIssue::HealthFactor);
+    }
+    if !within_tolerance(
+        liquidation.debtToCover,
+        recomputed.debt_to_liquidate,
+        tolerance_bps,
+    ) {
+        if liquidation.debtToCover > recomputed.max_debt_to_liquidate {
+            return Some(ParityIssue::CloseFactor);
+        }
+        return Some(ParityIssue::CollateralCap);
+    }
+    if !within_tolerance(
+        liquidation.liquidatedCollateralAmount,
+        recomputed.collateral_to_liquidate,
+        tolerance_bps,
+    ) {
+        if recomputed.collateral_capped {
+            return Some(ParityIssue::CollateralCap);
+        }
+        return Some(ParityIssue::Bonus);
+    }
+    None
+}
+
+async fn check_parity(
+    ipc_path: String,
+    market_name: String,
+    blocks: u64,
+    limit: usize,
+    tolerance_bps: u64,
+) {
+    let provider = match ProviderBuilder::new()
+        .on_ipc(IpcConnect::new(ipc_path))
+        .await
+    {
+        Ok(provider) => Arc::new(provider),
+        Err(e) => {
+            eprintln!("Failed to connect to the node: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let Some(preset) = market_presets_from_env()
+        .into_iter()
+        .find(|preset| preset.name.eq_ignore_ascii_case(&market_name))
+    else {
+        eprintln!("Unknown market {}", market_name);
+        std::process::exit(1);
+    };
+    let market = match AaveMarket::resolve(provider.clone(), preset).await {
+        Ok(market) => market,
+        Err(e) => {
+            eprintln!("Failed to resolve the {} market: {}", market_name, e);
+            std::process::exit(1);
+        }
+    };
+    // The params of today, which don't apply to liquidations before the last protocol upgrade
+    let liquidation_logic = std::env::var(LIQUIDATION_LOGIC_ADDRESS_ENV)
+        .ok()
+        .and_then(|value| Address::from_str(&value).ok());
+    let params = LiquidationParams::read(
+        provider.clone(),
+        &market,
+        liquidation_logic,
+        LiquidationParams::FALLBACK,
+    )
+    .await;
+
+    let head = match provider.get_block_number().await {
+        Ok(head) => head,
+        Err(e) => {
+            eprintln!("Failed to get the head block: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut liquidations: Vec<(u64, Option<B256>, AaveV3Pool::LiquidationCall)> = vec![];
+    let mut from_block = head.saturating_sub(blocks);
+    while from_block <= head {
+        let to_block = (from_block + LIQUIDATION_LOGS_RANGE - 1).min(head);
+        let filter = Filter::new()
+            .address(market.pool)
+            .event_signature(AaveV3Pool::LiquidationCall::SIGNATURE_HASH)
+            .from_block(from_block)
+            .to_block(to_block);
+        match provider.get_logs(&filter).await {
+            Ok(logs) => {
+                for log in logs {
+                    match (
+                        log.block_number,
+                        log.log_decode::<AaveV3Pool::LiquidationCall>(),
+                    ) {
+                        (Some(block_number), Ok(decoded)) => liquidations.push((
+                            block_number,
+                            log.transaction_hash,
+                            decoded.inner.data,
+                        )),
+                        (_, Err(e)) => eprintln!("Skipping undecodable LiquidationCall: {}", e),
+                        (None, _) => eprintln!("Skipping pending LiquidationCall"),
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to get the liquidations of blocks {}-{}: {}",
+                    from_block, to_block, e
+                );
+                std::process::exit(1);
+            }
+        }
+        from_block = to_block + 1;
+    }
+    let skipped = liquidations.len().saturating_sub(limit);
+    println!(
+        "{} liquidations on the {} market in the last {} blocks, comparing the last {}",
+        liquidations.len(),
+        market.name,
+        blocks,
+        liquidations.len() - skipped
+    );
+
+    let mut matches = 0;
+    let mut issues: BTreeMap<ParityIssue, usize> = BTreeMap::new();
+    for (block_number, tx_hash, liquidation) in liquidations.iter().skip(skipped) {
+        let label = format!(
+            "{} {} {}/{}",
+            block_number,
+            tx_hash.map_or("?".to_string(), |hash| hash.to_string()),
+            liquidation.collateralAsset,
+            liquidation.debtAsset
+        );
+        let issue = match recompute_liquidation(
+            provider.clone(),
+            &market,
+            &params,
+            liquidation,
+            *block_number,
+        )
+        .await
+        {
+            Ok(recomputed) => {
+                let issue = get_parity_issue(&recomputed, liquidation, tolerance_bps);
+                println!(
+                    "  {} | debt {} vs {} | collateral {} vs {} | HF {} | {}",
+                    label,
+                    liquidation.debtToCover,
+                    recomputed.debt_to_liquidate,
+                    liquidation.liquidatedCollateralAmount,
+                    recomputed.collateral_to_liquidate,
+                    recomputed.health_factor,
+                    issue.map_or("ok", |issue| issue.description())
+                );
+                issue
+            }
+            Err(e) => {
+                println!("  {} | {}", label, e);
+                Some(ParityIssue::Unreadable)
+            }
+        };
+        match issue {
+            Some(issue) => *issues.entry(issue).or_default() += 1,
+            None => matches += 1,
+        }
+    }
+
+    let compared = liquidations.len() - skipped;
+    if compared == 0 {
+        println!("Nothing to compare");
+        return;
+    }
+    println!(
+        "\nParity: {}/{} liquidations match ({:.1}%, on-chain amounts first)",
+        matches,
+        compared,
+        matches as f64 * 100.0 / compared as f64
+    );
+    for (issue, count) in &issues {
+        println!("  {:>4} {}", count, issue.description());
+    }
+    if !issues.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = ProfitoCliArgs::parse();
+    match args.command {
+        ProfitoCommand::ReplaySim {
+            snapshot,
+            fork_url,
+            block,
+        } => replay_sim(snapshot, fork_url, block).await,
+        ProfitoCommand::DecodeRevert { data } => {
+            let reason = RevertReason::decode(&data);
+            println!("[{}] {}", reason.category(), reason);
+        }
+        ProfitoCommand::CheckMath { iterations, seed } => check_math(iterations, seed),
+        ProfitoCommand::CheckParity {
+            ipc_path,
+            market,
+            blocks,
+            limit,
+            tolerance_bps,
+        } => check_parity(ipc_path, market, blocks, limit, tolerance_bps).await,
+        #[cfg(feature = "fixtures")]
+        ProfitoCommand::CaptureFixture {
+            out,
+            ipc_path,
+            market,
+            block,
+            users,
+            description,
+        } => {
+            capture_fixture::capture_fixture(out, ipc_path, market, block, users, description).await
+        }
+    }
+}
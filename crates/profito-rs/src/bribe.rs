@@ -0,0 +1,225 @@
+use once_cell::sync::Lazy;
+use std::{collections::VecDeque, fmt, str::FromStr, sync::Mutex};
+use tracing::{info, warn};
+
+use crate::calculations::BRIBE_IN_BASIS_POINTS;
+
+/// How the bribe of each bundle is picked: fixed (the default) or dynamic
+pub const BRIBE_STRATEGY_ENV: &str = "PROFITO_BRIBE_STRATEGY";
+/// Bribe (in basis points of the profit) of the fixed strategy
+pub const BRIBE_BPS_ENV: &str = "PROFITO_BRIBE_BPS";
+/// Least and most bribe (in basis points of the profit) the dynamic strategy picks
+pub const BRIBE_MIN_BPS_ENV: &str = "PROFITO_BRIBE_MIN_BPS";
+pub const BRIBE_MAX_BPS_ENV: &str = "PROFITO_BRIBE_MAX_BPS";
+/// Net profit (in USD) at which the dynamic strategy bids halfway on account of the profit
+pub const BRIBE_PROFIT_SCALE_USD_ENV: &str = "PROFITO_BRIBE_PROFIT_SCALE_USD";
+/// Gas price (in gwei) at which the dynamic strategy bids halfway on account of the gas
+pub const BRIBE_REFERENCE_GAS_GWEI_ENV: &str = "PROFITO_BRIBE_REFERENCE_GAS_GWEI";
+
+const DEFAULT_MIN_BPS: u16 = 8000;
+const DEFAULT_MAX_BPS: u16 = 9900;
+const DEFAULT_PROFIT_SCALE_USD: f64 = 500.0;
+const DEFAULT_REFERENCE_GAS_GWEI: f64 = 30.0;
+// Foxdie takes the bribe out of the profit, so it can't reach 100%
+const MAX_BRIBE_BPS: u16 = 9999;
+// How much each condition moves the dynamic bribe between its bounds, adding up to 1
+const PROFIT_WEIGHT: f64 = 0.4;
+const INCLUSION_WEIGHT: f64 = 0.25;
+const GAS_WEIGHT: f64 = 0.15;
+const COMPETITION_WEIGHT: f64 = 0.2;
+// The inclusion rate is taken over this many of the latest bundles whose outcome is known
+const INCLUSION_OUTCOMES_SIZE: usize = 50;
+const WEI_PER_GWEI: f64 = 1_000_000_000.0;
+
+pub static BRIBE_POLICY: Lazy<BribePolicy> = Lazy::new(BribePolicy::from_env);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BribeStrategy {
+    /// Always the same share of the profit
+    Fixed { bps: u16 },
+    /// Between `min_bps` and `max_bps`, higher the more a bundle makes (bigger liquidations draw
+    /// more competition), the fewer of the latest bundles landed, the higher the gas price and
+    /// the more others are calling the pools for the same assets
+    Dynamic {
+        min_bps: u16,
+        max_bps: u16,
+        profit_scale_usd: f64,
+        reference_gas_gwei: f64,
+    },
+}
+
+/// The bribe picked for a bundle, with what it was picked from so it can be logged and tuned
+#[derive(Clone, Copy, Debug)]
+pub struct BribeDecision {
+    pub bps: u16,
+    pub strategy: BribeStrategy,
+    pub net_profit_usd: f64,
+    /// None until the outcome of a bundle is known
+    pub inclusion_rate: Option<f64>,
+    pub gas_price_gwei: f64,
+    /// See CompetitionTracker::score()
+    pub competition: f64,
+}
+
+impl fmt::Display for BribeDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let strategy = match self.strategy {
+            BribeStrategy::Fixed { .. } => "fixed",
+            BribeStrategy::Dynamic { .. } => "dynamic",
+        };
+        let inclusion_rate = match self.inclusion_rate {
+            Some(rate) => format!("{:.0}%", rate * 100.0),
+            None => "unknown".to_string(),
+        };
+        write!(
+            f,
+            "{} bps ({}, profit ~${:.2}, inclusion rate {}, gas {:.2} gwei, competition {:.2})",
+            self.bps,
+            strategy,
+            self.net_profit_usd,
+            inclusion_rate,
+            self.gas_price_gwei,
+            self.competition
+        )
+    }
+}
+
+/// Picks the share of the profit each bundle bribes the builder with, and keeps track of how many
+/// of the latest bundles landed for the dynamic strategy
+pub struct BribePolicy {
+    strategy: BribeStrategy,
+    /// Whether each of the latest bundles landed, oldest first
+    outcomes: Mutex<VecDeque<bool>>,
+}
+
+impl BribePolicy {
+    pub fn from_env() -> Self {
+        let dynamic = match std::env::var(BRIBE_STRATEGY_ENV) {
+            Ok(value) if value.eq_ignore_ascii_case("dynamic") => true,
+            Ok(value) if value.eq_ignore_ascii_case("fixed") => false,
+            Ok(value) => {
+                warn!(
+                    "Invalid {} value {}, bribes are fixed",
+                    BRIBE_STRATEGY_ENV, value
+                );
+                false
+            }
+            Err(_) => false,
+        };
+        let valid_bps = |bps: &u16| *bps <= MAX_BRIBE_BPS;
+        let strategy = if dynamic {
+            let min_bps = parse_env(BRIBE_MIN_BPS_ENV, DEFAULT_MIN_BPS, valid_bps);
+            let max_bps = parse_env(BRIBE_MAX_BPS_ENV, DEFAULT_MAX_BPS, valid_bps);
+            let (min_bps, max_bps) = if min_bps > max_bps {
+                warn!(
+                    "{} is above {}, using {} and {}",
+                    BRIBE_MIN_BPS_ENV, BRIBE_MAX_BPS_ENV, DEFAULT_MIN_BPS, DEFAULT_MAX_BPS
+                );
+                (DEFAULT_MIN_BPS, DEFAULT_MAX_BPS)
+            } else {
+                (min_bps, max_bps)
+            };
+            BribeStrategy::Dynamic {
+                min_bps,
+                max_bps,
+                profit_scale_usd: parse_env(
+                    BRIBE_PROFIT_SCALE_USD_ENV,
+                    DEFAULT_PROFIT_SCALE_USD,
+                    |scale| *scale > 0.0,
+                ),
+                reference_gas_gwei: parse_env(
+                    BRIBE_REFERENCE_GAS_GWEI_ENV,
+                    DEFAULT_REFERENCE_GAS_GWEI,
+                    |gas| *gas > 0.0,
+                ),
+            }
+        } else {
+            BribeStrategy::Fixed {
+                bps: parse_env(BRIBE_BPS_ENV, BRIBE_IN_BASIS_POINTS, valid_bps),
+            }
+        };
+        info!("Bribe strategy: {:?}", strategy);
+        Self {
+            strategy,
+            outcomes: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Bribe for a bundle expected to make `net_profit_usd`, submitted when gas costs
+    /// `gas_price_wei`, for an opportunity with a `competition` score from 0 to 1
+    pub fn decide(
+        &self,
+        net_profit_usd: f64,
+        gas_price_wei: u128,
+        competition: f64,
+    ) -> BribeDecision {
+        let inclusion_rate = self.inclusion_rate();
+        let gas_price_gwei = gas_price_wei as f64 / WEI_PER_GWEI;
+        let bps = match self.strategy {
+            BribeStrategy::Fixed { bps } => bps,
+            BribeStrategy::Dynamic {
+                min_bps,
+                max_bps,
+                profit_scale_usd,
+                reference_gas_gwei,
+            } => {
+                // Each term goes from 0 to 1, and is 0.5 at its reference
+                let net_profit_usd = net_profit_usd.max(0.0);
+                let profit_term = net_profit_usd / (net_profit_usd + profit_scale_usd);
+                // Nothing known yet is as good as landing half of them
+                let inclusion_term = 1.0 - inclusion_rate.unwrap_or(0.5);
+                let gas_term = gas_price_gwei / (gas_price_gwei + reference_gas_gwei);
+                let pressure = PROFIT_WEIGHT * profit_term
+                    + INCLUSION_WEIGHT * inclusion_term
+                    + GAS_WEIGHT * gas_term
+                    + COMPETITION_WEIGHT * competition.clamp(0.0, 1.0);
+                let bps = min_bps as f64 + (max_bps - min_bps) as f64 * pressure.clamp(0.0, 1.0);
+                (bps.round() as u16).clamp(min_bps, max_bps)
+            }
+        };
+        BribeDecision {
+            bps,
+            strategy: self.strategy,
+            net_profit_usd,
+            inclusion_rate,
+            gas_price_gwei,
+            competition,
+        }
+    }
+
+    /// Records whether a submitted bundle landed
+    pub fn record_inclusion(&self, included: bool) {
+        let mut outcomes = self.outcomes.lock().unwrap();
+        outcomes.push_back(included);
+        if outcomes.len() > INCLUSION_OUTCOMES_SIZE {
+            outcomes.pop_front();
+        }
+    }
+
+    /// Share of the latest bundles that landed, None if no outcome is known yet
+    pub fn inclusion_rate(&self) -> Option<f64> {
+        let outcomes = self.outcomes.lock().unwrap();
+        if outcomes.is_empty() {
+            return None;
+        }
+        let included = outcomes.iter().filter(|included| **included).count();
+        Some(included as f64 / outcomes.len() as f64)
+    }
+}
+
+/// Value of `name`, or `default` if it's not set or isn't `valid`
+fn parse_env<T>(name: &str, default: T, valid: impl Fn(&T) -> bool) -> T
+where
+    T: FromStr + fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(value) => match value.parse::<T>() {
+            Ok(parsed) if valid(&parsed) => parsed,
+            Ok(_) | Err(_) => {
+                warn!("Invalid {} value {}, using {}", name, value, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,213 @@
+use ethers_core::types::Bytes;
+use ethers_signers::LocalWallet;
+use futures::future::join_all;
+use jsonrpsee::{core::client::ClientT, rpc_params};
+use mev_share::rpc::{MevApiClient, SendBundleRequest};
+use once_cell::sync::OnceCell;
+use std::{collections::HashMap, sync::Arc, sync::Mutex};
+use tracing::{info, warn};
+
+use crate::mev_share_service::{build_signed_client, MevShareClient};
+
+/// Comma separated `name=url` builders every bundle is also sent to, on top of the relay. URLs
+/// can be prefixed with the method the builder takes, `eth:` for eth_sendBundle (the default) or
+/// `mev:` for mev_sendBundle, e.g.
+/// `titan=https://rpc.titanbuilder.xyz,beaverbuild=https://rpc.beaverbuild.org,flashbots=mev:https://relay.flashbots.net`
+pub const BUILDERS_ENV: &str = "PROFITO_BUILDERS";
+
+/// How a builder takes bundles
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BundleMethod {
+    /// Raw txs for a single block. Bundles backrunning a tx known only by its hash can't be sent.
+    EthSendBundle,
+    /// Same bundle the relay gets
+    MevSendBundle,
+}
+
+#[derive(Debug, Default)]
+struct BuilderStats {
+    accepted: u64,
+    failed: u64,
+    landed: u64,
+}
+
+struct Builder {
+    name: String,
+    url: String,
+    method: BundleMethod,
+    /// Created the first time it's used
+    client: OnceCell<Arc<MevShareClient>>,
+}
+
+/// Sends each bundle to every builder of BUILDERS_ENV at the same time, signed with the same
+/// Flashbots key as the relay submissions, and keeps track of what each one answered and which
+/// one built the blocks our bundles landed in
+pub struct BuilderFanout {
+    signer: LocalWallet,
+    builders: Vec<Builder>,
+    stats: Mutex<HashMap<String, BuilderStats>>,
+}
+
+impl BuilderFanout {
+    pub fn from_env(signer: LocalWallet) -> Self {
+        let builders: Vec<Builder> = std::env::var(BUILDERS_ENV)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|builder| !builder.is_empty())
+                    .filter_map(|builder| match builder.split_once('=') {
+                        Some((name, url)) => {
+                            let (method, url) = match url.split_once(':') {
+                                Some(("eth", url)) => (BundleMethod::EthSendBundle, url),
+                                Some(("mev", url)) => (BundleMethod::MevSendBundle, url),
+                                _ => (BundleMethod::EthSendBundle, url),
+                            };
+                            Some(Builder {
+                                name: name.trim().to_string(),
+                                url: url.trim().to_string(),
+                                method,
+                                client: OnceCell::new(),
+                            })
+                        }
+                        None => {
+                            warn!(
+                                "Ignoring {} entry {}, it should be name=url",
+                                BUILDERS_ENV, builder
+                            );
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        for builder in builders.iter() {
+            info!(
+                "Bundles will also be sent to {} at {} ({:?})",
+                builder.name, builder.url, builder.method
+            );
+        }
+        Self {
+            signer,
+            stats: Mutex::new(
+                builders
+                    .iter()
+                    .map(|builder| (builder.name.clone(), BuilderStats::default()))
+                    .collect(),
+            ),
+            builders,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.builders.is_empty()
+    }
+
+    /// Sends `bundle` to every builder, returning the names of those that accepted it.
+    /// `raw_txs` is the body of the bundle for eth_sendBundle, None if it has txs only known by
+    /// their hash. eth_sendBundle bundles target a single block, so one is sent for each block
+    /// from `block` to `max_block`, and the builder accepted it if any of them went through.
+    pub async fn submit(
+        &self,
+        bundle: &SendBundleRequest,
+        raw_txs: Option<&[Bytes]>,
+        block: u64,
+        max_block: u64,
+    ) -> Vec<String> {
+        let submissions = self.builders.iter().map(|builder| async move {
+            let result = self
+                .submit_to(builder, bundle, raw_txs, block, max_block)
+                .await;
+            match &result {
+                Ok(response) => info!("Bundle accepted by {}: {}", builder.name, response),
+                Err(e) => warn!("Bundle not accepted by {}: {}", builder.name, e),
+            }
+            if let Some(stats) = self.stats.lock().unwrap().get_mut(&builder.name) {
+                match result {
+                    Ok(_) => stats.accepted += 1,
+                    Err(_) => stats.failed += 1,
+                }
+            }
+            result.ok().map(|_| builder.name.clone())
+        });
+        join_all(submissions).await.into_iter().flatten().collect()
+    }
+
+    async fn submit_to(
+        &self,
+        builder: &Builder,
+        bundle: &SendBundleRequest,
+        raw_txs: Option<&[Bytes]>,
+        block: u64,
+        max_block: u64,
+    ) -> Result<String, String> {
+        let client = builder
+            .client
+            .get_or_try_init(|| build_signed_client(&self.signer, &builder.url).map(Arc::new))
+            .map_err(|e| e.to_string())?;
+        match builder.method {
+            BundleMethod::MevSendBundle => MevApiClient::send_bundle(&**client, bundle.clone())
+                .await
+                .map(|response| format!("{:?}", response))
+                .map_err(|e| e.to_string()),
+            BundleMethod::EthSendBundle => {
+                let raw_txs = raw_txs.ok_or("the price update is only known by its hash")?;
+                let txs: Vec<String> = raw_txs.iter().map(|tx| tx.to_string()).collect();
+                let mut responses = vec![];
+                let mut errors = vec![];
+                for target_block in block..=max_block {
+                    let params = serde_json::json!({
+                        "txs": txs,
+                        "blockNumber": format!("{:#x}", target_block),
+                    });
+                    let response: Result<serde_json::Value, _> =
+                        client.request("eth_sendBundle", rpc_params![params]).await;
+                    match response {
+                        Ok(response) => responses.push(response),
+                        Err(e) => {
+                            warn!(
+                                "Bundle for block {} not accepted by {}: {}",
+                                target_block, builder.name, e
+                            );
+                            errors.push(format!("block {}: {}", target_block, e));
+                        }
+                    }
+                }
+                if responses.is_empty() {
+                    return Err(errors.join(", "));
+                }
+                Ok(format!("{:?}", responses))
+            }
+        }
+    }
+
+    /// Records that a bundle landed in a block with `extra_data`, which is where builders sign
+    /// their blocks. Returns the builder whose name is in it, if any.
+    pub fn record_landed(&self, extra_data: &[u8]) -> Option<String> {
+        let extra_data = String::from_utf8_lossy(extra_data).to_lowercase();
+        let builder = self
+            .builders
+            .iter()
+            .find(|builder| extra_data.contains(&builder.name.to_lowercase()))?;
+        if let Some(stats) = self.stats.lock().unwrap().get_mut(&builder.name) {
+            stats.landed += 1;
+        }
+        Some(builder.name.clone())
+    }
+
+    /// Accepted, failed and landed bundles of each builder so far
+    pub fn summary(&self) -> String {
+        self.stats
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, stats)| {
+                format!(
+                    "{}: {} accepted, {} failed, {} landed",
+                    name, stats.accepted, stats.failed, stats.landed
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
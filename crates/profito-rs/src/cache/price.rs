@@ -1,124 +1,311 @@
 use alloy::{
     primitives::{Address, U256},
-    providers::RootProvider,
-    pubsub::PubSubFrontend,
-};
-use overlord_shared::sol_bindings::AaveOracle;
-use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    sync::Arc,
+    providers::Provider,
+    rpc::types::BlockId,
+    transports::Transport,
 };
+use lru::LruCache;
+use overlord_shared::{common::get_asset_price_at, sol_bindings::AaveOracle};
+use std::{collections::HashMap, num::NonZeroUsize, sync::Arc};
 use tracing::info;
 
+/// vega-rs uses this trace id for the events of its initial run, which have no pending prices
+const INITIAL_RUN_TRACE_ID: &str = "initial-run";
+
+/// Prices used while evaluating the events of a trace
+#[derive(Debug, Clone, Default)]
+struct TracePrices {
+    /// Prices of the pending price update TX the trace comes from
+    overrides: HashMap<Address, U256>,
+    /// Prices read from the oracle for every other reserve, so all events of the trace see the same ones
+    fetched: HashMap<Address, U256>,
+}
+
+/// A trace and the AaveOracle it's priced with. Markets have oracles (and reserves) of their own,
+/// so the same price update gets a set of prices per market.
+type TraceKey = (String, Address);
+
 #[derive(Debug, Clone)]
 pub struct PriceCache {
-    prices: HashMap<String, HashMap<Address, U256>>,
-    trace_order: VecDeque<String>,
-    max_traces: usize,
-    overriden_traces: HashSet<String>,
+    traces: LruCache<TraceKey, TracePrices>,
 }
 
 impl PriceCache {
+    /// At least one trace is cached, even with a `max_traces` of 0
     pub fn new(max_traces: usize) -> Self {
         Self {
-            prices: HashMap::new(),
-            trace_order: VecDeque::with_capacity(max_traces),
-            max_traces,
-            overriden_traces: HashSet::new(),
+            traces: LruCache::new(NonZeroUsize::new(max_traces).unwrap_or(NonZeroUsize::MIN)),
         }
     }
 
-    /// This function overwrites the current price for a given asset and trace_id.
+    /// This function overwrites the current price for a given asset and trace_id, as read from
+    /// `oracle`.
     /// Useful for mimicking price calculations based on price update TXs that haven't
     /// been published yet. Returns true if successful, or false otherwise.
+    ///
+    /// A trace with no prices to update is still cached, so its events share the prices fetched
+    /// from the oracle. The least recently used trace is dropped once max_traces are cached.
     pub async fn override_price(
         &mut self,
         trace_id: String,
+        oracle: Address,
         new_prices_by_asset: Vec<(Address, String, U256)>,
     ) -> bool {
-        // Since we receive a list of candidates, each of them will try to override
-        // the price for the same asset. If an override is already present for a trace,
-        // then just ignore the new override and return quickly.
-        // This check is placed first, because it will be hit many more times than
-        // the emptyness of new_prices_by_asset (which comes next)
-        if self.overriden_traces.contains(&trace_id) {
+        // Prices of the initial run are never cached (see get_price)
+        if trace_id == INITIAL_RUN_TRACE_ID {
             return true;
         }
 
-        // This should only happen on initial vega-rs runs. No prices to update.
-        if new_prices_by_asset.is_empty() {
+        // Since we receive a list of candidates, each of them will try to override
+        // the price for the same asset. If an override is already present for a trace,
+        // then just ignore the new override and return quickly (get() marks it as recently used).
+        let key = (trace_id, oracle);
+        if self.traces.get(&key).is_some() {
             return true;
         }
 
-        // Get or create the prices HashMap for this trace_id
-        let prices = self.prices.entry(trace_id.clone()).or_default();
-
+        let mut trace_prices = TracePrices::default();
         for (reserve, symbol, new_price) in new_prices_by_asset.iter() {
-            prices.insert(*reserve, *new_price);
+            trace_prices.overrides.insert(*reserve, *new_price);
             info!(
                 "Successfully override {} price cache for {} (new value = {})",
-                trace_id, symbol, new_price,
+                key.0, symbol, new_price,
             );
         }
 
-        // Mark the trace as overriden
-        self.overriden_traces.insert(trace_id.clone());
-        self.trace_order.push_back(trace_id.clone());
-
-        // If we have reached the maximum number of traces, drop the oldest one
-        if self.trace_order.len() >= self.max_traces {
-            if let Some(oldest_trace) = self.trace_order.pop_front() {
-                info!("Dropping prices cached for {}", oldest_trace);
-                self.prices.remove(&oldest_trace);
-                self.overriden_traces.remove(&oldest_trace);
-            }
+        if let Some(((oldest_trace, oldest_oracle), _)) = self.traces.push(key, trace_prices) {
+            info!(
+                "Dropping prices cached for {} (oracle {})",
+                oldest_trace, oldest_oracle
+            );
         }
 
         true
     }
 
-    pub async fn get_price(
+    pub async fn get_price<T, P>(
         &mut self,
         reserve: Address,
         trace_id: Option<String>,
-        oracle: AaveOracle::AaveOracleInstance<PubSubFrontend, Arc<RootProvider<PubSubFrontend>>>,
-    ) -> Result<U256, Box<dyn std::error::Error + Send + Sync>> {
-        if trace_id.is_none() || trace_id == Some("initial-run".to_string()) {
-            // This means the caller wants the actual price, not an overriden one
-            // the caller is probably bpchecker or vega is initializing,
-            // so we don't care about caching the price
-            return match oracle.getAssetPrice(reserve).call().await {
-                Ok(price_response) => Ok(price_response._0),
-                Err(e) => Err(format!("Couldn't fetch price for {}: {}", reserve, e).into()),
-            };
-        }
+        oracle: AaveOracle::AaveOracleInstance<T, Arc<P>>,
+        block: BlockId,
+    ) -> Result<U256, Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: Transport + Clone,
+        P: Provider<T>,
+    {
+        let trace_id = match trace_id {
+            Some(trace_id) if trace_id != INITIAL_RUN_TRACE_ID => trace_id,
+            _ => {
+                // This means the caller wants the actual price, not an overriden one
+                // the caller is probably bpchecker or vega is initializing,
+                // so we don't care about caching the price
+                return get_asset_price_at(
+                    oracle.provider().clone(),
+                    *oracle.address(),
+                    reserve,
+                    block,
+                )
+                .await
+                .map_err(|e| format!("Couldn't fetch price for {}: {}", reserve, e).into());
+            }
+        };
 
-        let trace_id = trace_id.expect("trace_id should be Some - checked above");
+        let key = (trace_id, *oracle.address());
+        let Some(trace_prices) = self.traces.get_mut(&key) else {
+            return Err(format!(
+                "price for {} within trace_id {} not found in the price cache",
+                reserve, key.0
+            )
+            .into());
+        };
 
-        // Check if there are overrides for this price
-        if let Some(prices) = self.prices.get(&trace_id) {
-            // If there are overrides, and the price is present, return it
-            if let Some(&price) = prices.get(&reserve) {
-                return Ok(price);
-            }
+        // If there's an override (or it was already fetched for this trace), return it
+        if let Some(&price) = trace_prices
+            .overrides
+            .get(&reserve)
+            .or_else(|| trace_prices.fetched.get(&reserve))
+        {
+            return Ok(price);
+        }
 
-            // If there are overrides, but the price is not present, fetch it
-            let price = match oracle.getAssetPrice(reserve).call().await {
-                Ok(price_response) => price_response._0,
+        // Otherwise fetch it, and keep it for future calls within this trace_id context
+        let price =
+            match get_asset_price_at(oracle.provider().clone(), *oracle.address(), reserve, block)
+                .await
+            {
+                Ok(price) => price,
                 Err(e) => return Err(format!("Couldn't fetch price for {}: {}", reserve, e).into()),
             };
+        trace_prices.fetched.insert(reserve, price);
 
-            // And update it for future calls within this trace_id context
-            if let Some(prices) = self.prices.get_mut(&trace_id) {
-                prices.insert(reserve, price);
-            }
+        Ok(price)
+    }
+}
 
-            return Ok(price);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::{
+        primitives::address,
+        providers::{ProviderBuilder, RootProvider},
+        transports::http::{reqwest::Client, Http},
+    };
+    use tokio::sync::Mutex;
+
+    const ORACLE: Address = address!("54586bE62E3c3580375aE3723C145253060Ca0C2");
+    const OTHER_ORACLE: Address = address!("00000000000000000000000000000000000000bb");
+    const WETH: Address = address!("C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2");
+    const USDC: Address = address!("A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48");
+
+    type TestOracle = AaveOracle::AaveOracleInstance<Http<Client>, Arc<RootProvider<Http<Client>>>>;
+
+    /// Oracle on a node nobody listens on, so a test that doesn't get its price from the cache
+    /// fails instead of reading it from somewhere
+    fn oracle(address: Address) -> TestOracle {
+        let provider = ProviderBuilder::new().on_http("http://127.0.0.1:1".parse().unwrap());
+        AaveOracle::new(address, Arc::new(provider))
+    }
+
+    async fn cached_price(
+        cache: &mut PriceCache,
+        trace_id: &str,
+        oracle_address: Address,
+        reserve: Address,
+    ) -> Option<U256> {
+        let key = (trace_id.to_string(), oracle_address);
+        if !cache.traces.contains(&key) {
+            return None;
+        }
+        cache
+            .get_price(
+                reserve,
+                Some(trace_id.to_string()),
+                oracle(oracle_address),
+                BlockId::latest(),
+            )
+            .await
+            .ok()
+    }
+
+    fn prices(price: u64) -> Vec<(Address, String, U256)> {
+        vec![(WETH, "WETH".to_string(), U256::from(price))]
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_trace_is_dropped() {
+        let mut cache = PriceCache::new(2);
+        assert!(cache.override_price("0xa".into(), ORACLE, prices(1)).await);
+        assert!(cache.override_price("0xb".into(), ORACLE, prices(2)).await);
+        // Reading 0xa makes 0xb the least recently used trace
+        assert_eq!(
+            cached_price(&mut cache, "0xa", ORACLE, WETH).await,
+            Some(U256::from(1))
+        );
+        assert!(cache.override_price("0xc".into(), ORACLE, prices(3)).await);
+
+        assert_eq!(cached_price(&mut cache, "0xb", ORACLE, WETH).await, None);
+        let evicted = cache
+            .get_price(WETH, Some("0xb".into()), oracle(ORACLE), BlockId::latest())
+            .await;
+        assert!(evicted.unwrap_err().to_string().contains("not found"));
+        assert_eq!(
+            cached_price(&mut cache, "0xa", ORACLE, WETH).await,
+            Some(U256::from(1))
+        );
+        assert_eq!(
+            cached_price(&mut cache, "0xc", ORACLE, WETH).await,
+            Some(U256::from(3))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_trace_keeps_its_first_override() {
+        let mut cache = PriceCache::new(2);
+        assert!(cache.override_price("0xa".into(), ORACLE, prices(1)).await);
+        // As if USDC had been read from the oracle while evaluating an event of the trace
+        let key = ("0xa".to_string(), ORACLE);
+        cache
+            .traces
+            .get_mut(&key)
+            .unwrap()
+            .fetched
+            .insert(USDC, U256::from(100));
+
+        assert!(cache.override_price("0xa".into(), ORACLE, prices(2)).await);
+
+        assert_eq!(
+            cached_price(&mut cache, "0xa", ORACLE, WETH).await,
+            Some(U256::from(1))
+        );
+        assert_eq!(
+            cached_price(&mut cache, "0xa", ORACLE, USDC).await,
+            Some(U256::from(100))
+        );
+    }
+
+    #[tokio::test]
+    async fn oracles_of_a_trace_are_priced_apart() {
+        let mut cache = PriceCache::new(2);
+        assert!(cache.override_price("0xa".into(), ORACLE, prices(1)).await);
+        assert!(
+            cache
+                .override_price("0xa".into(), OTHER_ORACLE, prices(2))
+                .await
+        );
+
+        assert_eq!(
+            cached_price(&mut cache, "0xa", ORACLE, WETH).await,
+            Some(U256::from(1))
+        );
+        assert_eq!(
+            cached_price(&mut cache, "0xa", OTHER_ORACLE, WETH).await,
+            Some(U256::from(2))
+        );
+    }
+
+    #[tokio::test]
+    async fn initial_run_is_never_cached() {
+        let mut cache = PriceCache::new(1);
+        assert!(
+            cache
+                .override_price(INITIAL_RUN_TRACE_ID.into(), ORACLE, prices(1))
+                .await
+        );
+        assert!(cache.traces.is_empty());
+    }
+
+    #[tokio::test]
+    async fn zero_max_traces_still_caches_one() {
+        let mut cache = PriceCache::new(0);
+        assert!(cache.override_price("0xa".into(), ORACLE, prices(1)).await);
+        assert_eq!(
+            cached_price(&mut cache, "0xa", ORACLE, WETH).await,
+            Some(U256::from(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_events_of_a_trace_see_the_same_prices() {
+        let cache = Arc::new(Mutex::new(PriceCache::new(2)));
+        let tasks: Vec<_> = (1..=16u64)
+            .map(|i| {
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    // Every event of the trace comes with the prices of its own candidate
+                    let mut cache = cache.lock().await;
+                    assert!(cache.override_price("0xa".into(), ORACLE, prices(i)).await);
+                    cached_price(&mut cache, "0xa", ORACLE, WETH).await
+                })
+            })
+            .collect();
+
+        let mut seen = vec![];
+        for task in tasks {
+            seen.push(task.await.unwrap().unwrap());
         }
-        Err(format!(
-            "price for {} within trace_id {} not found in the price cache",
-            reserve, trace_id
-        )
-        .into())
+        seen.dedup();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(cache.lock().await.traces.len(), 1);
     }
 }
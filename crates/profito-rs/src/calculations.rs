@@ -1,27 +1,61 @@
 use alloy::{
-    primitives::{aliases::U24, utils::format_units, Address, U256},
+    primitives::{aliases::U24, Address, U256},
     providers::{Provider, RootProvider},
     pubsub::PubSubFrontend,
+    rpc::types::BlockId,
 };
+use futures::future::join_all;
 use overlord_shared::{
-    constants::{
-        AAVE_ORACLE_ADDRESS, AAVE_V3_POOL_ADDRESS, AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS, MORPHO,
-        UNISWAP_V3_FACTORY, WETH,
-    },
+    asset_filter::ASSET_FILTER,
+    chain_profile::ChainProfile,
+    common::{get_asset_price_at, get_reserves_list_at, BaseCurrency, UserEModeCategory},
+    constants::{AAVE_ORACLE_ADDRESS, BALANCER_VAULT, MORPHO, UNISWAP_V3_FACTORY, WETH},
+    decimals_guard::DECIMALS_GUARD,
+    error::NodeError,
+    feature_flags::{FlagReservation, FEATURE_FLAGS},
+    health_factor::{calculate_account_data, value_in_base_currency, AccountTerm},
+    kill_switch::KILL_SWITCH,
+    market::AaveMarket,
+    math::MathError,
+    reserve_configuration::ReserveConfiguration,
     sol_bindings::{
         pool::AaveV3Pool,
-        AaveOracle, AaveProtocolDataProvider, Foxdie, IAToken,
+        AaveOracle, AaveProtocolDataProvider, Foxdie, IAToken, IBalancerProtocolFeesCollector,
+        IBalancerVault,
         IUiPoolDataProviderV3::{AggregatedReserveData, UserReserveData},
         UniswapV3Factory, UniswapV3Pool, ERC20,
     },
 };
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use super::cache::PriceCache;
+use super::{
+    bribe::{BribeDecision, BRIBE_POLICY},
+    cache::PriceCache,
+    error::ProfitoError,
+    execution_mode::{ExecutionMode, HOLD_COLLATERAL},
+    gas::{wei_to_asset_units, GAS_ESTIMATES},
+    liquidation_params::LiquidationParams,
+    profit_policy::PROFIT_POLICY,
+    swap_routes::{SwapRoute, SWAP_ROUTER},
+};
 use tracing::warn;
 
+pub use overlord_shared::math::{percent_div, percent_mul, ray_mul, wad_div};
+
+/// Bribe of the fixed strategy, unless PROFITO_BRIBE_BPS is set
 pub const BRIBE_IN_BASIS_POINTS: u16 = 9500; // 95%
 
+/// Experiment: swap through the cheapest fee tier that's reasonably deep, instead of the deepest
+/// one. See overlord_shared::feature_flags.
+pub const CHEAPEST_SWAP_TIER_FLAG: &str = "cheapest_swap_tier";
+/// Share (in basis points) of the liquidity of the deepest pool a cheaper tier needs to be picked
+/// by the CHEAPEST_SWAP_TIER_FLAG experiment
+const CHEAPEST_SWAP_TIER_MIN_LIQUIDITY_BPS: u128 = 5000;
+
 #[derive(Clone, Debug)]
 pub struct BestPair {
     pub collateral_asset: Address,
@@ -30,149 +64,248 @@ pub struct BestPair {
     pub printable_net_profit: String,
     pub actual_collateral_to_liquidate: U256,
     pub actual_debt_to_liquidate: U256,
+    pub debt_to_liquidate_in_base_currency: U256,
     pub liquidation_protocol_fee_amount: U256,
     pub flash_loan_source: Foxdie::FlashLoanSource,
+    /// Premium of the flash loan, in debt asset units
+    pub flash_loan_premium: U256,
+    /// Price of WETH in base currency the pair was evaluated with
+    pub weth_price: U256,
+    /// Whether the collateral is swapped or kept as aTokens. Kept collateral needs no flash loan,
+    /// so flash_loan_source is NONE.
+    pub execution_mode: ExecutionMode,
+    /// Route the collateral was quoted to be swapped through, which the bundle is sent with. Empty
+    /// when the collateral is kept.
+    pub swap_route: SwapRoute,
+}
+
+impl BestPair {
+    /// Foxdie's aavePremium param, only set when the loan comes from AAVE V3
+    pub fn aave_premium(&self) -> U256 {
+        if self.flash_loan_source == Foxdie::FlashLoanSource::AAVE_V3 {
+            self.flash_loan_premium
+        } else {
+            U256::ZERO
+        }
+    }
+
+    /// Switches the pair to the flash loan of `source`, taking the difference between its premium
+    /// and the previous one out of the net profit
+    pub fn set_flash_loan(&mut self, source: Foxdie::FlashLoanSource, premium: U256) {
+        if self.actual_debt_to_liquidate != U256::ZERO {
+            // Priced like the debt was, at the evaluated block
+            let in_base_currency = |amount: U256| {
+                amount * self.debt_to_liquidate_in_base_currency / self.actual_debt_to_liquidate
+            };
+            self.net_profit = (self.net_profit + in_base_currency(self.flash_loan_premium))
+                .saturating_sub(in_base_currency(premium));
+        }
+        self.flash_loan_source = source;
+        self.flash_loan_premium = premium;
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct LiquiditySolution {
     pub source: Foxdie::FlashLoanSource,
+    /// What the loan costs on top of the borrowed amount, in debt asset units
+    pub premium: U256,
     pub reasons: Vec<String>,
 }
 
+/// Flash loan sources in the order of the gas their loans take, which breaks ties between equal
+/// premiums
+const FLASH_LOAN_SOURCES: [Foxdie::FlashLoanSource; 3] = [
+    Foxdie::FlashLoanSource::MORPHO,
+    Foxdie::FlashLoanSource::BALANCER,
+    Foxdie::FlashLoanSource::AAVE_V3,
+];
+/// Balancer flash loan fees are 18 decimals fixed point percentages
+const BALANCER_FEE_PERCENTAGE_ONE: u128 = 1_000_000_000_000_000_000;
+
+/// Picks the source that lends `actual_debt_to_liquidate` of `debt_asset` for the lowest premium
+/// at `block`. Morpho loans are free, Balancer charges the protocol's flash loan fee (zero so far)
+/// and AAVE V3 its FLASHLOAN_PREMIUM_TOTAL.
 pub async fn get_best_liquidity_provider(
     provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
     debt_asset: Address,
     actual_debt_to_liquidate: U256,
+    block: BlockId,
 ) -> LiquiditySolution {
-    let mut reasons = vec![];
-
-    // Query MORPHO's balanceOf asset that we'll need to borrow
-    let morpho_balance = match ERC20::new(debt_asset, provider.clone())
-        .balanceOf(MORPHO)
-        .call()
+    let mut best = LiquiditySolution {
+        source: Foxdie::FlashLoanSource::NONE,
+        premium: U256::MAX,
+        reasons: vec![],
+    };
+    for source in FLASH_LOAN_SOURCES {
+        match get_flash_loan_premium(
+            &provider,
+            market,
+            source,
+            debt_asset,
+            actual_debt_to_liquidate,
+            block,
+        )
         .await
-    {
-        Ok(balance_of_response) => balance_of_response.balance,
-        Err(e) => {
-            let error_msg = format!("Error trying to call balanceOf for {}: {}", debt_asset, e);
-            warn!("{}", error_msg);
-            reasons.push(error_msg);
-            return LiquiditySolution {
-                source: Foxdie::FlashLoanSource::NONE,
-                reasons,
-            };
+        {
+            Ok(premium) if premium < best.premium => {
+                best.source = source;
+                best.premium = premium;
+            }
+            Ok(premium) => best.reasons.push(format!(
+                "{:?} premium for {} ({}) is higher than {:?}'s",
+                source, debt_asset, premium, best.source
+            )),
+            Err(reason) => best.reasons.push(reason),
         }
-    };
-
-    // If MORPHO's balance is enough, then we don't need to continue processing
-    if morpho_balance >= actual_debt_to_liquidate {
-        return LiquiditySolution {
-            source: Foxdie::FlashLoanSource::MORPHO,
-            reasons,
-        };
-    } else {
-        reasons.push(format!(
-            "MORPHO balance for {} ({}) is not enough",
-            debt_asset, morpho_balance
-        ));
+        // Nothing is cheaper than a free loan
+        if best.premium == U256::ZERO {
+            break;
+        }
+    }
+    if best.source == Foxdie::FlashLoanSource::NONE {
+        best.premium = U256::ZERO;
     }
+    best
+}
 
-    let pool_data_provider =
-        AaveProtocolDataProvider::new(AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS, provider.clone());
-    let is_flashloan_enabled = match pool_data_provider
-        .getFlashLoanEnabled(debt_asset)
-        .call()
-        .await
-    {
-        Ok(res) => res._0,
-        Err(e) => {
-            let error_msg = format!(
-                "Error trying to determine if AAVE flashloan is enabled for {}: {}",
-                debt_asset, e
-            );
-            warn!("{}", error_msg.clone());
-            reasons.push(error_msg);
-            return LiquiditySolution {
-                source: Foxdie::FlashLoanSource::NONE,
-                reasons,
-            };
+/// Premium `source` charges for lending `amount` of `debt_asset` at `block`, or why it can't lend
+/// it
+async fn get_flash_loan_premium(
+    provider: &Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
+    source: Foxdie::FlashLoanSource,
+    debt_asset: Address,
+    amount: U256,
+    block: BlockId,
+) -> Result<U256, String> {
+    let (lender, premium) = match source {
+        Foxdie::FlashLoanSource::MORPHO => (MORPHO, U256::ZERO),
+        Foxdie::FlashLoanSource::BALANCER => {
+            let vault = IBalancerVault::new(BALANCER_VAULT, provider.clone());
+            let fees_collector = vault
+                .getProtocolFeesCollector()
+                .block(block)
+                .call()
+                .await
+                .map_err(|e| format!("Couldn't get the Balancer fees collector: {}", e))?
+                ._0;
+            let fee_percentage =
+                IBalancerProtocolFeesCollector::new(fees_collector, provider.clone())
+                    .getFlashLoanFeePercentage()
+                    .block(block)
+                    .call()
+                    .await
+                    .map_err(|e| format!("Couldn't get the Balancer flash loan fee: {}", e))?
+                    ._0;
+            // Rounded up, like the vault does
+            let one = U256::from(BALANCER_FEE_PERCENTAGE_ONE);
+            (BALANCER_VAULT, (amount * fee_percentage).div_ceil(one))
         }
-    };
-
-    if !is_flashloan_enabled {
-        reasons.push(format!("AAVE flashLoan is not enabled for {}", debt_asset));
-        return LiquiditySolution {
-            source: Foxdie::FlashLoanSource::NONE,
-            reasons,
-        };
-    };
-
-    // The process to query AAVE v3 balances is a little more indirect. First we need to get the
-    // AToken contract address corresponding to the underlying we want to borrow:
-    let a_token_debt_address = match AaveV3Pool::new(AAVE_V3_POOL_ADDRESS, provider.clone())
-        .getReserveData(debt_asset)
-        .call()
-        .await
-    {
-        Ok(reserve_data) => reserve_data._0.aTokenAddress,
-        Err(e) => {
-            let error_msg = format!("Couldn't get reserve data for calculating best flash loan provider for debt {}: {}", debt_asset, e);
-            warn!("{}", error_msg.clone());
-            reasons.push(error_msg);
-            return LiquiditySolution {
-                source: Foxdie::FlashLoanSource::NONE,
-                reasons,
-            };
+        Foxdie::FlashLoanSource::AAVE_V3 => {
+            let pool_data_provider =
+                AaveProtocolDataProvider::new(market.data_provider, provider.clone());
+            let is_flashloan_enabled = pool_data_provider
+                .getFlashLoanEnabled(debt_asset)
+                .block(block)
+                .call()
+                .await
+                .map_err(|e| {
+                    format!(
+                        "Error trying to determine if AAVE flashloan is enabled for {}: {}",
+                        debt_asset, e
+                    )
+                })?
+                ._0;
+            if !is_flashloan_enabled {
+                return Err(format!("AAVE flashLoan is not enabled for {}", debt_asset));
+            }
+            let pool = AaveV3Pool::new(market.pool, provider.clone());
+            // The balance lent is the one of the AToken of the asset
+            let a_token_address = pool
+                .getReserveData(debt_asset)
+                .block(block)
+                .call()
+                .await
+                .map_err(|e| {
+                    format!(
+                        "Couldn't get reserve data for calculating best flash loan provider for debt {}: {}",
+                        debt_asset, e
+                    )
+                })?
+                ._0
+                .aTokenAddress;
+            let premium_total = pool
+                .FLASHLOAN_PREMIUM_TOTAL()
+                .block(block)
+                .call()
+                .await
+                .map_err(|e| format!("Couldn't get the AAVE flash loan premium: {}", e))?
+                ._0;
+            let premium = percent_mul(amount, U256::from(premium_total))
+                .map_err(|e| format!("Couldn't calculate the AAVE flash loan premium: {}", e))?;
+            (a_token_address, premium)
         }
+        _ => return Err(format!("{:?} isn't a flash loan source", source)),
     };
-
-    // Now we query the asset's balanceOf of the AToken contract
-    let aave_balance = match ERC20::new(debt_asset, provider.clone())
-        .balanceOf(a_token_debt_address)
+    let balance = ERC20::new(debt_asset, provider.clone())
+        .balanceOf(lender)
+        .block(block)
         .call()
         .await
-    {
-        Ok(balance_of_response) => balance_of_response.balance,
-        Err(e) => {
-            let error_msg = format!("Error trying to call balanceOf for {}: {}", debt_asset, e);
-            warn!("{}", error_msg.clone());
-            reasons.push(error_msg);
-            return LiquiditySolution {
-                source: Foxdie::FlashLoanSource::NONE,
-                reasons,
-            };
-        }
-    };
-
-    if aave_balance >= actual_debt_to_liquidate {
-        return LiquiditySolution {
-            source: Foxdie::FlashLoanSource::AAVE_V3,
-            reasons,
-        };
-    } else {
-        reasons.push(format!(
-            "AAVE V3 balance for {} ({}) is not enough",
-            debt_asset, aave_balance
+        .map_err(|e| format!("Error trying to call balanceOf for {}: {}", debt_asset, e))?
+        .balance;
+    if balance < amount {
+        return Err(format!(
+            "{:?} balance for {} ({}) is not enough",
+            source, debt_asset, balance
         ));
     }
-
-    LiquiditySolution {
-        source: Foxdie::FlashLoanSource::NONE,
-        reasons,
-    }
+    Ok(premium)
 }
 
-/// This mimics `percentMul` at
-/// https://github.com/aave/aave-v3-core/blob/782f51917056a53a2c228701058a6c3fb233684a/contracts/protocol/libraries/math/PercentageMath.sol#L25
-pub fn percent_mul(value: U256, percentage: U256) -> U256 {
-    (value * percentage + U256::from(0.5e4)) / U256::from(1e4)
-}
-
-/// This mimics `percentDiv` at
-/// https://github.com/aave/aave-v3-core/blob/782f51917056a53a2c228701058a6c3fb233684a/contracts/protocol/libraries/math/PercentageMath.sol#L48
-pub fn percent_div(value: U256, percentage: U256) -> U256 {
-    ((value * U256::from(1e4)) + (percentage / U256::from(2))) / percentage
+/// Checks again at `block` that `source` still lends `actual_debt_to_liquidate` of `debt_asset`,
+/// and if it doesn't, picks the next viable source with get_best_liquidity_provider(). Balances
+/// read while evaluating can be gone by the time the bundle is built, Morpho's especially, since
+/// anyone can withdraw them.
+pub async fn recheck_liquidity_provider(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
+    source: Foxdie::FlashLoanSource,
+    debt_asset: Address,
+    actual_debt_to_liquidate: U256,
+    block: BlockId,
+) -> LiquiditySolution {
+    let reason = match get_flash_loan_premium(
+        &provider,
+        market,
+        source,
+        debt_asset,
+        actual_debt_to_liquidate,
+        block,
+    )
+    .await
+    {
+        Ok(premium) => {
+            return LiquiditySolution {
+                source,
+                premium,
+                reasons: vec![],
+            }
+        }
+        Err(reason) => reason,
+    };
+    let mut solution = get_best_liquidity_provider(
+        provider,
+        market,
+        debt_asset,
+        actual_debt_to_liquidate,
+        block,
+    )
+    .await;
+    solution.reasons.insert(0, reason);
+    solution
 }
 
 pub fn calculate_actual_debt_to_liquidate(
@@ -183,24 +316,21 @@ pub fn calculate_actual_debt_to_liquidate(
     total_debt_in_base_currency: U256,
     debt_asset_unit: U256,
     debt_asset_price: U256,
-) -> U256 {
-    let MIN_BASE_MAX_CLOSE_FACTOR_THRESHOLD = U256::from(2000e8);
-    let CLOSE_FACTOR_HF_THRESHOLD = U256::from(0.95e18);
-    let DEFAULT_LIQUIDATION_CLOSE_FACTOR = U256::from(0.5e4);
-
+    params: &LiquidationParams,
+) -> Result<U256, MathError> {
     // by default whole debt in the reserve could be liquidated
     let mut max_liquidatable_debt = user_reserve_debt;
 
     // but if debt and collateral are above or equal MIN_BASE_MAX_CLOSE_FACTOR_THRESHOLD
     // and health factor is above CLOSE_FACTOR_HF_THRESHOLD this amount may be adjusted
-    if user_reserve_collateral_in_base_currency >= MIN_BASE_MAX_CLOSE_FACTOR_THRESHOLD
-        && user_reserve_debt_in_base_currency >= MIN_BASE_MAX_CLOSE_FACTOR_THRESHOLD
-        && health_factor_v33 >= CLOSE_FACTOR_HF_THRESHOLD
+    if user_reserve_collateral_in_base_currency >= params.min_base_max_close_factor_threshold
+        && user_reserve_debt_in_base_currency >= params.min_base_max_close_factor_threshold
+        && health_factor_v33 >= params.close_factor_hf_threshold
     {
         let total_default_liquidatable_debt_in_base_currency = percent_mul(
             total_debt_in_base_currency,
-            DEFAULT_LIQUIDATION_CLOSE_FACTOR,
-        );
+            params.default_liquidation_close_factor,
+        )?;
 
         // if the debt is more than the DEFAULT_LIQUIDATION_CLOSE_FACTOR % of the whole,
         // then we CAN liquidate only up to DEFAULT_LIQUIDATION_CLOSE_FACTOR %
@@ -216,7 +346,7 @@ pub fn calculate_actual_debt_to_liquidate(
     // is higher than this and, if it is, then it uses this value instead.
     // We can't check for that here because we're working the other way around (that is, we first do the calculations
     // and THEN we determine the inputs to the liquidationCall)
-    max_liquidatable_debt
+    Ok(max_liquidatable_debt)
 }
 
 pub async fn calculate_user_balances(
@@ -225,8 +355,8 @@ pub async fn calculate_user_balances(
     borrowed_reserve: &UserReserveData,
     provider: Arc<RootProvider<PubSubFrontend>>,
     user_address: Address,
-) -> Result<(AggregatedReserveData, U256, AggregatedReserveData, U256), Box<dyn std::error::Error>>
-{
+    block: BlockId,
+) -> Result<(AggregatedReserveData, U256, AggregatedReserveData, U256), ProfitoError> {
     let collateral_reserve = reserves_data
         .iter()
         .find(|agg_reserve_data| {
@@ -234,11 +364,14 @@ pub async fn calculate_user_balances(
         })
         .unwrap();
     let collateral_a_token = IAToken::new(collateral_reserve.aTokenAddress, provider.clone());
-    let user_collateral_balance = match collateral_a_token.balanceOf(user_address).call().await {
+    let user_collateral_balance = match collateral_a_token
+        .balanceOf(user_address)
+        .block(block)
+        .call()
+        .await
+    {
         Ok(response) => response._0,
-        Err(e) => {
-            return Err(format!("Error trying to call collateralAToken.balanceOf(): {}", e).into())
-        }
+        Err(e) => return Err(NodeError::call("collateralAToken.balanceOf", e).into()),
     };
     let debt_reserve = reserves_data
         .iter()
@@ -247,11 +380,14 @@ pub async fn calculate_user_balances(
         })
         .unwrap();
     let debt_reserve_token = ERC20::new(debt_reserve.variableDebtTokenAddress, provider.clone());
-    let user_reserve_debt = match debt_reserve_token.balanceOf(user_address).call().await {
+    let user_reserve_debt = match debt_reserve_token
+        .balanceOf(user_address)
+        .block(block)
+        .call()
+        .await
+    {
         Ok(response) => response.balance,
-        Err(e) => {
-            return Err(format!("Error trying to call debt_reserve_token.balanceOf: {}", e).into())
-        }
+        Err(e) => return Err(NodeError::call("debt_reserve_token.balanceOf", e).into()),
     };
     Ok((
         collateral_reserve.clone(),
@@ -263,23 +399,73 @@ pub async fn calculate_user_balances(
 
 pub async fn get_reserves_list(
     provider: Arc<RootProvider<PubSubFrontend>>,
-) -> Result<Vec<Address>, Box<dyn std::error::Error>> {
-    /*
-       According to https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/pool/Pool.sol#L532
-       the reserves list is ordered the same way as the _reserveList storage in the Pool contract.
-    */
-    match AaveV3Pool::new(AAVE_V3_POOL_ADDRESS, provider.clone())
-        .getReservesList()
-        .call()
+    market: &AaveMarket,
+    block: BlockId,
+) -> Result<Vec<Address>, ProfitoError> {
+    get_reserves_list_at(provider, market, block)
         .await
+        .map_err(|e| NodeError::call("getReservesList", e).into())
+}
+
+/// Index of `asset` in `reserves_list`, which is the bit of the reserve in the user configuration
+/// and in the e-mode collateral bitmaps
+pub fn get_reserve_index(reserves_list: &[Address], asset: Address) -> Result<usize, ProfitoError> {
+    reserves_list
+        .iter()
+        .position(|reserve| *reserve == asset)
+        .ok_or_else(|| {
+            ProfitoError::InconsistentState(format!("{} isn't in the reserves list", asset))
+        })
+}
+
+/// The user configuration packs 2 bits per reserve into a uint256, so the pool can't list more
+/// reserves than this
+/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/configuration/ReserveConfiguration.sol#L60
+pub const MAX_RESERVES_COUNT: usize = 128;
+
+/// Checks that reserve `i` of `reserves_list` is reserve `i` of `reserves_data`, which is what
+/// lets the index of a reserve in the list be used as its bit in the user configuration. A
+/// reserve listed (or dropped) between both reads breaks that, so it's an error instead of a
+/// misaligned (or out of bounds) read.
+pub fn check_reserves_alignment(
+    reserves_list: &[Address],
+    reserves_data: &[AggregatedReserveData],
+) -> Result<(), ProfitoError> {
+    if reserves_list.len() > MAX_RESERVES_COUNT {
+        return Err(ProfitoError::InconsistentState(format!(
+            "Reserves list has {} reserves, more than the {} the user configuration can hold",
+            reserves_list.len(),
+            MAX_RESERVES_COUNT
+        )));
+    }
+    if reserves_list.len() != reserves_data.len() {
+        return Err(ProfitoError::InconsistentState(format!(
+            "Reserves list has {} reserves but reserves data has {}, reserves changed between \
+             both reads",
+            reserves_list.len(),
+            reserves_data.len()
+        )));
+    }
+    if let Some((i, (asset, data))) = reserves_list
+        .iter()
+        .zip(reserves_data)
+        .enumerate()
+        .find(|(_, (asset, data))| **asset != data.underlyingAsset)
     {
-        Ok(reserves_list) => Ok(reserves_list._0),
-        Err(e) => Err(format!("Error trying to call getReservesList: {}", e).into()),
+        return Err(ProfitoError::InconsistentState(format!(
+            "Reserve {} is {} in the reserves list but {} in reserves data",
+            i, asset, data.underlyingAsset
+        )));
     }
+    Ok(())
 }
 
 /// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/configuration/UserConfiguration.sol#L71
 pub fn is_using_as_collateral_or_borrowing(user_config: U256, reserve_index: usize) -> bool {
+    // The pool reverts with INVALID_RESERVE_INDEX, no reserve can have such an index
+    if reserve_index >= MAX_RESERVES_COUNT {
+        return false;
+    }
     // In Solidity: (self.data >> (reserveIndex << 1)) & 3 != 0
     // This checks both collateral AND borrowing bits
     let shift_amount = reserve_index * 2; // reserveIndex << 1
@@ -291,6 +477,9 @@ pub fn is_using_as_collateral_or_borrowing(user_config: U256, reserve_index: usi
 
 /// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/configuration/UserConfiguration.sol#L103
 pub fn is_using_as_collateral(user_config: U256, reserve_index: usize) -> bool {
+    if reserve_index >= MAX_RESERVES_COUNT {
+        return false;
+    }
     // In Solidity: (self.data >> ((reserveIndex << 1) + 1)) & 1 != 0
     // This checks only the collateral bit
     let shift_amount = (reserve_index * 2) + 1; // (reserveIndex << 1) + 1
@@ -302,6 +491,9 @@ pub fn is_using_as_collateral(user_config: U256, reserve_index: usize) -> bool {
 
 /// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/configuration/UserConfiguration.sol#L87
 pub fn is_borrowing(user_config: U256, reserve_index: usize) -> bool {
+    if reserve_index >= MAX_RESERVES_COUNT {
+        return false;
+    }
     // In Solidity: (self.data >> (reserveIndex << 1)) & 1 != 0
     // This checks only the borrowing bit
     let shift_amount = reserve_index * 2; // reserveIndex << 1
@@ -311,31 +503,24 @@ pub fn is_borrowing(user_config: U256, reserve_index: usize) -> bool {
     (shifted & mask) != U256::ZERO
 }
 
-/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/math/WadRayMath.sol#L47
-pub fn wad_div(a: U256, b: U256) -> U256 {
-    let wad: U256 = U256::from(10).pow(U256::from(18)); // 1e18
-    let half_b = b / U256::from(2); // div(b, 2)
-
-    // c = (a * WAD + halfB) / b
-    let numerator = a * wad + half_b;
-    numerator / b
-}
-
 /// TODO(Hernan): I'm not 100% sure that this function fetches a price internally. If it does, then you
 /// must refactor it to use the price cache, otherwise results will be skewed when called from
 /// underwater event processing at profito
 async fn get_user_balance_in_base_currency(
     provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
     reserve: Address,
     a_token_address: Address,
     user_address: Address,
     asset_price: U256,
     asset_unit: U256,
-) -> U256 {
+    block: BlockId,
+) -> Result<U256, MathError> {
     // Implementation of
     // https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/logic/GenericLogic.sol#L249
-    let normalized_income = match AaveV3Pool::new(AAVE_V3_POOL_ADDRESS, provider.clone())
+    let normalized_income = match AaveV3Pool::new(market.pool, provider.clone())
         .getReserveNormalizedIncome(reserve)
+        .block(block)
         .call()
         .await
     {
@@ -348,7 +533,12 @@ async fn get_user_balance_in_base_currency(
 
     let a_token = IAToken::new(a_token_address, provider);
     // TODO(Hernan): revisit this, because scaledBalanceOf != balanceOf
-    let scaled_balance = match a_token.scaledBalanceOf(user_address).call().await {
+    let scaled_balance = match a_token
+        .scaledBalanceOf(user_address)
+        .block(block)
+        .call()
+        .await
+    {
         Ok(balance_of_response) => balance_of_response._0,
         Err(e) => {
             eprintln!("Error trying to call balanceOf for {}: {}", user_address, e);
@@ -356,8 +546,11 @@ async fn get_user_balance_in_base_currency(
         }
     };
 
-    let balance = ray_mul(scaled_balance, normalized_income) * asset_price;
-    balance / asset_unit
+    value_in_base_currency(
+        ray_mul(scaled_balance, normalized_income)?,
+        asset_price,
+        asset_unit,
+    )
 }
 
 /// TODO(Hernan): I'm not 100% sure that this function fetches a price internally. If it does, then you
@@ -365,32 +558,36 @@ async fn get_user_balance_in_base_currency(
 /// underwater event processing at profito
 async fn get_user_debt_in_base_currency(
     provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
     reserve: Address,
     variable_debt_token_address: Address,
     user_address: Address,
     asset_price: U256,
     asset_unit: U256,
-) -> U256 {
+    block: BlockId,
+) -> Result<U256, MathError> {
     // Implementation of
     // https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/logic/GenericLogic.sol#L219
     // TODO(Hernan): revisit this, because scaledBalanceOf != balanceOf
     let variable_debt_token = IAToken::new(variable_debt_token_address, provider.clone());
     let mut user_total_debt = match variable_debt_token
         .scaledBalanceOf(user_address)
+        .block(block)
         .call()
         .await
     {
         Ok(balance) => balance._0,
         Err(e) => {
             eprintln!("Error getting scaled debt balance: {}", e);
-            return U256::ZERO;
+            return Ok(U256::ZERO);
         }
     };
     if user_total_debt == U256::ZERO {
-        return U256::ZERO;
+        return Ok(U256::ZERO);
     }
-    let normalized_debt = match AaveV3Pool::new(AAVE_V3_POOL_ADDRESS, provider.clone())
+    let normalized_debt = match AaveV3Pool::new(market.pool, provider.clone())
         .getReserveNormalizedVariableDebt(reserve)
+        .block(block)
         .call()
         .await
     {
@@ -400,47 +597,65 @@ async fn get_user_debt_in_base_currency(
             U256::ZERO
         }
     };
-    user_total_debt = ray_mul(user_total_debt, normalized_debt) * asset_price;
-    user_total_debt / asset_unit
+    user_total_debt = ray_mul(user_total_debt, normalized_debt)?;
+    value_in_base_currency(user_total_debt, asset_price, asset_unit)
 }
 
-/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/math/WadRayMath.sol#L65
-pub fn ray_mul(a: U256, b: U256) -> U256 {
-    let ray: U256 = U256::from_str_radix("1000000000000000000000000000", 10).unwrap(); // 1e27
-    let half_ray: U256 = U256::from_str_radix("500000000000000000000000000", 10).unwrap(); // 0.5e27
-
-    if a == U256::ZERO || b == U256::ZERO {
-        return U256::ZERO;
+/// If the user is in e-mode and the reserve is enabled as collateral in its category, the category
+/// liquidation threshold replaces the reserve one
+/// https://github.com/aave-dao/aave-v3-origin/blob/bb6ea42947f349fe8182a0ea30c5a7883d1f9ed1/src/contracts/protocol/libraries/logic/GenericLogic.sol#L108-L115
+pub fn get_effective_liquidation_threshold(
+    reserve_index: usize,
+    reserve_liquidation_threshold: U256,
+    user_emode: &Option<UserEModeCategory>,
+) -> U256 {
+    match user_emode {
+        Some(emode) if emode.is_collateral_in_category(reserve_index) => {
+            emode.liquidation_threshold
+        }
+        _ => reserve_liquidation_threshold,
     }
+}
 
-    // c = (a * b + half_ray) / ray
-    let product = a * b;
-    let with_half = product + half_ray;
-    with_half / ray
+/// Same as above, but for the liquidation bonus
+/// https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L252-L262
+pub fn get_effective_liquidation_bonus(
+    reserve_index: usize,
+    reserve_liquidation_bonus: U256,
+    user_emode: &Option<UserEModeCategory>,
+) -> U256 {
+    match user_emode {
+        Some(emode) if emode.is_collateral_in_category(reserve_index) => emode.liquidation_bonus,
+        _ => reserve_liquidation_bonus,
+    }
 }
 
 /// This is the equivalent of _calculateUserAccountData() in LiquidationLogic.sol
 /// https://github.com/aave-dao/aave-v3-origin/blob/bb6ea42947f349fe8182a0ea30c5a7883d1f9ed1/src/contracts/protocol/libraries/logic/GenericLogic.sol#L63
-/// except for emode support. We don't do that here.
+/// `user_emode` must be the category returned by get_user_emode_category() for this same user.
 pub async fn calculate_user_account_data(
     price_cache: Arc<tokio::sync::Mutex<PriceCache>>,
     provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
     user_address: Address,
     reserves_list: Vec<Address>,
     reserves_data: Vec<AggregatedReserveData>,
+    user_emode: &Option<UserEModeCategory>,
     trace_id: Option<String>,
-) -> Result<(U256, U256, U256), Box<dyn std::error::Error>> {
+    block: BlockId,
+) -> Result<(U256, U256, U256), ProfitoError> {
+    check_reserves_alignment(&reserves_list, &reserves_data)?;
+
     // Capture required input arguments
-    let mut total_collateral_in_base_currency = U256::ZERO;
-    let mut total_debt_in_base_currency = U256::ZERO;
-    let mut avg_liquidation_threshold = U256::ZERO;
-    let user_config = match AaveV3Pool::new(AAVE_V3_POOL_ADDRESS, provider.clone())
+    let mut terms = vec![];
+    let user_config = match AaveV3Pool::new(market.pool, provider.clone())
         .getUserConfiguration(user_address)
+        .block(block)
         .call()
         .await
     {
         Ok(user_config) => user_config._0,
-        Err(e) => return Err(format!("Error trying to call getUserConfiguration: {}", e).into()),
+        Err(e) => return Err(NodeError::call("getUserConfiguration", e).into()),
     };
 
     // Operate
@@ -448,12 +663,18 @@ pub async fn calculate_user_account_data(
         if !is_using_as_collateral_or_borrowing(user_config.data, i) {
             continue;
         }
+        if DECIMALS_GUARD.is_quarantined(market, &reserve_address) {
+            return Err(ProfitoError::Rejected(format!(
+                "user {} has a position in {}, whose decimals are quarantined",
+                user_address, reserve_address
+            )));
+        }
 
         /*
            Both reservesList and reservesData are aligned in the same order, meaning reservesList[i] is an asset
            address and reservesData[i] is the data for that asset.
 
-           That assertion is what makes the following valid.
+           That assertion (checked by check_reserves_alignment() above) is what makes the following valid.
         */
         let liquidation_threshold = reserves_data[i].reserveLiquidationThreshold;
         let decimals = reserves_data[i].decimals;
@@ -464,108 +685,104 @@ pub async fn calculate_user_account_data(
             .get_price(
                 reserve_address,
                 trace_id.clone(),
-                AaveOracle::new(AAVE_ORACLE_ADDRESS, provider.clone()),
+                AaveOracle::new(market.oracle, provider.clone()),
+                block,
             )
             .await
         {
             Ok(price) => price,
-            Err(e) => {
-                return Err(
-                    format!("Error trying to get price for {}: {}", reserve_address, e).into(),
-                )
+            Err(source) => {
+                return Err(ProfitoError::Price {
+                    asset: reserve_address,
+                    source,
+                })
             }
         };
 
+        // The LTV isn't needed for the health factor, so it's left at 0
+        let mut term = AccountTerm::default();
+
         // Calculate collateral totals
         if liquidation_threshold != U256::ZERO && is_using_as_collateral(user_config.data, i) {
             let user_balance_in_base_currency = get_user_balance_in_base_currency(
                 provider.clone(),
+                market,
                 reserve_address,
                 reserves_data[i].aTokenAddress,
                 user_address,
                 asset_price,
                 asset_unit,
+                block,
             )
-            .await;
-            total_collateral_in_base_currency += user_balance_in_base_currency;
-            avg_liquidation_threshold += user_balance_in_base_currency * liquidation_threshold;
+            .await?;
+            term.collateral_in_base_currency = user_balance_in_base_currency;
+            // the zero check above uses the reserve threshold, the average uses the e-mode one
+            term.liquidation_threshold =
+                get_effective_liquidation_threshold(i, liquidation_threshold, user_emode);
         };
 
         // Calculate debt totals
         if is_borrowing(user_config.data, i) {
-            if match AaveProtocolDataProvider::new(
-                AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS,
-                provider.clone(),
-            )
-            .getIsVirtualAccActive(reserve_address)
-            .call()
-            .await
+            if match AaveProtocolDataProvider::new(market.data_provider, provider.clone())
+                .getIsVirtualAccActive(reserve_address)
+                .block(block)
+                .call()
+                .await
             {
                 Ok(response) => response._0,
-                Err(e) => {
-                    return Err(format!("Error trying to call getIsVirtualAccActive: {}", e).into())
-                }
+                Err(e) => return Err(NodeError::call("getIsVirtualAccActive", e).into()),
             } {
                 let user_debt_in_base_currency = get_user_debt_in_base_currency(
                     provider.clone(),
+                    market,
                     reserve_address,
                     reserves_data[i].variableDebtTokenAddress,
                     user_address,
                     asset_price,
                     asset_unit,
+                    block,
                 )
-                .await;
-                total_debt_in_base_currency += user_debt_in_base_currency;
+                .await?;
+                term.debt_in_base_currency = user_debt_in_base_currency;
             } else {
                 // custom case for GHO, which applies the GHO discount on balanceOf
                 // https://github.com/aave-dao/aave-v3-origin/blob/bb6ea42947f349fe8182a0ea30c5a7883d1f9ed1/src/contracts/protocol/libraries/logic/GenericLogic.sol#L148
-                total_debt_in_base_currency +=
+                let balance =
                     match ERC20::new(reserves_data[i].variableDebtTokenAddress, provider.clone())
                         .balanceOf(user_address)
+                        .block(block)
                         .call()
                         .await
                     {
                         Ok(balance_of_response) => balance_of_response.balance,
                         Err(e) => {
-                            return Err(format!(
-                                "Error trying to call balanceOf for {}: {}",
-                                user_address, e
+                            return Err(
+                                NodeError::call(format!("balanceOf({})", user_address), e).into()
                             )
-                            .into())
                         }
-                    } * asset_price
-                        / asset_unit;
+                    };
+                term.debt_in_base_currency =
+                    value_in_base_currency(balance, asset_price, asset_unit)?;
             }
         }
+        terms.push(term);
     }
 
-    if total_collateral_in_base_currency != U256::ZERO {
-        avg_liquidation_threshold /= total_collateral_in_base_currency;
-    } else {
-        avg_liquidation_threshold = U256::ZERO;
-    }
-
-    let health_factor = if total_debt_in_base_currency != U256::ZERO {
-        let wad_numerator =
-            percent_mul(total_collateral_in_base_currency, avg_liquidation_threshold);
-        wad_div(wad_numerator, total_debt_in_base_currency)
-    } else {
-        U256::MAX
-    };
-
     // Return values
+    let account_data = calculate_account_data(terms)?;
     Ok((
-        total_collateral_in_base_currency,
-        total_debt_in_base_currency,
-        health_factor,
+        account_data.total_collateral_in_base_currency,
+        account_data.total_debt_in_base_currency,
+        account_data.health_factor,
     ))
 }
 
-/// Returns pools, fees and liquidity sorted by liquidity descending
-async fn get_uniswap_v3_pools(
+/// Returns pools, fees and liquidity (at `block`) sorted by liquidity descending
+pub(crate) async fn get_uniswap_v3_pools(
     provider: &RootProvider<PubSubFrontend>,
     token_a: Address,
     token_b: Address,
+    block: BlockId,
 ) -> Vec<(Address, U24, u128)> {
     let factory = UniswapV3Factory::new(UNISWAP_V3_FACTORY, provider.clone());
     let fee_tiers = [
@@ -577,7 +794,12 @@ async fn get_uniswap_v3_pools(
     let mut pools = Vec::new();
 
     for &fee in &fee_tiers {
-        let pool_address = match factory.getPool(token_a, token_b, fee).call().await {
+        let pool_address = match factory
+            .getPool(token_a, token_b, fee)
+            .block(block)
+            .call()
+            .await
+        {
             Ok(response) => response._0,
             Err(e) => {
                 println!("(Error fetching pool: {})", e);
@@ -585,7 +807,7 @@ async fn get_uniswap_v3_pools(
             }
         };
         let pool = UniswapV3Pool::new(pool_address, provider.clone());
-        let in_range_liquidity = match pool.liquidity().call().await {
+        let in_range_liquidity = match pool.liquidity().block(block).call().await {
             Ok(response) => response._0,
             Err(e) => {
                 println!("(Error fetching pool liquidity: {})", e);
@@ -601,19 +823,46 @@ async fn get_uniswap_v3_pools(
     pools
 }
 
+/// Fee of the deepest pool, or with `cheapest_tier` the lowest fee among the pools with at least
+/// CHEAPEST_SWAP_TIER_MIN_LIQUIDITY_BPS of its liquidity. `pools` must be sorted by liquidity
+/// descending, as returned by get_uniswap_v3_pools().
+fn pick_swap_fee(pools: &[(Address, U24, u128)], cheapest_tier: bool) -> U24 {
+    let deepest = pools[0];
+    if !cheapest_tier {
+        return deepest.1;
+    }
+    let min_liquidity = deepest.2 / 10_000 * CHEAPEST_SWAP_TIER_MIN_LIQUIDITY_BPS;
+    pools
+        .iter()
+        .filter(|(_, _, liquidity)| *liquidity >= min_liquidity)
+        .map(|(_, fee, _)| *fee)
+        .min()
+        .unwrap_or(deepest.1)
+}
+
 /// UniswapV3 fees are hundredths of basis points: 1% == 10000; 0,3% == 3000; 0,05% == 500; 0,01% == 100
-/// Calculate and return the lowest fee tier for which there's enough liquidity
+/// Calculate and return the lowest fee tier for which there's enough liquidity. These are the legs
+/// of triggerLiquidation(), which always swaps through WETH. Bundles are sent with the fees of
+/// the route SWAP_ROUTER quoted (see SwapRoute::uniswap_v3_fees()), these only pick the ones
+/// the CHEAPEST_SWAP_TIER_FLAG experiment quotes instead.
+///
+/// When the CHEAPEST_SWAP_TIER_FLAG experiment picks other fees than the deepest pools, a use of it
+/// is reserved and returned with them. Commit it once the bundle with those fees is submitted,
+/// dropping it gives the use back.
 pub async fn calculate_best_swap_fees(
     provider: Arc<RootProvider<PubSubFrontend>>,
     collateral_asset: Address,
     debt_asset: Address,
-) -> (U24, U24) {
+    block: BlockId,
+) -> (U24, U24, Option<FlagReservation>) {
     // collateral to weth, weth to debt
     let mut best_fees = (U24::from(10000), U24::from(10000));
+    let mut cheapest_fees = best_fees;
+    let cheapest_tier = FEATURE_FLAGS.is_enabled(CHEAPEST_SWAP_TIER_FLAG);
 
     // Get collateral -> WETH pools
     if collateral_asset != WETH {
-        let collateral_pools = get_uniswap_v3_pools(&provider, collateral_asset, WETH).await;
+        let collateral_pools = get_uniswap_v3_pools(&provider, collateral_asset, WETH, block).await;
         println!(
             "\t\tFound {} collateral/WETH pools:",
             collateral_pools.len()
@@ -624,18 +873,20 @@ pub async fn calculate_best_swap_fees(
                 in_range_liquidity, fee, addr
             );
         }
-        best_fees.0 = collateral_pools[0].1;
+        best_fees.0 = pick_swap_fee(&collateral_pools, false);
+        cheapest_fees.0 = pick_swap_fee(&collateral_pools, cheapest_tier);
     } else {
         // If collateral is WETH, foxdie won't swap this leg because we're already where we want
         // (that is, holding WETH balance and right before swapping that WETH for debt asset to repay loan)
         // so it'll ignore whatever we send as COLLATERAL_TO_WETH_FEE
         best_fees.0 = U24::from(0);
+        cheapest_fees.0 = best_fees.0;
         println!("\t\tCollateral is WETH, no need to swap this leg");
     }
 
     // Get WETH -> debt pools
     if debt_asset != WETH {
-        let debt_pools = get_uniswap_v3_pools(&provider, WETH, debt_asset).await;
+        let debt_pools = get_uniswap_v3_pools(&provider, WETH, debt_asset, block).await;
         println!("\t\tFound {} WETH/debt pools:", debt_pools.len());
         for (addr, fee, in_range_liquidity) in &debt_pools {
             println!(
@@ -643,55 +894,177 @@ pub async fn calculate_best_swap_fees(
                 in_range_liquidity, fee, addr
             );
         }
-        best_fees.1 = debt_pools[0].1;
+        best_fees.1 = pick_swap_fee(&debt_pools, false);
+        cheapest_fees.1 = pick_swap_fee(&debt_pools, cheapest_tier);
     } else {
         // Since foxdie swaps collateral for WETH irregardles of what the debt asset is, if it happens to be WETH
         // we're already where we want (that is, holding WETH after foxide swapped collateral for it, and right before
         // repaying the loan which, in this case, was WETH). Foxdie will ignore whatever we send as WETH_TO_DEBT_FEE
         best_fees.1 = U24::from(0);
+        cheapest_fees.1 = best_fees.1;
         println!("\t\tDebt is WETH, no need to swap this leg");
     }
 
     // TODO: Calculate best fees based on liquidity and price impact
-    best_fees
+    if cheapest_fees != best_fees {
+        if let Some(reservation) = FEATURE_FLAGS.try_reserve(CHEAPEST_SWAP_TIER_FLAG) {
+            return (cheapest_fees.0, cheapest_fees.1, Some(reservation));
+        }
+    }
+    (best_fees.0, best_fees.1, None)
 }
 
-/// TODO: This function is not returning the appropriate amount of gas and needs
-/// to be fixed.
-pub async fn estimate_gas(provider: Arc<RootProvider<PubSubFrontend>>) -> (U256, U256, U256) {
-    let default_gas_used = U256::from(700000);
-    let gas_price_in_gwei = match provider.get_gas_price().await {
-        Ok(price) => U256::from(price) / U256::from(1e3),
-        _ => U256::MAX,
-    };
-    (
-        default_gas_used,
-        gas_price_in_gwei,
-        default_gas_used * gas_price_in_gwei / U256::from(1000000),
-    )
-    /*
-    match Foxdie::new(FOXDIE_ADDRESS, provider.clone())
-        .triggerLiquidation(Foxdie::LiquidationParams {
-            debtAmount: U256::ZERO,
-            user: Address::ZERO,
-            debtAsset: Address::ZERO,
-            collateral: Address::ZERO,
-            collateralToWethFee: U24::from(0),
-            wethToDebtFee: U24::from(0),
-            bribePercentBps: BRIBE_IN_BASIS_POINTS,
-            flashLoanSource: Foxdie::FlashLoanSource::MORPHO,
-            aavePremium: U256::ZERO,
-        }).estimate_gas().await {
-            Ok(gas_used) => {
-                (U256::from(gas_used), gas_price_in_gwei, U256::from(gas_used) * gas_price_in_gwei / U256::from(1000000))
-            },
-            Err(e) => {
-                println!("Error estimating gas: {}", e);
-                warn!("Error estimating gas: {}", e);
-                return (default_gas_used, gas_price_in_gwei, default_gas_used * gas_price_in_gwei / U256::from(1000000))
-            }
+/// Route SWAP_ROUTER finds at `block` for Foxdie to swap `amount_in` of `collateral_asset`, with
+/// the debt asset it's quoted to return. The quote includes the pool fees and the price impact of
+/// the swaps. The CHEAPEST_SWAP_TIER_FLAG experiment may swap through other Uniswap V3 pools once
+/// the bundle is built, which are quoted then.
+pub async fn quote_collateral_swap(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    collateral_asset: Address,
+    debt_asset: Address,
+    amount_in: U256,
+    block: BlockId,
+) -> Result<SwapRoute, ProfitoError> {
+    SWAP_ROUTER
+        .find_best_route(provider, collateral_asset, debt_asset, amount_in, block)
+        .await
+}
+
+/// Gas expected to be used liquidating `debt_asset` for `collateral_asset` (see
+/// GasEstimates::gas_limit()), the current gas price and what the gas costs at that price, in wei.
+/// The gas price is U256::MAX if it can't be read, so nothing looks profitable.
+pub async fn estimate_gas(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    collateral_asset: Address,
+    debt_asset: Address,
+) -> (U256, U256, U256) {
+    let gas_used = U256::from(GAS_ESTIMATES.gas_limit(collateral_asset, debt_asset));
+    let gas_price = match provider.get_gas_price().await {
+        Ok(price) => U256::from(price),
+        Err(e) => {
+            warn!("Error trying to call eth_gasPrice: {}", e);
+            U256::MAX
         }
-     */
+    };
+    (gas_used, gas_price, gas_used.saturating_mul(gas_price))
+}
+
+/// Price of WETH in base currency, which gas is priced with. Markets without a WETH reserve (like
+/// EtherFi) have no price for it, so when they quote in USD like the core market, the core
+/// market's oracle prices it instead. Fails if it can't be read, since gas would be free without it.
+async fn get_native_asset_price(
+    price_cache: Arc<tokio::sync::Mutex<PriceCache>>,
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
+    trace_id: &str,
+    oracle: AaveOracle::AaveOracleInstance<PubSubFrontend, Arc<RootProvider<PubSubFrontend>>>,
+    base_currency: &BaseCurrency,
+    block: BlockId,
+) -> Result<U256, ProfitoError> {
+    let market_price = price_cache
+        .lock()
+        .await
+        .get_price(WETH, Some(trace_id.to_string()), oracle, block)
+        .await;
+    let error = match market_price {
+        Ok(price) if price > U256::ZERO => return Ok(price),
+        Ok(_) => "the oracle returned 0".to_string(),
+        Err(e) => e.to_string(),
+    };
+    if market.is_core() || *base_currency != BaseCurrency::USD {
+        return Err(ProfitoError::Price {
+            asset: WETH,
+            source: error.into(),
+        });
+    }
+    match get_asset_price_at(provider, AAVE_ORACLE_ADDRESS, WETH, block).await {
+        Ok(price) if price > U256::ZERO => Ok(price),
+        Ok(_) => Err(ProfitoError::Price {
+            asset: WETH,
+            source: format!(
+                "{} in the {} market, and 0 in the core one",
+                error, market.name
+            )
+            .into(),
+        }),
+        Err(e) => Err(ProfitoError::Price {
+            asset: WETH,
+            source: format!(
+                "{} in the {} market, and {} in the core one",
+                error, market.name, e
+            )
+            .into(),
+        }),
+    }
+}
+
+/// `a * b * c / (d * e)`, failing where the pool would revert: if a product doesn't fit in 256
+/// bits, or the divisor is zero
+fn checked_mul_div(numerator: [U256; 3], denominator: [U256; 2]) -> Result<U256, MathError> {
+    let [a, b, c] = numerator;
+    let [d, e] = denominator;
+    let numerator = a
+        .checked_mul(b)
+        .and_then(|ab| ab.checked_mul(c))
+        .ok_or(MathError::Overflow)?;
+    let denominator = d.checked_mul(e).ok_or(MathError::Overflow)?;
+    numerator
+        .checked_div(denominator)
+        .ok_or(MathError::DivisionByZero)
+}
+
+/// Collateral the liquidator gets for repaying `debt_to_cover` (net of the protocol fee), the debt
+/// actually repaid, and the protocol fee. When the collateral doesn't cover the debt plus the bonus,
+/// all of it is taken and less debt is repaid.
+/// https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L633
+pub fn calculate_liquidation_amounts(
+    collateral_asset_price: U256,
+    collateral_asset_unit: U256,
+    debt_asset_price: U256,
+    debt_asset_unit: U256,
+    debt_to_cover: U256,
+    user_collateral_balance: U256,
+    liquidation_bonus: U256,
+    liquidation_protocol_fee_percentage: U256,
+) -> Result<(U256, U256, U256), MathError> {
+    let base_collateral = checked_mul_div(
+        [debt_asset_price, debt_to_cover, collateral_asset_unit],
+        [collateral_asset_price, debt_asset_unit],
+    )?;
+    let max_collateral_to_liquidate = percent_mul(base_collateral, liquidation_bonus)?;
+
+    let mut collateral_amount;
+    let debt_amount_needed;
+    if max_collateral_to_liquidate > user_collateral_balance {
+        collateral_amount = user_collateral_balance;
+        debt_amount_needed = percent_div(
+            checked_mul_div(
+                [collateral_asset_price, collateral_amount, debt_asset_unit],
+                [debt_asset_price, collateral_asset_unit],
+            )?,
+            liquidation_bonus,
+        )?;
+    } else {
+        collateral_amount = max_collateral_to_liquidate;
+        debt_amount_needed = debt_to_cover;
+    }
+
+    let mut liquidation_protocol_fee = U256::ZERO;
+    if liquidation_protocol_fee_percentage != U256::ZERO {
+        let bonus_collateral = collateral_amount
+            .checked_sub(percent_div(collateral_amount, liquidation_bonus)?)
+            .ok_or(MathError::Overflow)?;
+        liquidation_protocol_fee =
+            percent_mul(bonus_collateral, liquidation_protocol_fee_percentage)?;
+        collateral_amount = collateral_amount
+            .checked_sub(liquidation_protocol_fee)
+            .ok_or(MathError::Overflow)?;
+    }
+    Ok((
+        collateral_amount,
+        debt_amount_needed,
+        liquidation_protocol_fee,
+    ))
 }
 
 /// This function is supposed to be the EXACT SAME copy of the one defined
@@ -704,7 +1077,11 @@ pub async fn estimate_gas(provider: Arc<RootProvider<PubSubFrontend>>) -> (U256,
 /// when called from bpchecker, and then you'll be able to remove this duplicate logic
 async fn calculate_available_collateral_to_liquidate(
     provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
     collateral_asset: Address,
+    debt_asset: Address,
+    // Price of the native asset (WETH) in base currency, to price gas with
+    native_asset_price: U256,
     // all original args for this function under this line
     collateral_asset_price: U256,
     collateral_asset_unit: U256,
@@ -713,53 +1090,39 @@ async fn calculate_available_collateral_to_liquidate(
     debt_to_cover: U256,
     user_collateral_balance: U256,
     liquidation_bonus: U256,
-) -> Result<(U256, U256, U256, U256), Box<dyn std::error::Error>> {
+    block: BlockId,
+) -> Result<(U256, U256, U256, U256, U256, SwapRoute), ProfitoError> {
     // Implementation of
     // https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L633
 
-    let protocol =
-        AaveProtocolDataProvider::new(AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS, provider.clone());
+    let protocol = AaveProtocolDataProvider::new(market.data_provider, provider.clone());
     let liquidation_protocol_fee_percentage = match protocol
         .getLiquidationProtocolFee(collateral_asset)
+        .block(block)
         .call()
         .await
     {
         Ok(response) => response._0,
-        Err(e) => {
-            return Err(format!("Error trying to call collateralAToken.balanceOf(): {}", e).into())
-        }
+        Err(e) => return Err(NodeError::call("getLiquidationProtocolFee", e).into()),
     };
-    let base_collateral = (debt_asset_price * debt_to_cover * collateral_asset_unit)
-        / (collateral_asset_price * debt_asset_unit);
-    let max_collateral_to_liquidate = percent_mul(base_collateral, liquidation_bonus);
-
-    let mut collateral_amount;
-    let debt_amount_needed;
-    if max_collateral_to_liquidate > user_collateral_balance {
-        collateral_amount = user_collateral_balance;
-        debt_amount_needed = percent_div(
-            (collateral_asset_price * collateral_amount * debt_asset_unit)
-                / (debt_asset_price * collateral_asset_unit),
+    let (collateral_amount, debt_amount_needed, liquidation_protocol_fee) =
+        calculate_liquidation_amounts(
+            collateral_asset_price,
+            collateral_asset_unit,
+            debt_asset_price,
+            debt_asset_unit,
+            debt_to_cover,
+            user_collateral_balance,
             liquidation_bonus,
-        );
-    } else {
-        collateral_amount = max_collateral_to_liquidate;
-        debt_amount_needed = debt_to_cover;
-    }
-
-    let mut liquidation_protocol_fee = U256::ZERO;
-    if liquidation_protocol_fee_percentage != U256::ZERO {
-        let bonus_collateral =
-            collateral_amount - percent_div(collateral_amount, liquidation_bonus);
-        liquidation_protocol_fee =
-            percent_mul(bonus_collateral, liquidation_protocol_fee_percentage);
-        collateral_amount -= liquidation_protocol_fee;
-    }
+            liquidation_protocol_fee_percentage,
+        )?;
 
     // THIS IS THE CORE OF THE CALCULATION, WHAT DECIDES WHETHER OR NOT WE MOVE ON WITH THE EXECUTION
     // this section doesn't belong to the original solidity function
-    let debt_in_collateral_units = (debt_amount_needed * debt_asset_price * collateral_asset_unit)
-        / (collateral_asset_price * debt_asset_unit);
+    let debt_in_collateral_units = checked_mul_div(
+        [debt_amount_needed, debt_asset_price, collateral_asset_unit],
+        [collateral_asset_price, debt_asset_unit],
+    )?;
     // This already has the liquidation fee deducted
     let base_profit = if collateral_amount >= debt_in_collateral_units {
         collateral_amount - debt_in_collateral_units
@@ -767,12 +1130,32 @@ async fn calculate_available_collateral_to_liquidate(
         debt_in_collateral_units - collateral_amount
     };
 
-    let execution_gas_cost = estimate_gas(provider.clone()).await.2;
-    // this assumes we will swap in 1% fee pools (could be more sophisticated)
-    // uniswap v3 fees are represented as hundredths of basis points: 1% == 100; 0,3% == 30; 0,05% == 5; 0,01% == 1
-    let swap_loss_factor = U256::from(100);
-    let swap_total_cost = percent_mul(collateral_amount, swap_loss_factor);
-    let total_cost = execution_gas_cost + swap_total_cost;
+    // Gas is paid in the native asset, and profit is counted in collateral units
+    let execution_gas_cost = wei_to_asset_units(
+        estimate_gas(provider.clone(), collateral_asset, debt_asset)
+            .await
+            .2,
+        native_asset_price,
+        collateral_asset_price,
+        collateral_asset_unit,
+    );
+    // What the swaps actually return for the collateral (pool fees and price impact included),
+    // against what it's worth at oracle prices
+    let swap_route = quote_collateral_swap(
+        provider.clone(),
+        collateral_asset,
+        debt_asset,
+        collateral_amount,
+        block,
+    )
+    .await?;
+    let debt_asset_out = swap_route.amount_out;
+    let debt_asset_out_in_collateral_units = checked_mul_div(
+        [debt_asset_out, debt_asset_price, collateral_asset_unit],
+        [collateral_asset_price, debt_asset_unit],
+    )?;
+    let swap_total_cost = collateral_amount.saturating_sub(debt_asset_out_in_collateral_units);
+    let total_cost = execution_gas_cost.saturating_add(swap_total_cost);
 
     // this will cause some weird numbers in output logs for positions with a single possible
     // pair, but would make sure no overflow errors accidentally replace the best pair
@@ -782,18 +1165,144 @@ async fn calculate_available_collateral_to_liquidate(
     } else {
         base_profit - total_cost
     };
+    // Keeping the collateral as aTokens skips the swap, so only gas is paid
+    let hold_net_profit = base_profit.saturating_sub(execution_gas_cost);
     Ok((
         collateral_amount,
         debt_amount_needed,
         liquidation_protocol_fee,
         (net_profit * collateral_asset_price) / collateral_asset_unit,
+        (hold_net_profit * collateral_asset_price) / collateral_asset_unit,
+        swap_route,
     ))
 }
 
-/// Returns the appropriate bribe based on the amount earned
-pub fn calculate_bribe() -> U256 {
+/// Returns the appropriate bribe based on the amount earned, see BRIBE_POLICY
+pub fn calculate_bribe(
+    net_profit_usd: f64,
+    gas_price_wei: u128,
+    competition: f64,
+) -> BribeDecision {
     // From 0 to 9999
-    U256::from(BRIBE_IN_BASIS_POINTS)
+    BRIBE_POLICY.decide(net_profit_usd, gas_price_wei, competition)
+}
+
+/// Timestamps until which liquidations involving each asset are disabled. Grace periods are set
+/// by the protocol when a reserve is unpaused (Aave v3.1+). Assets whose grace period can't be
+/// read are assumed not to have one, and none are read if the pool configurator can't give any
+/// (see LiquidationParams::max_grace_period).
+async fn get_liquidation_grace_periods(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
+    assets: HashSet<Address>,
+    block: BlockId,
+    params: &LiquidationParams,
+) -> HashMap<Address, u64> {
+    if params.max_grace_period == 0 {
+        return HashMap::new();
+    }
+    let pool = AaveV3Pool::new(market.pool, provider);
+    let pool = &pool;
+    join_all(assets.into_iter().map(|asset| async move {
+        match pool
+            .getLiquidationGracePeriod(asset)
+            .block(block)
+            .call()
+            .await
+        {
+            Ok(response) => Some((asset, response._0.to::<u64>())),
+            Err(e) => {
+                warn!(
+                    "Error trying to call getLiquidationGracePeriod({}): {}",
+                    asset, e
+                );
+                None
+            }
+        }
+    }))
+    .await
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Reason why liquidating `debt_reserve` for `collateral_reserve` would revert, if any. Mirrors
+/// the reserve checks of validateLiquidationCall() in
+/// https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/ValidationLogic.sol
+/// Frozen reserves can still be liquidated, the protocol only checks that on supplies and borrows.
+/// See ReserveConfiguration::get_collateral_blocker() for the checks on the collateral's
+/// configuration.
+fn get_liquidation_blocker(
+    collateral_reserve: &AggregatedReserveData,
+    debt_reserve: &AggregatedReserveData,
+    grace_periods: &HashMap<Address, u64>,
+    now: u64,
+    inclusion_timestamp: u64,
+) -> Option<String> {
+    for reserve in [collateral_reserve, debt_reserve] {
+        if !reserve.isActive {
+            return Some(format!("reserve {} is not active", reserve.symbol));
+        }
+        if reserve.isPaused {
+            return Some(format!("reserve {} is paused", reserve.symbol));
+        }
+        let grace_period_until = grace_periods
+            .get(&reserve.underlyingAsset)
+            .copied()
+            .unwrap_or_default();
+        if grace_period_until >= inclusion_timestamp {
+            return Some(format!(
+                "reserve {} is in its liquidation grace period for another {}s",
+                reserve.symbol,
+                grace_period_until - now
+            ));
+        }
+    }
+    if let Some(reason) =
+        ReserveConfiguration::from_reserve_data(collateral_reserve).get_collateral_blocker()
+    {
+        return Some(format!(
+            "collateral {} can't be liquidated, {}",
+            collateral_reserve.symbol, reason
+        ));
+    }
+    None
+}
+
+/// Reason why the liquidation would revert with MUST_NOT_LEAVE_DUST, if any. Unless it repays all
+/// the debt of the reserve or takes all of the collateral, it has to leave at least
+/// MIN_LEFTOVER_BASE of both. Taking all of the collateral is fine even if debt is left, since v3.3
+/// burns it as a deficit once the user has no collateral at all.
+/// https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L320-L344
+fn get_dust_leftover(
+    leftover_debt: U256,
+    debt_asset_price: U256,
+    debt_asset_unit: U256,
+    leftover_collateral: U256,
+    collateral_asset_price: U256,
+    collateral_asset_unit: U256,
+    params: &LiquidationParams,
+) -> Option<String> {
+    if leftover_debt == U256::ZERO || leftover_collateral == U256::ZERO {
+        return None;
+    }
+    let min_leftover_base = params.min_leftover_base();
+    let leftover_debt_in_base_currency = leftover_debt * debt_asset_price / debt_asset_unit;
+    if leftover_debt_in_base_currency < min_leftover_base {
+        return Some(format!(
+            "it would leave {} of debt in base currency (less than {})",
+            leftover_debt_in_base_currency, min_leftover_base
+        ));
+    }
+    let leftover_collateral_in_base_currency =
+        leftover_collateral * collateral_asset_price / collateral_asset_unit;
+    if leftover_collateral_in_base_currency < min_leftover_base {
+        return Some(format!(
+            "it would leave {} of collateral in base currency (less than {})",
+            leftover_collateral_in_base_currency, min_leftover_base
+        ));
+    }
+    None
 }
 
 /// Not exactly the same as the one from bpchecker
@@ -803,19 +1312,74 @@ pub fn calculate_bribe() -> U256 {
 /// This function also assumes trace_id will always be NOT none, as opposed to the one in bpchecker
 /// which passes none since it's only for compatibility purposes with the price cache integration
 /// in some of the helper functions.
-
 pub async fn get_best_liquidation_opportunity(
     user_reserve_data: Vec<UserReserveData>, // for borrowed_reserve and supplied_reserve
     reserves_data: Vec<AggregatedReserveData>,
+    reserves_list: &[Address],
     user_address: Address,
     health_factor_v33: U256,
     total_debt_in_base_currency: U256,
     price_cache: Arc<tokio::sync::Mutex<PriceCache>>,
     provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
     trace_id: String,
     oracle: AaveOracle::AaveOracleInstance<PubSubFrontend, Arc<RootProvider<PubSubFrontend>>>,
+    user_emode: &Option<UserEModeCategory>,
+    base_currency: &BaseCurrency,
+    chain_profile: &ChainProfile,
+    block: BlockId,
 ) -> Option<BestPair> {
     let mut best_pair: Option<BestPair> = None;
+    // Read once, so every pair is evaluated with the same params
+    let liquidation_params = LiquidationParams::current(market);
+    let native_asset_price = match get_native_asset_price(
+        price_cache.clone(),
+        provider.clone(),
+        market,
+        &trace_id,
+        oracle.clone(),
+        base_currency,
+        block,
+    )
+    .await
+    {
+        Ok(price) => price,
+        // Gas couldn't be counted as a cost, so any pair would look more profitable than it is
+        Err(e) => {
+            warn!("Not evaluating {}, gas can't be priced: {}", trace_id, e);
+            return None;
+        }
+    };
+    let min_profit = match PROFIT_POLICY.min_profit_in_base_units(base_currency, native_asset_price)
+    {
+        Some(min_profit) => min_profit,
+        // Any profit would pass without it
+        None => {
+            warn!(
+                "Not evaluating {}, the minimum profit can't be converted to base units",
+                trace_id
+            );
+            return None;
+        }
+    };
+    let grace_periods = get_liquidation_grace_periods(
+        provider.clone(),
+        market,
+        user_reserve_data
+            .iter()
+            .filter(|r| r.scaledVariableDebt > U256::ZERO || r.scaledATokenBalance > U256::ZERO)
+            .map(|r| r.underlyingAsset)
+            .collect(),
+        block,
+        &liquidation_params,
+    )
+    .await;
+    // A bundle can't land before the next block, so that's the earliest timestamp the
+    // liquidation is validated against
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let inclusion_timestamp = now + chain_profile.block_time().as_secs().max(1);
     for borrowed_reserve in user_reserve_data
         .iter()
         .filter(|r| r.scaledVariableDebt > U256::ZERO)
@@ -824,6 +1388,19 @@ pub async fn get_best_liquidation_opportunity(
             .iter()
             .filter(|r| r.usageAsCollateralEnabledOnUser && r.scaledATokenBalance > U256::ZERO)
         {
+            if let Some(asset) = KILL_SWITCH.first_blocked([
+                supplied_reserve.underlyingAsset,
+                borrowed_reserve.underlyingAsset,
+            ]) {
+                warn!("Skipping pair because {} is in the kill switch list", asset);
+                continue;
+            }
+            if !ASSET_FILTER.is_allowed(&supplied_reserve.underlyingAsset)
+                || !ASSET_FILTER.is_allowed(&borrowed_reserve.underlyingAsset)
+            {
+                continue;
+            }
+
             // begin section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L234-L238
             let (collateral_reserve, user_collateral_balance, debt_reserve, user_reserve_debt) =
                 match calculate_user_balances(
@@ -832,6 +1409,7 @@ pub async fn get_best_liquidation_opportunity(
                     borrowed_reserve,
                     provider.clone(),
                     user_address,
+                    block,
                 )
                 .await
                 {
@@ -843,10 +1421,37 @@ pub async fn get_best_liquidation_opportunity(
                 };
             // end section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L234-L238
 
+            // A bundle for this pair would revert, but another pair of the same user may still work
+            if let Some(reason) = get_liquidation_blocker(
+                &collateral_reserve,
+                &debt_reserve,
+                &grace_periods,
+                now,
+                inclusion_timestamp,
+            ) {
+                warn!(
+                    "Skipping pair {}/{} of {} @ {} because {}",
+                    collateral_reserve.symbol, debt_reserve.symbol, user_address, trace_id, reason
+                );
+                continue;
+            }
+
             // begin section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L252-L276
             // TODO(Hernan): you should at least visually check if liquidationBonus is returning what you're expecting, since
             // the solidity implementation uses bit masking to get the value.
-            let liquidation_bonus = collateral_reserve.reserveLiquidationBonus;
+            let collateral_reserve_index =
+                match get_reserve_index(reserves_list, collateral_reserve.underlyingAsset) {
+                    Ok(collateral_reserve_index) => collateral_reserve_index,
+                    Err(e) => {
+                        warn!("Skipping pair of {} @ {}: {}", user_address, trace_id, e);
+                        continue;
+                    }
+                };
+            let liquidation_bonus = get_effective_liquidation_bonus(
+                collateral_reserve_index,
+                collateral_reserve.reserveLiquidationBonus,
+                user_emode,
+            );
             let collateral_asset_price = price_cache
                 .lock()
                 .await
@@ -854,6 +1459,7 @@ pub async fn get_best_liquidation_opportunity(
                     supplied_reserve.underlyingAsset,
                     Some(trace_id.clone()),
                     oracle.clone(),
+                    block,
                 )
                 .await
                 .unwrap();
@@ -864,6 +1470,7 @@ pub async fn get_best_liquidation_opportunity(
                     borrowed_reserve.underlyingAsset,
                     Some(trace_id.clone()),
                     oracle.clone(),
+                    block,
                 )
                 .await
                 .unwrap();
@@ -876,7 +1483,7 @@ pub async fn get_best_liquidation_opportunity(
             // end section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L252-L276
 
             // begin section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L278-L302
-            let actual_debt_to_liquidate = calculate_actual_debt_to_liquidate(
+            let actual_debt_to_liquidate = match calculate_actual_debt_to_liquidate(
                 user_reserve_debt,
                 user_reserve_collateral_in_base_currency,
                 user_reserve_debt_in_base_currency,
@@ -884,7 +1491,14 @@ pub async fn get_best_liquidation_opportunity(
                 total_debt_in_base_currency,
                 debt_asset_unit,
                 debt_asset_price,
-            );
+                &liquidation_params,
+            ) {
+                Ok(actual_debt_to_liquidate) => actual_debt_to_liquidate,
+                Err(e) => {
+                    warn!("Error calculating actual debt to liquidate: {}", e);
+                    continue;
+                }
+            };
             // end section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L278-L302
 
             // begin section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L309
@@ -896,9 +1510,15 @@ pub async fn get_best_liquidation_opportunity(
                 // comparable across different assets:
                 //      (net_profit * collateral_asset_price) / collateral_asset_unit,
                 net_profit,
+                // Same, without the swap cost
+                hold_net_profit,
+                swap_route,
             ) = match calculate_available_collateral_to_liquidate(
                 provider.clone(),
+                market,
                 collateral_reserve.underlyingAsset,
+                debt_reserve.underlyingAsset,
+                native_asset_price,
                 collateral_asset_price,
                 collateral_asset_unit,
                 debt_asset_price,
@@ -906,6 +1526,7 @@ pub async fn get_best_liquidation_opportunity(
                 actual_debt_to_liquidate,
                 user_collateral_balance,
                 liquidation_bonus,
+                block,
             )
             .await
             {
@@ -918,19 +1539,80 @@ pub async fn get_best_liquidation_opportunity(
             // end section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L309
 
             // begin section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L320-L344
-            // TODO(Hernan): do we need to make sure this doesn't bite us in the ass?
+            // This only happens when the close factor caps the debt of a position barely above
+            // MIN_BASE_MAX_CLOSE_FACTOR_THRESHOLD. Liquidating less could leave enough of both,
+            // but the pair isn't worth resizing, so it's skipped.
+            if let Some(reason) = get_dust_leftover(
+                user_reserve_debt.saturating_sub(actual_debt_to_liquidate),
+                debt_asset_price,
+                debt_asset_unit,
+                user_collateral_balance
+                    .saturating_sub(actual_collateral_to_liquidate)
+                    .saturating_sub(liquidation_protocol_fee_amount),
+                collateral_asset_price,
+                collateral_asset_unit,
+                &liquidation_params,
+            ) {
+                warn!(
+                    "Skipping pair {}/{} of {} @ {} because {}",
+                    collateral_reserve.symbol, debt_reserve.symbol, user_address, trace_id, reason
+                );
+                continue;
+            }
             // end section https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L320-L344
 
-            let printable_net_profit =
-                format_units(net_profit, 8).unwrap_or_else(|_| "CONVERSION_ERROR".to_string());
             let best_liquidity_provider = get_best_liquidity_provider(
                 provider.clone(),
+                market,
                 debt_reserve.underlyingAsset,
                 actual_debt_to_liquidate,
+                block,
             )
             .await;
+            // The premium is repaid on top of the loan, out of the swapped collateral
+            let swap_net_profit = if best_liquidity_provider.source == Foxdie::FlashLoanSource::NONE
+            {
+                U256::ZERO
+            } else {
+                net_profit.saturating_sub(
+                    best_liquidity_provider.premium * debt_asset_price / debt_asset_unit,
+                )
+            };
+            // Only worth checking Foxdie's inventory for if holding beats swapping
+            let execution_mode = if hold_net_profit > swap_net_profit
+                && HOLD_COLLATERAL
+                    .can_hold(
+                        provider.clone(),
+                        market,
+                        collateral_reserve.underlyingAsset,
+                        debt_reserve.underlyingAsset,
+                        actual_debt_to_liquidate,
+                        block,
+                    )
+                    .await
+            {
+                ExecutionMode::ReceiveAToken
+            } else {
+                ExecutionMode::SwapAndRepay
+            };
+            let (net_profit, flash_loan_source, flash_loan_premium, swap_route) =
+                match execution_mode {
+                    ExecutionMode::ReceiveAToken => (
+                        hold_net_profit,
+                        Foxdie::FlashLoanSource::NONE,
+                        U256::ZERO,
+                        SwapRoute::default(),
+                    ),
+                    ExecutionMode::SwapAndRepay => (
+                        swap_net_profit,
+                        best_liquidity_provider.source,
+                        best_liquidity_provider.premium,
+                        swap_route,
+                    ),
+                };
+            let printable_net_profit = base_currency.format(net_profit);
             if net_profit > best_pair.as_ref().map_or(U256::ZERO, |p| p.net_profit)
-                && best_liquidity_provider.source != Foxdie::FlashLoanSource::NONE
+                && net_profit >= min_profit
             {
                 best_pair = Some(BestPair {
                     collateral_asset: supplied_reserve.underlyingAsset,
@@ -939,11 +1621,168 @@ pub async fn get_best_liquidation_opportunity(
                     printable_net_profit,
                     actual_collateral_to_liquidate,
                     actual_debt_to_liquidate,
+                    debt_to_liquidate_in_base_currency: actual_debt_to_liquidate * debt_asset_price
+                        / debt_asset_unit,
                     liquidation_protocol_fee_amount,
-                    flash_loan_source: best_liquidity_provider.source,
+                    flash_loan_source,
+                    flash_loan_premium,
+                    weth_price: native_asset_price,
+                    execution_mode,
+                    swap_route,
                 });
             }
         }
     }
     best_pair
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use overlord_shared::fixtures::ReservesFixture;
+
+    const UNIT_8: U256 = U256::from_limbs([100_000_000, 0, 0, 0]);
+    const UNIT_18: U256 = U256::from_limbs([1_000_000_000_000_000_000, 0, 0, 0]);
+
+    #[test]
+    fn checked_mul_div_fails_where_the_pool_reverts() {
+        let one = U256::from(1);
+        assert_eq!(
+            checked_mul_div(
+                [U256::from(6), U256::from(5), U256::from(4)],
+                [one, U256::from(3)]
+            ),
+            Ok(U256::from(40))
+        );
+        assert_eq!(
+            checked_mul_div([U256::MAX, U256::from(2), one], [one, one]),
+            Err(MathError::Overflow)
+        );
+        assert_eq!(
+            checked_mul_div([one, one, one], [U256::MAX, U256::from(2)]),
+            Err(MathError::Overflow)
+        );
+        assert_eq!(
+            checked_mul_div([one, one, one], [U256::ZERO, one]),
+            Err(MathError::DivisionByZero)
+        );
+    }
+
+    #[test]
+    fn liquidation_amounts_with_bonus_and_protocol_fee() {
+        // 1000 USDC (6 decimals, $1) repaid for WETH at $2000, 5% bonus, 10% of it to the protocol
+        let (collateral, debt, fee) = calculate_liquidation_amounts(
+            U256::from(2_000) * UNIT_8,
+            UNIT_18,
+            UNIT_8,
+            U256::from(1_000_000),
+            U256::from(1_000_000_000),
+            U256::from(10) * UNIT_18,
+            U256::from(10_500),
+            U256::from(1_000),
+        )
+        .unwrap();
+        assert_eq!(debt, U256::from(1_000_000_000));
+        // 0.5 WETH plus a 0.025 WETH bonus, 0.0025 of which goes to the protocol
+        assert_eq!(fee, U256::from(2_500_000_000_000_000u64));
+        assert_eq!(collateral, U256::from(522_500_000_000_000_000u64));
+    }
+
+    #[test]
+    fn liquidation_amounts_capped_by_the_collateral() {
+        // Only 0.21 WETH to take, so less debt is repaid
+        let (collateral, debt, fee) = calculate_liquidation_amounts(
+            U256::from(2_000) * UNIT_8,
+            UNIT_18,
+            UNIT_8,
+            U256::from(1_000_000),
+            U256::from(1_000_000_000),
+            U256::from(210_000_000_000_000_000u64),
+            U256::from(10_500),
+            U256::ZERO,
+        )
+        .unwrap();
+        assert_eq!(collateral, U256::from(210_000_000_000_000_000u64));
+        assert_eq!(debt, U256::from(400_000_000));
+        assert_eq!(fee, U256::ZERO);
+    }
+
+    #[test]
+    fn liquidation_amounts_overflow_is_an_error() {
+        let result = calculate_liquidation_amounts(
+            U256::from(2_000) * UNIT_8,
+            UNIT_18,
+            U256::MAX,
+            U256::from(1_000_000),
+            U256::from(2),
+            U256::from(10) * UNIT_18,
+            U256::from(10_500),
+            U256::ZERO,
+        );
+        assert_eq!(result, Err(MathError::Overflow));
+    }
+
+    /// Reserves data of the synthetic fixture (made up, not real state), and a reserves list that
+    /// matches it
+    fn fixture_reserves() -> (Vec<Address>, Vec<AggregatedReserveData>) {
+        let reserves_data = ReservesFixture::load("synthetic_core_37_reserves")
+            .unwrap()
+            .reserves_data()
+            .unwrap();
+        let reserves_list = reserves_data
+            .iter()
+            .map(|reserve| reserve.underlyingAsset)
+            .collect();
+        (reserves_list, reserves_data)
+    }
+
+    fn inconsistency(result: Result<(), ProfitoError>) -> String {
+        match result {
+            Err(ProfitoError::InconsistentState(message)) => message,
+            other => panic!("Expected an inconsistent state, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reserves_read_together_are_aligned() {
+        let (reserves_list, reserves_data) = fixture_reserves();
+        assert!(check_reserves_alignment(&reserves_list, &reserves_data).is_ok());
+    }
+
+    #[test]
+    fn reserve_listed_between_reads_is_misaligned() {
+        let (mut reserves_list, reserves_data) = fixture_reserves();
+        reserves_list.push(Address::repeat_byte(0x11));
+        let message = inconsistency(check_reserves_alignment(&reserves_list, &reserves_data));
+        assert!(
+            message.contains("changed between both reads"),
+            "{}",
+            message
+        );
+
+        // Dropped from the list, so the data has one more
+        reserves_list.truncate(reserves_data.len() - 1);
+        let message = inconsistency(check_reserves_alignment(&reserves_list, &reserves_data));
+        assert!(
+            message.contains("changed between both reads"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn reserves_in_another_order_are_misaligned() {
+        let (mut reserves_list, reserves_data) = fixture_reserves();
+        reserves_list.swap(3, 4);
+        let message = inconsistency(check_reserves_alignment(&reserves_list, &reserves_data));
+        assert!(message.starts_with("Reserve 3 is"), "{}", message);
+    }
+
+    #[test]
+    fn more_reserves_than_the_user_configuration_holds() {
+        let (_, reserves_data) = fixture_reserves();
+        let reserves_list = vec![Address::ZERO; MAX_RESERVES_COUNT + 1];
+        let message = inconsistency(check_reserves_alignment(&reserves_list, &reserves_data));
+        assert!(message.contains("more than the 128"), "{}", message);
+    }
+}
@@ -0,0 +1,118 @@
+use alloy::primitives::Address;
+use once_cell::sync::Lazy;
+use overlord_shared::{constants::PROFITO_COMPETITION_ENDPOINT, PoolActivity};
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{error, warn};
+
+// Activity older than this many blocks doesn't count
+const WINDOW_BLOCKS: u32 = 10;
+// Calls per block at which each term of the score is halfway
+const POOL_CALLS_PER_BLOCK_REFERENCE: f64 = 2.0;
+const ASSET_CALLS_PER_BLOCK_REFERENCE: f64 = 0.5;
+// Calls liquidating the same assets say more about an opportunity than calls to the pool at large
+const POOL_WEIGHT: f64 = 0.3;
+const ASSET_WEIGHT: f64 = 0.7;
+// Old activity is dropped when scoring, this bounds it when nothing is scored for a while
+const MAX_ACTIVITY: usize = 10_000;
+// Wait after a failed receive, doubled on each one in a row up to the max
+const MIN_RECV_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECV_BACKOFF: Duration = Duration::from_secs(10);
+
+pub static COMPETITION: Lazy<CompetitionTracker> = Lazy::new(CompetitionTracker::new);
+
+/// Keeps the pool activity oops-rs reports (pending txs and MEV-Share hints calling the pools) of
+/// the latest blocks, as a proxy of how many other searchers are after the same liquidations.
+/// When oops subscribes to the mempool filtered by sender, only the txs of the oracles' authorized
+/// senders reach it, so the score then comes from the hints alone.
+pub struct CompetitionTracker {
+    /// When each call was received, and the assets it liquidates if known, oldest first
+    activity: Mutex<VecDeque<(Instant, Vec<Address>)>>,
+}
+
+impl CompetitionTracker {
+    pub fn new() -> Self {
+        Self {
+            activity: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, activity: PoolActivity) {
+        let mut recorded = self.activity.lock().unwrap();
+        recorded.push_back((Instant::now(), activity.assets));
+        if recorded.len() > MAX_ACTIVITY {
+            recorded.pop_front();
+        }
+    }
+
+    /// From 0 (nobody else is calling the pools) towards 1, for liquidating `collateral_asset`
+    /// for `debt_asset`. Calls liquidating either of them weigh more than any other call to the
+    /// pools.
+    pub fn score(
+        &self,
+        collateral_asset: Address,
+        debt_asset: Address,
+        block_time: Duration,
+    ) -> f64 {
+        let window = block_time * WINDOW_BLOCKS;
+        let mut activity = self.activity.lock().unwrap();
+        while activity
+            .front()
+            .is_some_and(|(received_at, _)| received_at.elapsed() > window)
+        {
+            activity.pop_front();
+        }
+        let pool_calls = activity.len() as f64 / WINDOW_BLOCKS as f64;
+        let asset_calls = activity
+            .iter()
+            .filter(|(_, assets)| {
+                assets.contains(&collateral_asset) || assets.contains(&debt_asset)
+            })
+            .count() as f64
+            / WINDOW_BLOCKS as f64;
+        POOL_WEIGHT * pool_calls / (pool_calls + POOL_CALLS_PER_BLOCK_REFERENCE)
+            + ASSET_WEIGHT * asset_calls / (asset_calls + ASSET_CALLS_PER_BLOCK_REFERENCE)
+    }
+}
+
+impl Default for CompetitionTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Records the pool activity oops-rs reports into COMPETITION
+pub fn spawn_competition_listener(context: &zmq::Context) {
+    let socket = context.socket(zmq::PULL).unwrap();
+    if let Err(e) = socket.bind(PROFITO_COMPETITION_ENDPOINT) {
+        error!(
+            "Failed to bind {PROFITO_COMPETITION_ENDPOINT}, competition won't be estimated: {e}"
+        );
+        return;
+    }
+    tokio::task::spawn_blocking(move || {
+        let mut backoff = MIN_RECV_BACKOFF;
+        loop {
+            match socket.recv_bytes(0) {
+                Ok(bytes) => {
+                    backoff = MIN_RECV_BACKOFF;
+                    match bincode::deserialize::<PoolActivity>(&bytes) {
+                        Ok(activity) => COMPETITION.record(activity),
+                        Err(e) => warn!("Failed to deserialize pool activity: {e}"),
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to receive pool activity, retrying in {}ms: {e}",
+                        backoff.as_millis()
+                    );
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_RECV_BACKOFF);
+                }
+            }
+        }
+    });
+}
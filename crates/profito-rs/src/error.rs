@@ -0,0 +1,89 @@
+use alloy::primitives::Address;
+use overlord_shared::{error::NodeError, math::MathError};
+use thiserror::Error;
+
+use crate::revert::RevertReason;
+
+/// Why an underwater user couldn't be evaluated or liquidated. Node failures (which may go away
+/// if tried again) are told apart from responses that couldn't be decoded, state that doesn't
+/// add up and events dropped on purpose, so callers can react to each one.
+#[derive(Debug, Error)]
+pub enum ProfitoError {
+    /// A request to the node failed, see NodeError
+    #[error(transparent)]
+    Node(#[from] NodeError),
+    /// The price cache had no price for `asset`, and it couldn't be fetched
+    #[error("Error trying to get price for {asset}: {source}")]
+    Price {
+        asset: Address,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// What was read doesn't add up, e.g. reserves changed between two reads
+    #[error("Inconsistent state: {0}")]
+    InconsistentState(String),
+    /// The protocol would revert on these inputs
+    #[error(transparent)]
+    Math(#[from] MathError),
+    /// A setting is missing or invalid
+    #[error("{0}")]
+    Config(String),
+    /// The event was dropped on purpose (cancelled, past its deadline, kill switch, ...)
+    #[error("{0}")]
+    Rejected(String),
+    /// The bundle couldn't be signed or submitted
+    #[error("Error submitting bundle: {0}")]
+    Submission(String),
+    /// The bundle wasn't submitted because its simulation failed
+    #[error("Bundle simulation failed: {0}")]
+    Simulation(#[from] SimulationError),
+    /// Errors of overlord_shared, which aren't typed
+    #[error(transparent)]
+    Shared(#[from] Box<dyn std::error::Error>),
+}
+
+/// Why a bundle failed the simulation it goes through before being submitted
+#[derive(Debug, Error)]
+pub enum SimulationError {
+    /// The simulation couldn't be run, or its response couldn't be read
+    #[error("couldn't simulate the bundle: {0}")]
+    Request(String),
+    /// A tx of the bundle reverted
+    #[error("the bundle reverted at block {block}: {reason}")]
+    Reverted { block: u64, reason: String },
+    /// It'd leave less than the minimum profit once the bribe is paid
+    #[error(
+        "the bundle would pay a bribe of {bribe_wei} wei and keep {kept_wei} wei, under the minimum of {min_profit_wei} wei"
+    )]
+    Unprofitable {
+        bribe_wei: u128,
+        kept_wei: u128,
+        min_profit_wei: u128,
+    },
+}
+
+impl ProfitoError {
+    /// True for node failures that may go away if tried again, see NodeError::is_retryable()
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Node(e) if e.is_retryable())
+    }
+
+    /// Why the call or the bundle reverted, None if the error isn't a revert
+    pub fn revert_reason(&self) -> Option<RevertReason> {
+        match self {
+            Self::Node(NodeError::Rpc { source, .. }) => RevertReason::from_transport_error(source),
+            Self::Node(NodeError::Decode { source, .. }) => {
+                RevertReason::from_contract_error(source)
+            }
+            Self::Simulation(SimulationError::Reverted { reason, .. }) => {
+                Some(RevertReason::from_simulation_error(reason))
+            }
+            _ => None,
+        }
+    }
+
+    /// True if the event was dropped on purpose, which isn't a failure
+    pub fn is_rejection(&self) -> bool {
+        matches!(self, Self::Rejected(_))
+    }
+}
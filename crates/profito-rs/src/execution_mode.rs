@@ -0,0 +1,108 @@
+use alloy::{
+    primitives::{Address, U256},
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+    rpc::types::BlockId,
+};
+use once_cell::sync::Lazy;
+use overlord_shared::{market::AaveMarket, sol_bindings::ERC20};
+use std::{collections::HashSet, str::FromStr, sync::Arc};
+use tracing::{info, warn};
+
+use crate::utils::foxdie_address_env;
+
+/// Comma separated collateral assets worth holding. Liquidations of these may take the collateral
+/// as aTokens (receiveAToken), repaying the debt with Foxdie's own balance of the debt asset
+/// instead of a flash loan and a swap. Empty by default, which always swaps.
+pub const HOLD_COLLATERAL_ASSETS_ENV: &str = "PROFITO_HOLD_COLLATERAL_ASSETS";
+
+pub static HOLD_COLLATERAL: Lazy<HoldCollateral> = Lazy::new(HoldCollateral::from_env);
+
+/// How Foxdie executes a liquidation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExecutionMode {
+    /// Flash loans the debt, swaps the collateral for it and repays the loan
+    #[default]
+    SwapAndRepay,
+    /// Repays the debt with Foxdie's own balance and keeps the collateral as aTokens, so there's
+    /// no swap (nor its slippage) and no flash loan premium
+    ReceiveAToken,
+}
+
+/// The collateral assets Foxdie can keep, see HOLD_COLLATERAL_ASSETS_ENV
+pub struct HoldCollateral {
+    assets: HashSet<Address>,
+}
+
+impl HoldCollateral {
+    pub fn from_env() -> Self {
+        let assets: HashSet<Address> = std::env::var(HOLD_COLLATERAL_ASSETS_ENV)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|asset| !asset.is_empty())
+                    .filter_map(|asset| match Address::from_str(asset) {
+                        Ok(asset) => Some(asset),
+                        Err(e) => {
+                            warn!(
+                                "Ignoring invalid {} entry {}: {}",
+                                HOLD_COLLATERAL_ASSETS_ENV, asset, e
+                            );
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        if !assets.is_empty() {
+            info!(
+                "Collateral of {} assets may be kept as aTokens instead of swapped",
+                assets.len()
+            );
+        }
+        Self { assets }
+    }
+
+    /// True if `collateral_asset` can be kept and Foxdie holds `debt_amount` of `debt_asset` at
+    /// `block` to repay the debt with
+    pub async fn can_hold(
+        &self,
+        provider: Arc<RootProvider<PubSubFrontend>>,
+        market: &AaveMarket,
+        collateral_asset: Address,
+        debt_asset: Address,
+        debt_amount: U256,
+        block: BlockId,
+    ) -> bool {
+        if !self.assets.contains(&collateral_asset) {
+            return false;
+        }
+        let foxdie_address_env = foxdie_address_env(market);
+        let foxdie = match std::env::var(&foxdie_address_env)
+            .ok()
+            .and_then(|address| Address::from_str(&address).ok())
+        {
+            Some(foxdie) => foxdie,
+            None => {
+                warn!(
+                    "Couldn't read {}, collateral won't be kept",
+                    foxdie_address_env
+                );
+                return false;
+            }
+        };
+        match ERC20::new(debt_asset, provider)
+            .balanceOf(foxdie)
+            .block(block)
+            .call()
+            .await
+        {
+            Ok(balance_of_response) => balance_of_response.balance >= debt_amount,
+            Err(e) => {
+                warn!("Error trying to call balanceOf for {}: {}", debt_asset, e);
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use once_cell::sync::Lazy;
+use std::{collections::BTreeMap, sync::Mutex};
+use tracing::{info, warn};
+
+/// Most debt (in USD) the bundles aimed at a single block can repay between them
+pub const MAX_EXPOSURE_PER_BLOCK_USD_ENV: &str = "PROFITO_MAX_EXPOSURE_PER_BLOCK_USD";
+// Bundles commit to each block they're (re)submitted for right before submitting, so nothing
+// commits to a block once it's built. Blocks this far behind the one being committed to are
+// forgotten, releasing them afterwards is a no-op.
+const BLOCKS_REMEMBERED: u64 = 16;
+
+pub static EXPOSURE_GUARD: Lazy<ExposureGuard> = Lazy::new(ExposureGuard::from_env);
+
+/// Keeps the flash loan and swap exposure committed to a single block within the operator's risk
+/// policy. Every bundle commits the debt it repays to each block it's submitted for (its
+/// inclusion block, then every block it's resubmitted for), and it isn't submitted for blocks it
+/// would take over the ceiling. Without a ceiling, every bundle fits.
+pub struct ExposureGuard {
+    ceiling_usd: Option<f64>,
+    committed_usd: Mutex<BTreeMap<u64, f64>>,
+}
+
+impl ExposureGuard {
+    pub fn from_env() -> Self {
+        let ceiling_usd = match std::env::var(MAX_EXPOSURE_PER_BLOCK_USD_ENV) {
+            Ok(value) => match value.parse::<f64>() {
+                Ok(ceiling) if ceiling > 0.0 => Some(ceiling),
+                Ok(_) | Err(_) => {
+                    warn!(
+                        "Invalid {} value {}, exposure per block won't be limited",
+                        MAX_EXPOSURE_PER_BLOCK_USD_ENV, value
+                    );
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+        if let Some(ceiling) = ceiling_usd {
+            info!("Debt repaid per block is limited to ${:.2}", ceiling);
+        }
+        Self {
+            ceiling_usd,
+            committed_usd: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Commits `debt_usd` to `block`, or returns how much of the ceiling was already committed
+    /// if it doesn't fit
+    pub fn try_commit(&self, block: u64, debt_usd: f64) -> Result<(), f64> {
+        let Some(ceiling) = self.ceiling_usd else {
+            return Ok(());
+        };
+        let mut committed_usd = self.committed_usd.lock().unwrap();
+        let committed = committed_usd.entry(block).or_insert(0.0);
+        if *committed + debt_usd > ceiling {
+            return Err(*committed);
+        }
+        *committed += debt_usd;
+        committed_usd.retain(|committed_block, _| committed_block + BLOCKS_REMEMBERED > block);
+        Ok(())
+    }
+
+    /// Gives back what a bundle committed, for bundles that couldn't be submitted after all
+    pub fn release(&self, block: u64, debt_usd: f64) {
+        if self.ceiling_usd.is_none() {
+            return;
+        }
+        if let Some(committed) = self.committed_usd.lock().unwrap().get_mut(&block) {
+            *committed = (*committed - debt_usd).max(0.0);
+        }
+    }
+
+    pub fn ceiling_usd(&self) -> Option<f64> {
+        self.ceiling_usd
+    }
+}
@@ -0,0 +1,168 @@
+use alloy::{
+    primitives::{Address, Bytes, U256},
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::{
+        state::{AccountOverride, StateOverride},
+        BlockId, TransactionInput, TransactionRequest,
+    },
+};
+use ethers_core::types::transaction::eip2718::TypedTransaction;
+use once_cell::sync::Lazy;
+use overlord_shared::{error::NodeError, market::AaveMarket, sol_bindings::AaveOracle};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tracing::{info, warn};
+
+use crate::error::ProfitoError;
+
+/// Multiplier (in basis points) applied to gas estimates, so a liquidation that takes a slightly
+/// different path than the one simulated isn't priced short. 12000 (1.2x) by default.
+pub const GAS_SAFETY_MULTIPLIER_BPS_ENV: &str = "PROFITO_GAS_SAFETY_MULTIPLIER_BPS";
+const DEFAULT_GAS_SAFETY_MULTIPLIER_BPS: u64 = 12_000;
+/// Gas assumed for pairs that weren't simulated yet. Foxdie's flash loan, liquidationCall() and
+/// two swaps rarely use more.
+pub const DEFAULT_LIQUIDATION_GAS: u64 = 700_000;
+const BPS: u64 = 10_000;
+
+pub static GAS_ESTIMATES: Lazy<GasEstimates> = Lazy::new(GasEstimates::from_env);
+
+/// Gas used by triggerLiquidation() for each (collateral, debt) pair, as last simulated with
+/// eth_estimateGas. A pair takes the same path through Foxdie whoever is liquidated, so the
+/// estimate of the last bundle of a pair prices the next evaluations of it.
+pub struct GasEstimates {
+    safety_multiplier_bps: u64,
+    by_pair: Mutex<HashMap<(Address, Address), u64>>,
+}
+
+impl GasEstimates {
+    pub fn from_env() -> Self {
+        let safety_multiplier_bps = match std::env::var(GAS_SAFETY_MULTIPLIER_BPS_ENV) {
+            Ok(value) => match value.parse::<u64>() {
+                Ok(multiplier) if multiplier >= BPS => multiplier,
+                Ok(_) | Err(_) => {
+                    warn!(
+                        "Invalid {} value {} (it can't be below {}), using {}",
+                        GAS_SAFETY_MULTIPLIER_BPS_ENV,
+                        value,
+                        BPS,
+                        DEFAULT_GAS_SAFETY_MULTIPLIER_BPS
+                    );
+                    DEFAULT_GAS_SAFETY_MULTIPLIER_BPS
+                }
+            },
+            Err(_) => DEFAULT_GAS_SAFETY_MULTIPLIER_BPS,
+        };
+        Self {
+            safety_multiplier_bps,
+            by_pair: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gas to budget for liquidating `debt_asset` for `collateral_asset`: its last estimate (or
+    /// DEFAULT_LIQUIDATION_GAS if it wasn't simulated yet), with the safety multiplier applied
+    pub fn gas_limit(&self, collateral_asset: Address, debt_asset: Address) -> u64 {
+        let gas_used = self
+            .by_pair
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&(collateral_asset, debt_asset))
+            .copied()
+            .unwrap_or(DEFAULT_LIQUIDATION_GAS);
+        gas_used.saturating_mul(self.safety_multiplier_bps) / BPS
+    }
+
+    /// Simulates `tx` (a triggerLiquidation() call of the pair) with eth_estimateGas on top of
+    /// `block`, and keeps the estimate for later evaluations of the pair. The price sources of
+    /// the assets in `new_prices` are replaced by contracts returning those prices, so the
+    /// liquidation runs as it will right after the price update it backruns.
+    pub async fn simulate(
+        &self,
+        provider: Arc<RootProvider<PubSubFrontend>>,
+        market: &AaveMarket,
+        tx: &TypedTransaction,
+        collateral_asset: Address,
+        debt_asset: Address,
+        new_prices: &[(Address, String, U256)],
+        block: BlockId,
+    ) -> Result<u64, ProfitoError> {
+        let oracle = AaveOracle::new(market.oracle, provider.clone());
+        let mut overrides = StateOverride::default();
+        for (asset, _, price) in new_prices {
+            let source = match oracle.getSourceOfAsset(*asset).block(block).call().await {
+                Ok(response) => response._0,
+                Err(e) => return Err(NodeError::call("getSourceOfAsset", e).into()),
+            };
+            overrides.insert(
+                source,
+                AccountOverride {
+                    code: Some(constant_answer_code(*price)),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let mut request = TransactionRequest::default().input(TransactionInput::new(
+            Bytes::copy_from_slice(tx.data().map_or(&[][..], |data| data.as_ref())),
+        ));
+        if let Some(from) = tx.from() {
+            request = request.from(Address::from_slice(from.as_bytes()));
+        }
+        if let Some(to) = tx.to_addr() {
+            request = request.to(Address::from_slice(to.as_bytes()));
+        }
+        let gas_used = match provider
+            .estimate_gas(&request)
+            .block(block)
+            .overrides(&overrides)
+            .await
+        {
+            Ok(gas_used) => u64::try_from(gas_used).unwrap_or(u64::MAX),
+            // A revert means the bundle would revert too
+            Err(e) => return Err(NodeError::rpc("eth_estimateGas", e).into()),
+        };
+        let previous = self
+            .by_pair
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert((collateral_asset, debt_asset), gas_used);
+        info!(
+            "triggerLiquidation() of {}/{} estimated at {} gas (previously {})",
+            collateral_asset,
+            debt_asset,
+            gas_used,
+            previous.unwrap_or(DEFAULT_LIQUIDATION_GAS)
+        );
+        Ok(gas_used)
+    }
+}
+
+/// Code of a contract that returns `answer` to any call. AaveOracle only calls latestAnswer() on
+/// price sources.
+fn constant_answer_code(answer: U256) -> Bytes {
+    let mut code = Vec::with_capacity(41);
+    // PUSH32 answer
+    code.push(0x7f);
+    code.extend_from_slice(&answer.to_be_bytes::<32>());
+    // PUSH1 0, MSTORE, PUSH1 32, PUSH1 0, RETURN
+    code.extend_from_slice(&[0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3]);
+    Bytes::from(code)
+}
+
+/// `amount` of wei in units of an asset priced at `asset_price` (both prices in base currency)
+pub fn wei_to_asset_units(
+    amount: U256,
+    native_asset_price: U256,
+    asset_price: U256,
+    asset_unit: U256,
+) -> U256 {
+    if asset_price == U256::ZERO {
+        return U256::ZERO;
+    }
+    amount
+        .saturating_mul(native_asset_price)
+        .saturating_mul(asset_unit)
+        / (U256::from(10).pow(U256::from(18)) * asset_price)
+}
@@ -0,0 +1,201 @@
+use alloy::{
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::{BlockId, BlockTransactionsKind},
+};
+use once_cell::sync::Lazy;
+use overlord_shared::{chain_profile::ChainProfile, status::Gauge};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{error, info, warn};
+
+/// Most seconds the node's head can be behind before submissions are paused. Defaults to
+/// DEFAULT_MAX_HEAD_LAG_BLOCKS block times.
+pub const MAX_HEAD_LAG_SECS_ENV: &str = "PROFITO_MAX_HEAD_LAG_SECS";
+/// Beacon node API (e.g. http://localhost:5052) whose head the node's is compared against, on
+/// chains with slots. Without it, the head is only compared against the wall clock.
+pub const BEACON_API_URL_ENV: &str = "PROFITO_BEACON_API_URL";
+
+// A couple of missed slots in a row are normal, a stalled node falls further behind
+const DEFAULT_MAX_HEAD_LAG_BLOCKS: u32 = 4;
+// Block timestamps are in seconds, so fast chains get some slack
+const MIN_DEFAULT_MAX_HEAD_LAG: Duration = Duration::from_secs(10);
+const BEACON_API_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub static HEAD_LAG: Lazy<HeadLagMonitor> = Lazy::new(HeadLagMonitor::new);
+
+/// Seconds the node's head was behind at the last check, reported by the status line
+pub static HEAD_LAG_SECS: Gauge = Gauge::new();
+
+/// Pauses submissions while the node's head is behind, since every price and health factor read
+/// from it is stale then. The head block's timestamp is compared against the wall clock and,
+/// if BEACON_API_URL_ENV is set, against the slot of the consensus layer's head. A node that
+/// stops returning its head is taken to be stuck at the last one it returned. Submissions resume
+/// on their own once the node catches up.
+pub struct HeadLagMonitor {
+    paused: AtomicBool,
+    lag_secs: AtomicU64,
+}
+
+impl HeadLagMonitor {
+    pub fn new() -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            lag_secs: AtomicU64::new(0),
+        }
+    }
+
+    /// Seconds the node's head is behind, if that's enough to pause submissions
+    pub fn paused_lag_secs(&self) -> Option<u64> {
+        self.paused
+            .load(Ordering::Relaxed)
+            .then(|| self.lag_secs.load(Ordering::Relaxed))
+    }
+
+    /// Spawns the task that checks the node's head once per block time
+    pub fn spawn(&'static self, provider: Arc<RootProvider<PubSubFrontend>>, chain: ChainProfile) {
+        let max_lag = max_head_lag(&chain);
+        let beacon_api_url = std::env::var(BEACON_API_URL_ENV)
+            .ok()
+            .filter(|_| chain.slot_timestamp(0).is_some());
+        let compared_against = match beacon_api_url.as_ref() {
+            Some(url) => format!("the wall clock or the beacon head of {}", url),
+            None => "the wall clock".to_string(),
+        };
+        info!(
+            "Submissions pause while the node's head is more than {}s behind {}",
+            max_lag.as_secs(),
+            compared_against
+        );
+        let client = reqwest::Client::builder()
+            .timeout(BEACON_API_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        tokio::spawn(async move {
+            let mut ticker = interval(chain.block_time().max(Duration::from_secs(1)));
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // Until a head is read, the node counts as current as of startup
+            let mut last_head_timestamp = unix_now();
+            loop {
+                ticker.tick().await;
+                let head_timestamp = match provider
+                    .get_block(BlockId::latest(), BlockTransactionsKind::Hashes)
+                    .await
+                {
+                    Ok(Some(head)) => head.header.timestamp,
+                    Ok(None) => {
+                        warn!("Node returned no latest block to check its lag with");
+                        self.update(
+                            unix_now().saturating_sub(last_head_timestamp),
+                            max_lag.as_secs(),
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Couldn't get the latest block to check the node's lag: {}",
+                            e
+                        );
+                        self.update(
+                            unix_now().saturating_sub(last_head_timestamp),
+                            max_lag.as_secs(),
+                        );
+                        continue;
+                    }
+                };
+                last_head_timestamp = head_timestamp;
+                let mut lag = unix_now().saturating_sub(head_timestamp);
+                if let Some(url) = beacon_api_url.as_ref() {
+                    match get_beacon_head_slot(&client, url).await {
+                        Ok(slot) => {
+                            if let Some(slot_timestamp) = chain.slot_timestamp(slot) {
+                                lag = lag.max(slot_timestamp.saturating_sub(head_timestamp));
+                            }
+                        }
+                        Err(e) => warn!("Couldn't get the beacon head from {}: {}", url, e),
+                    }
+                }
+                self.update(lag, max_lag.as_secs());
+            }
+        });
+    }
+
+    fn update(&self, lag: u64, max_lag: u64) {
+        HEAD_LAG_SECS.set(lag);
+        self.lag_secs.store(lag, Ordering::Relaxed);
+        let behind = lag > max_lag;
+        let was_paused = self.paused.swap(behind, Ordering::Relaxed);
+        if behind && !was_paused {
+            error!(
+                "ALERT | node head is {}s behind (tolerance {}s), pausing submissions",
+                lag, max_lag
+            );
+        } else if !behind && was_paused {
+            info!(
+                "Node head caught up ({}s behind), resuming submissions",
+                lag
+            );
+        }
+    }
+}
+
+impl Default for HeadLagMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+fn max_head_lag(chain: &ChainProfile) -> Duration {
+    let default = (chain.block_time() * DEFAULT_MAX_HEAD_LAG_BLOCKS).max(MIN_DEFAULT_MAX_HEAD_LAG);
+    match std::env::var(MAX_HEAD_LAG_SECS_ENV) {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            Ok(_) | Err(_) => {
+                warn!(
+                    "Invalid {} value {}, using {}s",
+                    MAX_HEAD_LAG_SECS_ENV,
+                    value,
+                    default.as_secs()
+                );
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// Slot of the head the beacon node at `url` follows
+async fn get_beacon_head_slot(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let response: serde_json::Value = client
+        .get(format!(
+            "{}/eth/v1/beacon/headers/head",
+            url.trim_end_matches('/')
+        ))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    // Beacon APIs return numbers as strings
+    let slot = response
+        .pointer("/data/header/message/slot")
+        .and_then(|slot| slot.as_str())
+        .ok_or("Response has no head slot")?
+        .parse()?;
+    Ok(slot)
+}
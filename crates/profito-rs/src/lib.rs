@@ -1,4 +1,20 @@
+pub mod bribe;
+pub mod builders;
 pub mod cache;
 pub mod calculations;
+pub mod competition;
+pub mod error;
+pub mod execution_mode;
+pub mod exposure;
+pub mod gas;
+pub mod head_lag;
+pub mod liquidation_params;
 pub mod mev_share_service;
+pub mod profit_policy;
+pub mod relay_budget;
+pub mod resubmission;
+pub mod revert;
+pub mod simulation;
+pub mod snapshot;
+pub mod swap_routes;
 pub mod utils;
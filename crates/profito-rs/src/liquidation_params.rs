@@ -0,0 +1,182 @@
+use alloy::{
+    primitives::{Address, U256},
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+};
+use once_cell::sync::Lazy;
+use overlord_shared::{
+    market::AaveMarket,
+    sol_bindings::{ILiquidationLogic, IPoolConfigurator},
+};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{task::JoinHandle, time::interval};
+use tracing::{info, warn};
+
+/// Address of the LiquidationLogic library the pools are linked against. Without it, the close
+/// factor params keep their fallback values (see LiquidationParams::FALLBACK).
+pub const LIQUIDATION_LOGIC_ADDRESS_ENV: &str = "PROFITO_LIQUIDATION_LOGIC_ADDRESS";
+// These only change with a protocol upgrade, which goes through governance
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The params in use by pool, refreshed by spawn_liquidation_params_refresh()
+pub static LIQUIDATION_PARAMS: Lazy<RwLock<HashMap<Address, LiquidationParams>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// How much of a position the pool lets a liquidation repay, and how long reserves can stay in a
+/// liquidation grace period. See
+/// https://github.com/aave-dao/aave-v3-origin/blob/e8f6699e58038cbe3aba982557ceb2b0dda303a0/src/contracts/protocol/libraries/logic/LiquidationLogic.sol#L40-L64
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LiquidationParams {
+    /// Positions whose collateral or debt (in base currency) is below this can be liquidated whole
+    pub min_base_max_close_factor_threshold: U256,
+    /// Positions whose HF is below this can be liquidated whole
+    pub close_factor_hf_threshold: U256,
+    /// Share of the total debt (in basis points) that can be liquidated otherwise
+    pub default_liquidation_close_factor: U256,
+    /// Longest grace period (in seconds) the pool configurator can give a reserve. 0 means
+    /// reserves can't be in one, so their grace periods aren't read.
+    pub max_grace_period: u64,
+}
+
+impl LiquidationParams {
+    /// Values of Aave v3.3, for whatever can't be read
+    pub const FALLBACK: Self = Self {
+        // 2000e8
+        min_base_max_close_factor_threshold: U256::from_limbs([200_000_000_000, 0, 0, 0]),
+        // 0.95e18
+        close_factor_hf_threshold: U256::from_limbs([950_000_000_000_000_000, 0, 0, 0]),
+        // 0.5e4
+        default_liquidation_close_factor: U256::from_limbs([5_000, 0, 0, 0]),
+        max_grace_period: 4 * 60 * 60,
+    };
+
+    /// Least collateral and debt (in base currency) a partial liquidation can leave behind,
+    /// MIN_LEFTOVER_BASE in LiquidationLogic
+    pub fn min_leftover_base(&self) -> U256 {
+        self.min_base_max_close_factor_threshold / U256::from(2)
+    }
+
+    /// Params of `market`, FALLBACK if they haven't been read
+    pub fn current(market: &AaveMarket) -> Self {
+        LIQUIDATION_PARAMS
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&market.pool)
+            .copied()
+            .unwrap_or(Self::FALLBACK)
+    }
+
+    /// Reads the params from `liquidation_logic` and the pool configurator of `market`. Each one
+    /// that can't be read keeps its value in `fallback`.
+    pub async fn read(
+        provider: Arc<RootProvider<PubSubFrontend>>,
+        market: &AaveMarket,
+        liquidation_logic: Option<Address>,
+        fallback: Self,
+    ) -> Self {
+        let mut params = fallback;
+        let mut unread = vec![];
+        if let Some(liquidation_logic) = liquidation_logic {
+            let liquidation_logic = ILiquidationLogic::new(liquidation_logic, provider.clone());
+            match liquidation_logic
+                .MIN_BASE_MAX_CLOSE_FACTOR_THRESHOLD()
+                .call()
+                .await
+            {
+                Ok(response) => params.min_base_max_close_factor_threshold = response._0,
+                Err(e) => unread.push(format!("MIN_BASE_MAX_CLOSE_FACTOR_THRESHOLD ({})", e)),
+            }
+            match liquidation_logic.CLOSE_FACTOR_HF_THRESHOLD().call().await {
+                Ok(response) => params.close_factor_hf_threshold = response._0,
+                Err(e) => unread.push(format!("CLOSE_FACTOR_HF_THRESHOLD ({})", e)),
+            }
+            // Internal in some versions, in which case the call reverts
+            match liquidation_logic
+                .DEFAULT_LIQUIDATION_CLOSE_FACTOR()
+                .call()
+                .await
+            {
+                Ok(response) => params.default_liquidation_close_factor = response._0,
+                Err(e) => unread.push(format!("DEFAULT_LIQUIDATION_CLOSE_FACTOR ({})", e)),
+            }
+        }
+        match IPoolConfigurator::new(market.pool_configurator, provider)
+            .MAX_GRACE_PERIOD()
+            .call()
+            .await
+        {
+            Ok(response) => params.max_grace_period = response._0,
+            Err(e) => unread.push(format!("MAX_GRACE_PERIOD ({})", e)),
+        }
+        if !unread.is_empty() {
+            warn!(
+                "Couldn't read liquidation params of the {} market, keeping previous values: {}",
+                market.name,
+                unread.join(", ")
+            );
+        }
+        params
+    }
+}
+
+/// Reads the liquidation params of `market` right away, and again every REFRESH_INTERVAL so a
+/// protocol upgrade is picked up without a restart
+pub async fn spawn_liquidation_params_refresh(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    market: AaveMarket,
+) -> JoinHandle<()> {
+    let liquidation_logic = match std::env::var(LIQUIDATION_LOGIC_ADDRESS_ENV) {
+        Ok(value) => match Address::from_str(&value) {
+            Ok(address) => Some(address),
+            Err(e) => {
+                warn!(
+                    "Invalid {} value {}, close factor params won't be read: {}",
+                    LIQUIDATION_LOGIC_ADDRESS_ENV, value, e
+                );
+                None
+            }
+        },
+        Err(_) => {
+            info!(
+                "{} not set, close factor params won't be read",
+                LIQUIDATION_LOGIC_ADDRESS_ENV
+            );
+            None
+        }
+    };
+    refresh(&provider, &market, liquidation_logic).await;
+    tokio::spawn(async move {
+        let mut ticker = interval(REFRESH_INTERVAL);
+        // The first tick completes immediately, and the params were just read
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            refresh(&provider, &market, liquidation_logic).await;
+        }
+    })
+}
+
+async fn refresh(
+    provider: &Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
+    liquidation_logic: Option<Address>,
+) {
+    let current = LiquidationParams::current(market);
+    let params =
+        LiquidationParams::read(provider.clone(), market, liquidation_logic, current).await;
+    if params != current {
+        info!(
+            ?params,
+            "Liquidation params of the {} market updated", market.name
+        );
+        LIQUIDATION_PARAMS
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(market.pool, params);
+    }
+}
@@ -1,28 +1,408 @@
+mod bribe;
+mod builders;
 mod cache;
 mod calculations;
+mod competition;
+mod error;
+mod execution_mode;
+mod exposure;
+mod gas;
+mod head_lag;
+mod liquidation_params;
 mod mev_share_service;
+mod profit_policy;
+mod relay_budget;
+mod resubmission;
+mod revert;
+mod simulation;
+mod snapshot;
+mod swap_routes;
 mod utils;
 
-use alloy::{providers::RootProvider, pubsub::PubSubFrontend};
+use alloy::{
+    primitives::{Address, U256},
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::{BlockId, BlockTransactionsKind},
+};
+use bribe::BRIBE_POLICY;
 use cache::{PriceCache, ProviderCache};
 use calculations::{
     calculate_best_swap_fees, calculate_bribe, calculate_user_account_data,
-    get_best_liquidation_opportunity, get_reserves_list,
+    get_best_liquidation_opportunity, get_reserves_list, recheck_liquidity_provider, BestPair,
 };
-use mev_share_service::MevShareService;
+use competition::{spawn_competition_listener, COMPETITION};
+use error::ProfitoError;
+use execution_mode::{ExecutionMode, HOLD_COLLATERAL};
+use exposure::EXPOSURE_GUARD;
+use gas::GAS_ESTIMATES;
+use head_lag::{HEAD_LAG, HEAD_LAG_SECS};
+use liquidation_params::spawn_liquidation_params_refresh;
+use mev_share_service::{MevShareService, SubmittedBundle};
+use once_cell::sync::Lazy;
 use overlord_shared::{
-    common::get_reserves_data,
-    constants::{AAVE_ORACLE_ADDRESS, PROFITO_INBOUND_ENDPOINT},
-    sol_bindings::AaveOracle,
-    UnderwaterUserEvent,
+    chain_profile::ChainProfile,
+    common::{get_base_currency, get_reserves_data_at, get_user_emode_category_at, BaseCurrency},
+    constants::{PROFITO_CANCEL_ENDPOINT, PROFITO_INBOUND_ENDPOINT, VEGA_ACK_ENDPOINT},
+    error::NodeError,
+    kill_switch::KILL_SWITCH,
+    market::{resolve_markets_from_env, AaveMarket},
+    retry::RetryPolicy,
+    sol_bindings::{AaveOracle, Foxdie},
+    status::{Counter, StatusReporter},
+    PriceUpdateCancellation, PriceUpdateTx, UnderwaterUserAck, UnderwaterUserEvent,
+};
+use profit_policy::PROFIT_POLICY;
+use resubmission::invalidation;
+use revert::{record_revert, spawn_revert_summary};
+use simulation::ProfitCheck;
+use snapshot::{
+    capture_simulation_snapshot, health_factor_difference_bps,
+    health_factors_disagree_on_liquidation, health_factors_diverge, hf_divergence_tolerance_bps,
+    write_simulation_snapshot,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
 };
-use std::sync::Arc;
+use swap_routes::quote_uniswap_v3_through_weth;
 use tokio::sync::Mutex;
 use tracing::{error, info, warn};
 use tracing_appender::rolling::{self, Rotation};
 use tracing_subscriber::fmt::{time::LocalTime, writer::BoxMakeWriter};
 use utils::{create_trigger_liquidation_tx, get_user_reserves_data};
 
+// Reported by the periodic status line
+static UW_EVENTS_RECEIVED: Counter = Counter::new();
+static BUNDLES_SUBMITTED: Counter = Counter::new();
+static EVALUATIONS_ABORTED: Counter = Counter::new();
+static HF_DIVERGENCES: Counter = Counter::new();
+static EVALUATIONS_CANCELLED: Counter = Counter::new();
+static EVALUATIONS_PAST_DEADLINE: Counter = Counter::new();
+static BUNDLES_OVER_EXPOSURE_LIMIT: Counter = Counter::new();
+static REHEARSALS_EVALUATED: Counter = Counter::new();
+static FLASH_LOAN_SOURCE_FALLBACKS: Counter = Counter::new();
+static BUNDLES_INCLUDED: Counter = Counter::new();
+static BUNDLES_PAUSED_BY_HEAD_LAG: Counter = Counter::new();
+static BUNDLES_FAILED_SIMULATION: Counter = Counter::new();
+static BUNDLES_FAILED_GAS_ESTIMATE: Counter = Counter::new();
+static BUNDLES_RESUBMITTED: Counter = Counter::new();
+static BUNDLES_INVALIDATED: Counter = Counter::new();
+
+// How often the most frequent revert reasons are logged
+const REVERT_SUMMARY_INTERVAL: Duration = Duration::from_secs(600);
+
+// Evaluations are redone on the new head if it moves while they run, but only this many times
+const MAX_EVALUATION_ATTEMPTS: usize = 3;
+
+// vega retransmits events it didn't get an ack for, which can mean the ack was the one lost. This
+// many of the latest events are remembered so those aren't processed twice.
+const RECENT_UW_EVENTS_SIZE: usize = 1024;
+
+// Price updates are only cancelled while pending, so a few of the latest cancellations are enough
+const CANCELLED_UPDATES_SIZE: usize = 256;
+
+/// Trace ids of the latest price updates oops-rs cancelled, oldest first
+static CANCELLED_UPDATES: Lazy<std::sync::Mutex<VecDeque<String>>> =
+    Lazy::new(|| std::sync::Mutex::new(VecDeque::new()));
+
+/// Whether the slot deadline oops tagged the event with already passed, i.e. a bundle can no
+/// longer reach the builders in time for the event's inclusion block
+fn is_past_deadline(uw_event: &UnderwaterUserEvent) -> bool {
+    let Some(deadline_ms) = uw_event.slot_deadline_ms else {
+        return false;
+    };
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(false, |now| now.as_millis() as u64 > deadline_ms)
+}
+
+/// The minimum profit of PROFIT_POLICY in base units. Without it any profit would pass, so the
+/// event is rejected if it can't be converted (e.g. without a WETH price).
+fn min_profit_in_base_units(
+    base_currency: &BaseCurrency,
+    weth_price: U256,
+    trace_id: &str,
+) -> Result<U256, ProfitoError> {
+    PROFIT_POLICY
+        .min_profit_in_base_units(base_currency, weth_price)
+        .ok_or_else(|| {
+            ProfitoError::Rejected(format!(
+                "Not submitting bundle for {}: the minimum profit can't be converted to base units",
+                trace_id
+            ))
+        })
+}
+
+/// Follows `submitted` block by block until it lands or can't land anymore. Every block it
+/// misses, it's resubmitted for the next one (up to its last block) as long as it's still valid:
+/// the price update of `trace_id` wasn't cancelled, and the position of `user` in `market` is
+/// still there to liquidate (see invalidation()). Once it's done, records whether it landed,
+/// which the dynamic bribe strategy bids on, and which builder built the block it landed in.
+fn spawn_bundle_tracker(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    mev_share_client: Arc<MevShareService>,
+    submitted: SubmittedBundle,
+    chain_profile: ChainProfile,
+    trace_id: String,
+    user: Address,
+    market: AaveMarket,
+) {
+    tokio::spawn(async move {
+        let mut submitted = submitted;
+        loop {
+            tokio::time::sleep(chain_profile.block_time()).await;
+            let head = match provider.get_block_number().await {
+                Ok(head) => head,
+                Err(e) => {
+                    warn!(
+                        "Couldn't get the head to check bundle {:?}: {}",
+                        submitted.relay_response, e
+                    );
+                    continue;
+                }
+            };
+            // The block the bundle targets isn't built yet
+            if head < submitted.block {
+                continue;
+            }
+            match provider.get_transaction_receipt(submitted.tx_hash).await {
+                Ok(Some(_)) => break,
+                Ok(None) => (),
+                Err(e) => {
+                    warn!(
+                        "Couldn't get the receipt of {} to check whether its bundle landed: {}",
+                        submitted.tx_hash, e
+                    );
+                    continue;
+                }
+            }
+            let next_block = head + 1;
+            if next_block > submitted.last_block || KILL_SWITCH.is_market_blocked(&market) {
+                break;
+            }
+            if let Some(lag) = HEAD_LAG.paused_lag_secs() {
+                info!(
+                    "Not resubmitting bundle for {} for block {}: node head is {}s behind",
+                    trace_id, next_block, lag
+                );
+                continue;
+            }
+            if let Some(asset) = KILL_SWITCH.first_blocked(submitted.liquidated_assets) {
+                info!(
+                    "Not resubmitting bundle for {} for block {}: asset {} is in the kill switch list",
+                    trace_id, next_block, asset
+                );
+                break;
+            }
+            let invalidated = if is_cancelled(&trace_id) {
+                Some("its price update was cancelled".to_string())
+            } else {
+                match invalidation(&provider, &submitted, &market, user).await {
+                    Ok(invalidated) => invalidated,
+                    Err(e) => {
+                        warn!(
+                            "Couldn't check whether the bundle for {} is still valid: {}",
+                            trace_id, e
+                        );
+                        continue;
+                    }
+                }
+            };
+            if let Some(reason) = invalidated {
+                BUNDLES_INVALIDATED.inc();
+                info!(
+                    "Bundle for {} missed block {}, not resubmitting it: {}",
+                    trace_id, submitted.block, reason
+                );
+                break;
+            }
+            // Every block it's submitted for draws on that block's liquidity like any other bundle
+            if let Err(committed_usd) =
+                EXPOSURE_GUARD.try_commit(next_block, submitted.debt_to_liquidate_usd)
+            {
+                BUNDLES_OVER_EXPOSURE_LIMIT.inc();
+                info!(
+                    "Not resubmitting bundle for {} for block {}: repaying ~${:.2} would take it over the ${:.2} limit (${:.2} already committed)",
+                    trace_id,
+                    next_block,
+                    submitted.debt_to_liquidate_usd,
+                    EXPOSURE_GUARD.ceiling_usd().unwrap_or_default(),
+                    committed_usd
+                );
+                continue;
+            }
+            match mev_share_client
+                .resubmit(&submitted, next_block, &chain_profile)
+                .await
+            {
+                Ok(resubmitted) => {
+                    BUNDLES_RESUBMITTED.inc();
+                    info!(
+                        "Bundle for {} missed block {}, resubmitted for block {}. Response: {:?}, accepted by builders {:?}",
+                        trace_id,
+                        submitted.block,
+                        next_block,
+                        resubmitted.relay_response,
+                        resubmitted.builders
+                    );
+                    submitted = resubmitted;
+                }
+                // It would revert (or make too little) at the next block, which won't change
+                Err(e @ ProfitoError::Simulation(_)) => {
+                    EXPOSURE_GUARD.release(next_block, submitted.debt_to_liquidate_usd);
+                    if let Some(reason) = e.revert_reason() {
+                        record_revert(&trace_id, &reason);
+                    }
+                    BUNDLES_INVALIDATED.inc();
+                    info!(
+                        "Bundle for {} missed block {}, not resubmitting it: {}",
+                        trace_id, submitted.block, e
+                    );
+                    break;
+                }
+                Err(e) => {
+                    EXPOSURE_GUARD.release(next_block, submitted.debt_to_liquidate_usd);
+                    warn!(
+                        "Failed to resubmit the bundle for {} for block {}: {}",
+                        trace_id, next_block, e
+                    )
+                }
+            }
+        }
+        match provider.get_transaction_receipt(submitted.tx_hash).await {
+            Ok(receipt) => {
+                let included = receipt.is_some();
+                if included {
+                    BUNDLES_INCLUDED.inc();
+                }
+                info!(
+                    "Bundle {:?} {}",
+                    submitted.relay_response,
+                    if included { "landed" } else { "didn't land" }
+                );
+                BRIBE_POLICY.record_inclusion(included);
+                let builders = mev_share_client.builders();
+                if let Some(block_number) = receipt.and_then(|receipt| receipt.block_number) {
+                    if !builders.is_empty() {
+                        match provider
+                            .get_block(BlockId::number(block_number), BlockTransactionsKind::Hashes)
+                            .await
+                        {
+                            Ok(Some(block)) => {
+                                match builders.record_landed(&block.header.extra_data) {
+                                    Some(builder) => info!(
+                                        "Bundle landed in block {} built by {}",
+                                        block_number, builder
+                                    ),
+                                    None => info!(
+                                        "Bundle landed in block {} built by none of the builders \
+                                         it was sent to (extra data {})",
+                                        block_number, block.header.extra_data
+                                    ),
+                                }
+                            }
+                            Ok(None) => warn!("Block {} of the bundle not found", block_number),
+                            Err(e) => warn!("Couldn't get block {}: {}", block_number, e),
+                        }
+                    }
+                }
+                if !builders.is_empty() {
+                    info!("Builders so far: {}", builders.summary());
+                }
+            }
+            Err(e) => warn!(
+                "Couldn't get the receipt of {} to check whether its bundle landed: {}",
+                submitted.tx_hash, e
+            ),
+        }
+    });
+}
+
+fn is_cancelled(trace_id: &str) -> bool {
+    CANCELLED_UPDATES
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|cancelled| cancelled == trace_id)
+}
+
+/// Records the price update cancellations vega passes on, so the evaluations of their events stop
+/// before submitting a bundle that backruns a tx that won't land
+fn spawn_cancellation_listener(context: &zmq::Context) {
+    let socket = context.socket(zmq::PULL).unwrap();
+    if let Err(e) = socket.bind(PROFITO_CANCEL_ENDPOINT) {
+        error!("Failed to bind {PROFITO_CANCEL_ENDPOINT}, cancellations will be ignored: {e}");
+        return;
+    }
+    tokio::task::spawn_blocking(move || loop {
+        match socket.recv_bytes(0) {
+            Ok(bytes) => match bincode::deserialize::<PriceUpdateCancellation>(&bytes) {
+                Ok(cancellation) => {
+                    info!(
+                        "Price update {} was cancelled ({:?})",
+                        cancellation.trace_id, cancellation.reason
+                    );
+                    let mut cancelled = CANCELLED_UPDATES.lock().unwrap();
+                    cancelled.push_back(cancellation.trace_id);
+                    if cancelled.len() > CANCELLED_UPDATES_SIZE {
+                        cancelled.pop_front();
+                    }
+                }
+                Err(e) => warn!("Failed to deserialize cancellation: {e}"),
+            },
+            Err(e) => warn!("Failed to receive cancellation: {e}"),
+        }
+    });
+}
+
+/// The latest (trace_id, user) pairs received, oldest first
+#[derive(Default)]
+struct RecentUwEvents {
+    /// Trace id, user and market (PoolAddressesProvider), the same user can be underwater in
+    /// several markets on the same price update
+    seen: HashSet<(String, Address, Address)>,
+    order: VecDeque<(String, Address, Address)>,
+}
+
+impl RecentUwEvents {
+    /// False if the event was already received
+    fn insert(&mut self, uw_event: &UnderwaterUserEvent) -> bool {
+        let key = (
+            uw_event.trace_id.clone(),
+            uw_event.address,
+            uw_event.market.addresses_provider,
+        );
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > RECENT_UW_EVENTS_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+fn send_ack(ack_socket: &zmq::Socket, uw_event: &UnderwaterUserEvent) {
+    let ack = UnderwaterUserAck {
+        trace_id: uw_event.trace_id.clone(),
+        address: uw_event.address,
+        market: uw_event.market.addresses_provider,
+    };
+    match bincode::serialize(&ack) {
+        Ok(bytes) => {
+            if let Err(e) = ack_socket.send(&bytes, 0) {
+                warn!("Failed to ack uw event {}: {e}", uw_event.trace_id);
+            }
+        }
+        Err(e) => warn!("Failed to serialize ack: {e}"),
+    }
+}
+
 fn _setup_logging() {
     let log_file = rolling::RollingFileAppender::new(
         Rotation::DAILY,
@@ -37,118 +417,581 @@ fn _setup_logging() {
         .init();
 }
 
-async fn process_uw_event(
-    uw_event: UnderwaterUserEvent,
-    provider_cache: Arc<ProviderCache>,
+/// Reads everything the profit calculation needs at `block_number`, on top of the prices the
+/// event overrides, and returns the most profitable pair to liquidate, if any
+async fn evaluate_uw_event(
+    uw_event: &UnderwaterUserEvent,
+    provider: Arc<RootProvider<PubSubFrontend>>,
     price_cache: Arc<tokio::sync::Mutex<PriceCache>>,
-    mev_share_client: Arc<MevShareService>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let provider = match provider_cache.get_provider().await {
-        Ok(provider) => provider,
-        Err(e) => {
-            warn!("Failed to get the provider for uw processing: {e}");
-            return Err(e);
-        }
-    };
-    let user_reserve_data = get_user_reserves_data(provider.clone(), uw_event.address).await;
+    base_currency: &BaseCurrency,
+    chain_profile: &ChainProfile,
+    block_number: u64,
+) -> Result<Option<BestPair>, ProfitoError> {
+    let block = BlockId::number(block_number);
+    let market = &uw_event.market;
+    let user_reserve_data =
+        get_user_reserves_data(provider.clone(), market, uw_event.address, block).await;
     if user_reserve_data.is_empty() {
-        return Err("User reserves data came back empty".into());
+        return Err(ProfitoError::InconsistentState(
+            "User reserves data came back empty".to_string(),
+        ));
     };
 
     let aave_oracle: AaveOracle::AaveOracleInstance<
         PubSubFrontend,
         Arc<RootProvider<PubSubFrontend>>,
-    > = AaveOracle::new(AAVE_ORACLE_ADDRESS, provider.clone());
+    > = AaveOracle::new(market.oracle, provider.clone());
 
-    let reserves_list = match get_reserves_list(provider.clone()).await {
+    let reserves_list = match get_reserves_list(provider.clone(), market, block).await {
         Ok(reserves_list) => reserves_list,
         Err(e) => return Err(e),
     };
 
-    let reserves_data = match get_reserves_data(provider.clone()).await {
+    let reserves_data = match get_reserves_data_at(provider.clone(), market, block).await {
         Ok(reserves_data) => reserves_data,
-        Err(e) => return Err(e),
+        Err(e) => return Err(e.into()),
     };
 
-    let (_total_collateral_in_base_currency, total_debt_in_base_currency, health_factor_v33) =
+    let user_emode =
+        match get_user_emode_category_at(provider.clone(), market, uw_event.address, block).await {
+            Ok(user_emode) => user_emode,
+            Err(e) => return Err(e.into()),
+        };
+
+    let (total_collateral_in_base_currency, total_debt_in_base_currency, health_factor_v33) =
         match calculate_user_account_data(
             price_cache.clone(),
             provider.clone(),
+            market,
             uw_event.address,
             reserves_list.clone(),
             reserves_data.clone(),
+            &user_emode,
             Some(uw_event.trace_id.clone()),
+            block,
         )
         .await
         {
             Ok((collateral, debt, hf)) => (collateral, debt, hf),
-            Err(e) => {
-                return Err(format!("Error calculating user account data: {}", e).into());
-            }
+            Err(e) => return Err(e),
         };
 
-    if let Some(best_pair) = get_best_liquidation_opportunity(
+    // vega and profito get to the HF through different code paths, so this is where drift between
+    // both implementations shows up
+    let vega_health_factor = uw_event.user_account_data.healthFactor;
+    if health_factors_diverge(vega_health_factor, health_factor_v33) {
+        HF_DIVERGENCES.inc();
+        error!(
+            "ALERT | {} | Health factor for {} diverges from vega's (profito: {}, vega: {}, {} bps apart, tolerance {} bps){}, capturing snapshot",
+            uw_event.trace_id,
+            uw_event.address,
+            health_factor_v33,
+            vega_health_factor,
+            health_factor_difference_bps(vega_health_factor, health_factor_v33),
+            hf_divergence_tolerance_bps(),
+            if health_factors_disagree_on_liquidation(vega_health_factor, health_factor_v33) {
+                ", only one of them would liquidate"
+            } else {
+                ""
+            }
+        );
+        // The snapshot is only for debugging later, so it doesn't hold up the liquidation
+        let price_cache = price_cache.clone();
+        let aave_oracle = aave_oracle.clone();
+        let uw_event = uw_event.clone();
+        let user_reserve_data = user_reserve_data.clone();
+        let reserves_data = reserves_data.clone();
+        let user_emode = user_emode.clone();
+        tokio::spawn(async move {
+            let result = capture_simulation_snapshot(
+                price_cache,
+                aave_oracle,
+                block_number,
+                &uw_event,
+                &user_reserve_data,
+                &reserves_data,
+                &user_emode,
+                (
+                    total_collateral_in_base_currency,
+                    total_debt_in_base_currency,
+                    health_factor_v33,
+                ),
+            )
+            .await
+            .and_then(|snapshot| write_simulation_snapshot(&snapshot))
+            .map_err(|e| e.to_string());
+            match result {
+                Ok(path) => info!("Simulation snapshot written to {}", path.display()),
+                Err(e) => warn!("Failed to capture simulation snapshot: {}", e),
+            }
+        });
+    }
+
+    Ok(get_best_liquidation_opportunity(
         user_reserve_data,
         reserves_data,
+        &reserves_list,
         uw_event.address,
         health_factor_v33,
         total_debt_in_base_currency,
         price_cache,
-        provider.clone(),
+        provider,
+        market,
         uw_event.trace_id.clone(),
-        aave_oracle.clone(),
+        aave_oracle,
+        &user_emode,
+        base_currency,
+        chain_profile,
+        block,
     )
-    .await
-    {
-        // these are not part of the profit calculation
-        // they're here only for the purpose of submitting the appropriate parameters
-        // to the liquidation function
-        let (collateral_to_weth_fee, weth_to_debt_fee) = calculate_best_swap_fees(
+    .await)
+}
+
+async fn process_uw_event(
+    uw_event: UnderwaterUserEvent,
+    provider_cache: Arc<ProviderCache>,
+    price_cache: Arc<tokio::sync::Mutex<PriceCache>>,
+    mev_share_client: Arc<MevShareService>,
+    base_currency: BaseCurrency,
+    chain_profile: ChainProfile,
+) -> Result<(), ProfitoError> {
+    if KILL_SWITCH.is_halted() {
+        return Err(ProfitoError::Rejected(format!(
+            "Kill switch is halting everything, ignoring uw event {}",
+            uw_event.trace_id
+        )));
+    }
+    if KILL_SWITCH.is_market_blocked(&uw_event.market) {
+        return Err(ProfitoError::Rejected(format!(
+            "Market {} is in the kill switch list, ignoring uw event {}",
+            uw_event.market.name, uw_event.trace_id
+        )));
+    }
+    let provider = match provider_cache.get_provider().await {
+        Ok(provider) => provider,
+        Err(e) => {
+            warn!("Failed to get the provider for uw processing: {e}");
+            return Err(ProfitoError::Shared(e));
+        }
+    };
+    // The bundle lands on top of whatever the head is when the target block is built, so the
+    // evaluation is pinned to the current head and redone if the head moves while it runs. Once
+    // the head reaches the target block the bundle can no longer land where it was meant to.
+    let target_block = uw_event
+        .inclusion_block
+        .parse::<u64>()
+        .ok()
+        .filter(|block| *block > 0);
+    let mut block_number = match provider.get_block_number().await {
+        Ok(block_number) => block_number,
+        Err(e) => return Err(NodeError::rpc("eth_blockNumber", e).into()),
+    };
+    let mut attempt = 1;
+    let best_pair = loop {
+        if is_cancelled(&uw_event.trace_id) {
+            EVALUATIONS_CANCELLED.inc();
+            return Err(ProfitoError::Rejected(format!(
+                "Price update of {} was cancelled, dropping it",
+                uw_event.trace_id
+            )));
+        }
+        if is_past_deadline(&uw_event) {
+            EVALUATIONS_PAST_DEADLINE.inc();
+            return Err(ProfitoError::Rejected(format!(
+                "Slot deadline for {} passed before evaluating, dropping it",
+                uw_event.trace_id
+            )));
+        }
+        if let Some(target_block) = target_block.filter(|target| block_number >= *target) {
+            EVALUATIONS_ABORTED.inc();
+            return Err(ProfitoError::Rejected(format!(
+                "Head is at {} and target block for {} was {}, dropping it",
+                block_number, uw_event.trace_id, target_block
+            )));
+        }
+        let evaluation = evaluate_uw_event(
+            &uw_event,
             provider.clone(),
-            best_pair.collateral_asset,
-            best_pair.debt_asset,
+            price_cache.clone(),
+            &base_currency,
+            &chain_profile,
+            block_number,
         )
         .await;
-        let bribe = calculate_bribe();
+        // The node hiccuped, which is worth another attempt while the bundle can still land
+        let best_pair = match evaluation {
+            Ok(best_pair) => best_pair,
+            Err(e) if e.is_retryable() && attempt < MAX_EVALUATION_ATTEMPTS => {
+                let delay = RetryPolicy::RPC.delay(attempt as u32);
+                warn!(
+                    "Evaluating {} for {} failed (attempt {}): {}. Retrying in {:?}",
+                    uw_event.trace_id, uw_event.address, attempt, e, delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                block_number = match provider.get_block_number().await {
+                    Ok(head) => head,
+                    Err(e) => return Err(NodeError::rpc("eth_blockNumber", e).into()),
+                };
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let head = match provider.get_block_number().await {
+            Ok(head) => head,
+            Err(e) => return Err(NodeError::rpc("eth_blockNumber", e).into()),
+        };
+        if head == block_number {
+            break best_pair;
+        }
+        if attempt >= MAX_EVALUATION_ATTEMPTS {
+            EVALUATIONS_ABORTED.inc();
+            return Err(ProfitoError::Rejected(format!(
+                "Head kept moving while evaluating {} for {}, giving up after {} attempts",
+                uw_event.trace_id, uw_event.address, attempt
+            )));
+        }
+        info!(
+            "Head moved from {} to {} while evaluating {} for {}, evaluating again",
+            block_number, head, uw_event.trace_id, uw_event.address
+        );
+        attempt += 1;
+        block_number = head;
+    };
+
+    if let Some(mut best_pair) = best_pair {
+        // The route the pair was priced with, at the same block. Kept collateral isn't swapped at
+        // all.
+        let mut swap_route = std::mem::take(&mut best_pair.swap_route);
+        // Only triggerLiquidation() swaps through the fee tiers, so only its route can take the
+        // experiment's. It's quoted again with them, and only taken if it returns as much as the
+        // evaluated one. A use of the experiment only counts once the bundle is submitted.
+        let mut swap_fees_experiment = None;
+        if best_pair.execution_mode == ExecutionMode::SwapAndRepay
+            && swap_route.is_uniswap_v3_through_weth()
+        {
+            if let (collateral_to_weth_fee, weth_to_debt_fee, Some(reservation)) =
+                calculate_best_swap_fees(
+                    provider.clone(),
+                    best_pair.collateral_asset,
+                    best_pair.debt_asset,
+                    BlockId::number(block_number),
+                )
+                .await
+            {
+                match quote_uniswap_v3_through_weth(
+                    provider.clone(),
+                    best_pair.collateral_asset,
+                    best_pair.debt_asset,
+                    best_pair.actual_collateral_to_liquidate,
+                    (collateral_to_weth_fee, weth_to_debt_fee),
+                    BlockId::number(block_number),
+                )
+                .await
+                {
+                    Some(route) if route.amount_out >= swap_route.amount_out => {
+                        swap_route = route;
+                        swap_fees_experiment = Some(reservation);
+                    }
+                    _ => info!(
+                        "Not swapping {} through the cheapest fee tiers, they don't quote as much",
+                        uw_event.trace_id
+                    ),
+                }
+            }
+        }
+        // these are not part of the profit calculation
+        // they're here only for the purpose of submitting the appropriate parameters
+        // to the liquidation function
+        let (collateral_to_weth_fee, weth_to_debt_fee) = swap_route.uniswap_v3_fees();
 
         info!(
-            "liquidate {} @ {} for ${} (total collateral {})",
+            "liquidate {} on {} @ {} (run {}) for {} (~{}) (total collateral {}) with {:?} swapping {}",
             uw_event.address,
-            uw_event.trace_id.clone(),
+            uw_event.market.name,
+            uw_event.trace_id,
+            uw_event.run_id,
             best_pair.printable_net_profit,
+            PROFIT_POLICY.format(PROFIT_POLICY.denominate(
+                &base_currency,
+                best_pair.net_profit,
+                best_pair.weth_price
+            )),
             uw_event.total_collateral_base,
+            best_pair.execution_mode,
+            swap_route,
         );
 
+        // The flash loan source (or Foxdie's own balance, when keeping the collateral) was picked
+        // with the balances of the evaluated block, which may have moved since. Checked against
+        // the head, the last state before the bundle lands.
+        if best_pair.execution_mode == ExecutionMode::ReceiveAToken {
+            if !HOLD_COLLATERAL
+                .can_hold(
+                    provider.clone(),
+                    &uw_event.market,
+                    best_pair.collateral_asset,
+                    best_pair.debt_asset,
+                    best_pair.actual_debt_to_liquidate,
+                    BlockId::latest(),
+                )
+                .await
+            {
+                return Err(ProfitoError::Rejected(format!(
+                    "Not submitting bundle for {}: Foxdie can't repay {} of {} on its own anymore",
+                    uw_event.trace_id, best_pair.actual_debt_to_liquidate, best_pair.debt_asset
+                )));
+            }
+        } else {
+            let liquidity = recheck_liquidity_provider(
+                provider.clone(),
+                &uw_event.market,
+                best_pair.flash_loan_source,
+                best_pair.debt_asset,
+                best_pair.actual_debt_to_liquidate,
+                BlockId::latest(),
+            )
+            .await;
+            if liquidity.source != best_pair.flash_loan_source {
+                FLASH_LOAN_SOURCE_FALLBACKS.inc();
+                if liquidity.source == Foxdie::FlashLoanSource::NONE {
+                    return Err(ProfitoError::Rejected(format!(
+                        "Not submitting bundle for {}: no flash loan source can lend the debt anymore ({})",
+                        uw_event.trace_id,
+                        liquidity.reasons.join(", ")
+                    )));
+                }
+                warn!(
+                    "Flash loan source for {} moved from {:?} to {:?}: {}",
+                    uw_event.trace_id,
+                    best_pair.flash_loan_source,
+                    liquidity.source,
+                    liquidity.reasons.join(", ")
+                );
+            }
+            // The premium is encoded as Foxdie's aavePremium when the loan comes from AAVE V3, and a
+            // costlier loan than the evaluated one may leave too little profit
+            best_pair.set_flash_loan(liquidity.source, liquidity.premium);
+            let min_profit =
+                min_profit_in_base_units(&base_currency, best_pair.weth_price, &uw_event.trace_id)?;
+            if best_pair.net_profit == U256::ZERO || best_pair.net_profit < min_profit {
+                return Err(ProfitoError::Rejected(format!(
+                "Not submitting bundle for {}: the {:?} flash loan premium ({}) leaves {} of profit",
+                uw_event.trace_id,
+                liquidity.source,
+                liquidity.premium,
+                base_currency.format(best_pair.net_profit)
+            )));
+            }
+        }
+
+        let (collateral_asset, debt_asset) = (best_pair.collateral_asset, best_pair.debt_asset);
+        let mut expected_profit = best_pair.net_profit;
+        let weth_price = best_pair.weth_price;
+        // What the evaluation priced gas with, before this bundle's estimate replaces it
+        let budgeted_gas = GAS_ESTIMATES.gas_limit(collateral_asset, debt_asset);
+        let debt_to_liquidate_usd =
+            base_currency.to_usd(best_pair.debt_to_liquidate_in_base_currency);
+        // Picked on the final profit, since the recheck may have changed it
+        let gas_price = match provider.get_gas_price().await {
+            Ok(gas_price) => gas_price,
+            Err(e) => {
+                warn!("Couldn't get the gas price to pick the bribe with: {}", e);
+                0
+            }
+        };
+        let competition =
+            COMPETITION.score(collateral_asset, debt_asset, chain_profile.block_time());
+        let bribe_decision = calculate_bribe(
+            base_currency.to_usd(expected_profit),
+            gas_price,
+            competition,
+        );
+        info!("Bribe for {} is {}", uw_event.trace_id, bribe_decision);
+        let bribe = U256::from(bribe_decision.bps);
+        // The builder gets it on top of the bribe, so simulations don't count it as bribe
+        let priority_fee = match provider.get_max_priority_fee_per_gas().await {
+            Ok(priority_fee) => priority_fee,
+            Err(e) => {
+                warn!("Couldn't get the priority fee to simulate with: {}", e);
+                gas_price
+            }
+        };
+        let profit_check = ProfitCheck::new(
+            bribe_decision.bps,
+            Some(min_profit_in_base_units(
+                &base_currency,
+                best_pair.weth_price,
+                &uw_event.trace_id,
+            )?),
+            best_pair.weth_price,
+            priority_fee,
+        );
         let foxdie_tx = match create_trigger_liquidation_tx(
             best_pair,
+            &uw_event.market,
             uw_event.address,
             collateral_to_weth_fee,
             weth_to_debt_fee,
             bribe,
+            &swap_route,
         )
         .await
         {
             Ok(tx) => tx,
-            Err(e) => return Err(format!("Error creating foxdie tx: {}", e).into()),
+            Err(e) => return Err(e),
         };
+        // A revert here means the bundle would revert too. Otherwise the estimate replaces the
+        // gas the evaluation budgeted for the pair, which may leave too little profit.
+        if let Err(e) = GAS_ESTIMATES
+            .simulate(
+                provider.clone(),
+                &uw_event.market,
+                &foxdie_tx,
+                collateral_asset,
+                debt_asset,
+                &uw_event.new_asset_prices,
+                BlockId::number(block_number),
+            )
+            .await
+        {
+            BUNDLES_FAILED_GAS_ESTIMATE.inc();
+            if let Some(reason) = e.revert_reason() {
+                record_revert(&uw_event.trace_id, &reason);
+            }
+            return Err(ProfitoError::Rejected(format!(
+                "Not submitting bundle for {}: estimating its gas failed, so it would likely revert: {}",
+                uw_event.trace_id, e
+            )));
+        }
+        let extra_gas = GAS_ESTIMATES
+            .gas_limit(collateral_asset, debt_asset)
+            .saturating_sub(budgeted_gas);
+        if extra_gas > 0 {
+            let extra_gas_cost = U256::from(extra_gas)
+                .saturating_mul(U256::from(gas_price))
+                .saturating_mul(weth_price)
+                / U256::from(10).pow(U256::from(18));
+            expected_profit = expected_profit.saturating_sub(extra_gas_cost);
+            let min_profit =
+                min_profit_in_base_units(&base_currency, weth_price, &uw_event.trace_id)?;
+            if expected_profit == U256::ZERO || expected_profit < min_profit {
+                return Err(ProfitoError::Rejected(format!(
+                    "Not submitting bundle for {}: it needs {} more gas than budgeted, which leaves {} of profit",
+                    uw_event.trace_id,
+                    extra_gas,
+                    base_currency.format(expected_profit)
+                )));
+            }
+        }
+        if is_cancelled(&uw_event.trace_id) {
+            EVALUATIONS_CANCELLED.inc();
+            return Err(ProfitoError::Rejected(format!(
+                "Price update of {} was cancelled while evaluating, not submitting bundle",
+                uw_event.trace_id
+            )));
+        }
+        if is_past_deadline(&uw_event) {
+            EVALUATIONS_PAST_DEADLINE.inc();
+            return Err(ProfitoError::Rejected(format!(
+                "Slot deadline for {} passed while evaluating, not submitting bundle",
+                uw_event.trace_id
+            )));
+        }
+        // Everything was read from the node, which is only as current as its head
+        if let Some(lag) = HEAD_LAG.paused_lag_secs() {
+            BUNDLES_PAUSED_BY_HEAD_LAG.inc();
+            return Err(ProfitoError::Rejected(format!(
+                "Not submitting bundle for {}: node head is {}s behind",
+                uw_event.trace_id, lag
+            )));
+        }
+        // The list may have changed while we were evaluating, so check again right before submitting
+        if let Some(asset) = KILL_SWITCH.first_blocked([collateral_asset, debt_asset]) {
+            return Err(ProfitoError::Rejected(format!(
+                "Not submitting bundle for {} because asset {} is in the kill switch list",
+                uw_event.trace_id, asset
+            )));
+        }
+        if KILL_SWITCH.is_market_blocked(&uw_event.market) {
+            return Err(ProfitoError::Rejected(format!(
+                "Not submitting bundle for {} because market {} is in the kill switch list",
+                uw_event.trace_id, uw_event.market.name
+            )));
+        }
+        // Injected by an operator to rehearse a price move, everything up to here ran for real
+        if uw_event.price_update_tx == Some(PriceUpdateTx::Rehearsal) {
+            REHEARSALS_EVALUATED.inc();
+            info!(
+                "Rehearsal {} (run {}): would submit bundle liquidating {} ({} collateral, {} debt, ~${:.2} repaid) for block {}",
+                uw_event.trace_id,
+                uw_event.run_id,
+                uw_event.address,
+                collateral_asset,
+                debt_asset,
+                debt_to_liquidate_usd,
+                uw_event.inclusion_block
+            );
+            return Ok(());
+        }
+        // Bundles of the same block all draw on the same flash loan and swap liquidity
+        let exposure_block = target_block.unwrap_or(block_number + 1);
+        if let Err(committed_usd) = EXPOSURE_GUARD.try_commit(exposure_block, debt_to_liquidate_usd)
+        {
+            BUNDLES_OVER_EXPOSURE_LIMIT.inc();
+            return Err(ProfitoError::Rejected(format!(
+                "Not submitting bundle for {}: repaying ~${:.2} would take block {} over the ${:.2} limit (${:.2} already committed)",
+                uw_event.trace_id,
+                debt_to_liquidate_usd,
+                exposure_block,
+                EXPOSURE_GUARD.ceiling_usd().unwrap_or_default(),
+                committed_usd
+            )));
+        }
         match mev_share_client
             .submit_simple_liquidation_bundle(
-                uw_event.tx_hash,
-                uw_event.raw_tx,
+                uw_event.price_update_tx,
                 foxdie_tx,
                 uw_event.inclusion_block,
+                &chain_profile,
+                expected_profit,
+                profit_check,
+                debt_to_liquidate_usd,
+                [collateral_asset, debt_asset],
             )
             .await
         {
-            Ok(res) => {
-                info!("Submitted bundle. Response: {:?}", res);
+            Ok(submitted) => {
+                BUNDLES_SUBMITTED.inc();
+                if let Some(experiment) = swap_fees_experiment {
+                    experiment.commit();
+                }
+                info!(
+                    "Submitted bundle for {} (run {}). Response: {:?}, accepted by builders {:?}",
+                    uw_event.trace_id,
+                    uw_event.run_id,
+                    submitted.relay_response,
+                    submitted.builders
+                );
+                spawn_bundle_tracker(
+                    provider.clone(),
+                    mev_share_client.clone(),
+                    submitted,
+                    chain_profile,
+                    uw_event.trace_id.clone(),
+                    uw_event.address,
+                    uw_event.market.clone(),
+                );
             }
             Err(e) => {
-                return Err(format!(
-                    "Error processing uw event for bundle {}: {}",
-                    uw_event.trace_id, e
-                )
-                .into())
+                EXPOSURE_GUARD.release(exposure_block, debt_to_liquidate_usd);
+                if matches!(e, ProfitoError::Simulation(_)) {
+                    BUNDLES_FAILED_SIMULATION.inc();
+                }
+                if let Some(reason) = e.revert_reason() {
+                    record_revert(&uw_event.trace_id, &reason);
+                }
+                return Err(e);
             }
         };
     } else {
@@ -165,8 +1008,60 @@ async fn main() {
     _setup_logging();
     info!("Starting Profito RS");
     let provider_cache = Arc::new(ProviderCache::new());
-    let price_cache = Arc::new(Mutex::new(PriceCache::new(3)));
     let mev_share_client = Arc::new(MevShareService::new());
+    // Prices and profits are quoted in the market base currency, which is fixed for a deployed
+    // market, so it's only read once
+    let provider = match provider_cache.get_provider().await {
+        Ok(provider) => provider,
+        Err(e) => {
+            error!("Failed to get the provider to read the market base currency: {e}");
+            std::process::exit(1);
+        }
+    };
+    let markets = resolve_markets_from_env(provider.clone()).await;
+    // By PoolAddressesProvider, which is how events tell their market
+    let mut base_currencies = HashMap::new();
+    for market in markets.iter() {
+        match get_base_currency(provider.clone(), market).await {
+            Ok(base_currency) => {
+                info!(
+                    "Base currency of the {} market is {} ({} decimals)",
+                    market.name, base_currency.address, base_currency.decimals
+                );
+                base_currencies.insert(market.addresses_provider, base_currency);
+            }
+            Err(e) if market.is_core() => {
+                error!("Failed to get the market base currency: {e}");
+                std::process::exit(1);
+            }
+            Err(e) => error!(
+                "Failed to get the base currency of the {} market, its events will be ignored: {e}",
+                market.name
+            ),
+        }
+    }
+    // Each price update is evaluated once per market, with prices read from that market's oracle
+    let price_cache = Arc::new(Mutex::new(PriceCache::new(3 * markets.len())));
+    // Decides how long submitted bundles stay valid and when grace periods are considered over
+    let chain_profile = match ChainProfile::from_provider(&provider).await {
+        Ok(chain_profile) => chain_profile,
+        Err(e) => {
+            error!("Failed to get the chain profile: {e}");
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "Chain is {} ({}), block cadence: {:?}",
+        chain_profile.name, chain_profile.chain_id, chain_profile.cadence
+    );
+    // Read before the first event is evaluated, then kept up to date in the background
+    for market in markets.iter() {
+        spawn_liquidation_params_refresh(provider.clone(), market.clone()).await;
+    }
+    info!(
+        "Health factors diverging from vega's by more than {} bps will be alerted",
+        hf_divergence_tolerance_bps()
+    );
     let context = zmq::Context::new();
     let socket = context.socket(zmq::PULL).unwrap();
     if let Err(e) = socket.bind(PROFITO_INBOUND_ENDPOINT) {
@@ -177,10 +1072,58 @@ async fn main() {
         "Listening for health factor alerts on {}",
         PROFITO_INBOUND_ENDPOINT
     );
+    let ack_socket = context.socket(zmq::PUSH).unwrap();
+    if let Err(e) = ack_socket.connect(VEGA_ACK_ENDPOINT) {
+        error!("Failed to connect to {VEGA_ACK_ENDPOINT}: {e}");
+        std::process::exit(1);
+    }
+    spawn_cancellation_listener(&context);
+    spawn_competition_listener(&context);
+    HEAD_LAG.spawn(provider.clone(), chain_profile);
+    let mut recent_uw_events = RecentUwEvents::default();
+    StatusReporter::new("profito-rs")
+        .counter("uw_events", &UW_EVENTS_RECEIVED)
+        .counter("bundles_submitted", &BUNDLES_SUBMITTED)
+        .counter("evaluations_aborted", &EVALUATIONS_ABORTED)
+        .counter("hf_divergences", &HF_DIVERGENCES)
+        .counter("evaluations_cancelled", &EVALUATIONS_CANCELLED)
+        .counter("evaluations_past_deadline", &EVALUATIONS_PAST_DEADLINE)
+        .counter("bundles_over_exposure_limit", &BUNDLES_OVER_EXPOSURE_LIMIT)
+        .counter("rehearsals_evaluated", &REHEARSALS_EVALUATED)
+        .counter("flash_loan_source_fallbacks", &FLASH_LOAN_SOURCE_FALLBACKS)
+        .counter("bundles_included", &BUNDLES_INCLUDED)
+        .counter("bundles_paused_by_head_lag", &BUNDLES_PAUSED_BY_HEAD_LAG)
+        .counter("bundles_failed_simulation", &BUNDLES_FAILED_SIMULATION)
+        .counter("bundles_failed_gas_estimate", &BUNDLES_FAILED_GAS_ESTIMATE)
+        .counter("bundles_resubmitted", &BUNDLES_RESUBMITTED)
+        .counter("bundles_invalidated", &BUNDLES_INVALIDATED)
+        .gauge("head_lag_secs", &HEAD_LAG_SECS)
+        .spawn(provider);
+    spawn_revert_summary(REVERT_SUMMARY_INTERVAL);
     loop {
         match socket.recv_bytes(0) {
             Ok(bytes) => match bincode::deserialize::<UnderwaterUserEvent>(&bytes) {
                 Ok(uw_event) => {
+                    // Acked right away, vega only needs to know the event made it here
+                    send_ack(&ack_socket, &uw_event);
+                    if !recent_uw_events.insert(&uw_event) {
+                        info!(
+                            "Ignoring retransmitted uw event {} for {}",
+                            uw_event.trace_id, uw_event.address
+                        );
+                        continue;
+                    }
+                    UW_EVENTS_RECEIVED.inc();
+                    let Some(base_currency) = base_currencies
+                        .get(&uw_event.market.addresses_provider)
+                        .copied()
+                    else {
+                        warn!(
+                            "Ignoring uw event {} for {} of the {} market, which isn't followed",
+                            uw_event.trace_id, uw_event.address, uw_event.market.name
+                        );
+                        continue;
+                    };
                     let provider_cache = provider_cache.clone();
                     let cloned_uw_event = uw_event.clone();
                     let mev_share_client = mev_share_client.clone();
@@ -191,6 +1134,7 @@ async fn main() {
                         .await
                         .override_price(
                             cloned_uw_event.trace_id.clone(),
+                            cloned_uw_event.market.oracle,
                             cloned_uw_event.new_asset_prices,
                         )
                         .await
@@ -198,16 +1142,24 @@ async fn main() {
                         warn!("Price(s) for uw_event with trace_id {} couldn't be overriden. Next calculations won't consider the pending price update TX values.", uw_event.trace_id);
                     }
                     let price_cache = price_cache.clone();
+                    let run_id = uw_event.run_id.clone();
                     tokio::spawn(async move {
                         if let Err(e) = process_uw_event(
                             uw_event,
                             provider_cache,
                             price_cache,
                             mev_share_client,
+                            base_currency,
+                            chain_profile,
                         )
                         .await
                         {
-                            warn!("Failed to process underwater event: {e}");
+                            // Dropped on purpose, not worth a warning
+                            if e.is_rejection() {
+                                info!("Underwater event of run {run_id} not processed: {e}");
+                            } else {
+                                warn!("Failed to process underwater event of run {run_id}: {e}");
+                            }
                         }
                     });
                 }
@@ -1,8 +1,11 @@
-use alloy::primitives::Bytes;
+use alloy::primitives::{keccak256, Address, B256, U256};
 use ethers_core::{
     k256::ecdsa::SigningKey,
     rand::thread_rng,
-    types::{transaction::eip2718::TypedTransaction, Chain, H256, U64},
+    types::{
+        transaction::eip2718::TypedTransaction, Bytes, Chain, Eip1559TransactionRequest, H160,
+        H256, U64,
+    },
 };
 use ethers_signers::{LocalWallet, Signer, Wallet};
 use jsonrpsee::http_client::{
@@ -14,25 +17,90 @@ use mev_share::rpc::{
     SendBundleResponse,
 };
 use once_cell::sync::OnceCell;
-use std::{env, str::FromStr, sync::Arc};
-use tokio::sync::Mutex;
+use overlord_shared::{chain_profile::ChainProfile, PriceUpdateTx};
+use std::{env, str::FromStr, sync::Arc, time::Duration};
+use tokio::time::Instant;
 use tower::{util::MapErr, ServiceBuilder};
-use tracing::info;
+use tracing::{info, warn};
 
-type MevShareClient = HttpClient<
+use crate::builders::BuilderFanout;
+use crate::error::ProfitoError;
+use crate::relay_budget::{is_rate_limited, RelayBudget};
+use crate::resubmission::resubmit_blocks_from_env;
+use crate::simulation::{BundleSimulator, ProfitCheck};
+
+pub(crate) type MevShareClient = HttpClient<
     MapErr<
         FlashbotsSigner<Wallet<SigningKey>, HttpBackend>,
         fn(Box<dyn std::error::Error + Send + Sync>) -> HttpError,
     >,
 >;
 
-static MEVSHARE_CLIENT: OnceCell<Arc<MevShareClient>> = OnceCell::new();
+// How long a bundle is resubmitted for after its inclusion block by default (5 blocks on
+// mainnet). Prices and positions keep moving, so it's not worth landing a liquidation priced long
+// ago.
+const BUNDLE_VALIDITY: Duration = Duration::from_secs(60);
+
+/// A bundle the relay or at least one builder accepted
+#[derive(Debug)]
+pub struct SubmittedBundle {
+    /// None if only builders accepted it
+    pub relay_response: Option<SendBundleResponse>,
+    /// Builders of BuilderFanout that accepted it
+    pub builders: Vec<String>,
+    /// Hash of the signed Foxdie tx, which is only on chain if the bundle landed
+    pub tx_hash: B256,
+    /// Hash of the price update the bundle backruns, None if it only has the liquidation
+    pub price_update_hash: Option<B256>,
+    /// Whether the bundle lands the price update the liquidation depends on, be it the update it
+    /// backruns or a payload tx of its own. False for Synthetic bundles.
+    pub price_update_included: bool,
+    /// The only block the bundle was submitted for
+    pub block: u64,
+    /// Last block the bundle can be resubmitted for, see MevShareService::resubmit()
+    pub last_block: u64,
+    /// USD value of the debt the bundle repays, committed to the exposure of every block it's
+    /// submitted for
+    pub debt_to_liquidate_usd: f64,
+    /// Collateral and debt asset of the liquidation, which may be added to the kill switch list
+    /// after the bundle is first submitted
+    pub liquidated_assets: [Address; 2],
+    bundle: SendBundleRequest,
+    raw_txs: Option<Vec<Bytes>>,
+    expected_profit: U256,
+    profit_check: ProfitCheck,
+}
 
-#[derive(Clone)]
 pub struct MevShareService {
-    initialization: Arc<Mutex<()>>,
     fb_signer: LocalWallet,
     tx_signer: LocalWallet,
+    budget: RelayBudget,
+    /// Client of each relay of the budget, created the first time it's used
+    clients: Vec<(String, OnceCell<Arc<MevShareClient>>)>,
+    builders: BuilderFanout,
+    /// None if bundles are submitted without simulating them
+    simulator: Option<BundleSimulator>,
+    /// Blocks after the inclusion block bundles are resubmitted for, None for BUNDLE_VALIDITY
+    resubmit_blocks: Option<u64>,
+}
+
+/// Client for `url` that signs its requests flashbots-style with `signer`
+pub(crate) fn build_signed_client(
+    signer: &LocalWallet,
+    url: &str,
+) -> Result<MevShareClient, Box<dyn std::error::Error + Send + Sync>> {
+    // Set up flashbots-style auth middleware
+    let signing_middleware = FlashbotsSignerLayer::new(signer.clone());
+    let service_builder = ServiceBuilder::new()
+        // map signer errors to http errors
+        .map_err(HttpError::Http as fn(Box<dyn std::error::Error + Send + Sync>) -> HttpError)
+        .layer(signing_middleware);
+
+    // Set up the rpc client
+    HttpClientBuilder::default()
+        .set_middleware(service_builder)
+        .build(url)
+        .map_err(|e| format!("Failed to create http client for {}: {}", url, e).into())
 }
 
 impl Default for MevShareService {
@@ -43,94 +111,303 @@ impl Default for MevShareService {
 
 impl MevShareService {
     pub fn new() -> Self {
+        let budget = RelayBudget::from_env();
+        let fb_signer = LocalWallet::new(&mut thread_rng());
         Self {
-            fb_signer: LocalWallet::new(&mut thread_rng()),
+            builders: BuilderFanout::from_env(fb_signer.clone()),
+            simulator: BundleSimulator::from_env(fb_signer.clone()),
+            resubmit_blocks: resubmit_blocks_from_env(),
+            fb_signer,
             tx_signer: LocalWallet::from_str(&env::var("FOXDIE_OWNER_PK").unwrap())
                 .unwrap()
                 .with_chain_id(Chain::Mainnet),
-            initialization: Arc::new(Mutex::new(())),
+            clients: budget
+                .relay_urls()
+                .into_iter()
+                .map(|url| (url, OnceCell::new()))
+                .collect(),
+            budget,
         }
     }
 
-    pub async fn get_client(&self) -> Result<Arc<MevShareClient>, Box<dyn std::error::Error>> {
-        if let Some(client) = MEVSHARE_CLIENT.get() {
-            return Ok((*client).clone());
-        }
-        let _lock = self.initialization.lock().await;
-
-        if let Some(client) = MEVSHARE_CLIENT.get() {
-            return Ok(client.clone());
-        }
+    /// Client of the relay at `index` of the budget
+    pub fn get_client(
+        &self,
+        index: usize,
+    ) -> Result<Arc<MevShareClient>, Box<dyn std::error::Error + Send + Sync>> {
+        let (url, client) = self
+            .clients
+            .get(index)
+            .ok_or_else(|| format!("There's no relay {}", index))?;
+        client
+            .get_or_try_init(|| build_signed_client(&self.fb_signer, url).map(Arc::new))
+            .cloned()
+    }
 
-        // Set up flashbots-style auth middleware
-        let signing_middleware = FlashbotsSignerLayer::new(self.fb_signer.clone());
-        let service_builder = ServiceBuilder::new()
-            // map signer errors to http errors
-            .map_err(HttpError::Http as fn(Box<dyn std::error::Error + Send + Sync>) -> HttpError)
-            .layer(signing_middleware);
-
-        // Set up the rpc client
-        let url = "https://relay.flashbots.net:443";
-        let client = HttpClientBuilder::default()
-            .set_middleware(service_builder)
-            .build(url)
-            .expect("Failed to create http client");
-
-        MEVSHARE_CLIENT
-            .set(client.clone().into())
-            .map_err(|_| "Failed to SET Mev-Share client on cache")?;
-        Ok(client.into())
+    pub fn builders(&self) -> &BuilderFanout {
+        &self.builders
     }
 
+    /// Submits the bundle for its inclusion block only, see submit(). If it doesn't land there,
+    /// whoever submitted it can resubmit() it for each of the next blocks up to `last_block` of
+    /// the SubmittedBundle.
+    #[allow(clippy::too_many_arguments)]
     pub async fn submit_simple_liquidation_bundle(
         &self,
-        pub_tx: Option<String>,
-        raw_tx: Option<Bytes>,
+        price_update_tx: Option<PriceUpdateTx>,
         foxdie_tx: TypedTransaction,
         inclusion_block: String,
-    ) -> Result<SendBundleResponse, Box<dyn std::error::Error>> {
-        let signature = self.tx_signer.sign_transaction(&foxdie_tx.clone()).await?;
-        let bytes = foxdie_tx.rlp_signed(&signature);
-        let backrun_tx: BundleItem;
-        if let Some(raw) = raw_tx {
+        chain_profile: &ChainProfile,
+        expected_profit: U256,
+        profit_check: ProfitCheck,
+        debt_to_liquidate_usd: f64,
+        liquidated_assets: [Address; 2],
+    ) -> Result<SubmittedBundle, ProfitoError> {
+        let mut foxdie_tx = foxdie_tx;
+        let mut bundle_body = vec![];
+        // Body of the bundle for builders taking eth_sendBundle, which only takes signed txs
+        let mut raw_txs = Some(vec![]);
+        let mut price_update_hash = None;
+        match price_update_tx {
             // Convert from alloy::primitives::Bytes to ethers_core::types::Bytes
-            let ethers_bytes = ethers_core::types::Bytes::from(raw.to_vec());
-            backrun_tx = BundleItem::Tx {
-                tx: ethers_bytes,
-                can_revert: false,
-            };
-        } else if let Some(pub_hash) = pub_tx {
-            backrun_tx = BundleItem::Hash {
-                hash: H256::from_str(&pub_hash)?,
-            };
-        } else {
-            return Err("Didn't get a tx hash or raw data to backrun"
-                .to_string()
-                .into());
+            Some(PriceUpdateTx::RawTx(raw)) => {
+                price_update_hash = Some(keccak256(&raw));
+                let tx = Bytes::from(raw.to_vec());
+                raw_txs = Some(vec![tx.clone()]);
+                bundle_body.push(BundleItem::Tx {
+                    tx,
+                    can_revert: false,
+                })
+            }
+            Some(PriceUpdateTx::HashOnly(hash)) => {
+                price_update_hash = Some(hash);
+                raw_txs = None;
+                bundle_body.push(BundleItem::Hash {
+                    hash: H256::from_slice(hash.as_slice()),
+                })
+            }
+            // The price lands through a call of our own, signed by the same account and sent
+            // right before the liquidation
+            Some(PriceUpdateTx::Payload { to, input, value }) => {
+                let tx = self
+                    .sign_payload_tx(&mut foxdie_tx, to, input, value)
+                    .await?;
+                raw_txs = Some(vec![tx.clone()]);
+                bundle_body.push(BundleItem::Tx {
+                    tx,
+                    can_revert: false,
+                })
+            }
+            // The position changed without a price update, the liquidation goes on its own
+            Some(PriceUpdateTx::Synthetic) => (),
+            Some(PriceUpdateTx::Rehearsal) => {
+                return Err(ProfitoError::Rejected(
+                    "Rehearsal bundles are never submitted".to_string(),
+                ))
+            }
+            None => {
+                return Err(ProfitoError::Rejected(
+                    "Didn't get a tx hash or raw data to backrun".to_string(),
+                ))
+            }
         };
-        let bundle_body = vec![
-            backrun_tx,
-            BundleItem::Tx {
-                tx: bytes,
-                can_revert: false,
-            },
-        ];
-        let block = U64::from(inclusion_block.parse::<u64>()?);
-        let max_block = block + U64::from(5u64);
+        let price_update_included = !bundle_body.is_empty();
+        let signature = self
+            .tx_signer
+            .sign_transaction(&foxdie_tx)
+            .await
+            .map_err(|e| ProfitoError::Submission(format!("Error signing foxdie tx: {}", e)))?;
+        let bytes = foxdie_tx.rlp_signed(&signature);
+        let tx_hash = B256::from_slice(foxdie_tx.hash(&signature).as_bytes());
+        if let Some(raw_txs) = raw_txs.as_mut() {
+            raw_txs.push(bytes.clone());
+        }
+        bundle_body.push(BundleItem::Tx {
+            tx: bytes,
+            can_revert: false,
+        });
+        let block = inclusion_block.parse::<u64>().map_err(|e| {
+            ProfitoError::Submission(format!(
+                "Invalid inclusion block {}: {}",
+                inclusion_block, e
+            ))
+        })?;
         let bundle = SendBundleRequest {
             bundle_body,
             inclusion: Inclusion {
-                block,
-                max_block: Some(max_block),
+                block: U64::from(block),
+                max_block: Some(U64::from(block)),
             },
             ..Default::default()
         };
+        let resubmit_blocks = self
+            .resubmit_blocks
+            .unwrap_or_else(|| chain_profile.blocks_within(BUNDLE_VALIDITY));
+        let mut submitted = SubmittedBundle {
+            relay_response: None,
+            builders: vec![],
+            tx_hash,
+            price_update_hash,
+            price_update_included,
+            block,
+            last_block: block + resubmit_blocks,
+            debt_to_liquidate_usd,
+            liquidated_assets,
+            bundle,
+            raw_txs,
+            expected_profit,
+            profit_check,
+        };
+        (submitted.relay_response, submitted.builders) =
+            self.submit(&submitted, chain_profile).await?;
+        Ok(submitted)
+    }
 
-        let client = &*self.get_client().await?;
-        info!("Sending bundle: {:?}", bundle);
-        match MevApiClient::send_bundle(client, bundle.clone()).await {
-            Ok(res) => Ok(res),
-            Err(e) => Err(format!("Error on send_bundle: {}", e).into()),
+    /// Signs the call landing a price update, from the account sending `foxdie_tx`. If
+    /// `foxdie_tx` has a nonce, the payload takes it and `foxdie_tx` gets the next one.
+    async fn sign_payload_tx(
+        &self,
+        foxdie_tx: &mut TypedTransaction,
+        to: Address,
+        input: alloy::primitives::Bytes,
+        value: U256,
+    ) -> Result<Bytes, ProfitoError> {
+        let mut payload_tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .to(H160::from_slice(to.as_slice()))
+            .data(input.to_vec())
+            .value(ethers_core::types::U256::from_big_endian(
+                &value.to_be_bytes::<32>(),
+            ))
+            .into();
+        if let Some(from) = foxdie_tx.from() {
+            payload_tx.set_from(*from);
+        }
+        if let Some(nonce) = foxdie_tx.nonce().copied() {
+            payload_tx.set_nonce(nonce);
+            foxdie_tx.set_nonce(nonce + 1);
+        }
+        let signature = self
+            .tx_signer
+            .sign_transaction(&payload_tx)
+            .await
+            .map_err(|e| ProfitoError::Submission(format!("Error signing payload tx: {}", e)))?;
+        Ok(payload_tx.rlp_signed(&signature))
+    }
+
+    /// Submits `submitted` again, for `block` only, after it missed the block it was submitted
+    /// for. It goes through the same simulation as the first time, now at `block`, so a bundle
+    /// that would revert there (e.g. because the position was liquidated by someone else) isn't
+    /// resubmitted.
+    pub async fn resubmit(
+        &self,
+        submitted: &SubmittedBundle,
+        block: u64,
+        chain_profile: &ChainProfile,
+    ) -> Result<SubmittedBundle, ProfitoError> {
+        if block > submitted.last_block {
+            return Err(ProfitoError::Rejected(format!(
+                "Bundle of {:?} can't be resubmitted after block {}",
+                submitted.tx_hash, submitted.last_block
+            )));
+        }
+        let mut bundle = submitted.bundle.clone();
+        bundle.inclusion = Inclusion {
+            block: U64::from(block),
+            max_block: Some(U64::from(block)),
+        };
+        let mut resubmitted = SubmittedBundle {
+            relay_response: None,
+            builders: vec![],
+            tx_hash: submitted.tx_hash,
+            price_update_hash: submitted.price_update_hash,
+            price_update_included: submitted.price_update_included,
+            block,
+            last_block: submitted.last_block,
+            debt_to_liquidate_usd: submitted.debt_to_liquidate_usd,
+            liquidated_assets: submitted.liquidated_assets,
+            bundle,
+            raw_txs: submitted.raw_txs.clone(),
+            expected_profit: submitted.expected_profit,
+            profit_check: submitted.profit_check,
+        };
+        (resubmitted.relay_response, resubmitted.builders) =
+            self.submit(&resubmitted, chain_profile).await?;
+        Ok(resubmitted)
+    }
+
+    /// Submits the bundle to the first relay with room for it (see RelayBudget), and to the next
+    /// one if it's throttled. Bundles expected to make more are submitted first when relays are
+    /// busy, and each one waits at most a block time for a relay. The same bundle goes to every
+    /// builder of BuilderFanout at the same time, and it counts as submitted if any of them or
+    /// the relay accepted it. Unless simulations are turned off, the bundle is only submitted if
+    /// it succeeds in a simulation at its block and passes its profit check.
+    async fn submit(
+        &self,
+        submitted: &SubmittedBundle,
+        chain_profile: &ChainProfile,
+    ) -> Result<(Option<SendBundleResponse>, Vec<String>), ProfitoError> {
+        let bundle = &submitted.bundle;
+        let raw_txs = submitted.raw_txs.as_deref();
+        if let Some(simulator) = self.simulator.as_ref() {
+            let report = simulator
+                .simulate(bundle, raw_txs, submitted.block, submitted.profit_check)
+                .await?;
+            info!("Bundle of {:?} simulated: {:?}", submitted.tx_hash, report);
+        }
+        let (relay_result, builders) = tokio::join!(
+            self.submit_to_relay(bundle, chain_profile, submitted.expected_profit),
+            self.builders
+                .submit(bundle, raw_txs, submitted.block, submitted.block)
+        );
+        let relay_response = match relay_result {
+            Ok(response) => Some(response),
+            Err(e) if !builders.is_empty() => {
+                warn!(
+                    "Relay didn't take the bundle, builders {:?} did: {}",
+                    builders, e
+                );
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        Ok((relay_response, builders))
+    }
+
+    async fn submit_to_relay(
+        &self,
+        bundle: &SendBundleRequest,
+        chain_profile: &ChainProfile,
+        expected_profit: U256,
+    ) -> Result<SendBundleResponse, ProfitoError> {
+        let deadline = Instant::now() + chain_profile.block_time();
+        loop {
+            let Some(relay) = self.budget.acquire(expected_profit, deadline).await else {
+                return Err(ProfitoError::Submission(format!(
+                    "No relay had room for the bundle within {}s",
+                    chain_profile.block_time().as_secs()
+                )));
+            };
+            let url = &self.clients[relay].0;
+            let client = &*self
+                .get_client(relay)
+                .map_err(|e| ProfitoError::Submission(e.to_string()))?;
+            info!("Sending bundle to {}: {:?}", url, bundle);
+            match MevApiClient::send_bundle(client, bundle.clone()).await {
+                Ok(response) => {
+                    self.budget.report_accepted(relay);
+                    return Ok(response);
+                }
+                Err(e) if is_rate_limited(&e) => {
+                    warn!("Bundle throttled by {}, trying again: {}", url, e);
+                    self.budget.report_throttled(relay);
+                }
+                Err(e) => {
+                    return Err(ProfitoError::Submission(format!(
+                        "Error on send_bundle to {}: {}",
+                        url, e
+                    )))
+                }
+            }
         }
     }
 }
@@ -0,0 +1,106 @@
+use alloy::primitives::U256;
+use once_cell::sync::Lazy;
+use overlord_shared::common::BaseCurrency;
+use tracing::{info, warn};
+
+/// What profit thresholds and reports are expressed in: usd (the default) or weth
+pub const PROFIT_DENOMINATION_ENV: &str = "PROFITO_PROFIT_DENOMINATION";
+/// Least net profit a pair needs to be liquidated, in PROFIT_DENOMINATION_ENV units
+pub const MIN_PROFIT_ENV: &str = "PROFITO_MIN_PROFIT";
+// WETH has 18 decimals on every chain profito runs on
+const WETH_UNIT: u64 = 1_000_000_000_000_000_000;
+
+pub static PROFIT_POLICY: Lazy<ProfitPolicy> = Lazy::new(ProfitPolicy::from_env);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfitDenomination {
+    Usd,
+    Weth,
+}
+
+/// How much profit is worth liquidating for, and the unit it's reported in. Operators can think
+/// in ETH instead of the oracle's USD, in which case amounts are converted with the WETH price the
+/// pair was evaluated with, so thresholds follow the live ETH feed.
+pub struct ProfitPolicy {
+    denomination: ProfitDenomination,
+    min_profit: f64,
+}
+
+impl ProfitPolicy {
+    pub fn from_env() -> Self {
+        let denomination = match std::env::var(PROFIT_DENOMINATION_ENV) {
+            Ok(value) if value.eq_ignore_ascii_case("weth") => ProfitDenomination::Weth,
+            Ok(value) if value.eq_ignore_ascii_case("usd") => ProfitDenomination::Usd,
+            Ok(value) => {
+                warn!(
+                    "Invalid {} value {}, profits are in USD",
+                    PROFIT_DENOMINATION_ENV, value
+                );
+                ProfitDenomination::Usd
+            }
+            Err(_) => ProfitDenomination::Usd,
+        };
+        let min_profit = match std::env::var(MIN_PROFIT_ENV) {
+            Ok(value) => match value.parse::<f64>() {
+                Ok(min_profit) if min_profit.is_finite() && min_profit >= 0.0 => min_profit,
+                Ok(_) | Err(_) => {
+                    warn!(
+                        "Invalid {} value {}, any profit is enough",
+                        MIN_PROFIT_ENV, value
+                    );
+                    0.0
+                }
+            },
+            Err(_) => 0.0,
+        };
+        let policy = Self {
+            denomination,
+            min_profit,
+        };
+        info!(
+            "Profits are in {:?}, pairs need at least {}",
+            denomination,
+            policy.format(min_profit)
+        );
+        policy
+    }
+
+    /// `amount` of base currency in the policy's denomination. `weth_price` is the price of WETH
+    /// in base currency the amount was priced with.
+    pub fn denominate(&self, base_currency: &BaseCurrency, amount: U256, weth_price: U256) -> f64 {
+        match self.denomination {
+            ProfitDenomination::Usd => base_currency.to_usd(amount),
+            ProfitDenomination::Weth if weth_price == U256::ZERO => f64::NAN,
+            ProfitDenomination::Weth => {
+                base_currency.to_f64(amount) / base_currency.to_f64(weth_price)
+            }
+        }
+    }
+
+    /// The minimum profit in base units, None if it can't be converted (e.g. without a WETH price)
+    pub fn min_profit_in_base_units(
+        &self,
+        base_currency: &BaseCurrency,
+        weth_price: U256,
+    ) -> Option<U256> {
+        if self.min_profit == 0.0 {
+            return Some(U256::ZERO);
+        }
+        match self.denomination {
+            ProfitDenomination::Usd => base_currency.usd_to_base_units(self.min_profit).ok(),
+            ProfitDenomination::Weth if weth_price == U256::ZERO => None,
+            ProfitDenomination::Weth => {
+                let min_profit_in_wei = U256::try_from(self.min_profit * WETH_UNIT as f64).ok()?;
+                Some(min_profit_in_wei.saturating_mul(weth_price) / U256::from(WETH_UNIT))
+            }
+        }
+    }
+
+    /// Formats an amount already in the policy's denomination for logging
+    pub fn format(&self, amount: f64) -> String {
+        match self.denomination {
+            ProfitDenomination::Usd => format!("${:.2}", amount),
+            ProfitDenomination::Weth => format!("{:.5} WETH", amount),
+        }
+    }
+}
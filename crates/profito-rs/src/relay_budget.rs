@@ -0,0 +1,218 @@
+use alloy::primitives::U256;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::{sleep, Instant};
+use tracing::{info, warn};
+
+/// Comma separated relays bundles are submitted to, in order of preference. Defaults to the
+/// Flashbots relay.
+pub const RELAY_URLS_ENV: &str = "PROFITO_RELAY_URLS";
+/// Most submissions a single relay gets per second, 10 by default
+pub const RELAY_MAX_REQUESTS_PER_SECOND_ENV: &str = "PROFITO_RELAY_MAX_REQUESTS_PER_SECOND";
+const DEFAULT_RELAY_URL: &str = "https://relay.flashbots.net:443";
+const DEFAULT_MAX_REQUESTS_PER_SECOND: usize = 10;
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+// A relay that throttles us is left alone for this long, doubled every time it happens again
+// before it accepts a bundle
+const MIN_THROTTLE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_THROTTLE_BACKOFF: Duration = Duration::from_secs(60);
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Submissions waiting for a relay, most profitable first (and first come, first served among
+/// equals)
+type QueueKey = (Reverse<U256>, u64);
+
+struct RelayState {
+    url: String,
+    /// When the submissions of the last RATE_WINDOW were sent
+    sent: VecDeque<Instant>,
+    throttled_until: Option<Instant>,
+    backoff: Duration,
+    submitted: u64,
+    throttled: u64,
+}
+
+impl RelayState {
+    fn has_room(&mut self, now: Instant, max_requests_per_second: usize) -> bool {
+        while self
+            .sent
+            .front()
+            .is_some_and(|sent| now.duration_since(*sent) >= RATE_WINDOW)
+        {
+            self.sent.pop_front();
+        }
+        self.throttled_until.is_none_or(|until| until <= now)
+            && self.sent.len() < max_requests_per_second
+    }
+}
+
+struct BudgetState {
+    relays: Vec<RelayState>,
+    queue: BTreeSet<QueueKey>,
+    next_ticket: u64,
+}
+
+/// Keeps submissions within what the relays are willing to take. Each relay gets at most
+/// RELAY_MAX_REQUESTS_PER_SECOND_ENV submissions per second, and one that answers with a 429 is
+/// left alone for a while, so the next submissions go to the other relays. When none has room,
+/// submissions wait in a queue, and the most profitable ones are sent first.
+pub struct RelayBudget {
+    max_requests_per_second: usize,
+    state: Mutex<BudgetState>,
+}
+
+/// Takes a submission out of the queue when it stops waiting, whether it got a relay or not
+struct QueueGuard<'a> {
+    budget: &'a RelayBudget,
+    key: QueueKey,
+}
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.budget.lock().queue.remove(&self.key);
+    }
+}
+
+impl RelayBudget {
+    pub fn from_env() -> Self {
+        let urls: Vec<String> = std::env::var(RELAY_URLS_ENV)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let urls = if urls.is_empty() {
+            vec![DEFAULT_RELAY_URL.to_string()]
+        } else {
+            urls
+        };
+        let max_requests_per_second = match std::env::var(RELAY_MAX_REQUESTS_PER_SECOND_ENV) {
+            Ok(value) => match value.parse::<usize>() {
+                Ok(max) if max > 0 => max,
+                Ok(_) | Err(_) => {
+                    warn!(
+                        "Invalid {} value {}, using {}",
+                        RELAY_MAX_REQUESTS_PER_SECOND_ENV, value, DEFAULT_MAX_REQUESTS_PER_SECOND
+                    );
+                    DEFAULT_MAX_REQUESTS_PER_SECOND
+                }
+            },
+            Err(_) => DEFAULT_MAX_REQUESTS_PER_SECOND,
+        };
+        info!(
+            "Bundles will be submitted to {} (at most {} per second each)",
+            urls.join(", "),
+            max_requests_per_second
+        );
+        Self {
+            max_requests_per_second,
+            state: Mutex::new(BudgetState {
+                relays: urls
+                    .into_iter()
+                    .map(|url| RelayState {
+                        url,
+                        sent: VecDeque::new(),
+                        throttled_until: None,
+                        backoff: MIN_THROTTLE_BACKOFF,
+                        submitted: 0,
+                        throttled: 0,
+                    })
+                    .collect(),
+                queue: BTreeSet::new(),
+                next_ticket: 0,
+            }),
+        }
+    }
+
+    pub fn relay_urls(&self) -> Vec<String> {
+        self.lock()
+            .relays
+            .iter()
+            .map(|relay| relay.url.clone())
+            .collect()
+    }
+
+    /// Waits until a relay has room for a submission of a bundle expected to make
+    /// `expected_profit`, and returns its index. Relays are tried in the order they're configured.
+    /// None if no relay had room before `deadline`.
+    pub async fn acquire(&self, expected_profit: U256, deadline: Instant) -> Option<usize> {
+        let guard = {
+            let mut state = self.lock();
+            let key = (Reverse(expected_profit), state.next_ticket);
+            state.next_ticket += 1;
+            state.queue.insert(key);
+            QueueGuard { budget: self, key }
+        };
+        loop {
+            {
+                let mut state = self.lock();
+                let now = Instant::now();
+                if state.queue.first() == Some(&guard.key) {
+                    let max_requests_per_second = self.max_requests_per_second;
+                    if let Some((index, relay)) = state
+                        .relays
+                        .iter_mut()
+                        .enumerate()
+                        .find(|(_, relay)| relay.has_room(now, max_requests_per_second))
+                    {
+                        relay.sent.push_back(now);
+                        relay.submitted += 1;
+                        return Some(index);
+                    }
+                }
+                if now >= deadline {
+                    return None;
+                }
+            }
+            sleep(QUEUE_POLL_INTERVAL).await;
+        }
+    }
+
+    /// The relay at `index` answered with a 429. It's left alone for a while, twice as long as
+    /// the last time if it didn't accept anything since.
+    pub fn report_throttled(&self, index: usize) {
+        let mut state = self.lock();
+        let waiting = state.queue.len();
+        let Some(relay) = state.relays.get_mut(index) else {
+            return;
+        };
+        relay.throttled += 1;
+        relay.throttled_until = Some(Instant::now() + relay.backoff);
+        warn!(
+            "Relay {} is throttling submissions ({} of {} throttled), leaving it alone for {}s \
+             ({} submissions waiting)",
+            relay.url,
+            relay.throttled,
+            relay.submitted,
+            relay.backoff.as_secs(),
+            waiting
+        );
+        relay.backoff = (relay.backoff * 2).min(MAX_THROTTLE_BACKOFF);
+    }
+
+    /// The relay at `index` took a bundle, so it's no longer backing off
+    pub fn report_accepted(&self, index: usize) {
+        if let Some(relay) = self.lock().relays.get_mut(index) {
+            relay.backoff = MIN_THROTTLE_BACKOFF;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BudgetState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// True if a relay turned a submission down for going over its rate limit. The client doesn't
+/// expose the status code of a failed request, only its message.
+pub fn is_rate_limited(error: &impl std::fmt::Display) -> bool {
+    let message = error.to_string().to_lowercase();
+    message.contains("429") || message.contains("too many requests")
+}
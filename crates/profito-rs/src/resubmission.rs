@@ -0,0 +1,73 @@
+use alloy::{
+    primitives::{Address, U256},
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+};
+use overlord_shared::{error::NodeError, market::AaveMarket, sol_bindings::pool::AaveV3Pool};
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::error::ProfitoError;
+use crate::mev_share_service::SubmittedBundle;
+
+/// Blocks after its inclusion block a bundle that didn't land is submitted again for, one block
+/// at a time, while it's still valid (see invalidation()). 0 only targets the inclusion block.
+/// Defaults to the blocks within the bundle validity (5 on mainnet).
+pub const RESUBMIT_BLOCKS_ENV: &str = "PROFITO_RESUBMIT_BLOCKS";
+
+const ONE_HF: U256 = U256::from_limbs([1_000_000_000_000_000_000, 0, 0, 0]);
+
+/// None if RESUBMIT_BLOCKS_ENV isn't set (or is invalid), in which case the default applies
+pub fn resubmit_blocks_from_env() -> Option<u64> {
+    let value = std::env::var(RESUBMIT_BLOCKS_ENV).ok()?;
+    match value.parse::<u64>() {
+        Ok(blocks) => Some(blocks),
+        Err(_) => {
+            warn!(
+                "Invalid {} value {}, using the default",
+                RESUBMIT_BLOCKS_ENV, value
+            );
+            None
+        }
+    }
+}
+
+/// Why `submitted` isn't worth submitting again for `user` of `market`, None if it still is:
+/// - the price update it backruns landed without it, so the bundle would revert
+/// - the user has no debt left, someone else liquidated it or it was repaid
+/// - the user isn't underwater anymore, for bundles that don't land a price update. The others
+///   only liquidate once their update lands, which their simulation at the new block checks.
+pub async fn invalidation(
+    provider: &Arc<RootProvider<PubSubFrontend>>,
+    submitted: &SubmittedBundle,
+    market: &AaveMarket,
+    user: Address,
+) -> Result<Option<String>, ProfitoError> {
+    if let Some(price_update_hash) = submitted.price_update_hash {
+        let receipt = provider
+            .get_transaction_receipt(price_update_hash)
+            .await
+            .map_err(|e| NodeError::rpc("eth_getTransactionReceipt", e))?;
+        if let Some(block_number) = receipt.and_then(|receipt| receipt.block_number) {
+            return Ok(Some(format!(
+                "price update {} landed without it in block {}",
+                price_update_hash, block_number
+            )));
+        }
+    }
+    let account_data = AaveV3Pool::new(market.pool, provider.clone())
+        .getUserAccountData(user)
+        .call()
+        .await
+        .map_err(|e| NodeError::call("getUserAccountData", e))?;
+    if account_data.totalDebtBase.is_zero() {
+        return Ok(Some(format!("{} has no debt left", user)));
+    }
+    if !submitted.price_update_included && account_data.healthFactor >= ONE_HF {
+        return Ok(Some(format!(
+            "{} isn't underwater anymore (HF {})",
+            user, account_data.healthFactor
+        )));
+    }
+    Ok(None)
+}
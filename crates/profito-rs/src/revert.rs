@@ -0,0 +1,257 @@
+use alloy::{
+    primitives::{Bytes, FixedBytes},
+    sol_types::{Panic, Revert, SolError},
+    transports::TransportError,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, sync::Mutex, time::Duration};
+use tracing::{info, warn};
+
+// Reasons listed by spawn_revert_summary()
+const REVERT_SUMMARY_SIZE: usize = 5;
+
+/// Aave v3 `Errors.sol` codes a liquidation (or the flash loan funding it) can run into
+const AAVE_ERRORS: &[(u16, &str)] = &[
+    (13, "INVALID_FLASHLOAN_EXECUTOR_RETURN"),
+    (26, "INVALID_AMOUNT"),
+    (27, "RESERVE_INACTIVE"),
+    (28, "RESERVE_FROZEN"),
+    (29, "RESERVE_PAUSED"),
+    (32, "NOT_ENOUGH_AVAILABLE_USER_BALANCE"),
+    (34, "COLLATERAL_BALANCE_IS_ZERO"),
+    (43, "UNDERLYING_BALANCE_ZERO"),
+    (45, "HEALTH_FACTOR_NOT_BELOW_THRESHOLD"),
+    (46, "COLLATERAL_CANNOT_BE_LIQUIDATED"),
+    (47, "SPECIFIED_CURRENCY_NOT_BORROWED_BY_USER"),
+    (48, "SAME_BLOCK_BORROW_REPAY"),
+    (49, "INCONSISTENT_FLASHLOAN_PARAMS"),
+    (58, "INCONSISTENT_EMODE_CATEGORY"),
+    (59, "PRICE_ORACLE_SENTINEL_CHECK_FAILED"),
+    (91, "FLASHLOAN_DISABLED"),
+    (97, "LIQUIDATION_GRACE_SENTINEL_CHECK_FAILED"),
+    (103, "MUST_NOT_LEAVE_DUST"),
+];
+
+/// Revert strings of the Uniswap v3 pools, their TransferHelper and the SwapRouter
+const UNISWAP_ERRORS: &[(&str, &str)] = &[
+    ("LOK", "pool locked"),
+    ("AS", "zero amount specified"),
+    ("SPL", "sqrt price limit out of range"),
+    ("IIA", "insufficient input amount"),
+    ("L", "zero liquidity"),
+    ("LS", "liquidity underflow"),
+    ("LA", "liquidity overflow"),
+    ("F0", "flash fee 0 not paid"),
+    ("F1", "flash fee 1 not paid"),
+    ("STF", "safeTransferFrom failed"),
+    ("ST", "safeTransfer failed"),
+    ("SA", "safeApprove failed"),
+    ("STE", "safeTransferETH failed"),
+    ("Too little received", "too little received"),
+    ("Too much requested", "too much requested"),
+    ("Transaction too old", "deadline passed"),
+];
+
+/// Why a call reverted, decoded from its revert data so reverts can be told apart (and counted)
+/// by cause instead of by error string.
+///
+/// Foxdie's ABI doesn't declare custom errors, so its custom errors (and any other contract's)
+/// are classified by selector.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RevertReason {
+    /// `Error(string)` with an Aave error code
+    Aave { code: u16 },
+    /// `Error(string)` with one of UNISWAP_ERRORS
+    Uniswap { code: String },
+    /// `Error(string)` with a Balancer `BAL#` code
+    Balancer { code: u16 },
+    /// Any other `Error(string)`
+    Message { message: String },
+    /// `Panic(uint256)`, e.g. 0x11 for an arithmetic overflow
+    Panic { code: u64 },
+    /// A custom error, with its arguments left encoded
+    CustomError {
+        selector: FixedBytes<4>,
+        data: Bytes,
+    },
+    /// Reverted without data, e.g. a bare `revert()` or running out of gas
+    Empty,
+    /// Data that doesn't even have a selector
+    Unknown { data: Bytes },
+}
+
+impl RevertReason {
+    pub fn decode(data: &[u8]) -> Self {
+        if data.is_empty() {
+            return RevertReason::Empty;
+        }
+        if data.len() < 4 {
+            return RevertReason::Unknown {
+                data: Bytes::copy_from_slice(data),
+            };
+        }
+        if data[..4] == Revert::SELECTOR {
+            if let Ok(revert) = Revert::abi_decode(data, true) {
+                return Self::from_message(revert.reason);
+            }
+        }
+        if data[..4] == Panic::SELECTOR {
+            if let Ok(panic) = Panic::abi_decode(data, true) {
+                return RevertReason::Panic {
+                    code: panic.code.saturating_to(),
+                };
+            }
+        }
+        RevertReason::CustomError {
+            selector: FixedBytes::from_slice(&data[..4]),
+            data: Bytes::copy_from_slice(&data[4..]),
+        }
+    }
+
+    /// Decodes the revert data of a failed eth_call or eth_estimateGas. None if the error isn't a
+    /// revert, e.g. the node couldn't be reached.
+    pub fn from_transport_error(error: &TransportError) -> Option<Self> {
+        error
+            .as_error_resp()
+            .and_then(|payload| payload.as_revert_data())
+            .map(|data| Self::decode(&data))
+    }
+
+    pub fn from_contract_error(error: &alloy::contract::Error) -> Option<Self> {
+        match error {
+            alloy::contract::Error::TransportError(e) => Self::from_transport_error(e),
+            _ => None,
+        }
+    }
+
+    /// Decodes the error of a failed bundle simulation, either revert data as hex or the revert
+    /// string (with or without the node's "execution reverted" prefix)
+    pub fn from_simulation_error(error: &str) -> Self {
+        let error = error.trim().trim_matches('"');
+        if let Some(data) = error
+            .strip_prefix("0x")
+            .and_then(|hex| alloy::hex::decode(hex).ok())
+        {
+            return Self::decode(&data);
+        }
+        let message = error
+            .strip_prefix("execution reverted")
+            .map(|message| message.trim_start_matches(':').trim())
+            .unwrap_or(error);
+        if message.is_empty() {
+            return RevertReason::Empty;
+        }
+        Self::from_message(message.to_string())
+    }
+
+    fn from_message(message: String) -> Self {
+        if let Ok(code) = message.parse::<u16>() {
+            return RevertReason::Aave { code };
+        }
+        if let Some(code) = message
+            .strip_prefix("BAL#")
+            .and_then(|code| code.parse::<u16>().ok())
+        {
+            return RevertReason::Balancer { code };
+        }
+        if UNISWAP_ERRORS.iter().any(|(code, _)| *code == message) {
+            return RevertReason::Uniswap { code: message };
+        }
+        RevertReason::Message { message }
+    }
+
+    /// Who the revert is attributed to, for aggregation
+    pub fn category(&self) -> &'static str {
+        match self {
+            RevertReason::Aave { .. } => "aave",
+            RevertReason::Uniswap { .. } => "uniswap",
+            RevertReason::Balancer { .. } => "balancer",
+            RevertReason::Message { .. } => "message",
+            RevertReason::Panic { .. } => "panic",
+            RevertReason::CustomError { .. } => "custom",
+            RevertReason::Empty | RevertReason::Unknown { .. } => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for RevertReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RevertReason::Aave { code } => match AAVE_ERRORS.iter().find(|(c, _)| c == code) {
+                Some((_, name)) => {
+                    write!(f, "{} ({})", name.to_lowercase().replace('_', " "), code)
+                }
+                None => write!(f, "error {}", code),
+            },
+            RevertReason::Uniswap { code } => {
+                let description = UNISWAP_ERRORS
+                    .iter()
+                    .find(|(c, _)| c == code)
+                    .map_or("unknown", |(_, description)| description);
+                write!(f, "{} ({})", description, code)
+            }
+            RevertReason::Balancer { code } => write!(f, "BAL#{}", code),
+            RevertReason::Message { message } => write!(f, "{}", message),
+            RevertReason::Panic { code } => write!(f, "code 0x{:02x}", code),
+            RevertReason::CustomError { selector, .. } => write!(f, "selector {}", selector),
+            RevertReason::Empty => write!(f, "reverted without data"),
+            RevertReason::Unknown { data } => write!(f, "revert data {}", data),
+        }
+    }
+}
+
+/// How many times each reason has been seen since startup
+static REVERTS_BY_REASON: Lazy<Mutex<HashMap<RevertReason, u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Logs the revert of the opportunity identified by `trace_id` along with how many times the same
+/// reason was seen, so recurring causes stand out. Returns that count.
+pub fn record_revert(trace_id: &str, reason: &RevertReason) -> u64 {
+    let mut reverts = REVERTS_BY_REASON.lock().unwrap();
+    let count = reverts.entry(reason.clone()).or_insert(0);
+    *count += 1;
+    warn!(
+        "REVERT | {} | [{}] {} (seen {} times)",
+        trace_id,
+        reason.category(),
+        reason,
+        count
+    );
+    *count
+}
+
+/// Revert counts since startup, most frequent first
+pub fn revert_summary() -> Vec<(RevertReason, u64)> {
+    let mut summary = REVERTS_BY_REASON
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(reason, count)| (reason.clone(), *count))
+        .collect::<Vec<_>>();
+    summary.sort_by(|a, b| b.1.cmp(&a.1));
+    summary
+}
+
+/// Logs the most frequent revert reasons since startup every `interval`, if there was any
+pub fn spawn_revert_summary(interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes right away
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let summary = revert_summary();
+            if summary.is_empty() {
+                continue;
+            }
+            let top = summary
+                .iter()
+                .take(REVERT_SUMMARY_SIZE)
+                .map(|(reason, count)| format!("[{}] {}: {}", reason.category(), reason, count))
+                .collect::<Vec<_>>();
+            info!("Most frequent reverts since startup: {}", top.join(", "));
+        }
+    });
+}
@@ -0,0 +1,231 @@
+use alloy::primitives::U256;
+use ethers_core::types::Bytes;
+use ethers_signers::LocalWallet;
+use jsonrpsee::{core::client::ClientT, rpc_params};
+use mev_share::rpc::{MevApiClient, SendBundleRequest, SimBundleOverrides};
+use once_cell::sync::OnceCell;
+use tracing::{info, warn};
+
+use crate::builders::BundleMethod;
+use crate::error::SimulationError;
+use crate::mev_share_service::{build_signed_client, MevShareClient};
+
+/// Whether bundles are simulated before being submitted: true (the default) or false
+pub const SIMULATE_BUNDLES_ENV: &str = "PROFITO_SIMULATE_BUNDLES";
+/// Where bundles are simulated, with mev_simBundle unless the URL is prefixed with `eth:`, in
+/// which case it's eth_callBundle. Defaults to the Flashbots relay.
+pub const SIMULATION_URL_ENV: &str = "PROFITO_SIMULATION_URL";
+const DEFAULT_SIMULATION_URL: &str = "https://relay.flashbots.net:443";
+const BPS_DENOMINATOR: u128 = 10_000;
+// WETH has 18 decimals on every chain profito runs on
+const WETH_UNIT: u64 = 1_000_000_000_000_000_000;
+
+/// What a bundle has to make in its simulation to be submitted
+#[derive(Clone, Copy, Debug)]
+pub struct ProfitCheck {
+    /// Share of the profit Foxdie pays the builder
+    pub bribe_bps: u16,
+    /// Least profit left after the bribe, None if it couldn't be converted to wei
+    pub min_profit_wei: Option<u128>,
+    /// Priority fee per gas the builder gets on top of the bribe, taken out of what
+    /// mev_simBundle reports it's paid
+    pub priority_fee_wei: u128,
+}
+
+impl ProfitCheck {
+    /// Simulations report what the builder is paid in wei, so `min_profit` (in base units) is
+    /// converted with `weth_price`, the price of WETH in base units the pair was evaluated with
+    pub fn new(
+        bribe_bps: u16,
+        min_profit: Option<U256>,
+        weth_price: U256,
+        priority_fee_wei: u128,
+    ) -> Self {
+        let min_profit_wei = match min_profit {
+            Some(min_profit) if weth_price > U256::ZERO => {
+                u128::try_from(min_profit * U256::from(WETH_UNIT) / weth_price).ok()
+            }
+            Some(_) | None => None,
+        };
+        Self {
+            bribe_bps,
+            min_profit_wei,
+            priority_fee_wei,
+        }
+    }
+}
+
+/// How a bundle did in its simulation
+#[derive(Clone, Copy, Debug)]
+pub struct SimulationReport {
+    pub block: u64,
+    pub gas_used: u64,
+    /// Paid to the builder
+    pub bribe_wei: u128,
+    /// Left for Foxdie after the bribe, worked out from the bribe and its share of the profit.
+    /// None if the bribe is 0 bps.
+    pub kept_wei: Option<u128>,
+}
+
+/// Simulates bundles at their target block before they're submitted, so ones that would revert
+/// (or make too little once the bribe is paid) don't cost the searcher reputation, and encoding
+/// bugs show up as failed simulations instead of bundles that never land
+pub struct BundleSimulator {
+    signer: LocalWallet,
+    url: String,
+    method: BundleMethod,
+    /// Created the first time it's used
+    client: OnceCell<MevShareClient>,
+}
+
+impl BundleSimulator {
+    /// None if simulations are turned off
+    pub fn from_env(signer: LocalWallet) -> Option<Self> {
+        let enabled = match std::env::var(SIMULATE_BUNDLES_ENV) {
+            Ok(value) => match value.parse::<bool>() {
+                Ok(enabled) => enabled,
+                Err(_) => {
+                    warn!(
+                        "Invalid {} value {}, bundles are simulated",
+                        SIMULATE_BUNDLES_ENV, value
+                    );
+                    true
+                }
+            },
+            Err(_) => true,
+        };
+        if !enabled {
+            warn!("Bundles are submitted without simulating them first");
+            return None;
+        }
+        let url = std::env::var(SIMULATION_URL_ENV)
+            .unwrap_or_else(|_| DEFAULT_SIMULATION_URL.to_string());
+        let (method, url) = match url.strip_prefix("eth:") {
+            Some(url) => (BundleMethod::EthSendBundle, url.to_string()),
+            None => (
+                BundleMethod::MevSendBundle,
+                url.strip_prefix("mev:").unwrap_or(&url).to_string(),
+            ),
+        };
+        let call = match method {
+            BundleMethod::EthSendBundle => "eth_callBundle",
+            BundleMethod::MevSendBundle => "mev_simBundle",
+        };
+        info!("Bundles are simulated with {} at {}", call, url);
+        Some(Self {
+            signer,
+            url,
+            method,
+            client: OnceCell::new(),
+        })
+    }
+
+    /// Simulates `bundle` at `block`, failing if it reverts or leaves less than `check` asks for.
+    /// `raw_txs` is the body of the bundle for eth_callBundle, None if it has txs only known by
+    /// their hash.
+    pub async fn simulate(
+        &self,
+        bundle: &SendBundleRequest,
+        raw_txs: Option<&[Bytes]>,
+        block: u64,
+        check: ProfitCheck,
+    ) -> Result<SimulationReport, SimulationError> {
+        let client = self
+            .client
+            .get_or_try_init(|| build_signed_client(&self.signer, &self.url))
+            .map_err(|e| SimulationError::Request(e.to_string()))?;
+        let (gas_used, bribe_wei) = match self.method {
+            BundleMethod::MevSendBundle => {
+                let response = client
+                    .sim_bundle(bundle.clone(), SimBundleOverrides::default())
+                    .await
+                    .map_err(|e| SimulationError::Request(e.to_string()))?;
+                if !response.success {
+                    return Err(SimulationError::Reverted {
+                        block,
+                        reason: response.error.unwrap_or_else(|| "unknown".to_string()),
+                    });
+                }
+                let gas_used = u64::try_from(response.gas_used).map_err(|_| {
+                    SimulationError::Request(format!("Gas used {} out of range", response.gas_used))
+                })?;
+                // The builder's profit, which has the gas fees of the bundle on top of the bribe
+                let profit = u128::try_from(response.profit).map_err(|_| {
+                    SimulationError::Request(format!("Profit {} out of range", response.profit))
+                })?;
+                let gas_fees = (gas_used as u128).saturating_mul(check.priority_fee_wei);
+                (gas_used, profit.saturating_sub(gas_fees))
+            }
+            BundleMethod::EthSendBundle => {
+                let raw_txs = raw_txs.ok_or_else(|| {
+                    SimulationError::Request(
+                        "eth_callBundle can't simulate a tx only known by its hash".to_string(),
+                    )
+                })?;
+                let txs: Vec<String> = raw_txs.iter().map(|tx| tx.to_string()).collect();
+                let params = serde_json::json!({
+                    "txs": txs,
+                    "blockNumber": format!("{:#x}", block),
+                    "stateBlockNumber": "latest",
+                });
+                let response: serde_json::Value = client
+                    .request("eth_callBundle", rpc_params![params])
+                    .await
+                    .map_err(|e| SimulationError::Request(e.to_string()))?;
+                parse_call_bundle(&response, block)?
+            }
+        };
+        let kept_wei = (check.bribe_bps > 0).then(|| {
+            bribe_wei * (BPS_DENOMINATOR - check.bribe_bps as u128) / check.bribe_bps as u128
+        });
+        if let (Some(kept_wei), Some(min_profit_wei)) = (kept_wei, check.min_profit_wei) {
+            if bribe_wei == 0 || kept_wei < min_profit_wei {
+                return Err(SimulationError::Unprofitable {
+                    bribe_wei,
+                    kept_wei,
+                    min_profit_wei,
+                });
+            }
+        }
+        Ok(SimulationReport {
+            block,
+            gas_used,
+            bribe_wei,
+            kept_wei,
+        })
+    }
+}
+
+/// Gas used and ETH sent to the builder by an eth_callBundle simulation that succeeded
+fn parse_call_bundle(
+    response: &serde_json::Value,
+    block: u64,
+) -> Result<(u64, u128), SimulationError> {
+    let reverted = response
+        .get("results")
+        .and_then(|results| results.as_array())
+        .into_iter()
+        .flatten()
+        .find_map(|result| {
+            result
+                .get("error")
+                .or_else(|| result.get("revert"))
+                .map(|reason| reason.to_string())
+        });
+    if let Some(reason) = reverted {
+        return Err(SimulationError::Reverted { block, reason });
+    }
+    let gas_used = response
+        .get("totalGasUsed")
+        .and_then(|gas| gas.as_u64())
+        .unwrap_or_default();
+    // Wei amounts are decimal strings
+    let bribe_wei = response
+        .get("ethSentToCoinbase")
+        .and_then(|sent| sent.as_str())
+        .and_then(|sent| sent.parse().ok())
+        .ok_or_else(|| {
+            SimulationError::Request(format!("Response has no ethSentToCoinbase: {}", response))
+        })?;
+    Ok((gas_used, bribe_wei))
+}
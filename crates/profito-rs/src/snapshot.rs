@@ -0,0 +1,291 @@
+use alloy::{
+    eips::BlockId,
+    primitives::{Address, Bytes, U256},
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+};
+use once_cell::sync::Lazy;
+use overlord_shared::{
+    common::UserEModeCategory,
+    market::AaveMarket,
+    sol_bindings::{
+        pool::AaveV3Pool,
+        AaveOracle,
+        IUiPoolDataProviderV3::{AggregatedReserveData, UserReserveData},
+    },
+    UnderwaterUserEvent,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::warn;
+
+use super::cache::PriceCache;
+
+/// Directory where simulation snapshots are written. Defaults to DEFAULT_SNAPSHOT_DIR.
+pub const SNAPSHOT_DIR_ENV: &str = "PROFITO_SNAPSHOT_DIR";
+const DEFAULT_SNAPSHOT_DIR: &str = "./sim-snapshots";
+
+/// Health factors that differ by more than this (in basis points) are considered a divergence,
+/// unless HF_DIVERGENCE_BPS_ENV says otherwise
+pub const HF_DIVERGENCE_BPS: u64 = 10; // 0.1%
+pub const HF_DIVERGENCE_BPS_ENV: &str = "PROFITO_HF_DIVERGENCE_BPS";
+
+static HF_DIVERGENCE_TOLERANCE_BPS: Lazy<u64> = Lazy::new(|| {
+    let Ok(value) = std::env::var(HF_DIVERGENCE_BPS_ENV) else {
+        return HF_DIVERGENCE_BPS;
+    };
+    value.parse().unwrap_or_else(|e| {
+        warn!(
+            "Invalid {} value {}, using {} bps: {}",
+            HF_DIVERGENCE_BPS_ENV, value, HF_DIVERGENCE_BPS, e
+        );
+        HF_DIVERGENCE_BPS
+    })
+});
+
+const ONE_HF: U256 = U256::from_limbs([1_000_000_000_000_000_000, 0, 0, 0]);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotUserReserve {
+    pub underlying_asset: Address,
+    pub scaled_a_token_balance: U256,
+    pub usage_as_collateral_enabled: bool,
+    pub scaled_variable_debt: U256,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotReserve {
+    pub underlying_asset: Address,
+    pub symbol: String,
+    pub decimals: U256,
+    pub liquidation_threshold: U256,
+    pub liquidation_bonus: U256,
+    pub liquidity_index: U256,
+    pub variable_borrow_index: U256,
+    pub a_token: Address,
+    pub variable_debt_token: Address,
+    pub virtual_acc_active: bool,
+}
+
+/// Account data as (total collateral, total debt, health factor)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotAccountData {
+    pub total_collateral_base: U256,
+    pub total_debt_base: U256,
+    pub health_factor: U256,
+}
+
+/// Everything profito used to evaluate an underwater user, so the exact same calculation can be
+/// re-run later against an archive fork with `profito-cli replay-sim`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SimulationSnapshot {
+    pub trace_id: String,
+    /// Empty for snapshots written before runs had an id
+    #[serde(default)]
+    pub run_id: String,
+    pub tx_hash: Option<String>,
+    pub raw_tx: Option<Bytes>,
+    pub inclusion_block: String,
+    /// Block profito was reading state from when it evaluated the user
+    pub block_number: u64,
+    /// Market the user was found underwater in. The core market for snapshots written before
+    /// other markets were followed.
+    #[serde(default = "AaveMarket::core")]
+    pub market: AaveMarket,
+    pub user: Address,
+    pub user_configuration: U256,
+    pub user_emode_category: u8,
+    /// Prices sent by vega for the pending price update
+    pub new_asset_prices: Vec<(Address, String, U256)>,
+    /// Every price used in the calculation, including the overriden ones
+    pub prices: Vec<(Address, U256)>,
+    pub user_reserves: Vec<SnapshotUserReserve>,
+    pub reserves: Vec<SnapshotReserve>,
+    /// What vega got from getUserAccountData() on the fork with the price update applied
+    pub expected: SnapshotAccountData,
+    /// What profito got from calculate_user_account_data()
+    pub simulated: SnapshotAccountData,
+}
+
+/// Tolerance health_factors_diverge() uses, in basis points
+pub fn hf_divergence_tolerance_bps() -> u64 {
+    *HF_DIVERGENCE_TOLERANCE_BPS
+}
+
+/// True if only one of the health factors is below 1, i.e. only one side would liquidate
+pub fn health_factors_disagree_on_liquidation(expected: U256, simulated: U256) -> bool {
+    (expected < ONE_HF) != (simulated < ONE_HF)
+}
+
+fn health_factor_difference(expected: U256, simulated: U256) -> U256 {
+    if expected > simulated {
+        expected - simulated
+    } else {
+        simulated - expected
+    }
+}
+
+/// How far `simulated` is from `expected`, in basis points of `expected`. U256::MAX if only one
+/// of them has no debt.
+pub fn health_factor_difference_bps(expected: U256, simulated: U256) -> U256 {
+    if expected == simulated {
+        return U256::ZERO;
+    }
+    if expected == U256::ZERO || expected == U256::MAX || simulated == U256::MAX {
+        return U256::MAX;
+    }
+    health_factor_difference(expected, simulated).saturating_mul(U256::from(10000)) / expected
+}
+
+/// True if both health factors land on different sides of 1, or are more than
+/// hf_divergence_tolerance_bps() apart
+pub fn health_factors_diverge(expected: U256, simulated: U256) -> bool {
+    if expected == U256::MAX || simulated == U256::MAX {
+        // No debt on at least one side
+        return expected != simulated;
+    }
+    if health_factors_disagree_on_liquidation(expected, simulated) {
+        return true;
+    }
+    health_factor_difference(expected, simulated).saturating_mul(U256::from(10000))
+        > expected.saturating_mul(U256::from(hf_divergence_tolerance_bps()))
+}
+
+/// Builds the snapshot for an underwater user event. `block_number` must be the block profito
+/// read state from, and `simulated` the output of calculate_user_account_data().
+pub async fn capture_simulation_snapshot(
+    price_cache: Arc<tokio::sync::Mutex<PriceCache>>,
+    aave_oracle: AaveOracle::AaveOracleInstance<PubSubFrontend, Arc<RootProvider<PubSubFrontend>>>,
+    block_number: u64,
+    uw_event: &UnderwaterUserEvent,
+    user_reserves_data: &[UserReserveData],
+    reserves_data: &[AggregatedReserveData],
+    user_emode: &Option<UserEModeCategory>,
+    simulated: (U256, U256, U256),
+) -> Result<SimulationSnapshot, Box<dyn std::error::Error>> {
+    let user_configuration =
+        match AaveV3Pool::new(uw_event.market.pool, aave_oracle.provider().clone())
+            .getUserConfiguration(uw_event.address)
+            .block(BlockId::number(block_number))
+            .call()
+            .await
+        {
+            Ok(user_config) => user_config._0.data,
+            Err(e) => {
+                return Err(format!("Error trying to call getUserConfiguration: {}", e).into())
+            }
+        };
+
+    let mut prices = vec![];
+    for user_reserve in user_reserves_data {
+        let price = match price_cache
+            .lock()
+            .await
+            .get_price(
+                user_reserve.underlyingAsset,
+                Some(uw_event.trace_id.clone()),
+                aave_oracle.clone(),
+                BlockId::number(block_number),
+            )
+            .await
+        {
+            Ok(price) => price,
+            Err(e) => {
+                return Err(format!(
+                    "Error trying to get price for {}: {}",
+                    user_reserve.underlyingAsset, e
+                )
+                .into())
+            }
+        };
+        prices.push((user_reserve.underlyingAsset, price));
+    }
+
+    let user_reserves = user_reserves_data
+        .iter()
+        .map(|reserve| SnapshotUserReserve {
+            underlying_asset: reserve.underlyingAsset,
+            scaled_a_token_balance: reserve.scaledATokenBalance,
+            usage_as_collateral_enabled: reserve.usageAsCollateralEnabledOnUser,
+            scaled_variable_debt: reserve.scaledVariableDebt,
+        })
+        .collect();
+
+    let (total_collateral_base, total_debt_base, health_factor) = simulated;
+    Ok(SimulationSnapshot {
+        trace_id: uw_event.trace_id.clone(),
+        run_id: uw_event.run_id.clone(),
+        tx_hash: uw_event.tx_hash.clone(),
+        raw_tx: uw_event
+            .price_update_tx
+            .as_ref()
+            .and_then(|tx| tx.raw_tx().cloned()),
+        inclusion_block: uw_event.inclusion_block.clone(),
+        block_number,
+        market: uw_event.market.clone(),
+        user: uw_event.address,
+        user_configuration,
+        user_emode_category: user_emode.as_ref().map_or(0, |emode| emode.id),
+        new_asset_prices: uw_event.new_asset_prices.clone(),
+        prices,
+        user_reserves,
+        reserves: reserves_data.iter().map(SnapshotReserve::from).collect(),
+        expected: SnapshotAccountData {
+            total_collateral_base: uw_event.user_account_data.totalCollateralBase,
+            total_debt_base: uw_event.user_account_data.totalDebtBase,
+            health_factor: uw_event.user_account_data.healthFactor,
+        },
+        simulated: SnapshotAccountData {
+            total_collateral_base,
+            total_debt_base,
+            health_factor,
+        },
+    })
+}
+
+impl From<&AggregatedReserveData> for SnapshotReserve {
+    fn from(reserve: &AggregatedReserveData) -> Self {
+        Self {
+            underlying_asset: reserve.underlyingAsset,
+            symbol: reserve.symbol.clone(),
+            decimals: reserve.decimals,
+            liquidation_threshold: reserve.reserveLiquidationThreshold,
+            liquidation_bonus: reserve.reserveLiquidationBonus,
+            liquidity_index: U256::from(reserve.liquidityIndex),
+            variable_borrow_index: U256::from(reserve.variableBorrowIndex),
+            a_token: reserve.aTokenAddress,
+            variable_debt_token: reserve.variableDebtTokenAddress,
+            virtual_acc_active: reserve.virtualAccActive,
+        }
+    }
+}
+
+/// Writes the snapshot as `{run_id}-{user}.json` and returns its path
+pub fn write_simulation_snapshot(
+    snapshot: &SimulationSnapshot,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let dir = PathBuf::from(
+        std::env::var(SNAPSHOT_DIR_ENV).unwrap_or_else(|_| DEFAULT_SNAPSHOT_DIR.to_string()),
+    );
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| format!("Failed to create snapshot dir {}: {}", dir.display(), e))?;
+    let path = dir.join(format!("{}-{}.json", snapshot.run_id, snapshot.user));
+    let contents = serde_json::to_string_pretty(snapshot)
+        .map_err(|e| format!("Failed to serialize snapshot: {}", e))?;
+    std::fs::write(&path, contents)
+        .map_err(|e| format!("Failed to write snapshot {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Reads a snapshot written by write_simulation_snapshot()
+pub fn read_simulation_snapshot(
+    path: &Path,
+) -> Result<SimulationSnapshot, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read snapshot {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents)
+        .map_err(|e| format!("Failed to parse snapshot {}: {}", path.display(), e).into())
+}
@@ -0,0 +1,552 @@
+use alloy::{
+    primitives::{
+        aliases::{U160, U24},
+        Address, Bytes, B256, U256,
+    },
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+    rpc::types::BlockId,
+    sol_types::SolValue,
+};
+use once_cell::sync::Lazy;
+use overlord_shared::{
+    constants::{
+        BALANCER_VAULT, CURVE_META_REGISTRY, UNISWAP_V2_FACTORY, UNISWAP_V2_ROUTER,
+        UNISWAP_V3_QUOTER, WETH,
+    },
+    sol_bindings::{
+        Foxdie, IBalancerVault, ICurveMetaRegistry, ICurvePool, IUniswapV2Factory,
+        IUniswapV2Router, UniswapV3Quoter,
+    },
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tracing::{info, warn};
+
+use crate::{calculations::get_uniswap_v3_pools, error::ProfitoError};
+
+/// Set to 1 to also quote Uniswap V2, Curve and Balancer, and to swap straight from the
+/// collateral to the debt asset. Routes that aren't Uniswap V3 through WETH are sent with Foxdie's
+/// triggerLiquidationWithRoute(), which deployments before it don't have, so it's off by default.
+pub const SWAP_ROUTING_ENV: &str = "PROFITO_SWAP_ROUTING";
+/// Set to 1 to swap straight from the collateral to the debt asset on Uniswap V3 when its pool
+/// of the pair returns more than going through WETH, without quoting the other venues. Implied by
+/// SWAP_ROUTING_ENV, and also needs triggerLiquidationWithRoute().
+pub const DIRECT_SWAPS_ENV: &str = "PROFITO_DIRECT_SWAPS";
+/// Comma separated ids of the Balancer pools routes can swap through. Balancer has no on-chain
+/// registry of pools by token, so only these are quoted.
+pub const BALANCER_POOL_IDS_ENV: &str = "PROFITO_BALANCER_POOL_IDS";
+// queryBatchSwap() kind of the swaps with a fixed amount in
+const BALANCER_GIVEN_IN: u8 = 0;
+
+pub static SWAP_ROUTER: Lazy<SwapRouter> = Lazy::new(SwapRouter::from_env);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapVenue {
+    UniswapV3,
+    UniswapV2,
+    Curve,
+    Balancer,
+}
+
+impl SwapVenue {
+    fn name(&self) -> &'static str {
+        match self {
+            SwapVenue::UniswapV3 => "uniswap_v3",
+            SwapVenue::UniswapV2 => "uniswap_v2",
+            SwapVenue::Curve => "curve",
+            SwapVenue::Balancer => "balancer",
+        }
+    }
+}
+
+impl From<SwapVenue> for Foxdie::SwapVenue {
+    fn from(venue: SwapVenue) -> Self {
+        match venue {
+            SwapVenue::UniswapV3 => Foxdie::SwapVenue::UNISWAP_V3,
+            SwapVenue::UniswapV2 => Foxdie::SwapVenue::UNISWAP_V2,
+            SwapVenue::Curve => Foxdie::SwapVenue::CURVE,
+            SwapVenue::Balancer => Foxdie::SwapVenue::BALANCER,
+        }
+    }
+}
+
+/// One swap of a route, with what it was quoted to return
+#[derive(Clone, Debug)]
+pub struct SwapStep {
+    pub venue: SwapVenue,
+    pub token_in: Address,
+    pub token_out: Address,
+    /// What Foxdie needs to swap on the venue, see Foxdie::SwapStep
+    pub data: Bytes,
+    pub amount_out: U256,
+}
+
+/// Swaps taking the collateral of a liquidation to its debt asset
+#[derive(Clone, Debug, Default)]
+pub struct SwapRoute {
+    /// Empty if both are WETH
+    pub steps: Vec<SwapStep>,
+    pub amount_out: U256,
+}
+
+impl SwapRoute {
+    /// True if triggerLiquidation() can swap it with its two fee tiers: Uniswap V3 only, through
+    /// WETH
+    pub fn is_uniswap_v3_through_weth(&self) -> bool {
+        self.steps.iter().all(|step| {
+            step.venue == SwapVenue::UniswapV3 && (step.token_in == WETH || step.token_out == WETH)
+        })
+    }
+
+    /// Fee tiers of the Uniswap V3 steps that swap the collateral to WETH and WETH to the debt
+    /// asset, the ones triggerLiquidation() is sent with. A leg the route doesn't swap is 0,
+    /// which Foxdie ignores.
+    pub fn uniswap_v3_fees(&self) -> (U24, U24) {
+        let fee = |leg: fn(&SwapStep) -> bool| {
+            self.steps
+                .iter()
+                .find(|step| step.venue == SwapVenue::UniswapV3 && leg(step))
+                .map_or(U24::ZERO, |step| {
+                    U256::from_be_slice(&step.data).to::<U24>()
+                })
+        };
+        (
+            fee(|step| step.token_out == WETH),
+            fee(|step| step.token_in == WETH),
+        )
+    }
+
+    pub fn to_foxdie(&self) -> Vec<Foxdie::SwapStep> {
+        self.steps
+            .iter()
+            .map(|step| Foxdie::SwapStep {
+                venue: step.venue.into(),
+                tokenIn: step.token_in,
+                tokenOut: step.token_out,
+                data: step.data.clone(),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for SwapRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.steps.is_empty() {
+            return write!(f, "no swap");
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(
+                f,
+                "{} {} > {}",
+                step.venue.name(),
+                step.token_in,
+                step.token_out
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Picks the route that returns the most debt asset for the collateral of a liquidation. Every
+/// step is quoted on each venue at the evaluated block, so the quotes include fees and price
+/// impact. Without SWAP_ROUTING_ENV, only Uniswap V3 is quoted, and only through WETH (which is
+/// what triggerLiquidation() swaps through) unless DIRECT_SWAPS_ENV is set.
+pub struct SwapRouter {
+    enabled: bool,
+    direct_swaps: bool,
+    balancer_pool_ids: Vec<B256>,
+    /// Tokens of each Balancer pool, which never change
+    balancer_pool_tokens: Mutex<HashMap<B256, Vec<Address>>>,
+}
+
+impl SwapRouter {
+    pub fn from_env() -> Self {
+        let enabled = std::env::var(SWAP_ROUTING_ENV)
+            .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let direct_swaps = enabled
+            || std::env::var(DIRECT_SWAPS_ENV)
+                .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+                .unwrap_or(false);
+        let balancer_pool_ids = std::env::var(BALANCER_POOL_IDS_ENV)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|pool_id| !pool_id.is_empty())
+                    .filter_map(|pool_id| match B256::from_str(pool_id) {
+                        Ok(pool_id) => Some(pool_id),
+                        Err(e) => {
+                            warn!(
+                                "Ignoring invalid {} entry {}: {}",
+                                BALANCER_POOL_IDS_ENV, pool_id, e
+                            );
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        if enabled {
+            info!(
+                "Swap routing enabled: Uniswap V3, Uniswap V2, Curve and {} Balancer pools",
+                balancer_pool_ids.len()
+            );
+        } else if direct_swaps {
+            info!("Swap routing disabled, collateral is swapped on Uniswap V3 directly or through WETH");
+        } else {
+            info!("Swap routing disabled, collateral is swapped through WETH on Uniswap V3");
+        }
+        Self {
+            enabled,
+            direct_swaps,
+            balancer_pool_ids,
+            balancer_pool_tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Route that returns the most `debt_asset` for `amount_in` of `collateral_asset` at `block`:
+    /// through WETH (legs already in WETH aren't swapped), or with direct swaps enabled, straight
+    /// to the debt asset too
+    pub async fn find_best_route(
+        &self,
+        provider: Arc<RootProvider<PubSubFrontend>>,
+        collateral_asset: Address,
+        debt_asset: Address,
+        amount_in: U256,
+        block: BlockId,
+    ) -> Result<SwapRoute, ProfitoError> {
+        let mut candidates = vec![];
+
+        let mut through_weth = SwapRoute {
+            steps: vec![],
+            amount_out: amount_in,
+        };
+        let mut token_in = collateral_asset;
+        let mut complete = true;
+        for token_out in [WETH, debt_asset] {
+            if token_in == token_out {
+                continue;
+            }
+            match self
+                .quote_step(
+                    &provider,
+                    token_in,
+                    token_out,
+                    through_weth.amount_out,
+                    block,
+                )
+                .await
+            {
+                Some(step) => {
+                    through_weth.amount_out = step.amount_out;
+                    through_weth.steps.push(step);
+                }
+                None => {
+                    complete = false;
+                    break;
+                }
+            }
+            token_in = token_out;
+        }
+        if complete {
+            candidates.push(through_weth);
+        }
+
+        // Stable to stable and LST pairs often have a deep pool of their own, where skipping the
+        // WETH hop saves a pool fee and the price impact of a leg
+        if self.direct_swaps
+            && collateral_asset != debt_asset
+            && collateral_asset != WETH
+            && debt_asset != WETH
+        {
+            if let Some(step) = self
+                .quote_step(&provider, collateral_asset, debt_asset, amount_in, block)
+                .await
+            {
+                candidates.push(SwapRoute {
+                    amount_out: step.amount_out,
+                    steps: vec![step],
+                });
+            }
+        }
+
+        candidates
+            .into_iter()
+            .max_by_key(|route| route.amount_out)
+            .ok_or_else(|| {
+                ProfitoError::Rejected(format!(
+                    "there's no route to swap {} for {}",
+                    collateral_asset, debt_asset
+                ))
+            })
+    }
+
+    /// Best quote for swapping `amount_in` of `token_in` for `token_out` in a single step, or None
+    /// if no venue can
+    async fn quote_step(
+        &self,
+        provider: &Arc<RootProvider<PubSubFrontend>>,
+        token_in: Address,
+        token_out: Address,
+        amount_in: U256,
+        block: BlockId,
+    ) -> Option<SwapStep> {
+        let mut quotes =
+            vec![quote_uniswap_v3(provider, token_in, token_out, amount_in, block).await];
+        if self.enabled {
+            quotes.push(quote_uniswap_v2(provider, token_in, token_out, amount_in, block).await);
+            quotes.push(quote_curve(provider, token_in, token_out, amount_in, block).await);
+            quotes.push(
+                self.quote_balancer(provider, token_in, token_out, amount_in, block)
+                    .await,
+            );
+        }
+        // max_by_key() keeps the last of equal quotes, so reversed, ties go to Uniswap V3
+        quotes
+            .into_iter()
+            .rev()
+            .flatten()
+            .max_by_key(|step| step.amount_out)
+    }
+
+    async fn quote_balancer(
+        &self,
+        provider: &Arc<RootProvider<PubSubFrontend>>,
+        token_in: Address,
+        token_out: Address,
+        amount_in: U256,
+        block: BlockId,
+    ) -> Option<SwapStep> {
+        let vault = IBalancerVault::new(BALANCER_VAULT, provider.clone());
+        let mut best: Option<SwapStep> = None;
+        for pool_id in &self.balancer_pool_ids {
+            let cached = self.lock_pool_tokens().get(pool_id).cloned();
+            let tokens = match cached {
+                Some(tokens) => tokens,
+                None => match vault.getPoolTokens(*pool_id).block(block).call().await {
+                    Ok(response) => {
+                        self.lock_pool_tokens()
+                            .insert(*pool_id, response.tokens.clone());
+                        response.tokens
+                    }
+                    Err(e) => {
+                        warn!("Error trying to call getPoolTokens({}): {}", pool_id, e);
+                        continue;
+                    }
+                },
+            };
+            if !tokens.contains(&token_in) || !tokens.contains(&token_out) {
+                continue;
+            }
+            let swap = IBalancerVault::BatchSwapStep {
+                poolId: *pool_id,
+                assetInIndex: U256::ZERO,
+                assetOutIndex: U256::from(1),
+                amount: amount_in,
+                userData: Bytes::new(),
+            };
+            let funds = IBalancerVault::FundManagement {
+                sender: Address::ZERO,
+                fromInternalBalance: false,
+                recipient: Address::ZERO,
+                toInternalBalance: false,
+            };
+            let Ok(response) = vault
+                .queryBatchSwap(
+                    BALANCER_GIVEN_IN,
+                    vec![swap],
+                    vec![token_in, token_out],
+                    funds,
+                )
+                .block(block)
+                .call()
+                .await
+            else {
+                continue;
+            };
+            // What leaves the vault is negative
+            let Some(delta_out) = response.assetDeltas.get(1) else {
+                continue;
+            };
+            if !delta_out.is_negative() {
+                continue;
+            }
+            let amount_out = delta_out.unsigned_abs();
+            if best
+                .as_ref()
+                .map_or(true, |best| amount_out > best.amount_out)
+            {
+                best = Some(SwapStep {
+                    venue: SwapVenue::Balancer,
+                    token_in,
+                    token_out,
+                    data: Bytes::from(pool_id.abi_encode()),
+                    amount_out,
+                });
+            }
+        }
+        best
+    }
+
+    fn lock_pool_tokens(&self) -> std::sync::MutexGuard<'_, HashMap<B256, Vec<Address>>> {
+        self.balancer_pool_tokens
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Route through WETH on the Uniswap V3 pools with `fees` (collateral to WETH, WETH to debt), the
+/// way triggerLiquidation() swaps with them. Legs already in WETH aren't swapped, and their fee is
+/// ignored. None if a pool can't quote.
+pub async fn quote_uniswap_v3_through_weth(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    collateral_asset: Address,
+    debt_asset: Address,
+    amount_in: U256,
+    fees: (U24, U24),
+    block: BlockId,
+) -> Option<SwapRoute> {
+    let mut route = SwapRoute {
+        steps: vec![],
+        amount_out: amount_in,
+    };
+    let mut token_in = collateral_asset;
+    for (token_out, fee) in [(WETH, fees.0), (debt_asset, fees.1)] {
+        if token_in == token_out {
+            continue;
+        }
+        let step =
+            quote_uniswap_v3_pool(&provider, token_in, token_out, fee, route.amount_out, block)
+                .await?;
+        route.amount_out = step.amount_out;
+        route.steps.push(step);
+        token_in = token_out;
+    }
+    Some(route)
+}
+
+/// Quote of the deepest Uniswap V3 pool of the pair
+async fn quote_uniswap_v3(
+    provider: &Arc<RootProvider<PubSubFrontend>>,
+    token_in: Address,
+    token_out: Address,
+    amount_in: U256,
+    block: BlockId,
+) -> Option<SwapStep> {
+    let pools = get_uniswap_v3_pools(provider, token_in, token_out, block).await;
+    let (_, fee, _) = pools.first()?;
+    quote_uniswap_v3_pool(provider, token_in, token_out, *fee, amount_in, block).await
+}
+
+async fn quote_uniswap_v3_pool(
+    provider: &Arc<RootProvider<PubSubFrontend>>,
+    token_in: Address,
+    token_out: Address,
+    fee: U24,
+    amount_in: U256,
+    block: BlockId,
+) -> Option<SwapStep> {
+    let amount_out = UniswapV3Quoter::new(UNISWAP_V3_QUOTER, provider.clone())
+        .quoteExactInputSingle(token_in, token_out, fee, amount_in, U160::ZERO)
+        .block(block)
+        .call()
+        .await
+        .ok()?
+        .amountOut;
+    Some(SwapStep {
+        venue: SwapVenue::UniswapV3,
+        token_in,
+        token_out,
+        data: Bytes::from(U256::from(fee.to::<u32>()).abi_encode()),
+        amount_out,
+    })
+}
+
+async fn quote_uniswap_v2(
+    provider: &Arc<RootProvider<PubSubFrontend>>,
+    token_in: Address,
+    token_out: Address,
+    amount_in: U256,
+    block: BlockId,
+) -> Option<SwapStep> {
+    let pair = IUniswapV2Factory::new(UNISWAP_V2_FACTORY, provider.clone())
+        .getPair(token_in, token_out)
+        .block(block)
+        .call()
+        .await
+        .ok()?
+        .pair;
+    if pair == Address::ZERO {
+        return None;
+    }
+    let amounts = IUniswapV2Router::new(UNISWAP_V2_ROUTER, provider.clone())
+        .getAmountsOut(amount_in, vec![token_in, token_out])
+        .block(block)
+        .call()
+        .await
+        .ok()?
+        .amounts;
+    Some(SwapStep {
+        venue: SwapVenue::UniswapV2,
+        token_in,
+        token_out,
+        data: Bytes::from(pair.abi_encode()),
+        amount_out: *amounts.last()?,
+    })
+}
+
+/// Quote of the pool the Curve MetaRegistry finds for the pair. Only plain coins of stableswap
+/// pools are quoted: underlying coins (swapped with exchange_underlying()) are skipped, and crypto
+/// pools, which take uint256 indexes, fail to quote.
+async fn quote_curve(
+    provider: &Arc<RootProvider<PubSubFrontend>>,
+    token_in: Address,
+    token_out: Address,
+    amount_in: U256,
+    block: BlockId,
+) -> Option<SwapStep> {
+    let registry = ICurveMetaRegistry::new(CURVE_META_REGISTRY, provider.clone());
+    let pool = registry
+        .find_pool_for_coins(token_in, token_out)
+        .block(block)
+        .call()
+        .await
+        .ok()?
+        ._0;
+    if pool == Address::ZERO {
+        return None;
+    }
+    let indexes = registry
+        .get_coin_indices(pool, token_in, token_out)
+        .block(block)
+        .call()
+        .await
+        .ok()?;
+    if indexes._2 {
+        return None;
+    }
+    let (i, j) = (indexes._0, indexes._1);
+    let amount_out = ICurvePool::new(pool, provider.clone())
+        .get_dy(i, j, amount_in)
+        .block(block)
+        .call()
+        .await
+        .ok()?
+        ._0;
+    Some(SwapStep {
+        venue: SwapVenue::Curve,
+        token_in,
+        token_out,
+        data: Bytes::from((pool, i, j).abi_encode_params()),
+        amount_out,
+    })
+}
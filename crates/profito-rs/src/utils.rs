@@ -1,18 +1,23 @@
-use crate::calculations::BestPair;
+use crate::{
+    calculations::BestPair, error::ProfitoError, execution_mode::ExecutionMode,
+    swap_routes::SwapRoute,
+};
 
 use overlord_shared::{
-    constants::{
-        AAVE_V3_POOL_ADDRESS, AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS, AAVE_V3_PROVIDER_ADDRESS,
-        AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS,
-    },
+    common::get_reserves_list_at,
+    constants::AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS,
+    error::NodeError,
+    market::AaveMarket,
     sol_bindings::{
-        pool::AaveV3Pool, AaveProtocolDataProvider, AaveUIPoolDataProvider,
+        AaveProtocolDataProvider, AaveUIPoolDataProvider, Foxdie,
         GetReserveConfigurationDataReturn, IERC20Metadata, IUiPoolDataProviderV3::UserReserveData,
     },
 };
 
 use alloy::primitives::{aliases::U24, Address, U256};
 use alloy::providers::RootProvider;
+use alloy::rpc::types::BlockId;
+use alloy::sol_types::SolCall;
 
 use alloy::pubsub::PubSubFrontend;
 use ethers_core::{
@@ -49,12 +54,15 @@ async fn get_token_symbol(
 /// for the later, the ones that are allowed to be used as collateral
 pub async fn get_user_reserves_data(
     provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
     user_address: Address,
+    block: BlockId,
 ) -> Vec<UserReserveData> {
     let ui_data =
         AaveUIPoolDataProvider::new(AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS, provider.clone());
     let user_reserves_data = match ui_data
-        .getUserReservesData(AAVE_V3_PROVIDER_ADDRESS, user_address)
+        .getUserReservesData(market.addresses_provider, user_address)
+        .block(block)
         .call()
         .await
     {
@@ -78,52 +86,45 @@ pub async fn get_user_reserves_data(
 /// Fetches information on aave reserves and returns a map of reserve addresses to their configuration data, symbol and liquidation fee.
 pub async fn generate_reserve_details_by_asset(
     provider: Arc<RootProvider<PubSubFrontend>>,
-) -> Result<ReserveConfigurationData, Box<dyn std::error::Error>> {
+    market: &AaveMarket,
+    block: BlockId,
+) -> Result<ReserveConfigurationData, ProfitoError> {
     // Get reserve addresses from AAVE getReservesList
-    let reserve_addresses = match AaveV3Pool::new(AAVE_V3_POOL_ADDRESS, provider.clone())
-        .getReservesList()
-        .call()
-        .await
-    {
-        Ok(reserves) => reserves._0,
-        Err(e) => {
-            return Err(format!(
-                "Failed to get reserves list to initialize reserve configuration struct: {}",
-                e
-            )
-            .into());
-        }
+    let reserve_addresses = match get_reserves_list_at(provider.clone(), market, block).await {
+        Ok(reserves) => reserves,
+        Err(e) => return Err(NodeError::call("getReservesList", e).into()),
     };
     let mut configuration_data: ReserveConfigurationData = HashMap::new();
 
-    let aave_config =
-        AaveProtocolDataProvider::new(AAVE_V3_PROTOCOL_DATA_PROVIDER_ADDRESS, provider.clone());
+    let aave_config = AaveProtocolDataProvider::new(market.data_provider, provider.clone());
     for reserve_address in reserve_addresses {
         let symbol = get_token_symbol(provider.clone(), reserve_address).await;
         let data = match aave_config
             .getReserveConfigurationData(reserve_address)
+            .block(block)
             .call()
             .await
         {
             Ok(reserve_config) => reserve_config,
             Err(e) => {
-                return Err(format!(
-                    "Failed to get reserve configuration data for asset {}: {}",
-                    reserve_address, e
+                return Err(NodeError::call(
+                    format!("getReserveConfigurationData({})", reserve_address),
+                    e,
                 )
                 .into())
             }
         };
         let liquidation_fee = match aave_config
             .getLiquidationProtocolFee(reserve_address)
+            .block(block)
             .call()
             .await
         {
             Ok(fee_response) => fee_response._0,
             Err(e) => {
-                return Err(format!(
-                    "Failed to get reserve liquidation fee for asset {}: {}",
-                    reserve_address, e
+                return Err(NodeError::call(
+                    format!("getLiquidationProtocolFee({})", reserve_address),
+                    e,
                 )
                 .into())
             }
@@ -140,60 +141,101 @@ pub async fn generate_reserve_details_by_asset(
     Ok(configuration_data)
 }
 
+/// Env var with the Foxdie deployment that liquidates on `market`. triggerLiquidation() doesn't
+/// take a pool, so each deployment is bound to the pool of one market: FOXDIE_ADDRESS for the core
+/// one, and FOXDIE_ADDRESS_<MARKET> (e.g. FOXDIE_ADDRESS_LIDO) for the rest.
+pub(crate) fn foxdie_address_env(market: &AaveMarket) -> String {
+    if market.is_core() {
+        "FOXDIE_ADDRESS".to_string()
+    } else {
+        format!("FOXDIE_ADDRESS_{}", market.name.to_uppercase())
+    }
+}
+
 pub async fn create_trigger_liquidation_tx(
     best: BestPair,
+    market: &AaveMarket,
     user_address: Address,
     collateral_to_weth_fee: U24,
     weth_to_debt_fee: U24,
     bribe: U256,
-) -> Result<TypedTransaction, Box<dyn std::error::Error>> {
-    let params = vec![Token::Tuple(vec![
-        Token::Uint(ethersU256::from_little_endian(
-            &best.actual_debt_to_liquidate.to_le_bytes::<32>(),
-        )), // debtAmount
-        Token::Address(H160::from_slice(user_address.as_slice())), // user
-        Token::Address(H160::from_slice(best.debt_asset.as_slice())), // debtAsset
-        Token::Address(H160::from_slice(best.collateral_asset.as_slice())), // collateral
-        Token::Uint(ethersU256::from(collateral_to_weth_fee.to::<u32>())), // collateralToWethFee
-        Token::Uint(ethersU256::from(weth_to_debt_fee.to::<u32>())), // wethToDebtFee
-        Token::Uint(ethersU256::from(bribe.to::<u16>())),          // bribePercentBps
-        Token::Uint(ethersU256::from(best.flash_loan_source as u8)), // flashLoanSource
-        Token::Uint(ethersU256::from(0)),                          // aavePremium
-    ])];
-
-    let function_signature =
-        "triggerLiquidation((uint256,address,address,address,uint24,uint24,uint16,uint8,uint256))";
-    let selector = &keccak256(function_signature.as_bytes())[0..4];
-    let encoded_params = encode(&params);
-    let encoded = [selector, &encoded_params].concat();
+    swap_route: &SwapRoute,
+) -> Result<TypedTransaction, ProfitoError> {
+    let encoded = if best.execution_mode == ExecutionMode::ReceiveAToken {
+        // No flash loan nor swap, so the source is NONE and the fee tiers are ignored
+        Foxdie::triggerLiquidationReceiveATokenCall {
+            params: Foxdie::LiquidationParams {
+                debtAmount: best.actual_debt_to_liquidate,
+                user: user_address,
+                debtAsset: best.debt_asset,
+                collateral: best.collateral_asset,
+                collateralToWethFee: collateral_to_weth_fee,
+                wethToDebtFee: weth_to_debt_fee,
+                bribePercentBps: bribe.to::<u16>(),
+                flashLoanSource: best.flash_loan_source,
+                aavePremium: U256::ZERO,
+            },
+        }
+        .abi_encode()
+    } else if swap_route.is_uniswap_v3_through_weth() {
+        encode_trigger_liquidation(
+            &best,
+            user_address,
+            collateral_to_weth_fee,
+            weth_to_debt_fee,
+            bribe,
+        )
+    } else {
+        // The fee tiers are ignored, Foxdie swaps through the route
+        Foxdie::triggerLiquidationWithRouteCall {
+            params: Foxdie::LiquidationParams {
+                debtAmount: best.actual_debt_to_liquidate,
+                user: user_address,
+                debtAsset: best.debt_asset,
+                collateral: best.collateral_asset,
+                collateralToWethFee: collateral_to_weth_fee,
+                wethToDebtFee: weth_to_debt_fee,
+                bribePercentBps: bribe.to::<u16>(),
+                flashLoanSource: best.flash_loan_source,
+                aavePremium: best.aave_premium(),
+            },
+            route: swap_route.to_foxdie(),
+        }
+        .abi_encode()
+    };
     let foxdie_owner = match &env::var("FOXDIE_OWNER") {
         Ok(addr_str) => match addr_str.parse::<H160>() {
             Ok(addr) => addr,
             Err(e) => {
-                return Err(format!(
+                return Err(ProfitoError::Config(format!(
                     "Couldn't convert FOXDIE_OWNER value into formal address: {}",
                     e
-                )
-                .into())
+                )))
             }
         },
         Err(e) => {
-            return Err(format!("Couldn't read FOXDIE_OWNER environment value: {}", e).into())
+            return Err(ProfitoError::Config(format!(
+                "Couldn't read FOXDIE_OWNER environment value: {}",
+                e
+            )))
         }
     };
-    let foxdie_address = match &env::var("FOXDIE_ADDRESS") {
+    let foxdie_address_env = foxdie_address_env(market);
+    let foxdie_address = match &env::var(&foxdie_address_env) {
         Ok(addr_str) => match addr_str.parse::<H160>() {
             Ok(addr) => addr,
             Err(e) => {
-                return Err(format!(
-                    "Couldn't convert FOXDIE_ADDRESS value into formal address: {}",
-                    e
-                )
-                .into())
+                return Err(ProfitoError::Config(format!(
+                    "Couldn't convert {} value into formal address: {}",
+                    foxdie_address_env, e
+                )))
             }
         },
         Err(e) => {
-            return Err(format!("Couldn't read FOXDIE_ADDRESS environment value: {}", e).into())
+            return Err(ProfitoError::Config(format!(
+                "Couldn't read {} environment value: {}",
+                foxdie_address_env, e
+            )))
         }
     };
     let tx = Eip1559TransactionRequest::new()
@@ -202,3 +244,35 @@ pub async fn create_trigger_liquidation_tx(
         .data(encoded.to_vec());
     Ok(TypedTransaction::Eip1559(tx))
 }
+
+/// Calldata of triggerLiquidation(), which swaps the collateral through WETH on Uniswap V3 with
+/// the given fee tiers
+fn encode_trigger_liquidation(
+    best: &BestPair,
+    user_address: Address,
+    collateral_to_weth_fee: U24,
+    weth_to_debt_fee: U24,
+    bribe: U256,
+) -> Vec<u8> {
+    let params = vec![Token::Tuple(vec![
+        Token::Uint(ethersU256::from_little_endian(
+            &best.actual_debt_to_liquidate.to_le_bytes::<32>(),
+        )), // debtAmount
+        Token::Address(H160::from_slice(user_address.as_slice())), // user
+        Token::Address(H160::from_slice(best.debt_asset.as_slice())), // debtAsset
+        Token::Address(H160::from_slice(best.collateral_asset.as_slice())), // collateral
+        Token::Uint(ethersU256::from(collateral_to_weth_fee.to::<u32>())), // collateralToWethFee
+        Token::Uint(ethersU256::from(weth_to_debt_fee.to::<u32>())), // wethToDebtFee
+        Token::Uint(ethersU256::from(bribe.to::<u16>())),          // bribePercentBps
+        Token::Uint(ethersU256::from(best.flash_loan_source as u8)), // flashLoanSource
+        Token::Uint(ethersU256::from_little_endian(
+            &best.aave_premium().to_le_bytes::<32>(),
+        )), // aavePremium
+    ])];
+
+    let function_signature =
+        "triggerLiquidation((uint256,address,address,address,uint24,uint24,uint16,uint8,uint256))";
+    let selector = &keccak256(function_signature.as_bytes())[0..4];
+    let encoded_params = encode(&params);
+    [selector, &encoded_params].concat()
+}
@@ -4,7 +4,8 @@ use alloy::{
     pubsub::PubSubFrontend,
 };
 use overlord_shared::{
-    common::get_reserves_data,
+    common::{get_base_currency, get_reserves_data, BaseCurrency},
+    market::AaveMarket,
     sol_bindings::{
         pool::AaveV3Pool, AaveOracle, AaveProtocolDataProvider, AaveUIPoolDataProvider,
         GetReserveConfigurationDataReturn, IERC20Metadata,
@@ -142,9 +143,10 @@ pub async fn has_any_collateral_above_threshold(
     user_address: Address,
     user_positions: Vec<UserPosition>,
     min_collateral_in_usd: f64,
+    base_currency: &BaseCurrency,
 ) -> Result<bool, Box<dyn std::error::Error>> {
     // This should be something that we query only once, and make available for other services via shared memory, IPC or whatever
-    let reserves_data = get_reserves_data(provider.clone()).await?;
+    let reserves_data = get_reserves_data(provider.clone(), &AaveMarket::core()).await?;
     let reserves_data = reserves_data
         .into_iter()
         .map(|d| (d.underlyingAsset, d))
@@ -199,7 +201,7 @@ pub async fn has_any_collateral_above_threshold(
             f64::from(a_token_balance) / f64::from(10).powi(decimals.try_into().unwrap_or(0));
         let raw = a_token_balance.as_limbs()[0] as f64; // Get the lowest limb which is u64, then convert to f64
         let token_units = raw / 10f64.powi(decimals.try_into().unwrap_or(0)); // normalize the token amount
-        let a_token_balance_in_usd = token_units * (price.to::<u128>() as f64 / 1e8); // multiply by price, normalize 8 decimals
+        let a_token_balance_in_usd = token_units * base_currency.to_usd(price); // multiply by price, normalize base currency decimals
         println!(
             "\taToken balance = {}, in asset units = {}, in USD = ${}",
             a_token_balance, a_token_balance_in_asset_units, a_token_balance_in_usd,
@@ -262,12 +264,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    let base_currency = get_base_currency(provider.clone(), &AaveMarket::core()).await?;
     let min_collateral_in_usd = 1.5 as f64;
     let verdict = match has_any_collateral_above_threshold(
         provider,
         user_address,
         user_positions,
         min_collateral_in_usd,
+        &base_currency,
     )
     .await
     {
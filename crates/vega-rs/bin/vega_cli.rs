@@ -0,0 +1,202 @@
+//! # vega-cli
+//!
+//! Watchlist and cache repair commands for a running vega-rs, meant for external risk tooling
+//! (dashboards, a second bot instance, ...) that shouldn't read or write vega's internal files.
+//!
+//! ## Commands
+//!
+//! - `export-watchlist <path>`: vega writes every user whose last known health factor is below
+//!   `--max-hf`, plus every user with a priority, to `path` as json. Users of markets other than
+//!   the core one go to a file of their own next to it, e.g. `watchlist-lido.json`.
+//! - `import-watchlist <file>`: sends the users in `file` (a json list in the same format) to vega,
+//!   which starts tracking the ones it didn't know of and ranks them by their priority. Entries
+//!   with a `market` go to the cache of that market, the rest to the core one.
+//! - `undo-to-block <block> [--market <name>]`: vega undoes every change to its user cache made by
+//!   updates of the blocks after `block`, e.g. after a reorg it didn't hear about, without
+//!   rebuilding it. Only goes as far back as vega's in-memory mutation log.
+//!
+//! Commands are queued on vega's inbound socket like any other message, so they're handled
+//! between price updates.
+//!
+//! - `check-override [--aggregator <address>...]`: doesn't talk to vega. Writes the storage
+//!   override vega simulates price updates with into each aggregator on an Anvil fork, and checks
+//!   that latestRoundData() reads back the answer and timestamps that were written. Checks every
+//!   aggregator of the Chainlink mappings unless some are given. Exits with 1 if any of them fails.
+//!
+
+use alloy::{
+    primitives::{utils::parse_ether, Address},
+    providers::{IpcConnect, ProviderBuilder},
+};
+use clap::{Parser, Subcommand};
+use overlord_shared::{
+    constants::GHO_PRICE_ORACLE, market::resolve_markets_from_env, CacheUndoRequest, MessageBundle,
+    WatchlistEntry, WatchlistExportRequest,
+};
+use std::{collections::BTreeSet, error::Error, fs, path::PathBuf, sync::Arc};
+use vega_rs::{
+    fork_provider::ForkProvider, override_check::check_price_update_override,
+    user_reserve_cache::ChainlinkMappingSource,
+};
+
+const VEGA_INBOUND_ENDPOINT: &str = "ipc:///tmp/vega_inbound";
+const CHAINLINK_ADDRESSES_FILE_ENV: &str = "VEGA_CHAINLINK_ADDRESSES_FILE";
+// How long to wait for vega to take the command before giving up
+const SEND_TIMEOUT_MS: i32 = 5000;
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "vega-cli",
+    about = "Watchlist and cache repair commands for vega-rs"
+)]
+struct VegaCliArgs {
+    #[clap(subcommand)]
+    command: VegaCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum VegaCommand {
+    /// Ask vega to write the users close to liquidation to a json file
+    ExportWatchlist {
+        /// Where vega writes the watchlist. Relative paths are resolved from the current directory.
+        path: PathBuf,
+        /// Only users whose last known health factor is below this one are exported
+        #[clap(long, default_value = "1.1")]
+        max_hf: String,
+    },
+    /// Send a json list of users for vega to track, with their priority
+    ImportWatchlist {
+        /// e.g. [{"user": "0x...", "priority": "high"}]
+        file: PathBuf,
+    },
+    /// Undo every change to the user cache made by updates of the blocks after `block`
+    UndoToBlock {
+        block: u64,
+        /// Name of the market whose cache to undo (core, lido, etherfi). All of them if not given.
+        #[clap(long)]
+        market: Option<String>,
+    },
+    /// Check that the storage override for price updates decodes as intended on every aggregator
+    CheckOverride {
+        /// Aggregators to check. All the ones in the Chainlink mappings of every market if none is
+        /// given.
+        #[clap(long = "aggregator")]
+        aggregators: Vec<Address>,
+    },
+}
+
+async fn check_overrides(aggregators: Vec<Address>) -> Result<(), Box<dyn Error>> {
+    let provider = ProviderBuilder::new()
+        .on_ipc(IpcConnect::new("/tmp/reth.ipc".to_string()))
+        .await?;
+    let mut aggregators = aggregators;
+    if aggregators.is_empty() {
+        let mapping_source = match std::env::var(CHAINLINK_ADDRESSES_FILE_ENV) {
+            Ok(filename) => ChainlinkMappingSource::File(filename),
+            Err(_) => ChainlinkMappingSource::Onchain,
+        };
+        // Markets share most of their feeds, each one is checked once
+        let mut market_aggregators = BTreeSet::new();
+        for market in resolve_markets_from_env(Arc::new(provider.clone())).await {
+            market_aggregators.extend(
+                mapping_source
+                    .for_market(&market)
+                    .load(&provider, &market)
+                    .await?
+                    .into_keys()
+                    .filter(|aggregator| *aggregator != GHO_PRICE_ORACLE),
+            );
+        }
+        aggregators = market_aggregators.into_iter().collect();
+    }
+    let fork = ForkProvider::new(None).await?;
+    let fork_provider = fork
+        .fork_provider
+        .as_ref()
+        .map_err(|e| format!("Fork provider isn't available: {}", e))?;
+    let mut failed = 0;
+    for aggregator in aggregators.iter() {
+        match check_price_update_override(&provider, fork_provider, *aggregator).await {
+            Ok(check) if check.passed() => {
+                println!("PASS {} ({})", check.aggregator, check.type_and_version);
+            }
+            Ok(check) => {
+                failed += 1;
+                println!(
+                    "FAIL {} ({}): {}",
+                    check.aggregator,
+                    check.type_and_version,
+                    check.mismatches.join(", ")
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                println!("FAIL {}: {}", aggregator, e);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} aggregators failed", failed, aggregators.len()).into());
+    }
+    println!("All {} aggregators passed", aggregators.len());
+    Ok(())
+}
+
+fn send_to_vega(message: &MessageBundle) -> Result<(), Box<dyn Error>> {
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::PUSH)?;
+    socket.set_linger(SEND_TIMEOUT_MS)?;
+    socket.set_sndtimeo(SEND_TIMEOUT_MS)?;
+    socket.connect(VEGA_INBOUND_ENDPOINT)?;
+    socket
+        .send(bincode::serialize(message)?, 0)
+        .map_err(|e| format!("Failed to send command to vega (is it running?): {}", e))?;
+    Ok(())
+}
+
+fn run(command: VegaCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        VegaCommand::ExportWatchlist { path, max_hf } => {
+            let max_health_factor = parse_ether(&max_hf)
+                .map_err(|e| format!("Invalid health factor {}: {}", max_hf, e))?;
+            let path = std::env::current_dir()?.join(path);
+            send_to_vega(&MessageBundle::WatchlistExport(WatchlistExportRequest {
+                path: path.to_string_lossy().to_string(),
+                max_health_factor,
+            }))?;
+            println!(
+                "Export requested, vega will write the watchlist to {} (and next to it for other markets)",
+                path.display()
+            );
+        }
+        VegaCommand::ImportWatchlist { file } => {
+            let entries: Vec<WatchlistEntry> = serde_json::from_str(&fs::read_to_string(&file)?)
+                .map_err(|e| format!("Failed to parse watchlist {}: {}", file.display(), e))?;
+            let entry_count = entries.len();
+            send_to_vega(&MessageBundle::WatchlistImport(entries))?;
+            println!("Sent {} users to vega", entry_count);
+        }
+        VegaCommand::UndoToBlock { block, market } => {
+            send_to_vega(&MessageBundle::CacheUndo(CacheUndoRequest {
+                block_number: block,
+                market,
+            }))?;
+            println!(
+                "Undo requested, vega will log how many users it restored to block {}",
+                block
+            );
+        }
+        VegaCommand::CheckOverride { aggregators } => {
+            tokio::runtime::Runtime::new()?.block_on(check_overrides(aggregators))?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = VegaCliArgs::parse();
+    if let Err(e) = run(args.command) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
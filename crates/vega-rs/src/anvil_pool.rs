@@ -0,0 +1,266 @@
+use alloy::{
+    node_bindings::anvil::{Anvil, AnvilInstance},
+    providers::{ext::AnvilApi, IpcConnect, Provider, ProviderBuilder, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::anvil::Forking,
+};
+use overlord_shared::PriceUpdateBundle;
+use std::{
+    collections::VecDeque,
+    panic,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::{interval, Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+use crate::fork_provider::{apply_price_update_to_fork, ForkProvider, IpcForkFile};
+
+const IPC_URL: &str = "/tmp/reth.ipc";
+// One block behind is what you get between a new head and the next resync tick
+const MAX_BLOCK_LAG: u64 = 1;
+const RESYNC_INTERVAL: Duration = Duration::from_secs(4);
+// Anvil keeps every fetched slot in memory, so forks are replaced after a while even if healthy
+const MAX_FORK_AGE: Duration = Duration::from_secs(60 * 60);
+
+struct PooledFork {
+    anvil: AnvilInstance,
+    provider: RootProvider<PubSubFrontend>,
+    _fork_file: IpcForkFile,
+    block_number: u64,
+    spawned_at: Instant,
+    fork_id: usize,
+}
+
+impl Drop for PooledFork {
+    fn drop(&mut self) {
+        info!("Killing pooled fork {}", self.fork_id);
+        ForkProvider::cleanup_anvil_instance(&mut self.anvil);
+    }
+}
+
+/// A fork taken from the pool. It goes back to the pool (to be reset to the latest block) when dropped.
+pub struct PooledForkGuard {
+    fork: Option<PooledFork>,
+    recycle_tx: mpsc::UnboundedSender<PooledFork>,
+}
+
+impl PooledForkGuard {
+    pub fn provider(&self) -> &RootProvider<PubSubFrontend> {
+        &self.fork.as_ref().unwrap().provider
+    }
+}
+
+impl Drop for PooledForkGuard {
+    fn drop(&mut self) {
+        if let Some(fork) = self.fork.take() {
+            // If the pool is gone the fork is dropped (and killed) right here
+            let _ = self.recycle_tx.send(fork);
+        }
+    }
+}
+
+/// Keeps `size` Anvil forks spawned and re-synced to the latest block, so price updates don't
+/// have to pay the fork startup latency.
+///
+/// Forks are single use: once a price update has been written into one, it's reset to the latest
+/// block before being handed out again. Forks that fail to reset or get too old are killed and
+/// replaced.
+pub struct AnvilPool {
+    idle: Mutex<VecDeque<PooledFork>>,
+    recycle_tx: mpsc::UnboundedSender<PooledFork>,
+    main_provider: RootProvider<PubSubFrontend>,
+    size: usize,
+    in_use: AtomicUsize,
+    next_fork_id: AtomicUsize,
+}
+
+impl AnvilPool {
+    pub async fn new(size: usize) -> Result<Arc<Self>, Box<dyn std::error::Error>> {
+        let main_provider = ProviderBuilder::new()
+            .on_ipc(IpcConnect::new(IPC_URL.to_string()))
+            .await?;
+        let (recycle_tx, recycle_rx) = mpsc::unbounded_channel();
+        let pool = Arc::new(Self {
+            idle: Mutex::new(VecDeque::new()),
+            recycle_tx,
+            main_provider,
+            size,
+            in_use: AtomicUsize::new(0),
+            next_fork_id: AtomicUsize::new(0),
+        });
+        pool.refill().await;
+        info!(
+            "Anvil pool started with {} forks",
+            pool.idle.lock().await.len()
+        );
+        tokio::spawn(pool.clone().maintain(recycle_rx));
+        Ok(pool)
+    }
+
+    /// Takes a fork from the pool and applies the price update to it. Returns None if there's
+    /// no fresh fork available, in which case the caller should spin up its own.
+    pub async fn acquire(&self, bundle: &PriceUpdateBundle) -> Option<PooledForkGuard> {
+        let latest_block = self.main_provider.get_block_number().await.ok()?;
+        let fork = loop {
+            let fork = self.idle.lock().await.pop_front()?;
+            if fork.block_number + MAX_BLOCK_LAG >= latest_block {
+                break fork;
+            }
+            // Stale, let the maintenance task resync it and try the next one. It counts as in use
+            // until it's back in the idle queue so refill() doesn't replace it meanwhile.
+            self.in_use.fetch_add(1, Ordering::SeqCst);
+            let _ = self.recycle_tx.send(fork);
+        };
+        self.in_use.fetch_add(1, Ordering::SeqCst);
+        let guard = PooledForkGuard {
+            fork: Some(fork),
+            recycle_tx: self.recycle_tx.clone(),
+        };
+        if let Err(e) =
+            apply_price_update_to_fork(self.main_provider.clone(), guard.provider(), bundle).await
+        {
+            warn!("Failed to apply price update to pooled fork: {}", e);
+            return None;
+        }
+        info!(
+            "Using pooled fork {} at block {} for bundle {}",
+            guard.fork.as_ref().unwrap().fork_id,
+            guard.fork.as_ref().unwrap().block_number,
+            bundle.trace_id
+        );
+        Some(guard)
+    }
+
+    async fn maintain(self: Arc<Self>, mut recycle_rx: mpsc::UnboundedReceiver<PooledFork>) {
+        let mut resync_interval = interval(RESYNC_INTERVAL);
+        loop {
+            tokio::select! {
+                Some(fork) = recycle_rx.recv() => {
+                    self.in_use.fetch_sub(1, Ordering::SeqCst);
+                    if let Some(fork) = self.resync(fork, true).await {
+                        self.idle.lock().await.push_back(fork);
+                    }
+                }
+                _ = resync_interval.tick() => {
+                    let forks = self.idle.lock().await.drain(..).collect::<Vec<_>>();
+                    for fork in forks {
+                        if let Some(fork) = self.resync(fork, false).await {
+                            self.idle.lock().await.push_back(fork);
+                        }
+                    }
+                    self.refill().await;
+                }
+            }
+        }
+    }
+
+    /// Resets the fork to the latest block, which also discards any price update written into it.
+    /// Clean forks already at the latest block are left alone. Returns None (and kills the fork)
+    /// if it's too old or the reset fails, or if it's dirty and the latest block can't be read,
+    /// since it would go back to the pool with the price update still in it.
+    async fn resync(&self, mut fork: PooledFork, dirty: bool) -> Option<PooledFork> {
+        if fork.spawned_at.elapsed() > MAX_FORK_AGE {
+            info!("Pooled fork {} is too old, replacing it", fork.fork_id);
+            return None;
+        }
+        let latest_block = match self.main_provider.get_block_number().await {
+            Ok(block) => block,
+            Err(e) if dirty => {
+                warn!(
+                    "Failed to get latest block to reset pooled fork {}, replacing it: {}",
+                    fork.fork_id, e
+                );
+                return None;
+            }
+            Err(e) => {
+                warn!("Failed to get latest block to resync pooled forks: {}", e);
+                return Some(fork);
+            }
+        };
+        if !dirty && fork.block_number >= latest_block {
+            return Some(fork);
+        }
+        match fork
+            .provider
+            .anvil_reset(Some(Forking {
+                json_rpc_url: Some(IPC_URL.to_string()),
+                block_number: Some(latest_block),
+            }))
+            .await
+        {
+            Ok(_) => {
+                fork.block_number = latest_block;
+                Some(fork)
+            }
+            Err(e) => {
+                warn!("Failed to reset pooled fork {}: {}", fork.fork_id, e);
+                None
+            }
+        }
+    }
+
+    async fn refill(&self) {
+        loop {
+            let available = self.idle.lock().await.len() + self.in_use.load(Ordering::SeqCst);
+            if available >= self.size {
+                return;
+            }
+            match self.spawn_fork().await {
+                Ok(fork) => self.idle.lock().await.push_back(fork),
+                Err(e) => {
+                    error!("Failed to spawn pooled fork: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn spawn_fork(&self) -> Result<PooledFork, String> {
+        let fork_id = self.next_fork_id.fetch_add(1, Ordering::SeqCst);
+        let block_number = self
+            .main_provider
+            .get_block_number()
+            .await
+            .map_err(|e| format!("Failed to get block for pooled fork: {}", e))?;
+        let fork_path = format!("./fork_pool_{}.ipc", fork_id);
+        let fork_file = IpcForkFile::new(fork_path.clone());
+        let mut anvil = panic::catch_unwind(|| {
+            Anvil::new()
+                .fork(IPC_URL)
+                .fork_block_number(block_number)
+                .block_time(1_u64)
+                .args(vec![
+                    "--ipc".to_string(),
+                    fork_path.clone(),
+                    "--auto-impersonate".to_string(),
+                ])
+                .spawn()
+        })
+        .map_err(|e| format!("Anvil creation panicked for pooled fork: {:?}", e))?;
+        let provider = match ProviderBuilder::new()
+            .on_ipc(IpcConnect::new(fork_path))
+            .await
+        {
+            Ok(provider) => provider,
+            Err(e) => {
+                ForkProvider::cleanup_anvil_instance(&mut anvil);
+                return Err(format!("Failed to connect to pooled fork IPC: {:?}", e));
+            }
+        };
+        info!("Pooled fork {} started at block {}", fork_id, block_number);
+        Ok(PooledFork {
+            anvil,
+            provider,
+            _fork_file: fork_file,
+            block_number,
+            spawned_at: Instant::now(),
+            fork_id,
+        })
+    }
+}
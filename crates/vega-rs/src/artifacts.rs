@@ -0,0 +1,191 @@
+use alloy::primitives::{keccak256, B256};
+use chrono::Utc;
+use overlord_shared::{error::FileError, market::AaveMarket};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+use crate::error::CacheError;
+
+/// Directory vega keeps its artifacts in, see ArtifactStore. Nothing is written without it.
+pub const ARTIFACTS_DIR_ENV: &str = "VEGA_ARTIFACTS_DIR";
+// Older artifacts of each kind and market are deleted, a week of daily ones is plenty
+const RETAINED_ARTIFACTS: usize = 7;
+const MANIFEST_SUFFIX: &str = ".latest.json";
+
+/// What an artifact holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArtifactKind {
+    /// A CacheSnapshot (bincode)
+    CacheSnapshot,
+    /// A list of WatchlistEntry (json)
+    Watchlist,
+}
+
+impl ArtifactKind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::CacheSnapshot => "cache-snapshot",
+            Self::Watchlist => "watchlist",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::CacheSnapshot => "bin",
+            Self::Watchlist => "json",
+        }
+    }
+}
+
+/// Written next to the artifacts of a kind and market, pointing at the newest one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArtifactManifest {
+    /// File name of the artifact, in the same directory
+    pub file: String,
+    /// keccak256 of the artifact's contents
+    pub content_hash: B256,
+    /// Last block the contents are known to be up to date with
+    pub block_number: u64,
+    /// RFC 3339, in UTC
+    pub created_at: String,
+    pub size: u64,
+}
+
+/// Directory of files vega writes to start from after a restart, like the daily cache snapshot.
+/// Artifacts are never modified once written: each one gets a file named after its kind, market,
+/// day and block, and a manifest per kind and market points at the newest one along with the
+/// hash of its contents, so a truncated or tampered file is refused instead of misread.
+#[derive(Clone, Debug)]
+pub struct ArtifactStore {
+    dir: PathBuf,
+}
+
+impl ArtifactStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// None if ARTIFACTS_DIR_ENV isn't set
+    pub fn from_env() -> Option<Self> {
+        std::env::var(ARTIFACTS_DIR_ENV).ok().map(Self::new)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes `contents` as the newest artifact of `kind` for `market`, and deletes the ones
+    /// beyond RETAINED_ARTIFACTS
+    pub fn put(
+        &self,
+        kind: ArtifactKind,
+        market: &AaveMarket,
+        contents: &[u8],
+        block_number: u64,
+    ) -> Result<ArtifactManifest, CacheError> {
+        fs::create_dir_all(&self.dir).map_err(|e| FileError::new(&self.dir, e))?;
+        let prefix = market.scoped_path(kind.name());
+        let created_at = Utc::now();
+        // Padded, so file names sort in the order they were written
+        let file = format!(
+            "{}.{}-{:012}.{}",
+            prefix,
+            created_at.format("%Y%m%d"),
+            block_number,
+            kind.extension()
+        );
+        write_atomically(&self.dir.join(&file), contents)?;
+        let manifest = ArtifactManifest {
+            file,
+            content_hash: keccak256(contents),
+            block_number,
+            created_at: created_at.to_rfc3339(),
+            size: contents.len() as u64,
+        };
+        let manifest_path = self.manifest_path(kind, market);
+        let manifest_contents = serde_json::to_vec_pretty(&manifest)
+            .map_err(|e| CacheError::malformed(&manifest_path, e))?;
+        write_atomically(&manifest_path, &manifest_contents)?;
+        self.prune(kind, &prefix);
+        Ok(manifest)
+    }
+
+    /// The newest artifact of `kind` for `market` and its contents, None if there's none yet.
+    /// Contents that don't match the hash in the manifest are an error.
+    pub fn latest(
+        &self,
+        kind: ArtifactKind,
+        market: &AaveMarket,
+    ) -> Result<Option<(ArtifactManifest, Vec<u8>)>, CacheError> {
+        let manifest_path = self.manifest_path(kind, market);
+        if !manifest_path.exists() {
+            return Ok(None);
+        }
+        let manifest_contents =
+            fs::read(&manifest_path).map_err(|e| FileError::new(&manifest_path, e))?;
+        let manifest: ArtifactManifest = serde_json::from_slice(&manifest_contents)
+            .map_err(|e| CacheError::malformed(&manifest_path, e))?;
+        let path = self.dir.join(&manifest.file);
+        let contents = fs::read(&path).map_err(|e| FileError::new(&path, e))?;
+        let content_hash = keccak256(&contents);
+        if content_hash != manifest.content_hash {
+            return Err(CacheError::malformed(
+                &path,
+                format!(
+                    "contents hash to {}, the manifest says {}",
+                    content_hash, manifest.content_hash
+                ),
+            ));
+        }
+        Ok(Some((manifest, contents)))
+    }
+
+    fn manifest_path(&self, kind: ArtifactKind, market: &AaveMarket) -> PathBuf {
+        self.dir.join(format!(
+            "{}{}",
+            market.scoped_path(kind.name()),
+            MANIFEST_SUFFIX
+        ))
+    }
+
+    /// Deletes the oldest artifacts starting with `prefix`, keeping RETAINED_ARTIFACTS of them
+    fn prune(&self, kind: ArtifactKind, prefix: &str) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to list artifacts in {}: {}", self.dir.display(), e);
+                return;
+            }
+        };
+        let prefix = format!("{}.", prefix);
+        let extension = format!(".{}", kind.extension());
+        let mut files: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| {
+                name.starts_with(&prefix)
+                    && name.ends_with(&extension)
+                    && !name.ends_with(MANIFEST_SUFFIX)
+            })
+            .collect();
+        files.sort();
+        let excess = files.len().saturating_sub(RETAINED_ARTIFACTS);
+        for file in files.into_iter().take(excess) {
+            let path = self.dir.join(file);
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Failed to delete old artifact {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Writes to a temp file first, so a crash mid-write can't leave a truncated file at `path`
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), CacheError> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents).map_err(|e| FileError::new(&tmp_path, e))?;
+    fs::rename(&tmp_path, path).map_err(|e| FileError::new(path, e).into())
+}
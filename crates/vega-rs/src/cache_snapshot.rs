@@ -0,0 +1,293 @@
+use alloy::{
+    primitives::Address, providers::RootProvider, pubsub::PubSubFrontend, rpc::types::Log,
+    sol_types::SolEvent,
+};
+use overlord_shared::{
+    common::get_reserves_data,
+    error::FileError,
+    market::AaveMarket,
+    sol_bindings::{pool::AaveV3Pool, IAToken},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    sync::Arc,
+};
+use tracing::{info, warn};
+
+use crate::discovery::scan_logs;
+use crate::error::CacheError;
+use crate::hf_engine::UserScaledPosition;
+
+type UserAddress = Address;
+
+// Bumped whenever the snapshot layout changes, so an old snapshot is ignored instead of misread
+const SNAPSHOT_VERSION: u32 = 1;
+// Events in the last blocks before the watermark may not have been applied yet when the snapshot
+// was taken, so they're replayed too. Refreshing a user twice is harmless.
+const REPLAY_OVERLAP_BLOCKS: u64 = 5;
+
+/// Positions of every cached user as of `block_number`. Reserves and e-mode categories aren't
+/// part of it, they're cheap to read again and change on their own anyway.
+#[derive(Serialize, Deserialize)]
+pub struct CacheSnapshot {
+    version: u32,
+    pub block_number: u64,
+    pub positions_by_user: HashMap<UserAddress, Vec<UserScaledPosition>>,
+}
+
+impl CacheSnapshot {
+    pub fn new(
+        block_number: u64,
+        positions_by_user: HashMap<UserAddress, Vec<UserScaledPosition>>,
+    ) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            block_number,
+            positions_by_user,
+        }
+    }
+
+    /// Returns None if there's no snapshot at `path`, or if it was written by another version
+    pub fn load(path: &Path) -> Result<Option<Self>, CacheError> {
+        if !path.exists() {
+            info!("No cache snapshot at {}", path.display());
+            return Ok(None);
+        }
+        let contents = fs::read(path).map_err(|e| FileError::new(path, e))?;
+        Self::decode(&contents, path)
+    }
+
+    /// Same as load(), for a snapshot read by someone else (e.g. an artifact) from `source`
+    pub fn decode(contents: &[u8], source: &Path) -> Result<Option<Self>, CacheError> {
+        let snapshot: Self =
+            bincode::deserialize(contents).map_err(|e| CacheError::malformed(source, e))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            warn!(
+                "Cache snapshot {} has version {}, expected {}. Ignoring it.",
+                source.display(),
+                snapshot.version,
+                SNAPSHOT_VERSION
+            );
+            return Ok(None);
+        }
+        info!(
+            "Loaded cache snapshot with {} users at block {}",
+            snapshot.positions_by_user.len(),
+            snapshot.block_number
+        );
+        Ok(Some(snapshot))
+    }
+
+    /// Writes to a temp file first, so a crash mid-write can't leave a truncated snapshot
+    pub fn save(&self, path: &Path) -> Result<(), CacheError> {
+        save_atomically(path, self)
+    }
+
+    /// What save() writes, for whoever stores it somewhere else
+    pub fn encode(&self) -> Result<Vec<u8>, CacheError> {
+        bincode::serialize(self).map_err(|e| CacheError::malformed("cache snapshot", e))
+    }
+}
+
+/// Positions read so far by an initial cache build that hasn't finished. It's written after every
+/// group of buckets, so a build that crashes halfway resumes from there instead of from zero.
+#[derive(Serialize, Deserialize)]
+pub struct InitProgress {
+    version: u32,
+    /// Every position read is at least as recent as this block
+    pub block_number: u64,
+    /// Users already read, including the ones without positions worth tracking
+    pub processed_users: HashSet<UserAddress>,
+    pub positions_by_user: HashMap<UserAddress, Vec<UserScaledPosition>>,
+}
+
+impl InitProgress {
+    pub fn new(block_number: u64) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            block_number,
+            processed_users: HashSet::new(),
+            positions_by_user: HashMap::new(),
+        }
+    }
+
+    /// Returns None if there's no progress at `path`, or if it was written by another version
+    pub fn load(path: &Path) -> Result<Option<Self>, CacheError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read(path).map_err(|e| FileError::new(path, e))?;
+        let progress: Self =
+            bincode::deserialize(&contents).map_err(|e| CacheError::malformed(path, e))?;
+        if progress.version != SNAPSHOT_VERSION {
+            warn!(
+                "Cache init progress {} has version {}, expected {}. Ignoring it.",
+                path.display(),
+                progress.version,
+                SNAPSHOT_VERSION
+            );
+            return Ok(None);
+        }
+        info!(
+            "Loaded cache init progress with {} users read at block {}",
+            progress.processed_users.len(),
+            progress.block_number
+        );
+        Ok(Some(progress))
+    }
+
+    /// Writes to a temp file first, so a crash mid-write can't leave truncated progress
+    pub fn save(&self, path: &Path) -> Result<(), CacheError> {
+        save_atomically(path, self)
+    }
+
+    /// Drops the users affected by pool events after block_number, so they're read again, and
+    /// moves the watermark to `head_block`
+    pub fn invalidate_users(&mut self, affected_users: &HashSet<UserAddress>, head_block: u64) {
+        for user in affected_users {
+            self.processed_users.remove(user);
+            self.positions_by_user.remove(user);
+        }
+        self.block_number = head_block;
+    }
+}
+
+/// Serializes `value` to a temp file next to `path`, and renames it over `path` once written
+fn save_atomically<T: Serialize>(path: &Path, value: &T) -> Result<(), CacheError> {
+    let tmp_path = path.with_extension("tmp");
+    let contents = bincode::serialize(value).map_err(|e| CacheError::malformed(path, e))?;
+    fs::write(&tmp_path, contents).map_err(|e| FileError::new(&tmp_path, e))?;
+    fs::rename(&tmp_path, path).map_err(|e| FileError::new(path, e).into())
+}
+
+/// Users whose positions may have changed after `snapshot_block`, i.e. the ones whistleblower-rs
+/// would have reported while vega was down: the user of every Pool event the cache refreshes users
+/// on (see UserReservesCache::update_cache()), and both sides of aToken and variable debt token
+/// transfers.
+pub async fn get_users_affected_since(
+    provider: &RootProvider<PubSubFrontend>,
+    market: &AaveMarket,
+    snapshot_block: u64,
+    head_block: u64,
+) -> Result<HashSet<UserAddress>, CacheError> {
+    let from_block = snapshot_block.saturating_sub(REPLAY_OVERLAP_BLOCKS);
+    info!(
+        "Replaying pool events of the {} market from block {} to {} ({} blocks)",
+        market.name,
+        from_block,
+        head_block,
+        head_block.saturating_sub(from_block) + 1
+    );
+    let mut affected_users = HashSet::new();
+    scan_logs(
+        provider,
+        vec![market.pool],
+        vec![
+            AaveV3Pool::LiquidationCall::SIGNATURE_HASH,
+            AaveV3Pool::Borrow::SIGNATURE_HASH,
+            AaveV3Pool::Supply::SIGNATURE_HASH,
+            AaveV3Pool::Repay::SIGNATURE_HASH,
+            AaveV3Pool::Withdraw::SIGNATURE_HASH,
+            AaveV3Pool::ReserveUsedAsCollateralEnabled::SIGNATURE_HASH,
+            AaveV3Pool::ReserveUsedAsCollateralDisabled::SIGNATURE_HASH,
+            AaveV3Pool::UserEModeSet::SIGNATURE_HASH,
+            AaveV3Pool::SwapBorrowRateMode::SIGNATURE_HASH,
+            AaveV3Pool::RebalanceStableBorrowRate::SIGNATURE_HASH,
+        ],
+        from_block,
+        head_block,
+        |_, logs| {
+            affected_users.extend(logs.iter().filter_map(get_affected_user));
+            Ok(())
+        },
+    )
+    .await?;
+    // Tokens of the reserves listed now, which include the ones listed while vega was down
+    let tokens = get_reserves_data(Arc::new(provider.clone()), market)
+        .await?
+        .iter()
+        .flat_map(|reserve| [reserve.aTokenAddress, reserve.variableDebtTokenAddress])
+        .collect();
+    scan_logs(
+        provider,
+        tokens,
+        vec![IAToken::Transfer::SIGNATURE_HASH],
+        from_block,
+        head_block,
+        |_, logs| {
+            affected_users.extend(logs.iter().flat_map(get_transfer_users));
+            Ok(())
+        },
+    )
+    .await?;
+    info!(
+        "{} users were affected by pool events or token transfers since the snapshot",
+        affected_users.len()
+    );
+    Ok(affected_users)
+}
+
+/// Same user whistleblower-rs picks from each event, and UserReservesCache refreshes
+fn get_affected_user(log: &Log) -> Option<UserAddress> {
+    let decoded = match log.topic0() {
+        Some(&AaveV3Pool::LiquidationCall::SIGNATURE_HASH) => log
+            .log_decode::<AaveV3Pool::LiquidationCall>()
+            .map(|event| event.inner.data.user),
+        Some(&AaveV3Pool::Borrow::SIGNATURE_HASH) => log
+            .log_decode::<AaveV3Pool::Borrow>()
+            .map(|event| event.inner.data.onBehalfOf),
+        Some(&AaveV3Pool::Supply::SIGNATURE_HASH) => log
+            .log_decode::<AaveV3Pool::Supply>()
+            .map(|event| event.inner.data.onBehalfOf),
+        Some(&AaveV3Pool::Repay::SIGNATURE_HASH) => log
+            .log_decode::<AaveV3Pool::Repay>()
+            .map(|event| event.inner.data.user),
+        Some(&AaveV3Pool::Withdraw::SIGNATURE_HASH) => log
+            .log_decode::<AaveV3Pool::Withdraw>()
+            .map(|event| event.inner.data.user),
+        Some(&AaveV3Pool::ReserveUsedAsCollateralEnabled::SIGNATURE_HASH) => log
+            .log_decode::<AaveV3Pool::ReserveUsedAsCollateralEnabled>()
+            .map(|event| event.inner.data.user),
+        Some(&AaveV3Pool::ReserveUsedAsCollateralDisabled::SIGNATURE_HASH) => log
+            .log_decode::<AaveV3Pool::ReserveUsedAsCollateralDisabled>()
+            .map(|event| event.inner.data.user),
+        Some(&AaveV3Pool::UserEModeSet::SIGNATURE_HASH) => log
+            .log_decode::<AaveV3Pool::UserEModeSet>()
+            .map(|event| event.inner.data.user),
+        Some(&AaveV3Pool::SwapBorrowRateMode::SIGNATURE_HASH) => log
+            .log_decode::<AaveV3Pool::SwapBorrowRateMode>()
+            .map(|event| event.inner.data.user),
+        Some(&AaveV3Pool::RebalanceStableBorrowRate::SIGNATURE_HASH) => log
+            .log_decode::<AaveV3Pool::RebalanceStableBorrowRate>()
+            .map(|event| event.inner.data.user),
+        _ => return None,
+    };
+    decoded
+        .map_err(|e| {
+            warn!(
+                "Failed to decode pool event in tx {:?}: {}",
+                log.transaction_hash, e
+            )
+        })
+        .ok()
+}
+
+/// Both sides of a token transfer, except the zero address of mints and burns
+fn get_transfer_users(log: &Log) -> Vec<UserAddress> {
+    match log.log_decode::<IAToken::Transfer>() {
+        Ok(transfer) => [transfer.inner.data.from, transfer.inner.data.to]
+            .into_iter()
+            .filter(|user| *user != Address::ZERO)
+            .collect(),
+        Err(e) => {
+            warn!(
+                "Failed to decode token transfer in tx {:?}: {}",
+                log.transaction_hash, e
+            );
+            vec![]
+        }
+    }
+}
@@ -1,23 +1,36 @@
 use alloy::{
-    primitives::{address, Address, Bytes, U256},
-    providers::RootProvider,
+    primitives::{Address, U256},
+    providers::{Provider, RootProvider},
     pubsub::PubSubFrontend,
+    rpc::types::state::StateOverride,
+    transports::Transport,
 };
 use futures::future::join_all;
-use overlord_shared::{sol_bindings::pool::AaveV3Pool, UnderwaterUserEvent};
+use overlord_shared::{
+    common::BaseCurrency,
+    market::AaveMarket,
+    multicall::{get_user_account_data_batch, USER_ACCOUNT_DATA_BATCH_SIZE},
+    new_run_id,
+    sol_bindings::pool::AaveV3Pool,
+    PriceUpdateTx, UnderwaterUserEvent,
+};
 use std::{collections::HashMap, sync::Arc};
 use tokio::{sync::broadcast, task};
 use tracing::{info, warn};
 
+use crate::hf_engine::{current_timestamp, OffchainHfEngine};
+use crate::simulation::get_user_account_data_with_overrides;
+
 const HF_MIN_THRESHOLD: u128 = 1_000_000_000_000_000_000u128;
 
 // From observations in profito, these are comparisons between collateral
-// and expected profit:
+// (in 8 decimals USD) and expected profit:
 // > 1e9 ~ $0.xx to $9.xx
 // > 1e10 ~ $10.xx to $99.xx
 // > 1e11 ~ $100.xx to $999.xx
 // > 1e12 ~ $1000.xx and above
-const MIN_REPORTABLE_COLLATERAL: f64 = 1e10;
+// It's converted to the market base currency before comparing.
+const MIN_REPORTABLE_COLLATERAL_IN_USD: f64 = 100.0;
 
 pub struct UnderwaterUserEventBus {
     sender: broadcast::Sender<UnderwaterUserEvent>,
@@ -38,35 +51,120 @@ impl UnderwaterUserEventBus {
     }
 }
 
+/// MIN_REPORTABLE_COLLATERAL_IN_USD in base units. Every underwater user is reported if it can't be
+/// converted, e.g. when the base currency has no USD price.
+fn min_reportable_collateral(base_currency: &BaseCurrency) -> U256 {
+    match base_currency.usd_to_base_units(MIN_REPORTABLE_COLLATERAL_IN_USD) {
+        Ok(min_reportable_collateral) => min_reportable_collateral,
+        Err(e) => {
+            warn!(
+                "Failed to convert the minimum reportable collateral to base units, reporting every underwater user: {}",
+                e
+            );
+            U256::ZERO
+        }
+    }
+}
+
+/// True if the user is underwater with enough collateral to be worth sending to profito
+fn is_reportable(
+    data: &AaveV3Pool::getUserAccountDataReturn,
+    min_reportable_collateral: U256,
+) -> bool {
+    data.healthFactor < U256::from(HF_MIN_THRESHOLD)
+        && data.totalCollateralBase > min_reportable_collateral
+}
+
+type UserAccountDataResult =
+    Result<AaveV3Pool::getUserAccountDataReturn, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Gets the account data of every user in a single multicall. If the multicall itself fails
+/// (e.g. it ran out of gas) it falls back to one call per user.
+/// Results are aligned with `users`.
+pub(crate) async fn get_user_account_data_for_users<T, P>(
+    provider: &P,
+    market: &AaveMarket,
+    users: &[Address],
+    state_override: Option<&StateOverride>,
+) -> Vec<UserAccountDataResult>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    match get_user_account_data_batch(provider, market, users, state_override).await {
+        Ok(results) => {
+            return results
+                .into_iter()
+                .zip(users)
+                .map(|(result, user)| {
+                    result.ok_or_else(|| format!("getUserAccountData({}) reverted", user).into())
+                })
+                .collect()
+        }
+        Err(e) => warn!(
+            "Multicall for {} users failed, falling back to single calls: {:?}",
+            users.len(),
+            e
+        ),
+    }
+    let pool = AaveV3Pool::new(market.pool, provider);
+    let mut results = Vec::with_capacity(users.len());
+    for user in users {
+        // getUserAccountData already applies the user's e-mode category LT on chain
+        let result = match state_override {
+            Some(overrides) => {
+                get_user_account_data_with_overrides(provider, market.pool, *user, overrides).await
+            }
+            None => pool
+                .getUserAccountData(*user)
+                .call()
+                .await
+                .map_err(|e| e.into()),
+        };
+        results.push(result);
+    }
+    results
+}
+
 pub struct HealthFactorCalculationResults {
     pub raw_results: HashMap<Address, U256>,
     pub under_1_hf: HashMap<Address, U256>,
 }
 
-/// Given a array of user address buckets and a provider, query the AAVE v3's Pool contract
+/// Given a array of user address buckets and a provider, query the Pool contract of `market`
 /// and return a structure with the HF of all addresses, as well as a separate attribute with
-/// only underwater users
+/// only underwater users.
+/// Users are fetched in batches of USER_ACCOUNT_DATA_BATCH_SIZE through Multicall3.
+/// If `state_override` is set, every call is an eth_call with those overrides applied on top
+/// of the provider's latest state.
+/// Underwater users are reported under `run_id`, or a new one if it's not set.
 pub async fn get_hf_for_users(
     address_buckets: Vec<Vec<Address>>,
     provider: &RootProvider<PubSubFrontend>,
+    market: &AaveMarket,
     trace_id: Option<String>,
+    run_id: Option<String>,
     tx_hash: Option<String>,
-    raw_tx: Option<Bytes>,
+    price_update_tx: Option<PriceUpdateTx>,
     inclusion_block: Option<String>,
+    slot_deadline_ms: Option<u64>,
     new_prices_by_asset: Vec<(Address, String, U256)>,
     event_bus: Option<Arc<UnderwaterUserEventBus>>,
+    base_currency: BaseCurrency,
+    state_override: Option<StateOverride>,
 ) -> HealthFactorCalculationResults {
+    let min_reportable_collateral = min_reportable_collateral(&base_currency);
+    let run_id = run_id.unwrap_or_else(|| new_run_id(trace_id.as_deref().unwrap_or("initial-run")));
     let mut tasks = vec![];
-    let pool = Arc::new(AaveV3Pool::new(
-        address!("87870Bca3F3fD6335C3F4ce8392D69350B4fA4E2"),
-        provider.clone(),
-    ));
     for bucket in address_buckets {
-        let pool = pool.clone();
+        let provider = provider.clone();
+        let market = market.clone();
+        let state_override = state_override.clone();
         let event_bus = event_bus.clone();
         let new_prices_by_asset = new_prices_by_asset.clone();
         let tx_hash = tx_hash.as_ref().map(String::from);
-        let raw_tx = raw_tx.clone();
+        let price_update_tx = price_update_tx.clone();
+        let run_id = run_id.clone();
         let trace_id = trace_id
             .as_ref()
             .map(String::from)
@@ -77,20 +175,32 @@ pub async fn get_hf_for_users(
             .unwrap_or_else(|| String::from("initial-run"));
         let task = task::spawn(async move {
             let mut bucket_results = HashMap::new();
-            for address in bucket {
-                let result = pool.getUserAccountData(address).call().await;
+            let mut results = vec![];
+            for chunk in bucket.chunks(USER_ACCOUNT_DATA_BATCH_SIZE) {
+                results.extend(
+                    get_user_account_data_for_users(
+                        &provider,
+                        &market,
+                        chunk,
+                        state_override.as_ref(),
+                    )
+                    .await,
+                );
+            }
+            for (address, result) in bucket.into_iter().zip(results) {
                 match result {
                     Ok(data) => {
-                        if data.healthFactor < U256::from(HF_MIN_THRESHOLD)
-                            && data.totalCollateralBase > U256::from(MIN_REPORTABLE_COLLATERAL)
-                        {
+                        if is_reportable(&data, min_reportable_collateral) {
                             if let Some(bus) = &event_bus {
                                 bus.send(UnderwaterUserEvent {
                                     address,
+                                    market: market.clone(),
                                     trace_id: trace_id.clone(),
+                                    run_id: run_id.clone(),
                                     tx_hash: tx_hash.clone(),
-                                    raw_tx: raw_tx.clone(),
+                                    price_update_tx: price_update_tx.clone(),
                                     inclusion_block: inclusion_block.clone(),
+                                    slot_deadline_ms,
                                     total_collateral_base: data.totalCollateralBase,
                                     user_account_data: data.clone(),
                                     new_asset_prices: new_prices_by_asset.clone(),
@@ -130,3 +240,78 @@ pub async fn get_hf_for_users(
         under_1_hf,
     }
 }
+
+/// Same as get_hf_for_users(), but every account is computed in memory by the off-chain HF engine
+/// with `price_overrides` applied, so there's no RPC involved. Users unknown to the engine are
+/// left out of the results.
+/// If `incremental` is set, only the terms of the repriced assets are recomputed for users that
+/// have a cached breakdown in the engine.
+pub fn get_hf_for_users_offchain(
+    address_buckets: &[Vec<Address>],
+    market: &AaveMarket,
+    engine: &OffchainHfEngine,
+    price_overrides: &HashMap<Address, U256>,
+    incremental: bool,
+    trace_id: String,
+    run_id: String,
+    tx_hash: Option<String>,
+    price_update_tx: Option<PriceUpdateTx>,
+    inclusion_block: String,
+    slot_deadline_ms: Option<u64>,
+    new_prices_by_asset: Vec<(Address, String, U256)>,
+    event_bus: Option<Arc<UnderwaterUserEventBus>>,
+    base_currency: BaseCurrency,
+) -> HealthFactorCalculationResults {
+    let min_reportable_collateral = min_reportable_collateral(&base_currency);
+    let timestamp = current_timestamp();
+    let mut raw_results = HashMap::new();
+    let mut under_1_hf = HashMap::new();
+    let mut unknown_users = 0;
+    for address in address_buckets.iter().flatten() {
+        let repriced = if incremental {
+            engine.get_user_account_data_repriced(address, price_overrides)
+        } else {
+            None
+        };
+        let data = match repriced
+            .or_else(|| engine.get_user_account_data(address, price_overrides, timestamp))
+        {
+            Some(data) => data,
+            None => {
+                unknown_users += 1;
+                continue;
+            }
+        };
+        if is_reportable(&data, min_reportable_collateral) {
+            if let Some(bus) = &event_bus {
+                bus.send(UnderwaterUserEvent {
+                    address: *address,
+                    market: market.clone(),
+                    trace_id: trace_id.clone(),
+                    run_id: run_id.clone(),
+                    tx_hash: tx_hash.clone(),
+                    price_update_tx: price_update_tx.clone(),
+                    inclusion_block: inclusion_block.clone(),
+                    slot_deadline_ms,
+                    total_collateral_base: data.totalCollateralBase,
+                    user_account_data: data.clone(),
+                    new_asset_prices: new_prices_by_asset.clone(),
+                });
+            }
+        }
+        if data.healthFactor < U256::from(HF_MIN_THRESHOLD) {
+            under_1_hf.insert(*address, data.healthFactor);
+        }
+        raw_results.insert(*address, data.healthFactor);
+    }
+    if unknown_users > 0 {
+        warn!(
+            "{} candidates for bundle {} are not in the off-chain HF engine",
+            unknown_users, trace_id
+        );
+    }
+    HealthFactorCalculationResults {
+        raw_results,
+        under_1_hf,
+    }
+}
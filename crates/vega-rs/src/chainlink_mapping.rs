@@ -0,0 +1,76 @@
+use alloy::{providers::RootProvider, pubsub::PubSubFrontend};
+use overlord_shared::{
+    common::get_reserves_data, constants::GHO_PRICE_ORACLE, market::AaveMarket,
+    resolvers::resolve_price_source, sol_bindings::AaveOracle,
+};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::error::CacheError;
+use crate::user_reserve_cache::{AaveReserveInfo, ChainlinkMapping};
+
+/// Builds the Chainlink mapping from on-chain data, instead of a hand-maintained file.
+///
+/// For every reserve of `market`, the AaveOracle source is resolved down to the OCR aggregator
+/// behind its adapter chain, which is what price updates are forwarded to. Reserves whose source
+/// can't be read or resolved are logged and left out. GHO's price is fixed, so it's mapped to its price oracle,
+/// which is what oops forwards the synthetic updates of the GHO watcher to.
+pub async fn generate_chainlink_mapping(
+    provider: &RootProvider<PubSubFrontend>,
+    market: &AaveMarket,
+) -> Result<ChainlinkMapping, CacheError> {
+    let provider = Arc::new(provider.clone());
+    let reserves = get_reserves_data(provider.clone(), market).await?;
+    let aave_oracle = AaveOracle::new(market.oracle, provider.clone());
+    let mut mapping = ChainlinkMapping::new();
+    let mut unresolved = vec![];
+    for reserve in reserves {
+        let source = match aave_oracle
+            .getSourceOfAsset(reserve.underlyingAsset)
+            .call()
+            .await
+        {
+            Ok(response) => response._0,
+            Err(e) => {
+                warn!(
+                    "Error trying to call getSourceOfAsset({}): {}",
+                    reserve.symbol, e
+                );
+                unresolved.push(reserve.symbol);
+                continue;
+            }
+        };
+        let aggregator = match resolve_price_source(&provider, source).await {
+            Ok(resolved) => match resolved.aggregator {
+                Some(aggregator) => aggregator.address,
+                None if source == GHO_PRICE_ORACLE => GHO_PRICE_ORACLE,
+                None => continue,
+            },
+            Err(e) => {
+                warn!("Can't resolve price source of {}: {}", reserve.symbol, e);
+                unresolved.push(reserve.symbol);
+                continue;
+            }
+        };
+        mapping
+            .entry(aggregator)
+            .or_default()
+            .push(AaveReserveInfo {
+                symbol: reserve.symbol,
+                reserve_address: reserve.underlyingAsset,
+            });
+    }
+    if !unresolved.is_empty() {
+        warn!(
+            "Price updates won't be tracked for {} reserves: {}",
+            unresolved.len(),
+            unresolved.join(", ")
+        );
+    }
+    info!(
+        "Generated Chainlink mapping of the {} market with {} addresses",
+        market.name,
+        mapping.len()
+    );
+    Ok(mapping)
+}
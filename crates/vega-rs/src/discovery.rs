@@ -0,0 +1,196 @@
+use alloy::{
+    primitives::{Address, B256},
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::{Filter, Log},
+    sol_types::SolEvent,
+};
+use overlord_shared::{
+    error::{FileError, NodeError},
+    market::AaveMarket,
+    sol_bindings::pool::AaveV3Pool,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs, path::Path};
+use tracing::{info, warn};
+
+use crate::error::CacheError;
+
+type UserAddress = Address;
+
+// eth_getLogs range used when the previous one succeeded. Busy ranges are split until they fit
+// in the node's response limits.
+const MAX_LOGS_RANGE: u64 = 10_000;
+// A range this small that still fails means something other than the response size is wrong
+const MIN_LOGS_RANGE: u64 = 16;
+// Blocks scanned between checkpoint writes
+const CHECKPOINT_INTERVAL: u64 = 250_000;
+
+/// Everything discovered so far. It's written to disk periodically, so an interrupted backfill
+/// (or the next restart) resumes from `last_scanned_block` instead of from the pool deployment.
+#[derive(Default, Serialize, Deserialize)]
+struct DiscoveryCheckpoint {
+    last_scanned_block: Option<u64>,
+    borrowers: HashSet<UserAddress>,
+}
+
+impl DiscoveryCheckpoint {
+    fn load(path: &Path) -> Result<Self, CacheError> {
+        if !path.exists() {
+            info!(
+                "No borrower discovery checkpoint at {}, scanning from the pool deployment",
+                path.display()
+            );
+            return Ok(Self::default());
+        }
+        let contents = fs::read(path).map_err(|e| FileError::new(path, e))?;
+        let checkpoint: Self =
+            serde_json::from_slice(&contents).map_err(|e| CacheError::malformed(path, e))?;
+        info!(
+            "Loaded borrower discovery checkpoint with {} borrowers up to block {:?}",
+            checkpoint.borrowers.len(),
+            checkpoint.last_scanned_block
+        );
+        Ok(checkpoint)
+    }
+
+    /// Writes to a temp file first, so a crash mid-write can't leave a truncated checkpoint
+    fn save(&self, path: &Path) -> Result<(), CacheError> {
+        let tmp_path = path.with_extension("tmp");
+        let contents = serde_json::to_vec(self).map_err(|e| CacheError::malformed(path, e))?;
+        fs::write(&tmp_path, contents).map_err(|e| FileError::new(&tmp_path, e))?;
+        fs::rename(&tmp_path, path).map_err(|e| FileError::new(path, e))?;
+        Ok(())
+    }
+}
+
+/// Returns every address that ever borrowed from the pool of `market`, by backfilling Borrow
+/// events with eth_getLogs. Progress is checkpointed to `checkpoint_file`, so only blocks produced
+/// since the last run are scanned. Borrows from here on are picked up through whistleblower-rs.
+///
+/// Users that have since repaid are still returned, they're filtered out when their positions
+/// are read.
+pub async fn discover_borrowers(
+    provider: &RootProvider<PubSubFrontend>,
+    market: &AaveMarket,
+    checkpoint_file: &str,
+) -> Result<Vec<UserAddress>, CacheError> {
+    let checkpoint_path = Path::new(checkpoint_file);
+    let mut checkpoint = DiscoveryCheckpoint::load(checkpoint_path)?;
+    let head_block = provider
+        .get_block_number()
+        .await
+        .map_err(|e| NodeError::rpc("eth_blockNumber", e))?;
+    let first_block = checkpoint
+        .last_scanned_block
+        .map_or(market.deployment_block, |block| block + 1);
+    if first_block > head_block {
+        return Ok(checkpoint.borrowers.into_iter().collect());
+    }
+    info!(
+        "Scanning Borrow events of the {} market from block {} to {} ({} blocks)",
+        market.name,
+        first_block,
+        head_block,
+        head_block - first_block + 1
+    );
+
+    let initial_borrowers = checkpoint.borrowers.len();
+    let mut last_saved_block = first_block;
+    let scan = scan_logs(
+        provider,
+        vec![market.pool],
+        vec![AaveV3Pool::Borrow::SIGNATURE_HASH],
+        first_block,
+        head_block,
+        |to_block, logs| {
+            for log in logs {
+                match log.log_decode::<AaveV3Pool::Borrow>() {
+                    Ok(borrow) => {
+                        checkpoint.borrowers.insert(borrow.inner.data.onBehalfOf);
+                    }
+                    Err(e) => warn!(
+                        "Failed to decode Borrow event in tx {:?}: {}",
+                        log.transaction_hash, e
+                    ),
+                }
+            }
+            checkpoint.last_scanned_block = Some(to_block);
+            if to_block - last_saved_block >= CHECKPOINT_INTERVAL {
+                checkpoint.save(checkpoint_path)?;
+                last_saved_block = to_block;
+                info!(
+                    "Borrower discovery at block {} of {}, {} borrowers so far",
+                    to_block,
+                    head_block,
+                    checkpoint.borrowers.len()
+                );
+            }
+            Ok(())
+        },
+    )
+    .await;
+    if let Err(e) = scan {
+        // Keep what was found so far, the next run resumes from here
+        checkpoint.save(checkpoint_path)?;
+        return Err(e);
+    }
+    checkpoint.save(checkpoint_path)?;
+    info!(
+        "Borrower discovery complete up to block {}: {} borrowers ({} new)",
+        head_block,
+        checkpoint.borrowers.len(),
+        checkpoint.borrowers.len() - initial_borrowers
+    );
+    Ok(checkpoint.borrowers.into_iter().collect())
+}
+
+/// Feeds the logs of `addresses` matching any of `signatures` between `from_block` and `to_block`
+/// to `on_logs`, one range at a time and in order, along with the last block of the range.
+///
+/// Ranges start at MAX_LOGS_RANGE blocks and are halved whenever the node fails to return them,
+/// so busy stretches of the chain still fit in its response limits.
+pub(crate) async fn scan_logs<F>(
+    provider: &RootProvider<PubSubFrontend>,
+    addresses: Vec<Address>,
+    signatures: Vec<B256>,
+    from_block: u64,
+    to_block: u64,
+    mut on_logs: F,
+) -> Result<(), CacheError>
+where
+    F: FnMut(u64, Vec<Log>) -> Result<(), CacheError>,
+{
+    let mut from_block = from_block;
+    let mut range = MAX_LOGS_RANGE;
+    while from_block <= to_block {
+        let range_end = (from_block + range - 1).min(to_block);
+        let filter = Filter::new()
+            .address(addresses.clone())
+            .event_signature(signatures.clone())
+            .from_block(from_block)
+            .to_block(range_end);
+        let logs = match provider.get_logs(&filter).await {
+            Ok(logs) => logs,
+            Err(e) if range > MIN_LOGS_RANGE => {
+                range /= 2;
+                warn!(
+                    "Failed to get logs for blocks {}-{}, retrying with a range of {}: {}",
+                    from_block, range_end, range, e
+                );
+                continue;
+            }
+            Err(e) => {
+                return Err(NodeError::rpc(
+                    format!("eth_getLogs for blocks {}-{}", from_block, range_end),
+                    e,
+                )
+                .into())
+            }
+        };
+        on_logs(range_end, logs)?;
+        from_block = range_end + 1;
+        range = (range * 2).min(MAX_LOGS_RANGE);
+    }
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use alloy::primitives::Address;
+use overlord_shared::{
+    error::{FileError, NodeError},
+    multicall::MulticallError,
+};
+use std::{fmt::Display, path::Path};
+use thiserror::Error;
+
+/// Why vega couldn't simulate a price update, or set itself up
+#[derive(Debug, Error)]
+pub enum VegaError {
+    /// A request to the node failed, see NodeError
+    #[error(transparent)]
+    Node(#[from] NodeError),
+    /// What was read doesn't add up, e.g. a reserve the HF engine doesn't know of
+    #[error("Inconsistent state: {0}")]
+    InconsistentState(String),
+    /// A file couldn't be read or written
+    #[error(transparent)]
+    Io(#[from] FileError),
+    /// A setting is missing or invalid
+    #[error("{0}")]
+    Config(String),
+    /// A multicall failed as a whole, see overlord_shared::multicall
+    #[error("Multicall failed: {0}")]
+    Multicall(#[from] MulticallError),
+    /// Errors of overlord_shared (and the fork provider), which aren't typed
+    #[error(transparent)]
+    Shared(#[from] Box<dyn std::error::Error>),
+}
+
+impl VegaError {
+    /// True for node failures that may go away if tried again, see NodeError::is_retryable()
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Node(e) if e.is_retryable())
+    }
+}
+
+/// Why the user reserves cache (or what it's built from: discovery, snapshots, the Chainlink
+/// mapping) couldn't be loaded or updated
+#[derive(Debug, Error)]
+pub enum CacheError {
+    /// A request to the node failed, see NodeError
+    #[error(transparent)]
+    Node(#[from] NodeError),
+    /// A checkpoint, snapshot, mapping or dump couldn't be read or written
+    #[error(transparent)]
+    Io(#[from] FileError),
+    /// A file that can't be parsed (or encoded), written by another tool or by hand
+    #[error("Malformed {path}: {reason}")]
+    Malformed { path: String, reason: String },
+    /// A whistleblower update whose args aren't the ones of its event. Trying it again won't help.
+    #[error("Malformed whistleblower update: {0}")]
+    MalformedUpdate(String),
+    /// The mutation log doesn't go back far enough to undo the cache to `block_number`
+    #[error(
+        "Can't undo the cache to block {block_number}, mutations of block {oldest_block} were \
+         already dropped from the log"
+    )]
+    UndoOutOfRange {
+        block_number: u64,
+        oldest_block: u64,
+    },
+    /// A reserve that isn't in getReservesList(), so it has no index in the user configuration
+    /// or the e-mode collateral bitmaps
+    #[error("Reserve {0} isn't in the reserves list")]
+    UnknownReserve(Address),
+    /// Errors of overlord_shared, which aren't typed
+    #[error(transparent)]
+    Shared(#[from] Box<dyn std::error::Error>),
+}
+
+impl CacheError {
+    pub fn malformed(path: impl AsRef<Path>, reason: impl Display) -> Self {
+        Self::Malformed {
+            path: path.as_ref().display().to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// True for node failures that may go away if tried again, see NodeError::is_retryable()
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Node(e) if e.is_retryable())
+    }
+}
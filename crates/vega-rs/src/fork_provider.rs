@@ -41,12 +41,12 @@ type AnvilForkProvider = Result<
     alloy::transports::RpcError<alloy::transports::TransportErrorKind>,
 >;
 
-struct IpcForkFile {
+pub(crate) struct IpcForkFile {
     path: String,
 }
 
 impl IpcForkFile {
-    fn new(path: String) -> Self {
+    pub(crate) fn new(path: String) -> Self {
         File::create(&path).expect("Failed to create IPC fork file");
         IpcForkFile { path }
     }
@@ -76,7 +76,7 @@ async fn build_pub_tx(
         .max_priority_fee_per_gas(max_priority_fee)
 }
 
-async fn get_storage_key_for_price_update(
+pub(crate) async fn get_storage_key_for_price_update(
     provider: RootProvider<PubSubFrontend>,
     bundle: &PriceUpdateBundle,
 ) -> Result<U256, Box<dyn std::error::Error>> {
@@ -139,25 +139,35 @@ async fn get_storage_key_for_price_update(
 /// Expected:  0x680d728f680d727a000000000000000000000000000000000000000005f5d6e3
 ///              └─ts1──┘└──ts2─┘                                 └───answer────┘
 ///
-fn get_payload_for_price_update(bundle: &PriceUpdateBundle) -> FixedBytes<32> {
+pub(crate) fn get_payload_for_price_update(bundle: &PriceUpdateBundle) -> FixedBytes<32> {
     // We just need a timestamp, the actual value doesn't really matter
     let timestamp = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_secs() as u32;
+    encode_transmission(bundle.tx_new_price, timestamp, timestamp)
+}
 
+/// Packs a Transmission the way Solidity lays it out in its slot: the first field (answer) takes
+/// the lowest 24 bytes, observationsTimestamp the next 4 and transmissionTimestamp the highest 4.
+/// See check_price_update_override() for how this is validated against real aggregators.
+pub(crate) fn encode_transmission(
+    answer: U256,
+    observations_timestamp: u32,
+    transmission_timestamp: u32,
+) -> FixedBytes<32> {
     // Create a 32-byte array to hold our result
     let mut bytes = [0u8; 32];
 
     // Put timestamps at the beginning (bytes 0-7)
-    // First timestamp (bytes 0-3)
-    bytes[0..4].copy_from_slice(&timestamp.to_be_bytes());
-    // Second timestamp (bytes 4-7)
-    bytes[4..8].copy_from_slice(&timestamp.to_be_bytes());
+    // transmissionTimestamp (bytes 0-3)
+    bytes[0..4].copy_from_slice(&transmission_timestamp.to_be_bytes());
+    // observationsTimestamp (bytes 4-7)
+    bytes[4..8].copy_from_slice(&observations_timestamp.to_be_bytes());
 
     // The price (answer) needs to be in the last 24 bytes (bytes 8-31)
     // Get the price as bytes - U256 uses 32 bytes, but we need just 24 bytes
-    let price_bytes: [u8; 32] = bundle.tx_new_price.to_be_bytes_vec().try_into().unwrap_or([0; 32]);
+    let price_bytes: [u8; 32] = answer.to_be_bytes_vec().try_into().unwrap_or([0; 32]);
 
     // We need to copy the last 24 bytes of the price (32-byte value)
     // This preserves the big-endian representation while truncating to 24 bytes
@@ -167,6 +177,43 @@ fn get_payload_for_price_update(bundle: &PriceUpdateBundle) -> FixedBytes<32> {
     FixedBytes::from_slice(&bytes)
 }
 
+/// Writes the new answer of the bundle straight into the aggregator storage of the fork
+pub(crate) async fn apply_price_update_to_fork(
+    provider: RootProvider<PubSubFrontend>,
+    fork_provider: &RootProvider<PubSubFrontend>,
+    bundle: &PriceUpdateBundle,
+) -> Result<(), String> {
+    let trace_id = &bundle.trace_id;
+    let storage_key = match get_storage_key_for_price_update(provider, bundle).await {
+        Ok(storage_key) => storage_key,
+        Err(e) => return Err(format!("Failed to get storage key for bundle {}: {}", trace_id, e))
+    };
+    let storage_value = get_payload_for_price_update(bundle);
+    info!("About to call anvil_setStorageAt({}, {:?}, {:?})", bundle.forward_to, storage_key, storage_value);
+    match fork_provider
+        // The way price updates work, is that some address submits
+        // a transaction that calls the forward() method on a contract.
+        // That forward() method receives 2 args: `to_address` and `data`
+        // In anvil_setStorageAt, the first argument is that `to_address`,
+        // that we receive from the bundle in the `forward_to` attribute
+        .anvil_set_storage_at(
+            bundle.forward_to,
+            storage_key,
+            storage_value,
+        )
+        .await
+    {
+        Ok(_) => {
+            info!("Successfuly set storage for bundle {}", trace_id);
+        }
+        Err(e) => {
+            return Err(format!("Failed to set storage for bundle {}: {:?}", trace_id, e));
+        }
+    }
+    info!("Storage in fork for bundle {} has been tweaked", trace_id);
+    Ok(())
+}
+
 pub struct ForkProvider {
     // The instance of Anvil that is running the fork
     pub _anvil_instance: AnvilInstance,
@@ -186,7 +233,7 @@ impl Drop for ForkProvider {
 }
 
 impl ForkProvider {
-    fn cleanup_anvil_instance(anvil: &mut AnvilInstance) {
+    pub(crate) fn cleanup_anvil_instance(anvil: &mut AnvilInstance) {
         info!("Cleaning up anvil instance");
         if let Err(e) = anvil.child_mut().kill() {
             error!("Failed to kill AnvilInstance: {:?}", e);
@@ -280,38 +327,11 @@ impl ForkProvider {
         };
         // Step 4: Apply the price update to the fork
         if let Some(bundle) = bundle {
-            // Step 4.0: Fund the account the tx comes from, to prevent failures when applying the tx
-            let storage_key = get_storage_key_for_price_update(provider.clone(), &bundle.clone()).await.unwrap();
-            let storage_value = get_payload_for_price_update(&bundle.clone());
-            info!("About to call anvil_setStorageAt({}, {:?}, {:?})", bundle.forward_to, storage_key, storage_value);
-            match fork_provider
-                .as_ref()
-                .unwrap()
-                // The way price updates work, is that some address submits
-                // a transaction that calls the forward() method on a contract.
-                // That forward() method receives 2 args: `to_address` and `data`
-                // In anvil_setStorageAt, the first argument is that `to_address`,
-                // that we receive from the bundle in the `forward_to` attribute
-                .anvil_set_storage_at(
-                    bundle.forward_to,
-                    storage_key,
-                    storage_value,
-                )
-                .await
-            {
-                Ok(_) => {
-                    info!("Successfuly set storage for bundle {}", trace_id);
-                }
-                Err(e) => {
-                    error!(
-                        "Failed to set storage for bundle {}: {:?}",
-                        trace_id, e
-                    );
-                    Self::cleanup_anvil_instance(&mut anvil);
-                    return Err("Failed to set storage".to_string());
-                }
+            if let Err(e) = apply_price_update_to_fork(provider.clone(), fork_provider.as_ref().unwrap(), bundle).await {
+                error!("{}", e);
+                Self::cleanup_anvil_instance(&mut anvil);
+                return Err("Failed to set storage".to_string());
             }
-            info!("Storage in fork for bundle {} has been tweaked", trace_id);
         }
         // Step 5: Return the fork provider with the new state
         Ok((anvil, fork_provider, ipc_fork_file))
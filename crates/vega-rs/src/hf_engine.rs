@@ -0,0 +1,649 @@
+use alloy::{
+    primitives::{Address, U256},
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+};
+use overlord_shared::{
+    common::{get_reserves_data, UserEModeCategory},
+    decimals_guard::DECIMALS_GUARD,
+    error::NodeError,
+    health_factor::{calculate_account_data, value_in_base_currency, AccountTerm},
+    market::AaveMarket,
+    math::{ray_mul, RAY},
+    multicall::get_reserve_normalized_indexes_batch,
+    reserve_configuration::ReserveConfiguration,
+    sol_bindings::{
+        pool::AaveV3Pool, AccessControlledOCR2Aggregator,
+        IUiPoolDataProviderV3::AggregatedReserveData,
+    },
+    status::{Counter, Gauge},
+    PriceUpdateBundle,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::RwLock,
+    task::JoinHandle,
+    time::{interval, Duration, Instant},
+};
+use tracing::warn;
+
+use crate::error::VegaError;
+
+const SECONDS_PER_YEAR: U256 = U256::from_limbs([365 * 24 * 60 * 60, 0, 0, 0]);
+
+const RESERVES_REFRESH_INTERVAL: Duration = Duration::from_secs(12);
+const INDEX_DRIFT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Largest drift (in parts per million of the pool's value) allowed between the normalized
+/// income/debt the cached breakdowns were built with and the ones of the pool. Past it, the
+/// reserves are refreshed right away.
+pub const INDEX_DRIFT_TOLERANCE_PPM_ENV: &str = "VEGA_INDEX_DRIFT_TOLERANCE_PPM";
+const INDEX_DRIFT_TOLERANCE_PPM: u64 = 10;
+const PPM: U256 = U256::from_limbs([1_000_000, 0, 0, 0]);
+/// Reserves are refreshed every block by spawn_reserves_refresh(), so anything older than a few
+/// blocks means the refresh task is failing and the engine shouldn't be trusted.
+pub const MAX_RESERVES_AGE: Duration = Duration::from_secs(60);
+
+/// The subset of AggregatedReserveData needed to compute account data
+#[derive(Clone, Debug)]
+pub struct ReserveState {
+    pub reserve_index: usize,
+    pub asset_unit: U256,
+    pub ltv: U256,
+    pub liquidation_threshold: U256,
+    pub liquidity_index: U256,
+    pub liquidity_rate: U256,
+    pub variable_borrow_index: U256,
+    pub variable_borrow_rate: U256,
+    pub last_update_timestamp: u64,
+    /// In base currency, as returned by the AaveOracle
+    pub price: U256,
+    pub configuration: ReserveConfiguration,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserScaledPosition {
+    pub underlying_asset: Address,
+    pub scaled_atoken_balance: U256,
+    pub usage_as_collateral_enabled_on_user: bool,
+    pub scaled_variable_debt: U256,
+}
+
+/// What a single position contributes to the account data, with balances already normalized
+#[derive(Clone, Debug)]
+struct AssetTerms {
+    underlying_asset: Address,
+    asset_unit: U256,
+    /// Zero unless the position counts as collateral
+    collateral_balance: U256,
+    debt_balance: U256,
+    /// Balances in base currency at the price used to build the terms
+    collateral_in_base_currency: U256,
+    debt_in_base_currency: U256,
+    /// Effective parameters, i.e. the e-mode ones if the asset is collateral in the user category
+    ltv: U256,
+    liquidation_threshold: U256,
+}
+
+#[derive(Clone, Debug)]
+struct UserState {
+    positions: Vec<UserScaledPosition>,
+    emode_category: u8,
+    /// Terms at the cached prices, rebuilt whenever the user or the reserves change, so a price
+    /// update only needs to reprice the affected assets
+    breakdown: Option<Vec<AssetTerms>>,
+}
+
+/// Mirrors Aave's GenericLogic.calculateUserAccountData() from cached state, so health factors
+/// can be recomputed for a new price without any RPC.
+///
+/// The engine only knows what it was last fed: user positions are updated from the user cache
+/// (i.e. on whistleblower events) and reserves from getReservesData(). Interest accrued since
+/// the last reserve update is applied the same way the pool does it.
+///
+/// GHO debt is computed without the stkAAVE discount, so the debt of GHO borrowers with a
+/// discount is slightly overestimated.
+///
+/// Each user also keeps a per-asset breakdown of its account data at the cached prices, which
+/// get_user_account_data_repriced() uses to only recompute the terms of the assets whose price
+/// changed.
+#[derive(Default)]
+pub struct OffchainHfEngine {
+    reserves: HashMap<Address, ReserveState>,
+    emode_categories: HashMap<u8, UserEModeCategory>,
+    users: HashMap<Address, UserState>,
+    reserves_updated_at: Option<Instant>,
+    /// Timestamp the breakdowns of every user were last built at. Users set since then have
+    /// newer ones.
+    breakdowns_built_at: u64,
+}
+
+impl OffchainHfEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `reserves_data` must come straight from getReservesData(), so that its order matches the
+    /// reserve indexes used in the user configuration and e-mode bitmaps.
+    ///
+    /// Reserves quarantined by DECIMALS_GUARD are left out, so users with a balance or debt in them
+    /// can't be evaluated rather than valued with the wrong decimals.
+    pub fn update_reserves(
+        &mut self,
+        market: &AaveMarket,
+        reserves_data: &[AggregatedReserveData],
+    ) {
+        self.reserves = reserves_data
+            .iter()
+            .enumerate()
+            .filter(|(_, reserve)| !DECIMALS_GUARD.is_quarantined(market, &reserve.underlyingAsset))
+            .map(|(reserve_index, reserve)| {
+                (
+                    reserve.underlyingAsset,
+                    ReserveState {
+                        reserve_index,
+                        asset_unit: U256::from(10).pow(reserve.decimals),
+                        ltv: reserve.baseLTVasCollateral,
+                        liquidation_threshold: reserve.reserveLiquidationThreshold,
+                        liquidity_index: U256::from(reserve.liquidityIndex),
+                        liquidity_rate: U256::from(reserve.liquidityRate),
+                        variable_borrow_index: U256::from(reserve.variableBorrowIndex),
+                        variable_borrow_rate: U256::from(reserve.variableBorrowRate),
+                        last_update_timestamp: reserve.lastUpdateTimestamp,
+                        price: reserve.priceInMarketReferenceCurrency,
+                        configuration: ReserveConfiguration::from_reserve_data(reserve),
+                    },
+                )
+            })
+            .collect();
+        self.reserves_updated_at = Some(Instant::now());
+        self.refresh_breakdowns();
+    }
+
+    pub fn update_emode_categories(&mut self, emode_categories: HashMap<u8, UserEModeCategory>) {
+        self.emode_categories = emode_categories;
+        self.refresh_breakdowns();
+    }
+
+    fn refresh_breakdowns(&mut self) {
+        let timestamp = current_timestamp();
+        self.breakdowns_built_at = timestamp;
+        for user_state in self.users.values_mut() {
+            user_state.breakdown = build_asset_terms(
+                &self.reserves,
+                &self.emode_categories,
+                user_state,
+                &HashMap::new(),
+                timestamp,
+            );
+        }
+    }
+
+    pub fn set_user(
+        &mut self,
+        user: Address,
+        positions: Vec<UserScaledPosition>,
+        emode_category: u8,
+    ) {
+        let mut user_state = UserState {
+            positions,
+            emode_category,
+            breakdown: None,
+        };
+        user_state.breakdown = build_asset_terms(
+            &self.reserves,
+            &self.emode_categories,
+            &user_state,
+            &HashMap::new(),
+            current_timestamp(),
+        );
+        self.users.insert(user, user_state);
+    }
+
+    pub fn remove_user(&mut self, user: &Address) {
+        self.users.remove(user);
+    }
+
+    pub fn has_user(&self, user: &Address) -> bool {
+        self.users.contains_key(user)
+    }
+
+    pub fn user_positions(&self, user: &Address) -> Option<&[UserScaledPosition]> {
+        self.users
+            .get(user)
+            .map(|user_state| user_state.positions.as_slice())
+    }
+
+    pub fn user_emode_category(&self, user: &Address) -> Option<u8> {
+        self.users
+            .get(user)
+            .map(|user_state| user_state.emode_category)
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.users.len()
+    }
+
+    /// Positions of every user, which is everything needed to rebuild the user cache
+    pub fn positions_by_user(&self) -> HashMap<Address, Vec<UserScaledPosition>> {
+        self.users
+            .iter()
+            .map(|(user, user_state)| (*user, user_state.positions.clone()))
+            .collect()
+    }
+
+    /// True if reserves have been loaded recently enough to be trusted
+    pub fn is_ready(&self) -> bool {
+        self.reserves_updated_at
+            .is_some_and(|updated_at| updated_at.elapsed() <= MAX_RESERVES_AGE)
+    }
+
+    /// Normalized income and debt of every reserve as of when the breakdowns were last built,
+    /// which is what get_user_account_data_repriced() keeps valuing balances with
+    pub fn breakdown_indexes(&self) -> HashMap<Address, (U256, U256)> {
+        self.reserves
+            .iter()
+            .filter_map(|(asset, reserve)| {
+                Some((
+                    *asset,
+                    (
+                        get_normalized_income(reserve, self.breakdowns_built_at)?,
+                        get_normalized_debt(reserve, self.breakdowns_built_at)?,
+                    ),
+                ))
+            })
+            .collect()
+    }
+
+    pub fn get_reserve(&self, asset: &Address) -> Option<&ReserveState> {
+        self.reserves.get(asset)
+    }
+
+    /// Equivalent of pool.getUserAccountData(user), with `price_overrides` replacing the cached
+    /// price of those assets. Returns None if the user or any of its reserves isn't known, or if
+    /// the pool would revert on an overflow.
+    ///
+    /// https://github.com/aave-dao/aave-v3-origin/blob/bb6ea42947f349fe8182a0ea30c5a7883d1f9ed1/src/contracts/protocol/libraries/logic/GenericLogic.sol#L63
+    pub fn get_user_account_data(
+        &self,
+        user: &Address,
+        price_overrides: &HashMap<Address, U256>,
+        timestamp: u64,
+    ) -> Option<AaveV3Pool::getUserAccountDataReturn> {
+        let user_state = self.users.get(user)?;
+        let terms = build_asset_terms(
+            &self.reserves,
+            &self.emode_categories,
+            user_state,
+            price_overrides,
+            timestamp,
+        )?;
+        aggregate_account_data(&terms, &HashMap::new())
+    }
+
+    /// Same as get_user_account_data(), but starting from the breakdown built at the last reserves
+    /// refresh, so only the terms of the assets in `price_overrides` are recomputed. Interest
+    /// accrued since that refresh is ignored. Returns None if the user has no breakdown, or if the
+    /// pool would revert on an overflow.
+    pub fn get_user_account_data_repriced(
+        &self,
+        user: &Address,
+        price_overrides: &HashMap<Address, U256>,
+    ) -> Option<AaveV3Pool::getUserAccountDataReturn> {
+        let terms = self.users.get(user)?.breakdown.as_ref()?;
+        aggregate_account_data(terms, price_overrides)
+    }
+}
+
+/// Normalizes the balances of every position of the user and values them at the cached price of
+/// each reserve, or the one in `price_overrides`. Positions without a balance or debt are skipped.
+/// Returns None if the reserve of any other position (or the user e-mode category) isn't known,
+/// or if a balance overflows.
+///
+/// https://github.com/aave-dao/aave-v3-origin/blob/bb6ea42947f349fe8182a0ea30c5a7883d1f9ed1/src/contracts/protocol/libraries/logic/GenericLogic.sol#L63
+fn build_asset_terms(
+    reserves: &HashMap<Address, ReserveState>,
+    emode_categories: &HashMap<u8, UserEModeCategory>,
+    user_state: &UserState,
+    price_overrides: &HashMap<Address, U256>,
+    timestamp: u64,
+) -> Option<Vec<AssetTerms>> {
+    let user_emode = match user_state.emode_category {
+        0 => None,
+        id => Some(emode_categories.get(&id)?),
+    };
+    let mut terms = Vec::with_capacity(user_state.positions.len());
+    for position in &user_state.positions {
+        // getUserReservesData() returns every reserve of the market, so a reserve left out of the
+        // engine only matters to the users with something in it
+        if position.scaled_atoken_balance == U256::ZERO
+            && position.scaled_variable_debt == U256::ZERO
+        {
+            continue;
+        }
+        let reserve = reserves.get(&position.underlying_asset)?;
+        let asset_price = *price_overrides
+            .get(&position.underlying_asset)
+            .unwrap_or(&reserve.price);
+        let in_emode =
+            user_emode.is_some_and(|emode| emode.is_collateral_in_category(reserve.reserve_index));
+        let (ltv, liquidation_threshold) = match user_emode {
+            Some(emode) if in_emode => (emode.ltv, emode.liquidation_threshold),
+            _ => (reserve.ltv, reserve.liquidation_threshold),
+        };
+
+        let mut collateral_balance = U256::ZERO;
+        if reserve.liquidation_threshold != U256::ZERO
+            && position.usage_as_collateral_enabled_on_user
+            && position.scaled_atoken_balance != U256::ZERO
+        {
+            // https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/logic/GenericLogic.sol#L249
+            collateral_balance = ray_mul(
+                position.scaled_atoken_balance,
+                get_normalized_income(reserve, timestamp)?,
+            )
+            .ok()?;
+        }
+
+        let mut debt_balance = U256::ZERO;
+        if position.scaled_variable_debt != U256::ZERO {
+            // https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/logic/GenericLogic.sol#L219
+            debt_balance = ray_mul(
+                position.scaled_variable_debt,
+                get_normalized_debt(reserve, timestamp)?,
+            )
+            .ok()?;
+        }
+
+        terms.push(AssetTerms {
+            underlying_asset: position.underlying_asset,
+            asset_unit: reserve.asset_unit,
+            collateral_balance,
+            debt_balance,
+            collateral_in_base_currency: value_in_base_currency(
+                collateral_balance,
+                asset_price,
+                reserve.asset_unit,
+            )
+            .ok()?,
+            debt_in_base_currency: value_in_base_currency(
+                debt_balance,
+                asset_price,
+                reserve.asset_unit,
+            )
+            .ok()?,
+            ltv,
+            liquidation_threshold,
+        });
+    }
+    Some(terms)
+}
+
+/// Adds up the terms into account data. Terms of assets in `price_overrides` are valued at the
+/// new price, every other term is taken as is. Returns None where the pool would revert.
+fn aggregate_account_data(
+    terms: &[AssetTerms],
+    price_overrides: &HashMap<Address, U256>,
+) -> Option<AaveV3Pool::getUserAccountDataReturn> {
+    let mut account_terms = Vec::with_capacity(terms.len());
+    for term in terms {
+        let (collateral_in_base_currency, debt_in_base_currency) = match price_overrides
+            .get(&term.underlying_asset)
+        {
+            Some(price) => (
+                value_in_base_currency(term.collateral_balance, *price, term.asset_unit).ok()?,
+                value_in_base_currency(term.debt_balance, *price, term.asset_unit).ok()?,
+            ),
+            None => (term.collateral_in_base_currency, term.debt_in_base_currency),
+        };
+        account_terms.push(AccountTerm {
+            collateral_in_base_currency,
+            debt_in_base_currency,
+            ltv: term.ltv,
+            liquidation_threshold: term.liquidation_threshold,
+        });
+    }
+    calculate_account_data(account_terms).ok().map(Into::into)
+}
+
+/// Keeps the reserves of the engine of `market` (indexes, rates and prices) up to date, once per
+/// block
+pub fn spawn_reserves_refresh(
+    engine: Arc<RwLock<OffchainHfEngine>>,
+    provider: RootProvider<PubSubFrontend>,
+    market: AaveMarket,
+) -> JoinHandle<()> {
+    let provider = Arc::new(provider);
+    tokio::spawn(async move {
+        let mut refresh_interval = interval(RESERVES_REFRESH_INTERVAL);
+        loop {
+            refresh_interval.tick().await;
+            let reserves_data = match get_reserves_data(provider.clone(), &market).await {
+                Ok(reserves_data) => reserves_data,
+                Err(e) => {
+                    warn!(
+                        "Failed to refresh reserves of the {} HF engine: {}",
+                        market.name, e
+                    );
+                    continue;
+                }
+            };
+            engine
+                .write()
+                .await
+                .update_reserves(&market, &reserves_data);
+        }
+    })
+}
+
+/// Checks every INDEX_DRIFT_CHECK_INTERVAL how far the normalized income/debt the cached
+/// breakdowns were built with are from the ones of the pool, which bounds the HF error of
+/// get_user_account_data_repriced(). The largest drift of any reserve of any market is set on
+/// `max_drift_ppm`.
+///
+/// spawn_reserves_refresh() keeps the drift well under the tolerance, so going past it means the
+/// refresh is failing or an index jumped (e.g. flash loan premiums are added to the liquidity
+/// index). The reserves of that market are refreshed right away then, and counted on `refreshes`.
+pub fn spawn_index_drift_monitor(
+    engines: Vec<(Arc<RwLock<OffchainHfEngine>>, AaveMarket)>,
+    provider: RootProvider<PubSubFrontend>,
+    max_drift_ppm: &'static Gauge,
+    refreshes: &'static Counter,
+) -> JoinHandle<()> {
+    let tolerance_ppm = match std::env::var(INDEX_DRIFT_TOLERANCE_PPM_ENV) {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(tolerance_ppm) => tolerance_ppm,
+            Err(e) => {
+                warn!(
+                    "Invalid {} value {}, using {}: {}",
+                    INDEX_DRIFT_TOLERANCE_PPM_ENV, value, INDEX_DRIFT_TOLERANCE_PPM, e
+                );
+                INDEX_DRIFT_TOLERANCE_PPM
+            }
+        },
+        Err(_) => INDEX_DRIFT_TOLERANCE_PPM,
+    };
+    let provider = Arc::new(provider);
+    tokio::spawn(async move {
+        let mut check_interval = interval(INDEX_DRIFT_CHECK_INTERVAL);
+        loop {
+            check_interval.tick().await;
+            let mut max_drift = 0;
+            for (engine, market) in &engines {
+                let drift = match get_index_drift_ppm(&provider, engine, market).await {
+                    Ok(drift) => drift,
+                    Err(e) => {
+                        warn!(
+                            "Failed to check index drift of the {} HF engine: {}",
+                            market.name, e
+                        );
+                        continue;
+                    }
+                };
+                max_drift = max_drift.max(drift);
+                if drift <= tolerance_ppm {
+                    continue;
+                }
+                warn!(
+                    "Indexes of the {} HF engine drifted {} ppm from the pool (tolerance {}), \
+                     refreshing reserves",
+                    market.name, drift, tolerance_ppm
+                );
+                match get_reserves_data(provider.clone(), market).await {
+                    Ok(reserves_data) => {
+                        engine.write().await.update_reserves(market, &reserves_data);
+                        refreshes.inc();
+                    }
+                    Err(e) => warn!(
+                        "Failed to refresh reserves of the {} HF engine: {}",
+                        market.name, e
+                    ),
+                }
+            }
+            max_drift_ppm.set(max_drift);
+        }
+    })
+}
+
+/// Largest drift (in ppm of the pool's value) between the cached normalized income/debt of the
+/// reserves of `market` and the ones of the pool
+async fn get_index_drift_ppm(
+    provider: &RootProvider<PubSubFrontend>,
+    engine: &RwLock<OffchainHfEngine>,
+    market: &AaveMarket,
+) -> Result<u64, VegaError> {
+    let cached_indexes = engine.read().await.breakdown_indexes();
+    let assets: Vec<Address> = cached_indexes.keys().copied().collect();
+    let pool_indexes = get_reserve_normalized_indexes_batch(provider, market, &assets).await?;
+    let mut max_drift = U256::ZERO;
+    for (asset, pool_indexes) in assets.iter().zip(pool_indexes) {
+        let Some((pool_income, pool_debt)) = pool_indexes else {
+            return Err(VegaError::InconsistentState(format!(
+                "Normalized indexes of reserve {} couldn't be read from the pool",
+                asset
+            )));
+        };
+        let (cached_income, cached_debt) = cached_indexes[asset];
+        max_drift = max_drift
+            .max(drift_ppm(cached_income, pool_income))
+            .max(drift_ppm(cached_debt, pool_debt));
+    }
+    Ok(max_drift.saturating_to())
+}
+
+fn drift_ppm(cached: U256, pool: U256) -> U256 {
+    if pool.is_zero() {
+        return U256::ZERO;
+    }
+    cached.abs_diff(pool) * PPM / pool
+}
+
+/// Converts the new Chainlink answer carried by the bundle into new AaveOracle prices for the
+/// affected reserves. Reserves priced through an adapter (e.g. wstETH = ETH/USD * exchange rate)
+/// move proportionally to the feed, so each cached price is scaled by new answer / latest answer.
+pub async fn get_price_overrides_for_bundle(
+    provider: &RootProvider<PubSubFrontend>,
+    engine: &OffchainHfEngine,
+    bundle: &PriceUpdateBundle,
+    affected_reserves: &[Address],
+) -> Result<HashMap<Address, U256>, VegaError> {
+    let latest_answer =
+        match AccessControlledOCR2Aggregator::new(bundle.forward_to, provider.clone())
+            .latestRoundData()
+            .call()
+            .await
+        {
+            Ok(round_data) if round_data.answer.is_positive() => round_data.answer.into_raw(),
+            Ok(_) => {
+                return Err(VegaError::InconsistentState(format!(
+                    "Non positive latest answer from aggregator {}",
+                    bundle.forward_to
+                )))
+            }
+            Err(e) => {
+                return Err(NodeError::call(
+                    format!("latestRoundData on aggregator {}", bundle.forward_to),
+                    e,
+                )
+                .into())
+            }
+        };
+    let mut price_overrides = HashMap::new();
+    for asset in affected_reserves {
+        let reserve = match engine.get_reserve(asset) {
+            Some(reserve) => reserve,
+            None => {
+                return Err(VegaError::InconsistentState(format!(
+                    "Reserve {} not found in the HF engine",
+                    asset
+                )))
+            }
+        };
+        price_overrides.insert(*asset, reserve.price * bundle.tx_new_price / latest_answer);
+    }
+    Ok(price_overrides)
+}
+
+/// Seconds since the epoch, which is what the pool compares lastUpdateTimestamp against
+pub fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/logic/ReserveLogic.sol#L47
+fn get_normalized_income(reserve: &ReserveState, timestamp: u64) -> Option<U256> {
+    if timestamp <= reserve.last_update_timestamp {
+        return Some(reserve.liquidity_index);
+    }
+    ray_mul(
+        calculate_linear_interest(
+            reserve.liquidity_rate,
+            timestamp - reserve.last_update_timestamp,
+        ),
+        reserve.liquidity_index,
+    )
+    .ok()
+}
+
+/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/logic/ReserveLogic.sol#L71
+fn get_normalized_debt(reserve: &ReserveState, timestamp: u64) -> Option<U256> {
+    if timestamp <= reserve.last_update_timestamp {
+        return Some(reserve.variable_borrow_index);
+    }
+    ray_mul(
+        calculate_compounded_interest(
+            reserve.variable_borrow_rate,
+            timestamp - reserve.last_update_timestamp,
+        )?,
+        reserve.variable_borrow_index,
+    )
+    .ok()
+}
+
+/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/math/MathUtils.sol#L23
+fn calculate_linear_interest(rate: U256, elapsed: u64) -> U256 {
+    RAY + rate * U256::from(elapsed) / SECONDS_PER_YEAR
+}
+
+/// https://github.com/aave-dao/aave-v3-origin/blob/a0512f8354e97844a3ed819cf4a9a663115b8e20/src/contracts/protocol/libraries/math/MathUtils.sol#L50
+fn calculate_compounded_interest(rate: U256, elapsed: u64) -> Option<U256> {
+    if elapsed == 0 {
+        return Some(RAY);
+    }
+    let exp = U256::from(elapsed);
+    let exp_minus_one = exp - U256::from(1);
+    let exp_minus_two = exp.saturating_sub(U256::from(2));
+
+    let base_power_two = ray_mul(rate, rate).ok()? / (SECONDS_PER_YEAR * SECONDS_PER_YEAR);
+    let base_power_three = ray_mul(base_power_two, rate).ok()? / SECONDS_PER_YEAR;
+
+    let second_term = exp * exp_minus_one * base_power_two / U256::from(2);
+    let third_term = exp * exp_minus_one * exp_minus_two * base_power_three / U256::from(6);
+
+    Some(RAY + rate * exp / SECONDS_PER_YEAR + second_term + third_term)
+}
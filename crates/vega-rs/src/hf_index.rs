@@ -0,0 +1,160 @@
+use alloy::primitives::{Address, U256};
+use overlord_shared::{WatchlistEntry, WatchlistPriority};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+type UserAddress = Address;
+type ReserveAddress = Address;
+
+const ONE_HF: U256 = U256::from_limbs([1_000_000_000_000_000_000, 0, 0, 0]);
+
+/// Users of every asset ordered by how close their last known health factor is to 1, so the
+/// riskiest candidates of a price update are evaluated (and reported) first. Users imported with
+/// a high priority (see WatchlistPriority) go before everyone else.
+///
+/// Orderings are rebuilt lazily: recording new health factors or changing the users of an asset
+/// only marks orderings as stale, and each one is re-sorted the next time it's asked for.
+#[derive(Default)]
+pub struct HfIndex {
+    last_hf: HashMap<UserAddress, U256>,
+    ranked_users: HashMap<ReserveAddress, Vec<UserAddress>>,
+    stale: HashSet<ReserveAddress>,
+    /// Only users with a priority other than normal. Kept when a user is removed, since that
+    /// happens every time its positions change.
+    priorities: HashMap<UserAddress, WatchlistPriority>,
+}
+
+impl HfIndex {
+    pub fn new(users_by_asset: HashMap<ReserveAddress, HashSet<UserAddress>>) -> Self {
+        let stale = users_by_asset.keys().cloned().collect();
+        Self {
+            last_hf: HashMap::new(),
+            ranked_users: users_by_asset
+                .into_iter()
+                .map(|(asset, users)| (asset, users.into_iter().collect()))
+                .collect(),
+            stale,
+            priorities: HashMap::new(),
+        }
+    }
+
+    pub fn add_user(&mut self, asset: ReserveAddress, user: UserAddress) {
+        let users = self.ranked_users.entry(asset).or_default();
+        if !users.contains(&user) {
+            users.push(user);
+            self.stale.insert(asset);
+        }
+    }
+
+    /// Drops the user from every asset, along with its last known HF, since whatever changed its
+    /// positions also changed its HF
+    pub fn remove_user(&mut self, user: &UserAddress) {
+        self.last_hf.remove(user);
+        for users in self.ranked_users.values_mut() {
+            users.retain(|u| u != user);
+        }
+    }
+
+    /// Records the latest health factors. Users can hold any asset, so every ordering is
+    /// considered stale afterwards.
+    pub fn record_health_factors(&mut self, health_factors: &HashMap<UserAddress, U256>) {
+        if health_factors.is_empty() {
+            return;
+        }
+        self.last_hf.extend(health_factors.iter());
+        self.stale.extend(self.ranked_users.keys().cloned());
+    }
+
+    /// Records health factors computed a while ago, like the initial sweep's, only for users
+    /// without a known HF. Whatever was recorded since they were computed is fresher.
+    pub fn seed_health_factors(&mut self, health_factors: &HashMap<UserAddress, U256>) {
+        let mut seeded = false;
+        for (user, hf) in health_factors {
+            if let Entry::Vacant(entry) = self.last_hf.entry(*user) {
+                entry.insert(*hf);
+                seeded = true;
+            }
+        }
+        if seeded {
+            self.stale.extend(self.ranked_users.keys().cloned());
+        }
+    }
+
+    /// Sort key of the user: its priority first, then how far it is from being liquidatable.
+    /// Users without a known HF go first within their priority.
+    pub fn rank(&self, user: &UserAddress) -> (WatchlistPriority, U256) {
+        rank(&self.priorities, &self.last_hf, user)
+    }
+
+    pub fn set_priority(&mut self, user: UserAddress, priority: WatchlistPriority) {
+        let changed = if priority == WatchlistPriority::default() {
+            self.priorities.remove(&user).is_some()
+        } else {
+            self.priorities.insert(user, priority) != Some(priority)
+        };
+        if changed {
+            self.stale.extend(self.ranked_users.keys().cloned());
+        }
+    }
+
+    /// Users whose last known HF is below `max_health_factor`, plus every user with a priority,
+    /// closest to liquidation first
+    pub fn watchlist(&self, max_health_factor: U256) -> Vec<WatchlistEntry> {
+        let mut assets_by_user: HashMap<UserAddress, Vec<ReserveAddress>> = HashMap::new();
+        for (asset, users) in self.ranked_users.iter() {
+            for user in users {
+                assets_by_user.entry(*user).or_default().push(*asset);
+            }
+        }
+        let mut entries = assets_by_user
+            .into_iter()
+            .filter(|(user, _)| {
+                self.priorities.contains_key(user)
+                    || self
+                        .last_hf
+                        .get(user)
+                        .is_some_and(|hf| *hf < max_health_factor)
+            })
+            .map(|(user, assets)| WatchlistEntry {
+                user,
+                health_factor: self.last_hf.get(&user).cloned(),
+                assets,
+                priority: self.priorities.get(&user).cloned().unwrap_or_default(),
+                market: None,
+            })
+            .collect::<Vec<WatchlistEntry>>();
+        entries.sort_by_cached_key(|entry| self.rank(&entry.user));
+        entries
+    }
+
+    /// Users of `asset`, closest to HF = 1 first. Re-ranks them if anything changed since the last
+    /// time they were asked for.
+    pub fn ranked_users(&mut self, asset: &ReserveAddress) -> &[UserAddress] {
+        let Some(users) = self.ranked_users.get_mut(asset) else {
+            return &[];
+        };
+        if self.stale.remove(asset) {
+            let (priorities, last_hf) = (&self.priorities, &self.last_hf);
+            users.sort_by_cached_key(|user| rank(priorities, last_hf, user));
+        }
+        users
+    }
+}
+
+fn rank(
+    priorities: &HashMap<UserAddress, WatchlistPriority>,
+    last_hf: &HashMap<UserAddress, U256>,
+    user: &UserAddress,
+) -> (WatchlistPriority, U256) {
+    (
+        priorities.get(user).cloned().unwrap_or_default(),
+        distance_to_liquidation(last_hf.get(user)),
+    )
+}
+
+fn distance_to_liquidation(hf: Option<&U256>) -> U256 {
+    match hf {
+        Some(hf) if *hf >= ONE_HF => *hf - ONE_HF,
+        Some(hf) => ONE_HF - *hf,
+        None => U256::ZERO,
+    }
+}
@@ -1,3 +1,17 @@
+pub mod anvil_pool;
+pub mod artifacts;
+pub mod cache_snapshot;
 pub mod calc_utils;
+pub mod chainlink_mapping;
+pub mod discovery;
+pub mod error;
 pub mod fork_provider;
+pub mod hf_engine;
+pub mod hf_index;
+pub mod mutation_log;
+pub mod override_check;
+pub mod price_guard;
+pub mod profito_link;
+pub mod signal_stream;
+pub mod simulation;
 pub mod user_reserve_cache;
@@ -1,29 +1,76 @@
 use alloy::{
     primitives::{Address, U256},
-    providers::{IpcConnect, ProviderBuilder},
+    providers::{IpcConnect, ProviderBuilder, RootProvider},
+    pubsub::PubSubFrontend,
 };
 use bincode::deserialize;
 use chrono::Local;
 use clap::Parser;
-use overlord_shared::{MessageBundle, PriceUpdateBundle};
+use overlord_shared::{
+    common::BaseCurrency,
+    constants::GHO_PRICE_ORACLE,
+    error::{FileError, NodeError},
+    kill_switch::KILL_SWITCH,
+    market::{resolve_markets_from_env, AaveMarket},
+    new_run_id,
+    retry::RetryPolicy,
+    startup::{self, StartupPhase},
+    status::{Counter, Gauge, StatusReporter},
+    MessageBundle, PriceUpdateBundle, PriceUpdateHintBundle, PriceUpdateTx, WhistleblowerUpdate,
+};
+use std::collections::HashMap;
 use std::env;
-use std::error::Error;
 use std::fs::File;
 use std::io::Write;
 use std::sync::Arc;
-use tokio::time::Instant;
+use tokio::{
+    sync::mpsc,
+    time::{sleep, Instant},
+};
 use tracing::{error, info, warn};
 use tracing_appender::rolling::{self, Rotation};
 use tracing_subscriber::fmt::{time::LocalTime, writer::BoxMakeWriter};
-use vega_rs::calc_utils::{get_hf_for_users, UnderwaterUserEventBus};
+use vega_rs::anvil_pool::AnvilPool;
+use vega_rs::artifacts::{ArtifactStore, ARTIFACTS_DIR_ENV};
+use vega_rs::calc_utils::{get_hf_for_users, get_hf_for_users_offchain, UnderwaterUserEventBus};
+use vega_rs::error::VegaError;
 use vega_rs::fork_provider::ForkProvider;
-use vega_rs::user_reserve_cache::UserReservesCache;
+use vega_rs::hf_engine::{
+    get_price_overrides_for_bundle, spawn_index_drift_monitor, spawn_reserves_refresh,
+};
+use vega_rs::price_guard::PriceGuard;
+use vega_rs::profito_link::run_profito_sender;
+use vega_rs::signal_stream::{PipelineSignal, SignalStream};
+use vega_rs::simulation::{build_price_update_override, SimulationBackend, SimulationContext};
+use vega_rs::user_reserve_cache::{
+    spawn_chainlink_mapping_reload, ChainlinkMappingSource, UserReservesCache,
+};
 
 const VEGA_INBOUND_ENDPOINT: &str = "ipc:///tmp/vega_inbound";
-const PROFITO_INBOUND_ENDPOINT: &str = "ipc:///tmp/profito_inbound";
-const ADDRESSES_FILE_ENV: &str = "VEGA_ADDRESSES_FILE";
+const BORROWERS_CHECKPOINT_FILE_ENV: &str = "VEGA_BORROWERS_CHECKPOINT_FILE";
 const CHAINLINK_ADDRESSES_FILE_ENV: &str = "VEGA_CHAINLINK_ADDRESSES_FILE";
+// Optional, the cache is rebuilt from scratch on every start without it
+const CACHE_SNAPSHOT_FILE_ENV: &str = "VEGA_CACHE_SNAPSHOT_FILE";
+// Optional, cache mutations are only kept in memory without it
+const CACHE_MUTATION_LOG_FILE_ENV: &str = "VEGA_CACHE_MUTATION_LOG_FILE";
 const TEMP_OUTPUT_DIR: &str = "TEMP_OUTPUT_DIR";
+// UTC hour the daily snapshot is taken at, when ARTIFACTS_DIR_ENV is set. Late in the day by
+// default, so each one covers as much of its day as possible.
+const DAILY_SNAPSHOT_HOUR_ENV: &str = "VEGA_DAILY_SNAPSHOT_HOUR_UTC";
+const DEFAULT_DAILY_SNAPSHOT_HOUR: u32 = 23;
+
+// Reported by the periodic status line
+static PRICE_UPDATES_PROCESSED: Counter = Counter::new();
+static HINTS_PROCESSED: Counter = Counter::new();
+static PRICE_UPDATES_CANCELLED: Counter = Counter::new();
+static WHISTLEBLOWER_EVENTS_PROCESSED: Counter = Counter::new();
+static UNDERWATER_ALERTS: Counter = Counter::new();
+static UW_EVENTS_RETRANSMITTED: Counter = Counter::new();
+static UW_EVENTS_UNACKED: Counter = Counter::new();
+static IMPLAUSIBLE_PRICE_UPDATES: Counter = Counter::new();
+static INDEX_DRIFT_REFRESHES: Counter = Counter::new();
+static CACHED_USERS: Gauge = Gauge::new();
+static INDEX_DRIFT_PPM: Gauge = Gauge::new();
 
 #[derive(Parser)]
 #[clap(
@@ -35,12 +82,20 @@ const TEMP_OUTPUT_DIR: &str = "TEMP_OUTPUT_DIR";
 struct VegaArgs {
     #[clap(long, default_value = "64")]
     buckets: usize,
+    #[clap(long, value_enum, default_value = "state-override")]
+    simulation_backend: SimulationBackend,
+    /// Number of warm Anvil forks to keep around for the Anvil path (0 disables the pool)
+    #[clap(long, default_value = "0")]
+    anvil_pool_size: usize,
+    /// With the offchain backend, only recompute the terms of the assets affected by an update
+    #[clap(long)]
+    incremental_hf: bool,
 }
 
-fn get_required_env_var(key: &str) -> Result<String, Box<dyn std::error::Error>> {
+fn get_required_env_var(key: &str) -> Result<String, VegaError> {
     env::var(key).map_err(|e| {
         error!("Environment variable {} not set: {}", key, e);
-        Box::new(e) as Box<dyn std::error::Error>
+        VegaError::Config(format!("{}: {}", key, e))
     })
 }
 
@@ -49,27 +104,66 @@ async fn run_price_update_pipeline(
     bundle: Option<&PriceUpdateBundle>,
     output_data_dir: &str,
     event_bus: Arc<UnderwaterUserEventBus>,
+    simulation: &SimulationContext,
+    signal_stream: Option<&SignalStream>,
+    price_guard: &PriceGuard,
 ) {
     let pipeline_processing = Instant::now();
+    let market = cache.market().clone();
     let (address_buckets, affected_reserves) = cache.get_candidates_for_bundle(bundle).await;
     let trace_id = bundle.map_or("initial-run".to_string(), |b| b.trace_id.clone());
+    // Tells this run apart from any other of the same price update in logs and files
+    let run_id = new_run_id(&trace_id);
     let tx_hash = bundle.map_or("initial-run".to_string(), |b| b.tx_hash.clone());
-    let raw_tx = bundle.and_then(|b| b.raw_tx.clone());
+    let price_update_tx = bundle.map(|b| b.price_update_tx.clone());
     let inclusion_block = bundle.map_or("initial-run".to_string(), |b| b.inclusion_block.clone());
+    let slot_deadline_ms = bundle.and_then(|b| b.slot_deadline_ms);
     if address_buckets.len() == 1 && address_buckets[0].is_empty() {
         info!(
-            "Not processing bundle for trace_id {} because it doesn't contain any addresses",
-            trace_id
+            "Not processing bundle for trace_id {} on the {} market because it doesn't contain any addresses",
+            trace_id, market.name
         );
         return;
     }
-    let fork_provider = match ForkProvider::new(bundle).await {
-        Ok(provider) => provider,
-        Err(e) => {
-            warn!("Failed to spin up fork for bundle {}: {:?}", trace_id, e);
+    if KILL_SWITCH.is_market_blocked(&market) {
+        warn!(
+            "Not processing bundle for trace_id {} because the {} market is in the kill switch list",
+            trace_id, market.name
+        );
+        return;
+    }
+    if let Some(asset) =
+        KILL_SWITCH.first_blocked(affected_reserves.iter().map(|r| r.reserve_address))
+    {
+        warn!(
+            "Not processing bundle for trace_id {} because asset {} is in the kill switch list",
+            trace_id, asset
+        );
+        return;
+    }
+    if let Some(bundle) = bundle {
+        let affected_assets = affected_reserves
+            .iter()
+            .map(|r_info| r_info.reserve_address)
+            .collect::<Vec<Address>>();
+        if let Err(implausible) = price_guard
+            .check(&simulation.live_provider, bundle, &affected_assets)
+            .await
+        {
+            IMPLAUSIBLE_PRICE_UPDATES.inc();
+            error!(
+                "ALERT | {} | Not processing price update from {}: new price {} of {} is {} bps away from its latest answer {} (limit {} bps)",
+                trace_id,
+                bundle.source,
+                implausible.new_price,
+                bundle.forward_to,
+                implausible.move_bps,
+                implausible.latest_answer,
+                implausible.max_move_bps
+            );
             return;
         }
-    };
+    }
     let new_prices_by_asset = affected_reserves
         .iter()
         .map(|r_info| {
@@ -80,21 +174,136 @@ async fn run_price_update_pipeline(
             )
         })
         .collect::<Vec<(Address, String, U256)>>();
-    let results = get_hf_for_users(
-        address_buckets.clone(),
-        fork_provider.fork_provider.as_ref().unwrap(),
-        Some(trace_id.clone()),
-        Some(tx_hash.clone()),
-        raw_tx.clone(),
-        Some(inclusion_block.clone()),
-        new_prices_by_asset,
-        Some(event_bus),
-    )
-    .await;
+    // State overrides and forks only need the aggregator (forward_to) and the new price, so every
+    // bundle gets them, Synthetic and rehearsal ones included. GHO's price oracle isn't an
+    // aggregator, and the offchain engine ignores the GHO discount, so its candidates are checked
+    // on the live state.
+    let overridable_update = bundle.filter(|b| b.forward_to != GHO_PRICE_ORACLE);
+    let hf_engine = cache.hf_engine();
+    let mut offchain_price_overrides = None;
+    if let (SimulationBackend::Offchain, Some(bundle)) = (simulation.backend, overridable_update) {
+        let hf_engine = hf_engine.read().await;
+        let affected_assets = affected_reserves
+            .iter()
+            .map(|r_info| r_info.reserve_address)
+            .collect::<Vec<Address>>();
+        if !hf_engine.is_ready() {
+            warn!(
+                "Off-chain HF engine is not ready for bundle {}, falling back to state override",
+                trace_id
+            );
+        } else {
+            match get_price_overrides_for_bundle(
+                &simulation.live_provider,
+                &hf_engine,
+                bundle,
+                &affected_assets,
+            )
+            .await
+            {
+                Ok(price_overrides) => offchain_price_overrides = Some(price_overrides),
+                Err(e) => warn!(
+                    "Failed to get off-chain price overrides for bundle {}, falling back to state override: {}",
+                    trace_id, e
+                ),
+            }
+        }
+    }
+    let results = match offchain_price_overrides {
+        Some(price_overrides) => get_hf_for_users_offchain(
+            &address_buckets,
+            &market,
+            &*hf_engine.read().await,
+            &price_overrides,
+            simulation.incremental_hf,
+            trace_id.clone(),
+            run_id.clone(),
+            Some(tx_hash.clone()),
+            price_update_tx.clone(),
+            inclusion_block.clone(),
+            slot_deadline_ms,
+            new_prices_by_asset,
+            Some(event_bus),
+            cache.base_currency(),
+        ),
+        None => {
+            let mut state_override = None;
+            if let (SimulationBackend::StateOverride | SimulationBackend::Offchain, Some(bundle)) =
+                (simulation.backend, overridable_update)
+            {
+                match build_price_update_override(simulation.live_provider.clone(), bundle).await {
+                    Ok(overrides) => state_override = Some(overrides),
+                    Err(e) => warn!(
+                        "Failed to build state override for bundle {}, falling back to Anvil: {}",
+                        trace_id, e
+                    ),
+                }
+            }
+            // Only use a fork if we couldn't simulate the update with a state override, and
+            // prefer a warm one from the pool over spinning up a new one
+            let pooled_fork = match (&state_override, &simulation.anvil_pool, overridable_update) {
+                (None, Some(anvil_pool), Some(bundle)) => anvil_pool.acquire(bundle).await,
+                _ => None,
+            };
+            let needs_fork =
+                overridable_update.is_some() && state_override.is_none() && pooled_fork.is_none();
+            let fork_provider = if needs_fork {
+                match ForkProvider::new(bundle).await {
+                    Ok(provider) => Some(provider),
+                    Err(e) => {
+                        warn!("Failed to spin up fork for bundle {}: {:?}", trace_id, e);
+                        return;
+                    }
+                }
+            } else {
+                None
+            };
+            let simulation_provider = match (&pooled_fork, &fork_provider) {
+                (Some(pooled_fork), _) => pooled_fork.provider(),
+                (None, Some(fork_provider)) => fork_provider.fork_provider.as_ref().unwrap(),
+                (None, None) => &simulation.live_provider,
+            };
+            get_hf_for_users(
+                address_buckets.clone(),
+                simulation_provider,
+                &market,
+                Some(trace_id.clone()),
+                Some(run_id.clone()),
+                Some(tx_hash.clone()),
+                price_update_tx.clone(),
+                Some(inclusion_block.clone()),
+                slot_deadline_ms,
+                new_prices_by_asset,
+                Some(event_bus),
+                cache.base_currency(),
+                state_override,
+            )
+            .await
+        }
+    };
     let pipeline_processing_elapsed = pipeline_processing.elapsed().as_millis();
+    cache.record_health_factors(&results.raw_results);
+    if let Some(signal_stream) = signal_stream {
+        signal_stream.publish(|| PipelineSignal {
+            trace_id: trace_id.clone(),
+            run_id: run_id.clone(),
+            market: market.name.clone(),
+            source: bundle.map_or("initial-run".to_string(), |b| b.source.clone()),
+            inclusion_block: inclusion_block.clone(),
+            affected_assets: affected_reserves
+                .iter()
+                .map(|r_info| (r_info.reserve_address, r_info.symbol.clone()))
+                .collect(),
+            evaluated_at_ms: SignalStream::now_ms(),
+            elapsed_ms: pipeline_processing_elapsed as u64,
+            health_factors: results.raw_results.clone(),
+        });
+    }
     info!(
-        "Candidates analysis complete for {} | {} ms | {} candidates processed in {} buckets | {} with HF < 1",
-        trace_id.clone(),
+        "Candidates analysis complete for {} (run {}, {} market) | {} ms | {} candidates processed in {} buckets | {} with HF < 1",
+        trace_id,
+        run_id,
+        market.name,
         pipeline_processing_elapsed,
         results.raw_results.len(),
         address_buckets.len(),
@@ -116,7 +325,8 @@ async fn run_price_update_pipeline(
             );
         })
         .ok();
-    let hf_traces_filepath = format!("{}/{}.txt", hf_traces_dir, trace_id);
+    // One file per run, so processing the same update again doesn't overwrite the previous one
+    let hf_traces_filepath = format!("{}/{}.txt", hf_traces_dir, run_id);
     let hf_traces_file = match File::create(hf_traces_filepath.clone()) {
         Ok(file) => file,
         Err(e) => {
@@ -139,11 +349,140 @@ async fn run_price_update_pipeline(
     }
 }
 
+/// Pre-warms the candidates for a MEV-Share hint that didn't disclose the new price.
+///
+/// There's no price to write into a fork, so instead we read the current HF of every candidate
+/// and flag the ones that could go underwater if the price moves to the edge of the estimated range.
+async fn run_price_hint_pipeline(
+    cache: &mut UserReservesCache,
+    hint: &PriceUpdateHintBundle,
+    output_data_dir: &str,
+    live_provider: &RootProvider<PubSubFrontend>,
+) {
+    let hint_processing = Instant::now();
+    let tx_hash = match hint.tx_hash.parse() {
+        Ok(tx_hash) => tx_hash,
+        Err(e) => {
+            warn!(
+                "Skipping price hint {}: invalid tx hash {}: {}",
+                hint.trace_id, hint.tx_hash, e
+            );
+            return;
+        }
+    };
+    let mut at_risk: Vec<(Address, Address, U256)> = vec![];
+    let mut total_candidates = 0;
+    for range in hint.price_ranges.iter() {
+        if range.low == U256::ZERO {
+            continue;
+        }
+        // Only forward_to and trace_id are used when drawing candidates
+        let bundle = PriceUpdateBundle {
+            trace_id: hint.trace_id.clone(),
+            tx_hash: hint.tx_hash.clone(),
+            price_update_tx: PriceUpdateTx::HashOnly(tx_hash),
+            inclusion_block: hint.inclusion_block.clone(),
+            tx_new_price: range.low,
+            forward_to: range.chainlink_address,
+            tx_from: Address::ZERO,
+            tx_to: hint.tx_to,
+            tx_input: Default::default(),
+            source: hint.source.clone(),
+            slot_deadline_ms: None,
+        };
+        let (address_buckets, affected_reserves) =
+            cache.get_candidates_for_bundle(Some(&bundle)).await;
+        if address_buckets.len() == 1 && address_buckets[0].is_empty() {
+            continue;
+        }
+        if KILL_SWITCH
+            .first_blocked(affected_reserves.iter().map(|r| r.reserve_address))
+            .is_some()
+        {
+            continue;
+        }
+        // HF scales roughly linearly with the collateral price, and inversely with the debt
+        // price. Candidates may hold the asset on either side, so a user at or below the HF of
+        // either end could end up underwater: at the low end if it's their collateral, at the
+        // high end if it's their debt.
+        let collateral_at_risk_hf = U256::from(1e18) * range.latest_answer / range.low;
+        let debt_at_risk_hf = U256::from(1e18) * range.high / range.latest_answer;
+        let at_risk_hf = collateral_at_risk_hf.max(debt_at_risk_hf);
+        let results = get_hf_for_users(
+            address_buckets,
+            live_provider,
+            cache.market(),
+            Some(hint.trace_id.clone()),
+            None,
+            Some(hint.tx_hash.clone()),
+            None,
+            Some(hint.inclusion_block.clone()),
+            None,
+            vec![],
+            None,
+            cache.base_currency(),
+            None,
+        )
+        .await;
+        total_candidates += results.raw_results.len();
+        cache.record_health_factors(&results.raw_results);
+        for (address, hf) in results.raw_results.iter() {
+            if *hf < at_risk_hf {
+                at_risk.push((*address, range.chainlink_address, *hf));
+            }
+        }
+    }
+    info!(
+        "Hint pre-warm complete for {} ({} market) | {} ms | {} feeds | {} candidates | {} at risk",
+        hint.trace_id,
+        cache.market().name,
+        hint_processing.elapsed().as_millis(),
+        hint.price_ranges.len(),
+        total_candidates,
+        at_risk.len()
+    );
+    if at_risk.is_empty() {
+        return;
+    }
+    let hf_traces_dir = format!("{}/hf-traces", output_data_dir);
+    if let Err(e) = std::fs::create_dir_all(&hf_traces_dir) {
+        warn!(
+            "Failed to create hf-traces directory for hint {}: {}",
+            hint.trace_id, e
+        );
+        return;
+    }
+    let hint_traces_filepath = cache
+        .market()
+        .scoped_path(&format!("{}/{}-hint.txt", hf_traces_dir, hint.trace_id));
+    let mut hint_traces_file = match File::create(hint_traces_filepath.clone()) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!(
+                "Failed to create hint traces file {}: {}",
+                hint_traces_filepath, e
+            );
+            return;
+        }
+    };
+    for (address, feed, hf) in at_risk.iter() {
+        if let Err(e) = writeln!(hint_traces_file, "{:?} {:?} {}", address, feed, hf) {
+            warn!(
+                "Failed to write to hint traces file {}: {}",
+                hint_traces_filepath, e
+            );
+            return;
+        }
+    }
+}
+
 async fn _dump_initial_hf_results(
     user_buckets: Vec<Vec<Address>>,
+    market: &AaveMarket,
     output_data_dir: &str,
     event_bus: Arc<UnderwaterUserEventBus>,
-) -> Result<(), Box<dyn Error>> {
+    base_currency: BaseCurrency,
+) -> Result<HashMap<Address, U256>, VegaError> {
     let init_hf_results_timer = Instant::now();
     let ipc_url = "/tmp/reth.ipc";
     let ipc = IpcConnect::new(ipc_url.to_string());
@@ -151,44 +490,53 @@ async fn _dump_initial_hf_results(
         Ok(provider) => provider,
         Err(e) => {
             error!("Failed to connect to IPC: {}", e);
-            return Err(Box::new(e));
+            return Err(NodeError::rpc(format!("connect to {}", ipc_url), e).into());
         }
     };
     let init_hf_results = get_hf_for_users(
         user_buckets,
         &provider,
+        market,
+        None,
+        None,
         None,
         None,
         None,
         None,
         vec![],
         Some(event_bus),
+        base_currency,
+        None,
     )
     .await;
-    let init_hf_results_filepath = format!(
+    let init_hf_results_filepath = market.scoped_path(&format!(
         "{}/init_hf_under_1_results_{}.txt",
         output_data_dir,
         Local::now().format("%Y%m%d")
-    );
+    ));
 
     // Check if directory exists
     if !std::path::Path::new(output_data_dir).is_dir() {
         error!("Output directory does not exist: {}", output_data_dir);
-        return Err("Output directory does not exist".into());
+        return Err(FileError::new(
+            output_data_dir,
+            std::io::Error::new(std::io::ErrorKind::NotFound, "directory does not exist"),
+        )
+        .into());
     }
 
     let init_hf_results_file = match File::create(init_hf_results_filepath.clone()) {
         Ok(file) => file,
         Err(e) => {
             error!("Failed to create init HF results file: {}", e);
-            return Err(Box::new(e));
+            return Err(FileError::new(&init_hf_results_filepath, e).into());
         }
     };
     let mut init_hf_results_file = init_hf_results_file;
     for (address, hf) in init_hf_results.under_1_hf.iter() {
         if let Err(e) = writeln!(init_hf_results_file, "{:?}: {}", address, hf) {
             error!("Failed to write to init HF results file: {}", e);
-            return Err(Box::new(e));
+            return Err(FileError::new(&init_hf_results_filepath, e).into());
         }
     }
     let init_hf_results_elapsed = init_hf_results_timer.elapsed().as_millis();
@@ -197,7 +545,70 @@ async fn _dump_initial_hf_results(
         elapsed_ms = init_hf_results_elapsed,
         "Initial HF results dumped"
     );
-    Ok(())
+    Ok(init_hf_results.raw_results)
+}
+
+/// Users cached across every market
+async fn cached_users(caches: &[UserReservesCache]) -> u64 {
+    let mut users = 0;
+    for cache in caches {
+        users += cache.hf_engine().read().await.user_count() as u64;
+    }
+    users
+}
+
+/// Applies the whistleblower updates received for a block in one go, each to the cache of the
+/// market it came from, see UserReservesCache::update_cache()
+async fn apply_whistleblower_updates(
+    caches: &mut [UserReservesCache],
+    pending_updates: &mut Vec<WhistleblowerUpdate>,
+) {
+    let Some(block_number) = pending_updates.last().map(|update| update.block_number) else {
+        return;
+    };
+    WHISTLEBLOWER_EVENTS_PROCESSED.add(pending_updates.len() as u64);
+    let mut updates = std::mem::take(pending_updates);
+    for cache in caches.iter_mut() {
+        let (market_updates, rest): (Vec<_>, Vec<_>) = updates
+            .into_iter()
+            .partition(|update| update.market == cache.market().addresses_provider);
+        updates = rest;
+        if market_updates.is_empty() {
+            continue;
+        }
+        if let Err(e) = cache.update_cache(&market_updates).await {
+            warn!(
+                "Failed to update cache of the {} market: {}",
+                cache.market().name,
+                e
+            );
+        }
+        cache.save_snapshot_if_due(block_number).await;
+        cache.save_daily_snapshot_if_due(block_number).await;
+    }
+    for update in updates {
+        warn!(
+            "Ignoring whistleblower update {} of market {}, which isn't followed",
+            update.trace_id, update.market
+        );
+    }
+    CACHED_USERS.set(cached_users(caches).await);
+}
+
+fn daily_snapshot_hour() -> u32 {
+    match env::var(DAILY_SNAPSHOT_HOUR_ENV) {
+        Ok(value) => match value.parse::<u32>() {
+            Ok(hour) if hour < 24 => hour,
+            Ok(_) | Err(_) => {
+                warn!(
+                    "Invalid {} value {}, using {}",
+                    DAILY_SNAPSHOT_HOUR_ENV, value, DEFAULT_DAILY_SNAPSHOT_HOUR
+                );
+                DEFAULT_DAILY_SNAPSHOT_HOUR
+            }
+        },
+        Err(_) => DEFAULT_DAILY_SNAPSHOT_HOUR,
+    }
 }
 
 fn _setup_logging() {
@@ -217,19 +628,28 @@ async fn main() -> Result<(), String> {
 
     let args = VegaArgs::parse();
 
-    info!(buckets = args.buckets, "vega-rs starting");
-    let addresses_file = match get_required_env_var(ADDRESSES_FILE_ENV) {
+    info!(
+        buckets = args.buckets,
+        simulation_backend = ?args.simulation_backend,
+        anvil_pool_size = args.anvil_pool_size,
+        incremental_hf = args.incremental_hf,
+        "vega-rs starting"
+    );
+    let borrowers_checkpoint_file = match get_required_env_var(BORROWERS_CHECKPOINT_FILE_ENV) {
         Ok(filename) => filename,
         Err(e) => {
-            error!("Failed to get addresses file path: {}", e);
+            error!("Failed to get borrowers checkpoint file path: {}", e);
             std::process::exit(1);
         }
     };
-    let chainlink_addresses_file = match get_required_env_var(CHAINLINK_ADDRESSES_FILE_ENV) {
-        Ok(filename) => filename,
-        Err(e) => {
-            error!("Failed to get chainlink addresses file path: {}", e);
-            std::process::exit(1);
+    let chainlink_mapping_source = match env::var(CHAINLINK_ADDRESSES_FILE_ENV) {
+        Ok(filename) => ChainlinkMappingSource::File(filename),
+        Err(_) => {
+            info!(
+                "{} not set, the Chainlink mapping will be generated from the AaveOracle",
+                CHAINLINK_ADDRESSES_FILE_ENV
+            );
+            ChainlinkMappingSource::Onchain
         }
     };
     let temp_output_dir = match get_required_env_var(TEMP_OUTPUT_DIR) {
@@ -242,54 +662,186 @@ async fn main() -> Result<(), String> {
             std::process::exit(1);
         }
     };
-    let mut user_reserves_cache = UserReservesCache::new();
-    let user_buckets = match user_reserves_cache
-        .initialize_cache(&addresses_file, &chainlink_addresses_file, &temp_output_dir)
+    let cache_snapshot_file = env::var(CACHE_SNAPSHOT_FILE_ENV).ok();
+    if cache_snapshot_file.is_none() {
+        info!(
+            "{} not set, the cache won't be snapshotted",
+            CACHE_SNAPSHOT_FILE_ENV
+        );
+    }
+    let cache_mutation_log_file = env::var(CACHE_MUTATION_LOG_FILE_ENV).ok();
+    let artifacts = ArtifactStore::from_env();
+    let daily_snapshot_hour = daily_snapshot_hour();
+    match &artifacts {
+        Some(artifacts) => info!(
+            "Daily snapshots are stored in {} at {}:00 UTC",
+            artifacts.dir().display(),
+            daily_snapshot_hour
+        ),
+        None => info!(
+            "{} not set, there won't be daily snapshots",
+            ARTIFACTS_DIR_ENV
+        ),
+    }
+    // Used by the cache, for state override simulations and hint pre-warming. Anvil forks get their
+    // own provider.
+    let live_provider = match ProviderBuilder::new()
+        .on_ipc(IpcConnect::new("/tmp/reth.ipc".to_string()))
         .await
     {
-        Ok(buckets) => buckets,
+        Ok(provider) => provider,
         Err(e) => {
-            error!("Failed to initialize cache: {}", e);
+            error!("Failed to connect to IPC: {}", e);
             std::process::exit(1);
         }
     };
+    let startup_permit = startup::acquire("vega-rs", StartupPhase::Backfill).await;
+    // Each market gets a cache of its own, with its own checkpoint, snapshot and mapping files
+    let mut caches: Vec<UserReservesCache> = vec![];
+    let mut user_buckets_by_market = vec![];
+    for market in resolve_markets_from_env(Arc::new(live_provider.clone())).await {
+        let mut cache = UserReservesCache::new(Arc::new(live_provider.clone()), market.clone());
+        if let Some(mutation_log_file) = &cache_mutation_log_file {
+            cache.set_mutation_log_file(&market.scoped_path(mutation_log_file));
+        }
+        if let Some(artifacts) = &artifacts {
+            cache.set_daily_snapshots(artifacts.clone(), daily_snapshot_hour);
+        }
+        let cache_snapshot_file = cache_snapshot_file
+            .as_deref()
+            .map(|snapshot_file| market.scoped_path(snapshot_file));
+        // Node hiccups are worth another try, discovery and position reads resume from their
+        // checkpoints. Malformed files and settings won't fix themselves.
+        let mut attempt = 1;
+        let initialized = loop {
+            let initialized = cache
+                .initialize_cache(
+                    &market.scoped_path(&borrowers_checkpoint_file),
+                    &chainlink_mapping_source.for_market(&market),
+                    &temp_output_dir,
+                    cache_snapshot_file.as_deref(),
+                )
+                .await;
+            match initialized {
+                Err(e)
+                    if e.is_retryable()
+                        && RetryPolicy::RPC
+                            .max_attempts
+                            .is_some_and(|max_attempts| attempt < max_attempts) =>
+                {
+                    let delay = RetryPolicy::RPC.delay(attempt);
+                    warn!(
+                        "Failed to initialize cache of the {} market (attempt {}), retrying in {:?}: {}",
+                        market.name, attempt, delay, e
+                    );
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                initialized => break initialized,
+            }
+        };
+        match initialized {
+            Ok(buckets) => user_buckets_by_market.push(buckets),
+            // The core market is what vega is for, the others can be left out
+            Err(e) if market.is_core() => {
+                error!("Failed to initialize cache: {}", e);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                error!(
+                    "Failed to initialize cache of the {} market, it won't be followed: {}",
+                    market.name, e
+                );
+                continue;
+            }
+        }
+        caches.push(cache);
+    }
+
+    let anvil_pool = if args.anvil_pool_size > 0 {
+        match AnvilPool::new(args.anvil_pool_size).await {
+            Ok(pool) => Some(pool),
+            Err(e) => {
+                error!("Failed to start Anvil pool: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let simulation = SimulationContext {
+        backend: args.simulation_backend,
+        live_provider,
+        anvil_pool,
+        incremental_hf: args.incremental_hf,
+    };
+    let signal_stream = SignalStream::from_env().await;
+    let price_guard = PriceGuard::from_env();
+
+    for cache in &caches {
+        spawn_chainlink_mapping_reload(
+            cache.chainlink_mapping(),
+            chainlink_mapping_source.for_market(cache.market()),
+            simulation.live_provider.clone(),
+            cache.market().clone(),
+        );
+        if simulation.backend == SimulationBackend::Offchain {
+            spawn_reserves_refresh(
+                cache.hf_engine(),
+                simulation.live_provider.clone(),
+                cache.market().clone(),
+            );
+        }
+    }
+    if simulation.backend == SimulationBackend::Offchain {
+        spawn_index_drift_monitor(
+            caches
+                .iter()
+                .map(|cache| (cache.hf_engine(), cache.market().clone()))
+                .collect(),
+            simulation.live_provider.clone(),
+            &INDEX_DRIFT_PPM,
+            &INDEX_DRIFT_REFRESHES,
+        );
+    }
 
     let uw_event_bus = Arc::new(UnderwaterUserEventBus::new(10000));
     let mut uw_log_subscriber = uw_event_bus.subscribe();
     tokio::spawn(async move {
         while let Ok(event) = uw_log_subscriber.recv().await {
+            UNDERWATER_ALERTS.inc();
             info!(
-                "ALERT (from event bus) | {} | {} has HF < 1: {} (total collateral {})",
+                "ALERT (from event bus) | {} | {} has HF < 1 on the {} market: {} (total collateral {})",
                 event.trace_id,
                 event.address,
+                event.market.name,
                 event.user_account_data.healthFactor,
                 event.user_account_data.totalCollateralBase
             );
         }
     });
-    let mut profito_subscriber = uw_event_bus.subscribe();
-    tokio::spawn(async move {
-        let context = zmq::Context::new();
-        let profito_socket = context.socket(zmq::PUSH).unwrap();
-        if let Err(e) = profito_socket.connect(PROFITO_INBOUND_ENDPOINT) {
-            error!("Failed to connect to profito-rs: {}", e);
-            return;
-        }
-        while let Ok(event) = profito_subscriber.recv().await {
-            if let Ok(bytes) = bincode::serialize(&event) {
-                if let Err(e) = profito_socket.send(&bytes, 0) {
-                    error!("Failed to send message to profito-rs: {}", e);
-                }
-            }
-        }
-    });
+    let (cancellation_sender, cancellation_receiver) = mpsc::unbounded_channel();
+    tokio::spawn(run_profito_sender(
+        uw_event_bus.subscribe(),
+        cancellation_receiver,
+        &UW_EVENTS_RETRANSMITTED,
+        &UW_EVENTS_UNACKED,
+    ));
 
-    if let Err(e) =
-        _dump_initial_hf_results(user_buckets, &temp_output_dir, uw_event_bus.clone()).await
-    {
-        error!("Failed to dump initial HF results: {:?}", e);
-        std::process::exit(1);
-    }
+    CACHED_USERS.set(cached_users(&caches).await);
+    StatusReporter::new("vega-rs")
+        .counter("price_updates", &PRICE_UPDATES_PROCESSED)
+        .counter("hints", &HINTS_PROCESSED)
+        .counter("price_updates_cancelled", &PRICE_UPDATES_CANCELLED)
+        .counter("whistleblower_events", &WHISTLEBLOWER_EVENTS_PROCESSED)
+        .counter("underwater_alerts", &UNDERWATER_ALERTS)
+        .counter("uw_events_retransmitted", &UW_EVENTS_RETRANSMITTED)
+        .counter("uw_events_unacked", &UW_EVENTS_UNACKED)
+        .counter("implausible_price_updates", &IMPLAUSIBLE_PRICE_UPDATES)
+        .counter("index_drift_refreshes", &INDEX_DRIFT_REFRESHES)
+        .gauge("cached_users", &CACHED_USERS)
+        .gauge("index_drift_ppm", &INDEX_DRIFT_PPM)
+        .spawn(Arc::new(simulation.live_provider.clone()));
 
     // Create IPC file and start listening for price updates
     info!("Setting up vega-rs ZMQ socket for inbound connections...");
@@ -309,15 +861,77 @@ async fn main() -> Result<(), String> {
             std::process::exit(1);
         }
     };
+
+    // The initial sweep takes minutes on big address files, so it runs in the background while
+    // live updates are already being processed. Its results are handed back to this loop, which
+    // owns the caches, along with the index of the cache they're for. It's still part of the
+    // backfill, so the startup permit is held until the sweeps of every market end.
+    let (init_hf_results_tx, mut init_hf_results_rx) = mpsc::unbounded_channel();
+    let startup_permit = Arc::new(startup_permit);
+    for (cache_index, (cache, user_buckets)) in
+        caches.iter().zip(user_buckets_by_market).enumerate()
+    {
+        let temp_output_dir = temp_output_dir.clone();
+        let uw_event_bus = uw_event_bus.clone();
+        let market = cache.market().clone();
+        let base_currency = cache.base_currency();
+        let init_hf_results_tx = init_hf_results_tx.clone();
+        let startup_permit = startup_permit.clone();
+        tokio::spawn(async move {
+            let _startup_permit = startup_permit;
+            match _dump_initial_hf_results(
+                user_buckets,
+                &market,
+                &temp_output_dir,
+                uw_event_bus,
+                base_currency,
+            )
+            .await
+            {
+                Ok(init_hf_results) => {
+                    let _ = init_hf_results_tx.send((cache_index, init_hf_results));
+                }
+                Err(e) => error!(
+                    "Failed to dump initial HF results of the {} market, the HF index will only be seeded by live updates: {:?}",
+                    market.name, e
+                ),
+            }
+        });
+    }
+    drop(startup_permit);
+
     info!("VEGA is running and listening for price updates...");
+    // Whistleblower updates of the block being received. They're applied together once no more
+    // of them are queued, or before anything else is handled, so price updates never run on a
+    // cache that's missing them.
+    let mut pending_wb_updates: Vec<WhistleblowerUpdate> = vec![];
     loop {
-        let msg = match inbound_socket.recv_bytes(0) {
+        let flags = if pending_wb_updates.is_empty() {
+            0
+        } else {
+            zmq::DONTWAIT
+        };
+        let msg = match inbound_socket.recv_bytes(flags) {
             Ok(bytes) => bytes,
+            Err(zmq::Error::EAGAIN) => {
+                apply_whistleblower_updates(&mut caches, &mut pending_wb_updates).await;
+                continue;
+            }
             Err(e) => {
                 warn!("Failed to receive inbound update: {}", e);
                 continue;
             }
         };
+        // Seeds the HF index, so the next bundles evaluate the riskiest users first
+        while let Ok((cache_index, init_hf_results)) = init_hf_results_rx.try_recv() {
+            let cache = &mut caches[cache_index];
+            info!(
+                "Seeding the HF index of the {} market with {} initial results",
+                cache.market().name,
+                init_hf_results.len()
+            );
+            cache.seed_health_factors(&init_hf_results);
+        }
         let deserialized_message = match deserialize::<MessageBundle>(&msg) {
             Ok(message) => message,
             Err(e) => {
@@ -325,27 +939,148 @@ async fn main() -> Result<(), String> {
                 continue;
             }
         };
+        if !matches!(
+            deserialized_message,
+            MessageBundle::WhistleblowerNotification(_)
+        ) {
+            apply_whistleblower_updates(&mut caches, &mut pending_wb_updates).await;
+        }
         match deserialized_message {
             MessageBundle::PriceUpdate(price_update) => {
                 let trace_id =
                     Some(&price_update).map_or("initial-run".to_string(), |b| b.trace_id.clone());
-                info!("Vega received price update for trace_id {}", trace_id);
-                run_price_update_pipeline(
-                    &mut user_reserves_cache,
-                    Some(&price_update),
-                    &temp_output_dir,
-                    uw_event_bus.clone(),
-                )
-                .await;
+                info!(
+                    "Vega received price update for trace_id {} from {}",
+                    trace_id, price_update.source
+                );
+                // The same feed can price reserves of several markets
+                for cache in caches.iter_mut() {
+                    run_price_update_pipeline(
+                        cache,
+                        Some(&price_update),
+                        &temp_output_dir,
+                        uw_event_bus.clone(),
+                        &simulation,
+                        signal_stream.as_ref(),
+                        &price_guard,
+                    )
+                    .await;
+                }
+                PRICE_UPDATES_PROCESSED.inc();
+            }
+            MessageBundle::PriceUpdateHint(hint) => {
+                info!(
+                    "Vega received price update hint for trace_id {} from {} ({} feeds)",
+                    hint.trace_id,
+                    hint.source,
+                    hint.price_ranges.len()
+                );
+                for cache in caches.iter_mut() {
+                    run_price_hint_pipeline(
+                        cache,
+                        &hint,
+                        &temp_output_dir,
+                        &simulation.live_provider,
+                    )
+                    .await;
+                }
+                HINTS_PROCESSED.inc();
+            }
+            MessageBundle::PriceUpdateCancelled(cancellation) => {
+                // By now the update was processed, whatever is left to abort is in profito
+                warn!(
+                    "Vega received cancellation of trace_id {} ({:?}), passing it on to profito",
+                    cancellation.trace_id, cancellation.reason
+                );
+                if cancellation_sender.send(cancellation).is_err() {
+                    error!("profito sender is gone, the cancellation was lost");
+                }
+                PRICE_UPDATES_CANCELLED.inc();
             }
             MessageBundle::WhistleblowerNotification(whistleblower_update) => {
                 info!(update_details = ?whistleblower_update, "Received whistleblower update");
-                if let Err(e) = user_reserves_cache
-                    .update_cache(&whistleblower_update)
-                    .await
+                // Updates are coalesced per block
+                if pending_wb_updates
+                    .last()
+                    .is_some_and(|last| last.block_number != whistleblower_update.block_number)
+                {
+                    apply_whistleblower_updates(&mut caches, &mut pending_wb_updates).await;
+                }
+                pending_wb_updates.push(whistleblower_update);
+            }
+            MessageBundle::AssetSourceUpdate(update) => {
+                info!(update_details = ?update, "Received asset source update");
+                match caches
+                    .iter()
+                    .find(|cache| cache.market().addresses_provider == update.market)
                 {
-                    warn!("Failed to update cache: {}", e);
+                    Some(cache) => cache.apply_asset_source_update(&update).await,
+                    None => warn!(
+                        "Ignoring asset source update of market {}, which isn't followed",
+                        update.market
+                    ),
+                }
+            }
+            MessageBundle::WatchlistExport(request) => {
+                // One file per market, see AaveMarket::scoped_path()
+                for cache in &caches {
+                    match cache.export_watchlist(&request) {
+                        Ok((path, exported)) => {
+                            info!("Exported watchlist with {} users to {}", exported, path)
+                        }
+                        Err(e) => warn!(
+                            "Failed to export watchlist of the {} market: {}",
+                            cache.market().name,
+                            e
+                        ),
+                    }
+                }
+            }
+            MessageBundle::WatchlistImport(entries) => {
+                for cache in caches.iter_mut() {
+                    let market_entries = entries
+                        .iter()
+                        .filter(|entry| cache.tracks(entry))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    if !market_entries.is_empty() {
+                        cache.import_watchlist(&market_entries).await;
+                    }
+                }
+                let untracked = entries
+                    .iter()
+                    .filter(|entry| !caches.iter().any(|cache| cache.tracks(entry)))
+                    .count();
+                if untracked > 0 {
+                    warn!(
+                        "Ignored {} watchlist entries of markets that aren't followed",
+                        untracked
+                    );
+                }
+                CACHED_USERS.set(cached_users(&caches).await);
+            }
+            MessageBundle::CacheUndo(request) => {
+                for cache in caches.iter_mut().filter(|cache| {
+                    request
+                        .market
+                        .as_ref()
+                        .is_none_or(|name| *name == cache.market().name)
+                }) {
+                    match cache.undo_to_block(request.block_number).await {
+                        Ok(restored) => info!(
+                            "Cache of the {} market undone to block {} ({} users restored)",
+                            cache.market().name,
+                            request.block_number,
+                            restored
+                        ),
+                        Err(e) => error!(
+                            "Failed to undo cache of the {} market: {}",
+                            cache.market().name,
+                            e
+                        ),
+                    }
                 }
+                CACHED_USERS.set(cached_users(&caches).await);
             }
         };
     }
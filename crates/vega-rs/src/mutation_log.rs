@@ -0,0 +1,139 @@
+use alloy::primitives::Address;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::Write,
+};
+use tracing::{info, warn};
+
+use crate::error::CacheError;
+use crate::hf_engine::UserScaledPosition;
+
+/// Mutations kept in memory, enough for a few hundred busy blocks
+const MUTATION_LOG_CAPACITY: usize = 20_000;
+
+/// Everything the cache knows of a user
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CachedUser {
+    pub positions: Vec<UserScaledPosition>,
+    pub emode_category: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MutationKind {
+    Added,
+    Removed,
+    Updated,
+}
+
+/// A change to a single user of the cache, with what it looked like before so it can be undone
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheMutation {
+    /// Block of the updates that caused it. Changes not caused by an event (e.g. watchlist
+    /// imports) get the block of the last update applied before them.
+    pub block_number: u64,
+    pub user: Address,
+    pub kind: MutationKind,
+    /// Trace ids and events of the updates that caused it, or the operation that did
+    pub source: String,
+    /// None if the user wasn't cached before
+    pub before: Option<CachedUser>,
+    /// None if the user was dropped
+    pub after: Option<CachedUser>,
+}
+
+impl CacheMutation {
+    /// None if nothing changed
+    pub fn new(
+        block_number: u64,
+        user: Address,
+        source: &str,
+        before: Option<CachedUser>,
+        after: Option<CachedUser>,
+    ) -> Option<Self> {
+        let kind = match (&before, &after) {
+            (None, Some(_)) => MutationKind::Added,
+            (Some(_), None) => MutationKind::Removed,
+            (Some(before), Some(after)) if before != after => MutationKind::Updated,
+            _ => return None,
+        };
+        Some(Self {
+            block_number,
+            user,
+            kind,
+            source: source.to_string(),
+            before,
+            after,
+        })
+    }
+}
+
+/// Every mutation applied to the user cache, newest last. The last MUTATION_LOG_CAPACITY of them
+/// are kept in memory, which is how far back undo_to_block() can go. With a file, every mutation
+/// is also appended to it as a json line, for post mortems.
+pub struct MutationLog {
+    mutations: VecDeque<CacheMutation>,
+    /// Block of the newest mutation dropped from memory. Undoing to a block before it would
+    /// leave that mutation in place.
+    evicted_up_to_block: Option<u64>,
+    sink: Option<(String, File)>,
+}
+
+impl MutationLog {
+    pub fn new(sink_path: Option<&str>) -> Self {
+        let sink = sink_path.and_then(|path| {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => {
+                    info!("Cache mutations will be appended to {}", path);
+                    Some((path.to_string(), file))
+                }
+                Err(e) => {
+                    warn!("Failed to open cache mutation log {}: {}", path, e);
+                    None
+                }
+            }
+        });
+        Self {
+            mutations: VecDeque::new(),
+            evicted_up_to_block: None,
+            sink,
+        }
+    }
+
+    pub fn record(&mut self, mutation: CacheMutation) {
+        if let Some((path, file)) = &mut self.sink {
+            let written = serde_json::to_string(&mutation)
+                .map_err(|e| e.to_string())
+                .and_then(|line| writeln!(file, "{}", line).map_err(|e| e.to_string()));
+            if let Err(e) = written {
+                warn!("Failed to write to cache mutation log {}: {}", path, e);
+            }
+        }
+        if self.mutations.len() == MUTATION_LOG_CAPACITY {
+            if let Some(evicted) = self.mutations.pop_front() {
+                self.evicted_up_to_block = self.evicted_up_to_block.max(Some(evicted.block_number));
+            }
+        }
+        self.mutations.push_back(mutation);
+    }
+
+    /// Removes the mutations of the blocks after `block_number` and returns them, newest first.
+    /// Fails without removing anything if some of them were already dropped from memory.
+    pub fn take_after(&mut self, block_number: u64) -> Result<Vec<CacheMutation>, CacheError> {
+        if let Some(evicted_up_to_block) = self.evicted_up_to_block {
+            if evicted_up_to_block > block_number {
+                return Err(CacheError::UndoOutOfRange {
+                    block_number,
+                    oldest_block: evicted_up_to_block,
+                });
+            }
+        }
+        let (taken, kept): (Vec<_>, Vec<_>) = self
+            .mutations
+            .drain(..)
+            .partition(|mutation| mutation.block_number > block_number);
+        self.mutations = kept.into();
+        Ok(taken.into_iter().rev().collect())
+    }
+}
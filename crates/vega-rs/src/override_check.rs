@@ -0,0 +1,122 @@
+use alloy::{
+    primitives::{Address, I256, U256},
+    providers::{ext::AnvilApi, RootProvider},
+    pubsub::PubSubFrontend,
+};
+use overlord_shared::{
+    sol_bindings::AccessControlledOCR2Aggregator, PriceUpdateBundle, PriceUpdateTx,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::fork_provider::{encode_transmission, get_storage_key_for_price_update};
+
+/// What an aggregator reported after a known Transmission was written into its storage
+#[derive(Debug)]
+pub struct OverrideCheck {
+    pub aggregator: Address,
+    pub type_and_version: String,
+    /// Every field of latestRoundData() that didn't read back as written. Empty if the payload
+    /// decodes as intended.
+    pub mismatches: Vec<String>,
+}
+
+impl OverrideCheck {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Writes the override payload for a made-up update into the storage of `aggregator` on a fork,
+/// the same way price updates are simulated, and checks that latestRoundData() reads back the
+/// answer and both timestamps. Timestamps are different on purpose, so a swapped layout shows up.
+///
+/// `provider` is the live node, which the storage key is computed against, and `fork_provider`
+/// a fork of it whose storage can be written.
+pub async fn check_price_update_override(
+    provider: &RootProvider<PubSubFrontend>,
+    fork_provider: &RootProvider<PubSubFrontend>,
+    aggregator: Address,
+) -> Result<OverrideCheck, String> {
+    let live_aggregator = AccessControlledOCR2Aggregator::new(aggregator, provider.clone());
+    let type_and_version = live_aggregator
+        .typeAndVersion()
+        .call()
+        .await
+        .map(|response| response._0)
+        .unwrap_or_else(|_| "unknown".to_string());
+    let current_answer = live_aggregator
+        .latestAnswer()
+        .call()
+        .await
+        .map_err(|e| format!("Failed to get latestAnswer from {}: {}", aggregator, e))?
+        ._0;
+    // Close to the current answer, but different from it, so a stale read doesn't pass
+    let answer = if current_answer.is_positive() {
+        current_answer + current_answer / I256::from_raw(U256::from(100)) + I256::ONE
+    } else {
+        I256::from_raw(U256::from(100_000_000))
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as u32);
+    let (observations_timestamp, transmission_timestamp) =
+        (now.saturating_sub(7), now.saturating_sub(3));
+
+    let bundle = PriceUpdateBundle {
+        trace_id: "override-check".to_string(),
+        tx_hash: String::new(),
+        price_update_tx: PriceUpdateTx::Synthetic,
+        inclusion_block: String::new(),
+        tx_new_price: answer.into_raw(),
+        forward_to: aggregator,
+        tx_from: Address::ZERO,
+        tx_to: aggregator,
+        tx_input: Default::default(),
+        source: "override-check".to_string(),
+        slot_deadline_ms: None,
+    };
+    let storage_key = get_storage_key_for_price_update(provider.clone(), &bundle)
+        .await
+        .map_err(|e| format!("Failed to get storage key for {}: {}", aggregator, e))?;
+    let payload = encode_transmission(
+        bundle.tx_new_price,
+        observations_timestamp,
+        transmission_timestamp,
+    );
+    fork_provider
+        .anvil_set_storage_at(aggregator, storage_key, payload)
+        .await
+        .map_err(|e| format!("Failed to set storage of {} on the fork: {}", aggregator, e))?;
+
+    let round_data = AccessControlledOCR2Aggregator::new(aggregator, fork_provider.clone())
+        .latestRoundData()
+        .call()
+        .await
+        .map_err(|e| {
+            format!(
+                "Failed to get latestRoundData from {} on the fork: {}",
+                aggregator, e
+            )
+        })?;
+    let mut mismatches = vec![];
+    if round_data.answer != answer {
+        mismatches.push(format!("answer is {}, wrote {}", round_data.answer, answer));
+    }
+    if round_data.startedAt != U256::from(observations_timestamp) {
+        mismatches.push(format!(
+            "startedAt is {}, wrote observationsTimestamp {}",
+            round_data.startedAt, observations_timestamp
+        ));
+    }
+    if round_data.updatedAt != U256::from(transmission_timestamp) {
+        mismatches.push(format!(
+            "updatedAt is {}, wrote transmissionTimestamp {}",
+            round_data.updatedAt, transmission_timestamp
+        ));
+    }
+    Ok(OverrideCheck {
+        aggregator,
+        type_and_version,
+        mismatches,
+    })
+}
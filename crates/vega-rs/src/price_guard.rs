@@ -0,0 +1,150 @@
+use alloy::{
+    primitives::{Address, U256},
+    providers::RootProvider,
+    pubsub::PubSubFrontend,
+};
+use overlord_shared::{
+    constants::GHO_PRICE_ORACLE, sol_bindings::AccessControlledOCR2Aggregator, PriceUpdateBundle,
+    PriceUpdateTx,
+};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+/// Largest move (in bps of the latest answer) a single price update can imply. 0 turns it off.
+pub const MAX_PRICE_MOVE_BPS_ENV: &str = "VEGA_MAX_PRICE_MOVE_BPS";
+/// Comma-separated `asset=bps` pairs, for reserves that need a different limit (e.g. tighter for
+/// stablecoins). A limit of 0 lets any move of that asset through.
+pub const ASSET_MAX_PRICE_MOVE_BPS_ENV: &str = "VEGA_ASSET_MAX_PRICE_MOVE_BPS";
+const MAX_PRICE_MOVE_BPS: u64 = 3000;
+
+/// A price update whose price is too far from the latest answer of its feed to be trusted
+#[derive(Debug)]
+pub struct ImplausibleMove {
+    pub latest_answer: U256,
+    pub new_price: U256,
+    pub move_bps: U256,
+    pub max_move_bps: u64,
+}
+
+/// Refuses to run the pipeline for price updates that imply an implausible move, which are far
+/// more likely to come from a decoding bug than from the market. Running them would trigger HF
+/// checks for every holder of the asset, and maybe bundles built on a made-up price.
+///
+/// Each reserve gets the default limit unless it has one of its own. An update affecting several
+/// reserves is held to the strictest of their limits.
+pub struct PriceGuard {
+    default_max_move_bps: Option<u64>,
+    max_move_bps_by_asset: HashMap<Address, Option<u64>>,
+}
+
+fn limit_from_bps(bps: u64) -> Option<u64> {
+    (bps > 0).then_some(bps)
+}
+
+impl PriceGuard {
+    pub fn from_env() -> Self {
+        let default_max_move_bps = match std::env::var(MAX_PRICE_MOVE_BPS_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                warn!(
+                    "Invalid {} value {}, using {}: {}",
+                    MAX_PRICE_MOVE_BPS_ENV, value, MAX_PRICE_MOVE_BPS, e
+                );
+                MAX_PRICE_MOVE_BPS
+            }),
+            Err(_) => MAX_PRICE_MOVE_BPS,
+        };
+        let max_move_bps_by_asset = std::env::var(ASSET_MAX_PRICE_MOVE_BPS_ENV)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .filter_map(|pair| {
+                let parsed = pair.split_once('=').and_then(|(asset, bps)| {
+                    Some((
+                        asset.trim().parse::<Address>().ok()?,
+                        limit_from_bps(bps.trim().parse().ok()?),
+                    ))
+                });
+                if parsed.is_none() {
+                    warn!(
+                        "Ignoring invalid pair {} in {}",
+                        pair, ASSET_MAX_PRICE_MOVE_BPS_ENV
+                    );
+                }
+                parsed
+            })
+            .collect::<HashMap<Address, Option<u64>>>();
+        let guard = Self {
+            default_max_move_bps: limit_from_bps(default_max_move_bps),
+            max_move_bps_by_asset,
+        };
+        match guard.default_max_move_bps {
+            Some(bps) => info!(
+                "Price updates moving a feed more than {} bps won't be processed ({} assets with their own limit)",
+                bps,
+                guard.max_move_bps_by_asset.len()
+            ),
+            None => info!(
+                "Price moves aren't limited by default ({} assets with their own limit)",
+                guard.max_move_bps_by_asset.len()
+            ),
+        }
+        guard
+    }
+
+    /// Strictest limit among `assets`, None if none of them is limited
+    pub fn max_move_bps(&self, assets: &[Address]) -> Option<u64> {
+        assets
+            .iter()
+            .filter_map(|asset| {
+                *self
+                    .max_move_bps_by_asset
+                    .get(asset)
+                    .unwrap_or(&self.default_max_move_bps)
+            })
+            .min()
+    }
+
+    /// Compares the price of `bundle` with the latest answer of its feed. Updates pass if none of
+    /// the affected assets is limited, if they're rehearsals (whoever injected them meant the
+    /// move), for GHO, whose price oracle isn't an aggregator, and if the latest answer can't be
+    /// read, so a flaky node doesn't stop the pipeline.
+    pub async fn check(
+        &self,
+        provider: &RootProvider<PubSubFrontend>,
+        bundle: &PriceUpdateBundle,
+        affected_assets: &[Address],
+    ) -> Result<(), ImplausibleMove> {
+        let Some(max_move_bps) = self.max_move_bps(affected_assets) else {
+            return Ok(());
+        };
+        if bundle.price_update_tx == PriceUpdateTx::Rehearsal
+            || bundle.forward_to == GHO_PRICE_ORACLE
+        {
+            return Ok(());
+        }
+        let aggregator = AccessControlledOCR2Aggregator::new(bundle.forward_to, provider.clone());
+        let latest_answer = match aggregator.latestRoundData().call().await {
+            Ok(round_data) if round_data.answer.is_positive() => round_data.answer.into_raw(),
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                warn!(
+                    "Failed to get latestRoundData from {}, not checking the move of {}: {}",
+                    bundle.forward_to, bundle.trace_id, e
+                );
+                return Ok(());
+            }
+        };
+        let move_bps =
+            bundle.tx_new_price.abs_diff(latest_answer) * U256::from(10_000) / latest_answer;
+        if move_bps > U256::from(max_move_bps) {
+            return Err(ImplausibleMove {
+                latest_answer,
+                new_price: bundle.tx_new_price,
+                move_bps,
+                max_move_bps,
+            });
+        }
+        Ok(())
+    }
+}
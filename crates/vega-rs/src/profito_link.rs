@@ -0,0 +1,137 @@
+use alloy::primitives::Address;
+use overlord_shared::{
+    constants::{PROFITO_CANCEL_ENDPOINT, PROFITO_INBOUND_ENDPOINT, VEGA_ACK_ENDPOINT},
+    status::Counter,
+    PriceUpdateCancellation, UnderwaterUserAck, UnderwaterUserEvent,
+};
+use std::collections::HashMap;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{interval, Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+/// profito acks as soon as an event is deserialized, so anything slower than this over IPC means
+/// the message (or its ack) was lost
+const ACK_TIMEOUT: Duration = Duration::from_millis(250);
+const ACK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Trace id, user and PoolAddressesProvider of the market
+type EventKey = (String, Address, Address);
+
+struct PendingEvent {
+    serialized: Vec<u8>,
+    sent_at: Instant,
+    retransmitted: bool,
+}
+
+/// Pushes every underwater user event to profito and waits for its ack. An event that isn't acked
+/// within ACK_TIMEOUT is sent once more, and counted as unacked if the second attempt times out
+/// too. Liquidations are time sensitive, so there's no point in retrying further.
+///
+/// Cancelled price updates are passed on to profito on a socket of their own, and their events
+/// still waiting for an ack aren't retransmitted.
+pub async fn run_profito_sender(
+    mut events: broadcast::Receiver<UnderwaterUserEvent>,
+    mut cancellations: mpsc::UnboundedReceiver<PriceUpdateCancellation>,
+    retransmitted: &'static Counter,
+    unacked: &'static Counter,
+) {
+    let context = zmq::Context::new();
+    let profito_socket = context.socket(zmq::PUSH).unwrap();
+    if let Err(e) = profito_socket.connect(PROFITO_INBOUND_ENDPOINT) {
+        error!("Failed to connect to profito-rs: {}", e);
+        return;
+    }
+    let cancel_socket = context.socket(zmq::PUSH).unwrap();
+    if let Err(e) = cancel_socket.connect(PROFITO_CANCEL_ENDPOINT) {
+        error!("Failed to connect to {}: {}", PROFITO_CANCEL_ENDPOINT, e);
+        return;
+    }
+    let ack_socket = context.socket(zmq::PULL).unwrap();
+    if let Err(e) = ack_socket.bind(VEGA_ACK_ENDPOINT) {
+        error!("Failed to bind to {}: {}", VEGA_ACK_ENDPOINT, e);
+        return;
+    }
+    info!("Waiting for profito-rs acks on {}", VEGA_ACK_ENDPOINT);
+
+    let mut pending: HashMap<EventKey, PendingEvent> = HashMap::new();
+    let mut ticker = interval(ACK_POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("profito sender lagged behind, {} events were never sent", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let serialized = match bincode::serialize(&event) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        error!("Failed to serialize event for profito-rs: {}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = profito_socket.send(&serialized, 0) {
+                    error!("Failed to send message to profito-rs: {}", e);
+                }
+                pending.insert(
+                    (event.trace_id, event.address, event.market.addresses_provider),
+                    PendingEvent {
+                        serialized,
+                        sent_at: Instant::now(),
+                        retransmitted: false,
+                    },
+                );
+            }
+            Some(cancellation) = cancellations.recv() => {
+                pending.retain(|(trace_id, _, _), _| *trace_id != cancellation.trace_id);
+                match bincode::serialize(&cancellation) {
+                    Ok(bytes) => {
+                        if let Err(e) = cancel_socket.send(&bytes, 0) {
+                            error!("Failed to send cancellation to profito-rs: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Failed to serialize cancellation for profito-rs: {}", e),
+                }
+            }
+            _ = ticker.tick() => {
+                while let Ok(bytes) = ack_socket.recv_bytes(zmq::DONTWAIT) {
+                    match bincode::deserialize::<UnderwaterUserAck>(&bytes) {
+                        Ok(ack) => {
+                            pending.remove(&(ack.trace_id, ack.address, ack.market));
+                        }
+                        Err(e) => warn!("Failed to deserialize ack from profito-rs: {}", e),
+                    }
+                }
+                pending.retain(|(trace_id, address, _), event| {
+                    if event.sent_at.elapsed() < ACK_TIMEOUT {
+                        return true;
+                    }
+                    if event.retransmitted {
+                        unacked.inc();
+                        warn!(
+                            "profito-rs never acked {} of trace_id {}, giving up",
+                            address, trace_id
+                        );
+                        return false;
+                    }
+                    warn!(
+                        "profito-rs didn't ack {} of trace_id {}, sending it again",
+                        address, trace_id
+                    );
+                    if let Err(e) = profito_socket.send(&event.serialized, 0) {
+                        error!("Failed to resend message to profito-rs: {}", e);
+                    }
+                    retransmitted.inc();
+                    event.sent_at = Instant::now();
+                    event.retransmitted = true;
+                    true
+                });
+            }
+        }
+    }
+}
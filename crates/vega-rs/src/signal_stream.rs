@@ -0,0 +1,152 @@
+use alloy::primitives::{Address, U256};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{error, info, warn};
+
+/// Address (e.g. 127.0.0.1:9400) the NDJSON signal stream listens on. Disabled when unset.
+pub const SIGNAL_STREAM_ADDR_ENV: &str = "VEGA_SIGNAL_STREAM_ADDR";
+// Pipeline runs a slow client can fall behind by before it starts missing them
+const SIGNAL_STREAM_CAPACITY: usize = 256;
+
+/// Candidates evaluated by one run of the price update pipeline
+pub struct PipelineSignal {
+    pub trace_id: String,
+    pub run_id: String,
+    /// Name of the Aave market the candidates were drawn from
+    pub market: String,
+    pub source: String,
+    pub inclusion_block: String,
+    pub affected_assets: Vec<(Address, String)>,
+    /// Unix time (in milliseconds) the pipeline finished at
+    pub evaluated_at_ms: u64,
+    pub elapsed_ms: u64,
+    pub health_factors: HashMap<Address, U256>,
+}
+
+/// One line of the stream, for a single candidate
+#[derive(Serialize)]
+struct SignalLine<'a> {
+    trace_id: &'a str,
+    run_id: &'a str,
+    market: &'a str,
+    source: &'a str,
+    inclusion_block: &'a str,
+    user: Address,
+    health_factor: String,
+    underwater: bool,
+    affected_assets: &'a [(Address, String)],
+    evaluated_at_ms: u64,
+    elapsed_ms: u64,
+}
+
+/// Streams the health factors computed by vega to research clients over TCP, as one JSON object
+/// per candidate and line. The pipeline only hands a finished run to a broadcast channel, and
+/// serializing and writing it happen in each client's task, so the hot path never waits on a
+/// client. Clients that fall behind skip the runs they missed.
+#[derive(Clone)]
+pub struct SignalStream {
+    sender: broadcast::Sender<Arc<PipelineSignal>>,
+}
+
+impl SignalStream {
+    /// Starts listening on SIGNAL_STREAM_ADDR_ENV. None if it's unset or can't be bound.
+    pub async fn from_env() -> Option<Self> {
+        let addr = std::env::var(SIGNAL_STREAM_ADDR_ENV).ok()?;
+        let listener = match TcpListener::bind(&addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind the signal stream to {}: {}", addr, e);
+                return None;
+            }
+        };
+        info!("Streaming pipeline signals as NDJSON on {}", addr);
+        let (sender, _) = broadcast::channel(SIGNAL_STREAM_CAPACITY);
+        let stream = Self { sender };
+        tokio::spawn({
+            let sender = stream.sender.clone();
+            async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((socket, peer)) => {
+                            info!("Signal stream client connected from {}", peer);
+                            tokio::spawn(serve_client(socket, sender.subscribe()));
+                        }
+                        Err(e) => warn!("Failed to accept a signal stream client: {}", e),
+                    }
+                }
+            }
+        });
+        Some(stream)
+    }
+
+    pub fn now_ms() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_millis() as u64)
+            .unwrap_or_default()
+    }
+
+    /// Hands a pipeline run to the connected clients. The signal is only built if there are any.
+    pub fn publish(&self, signal: impl FnOnce() -> PipelineSignal) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.sender.send(Arc::new(signal()));
+    }
+}
+
+async fn serve_client(
+    mut socket: TcpStream,
+    mut signals: broadcast::Receiver<Arc<PipelineSignal>>,
+) {
+    let peer = socket
+        .peer_addr()
+        .map(|peer| peer.to_string())
+        .unwrap_or_default();
+    let one = U256::from(1e18);
+    loop {
+        let signal = match signals.recv().await {
+            Ok(signal) => signal,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(
+                    "Signal stream client {} fell behind, skipped {} pipeline runs",
+                    peer, skipped
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let mut lines = Vec::new();
+        for (user, hf) in signal.health_factors.iter() {
+            let line = SignalLine {
+                trace_id: &signal.trace_id,
+                run_id: &signal.run_id,
+                market: &signal.market,
+                source: &signal.source,
+                inclusion_block: &signal.inclusion_block,
+                user: *user,
+                health_factor: hf.to_string(),
+                underwater: *hf < one,
+                affected_assets: &signal.affected_assets,
+                evaluated_at_ms: signal.evaluated_at_ms,
+                elapsed_ms: signal.elapsed_ms,
+            };
+            if serde_json::to_writer(&mut lines, &line).is_ok() {
+                lines.push(b'\n');
+            }
+        }
+        if let Err(e) = socket.write_all(&lines).await {
+            info!("Signal stream client {} disconnected: {}", peer, e);
+            return;
+        }
+    }
+}
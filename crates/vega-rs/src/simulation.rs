@@ -0,0 +1,90 @@
+use alloy::{
+    network::TransactionBuilder,
+    primitives::{Address, B256},
+    providers::{Provider, RootProvider},
+    pubsub::PubSubFrontend,
+    rpc::types::{
+        state::{AccountOverride, StateOverride},
+        TransactionRequest,
+    },
+    sol_types::SolCall,
+    transports::Transport,
+};
+use clap::ValueEnum;
+use overlord_shared::{sol_bindings::pool::AaveV3Pool, PriceUpdateBundle};
+use std::{collections::HashMap, sync::Arc};
+use tracing::info;
+
+use crate::anvil_pool::AnvilPool;
+use crate::error::VegaError;
+use crate::fork_provider::{get_payload_for_price_update, get_storage_key_for_price_update};
+
+/// How vega applies a price update before computing health factors
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SimulationBackend {
+    /// Spawn an Anvil fork per price update and write the new answer into its storage
+    Anvil,
+    /// Run eth_call against the node with the new answer passed as a state override.
+    /// Falls back to Anvil if the override can't be built.
+    StateOverride,
+    /// Compute HFs in memory from cached user balances and reserve state, without any RPC.
+    /// Falls back to a state override if the engine isn't ready or doesn't know the reserve.
+    Offchain,
+}
+
+/// Everything the price update pipeline needs to simulate a bundle
+pub struct SimulationContext {
+    pub backend: SimulationBackend,
+    /// Provider for the node itself, used for state override calls
+    pub live_provider: RootProvider<PubSubFrontend>,
+    /// Warm forks for the Anvil path. If None (or empty), a fork is spawned per bundle.
+    pub anvil_pool: Option<Arc<AnvilPool>>,
+    /// For the Offchain backend, only reprice the affected assets of each candidate instead of
+    /// recomputing its whole account data
+    pub incremental_hf: bool,
+}
+
+/// Builds the same storage write that ForkProvider applies with anvil_setStorageAt, but as a
+/// state override that can be attached to an eth_call, so no extra process is needed.
+pub async fn build_price_update_override(
+    provider: RootProvider<PubSubFrontend>,
+    bundle: &PriceUpdateBundle,
+) -> Result<StateOverride, VegaError> {
+    let storage_key = get_storage_key_for_price_update(provider, bundle).await?;
+    let storage_value = get_payload_for_price_update(bundle);
+    info!(
+        "Built state override for bundle {}: {} @ {:?} = {:?}",
+        bundle.trace_id, bundle.forward_to, storage_key, storage_value
+    );
+    let mut state_diff = HashMap::new();
+    state_diff.insert(B256::from(storage_key), storage_value);
+    let mut overrides = StateOverride::default();
+    overrides.insert(
+        bundle.forward_to,
+        AccountOverride {
+            state_diff: Some(state_diff),
+            ..Default::default()
+        },
+    );
+    Ok(overrides)
+}
+
+/// Equivalent to `pool.getUserAccountData(user).call()`, with the state override applied
+pub async fn get_user_account_data_with_overrides<T, P>(
+    provider: &P,
+    pool: Address,
+    user: Address,
+    overrides: &StateOverride,
+) -> Result<AaveV3Pool::getUserAccountDataReturn, Box<dyn std::error::Error + Send + Sync>>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    let tx = TransactionRequest::default()
+        .with_to(pool)
+        .with_input(AaveV3Pool::getUserAccountDataCall { user }.abi_encode());
+    let output = provider.call(&tx).overrides(overrides).await?;
+    Ok(AaveV3Pool::getUserAccountDataCall::abi_decode_returns(
+        &output, true,
+    )?)
+}
@@ -1,14 +1,28 @@
 use alloy::{
     primitives::{address, Address, U256},
-    providers::{IpcConnect, ProviderBuilder, RootProvider},
+    providers::{Provider, RootProvider},
     pubsub::PubSubFrontend,
+    rpc::types::BlockId,
+    transports::Transport,
 };
-use chrono::Local;
+use chrono::{Local, NaiveDate, Timelike, Utc};
 use futures::future::join_all;
 use overlord_shared::{
-    common::get_reserves_data,
-    sol_bindings::{pool::AaveV3Pool, AaveOracle, AaveUIPoolDataProvider, ERC20},
-    PriceUpdateBundle, WhistleblowerEventType, WhistleblowerUpdate,
+    asset_filter::ASSET_FILTER,
+    common::{
+        get_asset_price_at, get_base_currency, get_emode_categories, get_reserves_data,
+        get_reserves_list_at, get_user_emode_category, BaseCurrency,
+    },
+    constants::GHO_PRICE_ORACLE,
+    error::{FileError, NodeError},
+    market::AaveMarket,
+    multicall::{
+        get_user_emode_batch, get_user_reserves_data_batch, USER_ACCOUNT_DATA_BATCH_SIZE,
+        USER_RESERVES_DATA_BATCH_SIZE,
+    },
+    sol_bindings::{AaveUIPoolDataProvider, IUiPoolDataProviderV3::UserReserveData, ERC20},
+    AssetSourceUpdate, PriceUpdateBundle, WatchlistEntry, WatchlistExportRequest,
+    WatchlistPriority, WhistleblowerEventType, WhistleblowerUpdate,
 };
 use rand::seq::IndexedRandom;
 use serde_json::json;
@@ -16,26 +30,51 @@ use std::fs::OpenOptions;
 use std::io::Write;
 use std::{
     collections::HashMap,
-    error::Error,
     fs::File,
     io::{self, BufRead},
+    path::Path,
     str::FromStr,
+    time::SystemTime,
+};
+use std::{collections::HashSet, marker::PhantomData, sync::Arc};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::RwLock,
+    task::{self, JoinHandle},
+    time::{interval, Duration, Instant},
 };
-use std::{collections::HashSet, sync::Arc};
-use tokio::{sync::RwLock, task, time::Instant};
 use tracing::{error, info, warn};
 
+use crate::artifacts::{ArtifactKind, ArtifactStore};
+use crate::cache_snapshot::{get_users_affected_since, CacheSnapshot, InitProgress};
+use crate::calc_utils::get_user_account_data_for_users;
+use crate::chainlink_mapping::generate_chainlink_mapping;
+use crate::discovery::discover_borrowers;
+use crate::error::CacheError;
+use crate::hf_engine::{OffchainHfEngine, UserScaledPosition};
+use crate::hf_index::HfIndex;
+use crate::mutation_log::{CacheMutation, CachedUser, MutationLog};
+
 type UserAddress = Address;
 type ReserveAddress = Address;
 type ChainlinkContractAddress = Address;
+pub type ChainlinkMapping = HashMap<ChainlinkContractAddress, Vec<AaveReserveInfo>>;
 
 const MIN_COLLATERAL_THRESHOLD_IN_USD: f64 = 6.0;
-pub const AAVE_ORACLE_ADDRESS: Address = address!("0x54586bE62E3c3580375aE3723C145253060Ca0C2");
-const AAVE_V3_PROVIDER_ADDRESS: Address = address!("2f39d218133afab8f2b819b1066c7e434ad94e9e");
+// aToken and debt token transfers worth less than this don't move a HF enough to refresh users for
+const MIN_TRANSFER_VALUE_IN_USD: f64 = 100.0;
 const AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS: Address =
     address!("3f78bbd206e4d3c504eb854232eda7e47e9fd8fc");
-const AAVE_V3_POOL: Address = address!("87870Bca3F3fD6335C3F4ce8392D69350B4fA4E2");
-const BUCKETS: usize = 64;
+// Each bucket is fetched with a single multicall
+const BUCKETS: usize = USER_ACCOUNT_DATA_BATCH_SIZE;
+// Minimum time between cache snapshots written while processing whistleblower updates
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How often the Chainlink mapping file is checked for changes
+const CHAINLINK_MAPPING_POLL_INTERVAL: Duration = Duration::from_secs(10);
+// Users whose last known HF is below 1.1 go in the daily watchlist, like vega-cli exports them
+const DAILY_WATCHLIST_MAX_HF: U256 = U256::from_limbs([1_100_000_000_000_000_000, 0, 0, 0]);
+// Buckets read concurrently by the initial cache build between progress checkpoints
+const INIT_PROGRESS_BUCKETS: usize = 32;
 
 #[derive(Debug, Eq, PartialEq, Hash)]
 enum PositionType {
@@ -51,6 +90,28 @@ struct UserPosition {
     underlying_asset: ReserveAddress,
 }
 
+impl From<&UserScaledPosition> for UserPosition {
+    fn from(position: &UserScaledPosition) -> Self {
+        Self {
+            scaled_atoken_balance: position.scaled_atoken_balance,
+            usage_as_collateral_enabled_on_user: position.usage_as_collateral_enabled_on_user,
+            scaled_variable_debt: position.scaled_variable_debt,
+            underlying_asset: position.underlying_asset,
+        }
+    }
+}
+
+impl From<&UserPosition> for UserScaledPosition {
+    fn from(position: &UserPosition) -> Self {
+        Self {
+            underlying_asset: position.underlying_asset,
+            scaled_atoken_balance: position.scaled_atoken_balance,
+            usage_as_collateral_enabled_on_user: position.usage_as_collateral_enabled_on_user,
+            scaled_variable_debt: position.scaled_variable_debt,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct UserReservesCacheInitStats {
     input_user_addresses: usize,
@@ -61,58 +122,238 @@ struct UserReservesCacheInitStats {
     total_user_addresses_in_cache: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct AaveReserveInfo {
     pub symbol: String,
     pub reserve_address: ReserveAddress,
 }
 
-pub struct UserReservesCache {
+/// Updates only need a Provider, so they can be tested against a mocked node. Initializing the
+/// cache still needs the pubsub RootProvider that vega connects with, see initialize_cache().
+pub struct UserReservesCache<P = RootProvider<PubSubFrontend>, T = PubSubFrontend> {
+    /// Connection every read of the cache goes through, shared with whoever created the cache
+    provider: Arc<P>,
+    _transport: PhantomData<T>,
+
+    /// Aave instance whose users are cached. Each market gets a cache of its own.
+    market: AaveMarket,
+
     user_reserves_cache: RwLock<HashMap<ReserveAddress, HashMap<PositionType, Vec<UserAddress>>>>,
 
     /// Given a Chainlink contract adddress that a price update forwarded an update to,
     /// it returns a vector of all ReserveAddresses from AAVE whose prices were affected by the update.
     /// (either directly, or indirectly as is the case of assets with a price computed based on other assets)
-    chainlink_address_to_asset: HashMap<ChainlinkContractAddress, Vec<AaveReserveInfo>>,
-}
+    /// Swapped as a whole when the mapping is reloaded, see spawn_chainlink_mapping_reload().
+    chainlink_address_to_asset: Arc<RwLock<ChainlinkMapping>>,
 
-impl Default for UserReservesCache {
-    fn default() -> Self {
-        Self::new()
-    }
+    /// Scaled balances of every user in the cache, kept in sync with it, so HFs can be computed
+    /// locally. Reserves in the engine are refreshed by whoever owns the cache.
+    hf_engine: Arc<RwLock<OffchainHfEngine>>,
+
+    /// Currency the AaveOracle quotes prices in, read when the cache is initialized
+    base_currency: BaseCurrency,
+
+    /// Users of every asset ranked by their last known HF, which decides the order candidates
+    /// are evaluated in
+    hf_index: HfIndex,
+
+    /// Where the cache is snapshotted to, if anywhere, and when it was last written
+    snapshot_file: Option<String>,
+    snapshot_saved_at: Instant,
+
+    /// Where the daily snapshot goes, if anywhere, the UTC hour it's taken at, and the day it
+    /// was last taken. A failed one is tried again every SNAPSHOT_INTERVAL until it's taken.
+    artifacts: Option<ArtifactStore>,
+    daily_snapshot_hour: u32,
+    daily_snapshot_day: Option<NaiveDate>,
+    daily_snapshot_failed_at: Option<Instant>,
+
+    /// Every change to a cached user since the cache was initialized, see undo_to_block()
+    mutation_log: MutationLog,
+    /// Block of the last whistleblower updates applied
+    last_update_block: u64,
+    /// Users refreshed by the updates of a block, so the transfers of that block (which come with
+    /// the Pool event that moved the tokens, or on their own) don't refresh them again
+    refreshed_in_block: (u64, HashSet<UserAddress>),
 }
 
-impl UserReservesCache {
-    pub fn new() -> Self {
+impl<T, P> UserReservesCache<P, T>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    pub fn new(provider: Arc<P>, market: AaveMarket) -> Self {
         UserReservesCache {
+            provider,
+            _transport: PhantomData,
+            market,
             user_reserves_cache: RwLock::new(HashMap::new()),
-            chainlink_address_to_asset: HashMap::new(),
+            chainlink_address_to_asset: Arc::new(RwLock::new(HashMap::new())),
+            hf_engine: Arc::new(RwLock::new(OffchainHfEngine::new())),
+            base_currency: BaseCurrency::USD,
+            hf_index: HfIndex::default(),
+            snapshot_file: None,
+            snapshot_saved_at: Instant::now(),
+            artifacts: None,
+            daily_snapshot_hour: 0,
+            daily_snapshot_day: None,
+            daily_snapshot_failed_at: None,
+            mutation_log: MutationLog::new(None),
+            last_update_block: 0,
+            refreshed_in_block: (0, HashSet::new()),
         }
     }
 
+    /// Appends every mutation of the cache to `path` too, as json lines
+    pub fn set_mutation_log_file(&mut self, path: &str) {
+        self.mutation_log = MutationLog::new(Some(path));
+    }
+
+    /// Takes a daily snapshot of the cache and the watchlist into `artifacts` once `hour` (UTC)
+    /// is reached, see save_daily_snapshot_if_due(). initialize_cache() warm starts from it
+    /// when there's no usable snapshot file.
+    pub fn set_daily_snapshots(&mut self, artifacts: ArtifactStore, hour: u32) {
+        self.artifacts = Some(artifacts);
+        self.daily_snapshot_hour = hour;
+    }
+
+    pub fn market(&self) -> &AaveMarket {
+        &self.market
+    }
+
+    pub fn base_currency(&self) -> BaseCurrency {
+        self.base_currency
+    }
+
+    /// Feeds new results to the HF index, so the next bundles evaluate the riskiest users first
+    pub fn record_health_factors(&mut self, health_factors: &HashMap<UserAddress, U256>) {
+        self.hf_index.record_health_factors(health_factors);
+    }
+
+    /// Feeds the initial sweep's results to the HF index, without overwriting the HFs live
+    /// updates recorded while it ran
+    pub fn seed_health_factors(&mut self, health_factors: &HashMap<UserAddress, U256>) {
+        self.hf_index.seed_health_factors(health_factors);
+    }
+
+    pub fn hf_engine(&self) -> Arc<RwLock<OffchainHfEngine>> {
+        self.hf_engine.clone()
+    }
+
+    pub fn chainlink_mapping(&self) -> Arc<RwLock<ChainlinkMapping>> {
+        self.chainlink_address_to_asset.clone()
+    }
+
     /// The user cache is a mapping from assets to (eventually) users that are either borrowing or
-    /// supplying those assets. On each whistleblower-rs update, this method is called and it determines
-    /// whether the user cache must be updated depending on it's event type. Liquidations, borrows,
-    /// supplyings, and repayments are the events that can affect whether a user is borrowing or supplying
-    /// a given asset.
+    /// supplying those assets. Whistleblower-rs updates are handed to this method, and it determines
+    /// whether the user cache must be updated depending on their event type. Liquidations, borrows,
+    /// supplyings, repayments and withdrawals are the events that can affect whether a user is borrowing
+    /// or supplying a given asset. Users enabling or disabling a reserve as collateral are refreshed
+    /// too, since that changes which of their supplies count towards their health factor, and so are
+    /// users entering or leaving an e-mode category (its thresholds replace the reserve ones) or
+    /// moving debt between rate modes.
+    ///
+    /// PoolConfigurator events don't affect any single user, so they refresh the reserves instead
+    /// (see _refresh_reserves()). Token transfers can affect two users, see _transfer_affected_users().
+    ///
+    /// Busy blocks bring dozens of events, often several for the same user, so `wb_updates` are
+    /// meant to be the updates of a block: reserves are refreshed at most once, and the affected
+    /// users are deduped and read together by _refresh_users().
     pub async fn update_cache(
         &mut self,
-        wb_update: &WhistleblowerUpdate,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let update_type = &wb_update.event_details.event;
+        wb_updates: &[WhistleblowerUpdate],
+    ) -> Result<(), CacheError> {
+        // Done first, users may have positions in a reserve that was just listed
+        if let Some(wb_update) = wb_updates.iter().find(|wb_update| {
+            matches!(
+                wb_update.event_details.event,
+                WhistleblowerEventType::ReserveInitialized
+                    | WhistleblowerEventType::CollateralConfigurationChanged
+                    | WhistleblowerEventType::ReserveFrozen
+                    | WhistleblowerEventType::ReservePaused
+            )
+        }) {
+            if let Err(e) = self._refresh_reserves(wb_update).await {
+                warn!("Failed to refresh reserves: {}", e);
+            }
+        }
 
+        if let Some(block_number) = wb_updates.iter().map(|u| u.block_number).max() {
+            self.last_update_block = block_number;
+            if self.refreshed_in_block.0 != block_number {
+                self.refreshed_in_block = (block_number, HashSet::new());
+            }
+        }
+        // Trace ids and events that affected each user, as the source of its mutation
+        let mut affected_users: HashMap<UserAddress, String> = HashMap::new();
+        for wb_update in wb_updates {
+            let users = match wb_update.event_details.event {
+                WhistleblowerEventType::Transfer => self._transfer_affected_users(wb_update).await,
+                _ => Self::_affected_user(wb_update).map(|user| user.into_iter().collect()),
+            };
+            match users {
+                Ok(users) => {
+                    let source = format!(
+                        "{} ({:?})",
+                        wb_update.trace_id, wb_update.event_details.event
+                    );
+                    for user in users {
+                        affected_users
+                            .entry(user)
+                            .and_modify(|sources| *sources = format!("{}, {}", sources, source))
+                            .or_insert_with(|| source.clone());
+                    }
+                }
+                // whistleblower-rs sent something its event can't have, which is a bug there
+                Err(e @ CacheError::MalformedUpdate(_)) => error!(
+                    "Skipping update {} ({:?}): {}",
+                    wb_update.trace_id, wb_update.event_details.event, e
+                ),
+                Err(e) => warn!(
+                    "Failed to get the users affected by {} ({:?}): {}",
+                    wb_update.trace_id, wb_update.event_details.event, e
+                ),
+            }
+        }
+        if affected_users.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "Refreshing {} users affected by {} updates",
+            affected_users.len(),
+            wb_updates.len()
+        );
+        self._refresh_users(&affected_users).await?;
+        self.refreshed_in_block.1.extend(affected_users.into_keys());
+        info!("Cache updated, all write locks released.");
+        Ok(())
+    }
+
+    /// The user a Pool event is about, None for events that don't affect a single user
+    fn _affected_user(wb_update: &WhistleblowerUpdate) -> Result<Option<UserAddress>, CacheError> {
+        let update_type = &wb_update.event_details.event;
         #[allow(unreachable_patterns)] // so rustc doesn't complain about the default case
         let affected_user_index = match update_type {
+            WhistleblowerEventType::UserEModeSet => 0,
             WhistleblowerEventType::Repay
             | WhistleblowerEventType::Borrow
-            | WhistleblowerEventType::Supply => 1,
+            | WhistleblowerEventType::Supply
+            | WhistleblowerEventType::Withdraw
+            | WhistleblowerEventType::ReserveUsedAsCollateralEnabled
+            | WhistleblowerEventType::ReserveUsedAsCollateralDisabled
+            | WhistleblowerEventType::SwapBorrowRateMode
+            | WhistleblowerEventType::RebalanceStableBorrowRate => 1,
             WhistleblowerEventType::LiquidationCall => 2,
+            WhistleblowerEventType::ReserveInitialized
+            | WhistleblowerEventType::CollateralConfigurationChanged
+            | WhistleblowerEventType::ReserveFrozen
+            | WhistleblowerEventType::ReservePaused => return Ok(None),
             _ => {
                 warn!(
                     "Update type {:?} shouldn't trigger a user cache update. Skipping.",
                     update_type
                 );
-                return Ok(());
+                return Ok(None);
             }
         };
 
@@ -123,138 +364,493 @@ impl UserReservesCache {
                     "Failed to get affected user arg at index {}. Args: {:?}",
                     affected_user_index, wb_update.event_details.args
                 );
-                return Err("Missing affected user argument".into());
+                return Err(CacheError::MalformedUpdate(format!(
+                    "missing affected user argument at index {}",
+                    affected_user_index
+                )));
             }
         };
 
-        let affected_user = match Address::from_str(affected_user_arg) {
-            Ok(address) => address,
+        match Address::from_str(affected_user_arg) {
+            Ok(address) => Ok(Some(address)),
             Err(e) => {
                 warn!("Failed to parse affected user address: {}", e);
-                return Err(e.into());
+                Err(CacheError::MalformedUpdate(format!(
+                    "invalid affected user address {}: {}",
+                    affected_user_arg, e
+                )))
             }
+        }
+    }
+
+    /// Users to refresh on either side of an aToken or variable debt token transfer.
+    /// Collateral can change hands without a Pool event of the user, so this is how the cache
+    /// hears about it. Transfers worth less than
+    /// MIN_TRANSFER_VALUE_IN_USD are skipped, and so are users the cache doesn't track, since
+    /// users with debt already are, and users already refreshed for the block of the transfer.
+    async fn _transfer_affected_users(
+        &self,
+        wb_update: &WhistleblowerUpdate,
+    ) -> Result<Vec<UserAddress>, CacheError> {
+        let args = &wb_update.event_details.args;
+        let malformed = || CacheError::MalformedUpdate(format!("Transfer args {:?}", args));
+        let [asset, from, to, amount] = args.as_slice() else {
+            return Err(malformed());
         };
+        let asset = Address::from_str(asset).map_err(|_| malformed())?;
+        let amount = U256::from_str(amount).map_err(|_| malformed())?;
+        let hf_engine = self.hf_engine.read().await;
+        let value = hf_engine
+            .get_reserve(&asset)
+            .map(|reserve| reserve.price.saturating_mul(amount) / reserve.asset_unit);
+        let min_value = self
+            .base_currency
+            .usd_to_base_units(MIN_TRANSFER_VALUE_IN_USD)
+            .ok();
+        // Without a price (or a minimum in base units) the transfer can't be told apart from dust,
+        // so it's applied anyway
+        if let (Some(value), Some(min_value)) = (value, min_value) {
+            if value < min_value {
+                return Ok(vec![]);
+            }
+        }
+        let mut users = vec![];
+        let from = Address::from_str(from).map_err(|_| malformed())?;
+        let to = Address::from_str(to).map_err(|_| malformed())?;
+        for user in [from, to] {
+            if user == Address::ZERO || !hf_engine.has_user(&user) {
+                continue;
+            }
+            if self.refreshed_in_block.0 == wb_update.block_number
+                && self.refreshed_in_block.1.contains(&user)
+            {
+                continue;
+            }
+            info!(
+                "Refreshing user {} after a transfer of {} {} (trace_id = {})",
+                user, amount, asset, wb_update.trace_id
+            );
+            users.push(user);
+        }
+        Ok(users)
+    }
 
-        self._drop_user_from_cache(&affected_user).await;
-        match self._add_user_to_cache(&affected_user).await {
-            Ok(_) => (),
-            Err(e) => {
-                warn!("Failed to add user to cache: {}", e);
-                return Err(e);
+    /// Reads every reserve again after a PoolConfigurator event. The HF engine gets the new
+    /// liquidation thresholds right away instead of on its next refresh, which only runs with the
+    /// offchain backend. Reserves the cache doesn't know of are added to it, since users with a
+    /// position in them couldn't be added otherwise.
+    ///
+    /// Prices of a newly listed reserve are followed once the AaveOracle emits its
+    /// AssetSourceUpdated event.
+    async fn _refresh_reserves(
+        &mut self,
+        wb_update: &WhistleblowerUpdate,
+    ) -> Result<(), CacheError> {
+        let reserves_data = get_reserves_data(self.provider.clone(), &self.market).await?;
+        let emode_categories = get_emode_categories(self.provider.clone(), &self.market).await?;
+        {
+            let mut cache = self.user_reserves_cache.write().await;
+            for reserve in &reserves_data {
+                if !cache.contains_key(&reserve.underlyingAsset) {
+                    info!(
+                        "Reserve {} ({}) is new, adding it to the cache",
+                        reserve.symbol, reserve.underlyingAsset
+                    );
+                    cache.insert(reserve.underlyingAsset, HashMap::new());
+                }
             }
-        };
-        info!("Cache updated, all write locks released.");
+        }
+        let mut hf_engine = self.hf_engine.write().await;
+        hf_engine.update_reserves(&self.market, &reserves_data);
+        hf_engine.update_emode_categories(emode_categories);
+        info!(
+            "Reserves of the {} market refreshed after {:?} of {} (trace_id = {})",
+            self.market.name,
+            wb_update.event_details.event,
+            wb_update
+                .event_details
+                .args
+                .first()
+                .map_or("unknown reserve", String::as_str),
+            wb_update.trace_id
+        );
         Ok(())
     }
 
-    /// Removes the user from the cache. This is done by iterating over all the assets in the cache and
-    /// removing the user from the list of users that are borrowing or supplying that asset.
-    async fn _drop_user_from_cache(&mut self, user: &UserAddress) {
-        info!("Dropping cache occurrences for user {}", user);
-        let mut cache = self.user_reserves_cache.write().await;
-        for (_asset, users_by_position) in cache.iter_mut() {
-            for (_position_type, users) in users_by_position.iter_mut() {
-                users.retain(|u| u != user);
-            }
+    /// Snapshots the cache if SNAPSHOT_INTERVAL has passed since the last snapshot. Called after
+    /// each whistleblower update, with the block of its event as the watermark, since that's the
+    /// last block the cache is known to be up to date with.
+    pub async fn save_snapshot_if_due(&mut self, block_number: u64) {
+        let Some(snapshot_file) = &self.snapshot_file else {
+            return;
+        };
+        if self.snapshot_saved_at.elapsed() < SNAPSHOT_INTERVAL {
+            return;
+        }
+        let positions_by_user = self.hf_engine.read().await.positions_by_user();
+        if positions_by_user.is_empty() {
+            // The HF engine failed to initialize, a snapshot of it would lose every user
+            return;
+        }
+        let snapshot = CacheSnapshot::new(block_number, positions_by_user);
+        match snapshot.save(Path::new(snapshot_file)) {
+            Ok(()) => info!(
+                "Cache snapshot of the {} market with {} users saved at block {}",
+                self.market.name,
+                snapshot.positions_by_user.len(),
+                block_number
+            ),
+            Err(e) => warn!("Failed to save cache snapshot: {}", e),
         }
+        self.snapshot_saved_at = Instant::now();
     }
 
-    /// This function
-    /// 0. calls getUserAccountData for the given user address. If the user has no debt, we don't care about them.
-    /// 1. calls getUserReservesData for the given user address
-    /// 2. parses the result into a list of UserPosition
-    /// 3. for each UserPosition, it updates the cache by adding the user to the list of users that are borrowing
-    ///     or supplying that asset.
-    async fn _add_user_to_cache(
-        &mut self,
-        user: &UserAddress,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let user_address = *user;
-        let ipc_path = "/tmp/reth.ipc";
-        let ipc = IpcConnect::new(ipc_path.to_string());
-        let provider = match ProviderBuilder::new().on_ipc(ipc).await {
-            Ok(p) => p,
+    /// Once a day, after the configured hour, stores a snapshot of the cache and the watchlist
+    /// as artifacts, with `block_number` as their watermark. Called along with
+    /// save_snapshot_if_due(). Failures are alerted, since without a recent snapshot the next
+    /// restart is a full cold scan.
+    pub async fn save_daily_snapshot_if_due(&mut self, block_number: u64) {
+        let Some(artifacts) = self.artifacts.clone() else {
+            return;
+        };
+        let now = Utc::now();
+        if now.hour() < self.daily_snapshot_hour
+            || self.daily_snapshot_day == Some(now.date_naive())
+            || self
+                .daily_snapshot_failed_at
+                .is_some_and(|failed_at| failed_at.elapsed() < SNAPSHOT_INTERVAL)
+        {
+            return;
+        }
+        let positions_by_user = self.hf_engine.read().await.positions_by_user();
+        if positions_by_user.is_empty() {
+            error!(
+                "ALERT | daily cache snapshot of the {} market skipped, the HF engine has no \
+                 users. The next restart will rebuild the cache from scratch.",
+                self.market.name
+            );
+            self.daily_snapshot_failed_at = Some(Instant::now());
+            return;
+        }
+        let snapshot = CacheSnapshot::new(block_number, positions_by_user);
+        let saved = snapshot.encode().and_then(|contents| {
+            artifacts.put(
+                ArtifactKind::CacheSnapshot,
+                &self.market,
+                &contents,
+                block_number,
+            )
+        });
+        let saved = saved.and_then(|cache_manifest| {
+            let entries = self._watchlist_entries(DAILY_WATCHLIST_MAX_HF);
+            let contents = serde_json::to_vec_pretty(&entries)
+                .map_err(|e| CacheError::malformed("daily watchlist", e))?;
+            artifacts
+                .put(
+                    ArtifactKind::Watchlist,
+                    &self.market,
+                    &contents,
+                    block_number,
+                )
+                .map(|watchlist_manifest| (cache_manifest, watchlist_manifest, entries.len()))
+        });
+        match saved {
+            Ok((cache_manifest, watchlist_manifest, watchlist_users)) => {
+                info!(
+                    "Daily snapshot of the {} market saved at block {}: {} users in {} ({}), {} \
+                     watchlist users in {} ({})",
+                    self.market.name,
+                    block_number,
+                    snapshot.positions_by_user.len(),
+                    cache_manifest.file,
+                    cache_manifest.content_hash,
+                    watchlist_users,
+                    watchlist_manifest.file,
+                    watchlist_manifest.content_hash
+                );
+                self.daily_snapshot_day = Some(now.date_naive());
+                self.daily_snapshot_failed_at = None;
+            }
             Err(e) => {
-                warn!("Failed to create provider: {}", e);
-                return Err(e.into());
+                error!(
+                    "ALERT | daily cache snapshot of the {} market failed, the next restart may \
+                     rebuild the cache from scratch: {}",
+                    self.market.name, e
+                );
+                self.daily_snapshot_failed_at = Some(Instant::now());
             }
-        };
-        let ui_data =
-            AaveUIPoolDataProvider::new(AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS, provider.clone());
-        let aave_pool = AaveV3Pool::new(AAVE_V3_POOL, provider.clone());
-        #[allow(unused_assignments)]
-        let mut user_positions: Vec<UserPosition> = vec![];
-        info!("Getting reserve data information for user {}", user_address);
-        let has_debt = match aave_pool.getUserAccountData(user_address).call().await {
-            Ok(data) => data.totalDebtBase > U256::ZERO,
+        }
+    }
+
+    /// Writes the users close to liquidation (see HfIndex::watchlist()) to the path in the
+    /// request, scoped to the market (see AaveMarket::scoped_path()). Returns the path and how many
+    /// users were written.
+    pub fn export_watchlist(
+        &self,
+        request: &WatchlistExportRequest,
+    ) -> Result<(String, usize), CacheError> {
+        let entries = self._watchlist_entries(request.max_health_factor);
+        let path = self.market.scoped_path(&request.path);
+        let tmp_path = Path::new(&path).with_extension("tmp");
+        let contents =
+            serde_json::to_string_pretty(&entries).map_err(|e| CacheError::malformed(&path, e))?;
+        std::fs::write(&tmp_path, contents).map_err(|e| FileError::new(&tmp_path, e))?;
+        std::fs::rename(&tmp_path, &path).map_err(|e| FileError::new(&path, e))?;
+        Ok((path, entries.len()))
+    }
+
+    /// Users whose last known HF is below `max_health_factor`, plus every prioritized user,
+    /// tagged with the market unless it's the core one
+    fn _watchlist_entries(&self, max_health_factor: U256) -> Vec<WatchlistEntry> {
+        let mut entries = self.hf_index.watchlist(max_health_factor);
+        if !self.market.is_core() {
+            for entry in entries.iter_mut() {
+                entry.market = Some(self.market.name.clone());
+            }
+        }
+        entries
+    }
+
+    /// Whether a watchlist entry is meant for this market. Entries without one are of the core
+    /// market, as are all of those written before there were others.
+    pub fn tracks(&self, entry: &WatchlistEntry) -> bool {
+        match &entry.market {
+            Some(name) => *name == self.market.name,
+            None => self.market.is_core(),
+        }
+    }
+
+    /// Sets the priority of every entry, adding users the cache doesn't know of yet. Users without
+    /// debt aren't added, but keep their priority in case they borrow later.
+    pub async fn import_watchlist(&mut self, entries: &[WatchlistEntry]) {
+        let mut uncached_users = HashMap::new();
+        for entry in entries {
+            self.hf_index.set_priority(entry.user, entry.priority);
+            if !self.hf_engine.read().await.has_user(&entry.user) {
+                uncached_users.insert(entry.user, "watchlist import".to_string());
+            }
+        }
+        let read_users = match self._refresh_users(&uncached_users).await {
+            Ok(added_users) => added_users,
             Err(e) => {
-                warn!("Couldn't get user account data for update: {:?}", e);
-                return Err(e.into());
+                warn!("Failed to add imported users to cache: {}", e);
+                0
             }
         };
-        if !has_debt {
-            info!("User {} has no debt, skipping cache update", user_address);
-            return Ok(());
+        info!(
+            "Imported watchlist of the {} market with {} users ({} weren't cached and were read from the pool)",
+            self.market.name,
+            entries.len(),
+            read_users
+        );
+    }
+
+    /// Reads `users` again and replaces whatever the cache had for them, dropping the ones left
+    /// without debt. Positions are read through multicalls on a single provider, and the cache and
+    /// HF engine are write locked once for all of them:
+    /// 0. getUserAccountData tells which users have debt. The ones without it are only dropped.
+    /// 1. getUserReservesData and getUserEMode read the positions and e-mode category of the rest
+    /// 2. every position adds the user to the list of users that are borrowing or supplying its
+    ///    asset
+    ///
+    /// Users whose reads fail are left as they were. `users` come with the source of their
+    /// refresh, which every change is recorded with in the mutation log. Returns how many users
+    /// were (re)added.
+    async fn _refresh_users(
+        &mut self,
+        users: &HashMap<UserAddress, String>,
+    ) -> Result<usize, CacheError> {
+        if users.is_empty() {
+            return Ok(0);
         }
-        let result = ui_data
-            .getUserReservesData(AAVE_V3_PROVIDER_ADDRESS, user_address)
-            .call()
-            .await;
-        match result {
-            Ok(data) => {
-                user_positions = data
-                    ._0
+        let provider = self.provider.clone();
+        let sources = users;
+        let users: Vec<UserAddress> = users.keys().copied().collect();
+        // None for the users that are dropped without being added back
+        let mut refreshed: HashMap<UserAddress, Option<CachedUser>> = HashMap::new();
+        let mut debtors = vec![];
+        for chunk in users.chunks(USER_ACCOUNT_DATA_BATCH_SIZE) {
+            let account_data =
+                get_user_account_data_for_users(&provider, &self.market, chunk, None).await;
+            for (user, data) in chunk.iter().zip(account_data) {
+                match data {
+                    Ok(data) if data.totalDebtBase > U256::ZERO => debtors.push(*user),
+                    Ok(_) => {
+                        info!("User {} has no debt, dropping it from the cache", user);
+                        refreshed.insert(*user, None);
+                    }
+                    Err(e) => warn!("Couldn't get user account data for update: {:?}", e),
+                }
+            }
+        }
+        for chunk in debtors.chunks(USER_RESERVES_DATA_BATCH_SIZE) {
+            let reserves_data =
+                get_user_reserves_data_for_users(&provider, &self.market, chunk).await;
+            let emodes = match get_user_emode_batch(&provider, &self.market, chunk).await {
+                Ok(emodes) => emodes,
+                Err(e) => {
+                    warn!(
+                        "Couldn't get the e-mode of {} users for update: {:?}",
+                        chunk.len(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            for ((user, data), emode) in chunk.iter().zip(reserves_data).zip(emodes) {
+                let (Some(data), Some(emode)) = (data, emode) else {
+                    warn!("Couldn't read the positions of user {} for update", user);
+                    continue;
+                };
+                let positions = data
                     .iter()
-                    .map(|d| UserPosition {
+                    .map(|d| UserScaledPosition {
+                        underlying_asset: d.underlyingAsset,
                         scaled_atoken_balance: d.scaledATokenBalance,
                         usage_as_collateral_enabled_on_user: d.usageAsCollateralEnabledOnUser,
                         scaled_variable_debt: d.scaledVariableDebt,
-                        underlying_asset: d.underlyingAsset,
                     })
                     .collect();
+                refreshed.insert(
+                    *user,
+                    Some(CachedUser {
+                        positions,
+                        emode_category: emode,
+                    }),
+                );
             }
-            Err(e) => {
-                warn!("Couldn't calculate address reserves: {:?}", e);
-                return Err(e.into());
+        }
+
+        let added_users = refreshed.values().filter(|user| user.is_some()).count();
+        for (user, before, after) in self._set_users(refreshed).await {
+            let source = sources.get(&user).map_or("", String::as_str);
+            if let Some(mutation) =
+                CacheMutation::new(self.last_update_block, user, source, before, after)
+            {
+                self.mutation_log.record(mutation);
+            }
+        }
+        Ok(added_users)
+    }
+
+    /// Undoes every change to the cache made by updates of the blocks after `block_number`, e.g.
+    /// after a reorg, and returns how many users were restored. Users go back to what they
+    /// looked like before the first of those changes, without reading anything from the node.
+    ///
+    /// The snapshot, if any, is rewritten with `block_number` as its watermark, so a restart reads
+    /// the users affected since then again instead of keeping the undone changes.
+    pub async fn undo_to_block(&mut self, block_number: u64) -> Result<usize, CacheError> {
+        let mutations = self.mutation_log.take_after(block_number)?;
+        // Newest first, so the oldest state of each user is the one left
+        let mut restored = HashMap::new();
+        for mutation in mutations {
+            restored.insert(mutation.user, mutation.before);
+        }
+        let restored_users = restored.len();
+        self._set_users(restored).await;
+        self.last_update_block = block_number;
+        self.refreshed_in_block = (block_number, HashSet::new());
+        info!(
+            "Undid the changes of {} users of the {} market made after block {}",
+            restored_users, self.market.name, block_number
+        );
+        if let Some(snapshot_file) = &self.snapshot_file {
+            let snapshot = CacheSnapshot::new(
+                block_number,
+                self.hf_engine.read().await.positions_by_user(),
+            );
+            if let Err(e) = snapshot.save(Path::new(snapshot_file)) {
+                warn!("Failed to save cache snapshot after undo: {}", e);
             }
+            self.snapshot_saved_at = Instant::now();
         }
+        Ok(restored_users)
+    }
+
+    /// Replaces whatever the cache and HF engine have for `users`, dropping the ones set to None.
+    /// Returns each user with what it looked like before and after.
+    async fn _set_users(
+        &mut self,
+        users: HashMap<UserAddress, Option<CachedUser>>,
+    ) -> Vec<(UserAddress, Option<CachedUser>, Option<CachedUser>)> {
+        let mut hf_engine = self.hf_engine.write().await;
         let mut cache = self.user_reserves_cache.write().await;
-        for position in user_positions {
-            let users_by_position = match cache.get_mut(&position.underlying_asset) {
-                Some(ubp) => ubp,
-                None => {
+        for (_asset, users_by_position) in cache.iter_mut() {
+            for (_position_type, cached_users) in users_by_position.iter_mut() {
+                cached_users.retain(|u| !users.contains_key(u));
+            }
+        }
+        let mut changes = Vec::with_capacity(users.len());
+        for (user, after) in users {
+            let before = hf_engine
+                .user_positions(&user)
+                .zip(hf_engine.user_emode_category(&user))
+                .map(|(positions, emode_category)| CachedUser {
+                    positions: positions.to_vec(),
+                    emode_category,
+                });
+            hf_engine.remove_user(&user);
+            self.hf_index.remove_user(&user);
+            let Some(cached_user) = &after else {
+                changes.push((user, before, after));
+                continue;
+            };
+            hf_engine.set_user(
+                user,
+                cached_user.positions.clone(),
+                cached_user.emode_category,
+            );
+            for position in &cached_user.positions {
+                let Some(users_by_position) = cache.get_mut(&position.underlying_asset) else {
                     warn!(
-                        "Underlying asset {} not found in user_reserves_cache",
-                        position.underlying_asset
+                        "Underlying asset {} of user {} not found in user_reserves_cache",
+                        position.underlying_asset, user
                     );
-                    return Err("Asset not found in cache".into());
+                    continue;
+                };
+                if position.scaled_variable_debt > U256::ZERO {
+                    users_by_position
+                        .entry(PositionType::Borrowed)
+                        .or_insert_with(Vec::new)
+                        .push(user);
+                    self.hf_index.add_user(position.underlying_asset, user);
+                }
+                if position.usage_as_collateral_enabled_on_user
+                    && position.scaled_atoken_balance > U256::ZERO
+                {
+                    users_by_position
+                        .entry(PositionType::Collateral)
+                        .or_insert_with(Vec::new)
+                        .push(user);
+                    self.hf_index.add_user(position.underlying_asset, user);
                 }
-            };
-            if position.scaled_variable_debt > U256::ZERO {
-                let users_vector = users_by_position
-                    .entry(PositionType::Borrowed)
-                    .or_insert_with(Vec::new);
-                users_vector.push(user_address);
-            }
-            if position.usage_as_collateral_enabled_on_user
-                && position.scaled_atoken_balance > U256::ZERO
-            {
-                let users_vector = users_by_position
-                    .entry(PositionType::Collateral)
-                    .or_insert_with(Vec::new);
-                users_vector.push(user_address);
             }
+            changes.push((user, before, after));
         }
-        Ok(())
+        changes
     }
+}
 
+impl UserReservesCache {
+    /// Loads the positions of every user worth tracking and builds the cache from them.
+    ///
+    /// With a `cache_snapshot_file`, positions are restored from the snapshot there and only users
+    /// touched by pool events since then are read again. Borrower discovery and the full position
+    /// read only happen when there's no usable snapshot. Either way the result is snapshotted
+    /// there, and again periodically by save_snapshot_if_due(). Without a usable snapshot file,
+    /// the newest daily snapshot (see set_daily_snapshots()) is restored instead, and so are the
+    /// priorities of its watchlist.
     pub async fn initialize_cache(
         &mut self,
-        addresses_file: &str,
-        chainlink_addresses_file: &str,
+        borrowers_checkpoint_file: &str,
+        chainlink_mapping_source: &ChainlinkMappingSource,
         output_data_dir: &str,
-    ) -> Result<Vec<Vec<UserAddress>>, Box<dyn Error>> {
-        info!("Initializing UserReservesCache");
+        cache_snapshot_file: Option<&str>,
+    ) -> Result<Vec<Vec<UserAddress>>, CacheError> {
+        info!(
+            "Initializing UserReservesCache of the {} market",
+            self.market.name
+        );
         // Step 0: Initialize stats
         let mut stats = UserReservesCacheInitStats {
             input_user_addresses: 0,
@@ -265,63 +861,152 @@ impl UserReservesCache {
             total_user_addresses_in_cache: 0,
         };
 
-        // Step 1: Load and prepare user and contract addresses
-        self.chainlink_address_to_asset = match load_chainlink_addresses(chainlink_addresses_file) {
-            Ok(addresses) => addresses,
+        // Step 1: Read the market base currency
+        let provider = self.provider.clone();
+        self.base_currency = match get_base_currency(provider.clone(), &self.market).await {
+            Ok(base_currency) => base_currency,
             Err(e) => {
-                error!("Failed to load chainlink addresses: {}", e);
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to load chainlink addresses",
-                )));
+                error!("Failed to get the market base currency: {}", e);
+                return Err(e.into());
             }
         };
-        let user_addresses: Vec<UserAddress> = match load_addresses_from_file(addresses_file) {
-            Ok(addresses) => addresses,
-            Err(e) => {
-                error!("Failed to load addresses from file: {}", e);
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to load addresses from file",
-                )));
+        info!(
+            "Market base currency is {} ({} decimals)",
+            self.base_currency.address, self.base_currency.decimals
+        );
+
+        // Step 2: Load contract addresses
+        *self.chainlink_address_to_asset.write().await =
+            match chainlink_mapping_source.load(&provider, &self.market).await {
+                Ok(addresses) => addresses,
+                Err(e) => {
+                    error!("Failed to load chainlink addresses: {}", e);
+                    return Err(e);
+                }
+            };
+
+        // Positions are read at the latest block, so they're at least as recent as this one
+        let head_block = provider
+            .get_block_number()
+            .await
+            .map_err(|e| NodeError::rpc("eth_blockNumber", e))?;
+
+        // Step 3: Restore user positions from the last snapshot, if there's a usable one
+        self.snapshot_file = cache_snapshot_file.map(String::from);
+        let progress_file = cache_snapshot_file.map(|snapshot_file| {
+            Path::new(snapshot_file)
+                .with_extension("progress")
+                .to_string_lossy()
+                .to_string()
+        });
+        let restored_positions = match self._load_snapshot(cache_snapshot_file) {
+            Some((snapshot, source)) => {
+                self._restore_positions(&provider, snapshot, &source, head_block)
+                    .await
             }
+            None => None,
         };
-        stats.input_user_addresses = user_addresses.len();
-        let user_addresses: Vec<UserAddress> = user_addresses
-            .into_iter()
-            .collect::<HashSet<_>>()
-            .into_iter()
-            .collect();
-        let user_addresses_buckets: Vec<Vec<UserAddress>> = user_addresses
+
+        let positions_by_user: HashMap<UserAddress, Vec<UserPosition>> = match restored_positions {
+            Some(positions) => {
+                stats.input_user_addresses = positions.len();
+                positions
+            }
+            None => {
+                // Step 4: Without one, discover every user that ever borrowed from the pool
+                let user_addresses: Vec<UserAddress> =
+                    match discover_borrowers(&provider, &self.market, borrowers_checkpoint_file)
+                        .await
+                    {
+                        Ok(addresses) => addresses,
+                        Err(e) => {
+                            error!("Failed to discover borrowers: {}", e);
+                            return Err(e);
+                        }
+                    };
+                stats.input_user_addresses = user_addresses.len();
+
+                // Step 5: Get information about user positions
+                info!("Getting user positions");
+                match self
+                    ._read_positions(
+                        &provider,
+                        user_addresses,
+                        head_block,
+                        progress_file.as_deref(),
+                    )
+                    .await
+                {
+                    Ok(positions) => positions,
+                    Err(e) => {
+                        error!("Failed to get positions by user: {}", e);
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        // Only users with positions are worth an initial HF
+        let user_addresses_buckets: Vec<Vec<UserAddress>> = positions_by_user
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>()
             .chunks(BUCKETS)
             .map(|chunk| chunk.to_vec())
             .collect();
 
-        // Step 2: Setup the provider
-        let ipc_path = "/tmp/reth.ipc";
-        let ipc = IpcConnect::new(ipc_path.to_string());
-        let provider = ProviderBuilder::new().on_ipc(ipc).await?;
-
-        // Step 3: Get information about user positions
-        info!("Getting user positions");
-        let positions_by_user: HashMap<UserAddress, Vec<UserPosition>> =
-            match get_positions_by_user(&user_addresses_buckets, &provider).await {
-                Ok(positions) => positions,
-                Err(e) => {
-                    error!("Failed to get positions by user: {}", e);
-                    return Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Failed to get positions by user",
-                    )));
+        // Step 6: Snapshot the positions, so the next start can skip most of the above
+        if let Some(snapshot_file) = cache_snapshot_file {
+            let snapshot = CacheSnapshot::new(
+                head_block,
+                positions_by_user
+                    .iter()
+                    .map(|(user, positions)| {
+                        (
+                            *user,
+                            positions.iter().map(UserScaledPosition::from).collect(),
+                        )
+                    })
+                    .collect(),
+            );
+            match snapshot.save(Path::new(snapshot_file)) {
+                Ok(()) => {
+                    info!("Cache snapshot saved at block {}", head_block);
+                    // The snapshot has everything the progress had
+                    if let Some(progress_file) = progress_file.as_deref() {
+                        let _ = std::fs::remove_file(progress_file);
+                    }
                 }
-            };
+                Err(e) => warn!("Failed to save cache snapshot: {}", e),
+            }
+            self.snapshot_saved_at = Instant::now();
+        }
 
-        // Step 4: Re-arrange the information into users by position by asset
+        // Step 7: Feed the same positions to the off-chain HF engine
+        if let Err(e) = self
+            ._initialize_hf_engine(&provider, &positions_by_user)
+            .await
+        {
+            warn!("Failed to initialize off-chain HF engine: {}", e);
+        }
+
+        // Step 8: Re-arrange the information into users by position by asset
         let user_by_position_by_asset: HashMap<
             ReserveAddress,
             HashMap<PositionType, Vec<UserAddress>>,
         > = generate_user_by_position_by_asset(positions_by_user);
+        self.hf_index = HfIndex::new(
+            user_by_position_by_asset
+                .iter()
+                .map(|(asset, users_by_position)| {
+                    (
+                        *asset,
+                        users_by_position.values().flatten().cloned().collect(),
+                    )
+                })
+                .collect(),
+        );
         self.user_reserves_cache = RwLock::new(user_by_position_by_asset);
+        self._restore_watchlist_priorities();
 
         self._collect_and_dump_cache_init_stats(&mut stats, output_data_dir)
             .await?;
@@ -329,27 +1014,346 @@ impl UserReservesCache {
         Ok(user_addresses_buckets)
     }
 
+    /// Reads the positions of every user, a group of INIT_PROGRESS_BUCKETS buckets at a time.
+    ///
+    /// With a `progress_file`, the positions read so far are checkpointed there after every group,
+    /// and users found in it are skipped. Users affected by pool events since it was written are
+    /// read again, the same way a snapshot is restored.
+    async fn _read_positions(
+        &self,
+        provider: &RootProvider<PubSubFrontend>,
+        user_addresses: Vec<UserAddress>,
+        head_block: u64,
+        progress_file: Option<&str>,
+    ) -> Result<HashMap<UserAddress, Vec<UserPosition>>, CacheError> {
+        let resumed_progress = match progress_file {
+            Some(progress_file) => {
+                self._resume_init_progress(provider, Path::new(progress_file), head_block)
+                    .await
+            }
+            None => None,
+        };
+        let mut progress = resumed_progress.unwrap_or_else(|| InitProgress::new(head_block));
+        let pending_users = user_addresses
+            .into_iter()
+            .filter(|user| !progress.processed_users.contains(user))
+            .collect::<Vec<UserAddress>>();
+        info!(
+            "Reading positions of {} users ({} were already read)",
+            pending_users.len(),
+            progress.processed_users.len()
+        );
+        let buckets: Vec<Vec<UserAddress>> = pending_users
+            .chunks(BUCKETS)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        let reading = Instant::now();
+        let mut read_users = 0;
+        for group in buckets.chunks(INIT_PROGRESS_BUCKETS) {
+            let positions =
+                get_positions_by_user(group, provider, &self.market, self.base_currency).await?;
+            progress
+                .positions_by_user
+                .extend(positions.iter().map(|(user, positions)| {
+                    (
+                        *user,
+                        positions.iter().map(UserScaledPosition::from).collect(),
+                    )
+                }));
+            for bucket in group {
+                progress.processed_users.extend(bucket.iter().cloned());
+                read_users += bucket.len();
+            }
+            if let Some(progress_file) = progress_file {
+                if let Err(e) = progress.save(Path::new(progress_file)) {
+                    warn!("Failed to save cache init progress: {}", e);
+                }
+            }
+            let users_per_second = read_users as f64 / reading.elapsed().as_secs_f64().max(1e-3);
+            let eta = Duration::from_secs_f64(
+                (pending_users.len() - read_users) as f64 / users_per_second,
+            );
+            info!(
+                "Read positions of {}/{} users ({:.0} users/s, ETA {:?})",
+                read_users,
+                pending_users.len(),
+                users_per_second,
+                eta
+            );
+        }
+        Ok(progress
+            .positions_by_user
+            .iter()
+            .map(|(user, positions)| (*user, positions.iter().map(UserPosition::from).collect()))
+            .collect())
+    }
+
+    /// Progress of a previous initial build, with the users affected by pool events since then
+    /// left out so they're read again. None if there's no usable progress at `progress_file`.
+    async fn _resume_init_progress(
+        &self,
+        provider: &RootProvider<PubSubFrontend>,
+        progress_file: &Path,
+        head_block: u64,
+    ) -> Option<InitProgress> {
+        let mut progress = match InitProgress::load(progress_file) {
+            Ok(Some(progress)) => progress,
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("Failed to load cache init progress, starting over: {}", e);
+                return None;
+            }
+        };
+        if progress.block_number > head_block {
+            warn!(
+                "Cache init progress is at block {}, ahead of the node head {}. Starting over.",
+                progress.block_number, head_block
+            );
+            return None;
+        }
+        match get_users_affected_since(provider, &self.market, progress.block_number, head_block)
+            .await
+        {
+            Ok(affected_users) => progress.invalidate_users(&affected_users, head_block),
+            Err(e) => {
+                warn!(
+                    "Failed to replay events since the cache init progress, starting over: {}",
+                    e
+                );
+                return None;
+            }
+        }
+        Some(progress)
+    }
+
+    /// The snapshot to warm start from, along with where it was read from: the one at
+    /// `snapshot_file` if it's usable, or else the newest daily one whose hash checks out
+    fn _load_snapshot(&self, snapshot_file: Option<&str>) -> Option<(CacheSnapshot, String)> {
+        if let Some(snapshot_file) = snapshot_file {
+            match CacheSnapshot::load(Path::new(snapshot_file)) {
+                Ok(Some(snapshot)) => return Some((snapshot, snapshot_file.to_string())),
+                Ok(None) => {}
+                Err(e) => warn!("Failed to load cache snapshot: {}", e),
+            }
+        }
+        let artifacts = self.artifacts.as_ref()?;
+        let (manifest, contents) = match artifacts.latest(ArtifactKind::CacheSnapshot, &self.market)
+        {
+            Ok(Some(latest)) => latest,
+            Ok(None) => {
+                info!("No daily cache snapshot of the {} market", self.market.name);
+                return None;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to load daily cache snapshot, rebuilding the cache: {}",
+                    e
+                );
+                return None;
+            }
+        };
+        let source = artifacts.dir().join(&manifest.file);
+        match CacheSnapshot::decode(&contents, &source) {
+            Ok(Some(snapshot)) => {
+                info!(
+                    "Warm starting from the daily snapshot {} taken at {}",
+                    manifest.file, manifest.created_at
+                );
+                Some((snapshot, source.display().to_string()))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                warn!(
+                    "Failed to decode daily cache snapshot, rebuilding the cache: {}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Priorities of the newest daily watchlist, which would otherwise be lost on every restart
+    fn _restore_watchlist_priorities(&mut self) {
+        let Some(artifacts) = &self.artifacts else {
+            return;
+        };
+        let entries: Vec<WatchlistEntry> =
+            match artifacts.latest(ArtifactKind::Watchlist, &self.market) {
+                Ok(Some((manifest, contents))) => match serde_json::from_slice(&contents) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        warn!("Malformed daily watchlist {}: {}", manifest.file, e);
+                        return;
+                    }
+                },
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Failed to load daily watchlist: {}", e);
+                    return;
+                }
+            };
+        let prioritized: Vec<WatchlistEntry> = entries
+            .into_iter()
+            .filter(|entry| self.tracks(entry) && entry.priority != WatchlistPriority::default())
+            .collect();
+        for entry in prioritized.iter() {
+            self.hf_index.set_priority(entry.user, entry.priority);
+        }
+        info!(
+            "Restored the priority of {} users from the daily watchlist",
+            prioritized.len()
+        );
+    }
+
+    /// Positions from `snapshot`, read from `source`, with the users affected by pool events since
+    /// it was taken read again. Returns None if it can't be used, in which case the cache has to
+    /// be built from scratch.
+    async fn _restore_positions(
+        &self,
+        provider: &RootProvider<PubSubFrontend>,
+        snapshot: CacheSnapshot,
+        source: &str,
+        head_block: u64,
+    ) -> Option<HashMap<UserAddress, Vec<UserPosition>>> {
+        if snapshot.block_number > head_block {
+            warn!(
+                "Cache snapshot is at block {}, ahead of the node head {}. Rebuilding the cache.",
+                snapshot.block_number, head_block
+            );
+            return None;
+        }
+        let affected_users = match get_users_affected_since(
+            provider,
+            &self.market,
+            snapshot.block_number,
+            head_block,
+        )
+        .await
+        {
+            Ok(users) => users,
+            Err(e) => {
+                warn!(
+                    "Failed to replay events since the snapshot, rebuilding the cache: {}",
+                    e
+                );
+                return None;
+            }
+        };
+        let mut positions_by_user: HashMap<UserAddress, Vec<UserPosition>> = snapshot
+            .positions_by_user
+            .iter()
+            .filter(|(user, _)| !affected_users.contains(*user))
+            .map(|(user, positions)| (*user, positions.iter().map(UserPosition::from).collect()))
+            .collect();
+        let affected_buckets: Vec<Vec<UserAddress>> = affected_users
+            .into_iter()
+            .collect::<Vec<_>>()
+            .chunks(BUCKETS)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        match get_positions_by_user(
+            &affected_buckets,
+            provider,
+            &self.market,
+            self.base_currency,
+        )
+        .await
+        {
+            Ok(refreshed) => positions_by_user.extend(refreshed),
+            Err(e) => {
+                warn!(
+                    "Failed to refresh affected users, rebuilding the cache: {}",
+                    e
+                );
+                return None;
+            }
+        }
+        info!(
+            "Restored {} users from the snapshot {} at block {}",
+            positions_by_user.len(),
+            source,
+            snapshot.block_number
+        );
+        Some(positions_by_user)
+    }
+
+    /// Loads reserves, e-mode categories and the positions of every user into the HF engine
+    async fn _initialize_hf_engine(
+        &mut self,
+        provider: &RootProvider<PubSubFrontend>,
+        positions_by_user: &HashMap<UserAddress, Vec<UserPosition>>,
+    ) -> Result<(), CacheError> {
+        let reserves_data = get_reserves_data(Arc::new(provider.clone()), &self.market).await?;
+        let emode_categories =
+            get_emode_categories(Arc::new(provider.clone()), &self.market).await?;
+        let users = positions_by_user.keys().cloned().collect::<Vec<_>>();
+        let mut emode_by_user = HashMap::new();
+        for chunk in users.chunks(USER_ACCOUNT_DATA_BATCH_SIZE) {
+            let emodes = match get_user_emode_batch(provider, &self.market, chunk).await {
+                Ok(emodes) => emodes,
+                Err(e) => {
+                    return Err(CacheError::Shared(
+                        format!("Failed to get user e-modes: {}", e).into(),
+                    ))
+                }
+            };
+            for (user, emode) in chunk.iter().zip(emodes) {
+                match emode {
+                    Some(emode) => {
+                        emode_by_user.insert(*user, emode);
+                    }
+                    None => warn!(
+                        "Couldn't get e-mode of user {}, not adding to HF engine",
+                        user
+                    ),
+                }
+            }
+        }
+        let mut hf_engine = self.hf_engine.write().await;
+        hf_engine.update_reserves(&self.market, &reserves_data);
+        hf_engine.update_emode_categories(emode_categories);
+        for (user, positions) in positions_by_user {
+            if let Some(emode) = emode_by_user.get(user) {
+                hf_engine.set_user(
+                    *user,
+                    positions.iter().map(UserScaledPosition::from).collect(),
+                    *emode,
+                );
+            }
+        }
+        info!(
+            "Off-chain HF engine of the {} market initialized with {} users",
+            self.market.name,
+            hf_engine.user_count()
+        );
+        Ok(())
+    }
+
     async fn _collect_and_dump_cache_init_stats(
         &mut self,
         stats: &mut UserReservesCacheInitStats,
         output_data_dir: &str,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), CacheError> {
         let mut most_borrowed: (String, usize) = (String::new(), 0);
         let mut most_supplied: (String, usize) = (String::new(), 0);
         let timestamp = Local::now().format("%Y%m%d").to_string();
         if !std::path::Path::new(output_data_dir).is_dir() {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                format!("Output data directory '{}' does not exist", output_data_dir),
-            )));
+            return Err(FileError::new(
+                output_data_dir,
+                std::io::Error::new(std::io::ErrorKind::NotFound, "directory does not exist"),
+            )
+            .into());
         }
-        let init_output_file_path =
-            format!("{}/user_reserves_cache_{}.json", output_data_dir, timestamp);
+        let init_output_file_path = self.market.scoped_path(&format!(
+            "{}/user_reserves_cache_{}.json",
+            output_data_dir, timestamp
+        ));
         let mut init_output_file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
-            .open(&init_output_file_path)?;
+            .open(&init_output_file_path)
+            .map_err(|e| FileError::new(&init_output_file_path, e))?;
         let mut json_data = vec![];
         let cache = self.user_reserves_cache.read().await;
         for (asset, users_by_position) in cache.iter() {
@@ -387,14 +1391,24 @@ impl UserReservesCache {
             "timestamp": timestamp,
             "data": json_data,
         });
-        init_output_file.write_all(serde_json::to_string_pretty(&json_output)?.as_bytes())?;
+        let contents = serde_json::to_string_pretty(&json_output)
+            .map_err(|e| CacheError::malformed(&init_output_file_path, e))?;
+        init_output_file
+            .write_all(contents.as_bytes())
+            .map_err(|e| FileError::new(&init_output_file_path, e))?;
         stats.most_borrowed_reserve = most_borrowed.0;
         stats.most_borrowed_reserve_count = most_borrowed.1;
         stats.most_supplied_reserve = most_supplied.0;
         stats.most_supplied_reserve_count = most_supplied.1;
         Ok(())
     }
+}
 
+impl<T, P> UserReservesCache<P, T>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
     /// Returns the user addresses affected by this price update bundle
     pub async fn get_candidates_for_bundle(
         &mut self,
@@ -409,7 +1423,9 @@ impl UserReservesCache {
         let mut duplicate_candidates: Vec<UserAddress> = vec![];
         let forwarded_to_address = &bundle.unwrap().forward_to;
 
-        let affected_reserves = self._calculate_affected_reserves(forwarded_to_address);
+        let affected_reserves = self
+            ._calculate_affected_reserves(forwarded_to_address)
+            .await;
         if affected_reserves.is_empty() {
             warn!(
                 "No affected reserves found for forwarded_to address {} (trace_id = {})",
@@ -467,8 +1483,37 @@ impl UserReservesCache {
             .collect::<Vec<_>>()
             .join(", ");
 
-        let unique_candidates: HashSet<UserAddress> =
-            duplicate_candidates.clone().into_iter().collect();
+        // High priority users first, then closest to HF = 1. Each ranked list is already sorted,
+        // so the stable sort only has to merge them.
+        let mut unique_candidates: Vec<UserAddress> = vec![];
+        let mut seen_candidates: HashSet<UserAddress> = HashSet::new();
+        for affected_reserve in affected_reserves.iter() {
+            for user in self
+                .hf_index
+                .ranked_users(&affected_reserve.reserve_address)
+            {
+                if seen_candidates.insert(*user) {
+                    unique_candidates.push(*user);
+                }
+            }
+        }
+        unique_candidates.sort_by_cached_key(|user| self.hf_index.rank(user));
+        {
+            let hf_engine = self.hf_engine.read().await;
+            let candidates_before = unique_candidates.len();
+            unique_candidates.retain(|user| {
+                hf_engine
+                    .user_positions(user)
+                    .is_none_or(|positions| has_liquidatable_pair(&hf_engine, positions))
+            });
+            if unique_candidates.len() < candidates_before {
+                info!(
+                    "Dropped {} candidates that can only be liquidated through ignored assets or collateral the pool won't liquidate (trace_id = {})",
+                    candidates_before - unique_candidates.len(),
+                    bundle.unwrap().trace_id
+                );
+            }
+        }
         let candidate_buckets: Vec<Vec<UserAddress>> =
             bucketize_optimally(unique_candidates.clone());
         let bundle_processing_elapsed = bundle_processing.elapsed().as_millis();
@@ -491,13 +1536,16 @@ impl UserReservesCache {
     /// So then, we first collect all the reserve addresses that are associated with the forwarded_to address.
     /// It will be a vector of at least 1 address, but it could be more if the same Chainlink address is associated
     /// with multiple Aave reserves.
-    fn _calculate_affected_reserves(
+    async fn _calculate_affected_reserves(
         &mut self,
         forwarded_to_address: &Address,
     ) -> Vec<AaveReserveInfo> {
         let mut affected_reserve_addresses: Vec<AaveReserveInfo> = vec![];
-        if let Some(affected_reserve_info) =
-            self.chainlink_address_to_asset.get(forwarded_to_address)
+        if let Some(affected_reserve_info) = self
+            .chainlink_address_to_asset
+            .read()
+            .await
+            .get(forwarded_to_address)
         {
             for reserve in affected_reserve_info {
                 affected_reserve_addresses.push((*reserve).clone());
@@ -505,32 +1553,231 @@ impl UserReservesCache {
         }
         affected_reserve_addresses
     }
+
+    /// Moves the asset of `update` to the aggregator behind its new price source. If the new
+    /// source couldn't be resolved, the asset is only removed, so it stops being matched against
+    /// a feed Aave no longer uses.
+    pub async fn apply_asset_source_update(&self, update: &AssetSourceUpdate) {
+        let mut mapping = self.chainlink_address_to_asset.write().await;
+        let mut new_mapping = mapping.clone();
+        let mut symbol = None;
+        for reserves in new_mapping.values_mut() {
+            if let Some(reserve) = reserves
+                .iter()
+                .find(|reserve| reserve.reserve_address == update.asset)
+            {
+                symbol = Some(reserve.symbol.clone());
+            }
+            reserves.retain(|reserve| reserve.reserve_address != update.asset);
+        }
+        new_mapping.retain(|_, reserves| !reserves.is_empty());
+        // GHO has no aggregator, its synthetic updates are forwarded to its price oracle
+        let aggregator = update
+            .aggregator
+            .or((update.source == GHO_PRICE_ORACLE).then_some(GHO_PRICE_ORACLE));
+        match aggregator {
+            Some(aggregator) => new_mapping
+                .entry(aggregator)
+                .or_default()
+                .push(AaveReserveInfo {
+                    symbol: symbol.unwrap_or_else(|| update.asset.to_string()),
+                    reserve_address: update.asset,
+                }),
+            None => warn!(
+                "New price source {} of {} has no aggregator, its price updates will be ignored",
+                update.source, update.asset
+            ),
+        }
+        log_chainlink_mapping_diff(&mapping, &new_mapping);
+        *mapping = new_mapping;
+    }
+}
+
+/// Where the Chainlink mapping comes from
+#[derive(Clone, Debug)]
+pub enum ChainlinkMappingSource {
+    /// A hand-maintained CSV file, with asset_symbol,aave_asset_address,chainlink_contract_address
+    /// lines
+    File(String),
+    /// The price sources of the AaveOracle, see generate_chainlink_mapping()
+    Onchain,
 }
 
-fn load_chainlink_addresses(
-    filepath: &str,
-) -> Result<HashMap<ChainlinkContractAddress, Vec<AaveReserveInfo>>, Box<dyn Error>> {
-    let mut chainlink_addresses: HashMap<ChainlinkContractAddress, Vec<AaveReserveInfo>> =
-        HashMap::new();
-    let file = File::open(filepath)?;
+impl ChainlinkMappingSource {
+    /// The source for `market`. Files are per market (see AaveMarket::scoped_path()), and markets
+    /// without a file of their own use the price sources of their oracle.
+    pub fn for_market(&self, market: &AaveMarket) -> Self {
+        match self {
+            ChainlinkMappingSource::File(filepath) => {
+                let filepath = market.scoped_path(filepath);
+                if market.is_core() || Path::new(&filepath).exists() {
+                    ChainlinkMappingSource::File(filepath)
+                } else {
+                    ChainlinkMappingSource::Onchain
+                }
+            }
+            ChainlinkMappingSource::Onchain => ChainlinkMappingSource::Onchain,
+        }
+    }
+
+    /// When the mapping file was last modified, None for generated mappings (or if it can't be
+    /// read)
+    fn modified(&self) -> Option<SystemTime> {
+        match self {
+            ChainlinkMappingSource::File(filepath) => {
+                std::fs::metadata(filepath).and_then(|m| m.modified()).ok()
+            }
+            ChainlinkMappingSource::Onchain => None,
+        }
+    }
+
+    pub async fn load(
+        &self,
+        provider: &RootProvider<PubSubFrontend>,
+        market: &AaveMarket,
+    ) -> Result<ChainlinkMapping, CacheError> {
+        match self {
+            ChainlinkMappingSource::File(filepath) => load_chainlink_addresses(filepath),
+            ChainlinkMappingSource::Onchain => generate_chainlink_mapping(provider, market).await,
+        }
+    }
+}
+
+/// Reloads the Chainlink mapping from `source` every time vega gets a SIGHUP, or the mapping file
+/// changes, so new feeds can be onboarded without a restart. The new mapping replaces the old one
+/// in a single write, so a bundle never sees half of each. If the new mapping can't be loaded
+/// (including files left empty or half written), the current one is kept.
+pub fn spawn_chainlink_mapping_reload(
+    mapping: Arc<RwLock<ChainlinkMapping>>,
+    source: ChainlinkMappingSource,
+    provider: RootProvider<PubSubFrontend>,
+    market: AaveMarket,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => Some(hangups),
+            Err(e) => {
+                error!(
+                    "Failed to listen for SIGHUP, Chainlink mapping reloads only follow the file: {}",
+                    e
+                );
+                None
+            }
+        };
+        let watch_file = matches!(source, ChainlinkMappingSource::File(_));
+        if hangups.is_none() && !watch_file {
+            return;
+        }
+        let mut last_modified = source.modified();
+        let mut polls = interval(CHAINLINK_MAPPING_POLL_INTERVAL);
+        loop {
+            let trigger = tokio::select! {
+                Some(_) = async { hangups.as_mut()?.recv().await } => "SIGHUP received",
+                _ = polls.tick(), if watch_file => {
+                    let modified = source.modified();
+                    if modified.is_none() || modified == last_modified {
+                        continue;
+                    }
+                    last_modified = modified;
+                    "Chainlink mapping file changed"
+                }
+                // SIGHUPs stopped coming, and there's no file to follow
+                else => return,
+            };
+            info!(
+                "{}, reloading Chainlink mapping of the {} market from {:?}",
+                trigger, market.name, source
+            );
+            let new_mapping = match source.load(&provider, &market).await {
+                Ok(new_mapping) => new_mapping,
+                Err(e) => {
+                    error!(
+                        "Failed to reload Chainlink mapping, keeping the current one: {}",
+                        e
+                    );
+                    continue;
+                }
+            };
+            let mut current_mapping = mapping.write().await;
+            log_chainlink_mapping_diff(&current_mapping, &new_mapping);
+            *current_mapping = new_mapping;
+        }
+    })
+}
+
+fn log_chainlink_mapping_diff(old: &ChainlinkMapping, new: &ChainlinkMapping) {
+    let symbols = |reserves: &[AaveReserveInfo]| {
+        reserves
+            .iter()
+            .map(|reserve| reserve.symbol.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut changes = 0;
+    for (chainlink_address, reserves) in new {
+        match old.get(chainlink_address) {
+            None => info!(
+                "Chainlink mapping: added {} -> [{}]",
+                chainlink_address,
+                symbols(reserves)
+            ),
+            Some(old_reserves) if old_reserves != reserves => info!(
+                "Chainlink mapping: changed {} from [{}] to [{}]",
+                chainlink_address,
+                symbols(old_reserves),
+                symbols(reserves)
+            ),
+            Some(_) => continue,
+        }
+        changes += 1;
+    }
+    for (chainlink_address, reserves) in old {
+        if !new.contains_key(chainlink_address) {
+            info!(
+                "Chainlink mapping: removed {} -> [{}]",
+                chainlink_address,
+                symbols(reserves)
+            );
+            changes += 1;
+        }
+    }
+    info!(
+        "Chainlink mapping now has {} addresses ({} changes)",
+        new.len(),
+        changes
+    );
+}
+
+fn load_chainlink_addresses(filepath: &str) -> Result<ChainlinkMapping, CacheError> {
+    let mut chainlink_addresses: ChainlinkMapping = HashMap::new();
+    let file = File::open(filepath).map_err(|e| FileError::new(filepath, e))?;
     let mut lines = io::BufReader::new(file).lines();
     lines.next(); // Skip the header line
     for line in lines {
-        let line = line?;
+        let line = line.map_err(|e| FileError::new(filepath, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
         let parts: Vec<&str> = line.split(",").collect();
+        if parts.len() < 3 {
+            return Err(CacheError::malformed(
+                filepath,
+                format!("expected 3 fields in line '{}'", line),
+            ));
+        }
         let symbol = parts[0].to_string();
         let reserve_address = match Address::from_str(parts[1]) {
             Ok(addr) => addr,
             Err(e) => {
                 error!("Failed to parse AAVE reserve address: {}", e);
-                return Err(Box::new(e));
+                return Err(CacheError::malformed(filepath, e));
             }
         };
         let chainlink_address = match Address::from_str(parts[2]) {
             Ok(addr) => addr,
             Err(e) => {
                 error!("Failed to parse chainlink address: {}", e);
-                return Err(Box::new(e));
+                return Err(CacheError::malformed(filepath, e));
             }
         };
         let reserve_info = AaveReserveInfo {
@@ -542,14 +1789,22 @@ fn load_chainlink_addresses(
             .or_default()
             .push(reserve_info);
     }
+    // Most likely a file caught while being rewritten, which would stop every price update from
+    // being matched
+    if chainlink_addresses.is_empty() {
+        return Err(CacheError::malformed(filepath, "no chainlink addresses"));
+    }
     info!("Loaded {} chainlink addresses.", chainlink_addresses.len());
     Ok(chainlink_addresses)
 }
 
-async fn get_asset_price(provider: Arc<RootProvider<PubSubFrontend>>, asset: Address) -> U256 {
-    let aave_oracle = AaveOracle::new(AAVE_ORACLE_ADDRESS, provider.clone());
-    match aave_oracle.getAssetPrice(asset).call().await {
-        Ok(price_response) => price_response._0,
+async fn get_asset_price(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
+    asset: Address,
+) -> U256 {
+    match get_asset_price_at(provider, market.oracle, asset, BlockId::latest()).await {
+        Ok(price) => price,
         Err(e) => {
             eprintln!("Error trying to call getAssetPrice: {}", e);
             U256::ZERO
@@ -557,23 +1812,50 @@ async fn get_asset_price(provider: Arc<RootProvider<PubSubFrontend>>, asset: Add
     }
 }
 
+/// profito only liquidates pairs of allowed assets, so a user without an allowed collateral or
+/// an allowed debt isn't worth evaluating. Neither is one whose collaterals the pool refuses to
+/// liquidate (see ReserveConfiguration::get_collateral_blocker()), like isolated assets being
+/// offboarded, whose suppliers otherwise look liquidatable with a HF of 0 forever.
+fn has_liquidatable_pair(hf_engine: &OffchainHfEngine, positions: &[UserScaledPosition]) -> bool {
+    let has_allowed_collateral = positions.iter().any(|position| {
+        position.usage_as_collateral_enabled_on_user
+            && position.scaled_atoken_balance > U256::ZERO
+            && ASSET_FILTER.is_allowed(&position.underlying_asset)
+            && hf_engine
+                .get_reserve(&position.underlying_asset)
+                .is_none_or(|reserve| reserve.configuration.get_collateral_blocker().is_none())
+    });
+    let has_allowed_debt = positions.iter().any(|position| {
+        position.scaled_variable_debt > U256::ZERO
+            && ASSET_FILTER.is_allowed(&position.underlying_asset)
+    });
+    has_allowed_collateral && has_allowed_debt
+}
+
 /// Remember collateral filtering is all about collateral, not debt
 /// By the point this function is called and given a list of users, we already
 /// know they have debt against the protocol, so only focus on collateral filtering
 /// conditions.
 pub async fn has_any_collateral_above_threshold(
     provider: RootProvider<PubSubFrontend>,
+    market: &AaveMarket,
     user_address: Address,
     user_positions: Vec<UserPosition>,
     min_collateral_in_usd: f64,
-) -> Result<bool, Box<dyn std::error::Error>> {
+    base_currency: &BaseCurrency,
+) -> Result<bool, CacheError> {
     let provider = Arc::new(provider.clone());
     // This should be something that we query only once, and make available for other services via shared memory, IPC or whatever
-    let reserves_data = get_reserves_data(provider.clone()).await?;
+    let reserves_data = get_reserves_data(provider.clone(), market).await?;
+    // The index of a reserve in the reserves list is what the e-mode collateral bitmap is keyed by
+    let reserves_list = get_reserves_list_at(provider.clone(), market, BlockId::latest())
+        .await
+        .map_err(|e| NodeError::call("getReservesList", e))?;
     let reserves_data = reserves_data
         .into_iter()
         .map(|d| (d.underlyingAsset, d))
         .collect::<HashMap<_, _>>();
+    let user_emode = get_user_emode_category(provider.clone(), market, user_address).await?;
 
     let collateral_positions = user_positions
         .into_iter()
@@ -594,14 +1876,26 @@ pub async fn has_any_collateral_above_threshold(
             Err(_) => U256::ZERO,
         };
 
-        // get the liquidation bonus for the underlying asset
-        let liquidation_bonus = reserves_data
-            .get(&position.underlying_asset)
-            .unwrap()
-            .reserveLiquidationBonus;
+        // get the liquidation bonus for the underlying asset, which is the e-mode category one
+        // if the user is in e-mode and the asset is enabled as collateral in that category
+        let reserve_index = reserves_list
+            .iter()
+            .position(|reserve| *reserve == position.underlying_asset)
+            .ok_or(CacheError::UnknownReserve(position.underlying_asset))?;
+        let liquidation_bonus = match &user_emode {
+            Some(emode) if emode.is_collateral_in_category(reserve_index) => {
+                emode.liquidation_bonus
+            }
+            _ => {
+                reserves_data
+                    .get(&position.underlying_asset)
+                    .unwrap()
+                    .reserveLiquidationBonus
+            }
+        };
 
         // get the price of the underlying asset
-        let price = get_asset_price(provider.clone(), position.underlying_asset).await;
+        let price = get_asset_price(provider.clone(), market, position.underlying_asset).await;
 
         // get the decimals of the underlying asset
         let decimals = reserves_data
@@ -610,7 +1904,7 @@ pub async fn has_any_collateral_above_threshold(
             .decimals;
         let raw = a_token_balance.as_limbs()[0] as f64; // Get the lowest limb which is u64, then convert to f64
         let token_units = raw / 10f64.powi(decimals.try_into().unwrap_or(0)); // normalize the token amount
-        let a_token_balance_in_usd = token_units * (price.to::<u128>() as f64 / 1e8); // multiply by price, normalize 8 decimals
+        let a_token_balance_in_usd = token_units * base_currency.to_usd(price); // multiply by price, normalize base currency decimals
 
         // In normal operation, AAVE applies the liquidation bonus on top of the max available collateral to liquidate
         // for this filter, we apply it on top of the whole user collateral, assuming that if it's not above the profit
@@ -624,88 +1918,106 @@ pub async fn has_any_collateral_above_threshold(
     Ok(false)
 }
 
-fn load_addresses_from_file(filepath: &str) -> Result<Vec<UserAddress>, Box<dyn Error>> {
-    let mut addresses: Vec<UserAddress> = Vec::new();
-    let file = File::open(filepath)?;
-    for line in io::BufReader::new(file).lines() {
-        let line = line?;
-        let address = match Address::from_str(str::trim(&line)) {
-            Ok(addr) => addr,
+/// Gets the reserves data of every user in a single multicall. If the multicall itself fails
+/// it falls back to one call per user.
+/// Results are aligned with `users`, with None for the users whose call failed.
+async fn get_user_reserves_data_for_users<T, P>(
+    provider: &P,
+    market: &AaveMarket,
+    users: &[UserAddress],
+) -> Vec<Option<Vec<UserReserveData>>>
+where
+    T: Transport + Clone,
+    P: Provider<T>,
+{
+    match get_user_reserves_data_batch(provider, market, users).await {
+        Ok(results) => return results,
+        Err(e) => warn!(
+            "Multicall for {} users failed, falling back to single calls: {:?}",
+            users.len(),
+            e
+        ),
+    }
+    let ui_data = AaveUIPoolDataProvider::new(AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS, provider);
+    let mut results = Vec::with_capacity(users.len());
+    for user in users {
+        // returns (UserReserveData[] memory, uint8)
+        match ui_data
+            .getUserReservesData(market.addresses_provider, *user)
+            .call()
+            .await
+        {
+            Ok(data) => results.push(Some(data._0)),
             Err(e) => {
-                warn!("Failed to parse address '{}': {}", line, e);
-                return Err(Box::new(e));
+                warn!("Couldn't calculate address reserves: {:?}", e);
+                results.push(None);
             }
-        };
-        addresses.push(address);
+        }
     }
-    info!("Loaded {} user addresses.", addresses.len());
-    Ok(addresses)
+    results
 }
 
 async fn get_positions_by_user(
     address_buckets: &[Vec<UserAddress>],
     provider: &RootProvider<PubSubFrontend>,
-) -> Result<HashMap<UserAddress, Vec<UserPosition>>, Box<dyn Error>> {
+    market: &AaveMarket,
+    base_currency: BaseCurrency,
+) -> Result<HashMap<UserAddress, Vec<UserPosition>>, CacheError> {
     let mut tasks = vec![];
-    let ui_data =
-        AaveUIPoolDataProvider::new(AAVE_V3_UI_POOL_DATA_PROVIDER_ADDRESS, provider.clone());
-    let aave_pool = AaveV3Pool::new(AAVE_V3_POOL, provider.clone());
     for bucket in address_buckets.iter().cloned() {
-        let ui_data = ui_data.clone();
-        let aave_pool = aave_pool.clone();
         let provider = provider.clone();
+        let market = market.clone();
         let task = task::spawn(async move {
             let mut results: HashMap<UserAddress, Vec<UserPosition>> = HashMap::new();
-            for address in bucket {
-                // First check if user has any debt
-                let has_debt = match aave_pool.getUserAccountData(address).call().await {
-                    Ok(data) => data.totalDebtBase > U256::ZERO,
-                    Err(e) => {
-                        warn!("Couldn't get user account data: {:?}", e);
-                        false
+            // First check which users have any debt
+            let mut debtors = vec![];
+            for chunk in bucket.chunks(USER_ACCOUNT_DATA_BATCH_SIZE) {
+                let account_data =
+                    get_user_account_data_for_users(&provider, &market, chunk, None).await;
+                for (address, data) in chunk.iter().zip(account_data) {
+                    match data {
+                        Ok(data) if data.totalDebtBase > U256::ZERO => debtors.push(*address),
+                        Ok(_) => {}
+                        Err(e) => warn!("Couldn't get user account data: {:?}", e),
                     }
-                };
-                if !has_debt {
-                    continue;
                 }
-                // returns (UserReserveData[] memory, uint8)
-                let result = ui_data
-                    .getUserReservesData(AAVE_V3_PROVIDER_ADDRESS, address)
-                    .call()
-                    .await;
-                match result {
-                    Ok(data) => {
-                        let user_positions: Vec<UserPosition> = data
-                            ._0
-                            .iter()
-                            .map(|d| UserPosition {
-                                scaled_atoken_balance: d.scaledATokenBalance,
-                                usage_as_collateral_enabled_on_user: d
-                                    .usageAsCollateralEnabledOnUser,
-                                scaled_variable_debt: d.scaledVariableDebt,
-                                underlying_asset: d.underlyingAsset,
-                            })
-                            .collect();
-                        // Then check if any collateral is above threshold
-                        let above_threshold = match has_any_collateral_above_threshold(
-                            provider.clone(),
-                            address,
-                            user_positions.clone(),
-                            MIN_COLLATERAL_THRESHOLD_IN_USD,
-                        )
-                        .await
-                        {
-                            Ok(res) => res,
-                            Err(_) => continue,
-                        };
-                        if !above_threshold {
-                            continue;
-                        }
-                        if !user_positions.is_empty() {
-                            results.insert(address, user_positions);
-                        }
+            }
+            for chunk in debtors.chunks(USER_RESERVES_DATA_BATCH_SIZE) {
+                let reserves_data =
+                    get_user_reserves_data_for_users(&provider, &market, chunk).await;
+                for (address, data) in chunk.iter().zip(reserves_data) {
+                    let Some(data) = data else {
+                        continue;
+                    };
+                    let user_positions: Vec<UserPosition> = data
+                        .iter()
+                        .map(|d| UserPosition {
+                            scaled_atoken_balance: d.scaledATokenBalance,
+                            usage_as_collateral_enabled_on_user: d.usageAsCollateralEnabledOnUser,
+                            scaled_variable_debt: d.scaledVariableDebt,
+                            underlying_asset: d.underlyingAsset,
+                        })
+                        .collect();
+                    // Then check if any collateral is above threshold
+                    let above_threshold = match has_any_collateral_above_threshold(
+                        provider.clone(),
+                        &market,
+                        *address,
+                        user_positions.clone(),
+                        MIN_COLLATERAL_THRESHOLD_IN_USD,
+                        &base_currency,
+                    )
+                    .await
+                    {
+                        Ok(res) => res,
+                        Err(_) => continue,
+                    };
+                    if !above_threshold {
+                        continue;
+                    }
+                    if !user_positions.is_empty() {
+                        results.insert(*address, user_positions);
                     }
-                    Err(e) => warn!("Couldn't calculate address reserves: {:?}", e),
                 }
             }
             results
@@ -758,8 +2070,9 @@ fn generate_user_by_position_by_asset(
     user_by_position_by_asset
 }
 
-fn bucketize_optimally(user_addresses: HashSet<UserAddress>) -> Vec<Vec<UserAddress>> {
-    let user_addresses = user_addresses.into_iter().collect::<Vec<_>>();
+/// Buckets are processed concurrently, so users are dealt round-robin to keep the order of
+/// `user_addresses` within each bucket: every bucket starts with the highest priority users.
+fn bucketize_optimally(user_addresses: Vec<UserAddress>) -> Vec<Vec<UserAddress>> {
     let len_addresses = user_addresses.len();
     let num_buckets = match len_addresses {
         0..=99 => 1,
@@ -768,20 +2081,358 @@ fn bucketize_optimally(user_addresses: HashSet<UserAddress>) -> Vec<Vec<UserAddr
         _ => *[8, 12, 16].choose(&mut rand::rng()).unwrap(),
     };
 
-    let mut buckets = Vec::with_capacity(num_buckets);
-    let chunk_size = len_addresses / num_buckets;
-    let mut remainder = len_addresses % num_buckets;
-    let mut start = 0;
+    let mut buckets = (0..num_buckets)
+        .map(|_| Vec::with_capacity(len_addresses / num_buckets + 1))
+        .collect::<Vec<Vec<UserAddress>>>();
+    for (i, user_address) in user_addresses.into_iter().enumerate() {
+        buckets[i % num_buckets].push(user_address);
+    }
+
+    buckets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::{
+        primitives::Bytes,
+        rpc::{
+            client::RpcClient,
+            json_rpc::{
+                RequestPacket, Response, ResponsePacket, ResponsePayload, SerializedRequest,
+            },
+        },
+        sol_types::SolCall,
+        transports::{TransportError, TransportFut},
+    };
+    use overlord_shared::{
+        sol_bindings::{
+            pool::AaveV3Pool,
+            IMulticall3::{self, Call3, Call3Result},
+        },
+        WhistleblowerEventDetails,
+    };
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        task::{Context, Poll},
+    };
+    use tower::Service;
+
+    const USER: Address = address!("00000000000000000000000000000000000000aa");
+    const OTHER_USER: Address = address!("00000000000000000000000000000000000000bb");
+    const DEBT_ASSET: Address = address!("0000000000000000000000000000000000000d00");
+    const COLLATERAL_ASSET: Address = address!("0000000000000000000000000000000000000c00");
+
+    /// What the mocked node returns for a user
+    #[derive(Clone, Default)]
+    struct MockUser {
+        total_debt: U256,
+        positions: Vec<UserReserveData>,
+        emode_category: u8,
+    }
+
+    /// Node that only answers the Multicall3.aggregate3() eth_calls the cache updates with,
+    /// from `users`. Every eth_call it gets is counted in `eth_calls`.
+    #[derive(Clone, Default)]
+    struct MockNode {
+        users: Arc<Mutex<HashMap<Address, MockUser>>>,
+        eth_calls: Arc<AtomicUsize>,
+    }
+
+    impl MockNode {
+        fn set_user(&self, user: Address, mock_user: MockUser) {
+            self.users.lock().unwrap().insert(user, mock_user);
+        }
+
+        fn respond(&self, request: &SerializedRequest) -> Response {
+            assert_eq!(request.method(), "eth_call");
+            self.eth_calls.fetch_add(1, Ordering::SeqCst);
+            let params: Vec<serde_json::Value> =
+                serde_json::from_str(request.params().unwrap().get()).unwrap();
+            let input = params[0]
+                .get("input")
+                .or_else(|| params[0].get("data"))
+                .and_then(serde_json::Value::as_str)
+                .unwrap();
+            let input = Bytes::from_str(input).unwrap();
+            let calls = IMulticall3::aggregate3Call::abi_decode(&input, true)
+                .unwrap()
+                .calls;
+            let results: Vec<Call3Result> = calls.iter().map(|call| self.answer(call)).collect();
+            let output = Bytes::from(IMulticall3::aggregate3Call::abi_encode_returns(&(results,)));
+            Response {
+                id: request.id().clone(),
+                payload: ResponsePayload::Success(
+                    serde_json::value::to_raw_value(&output).unwrap(),
+                ),
+            }
+        }
 
-    for _ in 0..num_buckets {
-        let mut end = start + chunk_size;
-        if remainder > 0 {
-            end += 1;
-            remainder -= 1;
+        fn answer(&self, call: &Call3) -> Call3Result {
+            let users = self.users.lock().unwrap();
+            let selector: [u8; 4] = call.callData[..4].try_into().unwrap();
+            let return_data = match selector {
+                AaveV3Pool::getUserAccountDataCall::SELECTOR => {
+                    let user = AaveV3Pool::getUserAccountDataCall::abi_decode(&call.callData, true)
+                        .unwrap()
+                        .user;
+                    let total_debt = users.get(&user).map_or(U256::ZERO, |u| u.total_debt);
+                    AaveV3Pool::getUserAccountDataCall::abi_encode_returns(&(
+                        U256::ZERO,
+                        total_debt,
+                        U256::ZERO,
+                        U256::ZERO,
+                        U256::ZERO,
+                        U256::MAX,
+                    ))
+                }
+                AaveUIPoolDataProvider::getUserReservesDataCall::SELECTOR => {
+                    let user = AaveUIPoolDataProvider::getUserReservesDataCall::abi_decode(
+                        &call.callData,
+                        true,
+                    )
+                    .unwrap()
+                    .user;
+                    let positions = users.get(&user).map_or(vec![], |u| u.positions.clone());
+                    AaveUIPoolDataProvider::getUserReservesDataCall::abi_encode_returns(&(
+                        positions, 0u8,
+                    ))
+                }
+                AaveV3Pool::getUserEModeCall::SELECTOR => {
+                    let user = AaveV3Pool::getUserEModeCall::abi_decode(&call.callData, true)
+                        .unwrap()
+                        .user;
+                    let emode_category = users.get(&user).map_or(0, |u| u.emode_category);
+                    AaveV3Pool::getUserEModeCall::abi_encode_returns(&(U256::from(emode_category),))
+                }
+                _ => panic!("Unexpected call to {}", call.target),
+            };
+            Call3Result {
+                success: true,
+                returnData: return_data.into(),
+            }
         }
-        buckets.push(user_addresses[start..end].to_vec());
-        start = end;
     }
 
-    buckets
+    impl Service<RequestPacket> for MockNode {
+        type Response = ResponsePacket;
+        type Error = TransportError;
+        type Future = TransportFut<'static>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: RequestPacket) -> Self::Future {
+            let RequestPacket::Single(request) = request else {
+                panic!("The cache doesn't batch requests");
+            };
+            let response = self.respond(&request);
+            Box::pin(async move { Ok(ResponsePacket::Single(response)) })
+        }
+    }
+
+    async fn new_cache(node: &MockNode) -> UserReservesCache<RootProvider<MockNode>, MockNode> {
+        let provider = Arc::new(RootProvider::new(RpcClient::new(node.clone(), true)));
+        let cache = UserReservesCache::new(provider, AaveMarket::core());
+        {
+            let mut user_reserves_cache = cache.user_reserves_cache.write().await;
+            user_reserves_cache.insert(DEBT_ASSET, HashMap::new());
+            user_reserves_cache.insert(COLLATERAL_ASSET, HashMap::new());
+        }
+        cache
+    }
+
+    fn borrower() -> MockUser {
+        MockUser {
+            total_debt: U256::from(1_000),
+            positions: vec![
+                UserReserveData {
+                    underlyingAsset: DEBT_ASSET,
+                    scaledATokenBalance: U256::ZERO,
+                    usageAsCollateralEnabledOnUser: false,
+                    scaledVariableDebt: U256::from(1_000),
+                },
+                UserReserveData {
+                    underlyingAsset: COLLATERAL_ASSET,
+                    scaledATokenBalance: U256::from(2_000),
+                    usageAsCollateralEnabledOnUser: true,
+                    scaledVariableDebt: U256::ZERO,
+                },
+            ],
+            emode_category: 1,
+        }
+    }
+
+    fn wb_update(event: WhistleblowerEventType, args: &[String]) -> WhistleblowerUpdate {
+        WhistleblowerUpdate {
+            trace_id: "0xtest".to_string(),
+            block_number: 100,
+            market: AaveMarket::core().addresses_provider,
+            event_details: WhistleblowerEventDetails {
+                event,
+                args: args.to_vec(),
+            },
+        }
+    }
+
+    async fn cached_users(
+        cache: &UserReservesCache<RootProvider<MockNode>, MockNode>,
+        asset: Address,
+        position_type: PositionType,
+    ) -> Vec<UserAddress> {
+        cache.user_reserves_cache.read().await[&asset]
+            .get(&position_type)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn borrow_adds_the_user_with_its_positions() {
+        let node = MockNode::default();
+        node.set_user(USER, borrower());
+        let mut cache = new_cache(&node).await;
+
+        let borrow = wb_update(
+            WhistleblowerEventType::Borrow,
+            &[DEBT_ASSET.to_string(), USER.to_string()],
+        );
+        cache.update_cache(&[borrow]).await.unwrap();
+
+        assert_eq!(
+            cached_users(&cache, DEBT_ASSET, PositionType::Borrowed).await,
+            vec![USER]
+        );
+        assert_eq!(
+            cached_users(&cache, COLLATERAL_ASSET, PositionType::Collateral).await,
+            vec![USER]
+        );
+        let hf_engine = cache.hf_engine.read().await;
+        assert_eq!(hf_engine.user_positions(&USER).map(<[_]>::len), Some(2));
+        assert_eq!(hf_engine.user_emode_category(&USER), Some(1));
+    }
+
+    #[tokio::test]
+    async fn user_without_debt_is_dropped() {
+        let node = MockNode::default();
+        node.set_user(USER, borrower());
+        let mut cache = new_cache(&node).await;
+        let borrow = wb_update(
+            WhistleblowerEventType::Borrow,
+            &[DEBT_ASSET.to_string(), USER.to_string()],
+        );
+        cache.update_cache(&[borrow]).await.unwrap();
+
+        node.set_user(USER, MockUser::default());
+        let repay = wb_update(
+            WhistleblowerEventType::Repay,
+            &[DEBT_ASSET.to_string(), USER.to_string()],
+        );
+        cache.update_cache(&[repay]).await.unwrap();
+
+        assert!(cached_users(&cache, DEBT_ASSET, PositionType::Borrowed)
+            .await
+            .is_empty());
+        assert!(
+            cached_users(&cache, COLLATERAL_ASSET, PositionType::Collateral)
+                .await
+                .is_empty()
+        );
+        assert!(!cache.hf_engine.read().await.has_user(&USER));
+    }
+
+    #[tokio::test]
+    async fn updates_of_a_block_are_read_together() {
+        let node = MockNode::default();
+        node.set_user(USER, borrower());
+        node.set_user(OTHER_USER, borrower());
+        let mut cache = new_cache(&node).await;
+
+        let updates = [
+            wb_update(
+                WhistleblowerEventType::Borrow,
+                &[DEBT_ASSET.to_string(), USER.to_string()],
+            ),
+            wb_update(
+                WhistleblowerEventType::Supply,
+                &[COLLATERAL_ASSET.to_string(), USER.to_string()],
+            ),
+            wb_update(
+                WhistleblowerEventType::Borrow,
+                &[DEBT_ASSET.to_string(), OTHER_USER.to_string()],
+            ),
+        ];
+        cache.update_cache(&updates).await.unwrap();
+
+        // getUserAccountData, getUserReservesData and getUserEMode, one multicall each
+        assert_eq!(node.eth_calls.load(Ordering::SeqCst), 3);
+        let mut borrowers = cached_users(&cache, DEBT_ASSET, PositionType::Borrowed).await;
+        borrowers.sort();
+        assert_eq!(borrowers, vec![USER, OTHER_USER]);
+    }
+
+    #[tokio::test]
+    async fn transfer_between_untracked_users_reads_nothing() {
+        let node = MockNode::default();
+        let mut cache = new_cache(&node).await;
+
+        let transfer = wb_update(
+            WhistleblowerEventType::Transfer,
+            &[
+                COLLATERAL_ASSET.to_string(),
+                USER.to_string(),
+                OTHER_USER.to_string(),
+                "1000".to_string(),
+            ],
+        );
+        cache.update_cache(&[transfer]).await.unwrap();
+
+        assert_eq!(node.eth_calls.load(Ordering::SeqCst), 0);
+        assert!(!cache.hf_engine.read().await.has_user(&USER));
+    }
+
+    #[tokio::test]
+    async fn transfer_skips_users_already_refreshed_in_its_block() {
+        let node = MockNode::default();
+        node.set_user(USER, borrower());
+        let mut cache = new_cache(&node).await;
+        let borrow = wb_update(
+            WhistleblowerEventType::Borrow,
+            &[DEBT_ASSET.to_string(), USER.to_string()],
+        );
+        cache.update_cache(&[borrow]).await.unwrap();
+
+        let mut transfer = wb_update(
+            WhistleblowerEventType::Transfer,
+            &[
+                COLLATERAL_ASSET.to_string(),
+                USER.to_string(),
+                OTHER_USER.to_string(),
+                "1000".to_string(),
+            ],
+        );
+        cache
+            .update_cache(std::slice::from_ref(&transfer))
+            .await
+            .unwrap();
+        assert_eq!(node.eth_calls.load(Ordering::SeqCst), 3);
+
+        transfer.block_number += 1;
+        cache.update_cache(&[transfer]).await.unwrap();
+        assert_eq!(node.eth_calls.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn malformed_update_is_skipped() {
+        let node = MockNode::default();
+        let mut cache = new_cache(&node).await;
+
+        let borrow = wb_update(WhistleblowerEventType::Borrow, &[DEBT_ASSET.to_string()]);
+        cache.update_cache(&[borrow]).await.unwrap();
+
+        assert_eq!(node.eth_calls.load(Ordering::SeqCst), 0);
+    }
 }
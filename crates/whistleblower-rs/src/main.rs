@@ -1,6 +1,7 @@
-use alloy::rpc::types::{Filter, Log};
+use alloy::rpc::types::{Block, BlockTransactionsKind, Filter, Log};
 use alloy::{
-    primitives::{address, FixedBytes, U64},
+    eips::BlockId,
+    primitives::{Address, FixedBytes, U64},
     providers::{IpcConnect, Provider, ProviderBuilder, RootProvider},
     pubsub::{PubSubFrontend, Subscription},
     sol,
@@ -8,14 +9,25 @@ use alloy::{
 use alloy_primitives::keccak256;
 use futures_util::{stream::select_all, StreamExt};
 use overlord_shared::{
-    MessageBundle, WhistleblowerEventDetails, WhistleblowerEventType, WhistleblowerUpdate,
+    common::get_reserves_data,
+    constants::OOPS_INBOUND_ENDPOINT,
+    market::{resolve_markets_from_env, AaveMarket},
+    resolvers::resolve_price_source,
+    retry::{any_error, is_retryable_rpc_error, retry, Backoff, RetryPolicy},
+    sol_bindings::{AaveOracle, IPoolConfigurator, ERC20},
+    status::{Counter, StatusReporter},
+    AssetSourceUpdate, MessageBundle, WhistleblowerEventDetails, WhistleblowerEventType,
+    WhistleblowerUpdate,
 };
-use std::{collections::HashMap, sync::Arc};
-use tokio::time::{sleep, Duration};
+use std::{collections::HashMap, sync::Arc, time::Instant};
+use tokio::time::sleep;
 use tracing::{error, info, warn};
 use tracing_appender::rolling::{self, Rotation};
 use tracing_subscriber::fmt::{time::LocalTime, writer::BoxMakeWriter};
 
+mod reorg;
+use reorg::ReorgTracker;
+
 sol!(
     #[allow(missing_docs)]
     #[sol(rpc)]
@@ -24,6 +36,21 @@ sol!(
     "src/abi/aave_v3_pool.json",
 );
 
+// Reported by the periodic status line
+static EVENTS_SEEN: Counter = Counter::new();
+static UPDATES_SENT: Counter = Counter::new();
+static SOURCE_UPDATES_SENT: Counter = Counter::new();
+static FOREIGN_LOGS_IGNORED: Counter = Counter::new();
+static REORGED_UPDATES_RESENT: Counter = Counter::new();
+
+// Contracts of the core market whose events are followed, for deployments other than Ethereum
+// mainnet
+const POOL_ADDRESS_ENV: &str = "WHISTLEBLOWER_POOL_ADDRESS";
+const POOL_CONFIGURATOR_ADDRESS_ENV: &str = "WHISTLEBLOWER_POOL_CONFIGURATOR_ADDRESS";
+const ORACLE_ADDRESS_ENV: &str = "WHISTLEBLOWER_ORACLE_ADDRESS";
+// Attempts at connecting to the vega and oops sockets before giving up
+const ZMQ_CONNECT_ATTEMPTS: u32 = 5;
+
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 enum WhistleblowerError {
@@ -53,6 +80,61 @@ trait EventProcessor {
     ) -> Result<WhistleblowerEventDetails, WhistleblowerError>;
 }
 
+/// Only lets through logs emitted by `emitter`. Subscriptions are already filtered by address,
+/// this keeps an identically-shaped event of another contract from ever reaching vega's cache
+/// with made-up users if one slips through anyway.
+struct ScopedProcessor {
+    emitter: Address,
+    processor: Box<dyn EventProcessor>,
+}
+
+impl EventProcessor for ScopedProcessor {
+    fn process(
+        &self,
+        log: &Log,
+        block_number: U64,
+    ) -> Result<WhistleblowerEventDetails, WhistleblowerError> {
+        if log.address() != self.emitter {
+            return Err(WhistleblowerError::EventProcessingError(format!(
+                "Event emitted by {} instead of {}",
+                log.address(),
+                self.emitter
+            )));
+        }
+        self.processor.process(log, block_number)
+    }
+}
+
+/// Keys every processor by its event signature, and scopes it to `emitter`
+fn scope_to(
+    emitter: Address,
+    processors: Vec<(FixedBytes<32>, Box<dyn EventProcessor>)>,
+) -> HashMap<FixedBytes<32>, Box<dyn EventProcessor>> {
+    processors
+        .into_iter()
+        .map(|(event_signature, processor)| {
+            (
+                event_signature,
+                Box::new(ScopedProcessor { emitter, processor }) as Box<dyn EventProcessor>,
+            )
+        })
+        .collect()
+}
+
+/// Address set in `env_name`, or `default` if it isn't set
+fn address_from_env(env_name: &str, default: Address) -> Address {
+    match std::env::var(env_name) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|e| {
+            warn!(
+                "Invalid {} value {}, using {}: {}",
+                env_name, value, default, e
+            );
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
 struct LiquidationCallProcessor;
 
 impl EventProcessor for LiquidationCallProcessor {
@@ -198,12 +280,610 @@ impl EventProcessor for RepayProcessor {
     }
 }
 
+struct WithdrawProcessor;
+
+impl EventProcessor for WithdrawProcessor {
+    fn process(
+        &self,
+        log: &Log,
+        block_number: U64,
+    ) -> Result<WhistleblowerEventDetails, WhistleblowerError> {
+        let decoded = log.log_decode().map_err(|e| {
+            WhistleblowerError::EventProcessingError(format!(
+                "Failed to decode Withdraw event: {}",
+                e
+            ))
+        })?;
+
+        let AAVE_V3_POOL::Withdraw {
+            reserve,
+            user,
+            to,
+            amount,
+        } = decoded.inner.data;
+
+        info!(
+            block = ?block_number,
+            reserve = %reserve,
+            user = %user,
+            amount = %amount,
+            "WITHDRAW"
+        );
+
+        Ok(WhistleblowerEventDetails {
+            event: WhistleblowerEventType::Withdraw,
+            args: vec![
+                reserve.to_string(),
+                user.to_string(),
+                to.to_string(),
+                amount.to_string(),
+            ],
+        })
+    }
+}
+
+struct ReserveUsedAsCollateralEnabledProcessor;
+
+impl EventProcessor for ReserveUsedAsCollateralEnabledProcessor {
+    fn process(
+        &self,
+        log: &Log,
+        block_number: U64,
+    ) -> Result<WhistleblowerEventDetails, WhistleblowerError> {
+        let decoded = log.log_decode().map_err(|e| {
+            WhistleblowerError::EventProcessingError(format!(
+                "Failed to decode ReserveUsedAsCollateralEnabled event: {}",
+                e
+            ))
+        })?;
+
+        let AAVE_V3_POOL::ReserveUsedAsCollateralEnabled { reserve, user } = decoded.inner.data;
+
+        info!(
+            block = ?block_number,
+            reserve = %reserve,
+            user = %user,
+            "RESERVE USED AS COLLATERAL ENABLED"
+        );
+
+        Ok(WhistleblowerEventDetails {
+            event: WhistleblowerEventType::ReserveUsedAsCollateralEnabled,
+            args: vec![reserve.to_string(), user.to_string()],
+        })
+    }
+}
+
+struct ReserveUsedAsCollateralDisabledProcessor;
+
+impl EventProcessor for ReserveUsedAsCollateralDisabledProcessor {
+    fn process(
+        &self,
+        log: &Log,
+        block_number: U64,
+    ) -> Result<WhistleblowerEventDetails, WhistleblowerError> {
+        let decoded = log.log_decode().map_err(|e| {
+            WhistleblowerError::EventProcessingError(format!(
+                "Failed to decode ReserveUsedAsCollateralDisabled event: {}",
+                e
+            ))
+        })?;
+
+        let AAVE_V3_POOL::ReserveUsedAsCollateralDisabled { reserve, user } = decoded.inner.data;
+
+        info!(
+            block = ?block_number,
+            reserve = %reserve,
+            user = %user,
+            "RESERVE USED AS COLLATERAL DISABLED"
+        );
+
+        Ok(WhistleblowerEventDetails {
+            event: WhistleblowerEventType::ReserveUsedAsCollateralDisabled,
+            args: vec![reserve.to_string(), user.to_string()],
+        })
+    }
+}
+
+struct UserEModeSetProcessor;
+
+impl EventProcessor for UserEModeSetProcessor {
+    fn process(
+        &self,
+        log: &Log,
+        block_number: U64,
+    ) -> Result<WhistleblowerEventDetails, WhistleblowerError> {
+        let decoded = log.log_decode().map_err(|e| {
+            WhistleblowerError::EventProcessingError(format!(
+                "Failed to decode UserEModeSet event: {}",
+                e
+            ))
+        })?;
+
+        let AAVE_V3_POOL::UserEModeSet { user, categoryId } = decoded.inner.data;
+
+        info!(
+            block = ?block_number,
+            user = %user,
+            category_id = categoryId,
+            "USER EMODE SET"
+        );
+
+        Ok(WhistleblowerEventDetails {
+            event: WhistleblowerEventType::UserEModeSet,
+            args: vec![user.to_string(), categoryId.to_string()],
+        })
+    }
+}
+
+struct SwapBorrowRateModeProcessor;
+
+impl EventProcessor for SwapBorrowRateModeProcessor {
+    fn process(
+        &self,
+        log: &Log,
+        block_number: U64,
+    ) -> Result<WhistleblowerEventDetails, WhistleblowerError> {
+        let decoded = log.log_decode().map_err(|e| {
+            WhistleblowerError::EventProcessingError(format!(
+                "Failed to decode SwapBorrowRateMode event: {}",
+                e
+            ))
+        })?;
+
+        let AAVE_V3_POOL::SwapBorrowRateMode {
+            reserve,
+            user,
+            interestRateMode,
+        } = decoded.inner.data;
+
+        info!(
+            block = ?block_number,
+            reserve = %reserve,
+            user = %user,
+            interest_rate_mode = interestRateMode,
+            "SWAP BORROW RATE MODE"
+        );
+
+        Ok(WhistleblowerEventDetails {
+            event: WhistleblowerEventType::SwapBorrowRateMode,
+            args: vec![
+                reserve.to_string(),
+                user.to_string(),
+                interestRateMode.to_string(),
+            ],
+        })
+    }
+}
+
+struct RebalanceStableBorrowRateProcessor;
+
+impl EventProcessor for RebalanceStableBorrowRateProcessor {
+    fn process(
+        &self,
+        log: &Log,
+        block_number: U64,
+    ) -> Result<WhistleblowerEventDetails, WhistleblowerError> {
+        let decoded = log.log_decode().map_err(|e| {
+            WhistleblowerError::EventProcessingError(format!(
+                "Failed to decode RebalanceStableBorrowRate event: {}",
+                e
+            ))
+        })?;
+
+        let AAVE_V3_POOL::RebalanceStableBorrowRate { reserve, user } = decoded.inner.data;
+
+        info!(
+            block = ?block_number,
+            reserve = %reserve,
+            user = %user,
+            "REBALANCE STABLE BORROW RATE"
+        );
+
+        Ok(WhistleblowerEventDetails {
+            event: WhistleblowerEventType::RebalanceStableBorrowRate,
+            args: vec![reserve.to_string(), user.to_string()],
+        })
+    }
+}
+
+struct ReserveInitializedProcessor;
+
+impl EventProcessor for ReserveInitializedProcessor {
+    fn process(
+        &self,
+        log: &Log,
+        block_number: U64,
+    ) -> Result<WhistleblowerEventDetails, WhistleblowerError> {
+        let decoded = log.log_decode().map_err(|e| {
+            WhistleblowerError::EventProcessingError(format!(
+                "Failed to decode ReserveInitialized event: {}",
+                e
+            ))
+        })?;
+
+        let IPoolConfigurator::ReserveInitialized {
+            asset,
+            aToken,
+            variableDebtToken,
+            ..
+        } = decoded.inner.data;
+
+        info!(
+            block = ?block_number,
+            tx_hash = ?log.transaction_hash,
+            asset = %asset,
+            a_token = %aToken,
+            "RESERVE INITIALIZED"
+        );
+
+        Ok(WhistleblowerEventDetails {
+            event: WhistleblowerEventType::ReserveInitialized,
+            args: vec![
+                asset.to_string(),
+                aToken.to_string(),
+                variableDebtToken.to_string(),
+            ],
+        })
+    }
+}
+
+struct CollateralConfigurationChangedProcessor;
+
+impl EventProcessor for CollateralConfigurationChangedProcessor {
+    fn process(
+        &self,
+        log: &Log,
+        block_number: U64,
+    ) -> Result<WhistleblowerEventDetails, WhistleblowerError> {
+        let decoded = log.log_decode().map_err(|e| {
+            WhistleblowerError::EventProcessingError(format!(
+                "Failed to decode CollateralConfigurationChanged event: {}",
+                e
+            ))
+        })?;
+
+        let IPoolConfigurator::CollateralConfigurationChanged {
+            asset,
+            ltv,
+            liquidationThreshold,
+            liquidationBonus,
+        } = decoded.inner.data;
+
+        info!(
+            block = ?block_number,
+            asset = %asset,
+            ltv = %ltv,
+            liquidation_threshold = %liquidationThreshold,
+            liquidation_bonus = %liquidationBonus,
+            "COLLATERAL CONFIGURATION CHANGED"
+        );
+
+        Ok(WhistleblowerEventDetails {
+            event: WhistleblowerEventType::CollateralConfigurationChanged,
+            args: vec![
+                asset.to_string(),
+                ltv.to_string(),
+                liquidationThreshold.to_string(),
+                liquidationBonus.to_string(),
+            ],
+        })
+    }
+}
+
+struct ReserveFrozenProcessor;
+
+impl EventProcessor for ReserveFrozenProcessor {
+    fn process(
+        &self,
+        log: &Log,
+        block_number: U64,
+    ) -> Result<WhistleblowerEventDetails, WhistleblowerError> {
+        let decoded = log.log_decode().map_err(|e| {
+            WhistleblowerError::EventProcessingError(format!(
+                "Failed to decode ReserveFrozen event: {}",
+                e
+            ))
+        })?;
+
+        let IPoolConfigurator::ReserveFrozen { asset, frozen } = decoded.inner.data;
+
+        info!(
+            block = ?block_number,
+            asset = %asset,
+            frozen = frozen,
+            "RESERVE FROZEN"
+        );
+
+        Ok(WhistleblowerEventDetails {
+            event: WhistleblowerEventType::ReserveFrozen,
+            args: vec![asset.to_string(), frozen.to_string()],
+        })
+    }
+}
+
+struct ReservePausedProcessor;
+
+impl EventProcessor for ReservePausedProcessor {
+    fn process(
+        &self,
+        log: &Log,
+        block_number: U64,
+    ) -> Result<WhistleblowerEventDetails, WhistleblowerError> {
+        let decoded = log.log_decode().map_err(|e| {
+            WhistleblowerError::EventProcessingError(format!(
+                "Failed to decode ReservePaused event: {}",
+                e
+            ))
+        })?;
+
+        let IPoolConfigurator::ReservePaused { asset, paused } = decoded.inner.data;
+
+        info!(
+            block = ?block_number,
+            asset = %asset,
+            paused = paused,
+            "RESERVE PAUSED"
+        );
+
+        Ok(WhistleblowerEventDetails {
+            event: WhistleblowerEventType::ReservePaused,
+            args: vec![asset.to_string(), paused.to_string()],
+        })
+    }
+}
+
+/// Transfers of aTokens and variable debt tokens, including mints and burns. Collateral moves
+/// through them without a Pool event of the user (aToken transfers), so they keep vega's cache
+/// from drifting. vega decides which ones are worth refreshing users for.
+struct TransferProcessor {
+    /// Underlying asset of every aToken and variable debt token
+    underlying_by_token: HashMap<Address, Address>,
+}
+
+impl EventProcessor for TransferProcessor {
+    fn process(
+        &self,
+        log: &Log,
+        block_number: U64,
+    ) -> Result<WhistleblowerEventDetails, WhistleblowerError> {
+        let decoded = log.log_decode().map_err(|e| {
+            WhistleblowerError::EventProcessingError(format!(
+                "Failed to decode Transfer event: {}",
+                e
+            ))
+        })?;
+
+        let ERC20::Transfer { from, to, value } = decoded.inner.data;
+        let asset = self
+            .underlying_by_token
+            .get(&log.address())
+            .ok_or_else(|| {
+                WhistleblowerError::EventProcessingError(format!(
+                    "Transfer of unknown token {}",
+                    log.address()
+                ))
+            })?;
+
+        info!(
+            block = ?block_number,
+            token = %log.address(),
+            from = %from,
+            to = %to,
+            value = %value,
+            "TRANSFER"
+        );
+
+        Ok(WhistleblowerEventDetails {
+            event: WhistleblowerEventType::Transfer,
+            args: vec![
+                asset.to_string(),
+                from.to_string(),
+                to.to_string(),
+                value.to_string(),
+            ],
+        })
+    }
+}
+
+/// Maps the aToken and variable debt token of every reserve of `market` to its underlying asset
+async fn get_underlying_by_token(
+    provider: Arc<RootProvider<PubSubFrontend>>,
+    market: &AaveMarket,
+) -> Result<HashMap<Address, Address>, WhistleblowerError> {
+    let reserves_data = get_reserves_data(provider, market)
+        .await
+        .map_err(|e| WhistleblowerError::ProviderError(e.to_string()))?;
+    Ok(reserves_data
+        .iter()
+        .flat_map(|reserve| {
+            [
+                (reserve.aTokenAddress, reserve.underlyingAsset),
+                (reserve.variableDebtTokenAddress, reserve.underlyingAsset),
+            ]
+        })
+        .collect())
+}
+
+/// Subscribes to the transfers of the aToken and variable debt token of a reserve that was just
+/// listed (see ReserveInitializedProcessor for its args), so they're followed without reconnecting.
+/// Returns the subscription and the underlying asset of each token, or None if the caller should
+/// reconnect to follow them instead.
+async fn follow_reserve_tokens(
+    provider: &Arc<RootProvider<PubSubFrontend>>,
+    event_details: &WhistleblowerEventDetails,
+    transfer_signature: FixedBytes<32>,
+) -> Option<(Subscription<Log>, [(Address, Address); 2])> {
+    let addresses: Vec<Address> = event_details
+        .args
+        .iter()
+        .filter_map(|arg| arg.parse().ok())
+        .collect();
+    let [asset, a_token, variable_debt_token] = addresses.as_slice() else {
+        warn!(
+            "Unexpected ReserveInitialized args {:?}",
+            event_details.args
+        );
+        return None;
+    };
+    let subscription = setup_subscription(
+        provider.clone(),
+        &[*a_token, *variable_debt_token],
+        transfer_signature,
+        "new reserve transfer",
+    )
+    .await
+    .ok()?;
+    info!(
+        "Following transfers of {} and {}, the tokens of the new reserve {}",
+        a_token, variable_debt_token, asset
+    );
+    Some((
+        subscription,
+        [(*a_token, *asset), (*variable_debt_token, *asset)],
+    ))
+}
+
+fn trace_id_of(log: &Log) -> String {
+    log.transaction_hash
+        .as_ref()
+        .map_or("".to_string(), |tx_hash| {
+            hex::encode(tx_hash.0)[2..10].to_string()
+        })
+}
+
 fn send_whistleblower_update(
     log: &Log,
+    market: Address,
     event_details: &WhistleblowerEventDetails,
     socket: &zmq::Socket,
+) -> bool {
+    send_update(
+        WhistleblowerUpdate {
+            trace_id: trace_id_of(log),
+            block_number: log.block_number.unwrap_or_default(),
+            market,
+            event_details: event_details.clone(),
+        },
+        socket,
+    )
+}
+
+fn send_update(event_update: WhistleblowerUpdate, socket: &zmq::Socket) -> bool {
+    let event_type = event_update.event_details.event.clone();
+    let message_bundle = MessageBundle::WhistleblowerNotification(event_update);
+    let serialized_update = match bincode::serialize(&message_bundle) {
+        Ok(update) => update,
+        Err(e) => {
+            warn!("Failed to serialize Whistleblower update: {}", e);
+            return false;
+        }
+    };
+    if let Err(e) = socket.send(&serialized_update, 0) {
+        warn!("Failed to send Whistleblower update: {}", e);
+        return false;
+    }
+    UPDATES_SENT.inc();
+    info!(event_type = ?event_type, "Whistleblower update sent to Vega");
+    true
+}
+
+/// Sends the updates of a block that was reorged out again, as of `block_number` on the
+/// canonical chain, so vega reads the users and reserves they affected once more
+fn resend_reorged_updates(
+    reorged_block: u64,
+    updates: Vec<(String, Address, WhistleblowerEventDetails)>,
+    block_number: u64,
+    socket: &zmq::Socket,
 ) {
-    let event_update = WhistleblowerUpdate {
+    if updates.is_empty() {
+        return;
+    }
+    warn!(
+        "Block {} was reorged out, sending its {} updates again",
+        reorged_block,
+        updates.len()
+    );
+    for (trace_id, market, event_details) in updates {
+        let update = WhistleblowerUpdate {
+            trace_id,
+            block_number,
+            market,
+            event_details,
+        };
+        if send_update(update, socket) {
+            REORGED_UPDATES_RESENT.inc();
+        }
+    }
+}
+
+/// Checks the blocks updates were sent for against the canonical chain, unless `head` just
+/// builds on the previous one
+async fn check_for_reorgs(
+    provider: &Arc<RootProvider<PubSubFrontend>>,
+    reorg_tracker: &mut ReorgTracker,
+    head: Block,
+    socket: &zmq::Socket,
+) {
+    let head = head.header;
+    if reorg_tracker.extends_last_head(head.number, head.hash, head.parent_hash) {
+        return;
+    }
+    for (number, hash) in reorg_tracker.recorded_blocks() {
+        match provider
+            .get_block(BlockId::number(number), BlockTransactionsKind::Hashes)
+            .await
+        {
+            Ok(Some(block)) if block.header.hash == hash => (),
+            // Past the new head, or replaced
+            Ok(_) => {
+                let updates = reorg_tracker.take_block(number, hash);
+                resend_reorged_updates(number, updates, head.number, socket);
+            }
+            Err(e) => warn!("Failed to check block {} for reorgs: {}", number, e),
+        }
+    }
+}
+
+/// Resolves the new price source of an AssetSourceUpdated event of the `market` oracle and sends
+/// the result to every socket. Unresolvable sources are sent anyway, so nobody keeps pricing the
+/// asset with the old one.
+async fn send_asset_source_update(
+    provider: &Arc<RootProvider<PubSubFrontend>>,
+    log: &Log,
+    market: Address,
+    sockets: &[&zmq::Socket],
+) {
+    let AaveOracle::AssetSourceUpdated { asset, source } =
+        match log.log_decode::<AaveOracle::AssetSourceUpdated>() {
+            Ok(decoded) => decoded.inner.data,
+            Err(e) => {
+                warn!("Failed to decode AssetSourceUpdated event: {}", e);
+                return;
+            }
+        };
+    let (aggregator, transmitters) = match resolve_price_source(provider, source).await {
+        Ok(resolved) => match resolved.aggregator {
+            Some(aggregator) => (Some(aggregator.address), aggregator.transmitters),
+            None => (None, vec![]),
+        },
+        Err(e) => {
+            warn!(
+                "Failed to resolve new price source {} of {}: {}",
+                source, asset, e
+            );
+            (None, vec![])
+        }
+    };
+    info!(
+        block = ?log.block_number,
+        tx_hash = ?log.transaction_hash,
+        asset = %asset,
+        source = %source,
+        aggregator = ?aggregator,
+        transmitters = transmitters.len(),
+        "ASSET SOURCE UPDATED"
+    );
+    let update = AssetSourceUpdate {
         trace_id: log
             .transaction_hash
             .as_ref()
@@ -211,21 +891,25 @@ fn send_whistleblower_update(
                 hex::encode(tx_hash.0)[2..10].to_string()
             }),
         block_number: log.block_number.unwrap_or_default(),
-        event_details: event_details.clone(),
+        market,
+        asset,
+        source,
+        aggregator,
+        transmitters,
     };
-    let message_bundle = MessageBundle::WhistleblowerNotification(event_update);
-    let serialized_update = match bincode::serialize(&message_bundle) {
+    let serialized_update = match bincode::serialize(&MessageBundle::AssetSourceUpdate(update)) {
         Ok(update) => update,
         Err(e) => {
-            warn!("Failed to serialize Whistleblower update: {}", e);
+            warn!("Failed to serialize asset source update: {}", e);
             return;
         }
     };
-    if let Err(e) = socket.send(&serialized_update, 0) {
-        warn!("Failed to send Whistleblower update: {}", e);
-        return;
+    for socket in sockets {
+        if let Err(e) = socket.send(&serialized_update, 0) {
+            warn!("Failed to send asset source update: {}", e);
+        }
     }
-    info!(event_type = ?event_details.event, "Whistleblower update sent to Vega");
+    SOURCE_UPDATES_SENT.inc();
 }
 
 fn _setup_logging() {
@@ -245,51 +929,112 @@ fn _setup_logging() {
 async fn setup_provider(
     ipc_url: String,
 ) -> Result<Arc<RootProvider<PubSubFrontend>>, WhistleblowerError> {
-    let ipc = IpcConnect::new(ipc_url);
-    ProviderBuilder::new()
-        .on_ipc(ipc)
-        .await
-        .map(Arc::new)
-        .map_err(|e| {
-            error!("Failed to connect to IPC: {}", e);
-            WhistleblowerError::ProviderError(e.to_string())
-        })
+    retry(RetryPolicy::RECONNECT, "connect to IPC", any_error, || {
+        ProviderBuilder::new().on_ipc(IpcConnect::new(ipc_url.clone()))
+    })
+    .await
+    .map(Arc::new)
+    .map_err(|e| {
+        error!("Failed to connect to IPC: {}", e);
+        WhistleblowerError::ProviderError(e.to_string())
+    })
 }
 
+/// Subscribes to the `event_signature` logs of `addresses` only, since other contracts can emit
+/// events with the same signature
 async fn setup_subscription(
     provider: Arc<RootProvider<PubSubFrontend>>,
+    addresses: &[Address],
     event_signature: FixedBytes<32>,
     event_name: &str,
 ) -> Result<Subscription<Log>, WhistleblowerError> {
-    provider
-        .subscribe_logs(&Filter::new().event_signature(event_signature))
-        .await
-        .map_err(|e| {
-            error!("Failed to subscribe to {} events: {}", event_name, e);
-            WhistleblowerError::SubscriptionError(e.to_string())
-        })
+    let filter = Filter::new()
+        .address(addresses.to_vec())
+        .event_signature(event_signature);
+    retry(
+        RetryPolicy::RPC,
+        &format!("subscribe to {} events", event_name),
+        is_retryable_rpc_error,
+        || provider.subscribe_logs(&filter),
+    )
+    .await
+    .map_err(|e| {
+        error!("Failed to subscribe to {} events: {}", event_name, e);
+        WhistleblowerError::SubscriptionError(e.to_string())
+    })
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     _setup_logging();
 
-    #[allow(non_snake_case)]
-    let AAVE_V3_POOL_ADDRESS = address!("87870Bca3F3fD6335C3F4ce8392D69350B4fA4E2");
-
     info!("Starting whistleblower-rs");
+    let ipc_url = "/tmp/reth.ipc";
+    // Events of every instance are followed, and updates say which one they came from
+    let mut markets = resolve_markets_from_env(setup_provider(ipc_url.to_string()).await?).await;
+    for market in markets.iter_mut().filter(|market| market.is_core()) {
+        market.pool = address_from_env(POOL_ADDRESS_ENV, market.pool);
+        market.pool_configurator =
+            address_from_env(POOL_CONFIGURATOR_ADDRESS_ENV, market.pool_configurator);
+        market.oracle = address_from_env(ORACLE_ADDRESS_ENV, market.oracle);
+    }
+    for market in &markets {
+        info!(
+            "Following pool {}, pool configurator {} and oracle {} of the {} market",
+            market.pool, market.pool_configurator, market.oracle, market.name
+        );
+    }
+    let pool_addresses: Vec<Address> = markets.iter().map(|market| market.pool).collect();
+    let configurator_addresses: Vec<Address> = markets
+        .iter()
+        .map(|market| market.pool_configurator)
+        .collect();
+    let oracle_addresses: Vec<Address> = markets.iter().map(|market| market.oracle).collect();
+    // The market every followed contract belongs to. Tokens are added on every connection.
+    let market_by_contract: HashMap<Address, Address> = markets
+        .iter()
+        .flat_map(|market| {
+            [market.pool, market.pool_configurator, market.oracle]
+                .map(|contract| (contract, market.addresses_provider))
+        })
+        .collect();
+
     let vega_context = zmq::Context::new();
     let vega_socket = vega_context.socket(zmq::PUSH).unwrap_or_else(|e| {
         error!("Failed to create ZMQ PUSH socket: {}", e);
         std::process::exit(1);
     });
-    if let Err(e) = vega_socket.connect("ipc:///tmp/vega_inbound") {
+    if let Err(e) = retry(
+        RetryPolicy::RECONNECT.with_max_attempts(ZMQ_CONNECT_ATTEMPTS),
+        "connect to Vega IPC",
+        any_error,
+        || async { vega_socket.connect("ipc:///tmp/vega_inbound") },
+    )
+    .await
+    {
         error!("Failed to connect to Vega IPC: {}", e);
         std::process::exit(1);
     }
     info!("Connected to vega");
 
-    let ipc_url = "/tmp/reth.ipc";
+    // oops only needs to hear about price source changes
+    let oops_socket = vega_context.socket(zmq::PUSH).unwrap_or_else(|e| {
+        error!("Failed to create ZMQ PUSH socket: {}", e);
+        std::process::exit(1);
+    });
+    if let Err(e) = retry(
+        RetryPolicy::RECONNECT.with_max_attempts(ZMQ_CONNECT_ATTEMPTS),
+        "connect to oops IPC",
+        any_error,
+        || async { oops_socket.connect(OOPS_INBOUND_ENDPOINT) },
+    )
+    .await
+    {
+        error!("Failed to connect to oops IPC: {}", e);
+        std::process::exit(1);
+    }
+    info!("Connected to oops");
+
     let liquidation_call_signature = keccak256(
         "LiquidationCall(address,address,address,uint256,uint256,address,bool)".as_bytes(),
     );
@@ -297,57 +1042,405 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         keccak256("Borrow(address,address,address,uint256,uint8,uint256,uint16)".as_bytes());
     let supply_signature = keccak256("Supply(address,address,address,uint256,uint16)".as_bytes());
     let repay_signature = keccak256("Repay(address,address,address,uint256,bool)".as_bytes());
+    let withdraw_signature = keccak256("Withdraw(address,address,address,uint256)".as_bytes());
+    let collateral_enabled_signature =
+        keccak256("ReserveUsedAsCollateralEnabled(address,address)".as_bytes());
+    let collateral_disabled_signature =
+        keccak256("ReserveUsedAsCollateralDisabled(address,address)".as_bytes());
+    let user_emode_set_signature = keccak256("UserEModeSet(address,uint8)".as_bytes());
+    let swap_borrow_rate_mode_signature =
+        keccak256("SwapBorrowRateMode(address,address,uint8)".as_bytes());
+    let rebalance_stable_borrow_rate_signature =
+        keccak256("RebalanceStableBorrowRate(address,address)".as_bytes());
+    let asset_source_updated_signature =
+        keccak256("AssetSourceUpdated(address,address)".as_bytes());
+    let reserve_initialized_signature =
+        keccak256("ReserveInitialized(address,address,address,address,address)".as_bytes());
+    let collateral_configuration_changed_signature =
+        keccak256("CollateralConfigurationChanged(address,uint256,uint256,uint256)".as_bytes());
+    let reserve_frozen_signature = keccak256("ReserveFrozen(address,bool)".as_bytes());
+    let reserve_paused_signature = keccak256("ReservePaused(address,bool)".as_bytes());
+    let transfer_signature = keccak256("Transfer(address,address,uint256)".as_bytes());
+
+    // Pool and configurator events are decoded the same for every market
+    let mut processors_by_contract: HashMap<
+        Address,
+        HashMap<FixedBytes<32>, Box<dyn EventProcessor>>,
+    > = HashMap::new();
+    for market in &markets {
+        processors_by_contract.extend([
+            (
+                market.pool,
+                scope_to(
+                    market.pool,
+                    vec![
+                        (
+                            liquidation_call_signature,
+                            Box::new(LiquidationCallProcessor) as Box<dyn EventProcessor>,
+                        ),
+                        (
+                            borrow_signature,
+                            Box::new(BorrowProcessor) as Box<dyn EventProcessor>,
+                        ),
+                        (
+                            supply_signature,
+                            Box::new(SupplyProcessor) as Box<dyn EventProcessor>,
+                        ),
+                        (
+                            repay_signature,
+                            Box::new(RepayProcessor) as Box<dyn EventProcessor>,
+                        ),
+                        (
+                            withdraw_signature,
+                            Box::new(WithdrawProcessor) as Box<dyn EventProcessor>,
+                        ),
+                        (
+                            collateral_enabled_signature,
+                            Box::new(ReserveUsedAsCollateralEnabledProcessor)
+                                as Box<dyn EventProcessor>,
+                        ),
+                        (
+                            collateral_disabled_signature,
+                            Box::new(ReserveUsedAsCollateralDisabledProcessor)
+                                as Box<dyn EventProcessor>,
+                        ),
+                        (
+                            user_emode_set_signature,
+                            Box::new(UserEModeSetProcessor) as Box<dyn EventProcessor>,
+                        ),
+                        (
+                            swap_borrow_rate_mode_signature,
+                            Box::new(SwapBorrowRateModeProcessor) as Box<dyn EventProcessor>,
+                        ),
+                        (
+                            rebalance_stable_borrow_rate_signature,
+                            Box::new(RebalanceStableBorrowRateProcessor) as Box<dyn EventProcessor>,
+                        ),
+                    ],
+                ),
+            ),
+            // Reserve listings and configuration changes, which vega needs to keep its reserves
+            // current
+            (
+                market.pool_configurator,
+                scope_to(
+                    market.pool_configurator,
+                    vec![
+                        (
+                            reserve_initialized_signature,
+                            Box::new(ReserveInitializedProcessor) as Box<dyn EventProcessor>,
+                        ),
+                        (
+                            collateral_configuration_changed_signature,
+                            Box::new(CollateralConfigurationChangedProcessor)
+                                as Box<dyn EventProcessor>,
+                        ),
+                        (
+                            reserve_frozen_signature,
+                            Box::new(ReserveFrozenProcessor) as Box<dyn EventProcessor>,
+                        ),
+                        (
+                            reserve_paused_signature,
+                            Box::new(ReservePausedProcessor) as Box<dyn EventProcessor>,
+                        ),
+                    ],
+                ),
+            ),
+        ]);
+    }
 
-    let event_processors: HashMap<FixedBytes<32>, Box<dyn EventProcessor>> = [
-        (
+    // The status line gets its own connection, since the one below is replaced on every
+    // reconnection
+    StatusReporter::new("whistleblower-rs")
+        .counter("events_seen", &EVENTS_SEEN)
+        .counter("updates_sent", &UPDATES_SENT)
+        .counter("source_updates_sent", &SOURCE_UPDATES_SENT)
+        .counter("foreign_logs_ignored", &FOREIGN_LOGS_IGNORED)
+        .counter("reorged_updates_resent", &REORGED_UPDATES_RESENT)
+        .spawn(setup_provider(ipc_url.to_string()).await?);
+
+    // Outlives connections, a reorg can happen while reconnecting
+    let mut reorg_tracker = ReorgTracker::default();
+    let mut backoff = Backoff::new(RetryPolicy::RECONNECT);
+    loop {
+        let provider = setup_provider(ipc_url.to_string()).await?;
+
+        let liquidation_sub = setup_subscription(
+            provider.clone(),
+            &pool_addresses,
             liquidation_call_signature,
-            Box::new(LiquidationCallProcessor) as Box<dyn EventProcessor>,
-        ),
-        (
+            "liquidation",
+        )
+        .await?;
+
+        let borrow_sub = setup_subscription(
+            provider.clone(),
+            &pool_addresses,
             borrow_signature,
-            Box::new(BorrowProcessor) as Box<dyn EventProcessor>,
-        ),
-        (
+            "borrow",
+        )
+        .await?;
+
+        let supply_sub = setup_subscription(
+            provider.clone(),
+            &pool_addresses,
             supply_signature,
-            Box::new(SupplyProcessor) as Box<dyn EventProcessor>,
-        ),
-        (
-            repay_signature,
-            Box::new(RepayProcessor) as Box<dyn EventProcessor>,
-        ),
-    ]
-    .into();
+            "supply",
+        )
+        .await?;
 
-    loop {
-        let provider = setup_provider(ipc_url.to_string()).await?;
+        let repay_sub =
+            setup_subscription(provider.clone(), &pool_addresses, repay_signature, "repay").await?;
+
+        let withdraw_sub = setup_subscription(
+            provider.clone(),
+            &pool_addresses,
+            withdraw_signature,
+            "withdraw",
+        )
+        .await?;
+
+        let collateral_enabled_sub = setup_subscription(
+            provider.clone(),
+            &pool_addresses,
+            collateral_enabled_signature,
+            "reserve used as collateral enabled",
+        )
+        .await?;
+
+        let collateral_disabled_sub = setup_subscription(
+            provider.clone(),
+            &pool_addresses,
+            collateral_disabled_signature,
+            "reserve used as collateral disabled",
+        )
+        .await?;
 
-        let liquidation_sub =
-            setup_subscription(provider.clone(), liquidation_call_signature, "liquidation").await?;
+        let user_emode_set_sub = setup_subscription(
+            provider.clone(),
+            &pool_addresses,
+            user_emode_set_signature,
+            "user emode set",
+        )
+        .await?;
 
-        let borrow_sub = setup_subscription(provider.clone(), borrow_signature, "borrow").await?;
+        let swap_borrow_rate_mode_sub = setup_subscription(
+            provider.clone(),
+            &pool_addresses,
+            swap_borrow_rate_mode_signature,
+            "swap borrow rate mode",
+        )
+        .await?;
 
-        let supply_sub = setup_subscription(provider.clone(), supply_signature, "supply").await?;
+        let rebalance_stable_borrow_rate_sub = setup_subscription(
+            provider.clone(),
+            &pool_addresses,
+            rebalance_stable_borrow_rate_signature,
+            "rebalance stable borrow rate",
+        )
+        .await?;
 
-        let repay_sub = setup_subscription(provider.clone(), repay_signature, "repay").await?;
+        let asset_source_sub = setup_subscription(
+            provider.clone(),
+            &oracle_addresses,
+            asset_source_updated_signature,
+            "asset source updated",
+        )
+        .await?;
+
+        let reserve_initialized_sub = setup_subscription(
+            provider.clone(),
+            &configurator_addresses,
+            reserve_initialized_signature,
+            "reserve initialized",
+        )
+        .await?;
+
+        let collateral_configuration_sub = setup_subscription(
+            provider.clone(),
+            &configurator_addresses,
+            collateral_configuration_changed_signature,
+            "collateral configuration changed",
+        )
+        .await?;
+
+        let reserve_frozen_sub = setup_subscription(
+            provider.clone(),
+            &configurator_addresses,
+            reserve_frozen_signature,
+            "reserve frozen",
+        )
+        .await?;
+
+        let reserve_paused_sub = setup_subscription(
+            provider.clone(),
+            &configurator_addresses,
+            reserve_paused_signature,
+            "reserve paused",
+        )
+        .await?;
+
+        // Read on every connection. The tokens of reserves listed while connected are followed
+        // through a subscription of their own, see follow_reserve_tokens().
+        let mut underlying_by_token = HashMap::new();
+        let mut market_by_emitter = market_by_contract.clone();
+        for market in &markets {
+            let tokens = get_underlying_by_token(provider.clone(), market).await?;
+            market_by_emitter.extend(
+                tokens
+                    .keys()
+                    .map(|token| (*token, market.addresses_provider)),
+            );
+            underlying_by_token.extend(tokens);
+        }
+        let transfer_sub = provider
+            .subscribe_logs(
+                &Filter::new()
+                    .address(
+                        underlying_by_token
+                            .keys()
+                            .cloned()
+                            .collect::<Vec<Address>>(),
+                    )
+                    .event_signature(transfer_signature),
+            )
+            .await
+            .map_err(|e| {
+                error!("Failed to subscribe to transfer events: {}", e);
+                WhistleblowerError::SubscriptionError(e.to_string())
+            })?;
+        let mut token_processors: HashMap<FixedBytes<32>, Box<dyn EventProcessor>> = [(
+            transfer_signature,
+            Box::new(TransferProcessor {
+                underlying_by_token: underlying_by_token.clone(),
+            }) as Box<dyn EventProcessor>,
+        )]
+        .into();
+
+        let mut heads = provider
+            .subscribe_blocks()
+            .await
+            .map_err(|e| {
+                error!("Failed to subscribe to new heads: {}", e);
+                WhistleblowerError::SubscriptionError(e.to_string())
+            })?
+            .into_stream();
 
         let mut all_event_streams = select_all(vec![
             liquidation_sub.into_stream(),
             borrow_sub.into_stream(),
             supply_sub.into_stream(),
             repay_sub.into_stream(),
+            withdraw_sub.into_stream(),
+            collateral_enabled_sub.into_stream(),
+            collateral_disabled_sub.into_stream(),
+            user_emode_set_sub.into_stream(),
+            swap_borrow_rate_mode_sub.into_stream(),
+            rebalance_stable_borrow_rate_sub.into_stream(),
+            asset_source_sub.into_stream(),
+            reserve_initialized_sub.into_stream(),
+            collateral_configuration_sub.into_stream(),
+            reserve_frozen_sub.into_stream(),
+            reserve_paused_sub.into_stream(),
+            transfer_sub.into_stream(),
         ]);
         info!("Listening for interesting transactions...");
+        let connected_at = Instant::now();
+        let mut reserve_listed = false;
 
-        while let Some(log) = all_event_streams.next().await {
+        loop {
+            let log = tokio::select! {
+                log = all_event_streams.next() => match log {
+                    Some(log) => log,
+                    None => break,
+                },
+                head = heads.next() => match head {
+                    Some(head) => {
+                        check_for_reorgs(&provider, &mut reorg_tracker, head, &vega_socket).await;
+                        continue;
+                    }
+                    None => break,
+                },
+            };
             let block_number = U64::from(log.block_number.unwrap_or_default());
+            // Sent by the node for the logs of a block it just reorged out
+            if log.removed {
+                if let Some(block_hash) = log.block_hash {
+                    let updates = reorg_tracker.take_block(block_number.to(), block_hash);
+                    let head = provider.get_block_number().await.unwrap_or_default();
+                    resend_reorged_updates(block_number.to(), updates, head, &vega_socket);
+                }
+                continue;
+            }
             if let Some(event_signature) = log.topics().first() {
-                if log.address() != AAVE_V3_POOL_ADDRESS {
+                let Some(market) = market_by_emitter.get(&log.address()).copied() else {
+                    FOREIGN_LOGS_IGNORED.inc();
+                    warn!(
+                        "Ignoring log emitted by {}, which isn't followed: {:?}",
+                        log.address(),
+                        log
+                    );
+                    continue;
+                };
+                if oracle_addresses.contains(&log.address())
+                    && *event_signature == asset_source_updated_signature
+                {
+                    EVENTS_SEEN.inc();
+                    send_asset_source_update(
+                        &provider,
+                        &log,
+                        market,
+                        &[&vega_socket, &oops_socket],
+                    )
+                    .await;
                     continue;
                 }
-                if let Some(event_processor) = event_processors.get(event_signature) {
+                let processors = match processors_by_contract.get(&log.address()) {
+                    Some(processors) => processors,
+                    None => &token_processors,
+                };
+                EVENTS_SEEN.inc();
+                if let Some(event_processor) = processors.get(event_signature) {
                     match event_processor.process(&log, block_number) {
                         Ok(event_details) => {
-                            send_whistleblower_update(&log, &event_details, &vega_socket);
+                            if send_whistleblower_update(&log, market, &event_details, &vega_socket)
+                            {
+                                if let Some(block_hash) = log.block_hash {
+                                    reorg_tracker.record(
+                                        block_number.to(),
+                                        block_hash,
+                                        trace_id_of(&log),
+                                        market,
+                                        event_details.clone(),
+                                    );
+                                }
+                            }
+                            if matches!(
+                                event_details.event,
+                                WhistleblowerEventType::ReserveInitialized
+                            ) {
+                                let Some(tokens) = follow_reserve_tokens(
+                                    &provider,
+                                    &event_details,
+                                    transfer_signature,
+                                )
+                                .await
+                                else {
+                                    reserve_listed = true;
+                                    break;
+                                };
+                                let (subscription, new_tokens) = tokens;
+                                all_event_streams.push(subscription.into_stream());
+                                market_by_emitter
+                                    .extend(new_tokens.iter().map(|(token, _)| (*token, market)));
+                                underlying_by_token.extend(new_tokens);
+                                token_processors.insert(
+                                    transfer_signature,
+                                    Box::new(TransferProcessor {
+                                        underlying_by_token: underlying_by_token.clone(),
+                                    }),
+                                );
+                            }
                         }
                         Err(e) => {
                             warn!("Failed to process event: {}", e);
@@ -360,7 +1453,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 warn!("Empty log topics detected: {:?}", log);
             }
         }
-        warn!("Stream closed. Reconnecting in 5 seconds...");
-        sleep(Duration::from_secs(5)).await;
+        if reserve_listed {
+            info!("Couldn't follow the tokens of a listed reserve, reconnecting to follow them");
+            continue;
+        }
+        backoff.connection_lasted(connected_at.elapsed());
+        let delay = backoff.next_delay();
+        warn!("Stream closed. Reconnecting in {:?}...", delay);
+        sleep(delay).await;
     }
 }
@@ -0,0 +1,72 @@
+use alloy::primitives::{Address, B256};
+use overlord_shared::WhistleblowerEventDetails;
+use std::collections::BTreeMap;
+
+/// Blocks are final after two epochs, so older ones can't be reorged out anymore
+pub const REORG_DEPTH: u64 = 64;
+
+/// Remembers the updates sent for the logs of the last REORG_DEPTH blocks, and the hash of the
+/// block they came from, so they can be sent again if that block is reorged out.
+///
+/// vega handles every update by reading the affected users (or reserves) again, so sending an
+/// update once the canonical chain is in place undoes whatever it read from the orphaned block.
+#[derive(Default)]
+pub struct ReorgTracker {
+    /// Trace id, market and details of the updates sent, by block number and hash
+    sent: BTreeMap<u64, BTreeMap<B256, Vec<(String, Address, WhistleblowerEventDetails)>>>,
+    last_head: Option<(u64, B256)>,
+}
+
+impl ReorgTracker {
+    pub fn record(
+        &mut self,
+        block_number: u64,
+        block_hash: B256,
+        trace_id: String,
+        market: Address,
+        event_details: WhistleblowerEventDetails,
+    ) {
+        self.sent
+            .entry(block_number)
+            .or_default()
+            .entry(block_hash)
+            .or_default()
+            .push((trace_id, market, event_details));
+    }
+
+    /// Forgets the updates sent for `block_hash`, and returns them
+    pub fn take_block(
+        &mut self,
+        block_number: u64,
+        block_hash: B256,
+    ) -> Vec<(String, Address, WhistleblowerEventDetails)> {
+        let Some(blocks) = self.sent.get_mut(&block_number) else {
+            return vec![];
+        };
+        let updates = blocks.remove(&block_hash).unwrap_or_default();
+        if blocks.is_empty() {
+            self.sent.remove(&block_number);
+        }
+        updates
+    }
+
+    /// Takes a new head, and returns false if it doesn't build on the previous one. That's the
+    /// case after a reorg, but also after missing heads (e.g. while reconnecting), so the blocks
+    /// that were recorded have to be checked against the canonical chain then.
+    pub fn extends_last_head(&mut self, number: u64, hash: B256, parent_hash: B256) -> bool {
+        let extends = self.last_head.is_none_or(|(last_number, last_hash)| {
+            number == last_number + 1 && parent_hash == last_hash
+        });
+        self.last_head = Some((number, hash));
+        self.sent = self.sent.split_off(&number.saturating_sub(REORG_DEPTH));
+        extends
+    }
+
+    /// Number and hash of every block updates were recorded for
+    pub fn recorded_blocks(&self) -> Vec<(u64, B256)> {
+        self.sent
+            .iter()
+            .flat_map(|(number, blocks)| blocks.keys().map(|hash| (*number, *hash)))
+            .collect()
+    }
+}
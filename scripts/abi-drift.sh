@@ -0,0 +1,147 @@
+#!/bin/bash
+
+########################################################################
+# ABI drift check
+#
+# Fetches the verified ABI of every contract listed in
+# crates/overlord-shared/src/abis/sources.txt and compares its functions,
+# events and errors with the ABI file embedded in overlord-shared.
+# Anything missing on either side is reported, so interface changes show
+# up here instead of as decode failures at runtime.
+#
+# ABIs come from Etherscan when ETHERSCAN_API_KEY is set, and from
+# Sourcify otherwise (or when Etherscan doesn't have the contract).
+# Proxies and EACAggregatorProxies are resolved with cast.
+#
+# Needs curl, jq and cast.
+#
+# Usage: ./scripts/abi-drift.sh [--rpc-url <url>]
+#
+# Exits with 1 if any drift was found, 2 if an ABI couldn't be fetched.
+########################################################################
+
+SCRIPT_DIR="$( cd "$( dirname "${BASH_SOURCE[0]}" )" && pwd )"
+ABIS_DIR="$SCRIPT_DIR/../crates/overlord-shared/src/abis"
+SOURCES_FILE="$ABIS_DIR/sources.txt"
+RPC_URL="/tmp/reth.ipc"
+EIP1967_IMPLEMENTATION_SLOT="0x360894a13ba1a3210667c828492db98dca3e2076cc3735a920c3ca505d382bbc"
+
+if [ "$1" = "--rpc-url" ]; then
+    if [ -z "$2" ]; then
+        echo "Error: --rpc-url requires a URL argument"
+        exit 2
+    fi
+    RPC_URL="$2"
+fi
+
+for tool in curl jq cast; do
+    if ! command -v $tool > /dev/null 2>&1; then
+        echo "Error: $tool is required"
+        exit 2
+    fi
+done
+
+# One line per function, event and error, with tuple components expanded, e.g.
+# function getUserAccountData(address) returns (uint256,uint256,uint256,uint256,uint256,uint256)
+normalize_abi() {
+    jq -r '
+        def param: if (.type | startswith("tuple"))
+            then "(" + ([.components[] | param] | join(",")) + ")" + (.type | ltrimstr("tuple"))
+            else .type end;
+        .[]
+        | select(.type == "function" or .type == "event" or .type == "error")
+        | "\(.type) \(.name)(\([.inputs[]? | param] | join(",")))"
+            + (if .type == "function" then " returns (\([.outputs[]? | param] | join(",")))" else "" end)
+    ' | sort -u
+}
+
+resolve_address() {
+    local address=$1
+    local kind=$2
+    case $kind in
+        direct)
+            echo "$address"
+            ;;
+        eip1967)
+            local slot
+            slot=$(cast storage --rpc-url "$RPC_URL" "$address" "$EIP1967_IMPLEMENTATION_SLOT") || return 1
+            cast parse-bytes32-address "$slot"
+            ;;
+        eac)
+            cast call --rpc-url "$RPC_URL" "$address" "aggregator()(address)"
+            ;;
+        *)
+            echo "Unknown resolution $kind" >&2
+            return 1
+            ;;
+    esac
+}
+
+fetch_abi() {
+    local address=$1
+    local response
+    if [ -n "$ETHERSCAN_API_KEY" ]; then
+        response=$(curl -s "https://api.etherscan.io/api?module=contract&action=getabi&address=$address&apikey=$ETHERSCAN_API_KEY")
+        if [ "$(echo "$response" | jq -r '.status')" = "1" ]; then
+            echo "$response" | jq -r '.result'
+            return 0
+        fi
+    fi
+    response=$(curl -s "https://sourcify.dev/server/v2/contract/1/$address?fields=abi")
+    if echo "$response" | jq -e '.abi | type == "array"' > /dev/null 2>&1; then
+        echo "$response" | jq '.abi'
+        return 0
+    fi
+    return 1
+}
+
+DRIFTED=0
+FAILED=0
+CONFIGURED=()
+
+while read -r abi_file address kind; do
+    if [ -z "$abi_file" ] || [[ "$abi_file" == \#* ]]; then
+        continue
+    fi
+    CONFIGURED+=("$abi_file")
+    contract=$(resolve_address "$address" "$kind")
+    if [ $? -ne 0 ] || [ -z "$contract" ]; then
+        echo "FAIL  | $abi_file | couldn't resolve $address ($kind)"
+        FAILED=1
+        continue
+    fi
+    verified_abi=$(fetch_abi "$contract")
+    if [ $? -ne 0 ]; then
+        echo "FAIL  | $abi_file | no verified ABI for $contract"
+        FAILED=1
+        continue
+    fi
+    embedded=$(normalize_abi < "$ABIS_DIR/$abi_file")
+    verified=$(echo "$verified_abi" | normalize_abi)
+    only_embedded=$(comm -23 <(echo "$embedded") <(echo "$verified"))
+    only_verified=$(comm -13 <(echo "$embedded") <(echo "$verified"))
+    if [ -z "$only_embedded" ] && [ -z "$only_verified" ]; then
+        echo "OK    | $abi_file | $contract"
+        continue
+    fi
+    DRIFTED=1
+    echo "DRIFT | $abi_file | $contract"
+    if [ -n "$only_embedded" ]; then
+        echo "$only_embedded" | sed 's/^/        - /'
+    fi
+    if [ -n "$only_verified" ]; then
+        echo "$only_verified" | sed 's/^/        + /'
+    fi
+done < "$SOURCES_FILE"
+
+for abi_path in "$ABIS_DIR"/*.json "$ABIS_DIR"/*/*.json; do
+    abi_file=${abi_path#"$ABIS_DIR/"}
+    if [[ ! " ${CONFIGURED[*]} " =~ " $abi_file " ]]; then
+        echo "SKIP  | $abi_file | not configured in sources.txt"
+    fi
+done
+
+if [ $FAILED -ne 0 ]; then
+    exit 2
+fi
+exit $DRIFTED
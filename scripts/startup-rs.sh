@@ -18,8 +18,6 @@
 # Get script directory regardless of how it's called
 SCRIPT_DIR="$( cd "$( dirname "${BASH_SOURCE[0]}" )" && pwd )"
 ENV_FILE="$SCRIPT_DIR/../.env"
-VENV_DIR="$SCRIPT_DIR/.venv"
-REQUIREMENTS_FILE="$SCRIPT_DIR/requirements.txt"
 
 # Load environment variables from .env file in parent directory
 if [ -f "$ENV_FILE" ]; then
@@ -65,56 +63,27 @@ echo "startup-rs.sh # Clearing $PID_DIR directory and creating a new one"
 rm -rf "$PID_DIR"
 mkdir -p "$PID_DIR"
 
-setup_venv() {
-    echo "Setting up virtual environment..."
-    python3 -m venv "$VENV_DIR"
-    source "$VENV_DIR/bin/activate"
-    pip install --upgrade pip
-}
-
-install_requirements() {
-    echo "Installing requirements..."
-    pip install -r "$REQUIREMENTS_FILE"
-}
-
-# Create venv if it doesn't exist
-if [ ! -d "$VENV_DIR" ]; then
-    setup_venv
-    install_requirements
-fi
-
 start_vega() {
-    local force_pmex_update=false
-
-    # Check function arguments (not script arguments)
-    if [ $# -gt 0 ] && [ "$1" == "--force-pmex-update" ]; then
-        force_pmex_update=true
-    fi
-
-    # Check if virtual environment is activated correctly
-    source "$VENV_DIR/bin/activate"
-    if [[ "$VIRTUAL_ENV" != "$VENV_DIR" ]]; then
-        echo "startup-rs.sh # Failed to activate virtual environment at $VENV_DIR"
-        exit 1
-    fi
-    echo "startup-rs.sh # Virtual environment activated at $VIRTUAL_ENV"
-    echo "startup-rs.sh # Running $SCRIPT_DIR/run_pmex.py with force-pmex-update=$force_pmex_update"
-    VEGA_ADDRESSES_FILE=$(DATA_DIR="$DATA_DIR" python3 $SCRIPT_DIR/run_pmex.py --force-pmex-update="$force_pmex_update" | tee /dev/tty | tail -n1)
-    source deactivate
-
-    # Check if output is empty
-    if ! [[ "$VEGA_ADDRESSES_FILE" =~ "addresses_" && "$VEGA_ADDRESSES_FILE" =~ \.txt$ ]]; then
-        echo "startup-rs.sh # seems run_pmex.py returned an error. Aborting startup." >&2
-        exit 1
-    fi
+    # Borrowers are discovered by vega itself. The checkpoint lives in DATA_DIR so restarts
+    # (and --ignore-temp-output) don't throw away the backfill
+    local borrowers_checkpoint_file="${VEGA_BORROWERS_CHECKPOINT_FILE:-$DATA_DIR/vega_borrowers_checkpoint.json}"
+    # Same for the cache snapshot, which lets vega skip most of the cache initialization
+    local cache_snapshot_file="${VEGA_CACHE_SNAPSHOT_FILE:-$DATA_DIR/vega_cache_snapshot.bin}"
 
     echo "startup-rs.sh # Attempting to start vega-rs with"
-    echo "startup-rs.sh #    - VEGA_ADDRESSES_FILE=$VEGA_ADDRESSES_FILE"
-    echo "startup-rs.sh #    - VEGA_CHAINLINK_ADDRESSES_FILE=$VEGA_CHAINLINK_ADDRESSES_FILE"
+    echo "startup-rs.sh #    - VEGA_BORROWERS_CHECKPOINT_FILE=$borrowers_checkpoint_file"
+    echo "startup-rs.sh #    - VEGA_CACHE_SNAPSHOT_FILE=$cache_snapshot_file"
+    echo "startup-rs.sh #    - VEGA_CHAINLINK_ADDRESSES_FILE=${VEGA_CHAINLINK_ADDRESSES_FILE:-<generated from the AaveOracle>}"
     echo "startup-rs.sh #    - TEMP_OUTPUT_DIR=$TEMP_OUTPUT_DIR"
+    # Only pass the mapping file when it's set, otherwise vega generates the mapping itself
+    local chainlink_env=()
+    if [ -n "$VEGA_CHAINLINK_ADDRESSES_FILE" ]; then
+        chainlink_env=(VEGA_CHAINLINK_ADDRESSES_FILE="$VEGA_CHAINLINK_ADDRESSES_FILE")
+    fi
     setsid env \
-        VEGA_ADDRESSES_FILE="$VEGA_ADDRESSES_FILE" \
-        VEGA_CHAINLINK_ADDRESSES_FILE="$VEGA_CHAINLINK_ADDRESSES_FILE" \
+        VEGA_BORROWERS_CHECKPOINT_FILE="$borrowers_checkpoint_file" \
+        VEGA_CACHE_SNAPSHOT_FILE="$cache_snapshot_file" \
+        "${chainlink_env[@]}" \
         TEMP_OUTPUT_DIR="$TEMP_OUTPUT_DIR" \
         "$VEGA_RS_BIN_PATH" > /dev/null 2>&1 &
     echo $! > "$PID_DIR/vega-rs.pid"
@@ -134,11 +103,7 @@ start_app() {
 
 # Start applications and store their PIDs
 
-if [[ " $* " =~ " --force-pmex-update " ]]; then
-    start_vega "--force-pmex-update"
-else
-    start_vega
-fi
+start_vega
 
 start_app "$WHISTLEBLOWER_RS_BIN_PATH" "$PID_DIR/whistleblower-rs.pid"
 start_app "$OOPS_RS_BIN_PATH" "$PID_DIR/oops-rs.pid"